@@ -2,6 +2,8 @@
 use assert_infrequent;
 use basics::*;
 use consensus::{self, Authority};
+use debug::invariants::InvariantViolation;
+use debug::snapshot::{GraphSnapshot, NodeDescription};
 use debug::stats;
 use failure::{self, ResultExt};
 use futures::{
@@ -20,7 +22,7 @@ use std::time::Duration;
 use table::{Table, TableBuilder, TableRpc};
 use tokio;
 use view::{View, ViewBuilder, ViewRpc};
-use ActivationResult;
+use {ActivationResult, BaseSchema, InputInfo, OutputInfo, RecipeInfo, UniverseInfo};
 
 /// Describes a running controller instance.
 ///
@@ -34,6 +36,25 @@ pub struct ControllerDescriptor {
     pub nonce: u64,
 }
 
+/// The reason why a `View` or `Table` could not be obtained for a given name.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Fail)]
+pub enum LookupBuilderError {
+    /// There is no table or view with the given name.
+    #[fail(display = "no table or view named {}", _0)]
+    NotFound(String),
+    /// A node with the given name exists, but it has no reader to serve lookups from.
+    #[fail(display = "{} is not a view", _0)]
+    NoReader(String),
+    /// A node with the given name exists, but it is not a base table.
+    #[fail(display = "{} is not a table", _0)]
+    NotATable(String),
+    /// The given table or view exists, but is not yet ready to be used, for example because its
+    /// state has not been materialized or because its worker has failed. Retrying later may
+    /// succeed.
+    #[fail(display = "{} is not yet available", _0)]
+    NotMaterialized(String),
+}
+
 /// A handle to a Soup controller.
 ///
 /// This handle is the primary mechanism for interacting with a running Soup instance, and lets you
@@ -164,10 +185,24 @@ impl<A: Authority> ControllerHandle<A> {
             self.req.as_mut().unwrap().unbounded_send((r, tx)).unwrap();
             let (status, body) = rx.wait()?;
             match status {
+                hyper::StatusCode::NOT_FOUND | hyper::StatusCode::SERVICE_UNAVAILABLE
+                    if !body.is_empty() =>
+                {
+                    // the controller told us why it could not give us a table or view
+                    let e: LookupBuilderError = serde_json::from_slice(&body)
+                        .context(format!("while decoding rpc error reply from {}", path))?;
+                    return Err(e.into());
+                }
                 hyper::StatusCode::SERVICE_UNAVAILABLE => {
                     thread::sleep(Duration::from_millis(100));
                     continue;
                 }
+                hyper::StatusCode::CONFLICT => {
+                    bail!("{} rejected: another migration is already in progress", path);
+                }
+                hyper::StatusCode::GATEWAY_TIMEOUT => {
+                    bail!("{} timed out waiting for other migrations to finish", path);
+                }
                 status @ hyper::StatusCode::INTERNAL_SERVER_ERROR
                 | status @ hyper::StatusCode::OK => {
                     if let hyper::StatusCode::OK = status {
@@ -202,16 +237,51 @@ impl<A: Authority> ControllerHandle<A> {
         self.rpc("outputs", &())
     }
 
+    /// Enumerate all known base tables, along with their columns and whether they are ready.
+    pub fn inputs_detailed(&mut self) -> Result<BTreeMap<String, InputInfo>, failure::Error> {
+        Ok(self.rpc("inputs_detailed", &()).context("listing inputs")?)
+    }
+
+    /// Enumerate all known external views, along with their columns, key columns, and whether
+    /// they are materialized and ready.
+    pub fn outputs_detailed(&mut self) -> Result<BTreeMap<String, OutputInfo>, failure::Error> {
+        Ok(self.rpc("outputs_detailed", &()).context("listing outputs")?)
+    }
+
+    /// Enumerate the replay paths planned so far, ordered by their tag.
+    ///
+    /// Each path lists its nodes in the order replays flow along it, starting at the materialized
+    /// node that the replay is sourced from.
+    pub fn replay_paths(&mut self) -> Result<Vec<(Tag, Vec<NodeIndex>)>, failure::Error> {
+        self.rpc("replay_paths", &())
+    }
+
+    /// Get the base table columns that the given column of the given node is derived from.
+    ///
+    /// Computed columns are derived from the columns they are computed from, while columns with
+    /// no inputs, such as literals or counts, are not derived from any base column.
+    pub fn column_lineage(
+        &mut self,
+        node: NodeIndex,
+        column: usize,
+    ) -> Result<Vec<(NodeIndex, usize)>, failure::Error> {
+        Ok(self
+            .rpc("column_lineage", (node, column))
+            .context(format!("tracing column {} of node {}", column, node.index()))?)
+    }
+
     /// Obtain a `View` that allows you to query the given external view.
+    ///
+    /// If no such view can be obtained, the error is caused by a `LookupBuilderError`.
     pub fn view(&mut self, name: &str) -> Result<View, failure::Error> {
         // This call attempts to detect if this function is being called in a loop. If this
         // is getting false positives, then it is safe to increase the allowed hit count.
         #[cfg(debug_assertions)]
         assert_infrequent::at_most(200);
 
-        self.rpc::<_, Option<ViewBuilder>>("view_builder", name)
-            .context(format!("building View for {}", name))?
-            .ok_or_else(|| format_err!("view {} does not exist", name))
+        self.rpc::<_, ViewBuilder>("view_builder", name)
+            .context(format!("building View for {}", name))
+            .map_err(failure::Error::from)
             .and_then(|mut g| {
                 if let Some(port) = self.local_port {
                     g = g.with_local_port(port);
@@ -229,15 +299,17 @@ impl<A: Authority> ControllerHandle<A> {
 
     /// Obtain a `Table` that allows you to perform writes, deletes, and other operations on the
     /// given base table.
+    ///
+    /// If no such table can be obtained, the error is caused by a `LookupBuilderError`.
     pub fn table(&mut self, name: &str) -> Result<Table, failure::Error> {
         // This call attempts to detect if this function is being called in a loop. If this
         // is getting false positives, then it is safe to increase the allowed hit count.
         #[cfg(debug_assertions)]
         assert_infrequent::at_most(200);
 
-        self.rpc::<_, Option<TableBuilder>>("table_builder", name)
-            .context(format!("building Table for {}", name))?
-            .ok_or_else(|| format_err!("view {} does not exist", name))
+        self.rpc::<_, TableBuilder>("table_builder", name)
+            .context(format!("building Table for {}", name))
+            .map_err(failure::Error::from)
             .and_then(|mut m| {
                 if let Some(port) = self.local_port {
                     m = m.with_local_port(port);
@@ -258,6 +330,158 @@ impl<A: Authority> ControllerHandle<A> {
         Ok(self.rpc("get_statistics", &()).context("getting stats")?)
     }
 
+    /// Get the processing time and memory used by each query, keyed by query name.
+    ///
+    /// See `stats::QueryStats` for what is included.
+    pub fn query_statistics(
+        &mut self,
+    ) -> Result<BTreeMap<String, stats::QueryStats>, failure::Error> {
+        Ok(self.rpc("query_stats", &()).context("getting per-query stats")?)
+    }
+
+    /// Get statistics about the time spent processing different parts of the graph, rendered in
+    /// the Prometheus text exposition format.
+    pub fn metrics(&mut self) -> Result<String, failure::Error> {
+        Ok(self.rpc("metrics", &()).context("getting metrics")?)
+    }
+
+    /// Get the progress of full replays performed by migrations, keyed by replay path tag.
+    ///
+    /// Unlike most other requests, this is answered even while a migration is in progress. Only
+    /// the most recently completed replays are included, along with those still running.
+    pub fn replay_status(&mut self) -> Result<HashMap<u32, stats::ReplayProgress>, failure::Error> {
+        Ok(self
+            .rpc("replay_status", &())
+            .context("fetching replay status")?)
+    }
+
+    /// Get the workers known to the controller, whether they are healthy, and how long ago they
+    /// last sent a heartbeat.
+    pub fn instances(&mut self) -> Result<Vec<(SocketAddr, bool, Duration)>, failure::Error> {
+        Ok(self.rpc("instances", &()).context("fetching instances")?)
+    }
+
+    /// Get the worker that each shard of each domain runs on, keyed by domain.
+    pub fn assignments(
+        &mut self,
+    ) -> Result<HashMap<DomainIndex, Vec<stats::ShardAssignment>>, failure::Error> {
+        Ok(self
+            .rpc("assignments", &())
+            .context("fetching domain assignments")?)
+    }
+
+    /// Get the index, name, and description of the data-flow nodes on the given worker, or of all
+    /// nodes if no worker is given.
+    ///
+    /// Base tables are only included if `with_bases` is set, and are described as `Base`.
+    pub fn nodes_on_worker(
+        &mut self,
+        worker: Option<SocketAddr>,
+        with_bases: bool,
+    ) -> Result<Vec<(NodeIndex, String, String)>, failure::Error> {
+        let mut vars = Vec::new();
+        if let Some(w) = worker {
+            vars.push(format!("w={}", w));
+        }
+        if with_bases {
+            vars.push(String::from("bases"));
+        }
+        let path = format!("nodes?{}", vars.join("&"));
+        Ok(self
+            .rpc(&path, &())
+            .context("fetching nodes on worker")?)
+    }
+
+    /// Place the domain of the base table `base` on `worker` once the base is created. Useful for
+    /// co-locating latency-sensitive writers with their clients.
+    ///
+    /// If the worker is unavailable when the base is created, the base is placed elsewhere.
+    pub fn pin_base(&mut self, base: &str, worker: SocketAddr) -> Result<(), failure::Error> {
+        self.rpc("pin_base", (base, worker))
+            .context(format!("pinning base {} to {}", base, worker))?;
+        Ok(())
+    }
+
+    /// Move all domains off `worker` and remove it from the deployment, so that it can be shut
+    /// down.
+    ///
+    /// The views on the worker are rebuilt on the remaining workers by replay from their base
+    /// tables. Base tables themselves cannot be moved, since their state lives on the worker
+    /// that hosts them, so this fails without changing anything if `worker` hosts any base
+    /// table. `pin_base` can be used to place base tables on workers that are to stay.
+    ///
+    /// If the views cannot be rebuilt elsewhere, the worker stays in the deployment, and keeps
+    /// running the domains that were not moved.
+    pub fn decommission(&mut self, worker: SocketAddr) -> Result<(), failure::Error> {
+        self.rpc("decommission", worker)
+            .context(format!("decommissioning worker {}", worker))?;
+        Ok(())
+    }
+
+    /// Change the number of shards of the view `name` to `shards`.
+    ///
+    /// A new reader with `shards` shards is filled from the view's current state before lookups
+    /// move over to it, so the view stays available throughout. Existing `View` handles for it
+    /// cannot be used afterwards though, and must be obtained anew.
+    pub fn reshard_view(&mut self, name: &str, shards: usize) -> Result<(), failure::Error> {
+        self.rpc("reshard_view", (name, shards))
+            .context(format!("resharding view {} to {} shards", name, shards))?;
+        Ok(())
+    }
+
+    /// Give the view `old` the name `new`. The view keeps its state, and existing `View` handles
+    /// for it keep working.
+    pub fn rename_view(&mut self, old: &str, new: &str) -> Result<(), failure::Error> {
+        self.rpc("rename_view", (old, new))
+            .context(format!("renaming view {} to {}", old, new))?;
+        Ok(())
+    }
+
+    /// Add a secondary index on the columns `key` of the view `view`, so that its rows can also
+    /// be looked up by those columns through the view `name`. The index is kept when the view is
+    /// recovered, and when the controller restarts.
+    pub fn add_view_index(
+        &mut self,
+        view: &str,
+        name: &str,
+        key: &[&str],
+    ) -> Result<(), failure::Error> {
+        self.rpc("add_view_index", (name, view, key))
+            .context(format!("adding index {} on view {}", name, view))?;
+        Ok(())
+    }
+
+    /// Undo the most recent `install_recipe` or `extend_recipe`, removing any views and tables it
+    /// added and restoring any it removed. The restored recipe gets a new version.
+    pub fn rollback_recipe(&mut self) -> Result<ActivationResult, failure::Error> {
+        Ok(self.rpc("rollback_recipe", &()).context("rolling back recipe")?)
+    }
+
+    /// Get the text of the currently active recipe, along with its version.
+    pub fn recipe(&mut self) -> Result<RecipeInfo, failure::Error> {
+        Ok(self.rpc("recipe", &()).context("fetching recipe")?)
+    }
+
+    /// Get the version of the currently active recipe.
+    pub fn recipe_version(&mut self) -> Result<usize, failure::Error> {
+        Ok(self.rpc("recipe/version", &()).context("fetching recipe version")?)
+    }
+
+    /// Get the memory size and materialization status of every materialized node.
+    pub fn node_sizes(&mut self) -> Result<HashMap<NodeIndex, stats::NodeSize>, failure::Error> {
+        Ok(self.rpc("node_sizes", &()).context("getting node sizes")?)
+    }
+
+    /// Limit the total size of partial state across all domains to the given number of bytes.
+    ///
+    /// Once the limit is exceeded, the controller evicts state from the largest partial nodes
+    /// until the limit is met again. Passing `None` removes the limit.
+    pub fn set_memory_limit(&mut self, bytes: Option<u64>) -> Result<(), failure::Error> {
+        self.rpc("set_memory_limit", bytes)
+            .context("setting memory limit")?;
+        Ok(())
+    }
+
     /// Flush all partial state, evicting all rows present.
     pub fn flush_partial(&mut self) -> Result<(), failure::Error> {
         self.rpc("flush_partial", &())
@@ -265,7 +489,19 @@ impl<A: Authority> ControllerHandle<A> {
         Ok(())
     }
 
+    /// Flush the partial state of a single node and of its reader, evicting all rows present.
+    ///
+    /// Returns the number of bytes evicted.
+    pub fn flush_partial_node(&mut self, node: NodeIndex) -> Result<u64, failure::Error> {
+        Ok(self
+            .rpc("flush_partial_node", node)
+            .context(format!("flushing partial state of node {}", node.index()))?)
+    }
+
     /// Extend the existing recipe with the given set of queries.
+    ///
+    /// If other migrations are running or waiting to run, this waits for them first. It fails if
+    /// that takes longer than the controller's migration queue timeout.
     pub fn extend_recipe(
         &mut self,
         recipe_addition: &str,
@@ -275,6 +511,27 @@ impl<A: Authority> ControllerHandle<A> {
             .context(format!("extending recipe with : {}", recipe_addition))?)
     }
 
+    /// Extend the existing recipe with the given set of queries, unless another migration is
+    /// already running or waiting to run.
+    ///
+    /// Unlike `ControllerHandle::extend_recipe`, this does not wait behind other migrations, but
+    /// returns an error immediately instead.
+    pub fn try_extend_recipe(
+        &mut self,
+        recipe_addition: &str,
+    ) -> Result<ActivationResult, failure::Error> {
+        Ok(self
+            .rpc("extend_recipe?nowait", recipe_addition)
+            .context(format!("extending recipe with : {}", recipe_addition))?)
+    }
+
+    /// Get the number of migrations that are currently running or waiting to run.
+    pub fn migration_queue(&mut self) -> Result<usize, failure::Error> {
+        Ok(self
+            .rpc("migration_queue", &())
+            .context("fetching migration queue depth")?)
+    }
+
     /// Replace the existing recipe with this one.
     pub fn install_recipe(&mut self, new_recipe: &str) -> Result<ActivationResult, failure::Error> {
         Ok(self
@@ -282,6 +539,54 @@ impl<A: Authority> ControllerHandle<A> {
             .context(format!("installing new recipe: {}", new_recipe))?)
     }
 
+    /// Replace the existing recipe with this one, even if it drops base tables that queries in
+    /// the new recipe still depend on.
+    ///
+    /// `ControllerHandle::install_recipe` refuses such recipes; this instead removes the dependent
+    /// queries along with the base tables.
+    pub fn force_install_recipe(
+        &mut self,
+        new_recipe: &str,
+    ) -> Result<ActivationResult, failure::Error> {
+        Ok(self
+            .rpc("install_recipe?force", new_recipe)
+            .context(format!("installing new recipe: {}", new_recipe))?)
+    }
+
+    /// Describe the columns, column types, and primary key of the given base table.
+    ///
+    /// If no such table exists, the error is caused by a `LookupBuilderError`.
+    pub fn base_schema(&mut self, name: &str) -> Result<BaseSchema, failure::Error> {
+        Ok(self
+            .rpc("base_schema", name)
+            .context(format!("fetching schema of {}", name))?)
+    }
+
+    /// Check whether extending the existing recipe with the given set of queries would succeed,
+    /// without changing the recipe or the running graph.
+    ///
+    /// The returned `ActivationResult` reports the queries that would be added, but its node
+    /// indices do not refer to nodes in the graph.
+    pub fn validate_recipe(
+        &mut self,
+        recipe_addition: &str,
+    ) -> Result<ActivationResult, failure::Error> {
+        Ok(self
+            .rpc("validate_recipe", recipe_addition)
+            .context(format!("validating recipe extension: {}", recipe_addition))?)
+    }
+
+    /// Check whether replacing the existing recipe with this one would succeed, without changing
+    /// the recipe or the running graph.
+    pub fn validate_install_recipe(
+        &mut self,
+        new_recipe: &str,
+    ) -> Result<ActivationResult, failure::Error> {
+        Ok(self
+            .rpc("validate_recipe?replace", new_recipe)
+            .context(format!("validating new recipe: {}", new_recipe))?)
+    }
+
     /// Fetch a graphviz description of the dataflow graph.
     pub fn graphviz(&mut self) -> Result<String, failure::Error> {
         Ok(self
@@ -289,6 +594,43 @@ impl<A: Authority> ControllerHandle<A> {
             .context("fetching graphviz representation")?)
     }
 
+    /// Fetch a graphviz description of the dataflow graph in which every materialized node is
+    /// labeled with the number of rows and bytes in its state.
+    pub fn graphviz_with_stats(&mut self) -> Result<String, failure::Error> {
+        Ok(self
+            .rpc("graphviz_with_stats", &())
+            .context("fetching graphviz representation with sizes")?)
+    }
+
+    /// Fetch a deterministic snapshot of the structure of the graph.
+    pub fn graph_snapshot(&mut self) -> Result<GraphSnapshot, failure::Error> {
+        Ok(self
+            .rpc("graph_snapshot", &())
+            .context("fetching graph snapshot")?)
+    }
+
+    /// Check the structure of the graph for inconsistencies, such as cross-domain edges that do
+    /// not go through ingress and egress nodes.
+    ///
+    /// An empty list means that no problems were found.
+    pub fn check_invariants(&mut self) -> Result<Vec<InvariantViolation>, failure::Error> {
+        Ok(self
+            .rpc("check", &())
+            .context("checking graph invariants")?)
+    }
+
+    /// Describe the node with the given index in detail.
+    pub fn describe_node(&mut self, node: NodeIndex) -> Result<NodeDescription, failure::Error> {
+        Ok(self
+            .rpc(&format!("node/{}", node.index()), &())
+            .context(format!("describing node {}", node.index()))?)
+    }
+
+    /// List the security universes that have been created, in the order they were created.
+    pub fn universes(&mut self) -> Result<Vec<UniverseInfo>, failure::Error> {
+        Ok(self.rpc("universes", &()).context("listing universes")?)
+    }
+
     /// Remove the given external view from the graph.
     pub fn remove_node(&mut self, view: NodeIndex) -> Result<(), failure::Error> {
         // TODO: this should likely take a view name, and we should verify that it's a Reader.
@@ -0,0 +1,58 @@
+/// A way in which the structure of the Soup data-flow graph is inconsistent.
+///
+/// Node and domain identifiers are the indices of the nodes and domains involved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvariantViolation {
+    /// A node other than the source node has no parents.
+    NoParents {
+        /// The parentless node.
+        node: usize,
+    },
+    /// A reader materializes a node that does not exist, or that has been removed.
+    DanglingReader {
+        /// The reader node.
+        reader: usize,
+        /// The node the reader claims to be for.
+        for_node: usize,
+    },
+    /// A node has not been assigned to a domain, or has been assigned to one that does not exist.
+    UnknownDomain {
+        /// The misassigned node.
+        node: usize,
+        /// The domain the node is assigned to, if any.
+        domain: Option<usize>,
+    },
+    /// A node is split into a different number of shards than the domain it is assigned to.
+    ShardMismatch {
+        /// The node in question.
+        node: usize,
+        /// The node's domain.
+        domain: usize,
+        /// The number of shards the node is split into.
+        node_shards: usize,
+        /// The number of shards of the node's domain.
+        domain_shards: usize,
+    },
+    /// An edge crosses a domain boundary without going from an egress or sharder node to an
+    /// ingress node.
+    UnroutedEdge {
+        /// The parent end of the edge.
+        from: usize,
+        /// The child end of the edge.
+        to: usize,
+    },
+    /// An egress node feeds a node in its own domain.
+    UnmatchedEgress {
+        /// The egress node.
+        egress: usize,
+        /// The child in the egress node's domain.
+        child: usize,
+    },
+    /// An ingress node is fed by a node in its own domain.
+    UnmatchedIngress {
+        /// The ingress node.
+        ingress: usize,
+        /// The parent in the ingress node's domain.
+        parent: usize,
+    },
+}
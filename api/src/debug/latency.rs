@@ -0,0 +1,279 @@
+use basics::*;
+use debug::trace::{Event, EventType, PacketEvent};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time;
+
+/// Number of bits of precision kept for each recorded value.
+///
+/// Values below `2^SUB_BUCKET_BITS` are recorded exactly. Larger values are rounded down to the
+/// nearest multiple of a power of two such that the relative error stays below
+/// `2^-(SUB_BUCKET_BITS - 1)`, i.e. under 2%.
+const SUB_BUCKET_BITS: u32 = 7;
+
+/// How long (in seconds) a packet's start time is kept around waiting for further events.
+///
+/// Packets that are dropped or filtered out along the way never reach a reader, so their start
+/// times would otherwise be kept forever.
+pub const UNFINISHED_TIMEOUT_SECS: u64 = 60;
+
+/// Find the lowest and highest value that share a bucket with `v`.
+fn bucket(v: u64) -> (u64, u64) {
+    let magnitude = 64 - v.leading_zeros();
+    if magnitude <= SUB_BUCKET_BITS {
+        (v, v)
+    } else {
+        let shift = magnitude - SUB_BUCKET_BITS;
+        let lower = v >> shift << shift;
+        (lower, lower + ((1 << shift) - 1))
+    }
+}
+
+fn nanos(d: time::Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+/// Summary of a latency distribution.
+///
+/// All times are in nanoseconds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyPercentiles {
+    /// Number of recorded samples.
+    pub count: u64,
+    /// Median latency.
+    pub p50: u64,
+    /// 95th percentile latency.
+    pub p95: u64,
+    /// 99th percentile latency.
+    pub p99: u64,
+    /// Highest recorded latency.
+    pub max: u64,
+}
+
+/// A histogram of latencies with a bounded relative error, in the style of HDR histograms.
+///
+/// Only a fixed number of significant bits of each sample are kept, so the histogram stays small
+/// no matter how many samples are recorded or how widely they are spread.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    /// Record a single latency sample.
+    pub fn record(&mut self, latency: time::Duration) {
+        let v = nanos(latency);
+        *self.buckets.entry(bucket(v).0).or_insert(0) += 1;
+        self.count += 1;
+        self.max = ::std::cmp::max(self.max, v);
+    }
+
+    /// Number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The latency (in nanoseconds) that the given fraction of samples did not exceed.
+    ///
+    /// Returns 0 if no samples have been recorded.
+    pub fn percentile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let rank = ::std::cmp::max(1, (q * self.count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (&lower, &n) in &self.buckets {
+            seen += n;
+            if seen >= rank {
+                return ::std::cmp::min(bucket(lower).1, self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Summarize the recorded samples.
+    pub fn percentiles(&self) -> LatencyPercentiles {
+        LatencyPercentiles {
+            count: self.count,
+            p50: self.percentile(0.50),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+/// Latency distributions derived from packet trace events.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LatencyStats {
+    /// Time from a packet leaving its input channel to reaching a reader.
+    pub end_to_end: LatencyPercentiles,
+    /// Time from a packet leaving its input channel to being processed at each node.
+    pub nodes: HashMap<NodeIndex, LatencyPercentiles>,
+}
+
+/// Aggregates the events of traced packets into latency histograms.
+///
+/// Latencies are measured from the first event seen for a packet's trace tag, which is normally
+/// `PacketEvent::ExitInputChannel`. Start times of packets that have not seen an event in
+/// `UNFINISHED_TIMEOUT_SECS` are forgotten.
+#[derive(Debug, Default)]
+pub struct LatencyCollector {
+    started: HashMap<u64, time::Instant>,
+    /// Tags in `started`, oldest first.
+    start_order: VecDeque<(time::Instant, u64)>,
+    end_to_end: LatencyHistogram,
+    nodes: HashMap<NodeIndex, LatencyHistogram>,
+}
+
+impl LatencyCollector {
+    /// Account for a single trace event.
+    pub fn record(&mut self, event: &Event) {
+        let EventType::PacketEvent(pe, tag) = event.event;
+        if let PacketEvent::Merged(_) = pe {
+            // the packet no longer triggers events of its own
+            self.started.remove(&tag);
+            return;
+        }
+
+        self.expire(event.instant);
+        let start_order = &mut self.start_order;
+        let start = *self.started.entry(tag).or_insert_with(|| {
+            start_order.push_back((event.instant, tag));
+            event.instant
+        });
+        let latency = event.instant.duration_since(start);
+        match pe {
+            PacketEvent::Process(ni) => self.nodes.entry(ni).or_default().record(latency),
+            PacketEvent::ReachedReader => self.end_to_end.record(latency),
+            PacketEvent::ExitInputChannel | PacketEvent::Handle | PacketEvent::Merged(_) => {}
+        }
+    }
+
+    /// Forget when the packet with the given trace tag started, once no more of its events are
+    /// expected.
+    pub fn finish(&mut self, tag: u64) {
+        self.started.remove(&tag);
+    }
+
+    /// Forget the start times of packets that started at least `UNFINISHED_TIMEOUT_SECS` before
+    /// `now`.
+    pub fn expire(&mut self, now: time::Instant) {
+        let timeout = time::Duration::from_secs(UNFINISHED_TIMEOUT_SECS);
+        while let Some(&(start, tag)) = self.start_order.front() {
+            if now < start + timeout {
+                break;
+            }
+            self.start_order.pop_front();
+            // the tag may have finished and then been reused since
+            if self.started.get(&tag) == Some(&start) {
+                self.started.remove(&tag);
+            }
+        }
+    }
+
+    /// Number of packets that are still waiting for further events.
+    pub fn unfinished(&self) -> usize {
+        self.started.len()
+    }
+
+    /// Summarize all latencies recorded so far.
+    pub fn stats(&self) -> LatencyStats {
+        LatencyStats {
+            end_to_end: self.end_to_end.percentiles(),
+            nodes: self
+                .nodes
+                .iter()
+                .map(|(&ni, h)| (ni, h.percentiles()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn event(instant: Instant, pe: PacketEvent, tag: u64) -> Event {
+        Event {
+            instant,
+            event: EventType::PacketEvent(pe, tag),
+        }
+    }
+
+    fn close(actual: u64, expected: u64) -> bool {
+        let diff = if actual > expected {
+            actual - expected
+        } else {
+            expected - actual
+        };
+        diff * 50 <= expected
+    }
+
+    #[test]
+    fn small_values_are_exact() {
+        let mut h = LatencyHistogram::default();
+        for i in 1..=100 {
+            h.record(Duration::new(0, i));
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.percentile(0.5), 50);
+        assert_eq!(h.percentile(0.99), 99);
+        assert_eq!(h.percentile(1.0), 100);
+        assert_eq!(LatencyHistogram::default().percentile(0.5), 0);
+    }
+
+    #[test]
+    fn percentiles_from_trace_events() {
+        let n = NodeIndex::new(3);
+        let t0 = Instant::now();
+        let mut c = LatencyCollector::default();
+        for tag in 1..=100u64 {
+            let ms = Duration::from_millis(tag);
+            c.record(&event(t0, PacketEvent::ExitInputChannel, tag));
+            c.record(&event(t0 + ms, PacketEvent::Process(n), tag));
+            c.record(&event(t0 + ms * 2, PacketEvent::ReachedReader, tag));
+            c.finish(tag);
+        }
+
+        let stats = c.stats();
+        let node = stats.nodes[&n];
+        assert_eq!(node.count, 100);
+        assert!(close(node.p50, 50_000_000), "{:?}", node);
+        assert!(close(node.p95, 95_000_000), "{:?}", node);
+        assert!(close(node.p99, 99_000_000), "{:?}", node);
+        assert_eq!(node.max, 100_000_000);
+
+        let e2e = stats.end_to_end;
+        assert_eq!(e2e.count, 100);
+        assert!(close(e2e.p50, 100_000_000), "{:?}", e2e);
+        assert!(close(e2e.p95, 190_000_000), "{:?}", e2e);
+        assert!(close(e2e.p99, 198_000_000), "{:?}", e2e);
+        assert_eq!(c.unfinished(), 0);
+    }
+
+    #[test]
+    fn unfinished_packets_expire() {
+        let n = NodeIndex::new(3);
+        let t0 = Instant::now();
+        let mut c = LatencyCollector::default();
+        // these never reach a reader
+        for tag in 1..=10u64 {
+            c.record(&event(t0, PacketEvent::ExitInputChannel, tag));
+        }
+        assert_eq!(c.unfinished(), 10);
+
+        let later = t0 + Duration::from_secs(UNFINISHED_TIMEOUT_SECS);
+        c.record(&event(later, PacketEvent::ExitInputChannel, 11));
+        c.record(&event(later, PacketEvent::Process(n), 11));
+        assert_eq!(c.unfinished(), 1);
+
+        // an expired packet is measured from its next event on
+        c.record(&event(later, PacketEvent::Process(n), 1));
+        assert_eq!(c.stats().nodes[&n].max, 0);
+        assert_eq!(c.unfinished(), 2);
+    }
+}
@@ -1,3 +1,12 @@
+/// Types related to graph consistency checks.
+pub mod invariants;
+
+/// Types related to packet latency distributions.
+pub mod latency;
+
+/// Types related to graph snapshots.
+pub mod snapshot;
+
 /// Types related to graph statistics.
 pub mod stats;
 
@@ -0,0 +1,58 @@
+use MaterializationStatus;
+
+/// A deterministic description of the structure of the Soup data-flow graph.
+///
+/// Nodes are listed in topological order, with ties broken by node index, so that the same
+/// sequence of migrations always produces the same snapshot. This makes snapshots suitable for
+/// golden-file tests of query planning.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphSnapshot {
+    /// The nodes of the graph.
+    pub nodes: Vec<NodeSnapshot>,
+}
+
+/// A single node in a `GraphSnapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    /// The node's index in the graph.
+    pub index: usize,
+    /// The node's name.
+    pub name: String,
+    /// The kind of node, or a description of its operator for internal nodes.
+    pub operator: String,
+    /// The node's columns.
+    pub columns: Vec<String>,
+    /// The domain the node has been assigned to, if any.
+    pub domain: Option<usize>,
+    /// How the node's output is sharded.
+    pub sharding: String,
+    /// The indices of the node's parents, in ascending order.
+    pub parents: Vec<usize>,
+}
+
+/// A detailed description of a single node, for tools that drill into one part of the graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeDescription {
+    /// The node's index in the graph.
+    pub index: usize,
+    /// The node's name.
+    pub name: String,
+    /// The kind of node, or a description of its operator for internal nodes.
+    pub operator: String,
+    /// The node's columns.
+    pub columns: Vec<String>,
+    /// The domain the node has been assigned to, if any.
+    pub domain: Option<usize>,
+    /// The number of shards the node is split into.
+    pub shards: usize,
+    /// How the node's output is sharded.
+    pub sharding: String,
+    /// Whether, and how, the node's state is materialized.
+    pub materialization: MaterializationStatus,
+    /// The indices of the node's parents, in ascending order.
+    pub parents: Vec<usize>,
+    /// The indices of the node's children, in ascending order.
+    pub children: Vec<usize>,
+    /// The node's label in the graphviz rendering of the graph.
+    pub graphviz: String,
+}
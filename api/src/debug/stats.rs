@@ -1,6 +1,8 @@
 use basics::*;
+use debug::latency::LatencyPercentiles;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use MaterializationStatus;
 
 type DomainMap = HashMap<(DomainIndex, usize), (DomainStats, HashMap<NodeIndex, NodeStats>)>;
@@ -16,6 +18,10 @@ pub struct DomainStats {
     pub total_ptime: u64,
     /// Total wall-clock time spent waiting for work in this domain.
     pub wait_time: u64,
+    /// Number of replay pieces this domain has handled.
+    pub replays: u64,
+    /// Latency of traced packets from entering this domain to reaching one of its readers.
+    pub reader_latency: LatencyPercentiles,
 }
 
 /// Statistics about a node.
@@ -31,8 +37,59 @@ pub struct NodeStats {
     pub process_ptime: u64,
     /// Total memory size of this node's state.
     pub mem_size: u64,
+    /// Number of rows in this node's state.
+    pub rows: u64,
     /// The materialization type of this node's state.
     pub materialized: MaterializationStatus,
+    /// When this node last processed a packet, in milliseconds since the UNIX epoch.
+    ///
+    /// This is `None` if the node has not processed any packets yet.
+    pub last_packet_processed: Option<u64>,
+    /// Latency of traced packets from entering this node's domain to being processed here.
+    pub latency: LatencyPercentiles,
+}
+
+/// The size of a node's state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeSize {
+    /// Total memory size of this node's state, summed across all shards.
+    pub mem_size: u64,
+    /// The materialization type of this node's state.
+    pub materialization_status: MaterializationStatus,
+}
+
+/// Resource usage attributed to a single query.
+///
+/// All times are in nanoseconds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryStats {
+    /// Total memory size of the state of the nodes that serve this query.
+    pub total_mem: u64,
+    /// Total wall-clock time elapsed while processing in the nodes that serve this query.
+    pub total_process_time: u64,
+}
+
+/// Progress of a full replay along a replay path.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayProgress {
+    /// Number of rows that have reached the end of the replay path so far.
+    pub replayed: u64,
+    /// Estimated number of rows to replay.
+    ///
+    /// This is the size of the replay source's state when the replay started. Since operators
+    /// along the path may filter or combine rows, `replayed` need not end up equal to `total`.
+    pub total: u64,
+    /// Whether the replay has completed.
+    pub done: bool,
+}
+
+/// The worker that a shard of a domain runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardAssignment {
+    /// The shard of the domain.
+    pub shard: usize,
+    /// The worker that the shard runs on.
+    pub worker: SocketAddr,
 }
 
 /// Statistics about the Soup data-flow.
@@ -44,6 +101,104 @@ pub struct GraphStats {
     pub domains: DomainMap,
 }
 
+impl GraphStats {
+    /// Render these statistics in the Prometheus text exposition format.
+    ///
+    /// Domain metrics are labeled with `domain` and `shard`, and node metrics additionally with
+    /// `node`. Samples are ordered by domain, shard, and node, so that the output is stable.
+    pub fn prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        let mut domains: Vec<_> = self.domains.iter().collect();
+        domains.sort_by_key(|&(&(di, shard), _)| (di.index(), shard));
+
+        let mut out = String::new();
+
+        let domain_metrics: [(&str, &str, &str, fn(&DomainStats) -> u64); 4] = [
+            (
+                "soup_domain_time_ns_total",
+                "counter",
+                "Wall-clock time spent processing in a domain shard, in nanoseconds.",
+                |s| s.total_time,
+            ),
+            (
+                "soup_domain_thread_time_ns_total",
+                "counter",
+                "Thread time spent processing in a domain shard, in nanoseconds.",
+                |s| s.total_ptime,
+            ),
+            (
+                "soup_domain_wait_time_ns_total",
+                "counter",
+                "Wall-clock time a domain shard spent waiting for work, in nanoseconds.",
+                |s| s.wait_time,
+            ),
+            (
+                "soup_domain_replays_total",
+                "counter",
+                "Replay pieces handled by a domain shard.",
+                |s| s.replays,
+            ),
+        ];
+        for &(name, kind, help, value) in &domain_metrics {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for &(&(di, shard), &(ref ds, _)) in &domains {
+                writeln!(
+                    out,
+                    "{}{{domain=\"{}\",shard=\"{}\"}} {}",
+                    name,
+                    di.index(),
+                    shard,
+                    value(ds)
+                ).unwrap();
+            }
+        }
+
+        let node_metrics: [(&str, &str, &str, fn(&NodeStats) -> u64); 3] = [
+            (
+                "soup_node_time_ns_total",
+                "counter",
+                "Wall-clock time spent processing in a node, in nanoseconds.",
+                |s| s.process_time,
+            ),
+            (
+                "soup_node_thread_time_ns_total",
+                "counter",
+                "Thread time spent processing in a node, in nanoseconds.",
+                |s| s.process_ptime,
+            ),
+            (
+                "soup_node_state_bytes",
+                "gauge",
+                "Memory size of a node's state, in bytes.",
+                |s| s.mem_size,
+            ),
+        ];
+        for &(name, kind, help, value) in &node_metrics {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for &(&(di, shard), &(_, ref nodes)) in &domains {
+                let mut nodes: Vec<_> = nodes.iter().collect();
+                nodes.sort_by_key(|&(ni, _)| ni.index());
+                for (ni, ns) in nodes {
+                    writeln!(
+                        out,
+                        "{}{{domain=\"{}\",shard=\"{}\",node=\"{}\"}} {}",
+                        name,
+                        di.index(),
+                        shard,
+                        ni.index(),
+                        value(ns)
+                    ).unwrap();
+                }
+            }
+        }
+
+        out
+    }
+}
+
 use std::ops::Deref;
 impl Deref for GraphStats {
     type Target = DomainMap;
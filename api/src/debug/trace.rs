@@ -1,3 +1,4 @@
+use basics::NodeIndex;
 use channel;
 use std::time;
 
@@ -11,8 +12,8 @@ pub enum PacketEvent {
     ExitInputChannel,
     /// The packet has been received by some domain, and is being handled.
     Handle,
-    /// The packet is being processed at some node.
-    Process,
+    /// The packet is being processed at the given node.
+    Process(NodeIndex),
     /// The packet has reached some reader node.
     ReachedReader,
     /// The packet has been merged with another, and will no longer trigger events.
@@ -98,7 +98,9 @@ extern crate assert_infrequent;
 extern crate basics;
 extern crate bincode;
 extern crate channel;
+extern crate chrono;
 extern crate consensus;
+extern crate csv;
 #[macro_use]
 extern crate failure;
 extern crate futures;
@@ -125,14 +127,18 @@ pub use consensus::{LocalAuthority, ZookeeperAuthority};
 /// The prelude contains most of the types needed in everyday operation.
 pub mod prelude {
     pub use super::ActivationResult;
+    pub use super::StatementResult;
     pub use super::ControllerHandle;
     pub use super::Table;
     pub use super::View;
 }
 
-pub use controller::{ControllerDescriptor, ControllerHandle, ControllerPointer};
-pub use table::{Input, Table, TableError};
-pub use view::{ReadQuery, ReadReply, View, ViewError};
+pub use controller::{ControllerDescriptor, ControllerHandle, ControllerPointer, LookupBuilderError};
+pub use table::{
+    CsvErrorPolicy, Input, LoadStats, Table, TableError, WriteId, WriteToken,
+    DEFAULT_LOAD_IN_FLIGHT, REJECTED_WRITE,
+};
+pub use view::{PendingLookup, ReadQuery, ReadReply, View, ViewError};
 
 #[doc(hidden)]
 pub mod builders {
@@ -161,10 +167,106 @@ pub struct ActivationResult {
     pub new_nodes: HashMap<String, NodeIndex>,
     /// List of leaf nodes that were removed.
     pub removed_leaves: Vec<NodeIndex>,
+    /// Dataflow nodes that were added to compute the added queries.
+    pub added_nodes: Vec<NodeIndex>,
+    /// Existing dataflow nodes that the added queries were built on instead of adding new nodes.
+    pub reused_nodes: Vec<NodeIndex>,
     /// Number of expressions the recipe added compared to the prior recipe.
     pub expressions_added: usize,
     /// Number of expressions the recipe removed compared to the prior recipe.
     pub expressions_removed: usize,
+    /// What each statement of the installed or added recipe text did, in the order the
+    /// statements appeared.
+    pub statements: Vec<StatementResult>,
+}
+
+/// Describes what a single recipe statement did when the recipe was activated.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum StatementResult {
+    /// The statement created the named base table.
+    BaseCreated(String),
+    /// The statement added the named query, along with new dataflow nodes to compute it.
+    QueryAdded(String),
+    /// The statement added a query called `name` that is served entirely by the existing query
+    /// `of`, without adding any dataflow nodes.
+    Reused {
+        /// The name the statement gave the query.
+        name: String,
+        /// The name of the existing query, or its text if it has no name.
+        of: String,
+    },
+    /// The statement was already part of the recipe, and changed nothing.
+    Unchanged,
+}
+
+/// The currently active recipe.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RecipeInfo {
+    /// The text of the recipe, in a form that can be installed again as-is.
+    pub text: String,
+    /// The version of the recipe. Every installed, extended, or rolled back recipe gets a new
+    /// version.
+    pub version: usize,
+}
+
+/// Describes the schema of a base table, so that clients can construct valid writes to it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct BaseSchema {
+    /// The name of the base table.
+    pub name: String,
+    /// The names of the table's current columns, in order.
+    pub columns: Vec<String>,
+    /// The SQL type of each of the current columns, if the table was created from SQL. Columns
+    /// added by a migration have no known type.
+    pub types: Vec<Option<String>>,
+    /// The names of the columns that make up the table's primary key. Empty if the table has no
+    /// primary key.
+    pub primary_key: Vec<String>,
+    /// The names of the columns that have been dropped from the table, along with the default
+    /// value that is filled in for them.
+    pub dropped: Vec<(String, DataType)>,
+}
+
+/// Describes a security universe that has been created on the controller.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct UniverseInfo {
+    /// The `id` in the universe's context.
+    pub id: DataType,
+    /// The group the universe belongs to, if it is a group universe rather than a user universe.
+    pub group: Option<String>,
+    /// For user universes, the ids of the instances of each security group the user is a member
+    /// of, keyed by group name.
+    pub groups: HashMap<String, Vec<DataType>>,
+}
+
+/// Describes a base table, as returned by `ControllerHandle::inputs_detailed`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct InputInfo {
+    /// The base table's node.
+    pub node: NodeIndex,
+    /// The names of the table's columns, in order, excluding columns that have been dropped.
+    pub columns: Vec<String>,
+    /// Whether a `Table` handle for the base table can currently be obtained.
+    pub ready: bool,
+}
+
+/// Describes an external view, as returned by `ControllerHandle::outputs_detailed`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct OutputInfo {
+    /// The node whose contents the view exposes; this is what `ControllerHandle::outputs` returns.
+    pub node: NodeIndex,
+    /// The reader node that holds the view's state.
+    pub reader: NodeIndex,
+    /// The names of the view's columns, in order.
+    pub columns: Vec<String>,
+    /// The names of the columns that lookups into the view are keyed by.
+    ///
+    /// This is empty until the view has been assigned a key.
+    pub key: Vec<String>,
+    /// Whether the reader keeps state that lookups can be answered from.
+    pub materialized: bool,
+    /// Whether a `View` handle for the view can currently be obtained.
+    pub ready: bool,
 }
 
 /// An error occured during transport (i.e., while sending or receiving).
@@ -176,6 +278,9 @@ pub enum TransportError {
     /// A protocol-level error occurred.
     #[fail(display = "{}", _0)]
     Serialization(#[cause] bincode::Error),
+    /// The receiving end rejected the write, because the node it was sent to has been removed.
+    #[fail(display = "write was rejected by a removed node")]
+    Rejected,
 }
 
 impl From<channel::tcp::SendError> for TransportError {
@@ -1,8 +1,12 @@
+use basics::data::SizeOf;
 use basics::*;
 use channel::{tcp, DomainConnectionBuilder, TcpSender};
 use debug::trace::Tracer;
-use nom_sql::CreateTableStatement;
+use chrono::{NaiveDate, NaiveDateTime};
+use csv;
+use nom_sql::{ColumnConstraint, CreateTableStatement, SqlType};
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
@@ -10,6 +14,61 @@ use std::rc::Rc;
 use vec_map::VecMap;
 use {ExclusiveConnection, SharedConnection, TransportError};
 
+/// Number of rows sent to the base table in each batch by `Table::load_from`.
+const LOAD_BATCH_ROWS: usize = 1024;
+
+/// Default bound on the number of unacknowledged bytes `Table::load_from` keeps in flight.
+pub const DEFAULT_LOAD_IN_FLIGHT: usize = 16 * 1024 * 1024;
+
+/// Summary of a bulk load performed by `Table::load_from`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadStats {
+    /// Number of rows loaded.
+    pub rows: usize,
+    /// Number of batches the rows were sent in.
+    pub batches: usize,
+    /// The largest number of bytes that were sent but not yet acknowledged at any one time.
+    pub max_in_flight: usize,
+    /// Number of rows that were skipped because they could not be parsed.
+    pub skipped: usize,
+}
+
+/// What `Table::load_csv` should do with a row that cannot be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsvErrorPolicy {
+    /// Leave the row out, and continue loading.
+    Skip,
+    /// Stop loading, and return the error.
+    Abort,
+}
+
+/// Acknowledgement that a base domain sends in place of a write sequence number when the base the
+/// write was addressed to has been removed. Sequence numbers start at 1, so this is never the
+/// sequence number of an actual write.
+#[doc(hidden)]
+pub const REJECTED_WRITE: u64 = 0;
+
+/// Identifies a single write to one shard of a base table.
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WriteId {
+    /// The base table that was written to.
+    pub base: NodeIndex,
+    /// The shard of the base table that was written to.
+    pub shard: usize,
+    /// The position of the write among all writes to that shard of the base table.
+    pub seq: u64,
+}
+
+/// Identifies the writes made to a base table by a `Table` operation.
+///
+/// Pass it to `View::lookup_at_least` to read a view only once it reflects those writes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteToken {
+    #[doc(hidden)]
+    pub writes: Vec<WriteId>,
+}
+
 #[doc(hidden)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Input {
@@ -35,6 +94,26 @@ pub enum TableError {
         _1
     )]
     WrongKeyColumnCount(usize, usize),
+    /// An inserted row omitted a column that has no default value and may not be `NULL`.
+    #[fail(display = "column {} has no default value and must be given", _0)]
+    MissingColumn(String),
+    /// A row was given by column name, and named a column the base table does not have.
+    #[fail(display = "table has no column {}", _0)]
+    UnknownColumn(String),
+    /// A keyed operation was attempted on a base table that has no primary key.
+    #[fail(display = "table {} has no primary key", _0)]
+    NoPrimaryKey(String),
+    /// The base table this handle writes to has been removed, or replaced during recovery.
+    ///
+    /// The handle will not work again; a new one must be obtained from the controller.
+    #[fail(
+        display = "table {} no longer exists; get a new handle from the controller",
+        _0
+    )]
+    StaleHandle(String),
+    /// A CSV row could not be parsed into the base table's column types.
+    #[fail(display = "could not parse CSV row {}: {}", _0, _1)]
+    BadCsvRow(usize, String),
     /// The underlying connection to Soup produced an error.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] TransportError),
@@ -50,6 +129,7 @@ impl From<TransportError> for TableError {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TableBuilder {
     pub txs: Vec<SocketAddr>,
+    pub node: NodeIndex,
     pub addr: LocalNodeIndex,
     pub key_is_primary: bool,
     pub key: Vec<usize>,
@@ -60,6 +140,8 @@ pub struct TableBuilder {
     pub schema: Option<CreateTableStatement>,
 
     pub local_port: Option<u16>,
+    /// How keys are mapped to shards.
+    pub shard_hasher: ShardHasher,
 }
 
 impl TableBuilder {
@@ -78,7 +160,7 @@ impl TableBuilder {
         let dih = match rpcs.entry(self.txs.clone()) {
             Entry::Occupied(e) => Rc::clone(e.get()),
             Entry::Vacant(h) => {
-                let c = DomainInputHandle::new_on(self.local_port, h.key())?;
+                let c = DomainInputHandle::new_on(self.local_port, h.key(), self.shard_hasher)?;
                 let c = Rc::new(RefCell::new(c));
                 h.insert(Rc::clone(&c));
                 c
@@ -88,6 +170,7 @@ impl TableBuilder {
         Ok(Table {
             domain_input_handle: dih,
             shard_addrs: self.txs,
+            node: self.node,
             addr: self.addr,
             key: self.key,
             key_is_primary: self.key_is_primary,
@@ -96,6 +179,8 @@ impl TableBuilder {
             table_name: self.table_name,
             columns: self.columns,
             schema: self.schema,
+            shard_hasher: self.shard_hasher,
+            written: VecMap::new(),
             exclusivity: SharedConnection,
         })
     }
@@ -110,6 +195,7 @@ impl TableBuilder {
 pub struct Table<E = SharedConnection> {
     domain_input_handle: TableRpc,
     shard_addrs: Vec<SocketAddr>,
+    node: NodeIndex,
     addr: LocalNodeIndex,
     key_is_primary: bool,
     key: Vec<usize>,
@@ -118,6 +204,9 @@ pub struct Table<E = SharedConnection> {
     table_name: String,
     columns: Vec<String>,
     schema: Option<CreateTableStatement>,
+    shard_hasher: ShardHasher,
+    /// The sequence number of the latest acknowledged write to each shard through this handle.
+    written: VecMap<u64>,
 
     #[allow(dead_code)]
     exclusivity: E,
@@ -128,6 +217,7 @@ impl Clone for Table<SharedConnection> {
         Table {
             domain_input_handle: self.domain_input_handle.clone(),
             shard_addrs: self.shard_addrs.clone(),
+            node: self.node,
             addr: self.addr,
             key_is_primary: self.key_is_primary,
             key: self.key.clone(),
@@ -136,6 +226,8 @@ impl Clone for Table<SharedConnection> {
             table_name: self.table_name.clone(),
             columns: self.columns.clone(),
             schema: self.schema.clone(),
+            shard_hasher: self.shard_hasher,
+            written: self.written.clone(),
             exclusivity: SharedConnection,
         }
     }
@@ -146,12 +238,13 @@ unsafe impl Send for Table<ExclusiveConnection> {}
 impl Table<SharedConnection> {
     /// Produce a `Table` with dedicated Soup connections so it can be safely sent across threads.
     pub fn into_exclusive(self) -> io::Result<Table<ExclusiveConnection>> {
-        let c = DomainInputHandle::new(&self.shard_addrs[..])?;
+        let c = DomainInputHandle::new(&self.shard_addrs[..], self.shard_hasher)?;
         let c = Rc::new(RefCell::new(c));
 
         Ok(Table {
             domain_input_handle: c,
             shard_addrs: self.shard_addrs,
+            node: self.node,
             addr: self.addr,
             key_is_primary: self.key_is_primary,
             key: self.key.clone(),
@@ -160,6 +253,8 @@ impl Table<SharedConnection> {
             table_name: self.table_name.clone(),
             columns: self.columns.clone(),
             schema: self.schema.clone(),
+            shard_hasher: self.shard_hasher,
+            written: self.written.clone(),
             exclusivity: ExclusiveConnection,
         })
     }
@@ -192,6 +287,67 @@ impl<E> Table<E> {
         self.domain_input_handle.borrow().local_addr()
     }
 
+    /// Check that `row` can be inserted into this base table.
+    ///
+    /// Trailing columns may be left out of the row, in which case the base table fills in their
+    /// `DEFAULT` value (or `NULL` if they have none). Columns that are declared `NOT NULL` without
+    /// a default, and columns the table is keyed by, must always be given. Partial rows are only
+    /// accepted for tables created from SQL that have not had any columns dropped.
+    fn check_row(&self, row: &[DataType]) -> Result<(), TableError> {
+        if row.len() == self.columns.len() {
+            return Ok(());
+        }
+        let schema = match self.schema {
+            Some(ref schema) if row.len() < self.columns.len() && self.dropped.is_empty() => schema,
+            _ => return Err(TableError::WrongColumnCount(self.columns.len(), row.len())),
+        };
+
+        for (i, name) in self.columns.iter().enumerate().skip(row.len()) {
+            let spec = match schema.fields.iter().find(|cs| cs.column.name == *name) {
+                Some(spec) => spec,
+                None => return Err(TableError::WrongColumnCount(self.columns.len(), row.len())),
+            };
+            let has_default = spec.constraints.iter().any(|c| match *c {
+                ColumnConstraint::DefaultValue(_) => true,
+                _ => false,
+            });
+            let not_null = spec.constraints.iter().any(|c| match *c {
+                ColumnConstraint::NotNull | ColumnConstraint::PrimaryKey => true,
+                _ => false,
+            });
+            if self.key.contains(&i) || (not_null && !has_default) {
+                return Err(TableError::MissingColumn(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an inserted row may leave out column `i`, in which case the base table gives it
+    /// its default value.
+    fn may_omit(&self, i: usize) -> bool {
+        let schema = match self.schema {
+            Some(ref schema) if self.dropped.is_empty() => schema,
+            _ => return false,
+        };
+        let spec = match schema.fields.iter().find(|cs| cs.column.name == self.columns[i]) {
+            Some(spec) => spec,
+            None => return false,
+        };
+        if self.key.contains(&i) {
+            return false;
+        }
+
+        let mut not_null = false;
+        for c in &spec.constraints {
+            match *c {
+                ColumnConstraint::DefaultValue(_) => return true,
+                ColumnConstraint::NotNull | ColumnConstraint::PrimaryKey => not_null = true,
+                _ => {}
+            }
+        }
+        !not_null
+    }
+
     fn inject_dropped_cols(&self, rs: &mut [TableOperation]) {
         let ndropped = self.dropped.len();
         if ndropped != 0 {
@@ -273,16 +429,42 @@ impl<E> Table<E> {
         }
     }
 
-    fn send(&mut self, ops: Vec<TableOperation>) -> Result<(), TransportError> {
+    fn token(&self, acked: Vec<(usize, u64)>) -> WriteToken {
+        WriteToken {
+            writes: acked
+                .into_iter()
+                .map(|(shard, seq)| WriteId {
+                    base: self.node,
+                    shard,
+                    seq,
+                }).collect(),
+        }
+    }
+
+    /// Turn an error from sending writes to this base table into a `TableError`.
+    fn write_error(&self, e: TransportError) -> TableError {
+        match e {
+            TransportError::Rejected => TableError::StaleHandle(self.table_name.clone()),
+            e => TableError::from(e),
+        }
+    }
+
+    fn send(&mut self, ops: Vec<TableOperation>) -> Result<Vec<(usize, u64)>, TableError> {
         let tracer = self.tracer.take();
         let m = self.prep_records(tracer, ops);
-        self.domain_input_handle
+        let acked = self
+            .domain_input_handle
             .borrow_mut()
             .base_send(m, &self.key[..])
+            .map_err(|e| self.write_error(e))?;
+        self.written.extend(acked.iter().cloned());
+        Ok(acked)
     }
 
     /// Perform multiple operations on this base table in one batch.
-    pub fn batch_insert<I, V>(&mut self, i: I) -> Result<(), TableError>
+    ///
+    /// The returned token identifies the resulting writes; see `View::lookup_at_least`.
+    pub fn batch_insert<I, V>(&mut self, i: I) -> Result<WriteToken, TableError>
     where
         I: IntoIterator<Item = V>,
         V: Into<TableOperation>,
@@ -294,9 +476,7 @@ impl<E> Table<E> {
             let data = vec![row.into()];
 
             if let Some(cols) = data[0].row() {
-                if cols.len() != self.columns.len() {
-                    return Err(TableError::WrongColumnCount(self.columns.len(), cols.len()));
-                }
+                self.check_row(cols)?;
             }
 
             let tracer = self.tracer.clone();
@@ -305,28 +485,79 @@ impl<E> Table<E> {
         }
 
         self.tracer.take();
-        batch_putter.wait()?;
-        Ok(())
+        let acked = batch_putter.wait().map_err(|e| self.write_error(e))?;
+        self.written.extend(acked.iter().cloned());
+        Ok(self.token(acked))
     }
 
     /// Insert a single row of data into this base table.
-    pub fn insert<V>(&mut self, u: V) -> Result<(), TableError>
+    ///
+    /// Trailing columns that have a `DEFAULT` value, or that may be `NULL`, can be left out of the
+    /// row; the base table fills them in. `CURRENT_TIMESTAMP` defaults are given the time at which
+    /// the base table receives the row.
+    ///
+    /// The returned token identifies the resulting write; see `View::lookup_at_least`.
+    pub fn insert<V>(&mut self, u: V) -> Result<WriteToken, TableError>
     where
         V: Into<Vec<DataType>>,
     {
         let data = vec![TableOperation::Insert(u.into())];
-        if data[0].row().unwrap().len() != self.columns.len() {
-            return Err(TableError::WrongColumnCount(
-                self.columns.len(),
-                data[0].row().unwrap().len(),
-            ));
+        self.check_row(data[0].row().unwrap())?;
+
+        let acked = self.send(data)?;
+        Ok(self.token(acked))
+    }
+
+    /// Insert a single row, given as a value for each named column, into this base table.
+    ///
+    /// The values are placed at the positions of their columns in `Table::columns`. Columns that
+    /// are not named are given their `DEFAULT` value, or `NULL` if they have none, just like
+    /// trailing columns left out of a row passed to `Table::insert`. As for those, the defaults
+    /// are filled in by the base table, so `CURRENT_TIMESTAMP` defaults are the time at which the
+    /// base receives the row. Columns the table is keyed by and columns declared `NOT NULL`
+    /// without a default must be named.
+    pub fn insert_named(
+        &mut self,
+        mut row: HashMap<&str, DataType>,
+    ) -> Result<WriteToken, TableError> {
+        if let Some(name) = row.keys().find(|&&n| !self.columns.iter().any(|c| c == n)) {
+            return Err(TableError::UnknownColumn(name.to_string()));
         }
 
-        self.send(data)?;
-        Ok(())
+        let len = self
+            .columns
+            .iter()
+            .rposition(|c| row.contains_key(&c[..]))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let mut values = Vec::with_capacity(len);
+        let mut omitted = Vec::new();
+        for (i, name) in self.columns[..len].iter().enumerate() {
+            match row.remove(&name[..]) {
+                Some(v) => values.push(v),
+                None if self.may_omit(i) => {
+                    // the base replaces this placeholder with the column's default
+                    values.push(DataType::None);
+                    omitted.push(i);
+                }
+                None => return Err(TableError::MissingColumn(name.clone())),
+            }
+        }
+        if omitted.is_empty() {
+            return self.insert(values);
+        }
+
+        self.check_row(&values)?;
+        let acked = self.send(vec![TableOperation::InsertWithDefaults {
+            row: values,
+            omitted,
+        }])?;
+        Ok(self.token(acked))
     }
 
     /// Insert multiple rows of data into this base table.
+    ///
+    /// As with `Table::insert`, rows may leave out trailing columns that have default values.
     pub fn insert_all<I, V>(&mut self, i: I) -> Result<(), TableError>
     where
         I: IntoIterator<Item = V>,
@@ -335,9 +566,7 @@ impl<E> Table<E> {
         i.into_iter()
             .map(|r| {
                 let row = r.into();
-                if row.len() != self.columns.len() {
-                    return Err(TableError::WrongColumnCount(self.columns.len(), row.len()));
-                }
+                self.check_row(&row)?;
                 Ok(TableOperation::Insert(row))
             }).collect::<Result<Vec<_>, _>>()
             .and_then(|data| {
@@ -346,6 +575,173 @@ impl<E> Table<E> {
             }).map(|_| ())
     }
 
+    /// Wait until the base table has acknowledged every write made through this handle.
+    ///
+    /// A base table acknowledges writes only once it has processed them, and with
+    /// `DurabilityMode::Permanent` that includes syncing them to its log on disk, so once this
+    /// returns the writes survive a restart. The write methods already wait for the writes they
+    /// send, but a method that fails part-way through, such as `Table::batch_insert` given an
+    /// invalid row, may return before the rows it already sent are acknowledged.
+    ///
+    /// The writes may not have reached views yet when this returns. Pass the token from
+    /// `Table::write_token` to `View::lookup_at_least` to wait for that.
+    pub fn flush(&mut self) -> Result<(), TableError> {
+        let acked = self
+            .domain_input_handle
+            .borrow_mut()
+            .sender()
+            .wait()
+            .map_err(|e| self.write_error(e))?;
+        self.written.extend(acked);
+        Ok(())
+    }
+
+    /// Get a token that identifies every acknowledged write made through this handle so far.
+    ///
+    /// Pass it to `View::lookup_at_least` to wait for those writes to reach a view, also after
+    /// writes such as `Table::insert_all` that do not return a token themselves.
+    pub fn write_token(&self) -> WriteToken {
+        self.token(self.written.iter().map(|(shard, &seq)| (shard, seq)).collect())
+    }
+
+    /// Load all the rows produced by `iter` into this base table, while bounding the amount of
+    /// data that has been sent but not yet acknowledged.
+    ///
+    /// Rows are sent in batches, and once `DEFAULT_LOAD_IN_FLIGHT` bytes are awaiting
+    /// acknowledgement from the base domain, this waits for those batches to be acknowledged
+    /// before sending more. This keeps memory use bounded when prepopulating large tables. Rows may
+    /// leave out trailing columns with default values, as with `Table::insert`. If a row is not
+    /// valid for the table, the batches before it have already been loaded when this returns.
+    pub fn load_from<I>(&mut self, iter: I) -> Result<LoadStats, TableError>
+    where
+        I: IntoIterator<Item = Vec<DataType>>,
+    {
+        self.load_from_bounded(iter, DEFAULT_LOAD_IN_FLIGHT)
+    }
+
+    /// Like `Table::load_from`, but keeps at most `max_in_flight` unacknowledged bytes in flight.
+    ///
+    /// A single batch larger than `max_in_flight` is still sent, but only once all earlier
+    /// batches have been acknowledged.
+    pub fn load_from_bounded<I>(
+        &mut self,
+        iter: I,
+        max_in_flight: usize,
+    ) -> Result<LoadStats, TableError>
+    where
+        I: IntoIterator<Item = Vec<DataType>>,
+    {
+        let mut stats = LoadStats::default();
+        let mut rows = iter.into_iter();
+
+        let mut dih = self.domain_input_handle.borrow_mut();
+        let mut batch_putter = dih.sender();
+        let mut in_flight = 0;
+        loop {
+            let mut bytes = 0;
+            let batch = rows
+                .by_ref()
+                .take(LOAD_BATCH_ROWS)
+                .map(|row| {
+                    self.check_row(&row)?;
+                    bytes += row.deep_size_of() as usize;
+                    Ok(TableOperation::Insert(row))
+                }).collect::<Result<Vec<_>, _>>();
+            let batch = match batch {
+                Ok(batch) => batch,
+                Err(e) => {
+                    // collect the acknowledgements for the batches we have already sent, so that
+                    // they are not mistaken for replies to later writes through this handle.
+                    self.tracer.take();
+                    let acked = batch_putter.wait().map_err(|e| self.write_error(e))?;
+                    self.written.extend(acked);
+                    return Err(e);
+                }
+            };
+            if batch.is_empty() {
+                break;
+            }
+
+            let nrows = batch.len();
+            if in_flight != 0 && in_flight + bytes > max_in_flight {
+                // wait for the base domain to catch up before sending more
+                batch_putter.flush().map_err(|e| self.write_error(e))?;
+                in_flight = 0;
+            }
+
+            let tracer = self.tracer.clone();
+            let m = self.prep_records(tracer, batch);
+            batch_putter.enqueue(m, &self.key[..])?;
+
+            in_flight += bytes;
+            stats.rows += nrows;
+            stats.batches += 1;
+            stats.max_in_flight = cmp::max(stats.max_in_flight, in_flight);
+        }
+
+        self.tracer.take();
+        let acked = batch_putter.wait().map_err(|e| self.write_error(e))?;
+        self.written.extend(acked);
+        Ok(stats)
+    }
+
+    /// Load rows of CSV data from `reader` into this base table.
+    ///
+    /// Each field is parsed according to the corresponding type in `schema`, which usually comes
+    /// from the table's `Table::schema`, and empty fields become `NULL`. The CSV data should not
+    /// have a header row. Rows that cannot be parsed are either skipped or abort the load,
+    /// depending on `on_error`; in the latter case, the rows before the bad one may already have
+    /// been loaded. The rows are fed through `Table::load_from`, so the load is backpressured.
+    pub fn load_csv<R>(
+        &mut self,
+        reader: R,
+        schema: &[SqlType],
+        on_error: CsvErrorPolicy,
+    ) -> Result<LoadStats, TableError>
+    where
+        R: io::Read,
+    {
+        if schema.len() != self.columns.len() {
+            return Err(TableError::WrongColumnCount(self.columns.len(), schema.len()));
+        }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+
+        let mut error = None;
+        let mut skipped = 0;
+        let mut stats = {
+            let rows = reader
+                .records()
+                .enumerate()
+                .scan((), |_, (i, record)| {
+                    let row = record
+                        .map_err(|e| e.to_string())
+                        .and_then(|record| parse_csv_row(&record, schema));
+                    match row {
+                        Ok(row) => Some(Some(row)),
+                        Err(_) if on_error == CsvErrorPolicy::Skip => {
+                            skipped += 1;
+                            Some(None)
+                        }
+                        Err(e) => {
+                            error = Some(TableError::BadCsvRow(i + 1, e));
+                            None
+                        }
+                    }
+                }).filter_map(|row| row);
+            self.load_from(rows)?
+        };
+
+        if let Some(e) = error {
+            return Err(e);
+        }
+        stats.skipped = skipped;
+        Ok(stats)
+    }
+
     /// Delete the row with the given key from this base table.
     pub fn delete<I>(&mut self, key: I) -> Result<(), TableError>
     where
@@ -355,18 +751,44 @@ impl<E> Table<E> {
         Ok(())
     }
 
+    /// Delete all rows in this base table that match the given predicate.
+    ///
+    /// A row matches if, for every pair `(i, v)` in `predicate`, column `i` of the row is equal to
+    /// `v`. The matching rows are found using an index on exactly the predicate columns if the
+    /// base table has one; otherwise, this falls back to a scan of all the rows in the base table.
+    /// Fails with `TableError::NoPrimaryKey` if the base table does not have a primary key.
+    pub fn delete_where(&mut self, predicate: Vec<(usize, DataType)>) -> Result<(), TableError> {
+        if self.key.is_empty() || !self.key_is_primary {
+            return Err(TableError::NoPrimaryKey(self.table_name.clone()));
+        }
+
+        for &(coli, _) in &predicate {
+            if coli >= self.columns.len() {
+                return Err(TableError::WrongColumnCount(self.columns.len(), coli + 1));
+            }
+        }
+
+        self.send(vec![TableOperation::DeleteWhere { predicate }])?;
+        Ok(())
+    }
+
     /// Update the row with the given key in this base table.
     ///
     /// `u` is a set of column-modification pairs, where for each pair `(i, m)`, the modification
-    /// `m` will be applied to column `i` of the record with key `key`.
-    pub fn update<V>(&mut self, key: Vec<DataType>, u: V) -> Result<(), TableError>
+    /// `m` will be applied to column `i` of the record with key `key`. A plain `DataType` can be
+    /// given in place of `m`, in which case column `i` is set to that value.
+    ///
+    /// The old and the new version of the row are emitted together, so downstream operators never
+    /// observe the row as missing. Fails with `TableError::NoPrimaryKey` if the base table does not
+    /// have a primary key.
+    pub fn update<V, M>(&mut self, key: Vec<DataType>, u: V) -> Result<(), TableError>
     where
-        V: IntoIterator<Item = (usize, Modification)>,
+        V: IntoIterator<Item = (usize, M)>,
+        M: Into<Modification>,
     {
-        assert!(
-            !self.key.is_empty() && self.key_is_primary,
-            "update operations can only be applied to base nodes with key columns"
-        );
+        if self.key.is_empty() || !self.key_is_primary {
+            return Err(TableError::NoPrimaryKey(self.table_name.clone()));
+        }
 
         if key.len() != self.key.len() {
             return Err(TableError::WrongKeyColumnCount(self.key.len(), key.len()));
@@ -377,7 +799,7 @@ impl<E> Table<E> {
             if coli >= self.columns.len() {
                 return Err(TableError::WrongColumnCount(self.columns.len(), coli + 1));
             }
-            set[coli] = m;
+            set[coli] = m.into();
         }
         self.send(vec![TableOperation::Update { key, set }])?;
         Ok(())
@@ -422,6 +844,25 @@ impl<E> Table<E> {
         Ok(())
     }
 
+    /// Insert `row` into this base table, replacing any existing row with the same primary key.
+    ///
+    /// Unlike `Table::insert_or_update`, the caller supplies the entire new row. If a row with
+    /// the same key already exists, its removal and the insertion of `row` are emitted together.
+    /// Fails with `TableError::NoPrimaryKey` if the base table does not have a primary key.
+    pub fn upsert(&mut self, row: Vec<DataType>) -> Result<(), TableError> {
+        if self.key.is_empty() || !self.key_is_primary {
+            return Err(TableError::NoPrimaryKey(self.table_name.clone()));
+        }
+
+        if row.len() != self.columns.len() {
+            return Err(TableError::WrongColumnCount(self.columns.len(), row.len()));
+        }
+
+        let update = row.iter().cloned().map(Modification::Set).collect();
+        self.send(vec![TableOperation::InsertOrUpdate { row, update }])?;
+        Ok(())
+    }
+
     /// Trace the next modification to this base table.
     ///
     /// When an input is traced, events are triggered as it flows through the dataflow, and are
@@ -435,14 +876,59 @@ impl<E> Table<E> {
     }
 }
 
+fn parse_csv_row(record: &csv::StringRecord, schema: &[SqlType]) -> Result<Vec<DataType>, String> {
+    if record.len() != schema.len() {
+        return Err(format!("expected {} fields, got {}", schema.len(), record.len()));
+    }
+
+    record
+        .iter()
+        .zip(schema)
+        .map(|(field, ty)| parse_csv_field(field, ty))
+        .collect()
+}
+
+fn parse_csv_field(field: &str, ty: &SqlType) -> Result<DataType, String> {
+    if field.is_empty() {
+        return Ok(DataType::None);
+    }
+
+    match *ty {
+        SqlType::Int(_) | SqlType::Bigint(_) | SqlType::Tinyint(_) => field
+            .trim()
+            .parse::<i64>()
+            .map(DataType::from)
+            .map_err(|e| format!("{:?} is not an integer: {}", field, e)),
+        SqlType::Double | SqlType::Float | SqlType::Real => match field.trim().parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(DataType::from(f)),
+            Ok(_) => Err(format!("{:?} is not a finite number", field)),
+            Err(e) => Err(format!("{:?} is not a number: {}", field, e)),
+        },
+        SqlType::Timestamp => NaiveDateTime::parse_from_str(field.trim(), "%Y-%m-%d %H:%M:%S")
+            .map(DataType::Timestamp)
+            .map_err(|e| format!("{:?} is not a timestamp: {}", field, e)),
+        SqlType::Date => NaiveDate::parse_from_str(field.trim(), "%Y-%m-%d")
+            .map(|d| DataType::Timestamp(d.and_hms(0, 0, 0)))
+            .map_err(|e| format!("{:?} is not a date: {}", field, e)),
+        _ => Ok(DataType::from(field)),
+    }
+}
+
 pub(crate) struct DomainInputHandle {
     txs: Vec<TcpSender<Input>>,
+    shard_hasher: ShardHasher,
+    /// The number of inputs sent to each shard whose acknowledgements have not been read yet.
+    unacked: Vec<usize>,
 }
 
 pub(crate) type TableRpc = Rc<RefCell<DomainInputHandle>>;
 
 impl DomainInputHandle {
-    pub(crate) fn new_on(mut local_port: Option<u16>, txs: &[SocketAddr]) -> io::Result<Self> {
+    pub(crate) fn new_on(
+        mut local_port: Option<u16>,
+        txs: &[SocketAddr],
+        shard_hasher: ShardHasher,
+    ) -> io::Result<Self> {
         let txs: io::Result<Vec<_>> = txs
             .into_iter()
             .map(|addr| {
@@ -455,11 +941,17 @@ impl DomainInputHandle {
                 Ok(c)
             }).collect();
 
-        Ok(Self { txs: txs? })
+        let txs = txs?;
+        let unacked = vec![0; txs.len()];
+        Ok(Self {
+            txs,
+            shard_hasher,
+            unacked,
+        })
     }
 
-    pub(crate) fn new(txs: &[SocketAddr]) -> Result<Self, io::Error> {
-        Self::new_on(None, txs)
+    pub(crate) fn new(txs: &[SocketAddr], shard_hasher: ShardHasher) -> Result<Self, io::Error> {
+        Self::new_on(None, txs, shard_hasher)
     }
 
     pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -470,30 +962,38 @@ impl DomainInputHandle {
         BatchSendHandle::new(self)
     }
 
-    pub(crate) fn base_send(&mut self, i: Input, key: &[usize]) -> Result<(), TransportError> {
+    pub(crate) fn base_send(
+        &mut self,
+        i: Input,
+        key: &[usize],
+    ) -> Result<Vec<(usize, u64)>, TransportError> {
         let mut s = BatchSendHandle::new(self);
         s.enqueue(i, key)?;
-        s.wait().map_err(|_| {
-            tcp::SendError::IoError(io::Error::new(io::ErrorKind::Other, "write failed")).into()
+        s.wait().map_err(|e| match e {
+            TransportError::Rejected => e,
+            _ => {
+                tcp::SendError::IoError(io::Error::new(io::ErrorKind::Other, "write failed")).into()
+            }
         })
     }
 }
 
 pub(crate) struct BatchSendHandle<'a> {
     dih: &'a mut DomainInputHandle,
-    sent: Vec<usize>,
+    /// The sequence number of the latest acknowledged write to each shard, if any.
+    acked: Vec<Option<u64>>,
 }
 
 impl<'a> BatchSendHandle<'a> {
     pub(crate) fn new(dih: &'a mut DomainInputHandle) -> Self {
-        let sent = vec![0; dih.txs.len()];
-        Self { dih, sent }
+        let acked = vec![None; dih.txs.len()];
+        Self { dih, acked }
     }
 
     pub(crate) fn enqueue(&mut self, mut i: Input, key: &[usize]) -> Result<(), TransportError> {
         if self.dih.txs.len() == 1 {
             self.dih.txs[0].send(i)?;
-            self.sent[0] += 1;
+            self.dih.unacked[0] += 1;
         } else {
             if key.is_empty() {
                 unreachable!("sharded base without a key?");
@@ -506,14 +1006,24 @@ impl<'a> BatchSendHandle<'a> {
 
             let mut shard_writes = vec![Vec::new(); self.dih.txs.len()];
             for r in i.data.drain(..) {
+                if let TableOperation::DeleteWhere { .. } = r {
+                    // rows matching the predicate may live on any shard
+                    for writes in &mut shard_writes {
+                        writes.push(r.clone());
+                    }
+                    continue;
+                }
+
                 let shard = {
                     let key = match r {
-                        TableOperation::Insert(ref r) => &r[key_col],
+                        TableOperation::Insert(ref r)
+                        | TableOperation::InsertWithDefaults { row: ref r, .. } => &r[key_col],
                         TableOperation::Delete { ref key } => &key[0],
                         TableOperation::Update { ref key, .. } => &key[0],
                         TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
+                        TableOperation::DeleteWhere { .. } => unreachable!(),
                     };
-                    shard_by(key, self.dih.txs.len())
+                    self.dih.shard_hasher.shard(key, self.dih.txs.len())
                 };
                 shard_writes[shard].push(r);
             }
@@ -525,7 +1035,7 @@ impl<'a> BatchSendHandle<'a> {
                         tracer: i.tracer.clone(),
                         data: rs,
                     })?;
-                    self.sent[s] += 1;
+                    self.dih.unacked[s] += 1;
                 }
             }
         }
@@ -533,14 +1043,45 @@ impl<'a> BatchSendHandle<'a> {
         Ok(())
     }
 
-    pub(crate) fn wait(self) -> Result<(), TransportError> {
-        for (shard, n) in self.sent.into_iter().enumerate() {
-            for _ in 0..n {
+    /// Wait for all the inputs enqueued so far to be acknowledged.
+    ///
+    /// This includes inputs sent through earlier handles on the same connections whose
+    /// acknowledgements were never read, for example because the operation that sent them failed
+    /// part-way through.
+    ///
+    /// Fails with `TransportError::Rejected` if any of the inputs were rejected, but only once all
+    /// of them have been acknowledged.
+    pub(crate) fn flush(&mut self) -> Result<(), TransportError> {
+        let mut rejected = false;
+        for (shard, n) in self.dih.unacked.iter_mut().enumerate() {
+            while *n > 0 {
                 use bincode;
-                let _: bool = bincode::deserialize_from(&mut (&mut self.dih.txs[shard]).reader())?;
+                let seq: u64 =
+                    bincode::deserialize_from(&mut (&mut self.dih.txs[shard]).reader())?;
+                *n -= 1;
+                if seq == REJECTED_WRITE {
+                    rejected = true;
+                } else {
+                    self.acked[shard] = Some(seq);
+                }
             }
         }
 
+        if rejected {
+            return Err(TransportError::Rejected);
+        }
         Ok(())
     }
+
+    /// Wait for all enqueued inputs to be acknowledged, and return the sequence number of the
+    /// latest write to each shard that was written to.
+    pub(crate) fn wait(mut self) -> Result<Vec<(usize, u64)>, TransportError> {
+        self.flush()?;
+        Ok(self
+            .acked
+            .iter()
+            .enumerate()
+            .filter_map(|(shard, seq)| seq.map(|seq| (shard, seq)))
+            .collect())
+    }
 }
@@ -1,10 +1,12 @@
 use basics::*;
-use channel::rpc::RpcClient;
+use channel::rpc::{RpcClient, Ticket};
+use futures::{task, Async, Future, Poll};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use table::{WriteId, WriteToken};
 use {ExclusiveConnection, SharedConnection, TransportError};
 
 pub(crate) type ViewRpc = Rc<RefCell<RpcClient<ReadQuery, ReadReply>>>;
@@ -15,6 +17,21 @@ pub enum ViewError {
     /// The given view is not yet available.
     #[fail(display = "the view is not yet available")]
     NotYetAvailable,
+    /// The view did not come to reflect the writes a lookup had to wait for in time.
+    #[fail(display = "the view did not reflect the given writes in time")]
+    WritesNotSeen,
+    /// The wrong number of key columns was given when looking up a key.
+    #[fail(
+        display = "wrong number of key columns used: expected {}, got {}",
+        _0,
+        _1
+    )]
+    WrongKeyColumnCount(usize, usize),
+    /// The reader this handle reads from has been removed, or replaced during recovery.
+    ///
+    /// The handle will not work again; a new one must be obtained from the controller.
+    #[fail(display = "the view no longer exists; get a new handle from the controller")]
+    StaleHandle,
     /// A lower-level error occurred while communicating with Soup.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] TransportError),
@@ -38,6 +55,26 @@ pub enum ReadQuery {
         /// Whether to block if a partial replay is triggered
         block: bool,
     },
+    /// Read from a leaf view once it reflects the given writes
+    AtLeast {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Keys to read with
+        keys: Vec<Vec<DataType>>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+        /// Writes the read must reflect
+        writes: Vec<WriteId>,
+    },
+    /// Count the rows for each key in a leaf view
+    Count {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Keys to count the rows of
+        keys: Vec<Vec<DataType>>,
+        /// Whether to block if a partial replay is triggered
+        block: bool,
+    },
     /// Read the size of a leaf view
     Size {
         /// Where to read from
@@ -50,8 +87,14 @@ pub enum ReadQuery {
 pub enum ReadReply {
     /// Errors if view isn't ready yet.
     Normal(Result<Vec<Datas>, ()>),
+    /// Number of rows for each key. Errors if view isn't ready yet.
+    Count(Result<Vec<usize>, ()>),
     /// Read size of view
     Size(usize),
+    /// The target reader does not exist (any more).
+    Stale,
+    /// The target reader did not reflect the writes an `AtLeast` read had to wait for in time.
+    WritesNotSeen,
 }
 
 #[doc(hidden)]
@@ -59,9 +102,13 @@ pub enum ReadReply {
 pub struct ViewBuilder {
     pub node: NodeIndex,
     pub columns: Vec<String>,
+    /// The columns the view is keyed by, in the order key values are given in lookups.
+    pub key: Vec<usize>,
     pub shards: Vec<SocketAddr>,
     // one per shard
     pub local_ports: Vec<u16>,
+    /// How keys are mapped to shards.
+    pub shard_hasher: ShardHasher,
 }
 
 impl ViewBuilder {
@@ -76,8 +123,10 @@ impl ViewBuilder {
         Ok(View {
             node: self.node,
             columns: self.columns,
+            key: self.key,
             shard_addrs: self.shards,
             shards: conns,
+            shard_hasher: self.shard_hasher,
             exclusivity: ExclusiveConnection,
         })
     }
@@ -123,8 +172,10 @@ impl ViewBuilder {
         Ok(View {
             node: self.node,
             columns: self.columns,
+            key: self.key,
             shard_addrs: self.shards,
             shards: conns,
+            shard_hasher: self.shard_hasher,
             exclusivity: SharedConnection,
         })
     }
@@ -139,8 +190,10 @@ impl ViewBuilder {
 pub struct View<E = SharedConnection> {
     node: NodeIndex,
     columns: Vec<String>,
+    key: Vec<usize>,
     shards: Vec<ViewRpc>,
     shard_addrs: Vec<SocketAddr>,
+    shard_hasher: ShardHasher,
 
     #[allow(dead_code)]
     exclusivity: E,
@@ -151,8 +204,10 @@ impl Clone for View<SharedConnection> {
         View {
             node: self.node,
             columns: self.columns.clone(),
+            key: self.key.clone(),
             shards: self.shards.clone(),
             shard_addrs: self.shard_addrs.clone(),
+            shard_hasher: self.shard_hasher,
             exclusivity: SharedConnection,
         }
     }
@@ -168,7 +223,9 @@ impl View<SharedConnection> {
             node: self.node,
             local_ports: vec![],
             columns: self.columns,
+            key: self.key,
             shards: self.shard_addrs,
+            shard_hasher: self.shard_hasher,
         }.build_exclusive()
     }
 }
@@ -180,6 +237,12 @@ impl<E> View<E> {
         self.columns.as_slice()
     }
 
+    /// Get the names of the columns this view is keyed by, in the order that key values must be
+    /// given in lookups.
+    pub fn key_columns(&self) -> Vec<&str> {
+        self.key.iter().map(|&c| &*self.columns[c]).collect()
+    }
+
     /// Get the local address this `View` is bound to.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.shards[0].borrow().local_addr()
@@ -195,6 +258,7 @@ impl<E> View<E> {
                 }).map_err(TransportError::from)?;
             match reply {
                 ReadReply::Size(rows) => Ok(rows),
+                ReadReply::Stale => Err(ViewError::StaleHandle),
                 _ => unreachable!(),
             }
         } else {
@@ -210,6 +274,7 @@ impl<E> View<E> {
 
                     match reply {
                         ReadReply::Size(rows) => Ok(acc + rows),
+                        ReadReply::Stale => Err(ViewError::StaleHandle),
                         _ => unreachable!(),
                     }
                 })
@@ -219,12 +284,18 @@ impl<E> View<E> {
 
     /// Retrieve the query results for the given parameter values.
     ///
-    /// The method will block if the results are not yet available only when `block` is `true`.
+    /// The results for each key are returned in the same order as `keys`. If the view is
+    /// sharded, the keys are grouped by shard, and all the shards are queried in parallel. The
+    /// method will block if the results are not yet available only when `block` is `true`.
     pub fn multi_lookup(
         &mut self,
         keys: Vec<Vec<DataType>>,
         block: bool,
     ) -> Result<Vec<Datas>, ViewError> {
+        if let Some(key) = keys.iter().find(|k| k.len() != self.key.len()) {
+            return Err(ViewError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+
         if self.shards.len() == 1 {
             let mut shard = self.shards[0].borrow_mut();
             let reply = shard
@@ -236,41 +307,55 @@ impl<E> View<E> {
             match reply {
                 ReadReply::Normal(Ok(rows)) => Ok(rows),
                 ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+                ReadReply::Stale => Err(ViewError::StaleHandle),
                 _ => unreachable!(),
             }
         } else {
             assert!(keys.iter().all(|k| k.len() == 1));
+            let nkeys = keys.len();
             let mut shard_queries = vec![Vec::new(); self.shards.len()];
-            for key in keys {
-                let shard = shard_by(&key[0], self.shards.len());
+            // where in the input each key sent to a given shard came from
+            let mut shard_positions = vec![Vec::new(); self.shards.len()];
+            for (i, key) in keys.into_iter().enumerate() {
+                let shard = self.shard_hasher.shard(&key[0], self.shards.len());
                 shard_queries[shard].push(key);
+                shard_positions[shard].push(i);
             }
 
             let mut borrow_all: Vec<_> = self.shards.iter().map(|s| s.borrow_mut()).collect();
 
+            // issue the requests to all the shards before waiting for any of them, so that the
+            // shards process their lookups in parallel.
             let qs = borrow_all
                 .iter_mut()
                 .enumerate()
                 .zip(shard_queries.iter_mut())
-                .filter(|&(_, ref sq)| !sq.is_empty())
-                .map(|((shardi, shard), shard_queries)| {
+                .zip(shard_positions.into_iter())
+                .filter(|&((_, ref sq), _)| !sq.is_empty())
+                .map(|(((shardi, shard), shard_queries), positions)| {
                     use std::mem;
-                    Ok(shard
+                    let res = shard
                         .send_async(&ReadQuery::Normal {
                             target: (self.node, shardi),
                             keys: mem::replace(shard_queries, Vec::new()),
                             block,
-                        }).map_err(TransportError::from)?)
+                        }).map_err(TransportError::from)?;
+                    Ok((res, positions))
                 }).collect::<Result<Vec<_>, ViewError>>()?;
 
-            let mut results = Vec::new();
-            for res in qs {
+            // then put each shard's results back where their keys were in the input
+            let mut results = vec![Vec::new(); nkeys];
+            for (res, positions) in qs {
                 let reply = res.wait().map_err(TransportError::from)?;
                 match reply {
                     ReadReply::Normal(Ok(rows)) => {
-                        results.extend(rows);
+                        assert_eq!(rows.len(), positions.len());
+                        for (i, rows) in positions.into_iter().zip(rows) {
+                            results[i] = rows;
+                        }
                     }
                     ReadReply::Normal(Err(())) => return Err(ViewError::NotYetAvailable),
+                    ReadReply::Stale => return Err(ViewError::StaleHandle),
                     _ => unreachable!(),
                 }
             }
@@ -286,4 +371,175 @@ impl<E> View<E> {
         self.multi_lookup(vec![Vec::from(key)], block)
             .map(|rs| rs.into_iter().next().unwrap())
     }
+
+    /// Retrieve all the rows in a view that is not keyed by any parameter.
+    ///
+    /// This works for views maintained with an empty key, and for queries without parameters,
+    /// whose views are keyed by a constant `bogokey` column. Views keyed by any other columns
+    /// return `ViewError::WrongKeyColumnCount`. The method blocks until the view is available.
+    pub fn all(&mut self) -> Result<Datas, ViewError> {
+        if self.key.is_empty() {
+            self.lookup(&[], true)
+        } else if self.key_columns() == ["bogokey"] {
+            self.lookup(&[0.into()], true)
+        } else {
+            Err(ViewError::WrongKeyColumnCount(self.key.len(), 0))
+        }
+    }
+
+    /// Retrieve the query results for the given parameter value once they reflect the writes
+    /// identified by `token`.
+    ///
+    /// This waits until the view has seen the writes, which gives read-your-writes consistency
+    /// without any global synchronization. If the view does not reflect the writes within ten
+    /// seconds, this fails with `ViewError::WritesNotSeen`. That happens if the writes are not to a
+    /// base table that this view is computed from, or if the base table lost them because it was
+    /// restarted without durable storage. The method will block if the results are not yet
+    /// available only when `block` is `true`.
+    pub fn lookup_at_least(
+        &mut self,
+        key: &[DataType],
+        token: &WriteToken,
+        block: bool,
+    ) -> Result<Datas, ViewError> {
+        if key.len() != self.key.len() {
+            return Err(ViewError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+
+        let shardi = if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            self.shard_hasher.shard(&key[0], self.shards.len())
+        };
+
+        let mut shard = self.shards[shardi].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::AtLeast {
+                target: (self.node, shardi),
+                keys: vec![Vec::from(key)],
+                block,
+                writes: token.writes.clone(),
+            }).map_err(TransportError::from)?;
+        match reply {
+            ReadReply::Normal(Ok(rows)) => Ok(rows.into_iter().next().unwrap()),
+            ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+            ReadReply::Stale => Err(ViewError::StaleHandle),
+            ReadReply::WritesNotSeen => Err(ViewError::WritesNotSeen),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Retrieve the query results for the given parameter value without waiting for them.
+    ///
+    /// The lookup is sent immediately, and the returned future resolves to its results. Any number
+    /// of lookups can be in flight at once, even over connections shared with other `View`s.
+    /// Polling the future does not wait for the reply; it is `NotReady` until the reply arrives.
+    /// The lookup will block if the results are not yet available only when `block` is `true`.
+    pub fn lookup_async(
+        &mut self,
+        key: &[DataType],
+        block: bool,
+    ) -> Result<PendingLookup, ViewError> {
+        if key.len() != self.key.len() {
+            return Err(ViewError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+
+        let shardi = if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            self.shard_hasher.shard(&key[0], self.shards.len())
+        };
+
+        let ticket = self.shards[shardi]
+            .borrow_mut()
+            .send_pipelined(&ReadQuery::Normal {
+                target: (self.node, shardi),
+                keys: vec![Vec::from(key)],
+                block,
+            }).map_err(TransportError::from)?;
+        Ok(PendingLookup {
+            rpc: Rc::clone(&self.shards[shardi]),
+            ticket,
+            done: false,
+        })
+    }
+
+    /// Retrieve the number of query results for the given parameter value.
+    ///
+    /// Only the count is sent back by the reader, not the rows themselves. The method will block
+    /// if the results are not yet available only when `block` is `true`.
+    pub fn count(&mut self, key: &[DataType], block: bool) -> Result<usize, ViewError> {
+        if key.len() != self.key.len() {
+            return Err(ViewError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+
+        let shardi = if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            self.shard_hasher.shard(&key[0], self.shards.len())
+        };
+
+        let mut shard = self.shards[shardi].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::Count {
+                target: (self.node, shardi),
+                keys: vec![Vec::from(key)],
+                block,
+            }).map_err(TransportError::from)?;
+        match reply {
+            ReadReply::Count(Ok(counts)) => Ok(counts[0]),
+            ReadReply::Count(Err(())) => Err(ViewError::NotYetAvailable),
+            ReadReply::Stale => Err(ViewError::StaleHandle),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A lookup issued by `View::lookup_async` whose results have not been received yet.
+///
+/// If it is dropped before its results arrive, they are discarded when they do.
+pub struct PendingLookup {
+    rpc: ViewRpc,
+    ticket: Ticket,
+    done: bool,
+}
+
+impl Future for PendingLookup {
+    type Item = Datas;
+    type Error = ViewError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let reply = self
+            .rpc
+            .borrow_mut()
+            .try_recv(self.ticket)
+            .map_err(TransportError::from)?;
+        let reply = match reply {
+            Some(reply) => reply,
+            None => {
+                // the connection is not registered with any event loop that could wake us up
+                // once the reply arrives, so ask to be polled again instead.
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+        };
+        self.done = true;
+        match reply {
+            ReadReply::Normal(Ok(rows)) => Ok(Async::Ready(rows.into_iter().next().unwrap())),
+            ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
+            ReadReply::Stale => Err(ViewError::StaleHandle),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Drop for PendingLookup {
+    fn drop(&mut self) {
+        if !self.done {
+            self.rpc.borrow_mut().forget(self.ticket);
+        }
+    }
 }
@@ -2,7 +2,7 @@ use arccstr::ArcCStr;
 
 use chrono::{self, NaiveDateTime};
 
-use nom_sql::Literal;
+use nom_sql::{Literal, SqlType};
 
 use std::fmt;
 use std::hash::{Hash, Hasher};
@@ -73,6 +73,31 @@ impl DataType {
             ref dt => dt.clone(),
         }
     }
+
+    /// Evaluate the `DEFAULT` literal `dv` of a column as a value of the column's type `ty`.
+    ///
+    /// Defaults are often quoted (`int DEFAULT '10'`), but rows filled in with them should hold the
+    /// same values as rows that give the column explicitly.
+    pub fn column_default(dv: &Literal, ty: &SqlType) -> DataType {
+        match (dv, ty) {
+            (&Literal::String(ref s), &SqlType::Int(_))
+            | (&Literal::String(ref s), &SqlType::Bigint(_))
+            | (&Literal::String(ref s), &SqlType::Tinyint(_)) => match s.trim().parse::<i64>() {
+                Ok(i) => i.into(),
+                Err(_) => dv.into(),
+            },
+            (&Literal::String(ref s), &SqlType::Double)
+            | (&Literal::String(ref s), &SqlType::Float)
+            | (&Literal::String(ref s), &SqlType::Real) => match s.trim().parse::<f64>() {
+                Ok(f) if f.is_finite() => f.into(),
+                _ => dv.into(),
+            },
+            (&Literal::Integer(i), &SqlType::Double)
+            | (&Literal::Integer(i), &SqlType::Float)
+            | (&Literal::Integer(i), &SqlType::Real) => (i as f64).into(),
+            _ => dv.into(),
+        }
+    }
 }
 
 impl PartialEq for DataType {
@@ -467,6 +492,12 @@ pub enum Modification {
     None,
 }
 
+impl From<DataType> for Modification {
+    fn from(other: DataType) -> Self {
+        Modification::Set(other)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum TableOperation {
     Insert(Vec<DataType>),
@@ -481,6 +512,15 @@ pub enum TableOperation {
         set: Vec<Modification>,
         key: Vec<DataType>,
     },
+    DeleteWhere {
+        predicate: Vec<(usize, DataType)>,
+    },
+    /// Insert a row that leaves out the columns at the positions in `omitted`. The base replaces
+    /// the placeholder values at those positions with the columns' default values.
+    InsertWithDefaults {
+        row: Vec<DataType>,
+        omitted: Vec<usize>,
+    },
 }
 
 impl TableOperation {
@@ -488,6 +528,7 @@ impl TableOperation {
         match *self {
             TableOperation::Insert(ref r) => Some(r),
             TableOperation::InsertOrUpdate { ref row, .. } => Some(row),
+            TableOperation::InsertWithDefaults { ref row, .. } => Some(row),
             _ => None,
         }
     }
@@ -688,6 +729,21 @@ impl SizeOf for Vec<DataType> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn column_defaults_take_the_column_type() {
+        let ten = Literal::String("10".into());
+        assert_eq!(DataType::column_default(&ten, &SqlType::Int(32)), 10.into());
+        assert_eq!(DataType::column_default(&ten, &SqlType::Double), (10.0).into());
+        assert_eq!(DataType::column_default(&ten, &SqlType::Text), "10".into());
+        assert_eq!(
+            DataType::column_default(&Literal::Integer(10), &SqlType::Real),
+            (10.0).into()
+        );
+        // defaults that are not valid values of the type are kept as they are
+        let bad = Literal::String("ten".into());
+        assert_eq!(DataType::column_default(&bad, &SqlType::Int(32)), "ten".into());
+    }
+
     #[test]
     fn real_to_string() {
         let a: DataType = (2.5).into();
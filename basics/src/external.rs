@@ -20,7 +20,7 @@ impl fmt::Debug for Link {
 }
 
 /// Describe the materialization state of an operator.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MaterializationStatus {
     /// Operator's state is not materialized.
     Not,
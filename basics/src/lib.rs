@@ -24,17 +24,17 @@ pub use map::Map;
 pub use petgraph::graph::NodeIndex;
 
 #[inline]
-pub fn shard_by(dt: &DataType, shards: usize) -> usize {
+fn shard_key(dt: &DataType) -> u64 {
     match *dt {
-        DataType::Int(n) => n as usize % shards,
-        DataType::BigInt(n) => n as usize % shards,
+        DataType::Int(n) => n as u64,
+        DataType::BigInt(n) => n as u64,
         DataType::Text(..) | DataType::TinyText(..) => {
             use std::borrow::Cow;
             use std::hash::Hasher;
             let mut hasher = fnv::FnvHasher::default();
             let s: Cow<str> = dt.into();
             hasher.write(s.as_bytes());
-            hasher.finish() as usize % shards
+            hasher.finish()
         }
         // a bit hacky: send all NULL values to the first shard
         DataType::None => 0,
@@ -43,3 +43,110 @@ pub fn shard_by(dt: &DataType, shards: usize) -> usize {
         }
     }
 }
+
+#[inline]
+pub fn shard_by(dt: &DataType, shards: usize) -> usize {
+    shard_key(dt) as usize % shards
+}
+
+/// Determines which shard of a sharded node each key belongs to.
+///
+/// Everything that routes records or lookups by key (sharders, domains, and client `Table` and
+/// `View` handles) must use the same hasher, so it is chosen once for the whole controller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShardHasher {
+    /// Take the key modulo the number of shards. Changing the number of shards moves almost every
+    /// key to a different shard.
+    Modulo,
+    /// Jump consistent hashing. Going from `n` to `m > n` shards only moves the keys that now
+    /// belong on one of the new shards, so little state has to move when rescaling.
+    Consistent,
+}
+
+impl Default for ShardHasher {
+    fn default() -> Self {
+        ShardHasher::Modulo
+    }
+}
+
+impl ShardHasher {
+    /// The shard that `dt` belongs to if there are `shards` shards.
+    #[inline]
+    pub fn shard(self, dt: &DataType, shards: usize) -> usize {
+        match self {
+            ShardHasher::Modulo => shard_by(dt, shards),
+            ShardHasher::Consistent => jump_consistent_hash(shard_key(dt), shards),
+        }
+    }
+}
+
+/// Lamping and Veach, "A Fast, Minimal Memory, Consistent Hash Algorithm".
+fn jump_consistent_hash(mut key: u64, buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<DataType> {
+        (0..10_000i32)
+            .map(DataType::from)
+            .chain((0..10_000).map(|i| DataType::from(format!("key{}", i))))
+            .collect()
+    }
+
+    #[test]
+    fn hashers_are_deterministic() {
+        for &hasher in &[ShardHasher::Modulo, ShardHasher::Consistent] {
+            for key in keys() {
+                let shard = hasher.shard(&key, 4);
+                assert!(shard < 4);
+                assert_eq!(hasher.shard(&key.clone(), 4), shard);
+            }
+            assert_eq!(hasher.shard(&DataType::None, 4), 0);
+        }
+    }
+
+    #[test]
+    fn hashers_distribute_keys() {
+        for &hasher in &[ShardHasher::Modulo, ShardHasher::Consistent] {
+            let keys = keys();
+            let mut counts = vec![0; 4];
+            for key in &keys {
+                counts[hasher.shard(key, 4)] += 1;
+            }
+            let even = keys.len() / 4;
+            for count in counts {
+                assert!(count > even * 9 / 10 && count < even * 11 / 10, "{:?}", hasher);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hasher_moves_few_keys() {
+        let keys = keys();
+        let moved = |hasher: ShardHasher| {
+            keys.iter()
+                .filter(|k| hasher.shard(k, 4) != hasher.shard(k, 5))
+                .count()
+        };
+
+        // a fifth of the keys should move to the new shard, and no others
+        let consistent = moved(ShardHasher::Consistent);
+        assert!(consistent < keys.len() / 4);
+        for key in &keys {
+            let before = ShardHasher::Consistent.shard(key, 4);
+            let after = ShardHasher::Consistent.shard(key, 5);
+            assert!(before == after || after == 4);
+        }
+        assert!(moved(ShardHasher::Modulo) > keys.len() / 2);
+    }
+}
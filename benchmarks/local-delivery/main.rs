@@ -0,0 +1,81 @@
+#![feature(duration_as_u128)]
+
+#[macro_use]
+extern crate clap;
+extern crate distributary;
+
+use clap::{App, Arg};
+use distributary::{ControllerBuilder, DurabilityMode, PersistenceParameters};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ARTICLES: i32 = 10;
+
+/// Measure how many writes per second make it through a single-worker, multi-domain graph, with
+/// packets between domains either handed over in memory or sent over loopback.
+fn throughput(local: bool, shards: usize, votes: i32) -> u128 {
+    let mut persistence = PersistenceParameters::default();
+    persistence.mode = DurabilityMode::MemoryOnly;
+
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(Some(shards));
+    g.set_local_delivery(local);
+    g.set_persistence(persistence);
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    let mut counts = g.view("VoteCount").unwrap();
+
+    let start = Instant::now();
+    vote.batch_insert((0..votes).map(|id| vec![id.into(), (id % ARTICLES).into()]))
+        .unwrap();
+
+    // writes are only done once every vote is counted
+    loop {
+        let counted: i64 = (0..ARTICLES)
+            .map(|article| {
+                counts
+                    .lookup(&[article.into()], true)
+                    .unwrap()
+                    .get(0)
+                    .map(|r| -> i64 { (&r[1]).into() })
+                    .unwrap_or(0)
+            }).sum();
+        if counted == i64::from(votes) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    votes as u128 * 1000 / start.elapsed().as_millis().max(1)
+}
+
+fn main() {
+    let args = App::new("local-delivery")
+        .version("0.1")
+        .about("Compares in-memory and loopback delivery between domains on one worker")
+        .arg(
+            Arg::with_name("votes")
+                .long("votes")
+                .value_name("N")
+                .default_value("200000")
+                .help("Number of votes to write."),
+        ).arg(
+            Arg::with_name("shards")
+                .long("shards")
+                .value_name("N")
+                .default_value("2")
+                .help("Number of shards for sharded nodes."),
+        ).get_matches();
+    let votes = value_t_or_exit!(args, "votes", i32);
+    let shards = value_t_or_exit!(args, "shards", usize);
+
+    let local = throughput(true, shards, votes);
+    let loopback = throughput(false, shards, votes);
+    println!("in memory: {} writes/s", local);
+    println!("over loopback: {} writes/s", loopback);
+}
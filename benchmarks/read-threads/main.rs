@@ -0,0 +1,99 @@
+#[macro_use]
+extern crate clap;
+extern crate distributary;
+
+use clap::{App, Arg};
+use distributary::{ControllerBuilder, DurabilityMode, PersistenceParameters};
+
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BRANDS: i32 = 100;
+
+/// Measure how many lookups per second `clients` clients, each with its own connection, get out
+/// of a worker that serves lookups from `threads` read threads.
+fn throughput(threads: usize, clients: usize, runtime: Duration) -> u64 {
+    let mut persistence = PersistenceParameters::default();
+    persistence.mode = DurabilityMode::MemoryOnly;
+
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(None);
+    g.set_read_threads(threads);
+    g.set_persistence(persistence);
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand int, PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    ).unwrap();
+
+    let mut car = g.table("Car").unwrap();
+    car.batch_insert((0..10 * BRANDS).map(|id| vec![id.into(), (id % BRANDS).into()]))
+        .unwrap();
+
+    // make sure every key is filled before we start timing
+    let mut q = g.view("CarsByBrand").unwrap();
+    for brand in 0..BRANDS {
+        while q.lookup(&[brand.into()], true).unwrap().len() != 10 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let start = Arc::new(Barrier::new(clients));
+    let clients: Vec<_> = (0..clients)
+        .map(|client| {
+            let mut q = g.view("CarsByBrand").unwrap().into_exclusive().unwrap();
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                let end = Instant::now() + runtime;
+                let mut lookups = 0u64;
+                let mut brand = client as i32;
+                while Instant::now() < end {
+                    q.lookup(&[(brand % BRANDS).into()], true).unwrap();
+                    brand += 1;
+                    lookups += 1;
+                }
+                lookups
+            })
+        }).collect();
+
+    let lookups: u64 = clients.into_iter().map(|c| c.join().unwrap()).sum();
+    lookups / runtime.as_secs().max(1)
+}
+
+fn main() {
+    let args = App::new("read-threads")
+        .version("0.1")
+        .about("Measures lookup throughput for different numbers of read threads")
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N,...")
+                .default_value("1,2,4,8")
+                .help("Numbers of read threads to measure with."),
+        ).arg(
+            Arg::with_name("clients")
+                .long("clients")
+                .value_name("N")
+                .default_value("8")
+                .help("Number of clients looking up concurrently."),
+        ).arg(
+            Arg::with_name("runtime")
+                .long("runtime")
+                .value_name("S")
+                .default_value("10")
+                .help("Seconds to measure each number of read threads for."),
+        ).get_matches();
+    let clients = value_t_or_exit!(args, "clients", usize);
+    let runtime = Duration::from_secs(value_t_or_exit!(args, "runtime", u64));
+
+    for threads in args.value_of("threads").unwrap().split(',') {
+        let threads: usize = threads.parse().expect("--threads takes a list of numbers");
+        println!(
+            "{} read threads: {} lookups/s",
+            threads,
+            throughput(threads, clients, runtime)
+        );
+    }
+}
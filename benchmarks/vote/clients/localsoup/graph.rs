@@ -27,6 +27,8 @@ pub struct Setup {
     pub partial: bool,
     pub sharding: Option<usize>,
     pub logging: bool,
+    pub read_threads: Option<usize>,
+    pub local_delivery: bool,
 }
 
 impl Default for Setup {
@@ -36,6 +38,8 @@ impl Default for Setup {
             partial: true,
             sharding: None,
             logging: false,
+            read_threads: None,
+            local_delivery: true,
         }
     }
 }
@@ -49,6 +53,10 @@ impl Setup {
         }
         g.set_sharding(self.sharding);
         g.set_persistence(persistence_params);
+        g.set_local_delivery(self.local_delivery);
+        if let Some(threads) = self.read_threads {
+            g.set_read_threads(threads);
+        }
         if self.logging {
             g.log_with(distributary::logger_pls());
         }
@@ -57,6 +57,10 @@ impl VoteClientConstructor for Constructor {
             x => Some(x),
         };
         s.stupid = args.is_present("stupid");
+        s.local_delivery = !args.is_present("no-local-delivery");
+        if args.is_present("read-threads") {
+            s.read_threads = Some(value_t_or_exit!(args, "read-threads", usize));
+        }
         let mut g = s.make(persistence);
 
         // prepopulate
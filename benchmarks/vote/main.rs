@@ -646,6 +646,15 @@ fn main() {
                         .takes_value(true)
                         .default_value("1")
                         .help("Number of background threads used by PersistentState."),
+                ).arg(
+                    Arg::with_name("read-threads")
+                        .long("read-threads")
+                        .takes_value(true)
+                        .help("Number of threads serving view lookups (default: one per core)."),
+                ).arg(
+                    Arg::with_name("no-local-delivery")
+                        .long("no-local-delivery")
+                        .help("Send packets between domains over loopback connections."),
                 ).arg(
                     Arg::with_name("stupid")
                         .long("stupid")
@@ -1,5 +1,6 @@
 use std;
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
 use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddr};
 
@@ -19,22 +20,30 @@ pub struct RpcClient<Q, R> {
     phantom: PhantomData<Q>,
     phantom2: PhantomData<R>,
     is_local: bool,
+
+    /// Number of queries sent so far.
+    sent: u64,
+    /// Number of replies read off the stream so far.
+    received: u64,
+    /// Replies that were read while waiting for the reply to a later query.
+    early: HashMap<u64, R>,
+    /// Queries whose replies have not been read yet, but will never be asked for.
+    abandoned: HashSet<u64>,
 }
 
-pub struct Eventually<'a, Q: 'a, R: 'a>(&'a mut RpcClient<Q, R>);
+/// Identifies a query that has been sent, but whose reply has not yet been received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ticket(u64);
+
+pub struct Eventually<'a, Q: 'a, R: 'a>(&'a mut RpcClient<Q, R>, Ticket);
 
 impl<'a, Q: 'a, R: 'a> Eventually<'a, Q, R>
 where
+    Q: Serialize,
     for<'de> R: Deserialize<'de>,
 {
     pub fn wait(self) -> Result<R, SendError> {
-        match bincode::deserialize_from(&mut self.0.stream) {
-            Ok(r) => Ok(r),
-            Err(e) => {
-                self.0.poisoned = true;
-                Err(e.into())
-            }
-        }
+        self.0.recv(self.1)
     }
 }
 
@@ -50,6 +59,10 @@ where
             phantom: PhantomData,
             phantom2: PhantomData,
             is_local,
+            sent: 0,
+            received: 0,
+            early: HashMap::new(),
+            abandoned: HashSet::new(),
         })
     }
 
@@ -77,7 +90,7 @@ where
         self.stream.get_ref().local_addr()
     }
 
-    fn send_internal(&mut self, query: &Q) -> Result<Eventually<Q, R>, SendError> {
+    fn send_internal(&mut self, query: &Q) -> Result<Ticket, SendError> {
         if self.poisoned {
             return Err(SendError::Poisoned);
         }
@@ -86,15 +99,109 @@ where
         self.stream.write_u32::<NetworkEndian>(size)?;
         bincode::serialize_into(&mut self.stream, query)?;
         self.stream.flush()?;
-        Ok(Eventually(self))
+        self.sent += 1;
+        Ok(Ticket(self.sent - 1))
     }
 
     pub fn send_async(&mut self, query: &Q) -> Result<Eventually<Q, R>, SendError> {
+        let ticket = self.send_internal(query)?;
+        Ok(Eventually(self, ticket))
+    }
+
+    /// Send a query without waiting for its reply, which can later be retrieved with `recv`.
+    ///
+    /// Replies arrive in the order the queries were sent, so any number of queries can be in
+    /// flight on the connection at once.
+    pub fn send_pipelined(&mut self, query: &Q) -> Result<Ticket, SendError> {
         self.send_internal(query)
     }
 
+    /// Wait for the reply to a query sent earlier.
+    ///
+    /// Replies to queries sent before the given one are kept until they are asked for.
+    pub fn recv(&mut self, ticket: Ticket) -> Result<R, SendError> {
+        if let Some(r) = self.early.remove(&ticket.0) {
+            return Ok(r);
+        }
+        assert!(ticket.0 >= self.received && ticket.0 < self.sent);
+
+        loop {
+            if let Some(r) = self.recv_one(ticket)? {
+                return Ok(r);
+            }
+        }
+    }
+
+    /// Like `recv`, but returns `None` instead of waiting if the reply has not arrived yet.
+    pub fn try_recv(&mut self, ticket: Ticket) -> Result<Option<R>, SendError> {
+        if let Some(r) = self.early.remove(&ticket.0) {
+            return Ok(Some(r));
+        }
+        assert!(ticket.0 >= self.received && ticket.0 < self.sent);
+
+        loop {
+            if self.poisoned {
+                return Err(SendError::Poisoned);
+            }
+            if !self.reply_available()? {
+                return Ok(None);
+            }
+            if let Some(r) = self.recv_one(ticket)? {
+                return Ok(Some(r));
+            }
+        }
+    }
+
+    /// Give up on the reply to a query sent earlier, so that it is discarded instead of kept
+    /// around once it arrives.
+    pub fn forget(&mut self, ticket: Ticket) {
+        if self.early.remove(&ticket.0).is_none() && ticket.0 >= self.received {
+            self.abandoned.insert(ticket.0);
+        }
+    }
+
+    /// Whether the start of a reply can be read without blocking.
+    ///
+    /// The server writes each reply in one go, so once it has started to arrive, the rest of it
+    /// follows shortly.
+    fn reply_available(&mut self) -> Result<bool, SendError> {
+        self.stream.get_ref().set_nonblocking(true)?;
+        let available = match self.stream.fill_buf() {
+            // an empty buffer means the connection was closed, which reading will report
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.get_ref().set_nonblocking(false)?;
+        Ok(available?)
+    }
+
+    /// Read the next reply off the stream, and return it if it is the reply to `ticket`.
+    fn recv_one(&mut self, ticket: Ticket) -> Result<Option<R>, SendError> {
+        if self.poisoned {
+            return Err(SendError::Poisoned);
+        }
+        let r = match bincode::deserialize_from(&mut self.stream) {
+            Ok(r) => r,
+            Err(e) => {
+                self.poisoned = true;
+                return Err(e.into());
+            }
+        };
+        self.received += 1;
+        let id = self.received - 1;
+        if id == ticket.0 {
+            return Ok(Some(r));
+        }
+        if !self.abandoned.remove(&id) {
+            self.early.insert(id, r);
+        }
+        Ok(None)
+    }
+
     pub fn send(&mut self, query: &Q) -> Result<R, SendError> {
-        self.send_internal(query)?.wait()
+        let ticket = self.send_internal(query)?;
+        self.recv(ticket)
     }
 }
 
@@ -54,6 +54,11 @@ pub struct TcpSender<T> {
     stream: BufStream<std::net::TcpStream>,
     poisoned: bool,
 
+    /// If set, sent messages are held back until at least this many bytes are waiting.
+    coalesce: Option<usize>,
+    /// Bytes sent since the last flush.
+    unflushed: usize,
+
     phantom: PhantomData<T>,
 }
 
@@ -63,6 +68,8 @@ impl<T: Serialize> TcpSender<T> {
         Ok(Self {
             stream: BufStream::new(stream),
             poisoned: false,
+            coalesce: None,
+            unflushed: 0,
             phantom: PhantomData,
         })
     }
@@ -109,6 +116,36 @@ impl<T: Serialize> TcpSender<T> {
         let size = u32::try_from(bincode::serialized_size(t).unwrap()).unwrap();
         poisoning_try!(self, self.stream.write_u32::<NetworkEndian>(size));
         poisoning_try!(self, bincode::serialize_into(&mut self.stream, t));
+        self.unflushed += 4 + size as usize;
+
+        match self.coalesce {
+            Some(max_bytes) if self.unflushed < max_bytes => Ok(()),
+            _ => self.flush(),
+        }
+    }
+
+    /// Hold on to sent messages until at least `max_bytes` of them are waiting, and then write
+    /// them all out together. `None` writes out every message as soon as it is sent.
+    ///
+    /// Every message keeps its own length prefix, so a coalesced write is decoded by the receiver
+    /// into the same messages, in the same order, as if they had been written one at a time.
+    /// Messages are only written out as a result of a send or a `flush`, so a sender that may go
+    /// quiet must call `flush` before it waits for the receiver to act on what it sent. Errors
+    /// writing held-back messages are returned by whichever call writes them out.
+    pub fn coalesce(&mut self, max_bytes: Option<usize>) {
+        self.coalesce = max_bytes;
+    }
+
+    /// Write out any messages held back by `coalesce`.
+    pub fn flush(&mut self) -> Result<(), SendError> {
+        if self.poisoned {
+            return Err(SendError::Poisoned);
+        }
+        if self.unflushed == 0 {
+            return Ok(());
+        }
+
+        self.unflushed = 0;
         poisoning_try!(self, self.stream.flush());
         Ok(())
     }
@@ -131,10 +168,11 @@ pub enum RecvError {
     DeserializationError(bincode::Error),
 }
 
+/// A stream of inputs to a domain, on which the sequence numbers of applied writes are sent back.
 pub enum DualTcpStream<S, T, T2, D> {
-    Passthrough(AsyncBincodeStream<S, T, bool, D>),
+    Passthrough(AsyncBincodeStream<S, T, u64, D>),
     Upgrade(
-        AsyncBincodeStream<S, T2, bool, D>,
+        AsyncBincodeStream<S, T2, u64, D>,
         Box<FnMut(T2) -> T + Send + Sync>,
     ),
 }
@@ -147,7 +185,7 @@ impl<S, T, T2> From<S> for DualTcpStream<S, T, T2, SyncDestination> {
 
 impl<S, T, T2> DualTcpStream<S, T, T2, SyncDestination> {
     pub fn upgrade<F: 'static + FnMut(T2) -> T + Send + Sync>(stream: S, f: F) -> Self {
-        let s: AsyncBincodeStream<S, T2, bool, SyncDestination> = AsyncBincodeStream::from(stream);
+        let s: AsyncBincodeStream<S, T2, u64, SyncDestination> = AsyncBincodeStream::from(stream);
         DualTcpStream::Upgrade(s, Box::new(f))
     }
 
@@ -162,9 +200,9 @@ impl<S, T, T2> DualTcpStream<S, T, T2, SyncDestination> {
 impl<S, T, T2, D> Sink for DualTcpStream<S, T, T2, D>
 where
     S: AsyncWrite,
-    AsyncBincodeWriter<S, bool, D>: Sink<SinkItem = bool, SinkError = bincode::Error>,
+    AsyncBincodeWriter<S, u64, D>: Sink<SinkItem = u64, SinkError = bincode::Error>,
 {
-    type SinkItem = bool;
+    type SinkItem = u64;
     type SinkError = bincode::Error;
     fn start_send(
         &mut self,
@@ -366,6 +404,73 @@ mod tests {
         assert_eq!(receiver.recv().unwrap(), 13);
     }
 
+    #[test]
+    fn coalescing() {
+        let (mut sender, mut receiver) = channel::<u32>("127.0.0.1:0".parse().unwrap());
+
+        // each message is 8 bytes on the wire, so three of them fill a batch
+        sender.coalesce(Some(24));
+        sender.send(12).unwrap();
+        sender.send(65).unwrap();
+        assert_eq!(sender.unflushed, 16);
+        sender.send(13).unwrap();
+        assert_eq!(sender.unflushed, 0);
+
+        // the batch is decoded into the messages that went into it
+        assert_eq!(receiver.recv().unwrap(), 12);
+        assert_eq!(receiver.recv().unwrap(), 65);
+        assert_eq!(receiver.recv().unwrap(), 13);
+
+        // an explicit flush writes out a partial batch
+        sender.send(7).unwrap();
+        assert_eq!(sender.unflushed, 8);
+        sender.flush().unwrap();
+        assert_eq!(receiver.recv().unwrap(), 7);
+
+        // and without coalescing, every message is written out right away
+        sender.coalesce(None);
+        sender.send(1).unwrap();
+        assert_eq!(sender.unflushed, 0);
+        assert_eq!(receiver.recv().unwrap(), 1);
+    }
+
+    /// The number of write system calls the calling thread has made.
+    #[cfg(target_os = "linux")]
+    fn write_syscalls() -> u64 {
+        let io = std::fs::read_to_string("/proc/thread-self/io").unwrap();
+        io.lines()
+            .find(|l| l.starts_with("syscw:"))
+            .and_then(|l| l["syscw:".len()..].trim().parse().ok())
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn coalescing_saves_syscalls() {
+        let (mut sender, mut receiver) = channel::<u32>("127.0.0.1:0".parse().unwrap());
+
+        let mut send_all = |sender: &mut TcpSender<u32>| {
+            let before = write_syscalls();
+            for i in 0..1000 {
+                sender.send(i).unwrap();
+            }
+            sender.flush().unwrap();
+            let writes = write_syscalls() - before;
+            for i in 0..1000 {
+                assert_eq!(receiver.recv().unwrap(), i);
+            }
+            writes
+        };
+
+        let unbatched = send_all(&mut sender);
+        sender.coalesce(Some(4096));
+        let batched = send_all(&mut sender);
+
+        // 8000 bytes of messages in batches of at least 4096 bytes take a handful of writes
+        assert!(unbatched >= 1000, "{} writes without coalescing", unbatched);
+        assert!(batched <= 10, "{} writes with coalescing", batched);
+    }
+
     #[test]
     fn multithread() {
         let (mut sender, mut receiver) = channel::<u32>("127.0.0.1:0".parse().unwrap());
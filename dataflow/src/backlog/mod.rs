@@ -1,12 +1,19 @@
+use api::WriteId;
 use basics::data::SizeOf;
-use basics::{DataType, Record};
-use fnv::FnvBuildHasher;
+use basics::{DataType, NodeIndex, Record, ShardHasher};
+use fnv::{FnvBuildHasher, FnvHashMap};
 use std::borrow::Cow;
+use std::cmp;
 
 use rand::{Rng, ThreadRng};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+/// The latest write to each base shard that a reader reflects.
+type Watermarks = Arc<RwLock<FnvHashMap<(NodeIndex, usize), u64>>>;
 
 /// Allocate a new end-user facing result table.
+///
+/// If `key` is empty, all rows are stored under the single empty key.
 pub(crate) fn new(cols: usize, key: &[usize]) -> (SingleReadHandle, WriteHandle) {
     new_inner(cols, key, None)
 }
@@ -31,7 +38,7 @@ fn new_inner(
     trigger: Option<Arc<Fn(&[DataType]) + Send + Sync>>,
 ) -> (SingleReadHandle, WriteHandle) {
     let contiguous = {
-        let mut contiguous = true;
+        let mut contiguous = !key.is_empty();
         let mut last = None;
         for &k in key {
             if let Some(last) = last {
@@ -58,12 +65,12 @@ fn new_inner(
     }
 
     let (r, w) = match key.len() {
-        0 => unreachable!(),
         1 => make!(Single),
         2 => make!(Double),
         _ => make!(Many),
     };
 
+    let watermarks = Watermarks::default();
     let w = WriteHandle {
         partial: trigger.is_some(),
         handle: w,
@@ -71,11 +78,15 @@ fn new_inner(
         cols: cols,
         contiguous,
         mem_size: 0,
+        rows: 0,
+        seen: Vec::new(),
+        watermarks: Arc::clone(&watermarks),
     };
     let r = SingleReadHandle {
         handle: r,
         trigger: trigger,
         key: Vec::from(key),
+        watermarks,
     };
 
     (r, w)
@@ -112,6 +123,12 @@ pub(crate) struct WriteHandle {
     key: Vec<usize>,
     contiguous: bool,
     mem_size: usize,
+    /// Number of rows in the backlog, including those added since the last swap.
+    rows: usize,
+
+    /// Writes reflected by the records added since the last swap.
+    seen: Vec<WriteId>,
+    watermarks: Watermarks,
 }
 
 type Key<'a> = Cow<'a, [DataType]>;
@@ -138,14 +155,15 @@ impl<'a> MutWriteHandleEntry<'a> {
     }
 
     pub fn mark_hole(self) {
-        let size = self
+        let (size, rows) = self
             .handle
             .handle
             .meta_get_and(Cow::Borrowed(&*self.key), |rs| {
-                rs.iter().map(|r| r.deep_size_of()).sum()
-            }).map(|r| r.0.unwrap_or(0))
-            .unwrap_or(0);
+                (rs.iter().map(|r| r.deep_size_of()).sum::<u64>(), rs.len())
+            }).and_then(|r| r.0)
+            .unwrap_or((0, 0));
         self.handle.mem_size = self.handle.mem_size.checked_sub(size as usize).unwrap();
+        self.handle.rows = self.handle.rows.saturating_sub(rows);
         self.handle.handle.empty(self.key)
     }
 }
@@ -230,6 +248,22 @@ impl WriteHandle {
 
     pub(crate) fn swap(&mut self) {
         self.handle.refresh();
+
+        // only now that the records are visible can readers be told about the writes
+        if !self.seen.is_empty() {
+            let mut watermarks = self.watermarks.write().unwrap();
+            for w in self.seen.drain(..) {
+                let seq = watermarks.entry((w.base, w.shard)).or_insert(0);
+                if w.seq > *seq {
+                    *seq = w.seq;
+                }
+            }
+        }
+    }
+
+    /// Note that the records added since the last swap reflect the given base write.
+    pub(crate) fn saw_write(&mut self, w: WriteId) {
+        self.seen.push(w);
     }
 
     /// Add a new set of records to the backlog.
@@ -239,7 +273,9 @@ impl WriteHandle {
     where
         I: IntoIterator<Item = Record>,
     {
-        let mem_delta = self.handle.add(&self.key[..], self.cols, rs);
+        let (mem_delta, row_delta) = self.handle.add(&self.key[..], self.cols, rs);
+        // a negative for a row that is not there (e.g., in a hole) does not remove anything
+        self.rows = cmp::max(self.rows as isize + row_delta, 0) as usize;
         if mem_delta > 0 {
             self.mem_size += mem_delta as usize;
         } else if mem_delta < 0 {
@@ -254,6 +290,12 @@ impl WriteHandle {
         self.partial
     }
 
+    /// The number of rows in the backlog, including those that readers will only see after the
+    /// next `swap()`.
+    pub(crate) fn rows(&self) -> usize {
+        self.rows
+    }
+
     /// Evict `count` randomly selected keys from state and return them along with the number of
     /// bytes that will be freed once the underlying `evmap` applies the operation.
     pub fn evict_random_key(&mut self, rng: &mut ThreadRng) -> u64 {
@@ -268,6 +310,7 @@ impl WriteHandle {
                 Some(vs) => {
                     let size: u64 = vs.into_iter().map(|r| r.deep_size_of() as u64).sum();
                     bytes_to_be_freed += size;
+                    self.rows = self.rows.saturating_sub(vs.len());
                 }
             }
             self.mem_size = self
@@ -297,6 +340,7 @@ pub struct SingleReadHandle {
     handle: multir::Handle,
     trigger: Option<Arc<Fn(&[DataType]) + Send + Sync>>,
     key: Vec<usize>,
+    watermarks: Watermarks,
 }
 
 impl SingleReadHandle {
@@ -339,6 +383,16 @@ impl SingleReadHandle {
         self.handle.len()
     }
 
+    /// Whether reads from this handle reflect the given base write.
+    pub fn has_seen(&self, w: &WriteId) -> bool {
+        self.watermarks
+            .read()
+            .unwrap()
+            .get(&(w.base, w.shard))
+            .map(|&seq| seq >= w.seq)
+            .unwrap_or(false)
+    }
+
     /// Count the number of rows in the reader.
     /// This is a potentially very costly operation, since it will
     /// hold up writers until all rows are iterated through.
@@ -351,7 +405,9 @@ impl SingleReadHandle {
 
 #[derive(Clone)]
 pub enum ReadHandle {
-    Sharded(Vec<Option<SingleReadHandle>>),
+    /// The handles of each shard of a sharded reader, along with the hasher that assigns keys to
+    /// those shards.
+    Sharded(Vec<Option<SingleReadHandle>>, ShardHasher),
     Singleton(Option<SingleReadHandle>),
 }
 
@@ -369,9 +425,9 @@ impl ReadHandle {
         F: FnMut(&[Vec<DataType>]) -> T,
     {
         match *self {
-            ReadHandle::Sharded(ref shards) => {
+            ReadHandle::Sharded(ref shards, hasher) => {
                 assert_eq!(key.len(), 1);
-                shards[::shard_by(&key[0], shards.len())]
+                shards[hasher.shard(&key[0], shards.len())]
                     .as_ref()
                     .unwrap()
                     .try_find_and(key, then)
@@ -382,7 +438,7 @@ impl ReadHandle {
 
     pub fn len(&self) -> usize {
         match *self {
-            ReadHandle::Sharded(ref shards) => {
+            ReadHandle::Sharded(ref shards, _) => {
                 shards.iter().map(|s| s.as_ref().unwrap().len()).sum()
             }
             ReadHandle::Singleton(ref srh) => srh.as_ref().unwrap().len(),
@@ -394,14 +450,14 @@ impl ReadHandle {
             (&mut ReadHandle::Singleton(ref mut srh), None) => {
                 *srh = Some(handle);
             }
-            (&mut ReadHandle::Sharded(ref mut rhs), None) => {
+            (&mut ReadHandle::Sharded(ref mut rhs, _), None) => {
                 // when ::SHARDS == 1, sharded domains think they're unsharded
                 assert_eq!(rhs.len(), 1);
                 let srh = rhs.get_mut(0).unwrap();
                 assert!(srh.is_none());
                 *srh = Some(handle)
             }
-            (&mut ReadHandle::Sharded(ref mut rhs), Some(shard)) => {
+            (&mut ReadHandle::Sharded(ref mut rhs, _), Some(shard)) => {
                 let srh = rhs.get_mut(shard).unwrap();
                 assert!(srh.is_none());
                 *srh = Some(handle)
@@ -447,6 +503,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_counts_rows() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![1.into(), "b".into()];
+        let c = vec![2.into(), "c".into()];
+
+        let (_r, mut w) = new_partial(2, &[0], |_: &[DataType]| {});
+        w.swap();
+        assert_eq!(w.rows(), 0);
+
+        w.mut_with_key(&a[0..1]).mark_filled();
+        w.mut_with_key(&c[0..1]).mark_filled();
+        w.add(vec![
+            Record::Positive(a.clone()),
+            Record::Positive(b.clone()),
+            Record::Positive(c.clone()),
+        ]);
+        w.swap();
+        assert_eq!(w.rows(), 3);
+
+        w.add(vec![Record::Negative(b.clone())]);
+        w.swap();
+        assert_eq!(w.rows(), 2);
+
+        w.mut_with_key(&a[0..1]).mark_hole();
+        w.swap();
+        assert_eq!(w.rows(), 1);
+    }
+
+    #[test]
+    fn sharded_lookups_use_hasher() {
+        let shards = 4;
+        let hasher = ShardHasher::Consistent;
+
+        // a key that the two hashers place on different shards
+        let key: DataType = (0..100i64)
+            .map(DataType::from)
+            .find(|k| hasher.shard(k, shards) != ShardHasher::Modulo.shard(k, shards))
+            .unwrap();
+
+        let (rs, mut ws): (Vec<_>, Vec<_>) = (0..shards).map(|_| new(1, &[0])).unzip();
+        ws[hasher.shard(&key, shards)].add(vec![Record::Positive(vec![key.clone()])]);
+        for w in &mut ws {
+            w.swap();
+        }
+
+        let r = ReadHandle::Sharded(rs.into_iter().map(Some).collect(), hasher);
+        assert_eq!(r.try_find_and(&[key], |rs| rs.len()).unwrap().0, Some(1));
+    }
+
     #[test]
     fn busybusybusy() {
         use std::thread;
@@ -610,4 +716,25 @@ mod tests {
             .unwrap()
         );
     }
+
+    #[test]
+    fn keyless() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![2.into(), "b".into()];
+
+        let (r, mut w) = new(2, &[]);
+        w.swap();
+        assert_eq!(r.try_find_and(&[], |rs| rs.len()), Ok((Some(0), -1)));
+
+        w.add(vec![
+            Record::Positive(a.clone()),
+            Record::Positive(b.clone()),
+        ]);
+        w.swap();
+        assert_eq!(r.try_find_and(&[], |rs| rs.len()).unwrap().0, Some(2));
+
+        w.add(vec![Record::Negative(a)]);
+        w.swap();
+        assert_eq!(r.try_find_and(&[], |rs| rs.to_vec()).unwrap().0, Some(vec![b]));
+    }
 }
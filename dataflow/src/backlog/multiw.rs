@@ -93,11 +93,13 @@ impl Handle {
         }
     }
 
-    pub fn add<I>(&mut self, key: &[usize], cols: usize, rs: I) -> isize
+    /// Apply the given records, and return by how many bytes and rows the map grew.
+    pub fn add<I>(&mut self, key: &[usize], cols: usize, rs: I) -> (isize, isize)
     where
         I: IntoIterator<Item = Record>,
     {
         let mut memory_delta = 0isize;
+        let mut row_delta = 0isize;
         match *self {
             Handle::Single(ref mut h) => {
                 assert_eq!(key.len(), 1);
@@ -106,6 +108,7 @@ impl Handle {
                     match r {
                         Record::Positive(r) => {
                             memory_delta += r.deep_size_of() as isize;
+                            row_delta += 1;
                             h.insert(r[key[0]].clone(), r);
                         }
                         Record::Negative(r) => {
@@ -114,6 +117,7 @@ impl Handle {
                             // replay, which will produce an empty result. this will work, but is
                             // somewhat inefficient.
                             memory_delta -= r.deep_size_of() as isize;
+                            row_delta -= 1;
                             h.remove(r[key[0]].clone(), r);
                         }
                     }
@@ -126,10 +130,12 @@ impl Handle {
                     match r {
                         Record::Positive(r) => {
                             memory_delta += r.deep_size_of() as isize;
+                            row_delta += 1;
                             h.insert((r[key[0]].clone(), r[key[1]].clone()), r);
                         }
                         Record::Negative(r) => {
                             memory_delta -= r.deep_size_of() as isize;
+                            row_delta -= 1;
                             h.remove((r[key[0]].clone(), r[key[1]].clone()), r);
                         }
                     }
@@ -141,15 +147,17 @@ impl Handle {
                 match r {
                     Record::Positive(r) => {
                         memory_delta += r.deep_size_of() as isize;
+                        row_delta += 1;
                         h.insert(key, r);
                     }
                     Record::Negative(r) => {
                         memory_delta -= r.deep_size_of() as isize;
+                        row_delta -= 1;
                         h.remove(key, r);
                     }
                 }
             },
         }
-        memory_delta
+        (memory_delta, row_delta)
     }
 }
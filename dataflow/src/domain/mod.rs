@@ -5,16 +5,18 @@ use std::cell;
 use std::cmp;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::mem;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::time;
 
 use api;
+use api::debug::latency::LatencyCollector;
 pub use basics::DomainIndex as Index;
 use channel::poll::{PollEvent, ProcessResult};
-use channel::{DomainConnectionBuilder, TcpSender};
+use channel::{DomainConnectionBuilder, TcpSender, TraceSender};
 use futures;
 use group_commit::GroupCommitQueueSet;
 use payload::{ControlReplyPacket, ReplayPieceContext};
@@ -26,16 +28,32 @@ use timekeeper::{RealTime, SimpleTracker, ThreadTime, Timer, TimerSet};
 use tokio::{self, prelude::*};
 use Readers;
 
+#[cfg(feature = "replay_ordering")]
+mod ordering;
+#[cfg(feature = "replay_ordering")]
+pub use self::ordering::PacketOrder;
+
 type EnqueuedSends = FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Config {
     pub concurrent_replays: usize,
     pub replay_batch_timeout: time::Duration,
+    /// The most records sent in a single packet when a node's entire state is replayed.
+    pub replay_batch_size: usize,
+    /// How keys are mapped to the shards of sharded nodes.
+    pub shard_hasher: ShardHasher,
+    /// How keys are chosen when evicting from partially materialized state.
+    pub eviction_policy: EvictionPolicy,
+    /// The most bytes of partially materialized state that a single node may hold. Whenever a
+    /// node's state grows beyond this, keys are evicted from it according to `eviction_policy`.
+    pub partial_state_limit: Option<u64>,
+    /// Makes the domain that hosts the named node handle partial replays and regular updates in
+    /// the given order, so that tests can reproduce races between the two.
+    #[cfg(feature = "replay_ordering")]
+    pub packet_order: Option<(String, PacketOrder)>,
 }
 
-const BATCH_SIZE: usize = 256;
-
 #[derive(Debug)]
 enum DomainMode {
     Forwarding,
@@ -125,6 +143,9 @@ unsafe impl Send for DomainBuilder {}
 
 impl DomainBuilder {
     /// Starts up the domain represented by this `DomainBuilder`.
+    ///
+    /// Fails if the domain cannot connect back to the controller, for example because the
+    /// controller gave up waiting for it to boot.
     pub fn build(
         self,
         log: Logger,
@@ -133,7 +154,7 @@ impl DomainBuilder {
         addr: SocketAddr,
         shutdown_valve: &Valve,
         state_size: Arc<AtomicUsize>,
-    ) -> Domain {
+    ) -> io::Result<Domain> {
         // initially, all nodes are not ready
         let not_ready = self
             .nodes
@@ -143,15 +164,32 @@ impl DomainBuilder {
 
         let log = log.new(o!("domain" => self.index.index(), "shard" => self.shard.unwrap_or(0)));
 
-        let debug_tx = self
-            .debug_addr
-            .as_ref()
-            .map(|addr| TcpSender::connect(addr).unwrap());
-        let control_reply_tx = TcpSender::connect(&self.control_addr).unwrap();
+        let debug_tx = match self.debug_addr {
+            Some(ref addr) => Some(TcpSender::connect(addr)?),
+            None => None,
+        };
+        let control_reply_tx = TcpSender::connect(&self.control_addr)?;
 
         let group_commit_queues = GroupCommitQueueSet::new(&self.persistence_parameters);
+        let (trace_tx, trace_rx) = mpsc::channel();
 
-        Domain {
+        #[cfg(feature = "replay_ordering")]
+        let packet_order = self
+            .config
+            .packet_order
+            .as_ref()
+            .filter(|&&(ref name, _)| self.nodes.values().any(|n| n.borrow().name() == &name[..]))
+            .map(|&(_, order)| ordering::Gate::new(order));
+
+        let shard_hasher = self.config.shard_hasher;
+        for n in self.nodes.values() {
+            let mut n = n.borrow_mut();
+            if n.is_sharder() {
+                n.with_sharder_mut(|s| s.set_hasher(shard_hasher));
+            }
+        }
+
+        Ok(Domain {
             index: self.index,
             shard: self.shard,
             _nshards: self.nshards,
@@ -172,27 +210,38 @@ impl DomainBuilder {
             shutdown_valve: shutdown_valve.clone(),
             readers,
             _debug_tx: debug_tx,
+            trace_tx: TraceSender::from_local(trace_tx),
+            trace_rx,
+            latencies: LatencyCollector::default(),
             control_reply_tx,
             channel_coordinator,
 
             buffered_replay_requests: Default::default(),
             has_buffered_replay_requests: false,
             replay_batch_timeout: self.config.replay_batch_timeout,
+            replay_batch_size: self.config.replay_batch_size,
 
             concurrent_replays: 0,
             max_concurrent_replays: self.config.concurrent_replays,
+            shard_hasher,
+            eviction_policy: self.config.eviction_policy,
+            partial_state_limit: self.config.partial_state_limit,
             replay_request_queue: Default::default(),
             delayed_for_self: Default::default(),
 
             group_commit_queues,
+            #[cfg(feature = "replay_ordering")]
+            packet_order,
 
             state_size: state_size,
+            replays_handled: 0,
+            last_processed: HashMap::default(),
             total_time: Timer::new(),
             total_ptime: Timer::new(),
             wait_time: Timer::new(),
             process_times: TimerSet::new(),
             process_ptimes: TimerSet::new(),
-        }
+        })
     }
 }
 
@@ -219,22 +268,34 @@ pub struct Domain {
 
     concurrent_replays: usize,
     max_concurrent_replays: usize,
+    shard_hasher: ShardHasher,
+    eviction_policy: EvictionPolicy,
+    partial_state_limit: Option<u64>,
     replay_request_queue: VecDeque<(Tag, Vec<DataType>)>,
 
     shutdown_valve: Valve,
     readers: Readers,
     _debug_tx: Option<TcpSender<api::debug::trace::Event>>,
+    /// Traced packets that enter this domain report their events here.
+    trace_tx: TraceSender<api::debug::trace::Event>,
+    trace_rx: mpsc::Receiver<api::debug::trace::Event>,
+    latencies: LatencyCollector,
     control_reply_tx: TcpSender<ControlReplyPacket>,
     channel_coordinator: Arc<ChannelCoordinator>,
 
     buffered_replay_requests: HashMap<Tag, (time::Instant, HashSet<Vec<DataType>>)>,
     has_buffered_replay_requests: bool,
     replay_batch_timeout: time::Duration,
+    replay_batch_size: usize,
     delayed_for_self: VecDeque<Box<Packet>>,
 
     group_commit_queues: GroupCommitQueueSet,
+    #[cfg(feature = "replay_ordering")]
+    packet_order: Option<ordering::Gate>,
 
     state_size: Arc<AtomicUsize>,
+    replays_handled: u64,
+    last_processed: HashMap<LocalNodeIndex, time::SystemTime>,
     total_time: Timer<SimpleTracker, RealTime>,
     total_ptime: Timer<SimpleTracker, ThreadTime>,
     wait_time: Timer<SimpleTracker, RealTime>,
@@ -243,6 +304,21 @@ pub struct Domain {
 }
 
 impl Domain {
+    /// Have a traced packet that just entered this domain report its events to this domain.
+    fn start_trace(&self, m: &mut Packet) {
+        if let Some(&mut Some((_, ref mut sender))) = m.tracer() {
+            *sender = Some(self.trace_tx.clone());
+        }
+    }
+
+    /// Account for the trace events reported since the last call.
+    fn collect_trace_events(&mut self) {
+        for event in self.trace_rx.try_iter() {
+            self.latencies.record(&event);
+        }
+        self.latencies.expire(time::Instant::now());
+    }
+
     fn find_tags_and_replay(
         &mut self,
         miss_key: Vec<DataType>,
@@ -392,7 +468,7 @@ impl Domain {
                 0
             } else {
                 assert_eq!(key.len(), 1);
-                ::shard_by(&key[0], options.len())
+                self.shard_hasher.shard(&key[0], options.len())
             };
             self.concurrent_replays += 1;
             trace!(self.log, "sending replay request";
@@ -532,6 +608,7 @@ impl Domain {
             assert_eq!(captured.len(), 0);
             self.process_ptimes.stop();
             self.process_times.stop();
+            self.last_processed.insert(me, time::SystemTime::now());
 
             if m.is_none() {
                 // no need to deal with our children if we're not sending them anything
@@ -642,7 +719,7 @@ impl Domain {
         }
 
         match m.as_ref().unwrap() {
-            m @ &box Packet::Message { .. } if m.is_empty() => {
+            m @ &box Packet::Message { write: None, .. } if m.is_empty() => {
                 // no need to deal with our children if we're not sending them anything
                 return output_messages;
             }
@@ -712,13 +789,25 @@ impl Domain {
         self.wait_time.stop();
         m.trace(PacketEvent::Handle);
 
+        #[cfg(feature = "replay_ordering")]
+        let (m, released) = match self.order_packet(m, top) {
+            Some(admitted) => admitted,
+            None => {
+                self.wait_time.start();
+                return;
+            }
+        };
+
         match *m {
             Packet::Message { .. } | Packet::Input { .. } => {
                 // WO for https://github.com/rust-lang/rfcs/issues/1403
                 self.dispatch(m, true, sends, Some(executor));
+                self.enforce_partial_state_limit(sends);
+                self.collect_trace_events();
             }
             Packet::ReplayPiece { .. } => {
                 self.handle_replay(m, sends);
+                self.enforce_partial_state_limit(sends);
             }
             Packet::Evict { .. } | Packet::EvictKeys { .. } => {
                 self.handle_eviction(m, sends);
@@ -726,7 +815,11 @@ impl Domain {
             consumed => {
                 match consumed {
                     // workaround #16223
-                    Packet::AddNode { node, parents } => {
+                    Packet::AddNode { mut node, parents } => {
+                        if node.is_sharder() {
+                            let shard_hasher = self.shard_hasher;
+                            node.with_sharder_mut(|s| s.set_hasher(shard_hasher));
+                        }
                         let addr = *node.local_addr();
                         self.not_ready.insert(addr);
 
@@ -742,6 +835,18 @@ impl Domain {
                     }
                     Packet::RemoveNodes { nodes } => {
                         for node in &nodes {
+                            // clients that still have a handle for a removed reader should be
+                            // told that it is gone, rather than that it is not yet ready.
+                            let n = self.nodes[node].borrow();
+                            if n.is_reader() {
+                                let gid = n.global_addr();
+                                self.readers
+                                    .lock()
+                                    .unwrap()
+                                    .remove(&(gid, *self.shard.as_ref().unwrap_or(&0)));
+                            }
+                            drop(n);
+
                             self.nodes[node].borrow_mut().remove();
                             self.state.remove(node);
                             trace!(self.log, "node removed"; "local" => node.id());
@@ -799,6 +904,10 @@ impl Domain {
                             }
                         });
                     }
+                    Packet::RemoveEgressTx { node, dst } => {
+                        let mut n = self.nodes[&node].borrow_mut();
+                        n.with_egress_mut(|e| e.remove_tx(dst));
+                    }
                     Packet::UpdateSharder { node, new_txs } => {
                         let mut n = self.nodes[&node].borrow_mut();
                         n.with_sharder_mut(move |s| {
@@ -827,7 +936,9 @@ impl Domain {
                         match state {
                             InitialState::PartialLocal(index) => {
                                 if !self.state.contains_key(&node) {
-                                    self.state.insert(node, box MemoryState::default());
+                                    let mut state = MemoryState::default();
+                                    state.set_eviction_policy(self.eviction_policy);
+                                    self.state.insert(node, box state);
                                 }
                                 let state = self.state.get_mut(&node).unwrap();
                                 for (key, tags) in index {
@@ -856,6 +967,7 @@ impl Domain {
                             } => {
                                 use backlog;
                                 let k = key.clone(); // ugh
+                                let shard_hasher = self.shard_hasher;
                                 let txs = (0..shards)
                                     .map(|shard| {
                                         let key = key.clone();
@@ -906,7 +1018,7 @@ impl Domain {
                                         } else {
                                             // TODO: compound reader
                                             assert_eq!(miss.len(), 1);
-                                            &txs[::shard_by(&miss[0], n)]
+                                            &txs[shard_hasher.shard(&miss[0], n)]
                                         };
                                         tx.unbounded_send(Vec::from(miss)).unwrap();
                                     });
@@ -1127,6 +1239,7 @@ impl Domain {
                                 r
                             };
 
+                            let batch_size = self.replay_batch_size;
                             thread::Builder::new()
                                 .name(format!(
                                     "replay{}.{}",
@@ -1149,7 +1262,7 @@ impl Domain {
                                     let start = time::Instant::now();
                                     debug!(log, "starting state chunker"; "node" => %link.dst);
 
-                                    let iter = state.into_iter().chunks(BATCH_SIZE);
+                                    let iter = state.into_iter().chunks(batch_size);
                                     let mut iter = iter.into_iter().enumerate().peekable();
 
                                     // process all records in state to completion within domain
@@ -1240,10 +1353,14 @@ impl Domain {
                             .unwrap();
                     }
                     Packet::GetStatistics => {
+                        self.collect_trace_events();
+                        let latencies = self.latencies.stats();
                         let domain_stats = api::debug::stats::DomainStats {
                             total_time: self.total_time.num_nanoseconds(),
                             total_ptime: self.total_ptime.num_nanoseconds(),
                             wait_time: self.wait_time.num_nanoseconds(),
+                            replays: self.replays_handled,
+                            reader_latency: latencies.end_to_end,
                         };
 
                         let node_stats = self
@@ -1256,16 +1373,23 @@ impl Domain {
 
                                 let time = self.process_times.num_nanoseconds(local_index);
                                 let ptime = self.process_ptimes.num_nanoseconds(local_index);
-                                let mem_size = if n.is_reader() {
-                                    let mut size = 0;
-                                    n.with_reader(|r| size = r.state_size().unwrap_or(0))
-                                        .unwrap();
-                                    size
+                                let last_processed = self
+                                    .last_processed
+                                    .get(&local_index)
+                                    .and_then(|t| t.duration_since(time::UNIX_EPOCH).ok())
+                                    .map(|d| d.as_secs() * 1_000 + u64::from(d.subsec_millis()));
+                                let (mem_size, rows) = if n.is_reader() {
+                                    n.with_reader(|r| {
+                                        (
+                                            r.state_size().unwrap_or(0),
+                                            r.state_rows().unwrap_or(0) as u64,
+                                        )
+                                    }).unwrap()
                                 } else {
                                     self.state
                                         .get(&local_index)
-                                        .map(|state| state.deep_size_of())
-                                        .unwrap_or(0)
+                                        .map(|state| (state.deep_size_of(), state.rows() as u64))
+                                        .unwrap_or((0, 0))
                                 };
 
                                 let mat_state = if !n.is_reader() {
@@ -1289,15 +1413,28 @@ impl Domain {
                                     }).unwrap()
                                 };
 
-                                if time.is_some() && ptime.is_some() {
+                                // materialized nodes and nodes that have seen traffic are always
+                                // reported, so that their sizes and staleness are known even when
+                                // we are not profiling
+                                if (time.is_some() && ptime.is_some())
+                                    || mat_state != MaterializationStatus::Not
+                                    || last_processed.is_some()
+                                {
                                     Some((
                                         node_index,
                                         api::debug::stats::NodeStats {
                                             desc: format!("{:?}", n),
-                                            process_time: time.unwrap(),
-                                            process_ptime: ptime.unwrap(),
+                                            process_time: time.unwrap_or(0),
+                                            process_ptime: ptime.unwrap_or(0),
                                             mem_size: mem_size,
+                                            rows: rows,
                                             materialized: mat_state,
+                                            last_packet_processed: last_processed,
+                                            latency: latencies
+                                                .nodes
+                                                .get(&node_index)
+                                                .cloned()
+                                                .unwrap_or_default(),
                                         },
                                     ))
                                 } else {
@@ -1312,6 +1449,11 @@ impl Domain {
                     Packet::UpdateStateSize => {
                         self.update_state_sizes();
                     }
+                    Packet::Sync { id } => {
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Synced(id))
+                            .unwrap();
+                    }
                     Packet::Quit => unreachable!("Quit messages are handled by event loop"),
                     Packet::Spin => {
                         // spinning as instructed
@@ -1347,6 +1489,10 @@ impl Domain {
             }
         }
 
+        // packets that were held back go after anything this packet caused to be sent locally
+        #[cfg(feature = "replay_ordering")]
+        self.delayed_for_self.extend(released.into_iter().flat_map(|held| held));
+
         if top {
             while let Some(m) = self.delayed_for_self.pop_front() {
                 trace!(self.log, "handling local transmission");
@@ -1361,6 +1507,23 @@ impl Domain {
         self.wait_time.start();
     }
 
+    /// Holds back packets that the domain's `PacketOrder` says must wait. Returns the packet if it
+    /// can be handled now, along with any held packets that it releases.
+    #[cfg(feature = "replay_ordering")]
+    fn order_packet(
+        &mut self,
+        m: Box<Packet>,
+        top: bool,
+    ) -> Option<(Box<Packet>, Option<VecDeque<Box<Packet>>>)> {
+        match self.packet_order {
+            Some(ref mut gate) if top => gate.admit(m).map(|m| {
+                let released = gate.handling(&m);
+                (m, released)
+            }),
+            _ => Some((m, None)),
+        }
+    }
+
     fn seed_row<'a>(&self, source: LocalNodeIndex, row: Cow<'a, [DataType]>) -> Record {
         if let Some(&(start, ref defaults)) = self.ingress_inject.get(&source) {
             let mut v = Vec::with_capacity(start + defaults.len());
@@ -1555,6 +1718,7 @@ impl Domain {
     }
 
     fn handle_replay(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends) {
+        self.replays_handled += 1;
         let tag = m.tag().unwrap();
         if self.nodes[&self.replay_paths[&tag].path.last().unwrap().node]
             .borrow()
@@ -1618,6 +1782,12 @@ impl Domain {
                         );
                     } else {
                         debug!(self.log, "replaying batch"; "#" => data.len());
+                        if notify_done && !data.is_empty() {
+                            // let the controller know how far along this full replay is
+                            self.control_reply_tx
+                                .send(ControlReplyPacket::ReplayProgress(tag, data.len()))
+                                .unwrap();
+                        }
                     }
 
                     // let's collect some information about the destination of this replay
@@ -1741,6 +1911,7 @@ impl Domain {
                             sends,
                             None,
                         );
+                        self.last_processed.insert(segment.node, time::SystemTime::now());
 
                         // ignore duplicate misses
                         misses.sort_unstable_by(|a, b| {
@@ -2177,6 +2348,32 @@ impl Domain {
         }
     }
 
+    /// Evict keys from every partially materialized node whose state has grown beyond the
+    /// configured limit, until it is back within the limit.
+    fn enforce_partial_state_limit(&mut self, sends: &mut EnqueuedSends) {
+        let limit = match self.partial_state_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let over: Vec<_> = self
+            .state
+            .iter()
+            .filter(|&(_, s)| s.is_partial() && s.deep_size_of() > limit)
+            .map(|(node, s)| (node, s.deep_size_of() - limit))
+            .collect();
+        for (node, excess) in over {
+            trace!(self.log, "partial state over limit"; "node" => node.id(), "excess" => excess);
+            self.handle_eviction(
+                box Packet::Evict {
+                    node: Some(node),
+                    num_bytes: excess as usize,
+                },
+                sends,
+            );
+        }
+    }
+
     pub fn handle_eviction(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends) {
         fn trigger_downstream_evictions(
             log: &Logger,
@@ -2312,7 +2509,7 @@ impl Domain {
                             }
                         } else {
                             let (key_columns, keys, bytes) = {
-                                let k = self.state[&node].evict_random_keys(100);
+                                let k = self.state[&node].evict_keys_by_policy(100);
                                 (k.0.to_vec(), k.1, k.2)
                             };
                             freed += bytes;
@@ -2466,13 +2663,21 @@ impl Domain {
                 });
                 ProcessResult::KeepPolling
             }
-            PollEvent::Process(packet) => {
+            PollEvent::Process(mut packet) => {
                 if let Packet::Quit = *packet {
+                    // let the controller know that we are going away. it may already be gone
+                    // itself, so don't unwrap.
+                    drop(self.control_reply_tx.send(ControlReplyPacket::ack()));
                     return ProcessResult::StopPolling;
                 }
 
-                // TODO: Initialize tracer here, and when flushing group commit
-                // queue.
+                self.start_trace(&mut packet);
+                if let Packet::Input { ref mut inner, .. } = *packet {
+                    let n = self.nodes[&inner.link.dst].borrow();
+                    if let Some(b) = n.get_base() {
+                        b.fill_defaults(&mut inner.data);
+                    }
+                }
                 if self.group_commit_queues.should_append(&packet, &self.nodes) {
                     packet.trace(PacketEvent::ExitInputChannel);
                     let merged_packet = self.group_commit_queues.append(packet);
@@ -0,0 +1,91 @@
+//! Control over the order in which a domain handles partial replays and regular updates.
+//!
+//! Whether a partial materialization bug shows up often depends on how upstream updates
+//! interleave with replays, which is normally down to timing. Tests can make a domain hold back
+//! one kind of packet until it has handled enough of the other kind, which makes that
+//! interleaving deterministic. This is only compiled with the `replay_ordering` feature.
+
+use std::collections::VecDeque;
+
+use payload::{Packet, ReplayPieceContext};
+
+/// The order in which a domain handles partial replays and regular updates.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PacketOrder {
+    /// Hold back partial replay requests and responses until this many regular updates have been
+    /// handled.
+    ReplaysAfterUpdates(usize),
+    /// Hold back regular updates until this many partial replay requests and responses have been
+    /// handled.
+    UpdatesAfterReplays(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Replay,
+    Update,
+}
+
+impl Kind {
+    fn of(m: &Packet) -> Option<Kind> {
+        match *m {
+            Packet::Input { .. } | Packet::Message { .. } => Some(Kind::Update),
+            Packet::RequestReaderReplay { .. } | Packet::RequestPartialReplay { .. } => {
+                Some(Kind::Replay)
+            }
+            Packet::ReplayPiece {
+                context: ReplayPieceContext::Partial { .. },
+                ..
+            } => Some(Kind::Replay),
+            _ => None,
+        }
+    }
+}
+
+/// Holds back the packets that a `PacketOrder` says must wait.
+pub(super) struct Gate {
+    wait_for: Kind,
+    remaining: usize,
+    held: VecDeque<Box<Packet>>,
+}
+
+impl Gate {
+    pub(super) fn new(order: PacketOrder) -> Self {
+        let (wait_for, remaining) = match order {
+            PacketOrder::ReplaysAfterUpdates(n) => (Kind::Update, n),
+            PacketOrder::UpdatesAfterReplays(n) => (Kind::Replay, n),
+        };
+        Gate {
+            wait_for,
+            remaining,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Returns the packet if it may be handled now, and holds on to it otherwise.
+    pub(super) fn admit(&mut self, m: Box<Packet>) -> Option<Box<Packet>> {
+        match Kind::of(&m) {
+            Some(kind) if self.remaining > 0 && kind != self.wait_for => {
+                self.held.push_back(m);
+                None
+            }
+            _ => Some(m),
+        }
+    }
+
+    /// Records that a packet of the given kind is about to be handled.
+    ///
+    /// Once the last packet that the held packets wait for has been handled, the held packets are
+    /// returned in the order they arrived in.
+    pub(super) fn handling(&mut self, m: &Packet) -> Option<VecDeque<Box<Packet>>> {
+        if self.remaining == 0 || Kind::of(m) != Some(self.wait_for) {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            Some(self.held.split_off(0))
+        } else {
+            None
+        }
+    }
+}
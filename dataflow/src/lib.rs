@@ -54,6 +54,8 @@ pub type Readers = Arc<Mutex<HashMap<(basics::NodeIndex, usize), backlog::Single
 pub type DomainConfig = domain::Config;
 
 pub use domain::{Domain, DomainBuilder, Index};
+#[cfg(feature = "replay_ordering")]
+pub use domain::PacketOrder;
 pub use payload::{LocalBypass, Packet};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -91,6 +93,13 @@ pub enum DurabilityMode {
     Permanent,
 }
 
+/// Compression algorithm used for the on-disk state of persisted base tables.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Compression {
+    Lz4,
+    Zstd,
+}
+
 /// Parameters to control the operation of GroupCommitQueue.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct PersistenceParameters {
@@ -106,6 +115,13 @@ pub struct PersistenceParameters {
     pub log_dir: Option<PathBuf>,
     /// Number of background threads PersistentState can use (shared acrosss all worker threads).
     pub persistence_threads: i32,
+    /// How to compress base table data as it is flushed to disk. Recovery reads the data back
+    /// transparently, but the same setting must be used each time a log is opened.
+    pub compression: Option<Compression>,
+    /// Size in bytes of the buffer that a base table's writes are collected in before they are
+    /// flushed to a new table file on disk, which bounds the size of each such file. Recovery
+    /// reads all of a base's table files. Defaults to RocksDB's own limits.
+    pub max_table_file_bytes: Option<usize>,
 }
 
 impl Default for PersistenceParameters {
@@ -117,6 +133,8 @@ impl Default for PersistenceParameters {
             log_prefix: String::from("soup"),
             log_dir: None,
             persistence_threads: 1,
+            compression: None,
+            max_table_file_bytes: None,
         }
     }
 }
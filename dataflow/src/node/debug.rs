@@ -18,10 +18,15 @@ impl fmt::Debug for Node {
 }
 
 impl Node {
+    /// Describe this node as a graphviz node.
+    ///
+    /// If `size` is given as the number of rows and bytes in the node's state, it is shown next to
+    /// the node.
     pub fn describe(
         &self,
         idx: NodeIndex,
         materialization_status: MaterializationStatus,
+        size: Option<(u64, u64)>,
     ) -> String {
         let mut s = String::new();
         let border = match self.sharded_by {
@@ -34,13 +39,17 @@ impl Node {
         };
 
         s.push_str(&format!(
-            " [style=\"{}\", fillcolor={}, label=\"",
+            " [style=\"{}\", fillcolor={}, ",
             border,
             self.domain
                 .map(|d| -> usize { d.into() })
                 .map(|d| format!("\"/set312/{}\"", (d % 12) + 1))
                 .unwrap_or("white".into())
         ));
+        if let Some((rows, bytes)) = size {
+            s.push_str(&format!("xlabel=\"{} rows\\n{} B\", ", rows, bytes));
+        }
+        s.push_str("label=\"");
 
         let materialized = match materialization_status {
             MaterializationStatus::Not => "",
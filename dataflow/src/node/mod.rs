@@ -247,6 +247,10 @@ impl Node {
         &*self.name
     }
 
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+
     pub fn fields(&self) -> &[String] {
         &self.fields[..]
     }
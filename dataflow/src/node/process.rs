@@ -1,3 +1,4 @@
+use api;
 use fnv::FnvHashMap;
 use node::NodeType;
 use payload;
@@ -17,9 +18,9 @@ impl Node {
         output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
         executor: Option<&mut Executor>,
     ) -> (Vec<Miss>, HashSet<Vec<DataType>>) {
-        m.as_mut().unwrap().trace(PacketEvent::Process);
-
         let addr = *self.local_addr();
+        let gaddr = self.global_addr();
+        m.as_mut().unwrap().trace(PacketEvent::Process(gaddr));
         match self.inner {
             NodeType::Ingress => {
                 let m = m.as_mut().unwrap();
@@ -51,9 +52,12 @@ impl Node {
                         }
 
                         // Send write-ACKs to all the clients with updates that made
-                        // it into this merged packet:
+                        // it into this merged packet. A persistent base has already synced the
+                        // writes to disk in materialize() above, so an ACK means they are durable:
+                        let generation = state.get(&addr).map(|s| s.generation()).unwrap_or(0);
+                        let seq = b.next_write(generation);
                         if let Some(ex) = executor {
-                            senders.drain(..).for_each(|src| ex.send_back(src, ()));
+                            senders.drain(..).for_each(|src| ex.send_back(src, seq));
                         }
 
                         *m = Some(Box::new(Packet::Message {
@@ -62,6 +66,11 @@ impl Node {
                             data: rs,
                             tracer,
                             senders,
+                            write: Some(api::WriteId {
+                                base: gaddr,
+                                shard: on_shard.unwrap_or(0),
+                                seq,
+                            }),
                         }));
                     }
                     Some(ref p) => {
@@ -223,7 +232,15 @@ impl Node {
                 (misses, captured)
             }
             NodeType::Dropped => {
-                *m = None;
+                // writers that still have a handle for a removed base are waiting for an ACK, so
+                // tell them that their writes were rejected.
+                if let Some(box Packet::Input { mut senders, .. }) = m.take() {
+                    if let Some(ex) = executor {
+                        senders
+                            .drain(..)
+                            .for_each(|src| ex.send_back(src, api::REJECTED_WRITE));
+                    }
+                }
                 (vec![], HashSet::new())
             }
             NodeType::Source => unreachable!(),
@@ -1,9 +1,14 @@
+use nom_sql::Literal;
 use prelude::*;
 use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::mem;
 use vec_map::VecMap;
 
+/// Position of the state generation within write sequence numbers; see `Base::next_write`.
+const WRITE_GENERATION_SHIFT: u32 = 40;
+
 /// Base is used to represent the root nodes of the distributary data flow graph.
 ///
 /// These nodes perform no computation, and their job is merely to persist all received updates and
@@ -14,8 +19,14 @@ pub struct Base {
     primary_key: Option<Vec<usize>>,
 
     defaults: Vec<DataType>,
+    /// Columns whose default is the time at which a row is inserted.
+    timestamp_defaults: Vec<usize>,
     dropped: Vec<usize>,
     unmodified: bool,
+
+    /// Number of writes applied to this base (shard) since its state was last opened.
+    #[serde(skip)]
+    writes: u64,
 }
 
 impl Base {
@@ -32,6 +43,15 @@ impl Base {
         self
     }
 
+    /// Builder with columns that default to `CURRENT_TIMESTAMP`.
+    ///
+    /// Inserted rows that omit these columns are given the time of the insert, rather than the
+    /// fixed default value.
+    pub fn with_timestamp_defaults(mut self, columns: Vec<usize>) -> Base {
+        self.timestamp_defaults = columns;
+        self
+    }
+
     pub fn key(&self) -> Option<&[usize]> {
         self.primary_key.as_ref().map(|cols| &cols[..])
     }
@@ -81,6 +101,54 @@ impl Base {
             row.extend(self.defaults.iter().skip(rlen).cloned());
         }
     }
+
+    /// Fill in default values for any columns omitted from the inserted rows in `ops`, and turn
+    /// `TableOperation::InsertWithDefaults` into plain inserts.
+    ///
+    /// Domains call this as soon as a write arrives, before it is queued for persistence, so that
+    /// `CURRENT_TIMESTAMP` defaults are evaluated once and the same values are persisted and
+    /// propagated.
+    pub(crate) fn fill_defaults(&self, ops: &mut [TableOperation]) {
+        for op in ops {
+            let row = match *op {
+                TableOperation::Insert(ref mut row)
+                | TableOperation::InsertOrUpdate { ref mut row, .. } => {
+                    self.fill_row_defaults(row);
+                    continue;
+                }
+                TableOperation::InsertWithDefaults {
+                    ref mut row,
+                    ref omitted,
+                } => {
+                    for &col in omitted {
+                        row[col] = self.default_value(col);
+                    }
+                    self.fill_row_defaults(row);
+                    mem::replace(row, Vec::new())
+                }
+                _ => continue,
+            };
+            *op = TableOperation::Insert(row);
+        }
+    }
+
+    fn fill_row_defaults(&self, row: &mut Vec<DataType>) {
+        let rlen = row.len();
+        if rlen >= self.defaults.len() {
+            return;
+        }
+
+        row.extend((rlen..self.defaults.len()).map(|col| self.default_value(col)));
+    }
+
+    /// The value that column `col` is given in a row inserted now that leaves it out.
+    fn default_value(&self, col: usize) -> DataType {
+        if self.timestamp_defaults.contains(&col) {
+            DataType::from(Literal::CurrentTimestamp)
+        } else {
+            self.defaults[col].clone()
+        }
+    }
 }
 
 /// A Base clone must have a different unique_id so that no two copies write to the same file.
@@ -92,8 +160,10 @@ impl Clone for Base {
             primary_key: self.primary_key.clone(),
 
             defaults: self.defaults.clone(),
+            timestamp_defaults: self.timestamp_defaults.clone(),
             dropped: self.dropped.clone(),
             unmodified: self.unmodified,
+            writes: self.writes,
         }
     }
 }
@@ -104,18 +174,42 @@ impl Default for Base {
             primary_key: None,
 
             defaults: Vec::new(),
+            timestamp_defaults: Vec::new(),
             dropped: Vec::new(),
             unmodified: true,
+            writes: 0,
         }
     }
 }
 
 fn key_val(i: usize, col: usize, r: &TableOperation) -> &DataType {
     match *r {
-        TableOperation::Insert(ref row) => &row[col],
+        TableOperation::Insert(ref row) | TableOperation::InsertWithDefaults { ref row, .. } => {
+            &row[col]
+        }
         TableOperation::Delete { ref key } => &key[i],
         TableOperation::Update { ref key, .. } => &key[i],
         TableOperation::InsertOrUpdate { ref row, .. } => &row[col],
+        TableOperation::DeleteWhere { .. } => unreachable!("conditional deletes have no key"),
+    }
+}
+
+/// Find all rows in `db` that match every `(column, value)` pair in `predicate`.
+///
+/// If `db` has an index on exactly the predicate columns, that index is used. Otherwise, all rows
+/// are scanned.
+fn rows_where(db: &State, predicate: &[(usize, DataType)]) -> Vec<Vec<DataType>> {
+    let cols: Vec<usize> = predicate.iter().map(|&(col, _)| col).collect();
+    if db.keys().iter().any(|k| k[..] == cols[..]) {
+        match db.lookup(&cols[..], &KeyType::from(predicate.iter().map(|&(_, ref v)| v))) {
+            LookupResult::Some(rows) => rows.into_iter().map(|r| r.into_owned()).collect(),
+            LookupResult::Missing => unreachable!("base state is never partial"),
+        }
+    } else {
+        db.cloned_records()
+            .into_iter()
+            .filter(|r| predicate.iter().all(|&(col, ref v)| r[col] == *v))
+            .collect()
     }
 }
 
@@ -131,18 +225,30 @@ impl Base {
         Clone::clone(self)
     }
 
+    /// Assign the next sequence number to a write to this base, whose state is in the given
+    /// generation (see `State::generation`).
+    ///
+    /// The count of writes starts over when the base is recovered, so the generation goes in the
+    /// upper bits. That way, sequence numbers keep growing across recoveries, and tokens for
+    /// writes from before a recovery are satisfied by the writes after it.
+    pub(crate) fn next_write(&mut self, generation: u64) -> u64 {
+        self.writes += 1;
+        (generation << WRITE_GENERATION_SHIFT) + self.writes
+    }
+
     pub(crate) fn process(
         &mut self,
         us: LocalNodeIndex,
         mut ops: Vec<TableOperation>,
         state: &StateMap,
     ) -> Records {
+        self.fill_defaults(&mut ops);
+
         if self.primary_key.is_none() || ops.is_empty() {
             return ops
                 .into_iter()
                 .map(|r| {
-                    if let TableOperation::Insert(mut r) = r {
-                        self.fix(&mut r);
+                    if let TableOperation::Insert(r) = r {
                         Record::Positive(r)
                     } else {
                         unreachable!("unkeyed base got non-insert operation {:?}", r);
@@ -151,16 +257,39 @@ impl Base {
         }
 
         let key_cols = &self.primary_key.as_ref().unwrap()[..];
-        ops.sort_by(|a, b| key_of(key_cols, a).cmp(key_of(key_cols, b)));
-
-        // starting key
-        let mut this_key: Vec<_> = key_of(key_cols, &ops[0]).cloned().collect();
 
         // starting record state
         let db = state
             .get(&us)
             .expect("base with primary key must be materialized");
 
+        // conditional deletes turn into deletes of each matching row. note that the condition is
+        // evaluated against the state *before* any of the operations in this batch are applied.
+        let is_conditional = |op: &TableOperation| match *op {
+            TableOperation::DeleteWhere { .. } => true,
+            _ => false,
+        };
+        if ops.iter().any(is_conditional) {
+            ops = ops
+                .into_iter()
+                .flat_map(|op| match op {
+                    TableOperation::DeleteWhere { predicate } => rows_where(&**db, &predicate)
+                        .into_iter()
+                        .map(|r| TableOperation::Delete {
+                            key: key_cols.iter().map(|&col| r[col].clone()).collect(),
+                        }).collect(),
+                    op => vec![op],
+                }).collect();
+            if ops.is_empty() {
+                return Records::default();
+            }
+        }
+
+        ops.sort_by(|a, b| key_of(key_cols, a).cmp(key_of(key_cols, b)));
+
+        // starting key
+        let mut this_key: Vec<_> = key_of(key_cols, &ops[0]).cloned().collect();
+
         let get_current = |current_key: &'_ _| {
             match db.lookup(key_cols, &KeyType::from(current_key)) {
                 LookupResult::Some(rows) => {
@@ -224,6 +353,12 @@ impl Base {
                     }
                     update
                 }
+                TableOperation::DeleteWhere { .. } => {
+                    unreachable!("conditional deletes are turned into deletes above")
+                }
+                TableOperation::InsertWithDefaults { .. } => {
+                    unreachable!("defaults are filled in before operations are applied")
+                }
             };
 
             if current.is_none() {
@@ -305,7 +440,56 @@ mod tests {
         assert_eq!(b.unmodified, true);
     }
 
-    fn test_lots_of_changes_in_same_batch(mut state: Box<State>) {
+    #[test]
+    fn it_fills_omitted_columns() {
+        let b = Base::new(vec![DataType::None, 7.into(), DataType::None])
+            .with_timestamp_defaults(vec![2]);
+
+        let mut row = vec![1.into()];
+        b.fill_row_defaults(&mut row);
+        assert_eq!(row.len(), 3);
+        assert_eq!(row[1], 7.into());
+        match row[2] {
+            DataType::Timestamp(_) => {}
+            ref dt => panic!("expected a timestamp, got {:?}", dt),
+        }
+
+        // complete rows are left alone
+        let mut row = vec![1.into(), 2.into(), 3.into()];
+        b.fill_row_defaults(&mut row);
+        assert_eq!(row, vec![1.into(), 2.into(), 3.into()]);
+    }
+
+    #[test]
+    fn it_fills_columns_left_out_of_the_middle() {
+        let b = Base::new(vec![DataType::None, 7.into(), DataType::None, 9.into()])
+            .with_timestamp_defaults(vec![2]);
+
+        let mut ops = vec![TableOperation::InsertWithDefaults {
+            row: vec![1.into(), DataType::None, DataType::None],
+            omitted: vec![1, 2],
+        }];
+        b.fill_defaults(&mut ops);
+        match ops[0] {
+            TableOperation::Insert(ref row) => {
+                assert_eq!(row.len(), 4);
+                assert_eq!(row[1], 7.into());
+                match row[2] {
+                    DataType::Timestamp(_) => {}
+                    ref dt => panic!("expected a timestamp, got {:?}", dt),
+                }
+                assert_eq!(row[3], 9.into());
+            }
+            ref op => panic!("expected a plain insert, got {:?}", op),
+        }
+    }
+
+    /// Set up a base with columns x, y and z that is keyed by `key` and materialized in `state`,
+    /// and return a function that has it process a batch of operations.
+    fn base_processor(
+        key: Vec<usize>,
+        mut state: Box<State>,
+    ) -> impl FnMut(Vec<TableOperation>) -> Records {
         use node;
         use prelude::*;
         use std::collections::HashMap;
@@ -318,7 +502,7 @@ mod tests {
             node::NodeType::Source,
         ));
 
-        let b = Base::new(vec![]).with_key(vec![0, 2]);
+        let b = Base::new(vec![]).with_key(key);
         let global = graph.add_node(Node::new("b", &["x", "y", "z"], b));
         graph.add_edge(source, global, ());
         let local = unsafe { LocalNodeIndex::make(0 as u32) };
@@ -343,12 +527,38 @@ mod tests {
         let n = graph[global].take();
         let mut n = n.finalize(&graph);
 
-        let mut one = move |u: Vec<TableOperation>| {
+        move |u: Vec<TableOperation>| {
             let mut m = n.get_base_mut().unwrap().process(local, u, &states);
             node::materialize(&mut m, None, states.get_mut(&local));
             m
-        };
+        }
+    }
+
+    #[test]
+    fn update_emits_old_and_new_row_together() {
+        let mut one = base_processor(vec![0], box MemoryState::default());
+        one(vec![TableOperation::Insert(vec![1.into(), 10.into(), 2.into()])]);
+
+        // the old row is retracted in the same batch that adds the new one, so nothing downstream
+        // ever sees the row missing
+        assert_eq!(
+            one(vec![TableOperation::Update {
+                key: vec![1.into()],
+                set: vec![
+                    Modification::None,
+                    Modification::None,
+                    Modification::Set(5.into()),
+                ],
+            }]),
+            vec![
+                Record::Negative(vec![1.into(), 10.into(), 2.into()]),
+                Record::Positive(vec![1.into(), 10.into(), 5.into()]),
+            ].into()
+        );
+    }
 
+    fn test_lots_of_changes_in_same_batch(state: Box<State>) {
+        let mut one = base_processor(vec![0, 2], state);
         assert_eq!(
             one(vec![
                 TableOperation::Insert(vec![1.into(), "a".into(), 1.into()]),
@@ -48,6 +48,13 @@ impl Egress {
         self.tags.insert(tag, dst);
     }
 
+    /// Stop sending to the ingress node `dst_g`, which is being removed.
+    ///
+    /// Tags that lead to it are kept, so that replays still underway along them are dropped here.
+    pub fn remove_tx(&mut self, dst_g: NodeIndex) {
+        self.txs.retain(|tx| tx.node != dst_g);
+    }
+
     pub fn process(
         &mut self,
         m: &mut Option<Box<Packet>>,
@@ -97,10 +104,22 @@ impl Egress {
             m.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
             m.link_mut().dst = tx.local;
 
+            // trace events are collected by the domain they happen in, and the receiving domain
+            // hands the packet its own sender
+            if let Some(&mut Some((_, ref mut sender))) = m.tracer() {
+                *sender = None;
+            }
+
             output.entry(tx.dest).or_default().push_back(m);
             if take {
                 break;
             }
         }
+
+        // the ingress node a replay was headed for may have been removed, and then there is no
+        // one left to send it to
+        if replay_to.is_some() {
+            m.take();
+        }
     }
 }
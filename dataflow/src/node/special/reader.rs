@@ -36,6 +36,8 @@ pub struct Reader {
 
     for_node: NodeIndex,
     state: Option<Vec<usize>>,
+    /// Whether this reader only provides a secondary index on `for_node`.
+    secondary: bool,
 }
 
 impl Clone for Reader {
@@ -46,6 +48,7 @@ impl Clone for Reader {
             streamers: self.streamers.clone(),
             state: self.state.clone(),
             for_node: self.for_node,
+            secondary: self.secondary,
         }
     }
 }
@@ -57,6 +60,15 @@ impl Reader {
             streamers: Vec::new(),
             state: None,
             for_node,
+            secondary: false,
+        }
+    }
+
+    /// A reader that provides a secondary index on `for_node`, next to its primary reader.
+    pub fn secondary(for_node: NodeIndex) -> Self {
+        Reader {
+            secondary: true,
+            ..Reader::new(for_node)
         }
     }
 
@@ -66,6 +78,10 @@ impl Reader {
         self.for_node
     }
 
+    pub fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
     #[allow(dead_code)]
     pub(crate) fn writer(&self) -> Option<&backlog::WriteHandle> {
         self.writer.as_ref()
@@ -126,6 +142,10 @@ impl Reader {
         self.writer.as_ref().map(|w| w.deep_size_of())
     }
 
+    pub fn state_rows(&self) -> Option<usize> {
+        self.writer.as_ref().map(|w| w.rows())
+    }
+
     /// Evict a randomly selected key, returning the number of bytes evicted.
     /// Note that due to how `evmap` applies the evictions asynchronously, we can only evict a
     /// single key at a time here.
@@ -202,6 +222,10 @@ impl Reader {
                 });
             }
 
+            if let Some(w) = m.write() {
+                state.saw_write(w);
+            }
+
             if self.streamers.is_empty() {
                 state.add(m.take_data());
             } else {
@@ -9,6 +9,7 @@ pub struct Sharder {
     txs: Vec<(LocalNodeIndex, ReplicaAddr)>,
     sharded: VecMap<Box<Packet>>,
     shard_by: usize,
+    hasher: ShardHasher,
 }
 
 impl Clone for Sharder {
@@ -19,6 +20,7 @@ impl Clone for Sharder {
             txs: Vec::new(),
             sharded: Default::default(),
             shard_by: self.shard_by,
+            hasher: self.hasher,
         }
     }
 }
@@ -29,6 +31,7 @@ impl Sharder {
             txs: Default::default(),
             shard_by: by,
             sharded: VecMap::default(),
+            hasher: ShardHasher::default(),
         }
     }
 
@@ -39,6 +42,7 @@ impl Sharder {
             txs: txs,
             sharded: VecMap::default(),
             shard_by: self.shard_by,
+            hasher: self.hasher,
         }
     }
 
@@ -54,6 +58,11 @@ impl Sharder {
         self.shard_by
     }
 
+    /// Set how keys are mapped to shards. This is done by the domain the sharder runs in.
+    pub fn set_hasher(&mut self, hasher: ShardHasher) {
+        self.hasher = hasher;
+    }
+
     #[inline]
     fn to_shard(&self, r: &Record) -> usize {
         self.shard(&r[self.shard_by])
@@ -61,7 +70,7 @@ impl Sharder {
 
     #[inline]
     fn shard(&self, dt: &DataType) -> usize {
-        ::shard_by(dt, self.txs.len())
+        self.hasher.shard(dt, self.txs.len())
     }
 
     pub fn process(
@@ -102,6 +111,10 @@ impl Sharder {
             // eventual shard merged! pretty unfortunate. TODO
             force_all = true;
         }
+        if m.write().is_some() {
+            // every shard needs to learn about the write, even those that get no records from it
+            force_all = true;
+        }
         if force_all {
             for shard in 0..self.txs.len() {
                 self.sharded
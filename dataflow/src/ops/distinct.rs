@@ -1,14 +1,20 @@
-use std::cmp::Ordering;
 use std::collections::HashMap;
 
 use prelude::*;
 
 /// This will get distinct records from a set of records compared over a given set of columns
+///
+/// The operator emits one record for each distinct group that is present in its parent. Which
+/// records make up a group is read from the parent's materialized state, so a group is only
+/// retracted once the last record for it has been removed there. If records in the same group
+/// differ in columns that are not part of the group, one of them is emitted, and it is replaced by
+/// another record of the group if it is itself removed.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Distinct {
     // Parent Node
     src: IndexPair,
 
+    // Current node
     us: Option<IndexPair>,
 
     group_by: Vec<usize>,
@@ -41,13 +47,12 @@ impl Ingredient for Distinct {
         from: LocalNodeIndex,
         rs: Records,
         _: &mut Tracer,
-        _: Option<&[usize]>,
+        replay_key_cols: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
-        // Check that the records aren't empty
         if rs.is_empty() {
             return ProcessingResult {
                 results: rs,
@@ -55,82 +60,90 @@ impl Ingredient for Distinct {
             };
         }
 
-        let us = self.us.unwrap();
-        let db = state
-            .get(&*us)
-            .expect("Distinct must have its own state initialized");
-
-        let pos_comp = |a: &Record, b: &Record| a.is_positive().cmp(&b.is_positive());
-
+        // handle all the records for a group at once, since we only need to know how the group
+        // looks once the whole batch has been applied.
+        let group_by = &self.group_by[..];
         let mut rs: Vec<_> = rs.into();
-        // Sort by positive or negative
-        rs.sort_by(&pos_comp);
-
-        let group_by = &self.group_by;
-
-        let group_cmp = |a: &Record, b: &Record| {
+        rs.sort_by(|a, b| {
             group_by
                 .iter()
                 .map(|&col| &a[col])
                 .cmp(group_by.iter().map(|&col| &b[col]))
-        };
+        });
 
-        // First, we want to be smart about multiple added/removed rows with same group.
-        // For example, if we get a -, then a +, for the same group, we don't want to
-        // execute two queries. We'll do this by sorting the batch by our group by.
-        rs.sort_by(&group_cmp);
+        let us = self.us.unwrap();
+        let db = state
+            .get(&*us)
+            .expect("distinct operators must have their own state materialized");
+        // our parent has already absorbed the batch, so its state is up to date.
+        let parent = state
+            .get(&*self.src)
+            .expect("distinct operators need their parent state");
 
+        let mut misses = Vec::new();
         let mut output = Vec::new();
-        let mut prev_grp = Vec::new();
-        let mut prev_pos = false;
-
-        for rec in rs {
-            let group_by = &self.group_by[..];
-            let group = rec
+        let mut start = 0;
+        while start < rs.len() {
+            let group: Vec<_> = group_by.iter().map(|&col| rs[start][col].clone()).collect();
+            let end = start + rs[start..]
                 .iter()
-                .enumerate()
-                .filter_map(|(i, v)| {
-                    if self.group_by.iter().any(|col| col == &i) {
-                        Some(v)
-                    } else {
-                        None
-                    }
-                }).cloned()
-                .collect::<Vec<_>>();
-
-            // Do not take overlapping elements
-            if prev_grp.iter().cmp(group_by.iter().map(|&col| &rec[col])) == Ordering::Equal
-                && prev_pos == rec.is_positive()
-            {
-                continue;
-            }
+                .take_while(|r| group_by.iter().zip(&group).all(|(&col, v)| &r[col] == v))
+                .count();
+            let group_rs = &rs[start..end];
+            start = end;
+
+            let miss = |on: IndexPair| {
+                group_rs
+                    .iter()
+                    .map(|r| Miss {
+                        on: *on,
+                        lookup_idx: group_by.to_vec(),
+                        lookup_cols: group_by.to_vec(),
+                        replay_cols: replay_key_cols.map(Vec::from),
+                        record: (**r).clone(),
+                    }).collect::<Vec<_>>()
+            };
 
-            // make ready for the new one
-            prev_grp.clear();
-            prev_grp.extend(group_by.iter().map(|&col| &rec[col]).cloned());
-            prev_pos = rec.is_positive().clone();
-
-            let positive = rec.is_positive();
-            match db.lookup(group_by, &KeyType::from(&group[..])) {
-                LookupResult::Some(rr) => {
-                    if positive {
-                        //println!("record {:?}", rr);
-                        if rr.len() == 0 {
-                            output.push(rec.clone());
-                        }
-                    } else {
-                        if rr.len() != 0 {
-                            output.push(rec.clone());
-                        }
+            // the record we currently emit for this group, if any
+            let current = match db.lookup(group_by, &KeyType::from(&group[..])) {
+                LookupResult::Some(rs) => {
+                    debug_assert!(rs.len() <= 1, "a group had more than 1 result");
+                    rs.into_iter().next().map(|r| r.into_owned())
+                }
+                LookupResult::Missing => {
+                    misses.extend(miss(us));
+                    continue;
+                }
+            };
+
+            let rows: Vec<_> = match parent.lookup(group_by, &KeyType::from(&group[..])) {
+                LookupResult::Some(rs) => rs.into_iter().collect(),
+                LookupResult::Missing => {
+                    misses.extend(miss(self.src));
+                    continue;
+                }
+            };
+
+            match current {
+                Some(ref current) if rows.iter().any(|r| &r[..] == &current[..]) => {
+                    // the record we emitted is still in the group
+                }
+                current => {
+                    // note that a negative for a group we never saw ends up here too, and then
+                    // does nothing, since the group is empty in our parent as well.
+                    if let Some(current) = current {
+                        output.push(Record::Negative(current));
+                    }
+                    if let Some(r) = rows.first() {
+                        output.push(Record::Positive(r.to_vec()));
                     }
                 }
-                LookupResult::Missing => unimplemented!("Distinct does not yet support partial"),
             }
         }
 
         ProcessingResult {
             results: output.into(),
-            misses: Vec::new(),
+            misses: misses,
         }
     }
 
@@ -141,7 +154,10 @@ impl Ingredient for Distinct {
     fn on_connected(&mut self, _: &Graph) {}
 
     fn on_commit(&mut self, us: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        // who's our parent really?
         self.src.remap(remap);
+
+        // who are we?
         self.us = Some(remap[&us]);
     }
 
@@ -158,9 +174,12 @@ impl Ingredient for Distinct {
     }
 
     fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
-        vec![(this, (self.group_by.clone(), true))]
-            .into_iter()
-            .collect()
+        // index by our group, and find all the records of a group in our parent
+        vec![
+            (this, (self.group_by.clone(), true)),
+            (self.src.as_global(), (self.group_by.clone(), true)),
+        ].into_iter()
+        .collect()
     }
 }
 
@@ -171,7 +190,7 @@ mod tests {
 
     use ops;
 
-    fn setup(materialized: bool) -> ops::test::MockGraph {
+    fn setup(materialized: bool) -> (ops::test::MockGraph, IndexPair) {
         let mut g = ops::test::MockGraph::new();
 
         let s = g.add_base("source", &["x", "y", "z"]);
@@ -181,77 +200,143 @@ mod tests {
             Distinct::new(s.as_global(), vec![1, 2]),
             materialized,
         );
-        g
+        (g, s)
+    }
+
+    // the domain updates our parent's state before we see a batch, so do the same here.
+    fn feed(g: &mut ops::test::MockGraph, s: IndexPair, rs: Vec<(Vec<DataType>, bool)>) -> Records {
+        let rs: Records = rs.into();
+        g.states
+            .get_mut(&*s)
+            .unwrap()
+            .process_records(&mut rs.clone(), None);
+        g.narrow_one(rs, true)
     }
 
     #[test]
     fn simple_distinct() {
-        let mut g = setup(true);
+        let (mut g, s) = setup(true);
 
         let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
         let r2: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
         let r3: Vec<DataType> = vec![1.into(), "c".into(), 2.into()];
 
-        let a = g.narrow_one_row(r1.clone(), true);
+        let a = feed(&mut g, s, vec![(r1.clone(), true)]);
         assert_eq!(a, vec![r1.clone()].into());
 
-        let a = g.narrow_one_row(r2.clone(), false);
+        let a = feed(&mut g, s, vec![(r2.clone(), true)]);
         assert_eq!(a.len(), 0);
 
-        let a = g.narrow_one_row(r3.clone(), true);
+        let a = feed(&mut g, s, vec![(r3.clone(), true)]);
         assert_eq!(a, vec![r3.clone()].into());
     }
 
     #[test]
     fn distinct_neg_record() {
-        let mut g = setup(true);
+        let (mut g, s) = setup(true);
 
         let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
         let r2: Vec<DataType> = vec![2.into(), "a".into(), 2.into()];
         let r3: Vec<DataType> = vec![3.into(), "c".into(), 2.into()];
 
-        let a = g.narrow_one_row(r1.clone(), true);
-        println!("{:?}", a);
+        let a = feed(&mut g, s, vec![(r1.clone(), true)]);
         assert_eq!(a, vec![r1.clone()].into());
 
-        let a = g.narrow_one_row(r2.clone(), true);
-        println!("{:?}", a);
+        let a = feed(&mut g, s, vec![(r2.clone(), true)]);
         assert_eq!(a, vec![r2.clone()].into());
 
-        let a = g.narrow_one_row(r3.clone(), true);
+        let a = feed(&mut g, s, vec![(r3.clone(), true)]);
         assert_eq!(a, vec![r3.clone()].into());
 
-        let a = g.narrow_one_row((r1.clone(), false), true);
-        println!("{:?}", a);
+        let a = feed(&mut g, s, vec![(r1.clone(), false)]);
+        assert_eq!(a, vec![(r1.clone(), false)].into());
 
-        let a = g.narrow_one_row((r1.clone(), true), true);
-        println!("{:?}", a);
+        let a = feed(&mut g, s, vec![(r1.clone(), true)]);
         assert_eq!(a, vec![r1.clone()].into());
     }
 
     #[test]
     fn multiple_records_distinct() {
-        let mut g = setup(true);
+        let (mut g, s) = setup(true);
 
         let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
         let r2: Vec<DataType> = vec![2.into(), "a".into(), 2.into()];
         let r3: Vec<DataType> = vec![3.into(), "c".into(), 2.into()];
 
-        let a = g.narrow_one(
+        let a = feed(
+            &mut g,
+            s,
             vec![
                 (r2.clone(), true),
                 (r1.clone(), true),
                 (r1.clone(), true),
                 (r3.clone(), true),
             ],
-            true,
         );
+        assert_eq!(a.len(), 3);
         assert!(a.iter().any(|r| r == &(r1.clone(), true).into()));
         assert!(a.iter().any(|r| r == &(r2.clone(), true).into()));
         assert!(a.iter().any(|r| r == &(r3.clone(), true).into()));
 
-        let a = g.narrow_one(vec![(r1.clone(), false), (r3.clone(), true)], true);
-        assert!(a.iter().any(|r| r == &(r1.clone(), false).into()));
-        assert!(!a.iter().any(|r| r == &(r3.clone(), true).into()));
+        // r1 was seen twice, so removing it once must not retract it
+        let a = feed(&mut g, s, vec![(r1.clone(), false), (r3.clone(), true)]);
+        assert!(a.is_empty());
+
+        let a = feed(&mut g, s, vec![(r1.clone(), false)]);
+        assert_eq!(a, vec![(r1.clone(), false)].into());
+    }
+
+    #[test]
+    fn distinct_counts_duplicates() {
+        let (mut g, s) = setup(true);
+
+        let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
+        let r2: Vec<DataType> = vec![2.into(), "z".into(), 1.into()];
+
+        // duplicates only produce a single output row
+        let a = feed(&mut g, s, vec![(r1.clone(), true), (r1.clone(), true)]);
+        assert_eq!(a, vec![(r1.clone(), true)].into());
+        let a = feed(&mut g, s, vec![(r2.clone(), true)]);
+        assert!(a.is_empty());
+
+        // removing one of the duplicates leaves the row in place
+        let a = feed(&mut g, s, vec![(r1.clone(), false)]);
+        assert!(a.is_empty());
+        let a = feed(&mut g, s, vec![(r2.clone(), false)]);
+        assert!(a.is_empty());
+
+        // removing the last one removes the row
+        let a = feed(&mut g, s, vec![(r1.clone(), false)]);
+        assert_eq!(a, vec![(r1.clone(), false)].into());
+
+        // and it can then be added back
+        let a = feed(&mut g, s, vec![(r2.clone(), true)]);
+        assert_eq!(a, vec![(r2.clone(), true)].into());
+    }
+
+    #[test]
+    fn distinct_replaces_removed_row() {
+        let (mut g, s) = setup(true);
+
+        let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
+        let r2: Vec<DataType> = vec![2.into(), "z".into(), 1.into()];
+
+        let a = feed(&mut g, s, vec![(r1.clone(), true), (r2.clone(), true)]);
+        assert_eq!(a.len(), 1);
+        let emitted: Vec<DataType> = a.iter().next().unwrap().to_vec();
+
+        // removing the emitted row makes the other row of the group show up instead
+        let other = if emitted == r1 { r2.clone() } else { r1.clone() };
+        let a = feed(&mut g, s, vec![(emitted.clone(), false)]);
+        assert_eq!(a, vec![(emitted, false), (other, true)].into());
+    }
+
+    #[test]
+    fn distinct_ignores_unknown_negative() {
+        let (mut g, s) = setup(true);
+
+        let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
+        let a = feed(&mut g, s, vec![(r1.clone(), false)]);
+        assert!(a.is_empty());
     }
 }
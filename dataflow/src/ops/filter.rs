@@ -1,7 +1,7 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
-use std::sync;
+use std::{mem, sync};
 
 pub use nom_sql::Operator;
 use prelude::*;
@@ -34,10 +34,214 @@ impl Display for Value {
     }
 }
 
+/// `IN` lists with more than this many values are checked using a `HashSet` rather than by
+/// comparing against each value in turn.
+const IN_SET_THRESHOLD: usize = 8;
+
+/// A condition on a single column of a filter.
+///
+/// Conditions follow SQL's three-valued logic: comparing NULL to anything, including another
+/// NULL, is unknown, and a row is only let through if all its conditions are known to hold. Use
+/// `IsNull` and `IsNotNull` to check for NULL.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum FilterCondition {
     Comparison(Operator, Value),
+    /// Matches only NULL.
+    IsNull,
+    /// Matches anything but NULL.
+    IsNotNull,
     In(Vec<DataType>),
+    InSet(HashSet<DataType>),
+    Like(LikePattern),
+    NotLike(LikePattern),
+    /// Matches values within the given bounds, inclusive at both ends.
+    Between(DataType, DataType),
+    /// Matches values outside the given bounds; the complement of `Between`.
+    NotBetween(DataType, DataType),
+}
+
+impl FilterCondition {
+    /// Construct a condition that matches values equal to any of `values`.
+    ///
+    /// Since NULL is not equal to anything, NULLs in `values` are ignored, and NULL values never
+    /// match. An empty list matches no values at all.
+    pub fn in_list(values: Vec<DataType>) -> FilterCondition {
+        let values: Vec<_> = values
+            .into_iter()
+            .filter(|v| *v != DataType::None)
+            .collect();
+        if values.len() > IN_SET_THRESHOLD {
+            FilterCondition::InSet(values.into_iter().collect())
+        } else {
+            FilterCondition::In(values)
+        }
+    }
+
+    /// Check whether the value `d` in the row `r` satisfies this condition.
+    fn matches(&self, d: &DataType, r: &[DataType]) -> bool {
+        match *self {
+            FilterCondition::Comparison(ref op, ref f) => {
+                let v = match *f {
+                    Value::Constant(ref dt) => dt,
+                    Value::Column(c) => &r[c],
+                };
+                if *d == DataType::None || *v == DataType::None {
+                    // the comparison is unknown, so the row does not match
+                    return false;
+                }
+                match *op {
+                    Operator::Equal => d == v,
+                    Operator::NotEqual => d != v,
+                    Operator::Greater => d > v,
+                    Operator::GreaterOrEqual => d >= v,
+                    Operator::Less => d < v,
+                    Operator::LessOrEqual => d <= v,
+                    Operator::In => unreachable!(),
+                    _ => unimplemented!(),
+                }
+            }
+            FilterCondition::IsNull => *d == DataType::None,
+            FilterCondition::IsNotNull => *d != DataType::None,
+            FilterCondition::In(ref fs) => *d != DataType::None && fs.contains(d),
+            FilterCondition::InSet(ref fs) => *d != DataType::None && fs.contains(d),
+            FilterCondition::Like(ref p) => p.matches(d),
+            // NULL is neither like nor unlike any pattern
+            FilterCondition::NotLike(ref p) => *d != DataType::None && !p.matches(d),
+            // like any comparison with NULL, NULL is neither within nor outside of a range
+            FilterCondition::Between(ref lo, ref hi) => *d != DataType::None && lo <= d && d <= hi,
+            FilterCondition::NotBetween(ref lo, ref hi) => {
+                *d != DataType::None && (d < lo || hi < d)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum LikeToken {
+    Literal(String),
+    AnyChar,
+    AnyString,
+}
+
+/// A compiled SQL `LIKE` pattern.
+///
+/// `%` matches any sequence of characters (including an empty one), `_` matches exactly one
+/// character, and a backslash makes the character that follows it match only itself (so `\%`
+/// matches a literal percent sign). Patterns must match the entire value, and matching is
+/// case-sensitive, just like equality between text values. Only text values can match a pattern,
+/// so a pattern without any wildcards behaves like equality with the unescaped pattern.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LikePattern {
+    pattern: String,
+    tokens: Vec<LikeToken>,
+}
+
+impl LikePattern {
+    /// Compile the SQL pattern `pattern`.
+    pub fn new(pattern: &str) -> LikePattern {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '%' => LikeToken::AnyString,
+                '_' => LikeToken::AnyChar,
+                '\\' => {
+                    // a trailing backslash has nothing to escape, and so matches itself
+                    literal.push(chars.next().unwrap_or('\\'));
+                    continue;
+                }
+                c => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+
+            if !literal.is_empty() {
+                tokens.push(LikeToken::Literal(mem::replace(&mut literal, String::new())));
+            }
+            // consecutive %s are equivalent to a single one, but would make matching slower
+            if token == LikeToken::AnyString && tokens.last() == Some(&LikeToken::AnyString) {
+                continue;
+            }
+            tokens.push(token);
+        }
+        if !literal.is_empty() {
+            tokens.push(LikeToken::Literal(literal));
+        }
+
+        LikePattern {
+            pattern: pattern.to_owned(),
+            tokens,
+        }
+    }
+
+    /// Check whether `d` matches this pattern.
+    pub fn matches(&self, d: &DataType) -> bool {
+        match *d {
+            DataType::Text(..) | DataType::TinyText(..) => {
+                let text: Cow<str> = d.into();
+                Self::matches_tokens(&self.tokens, &text)
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_tokens(tokens: &[LikeToken], s: &str) -> bool {
+        let (mut t, mut i) = (0, 0);
+        // where to resume if the tokens after the last `%` we passed fail to match: the token after
+        // that `%`, and the position in `s` up to which the `%` has consumed characters. since
+        // everything between two `%`s matches a fixed number of characters, we never need to
+        // revisit an earlier `%`, which keeps matching polynomial.
+        let mut resume = None;
+        loop {
+            let next = match tokens.get(t) {
+                None if i == s.len() => return true,
+                None => None,
+                Some(&LikeToken::AnyString) => {
+                    t += 1;
+                    resume = Some((t, i));
+                    continue;
+                }
+                Some(&LikeToken::Literal(ref l)) => {
+                    if s[i..].starts_with(&**l) {
+                        Some(i + l.len())
+                    } else {
+                        None
+                    }
+                }
+                Some(&LikeToken::AnyChar) => s[i..].chars().next().map(|c| i + c.len_utf8()),
+            };
+
+            match next {
+                Some(next) => {
+                    t += 1;
+                    i = next;
+                }
+                None => {
+                    // let the last `%` consume one more character, and try again from there
+                    let (rt, ri) = match resume {
+                        Some(r) => r,
+                        None => return false,
+                    };
+                    match s[ri..].chars().next() {
+                        Some(c) => {
+                            t = rt;
+                            i = ri + c.len_utf8();
+                            resume = Some((t, i));
+                        }
+                        None => return false,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for LikePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.pattern)
+    }
 }
 
 impl Filter {
@@ -86,25 +290,7 @@ impl Ingredient for Filter {
                 // check if this filter matches
                 let d = &r[i];
                 if let Some(ref cond) = *fi {
-                    match *cond {
-                        FilterCondition::Comparison(ref op, ref f) => {
-                            let v = match *f {
-                                Value::Constant(ref dt) => dt,
-                                Value::Column(c) => &r[c],
-                            };
-                            match *op {
-                                Operator::Equal => d == v,
-                                Operator::NotEqual => d != v,
-                                Operator::Greater => d > v,
-                                Operator::GreaterOrEqual => d >= v,
-                                Operator::Less => d < v,
-                                Operator::LessOrEqual => d <= v,
-                                Operator::In => unreachable!(),
-                                _ => unimplemented!(),
-                            }
-                        }
-                        FilterCondition::In(ref fs) => fs.contains(d),
-                    }
+                    cond.matches(d, r)
                 } else {
                     // everything matches no condition
                     true
@@ -145,6 +331,8 @@ impl Ingredient for Filter {
                         FilterCondition::Comparison(ref op, ref x) => {
                             Some(format!("f{} {} {}", i, escape(&format!("{}", op)), x))
                         }
+                        FilterCondition::IsNull => Some(format!("f{} IS NULL", i)),
+                        FilterCondition::IsNotNull => Some(format!("f{} IS NOT NULL", i)),
                         FilterCondition::In(ref xs) => Some(format!(
                             "f{} IN ({})",
                             i,
@@ -153,6 +341,17 @@ impl Ingredient for Filter {
                                 .collect::<Vec<_>>()
                                 .join(", ")
                         )),
+                        FilterCondition::InSet(ref xs) => {
+                            Some(format!("f{} IN ({} values)", i, xs.len()))
+                        }
+                        FilterCondition::Like(ref p) => Some(format!("f{} LIKE {}", i, p)),
+                        FilterCondition::NotLike(ref p) => Some(format!("f{} NOT LIKE {}", i, p)),
+                        FilterCondition::Between(ref lo, ref hi) => {
+                            Some(format!("f{} BETWEEN {} AND {}", i, lo, hi))
+                        }
+                        FilterCondition::NotBetween(ref lo, ref hi) => {
+                            Some(format!("f{} NOT BETWEEN {} AND {}", i, lo, hi))
+                        }
                     },
                     None => None,
                 }).collect::<Vec<_>>()
@@ -179,24 +378,7 @@ impl Ingredient for Filter {
                     r.iter().enumerate().all(|(i, d)| {
                         // check if this filter matches
                         if let Some(ref cond) = f[i] {
-                            match *cond {
-                                FilterCondition::Comparison(ref op, ref f) => {
-                                    let v = match *f {
-                                        Value::Constant(ref dt) => dt,
-                                        Value::Column(c) => &r[c],
-                                    };
-                                    match *op {
-                                        Operator::Equal => d == v,
-                                        Operator::NotEqual => d != v,
-                                        Operator::Greater => d > v,
-                                        Operator::GreaterOrEqual => d >= v,
-                                        Operator::Less => d < v,
-                                        Operator::LessOrEqual => d <= v,
-                                        _ => unimplemented!(),
-                                    }
-                                }
-                                FilterCondition::In(ref fs) => fs.contains(d),
-                            }
+                            cond.matches(d, r)
                         } else {
                             // everything matches no condition
                             true
@@ -405,6 +587,129 @@ mod tests {
         assert_eq!(g.narrow_one_row(left.clone(), false), Records::default());
     }
 
+    #[test]
+    fn in_list_representation() {
+        let small: Vec<DataType> = (0..IN_SET_THRESHOLD as i32).map(DataType::from).collect();
+        let large: Vec<DataType> = (0..=IN_SET_THRESHOLD as i32).map(DataType::from).collect();
+        match FilterCondition::in_list(small.clone()) {
+            FilterCondition::In(ref xs) => assert_eq!(xs, &small),
+            ref c => panic!("expected In, got {:?}", c),
+        }
+        match FilterCondition::in_list(large) {
+            FilterCondition::InSet(ref xs) => assert_eq!(xs.len(), IN_SET_THRESHOLD + 1),
+            ref c => panic!("expected InSet, got {:?}", c),
+        }
+
+        // NULLs never match, whether or not they are in the list
+        let c = FilterCondition::in_list(vec![1.into(), DataType::None]);
+        assert_eq!(c, FilterCondition::In(vec![1.into()]));
+        assert!(c.matches(&1.into(), &[]));
+        assert!(!c.matches(&DataType::None, &[]));
+
+        // an empty list matches nothing
+        let c = FilterCondition::in_list(vec![]);
+        assert!(!c.matches(&1.into(), &[]));
+        assert!(!c.matches(&DataType::None, &[]));
+    }
+
+    #[test]
+    fn it_works_with_like() {
+        let mut g = setup(
+            false,
+            Some(&[
+                None,
+                Some(FilterCondition::Like(LikePattern::new("b_%d"))),
+            ]),
+        );
+
+        let mut left: Vec<DataType>;
+
+        left = vec![1.into(), "bad".into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        left = vec![1.into(), "bread".into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        // _ must match exactly one character
+        left = vec![1.into(), "bd".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        // the pattern must match the whole value
+        left = vec![1.into(), "bread!".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        // matching is case-sensitive
+        left = vec![1.into(), "Bad".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+    }
+
+    #[test]
+    fn it_works_with_not_like() {
+        let mut g = setup(
+            false,
+            Some(&[
+                None,
+                Some(FilterCondition::NotLike(LikePattern::new("a%"))),
+            ]),
+        );
+
+        let mut left: Vec<DataType>;
+
+        left = vec![1.into(), "ba".into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        left = vec![1.into(), "ab".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        // NULL is neither like nor unlike a pattern
+        left = vec![1.into(), DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+    }
+
+    #[test]
+    fn like_patterns() {
+        let matches = |p: &str, s: &str| LikePattern::new(p).matches(&s.into());
+
+        assert!(matches("Ha%", "Harry"));
+        assert!(matches("Ha%", "Ha"));
+        assert!(!matches("Ha%", "harry"));
+        assert!(matches("%er", "Baker"));
+        assert!(!matches("%er", "Bakers"));
+        assert!(matches("%an%", "Nathan"));
+        assert!(matches("%%an%%", "an"));
+        assert!(!matches("%an%", "Harry"));
+        assert!(matches("_é_", "cél"));
+
+        // escaped wildcards only match themselves
+        assert!(matches("100\\%", "100%"));
+        assert!(!matches("100\\%", "1000"));
+        assert!(matches("a\\_b", "a_b"));
+        assert!(!matches("a\\_b", "acb"));
+        assert!(matches("a\\\\b", "a\\b"));
+        assert!(matches("a\\", "a\\"));
+
+        // patterns without wildcards behave like equality
+        assert!(matches("abc", "abc"));
+        assert!(!matches("abc", "abcd"));
+        assert!(!matches("abc", "ab"));
+        assert!(!LikePattern::new("1").matches(&1.into()));
+        assert!(!LikePattern::new("%").matches(&DataType::None));
+    }
+
+    #[test]
+    fn like_patterns_do_not_backtrack_exponentially() {
+        let matches = |p: &str, s: &str| LikePattern::new(p).matches(&s.into());
+
+        // trying every way of splitting the `a`s between the `%`s would take far too long
+        let a = "a".repeat(5_000);
+        let pattern = "%a".repeat(20) + "%b";
+        assert!(!matches(&pattern, &a));
+        assert!(matches(&pattern, &format!("{}b", a)));
+        assert!(!matches("%a%a%a%b", &a[..64]));
+        assert!(matches("%a_%a%b", "xaxyab"));
+        assert!(!matches("%a_%a%b", "xaab"));
+    }
+
     #[test]
     fn it_works_with_in_list() {
         let mut g = setup(
@@ -433,4 +738,93 @@ mod tests {
         left = vec![42.into(), "b".into()];
         assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
     }
+
+    #[test]
+    fn comparisons_with_null_are_unknown() {
+        let mut g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Comparison(Operator::NotEqual, Value::Constant(2.into()))),
+                Some(FilterCondition::Comparison(Operator::Equal, Value::Column(0))),
+            ]),
+        );
+
+        let mut left: Vec<DataType>;
+
+        left = vec![1.into(), 1.into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        // NULL != 2 is unknown, not true
+        left = vec![DataType::None, DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        // NULL = NULL is unknown too
+        let mut g = setup(
+            false,
+            Some(&[
+                None,
+                Some(FilterCondition::Comparison(Operator::Equal, Value::Column(0))),
+            ]),
+        );
+        left = vec![DataType::None, DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+        left = vec![1.into(), DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+    }
+
+    #[test]
+    fn it_works_with_is_null() {
+        let mut g = setup(
+            false,
+            Some(&[Some(FilterCondition::IsNull), Some(FilterCondition::IsNotNull)]),
+        );
+
+        let mut left: Vec<DataType>;
+
+        left = vec![DataType::None, "a".into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        left = vec![1.into(), "a".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        left = vec![DataType::None, DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+    }
+
+    #[test]
+    fn it_works_with_between() {
+        let mut g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Between(2.into(), 4.into())),
+                Some(FilterCondition::NotBetween("b".into(), "c".into())),
+            ]),
+        );
+
+        let mut left: Vec<DataType>;
+
+        // both bounds are inclusive
+        for x in 2..5 {
+            left = vec![x.into(), "a".into()];
+            assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+        }
+        left = vec![1.into(), "a".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+        left = vec![5.into(), "a".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+
+        // NOT BETWEEN excludes both bounds
+        left = vec![2.into(), "b".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+        left = vec![2.into(), "c".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+        left = vec![2.into(), "d".into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        // NULL is neither within nor outside of a range
+        left = vec![DataType::None, "a".into()];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+        left = vec![2.into(), DataType::None];
+        assert!(g.narrow_one_row(left.clone(), false).is_empty());
+    }
 }
@@ -97,14 +97,14 @@ impl GroupedOperation for Aggregator {
         &self,
         current: Option<&DataType>,
         diffs: &mut Iterator<Item = Self::Diff>,
-    ) -> DataType {
+    ) -> Option<DataType> {
         let n = match current {
             Some(&DataType::Int(n)) => n as i64,
             Some(&DataType::BigInt(n)) => n,
             None => 0,
             _ => unreachable!(),
         };
-        diffs.into_iter().fold(n, |n, d| n + d).into()
+        Some(diffs.into_iter().fold(n, |n, d| n + d).into())
     }
 
     fn description(&self) -> String {
@@ -285,6 +285,45 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn it_absorbs_updates_to_a_group() {
+        let mut c = setup(true);
+        let u: Record = vec![1.into(), 2.into()].into();
+        c.narrow_one(u, true);
+
+        // an update reaches the aggregation as a negative and a positive in the same batch, so
+        // the count never drops to zero in between and nothing changes downstream
+        let u = vec![
+            (vec![1.into(), 2.into()], false),
+            (vec![1.into(), 5.into()], true),
+        ];
+        assert_eq!(c.narrow_one(u, true), Records::default());
+
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        g.set_op(
+            "identity",
+            &["x", "ys"],
+            Aggregation::SUM.over(s.as_global(), 1, &[0]),
+            true,
+        );
+        let u: Record = vec![1.into(), 2.into()].into();
+        g.narrow_one(u, true);
+
+        // a sum moves straight from the old value to the new one
+        let u = vec![
+            (vec![1.into(), 2.into()], false),
+            (vec![1.into(), 5.into()], true),
+        ];
+        assert_eq!(
+            g.narrow_one(u, true),
+            vec![
+                Record::Negative(vec![1.into(), 2.into()]),
+                Record::Positive(vec![1.into(), 5.into()]),
+            ].into()
+        );
+    }
+
     #[test]
     fn it_groups_by_multiple_columns() {
         let mut c = setup_multicolumn(true);
@@ -153,7 +153,7 @@ impl GroupedOperation for GroupConcat {
         &self,
         current: Option<&DataType>,
         diffs: &mut Iterator<Item = Self::Diff>,
-    ) -> DataType {
+    ) -> Option<DataType> {
         use std::collections::BTreeSet;
         use std::iter::FromIterator;
 
@@ -199,7 +199,7 @@ impl GroupedOperation for GroupConcat {
         // we pushed one separator too many above
         let real_len = new.len() - self.separator.len();
         new.truncate(real_len);
-        new.into()
+        Some(new.into())
     }
 
     fn description(&self) -> String {
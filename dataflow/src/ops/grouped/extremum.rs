@@ -1,5 +1,6 @@
 use ops::grouped::GroupedOperation;
 use ops::grouped::GroupedOperator;
+use std::borrow::Cow;
 
 use prelude::*;
 
@@ -51,6 +52,9 @@ impl Extremum {
 /// incoming record. The output record is constructed by concatenating the columns identifying the
 /// group, and appending the aggregated value. For example, for a sum with `self.over == 1`, a
 /// previous sum of `3`, and an incoming record with `[a, 1, x]`, the output would be `[a, x, 4]`.
+///
+/// If the current extremum of a group is removed, the new one cannot be derived from the removed
+/// value alone. The operator then finds it by looking at all the group's records in its parent.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExtremumOperator {
     op: Extremum,
@@ -106,7 +110,7 @@ impl GroupedOperation for ExtremumOperator {
         &self,
         current: Option<&DataType>,
         diffs: &mut Iterator<Item = Self::Diff>,
-    ) -> DataType {
+    ) -> Option<DataType> {
         // Extreme values are those that are at least as extreme as the current min/max (if any).
         // let mut is_extreme_value : Box<Fn(i64) -> bool> = Box::new(|_|true);
         let mut extreme_values: Vec<i64> = vec![];
@@ -151,12 +155,25 @@ impl GroupedOperation for ExtremumOperator {
             Extremum::MAX => extreme_values.into_iter().max(),
         };
 
-        if let Some(extreme) = extreme {
-            return extreme.into();
-        }
+        // if every value at least as extreme as the old extremum was removed, the new extremum
+        // is somewhere among the group's remaining records
+        extreme.map(DataType::from)
+    }
 
-        // TODO: handle this case by querying into the parent.
-        unimplemented!();
+    fn recomputes_groups(&self) -> bool {
+        true
+    }
+
+    fn recompute(&self, records: &mut Iterator<Item = Cow<[DataType]>>) -> Option<DataType> {
+        let values = records.map(|r| match self.to_diff(&r[..], true) {
+            DiffType::Insert(v) => v,
+            DiffType::Remove(_) => unreachable!(),
+        });
+        let extreme = match self.op {
+            Extremum::MIN => values.min(),
+            Extremum::MAX => values.max(),
+        };
+        extreme.map(DataType::from)
     }
     fn description(&self) -> String {
         let op_string = match self.op {
@@ -305,18 +322,90 @@ mod tests {
         assert!(out.is_empty());
     }
 
+    // Apply a change to the parent's state, like the domain would, and then feed it through the
+    // operator.
+    fn feed(c: &mut ops::test::MockGraph, row: Vec<DataType>, positive: bool) -> Records {
+        let base = c.narrow_base_id();
+        c.states
+            .get_mut(&*base)
+            .unwrap()
+            .process_records(&mut vec![(row.clone(), positive)].into(), None);
+        c.narrow_one(vec![(row, positive)], true)
+    }
+
+    fn assert_negative_record(group: i32, old: i32, rs: Records) {
+        assert_eq!(rs.len(), 1);
+
+        match rs.into_iter().next().unwrap() {
+            Record::Negative(r) => {
+                assert_eq!(r[0], group.into());
+                assert_eq!(r[1], old.into());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_recomputes_removed_maximum() {
+        let mut c = setup(Extremum::MAX, true);
+        let key = 1;
+        let row = |v: i32| vec![key.into(), v.into()];
+
+        assert_positive_record(key, 4, feed(&mut c, row(4), true));
+
+        // inserting a new max replaces the old one
+        assert_record_change(key, 4, 7, feed(&mut c, row(7), true));
+        assert!(feed(&mut c, row(5), true).is_empty());
+        assert!(feed(&mut c, row(7), true).is_empty());
+
+        // removing a value that isn't the max changes nothing
+        assert!(feed(&mut c, row(4), false).is_empty());
+
+        // removing one of two copies of the max changes nothing either
+        assert!(feed(&mut c, row(7), false).is_empty());
+
+        // removing the max falls back to the next-largest value
+        assert_record_change(key, 7, 5, feed(&mut c, row(7), false));
+
+        // removing the last value removes the group
+        assert_negative_record(key, 5, feed(&mut c, row(5), false));
+    }
+
+    #[test]
+    fn it_recomputes_removed_minimum() {
+        let mut c = setup(Extremum::MIN, true);
+        let key = 1;
+        let row = |v: i32| vec![key.into(), v.into()];
+
+        assert_positive_record(key, 10, feed(&mut c, row(10), true));
+        assert_record_change(key, 10, 7, feed(&mut c, row(7), true));
+        assert!(feed(&mut c, row(9), true).is_empty());
+        assert!(feed(&mut c, row(10), false).is_empty());
+
+        // removing the min and adding a value that isn't smaller than the next one in the same
+        // batch still finds the right new min
+        let u = vec![(row(7), false), (row(12), true)];
+        let base = c.narrow_base_id();
+        c.states
+            .get_mut(&*base)
+            .unwrap()
+            .process_records(&mut u.clone().into(), None);
+        assert_record_change(key, 7, 9, c.narrow_one(u, true));
+    }
+
     #[test]
     fn it_suggests_indices() {
         let me = 1.into();
         let c = setup(Extremum::MAX, false);
         let idx = c.node().suggest_indexes(me);
 
-        // should only add index on own columns
-        assert_eq!(idx.len(), 1);
+        // should add an index on own columns, and one on the parent to recompute extrema
+        assert_eq!(idx.len(), 2);
         assert!(idx.contains_key(&me));
 
         // should only index on the group-by column
         assert_eq!(idx[&me], (vec![0], true));
+        assert_eq!(idx[&c.narrow_base_id().as_global()], (vec![0], true));
     }
 
     #[test]
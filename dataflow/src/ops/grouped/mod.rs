@@ -52,11 +52,30 @@ pub trait GroupedOperation: fmt::Debug + Clone {
 
     /// Given the given `current` value, and a number of changes for a group (`diffs`), compute the
     /// updated group value.
+    ///
+    /// Returns `None` if the updated value cannot be derived from `current` and `diffs` alone, in
+    /// which case it is recomputed from all of the group's records using `recompute`. Only
+    /// operations for which `recomputes_groups` returns true may do so.
     fn apply(
         &self,
         current: Option<&DataType>,
         diffs: &mut Iterator<Item = Self::Diff>,
-    ) -> DataType;
+    ) -> Option<DataType>;
+
+    /// Whether `apply` may need a group's value to be recomputed from scratch.
+    ///
+    /// If it does, the parent is indexed by the group columns so that all of a group's current
+    /// records can be looked up.
+    fn recomputes_groups(&self) -> bool {
+        false
+    }
+
+    /// Compute the value of a group from all of the records currently in the group.
+    ///
+    /// Returns `None` if the group has no records left.
+    fn recompute(&self, _records: &mut Iterator<Item = Cow<[DataType]>>) -> Option<DataType> {
+        unreachable!("{} cannot recompute groups", self.description())
+    }
 
     fn description(&self) -> String;
 }
@@ -181,6 +200,7 @@ where
         let mut misses = Vec::new();
         let mut out = Vec::new();
         {
+            let src = self.src;
             let out_key = &self.out_key;
             let mut handle_group =
                 |inner: &mut T,
@@ -231,9 +251,33 @@ where
                     });
 
                     // new is the result of applying all diffs for the group to the current value
-                    let new = inner.apply(current.as_ref().map(|v| &**v), &mut diffs as &mut _);
+                    let mut new =
+                        inner.apply(current.as_ref().map(|v| &**v), &mut diffs as &mut _);
+                    if new.is_none() {
+                        // the diffs were not enough to tell, so look at the whole group. our
+                        // parent has already absorbed the diffs, so its state is up to date.
+                        let parent = state
+                            .get(&*src)
+                            .expect("grouped operators that recompute need their parent state");
+                        match parent.lookup(&group_by[..], &KeyType::from(&group[..])) {
+                            LookupResult::Some(rs) => {
+                                // None now means that the group is empty
+                                new = inner.recompute(&mut rs.into_iter());
+                            }
+                            LookupResult::Missing => {
+                                misses.extend(group_rs.map(|r| Miss {
+                                    on: *src,
+                                    lookup_idx: group_by.clone(),
+                                    lookup_cols: group_by.clone(),
+                                    replay_cols: replay_key_cols.map(Vec::from),
+                                    record: r.extract().0,
+                                }));
+                                return;
+                            }
+                        }
+                    }
                     match current {
-                        Some(ref current) if new == **current => {
+                        Some(ref current) if new.as_ref() == Some(&**current) => {
                             // no change
                         }
                         _ => {
@@ -243,10 +287,12 @@ where
                                 out.push(Record::Negative(old.into_owned()));
                             }
 
-                            // emit positive, which is group + new.
-                            let mut rec = group;
-                            rec.push(new);
-                            out.push(Record::Positive(rec));
+                            // emit positive, which is group + new, unless the group is now empty
+                            if let Some(new) = new {
+                                let mut rec = group;
+                                rec.push(new);
+                                out.push(Record::Positive(rec));
+                            }
                         }
                     }
                 };
@@ -273,9 +319,14 @@ where
 
     fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
         // index by our primary key
-        Some((this, (self.out_key.clone(), true)))
+        let mut idx: HashMap<_, _> = Some((this, (self.out_key.clone(), true)))
             .into_iter()
-            .collect()
+            .collect();
+        if self.inner.recomputes_groups() {
+            // so that we can find all the records of a group
+            idx.insert(self.src.as_global(), (self.group_by.clone(), true));
+        }
+        idx
     }
 
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
@@ -8,7 +8,10 @@ pub mod grouped;
 pub mod identity;
 pub mod join;
 pub mod latest;
+pub mod offset;
 pub mod project;
+#[doc(hidden)]
+pub mod recorder;
 pub mod rewrite;
 pub mod topk;
 pub mod trigger;
@@ -27,6 +30,7 @@ pub enum NodeOperator {
     Identity(identity::Identity),
     Filter(filter::Filter),
     TopK(topk::TopK),
+    Offset(offset::Offset),
     Trigger(trigger::Trigger),
     Rewrite(rewrite::Rewrite),
     Distinct(distinct::Distinct),
@@ -61,6 +65,7 @@ nodeop_from_impl!(NodeOperator::Union, union::Union);
 nodeop_from_impl!(NodeOperator::Identity, identity::Identity);
 nodeop_from_impl!(NodeOperator::Filter, filter::Filter);
 nodeop_from_impl!(NodeOperator::TopK, topk::TopK);
+nodeop_from_impl!(NodeOperator::Offset, offset::Offset);
 nodeop_from_impl!(NodeOperator::Trigger, trigger::Trigger);
 nodeop_from_impl!(NodeOperator::Rewrite, rewrite::Rewrite);
 nodeop_from_impl!(NodeOperator::Distinct, distinct::Distinct);
@@ -78,6 +83,7 @@ macro_rules! impl_ingredient_fn_mut {
             NodeOperator::Identity(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Filter(ref mut i) => i.$fn($($arg),*),
             NodeOperator::TopK(ref mut i) => i.$fn($($arg),*),
+            NodeOperator::Offset(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Trigger(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Rewrite(ref mut i) => i.$fn($($arg),*),
             NodeOperator::Distinct(ref mut i) => i.$fn($($arg),*),
@@ -98,6 +104,7 @@ macro_rules! impl_ingredient_fn_ref {
             NodeOperator::Identity(ref i) => i.$fn($($arg),*),
             NodeOperator::Filter(ref i) => i.$fn($($arg),*),
             NodeOperator::TopK(ref i) => i.$fn($($arg),*),
+            NodeOperator::Offset(ref i) => i.$fn($($arg),*),
             NodeOperator::Trigger(ref i) => i.$fn($($arg),*),
             NodeOperator::Rewrite(ref i) => i.$fn($($arg),*),
             NodeOperator::Distinct(ref i) => i.$fn($($arg),*),
@@ -206,6 +213,9 @@ impl Ingredient for NodeOperator {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         impl_ingredient_fn_ref!(self, parent_columns, column)
     }
+    fn column_sources(&self, column: usize) -> Vec<(NodeIndex, usize)> {
+        impl_ingredient_fn_ref!(self, column_sources, column)
+    }
     fn is_selective(&self) -> bool {
         impl_ingredient_fn_ref!(self, is_selective,)
     }
@@ -312,7 +322,7 @@ pub mod test {
                 };
                 indentln(&mut s);
                 s.push_str(&format!("{}", index.index()));
-                s.push_str(&node.describe(index, materialization_status));
+                s.push_str(&node.describe(index, materialization_status, None));
             }
 
             // edges.
@@ -0,0 +1,294 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use prelude::*;
+
+use nom_sql::OrderType;
+
+use ops::topk::Order;
+
+/// Offset skips the first rows of each group of its parent, and emits the rest.
+///
+/// Together with a `TopK` parent that keeps the top `k + offset` rows of each group, this
+/// implements `LIMIT k OFFSET offset`: the `TopK` maintains all the rows up to the end of the
+/// window, and this operator the (at most `k`) rows in it. Whenever rows enter or leave the
+/// parent's group, the window is recomputed from the parent's state, which must therefore be
+/// materialized. Since both operators keep their rows, a group costs state for `2k + offset`
+/// rows, so large offsets are expensive even if only few rows are shown.
+///
+/// `order` is given the same way as for `TopK`, and the rows that `TopK` would keep first are the
+/// ones that are skipped. Rows that are equal under `order` are ordered by their values, so that
+/// the window is stable.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Offset {
+    src: IndexPair,
+    us: Option<IndexPair>,
+
+    group_by: Vec<usize>,
+    order: Order,
+    offset: usize,
+}
+
+impl Offset {
+    /// Construct a new Offset operator.
+    ///
+    /// `src` is this operator's ancestor, `order` the order in which its rows are ranked,
+    /// `group_by` the columns that this operator is keyed on, and `offset` the number of rows to
+    /// skip in each group.
+    pub fn new(
+        src: NodeIndex,
+        order: Vec<(usize, OrderType)>,
+        group_by: Vec<usize>,
+        offset: usize,
+    ) -> Self {
+        let mut group_by = group_by;
+        group_by.sort();
+
+        Offset {
+            src: src.into(),
+            us: None,
+
+            group_by,
+            order: order.into(),
+            offset,
+        }
+    }
+
+    /// The rows of a group that are in the window, given all of the parent's rows for it.
+    fn window<'a>(&self, mut rows: Vec<Cow<'a, [DataType]>>) -> Vec<Cow<'a, [DataType]>> {
+        // the rows that TopK sorts last are the first ones in the query's order
+        rows.sort_by(|a, b| match self.order.cmp(b, a) {
+            Ordering::Equal => a.cmp(b),
+            o => o,
+        });
+        rows.into_iter().skip(self.offset).collect()
+    }
+}
+
+impl Ingredient for Offset {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.src.as_global()]
+    }
+
+    fn on_connected(&mut self, _: &Graph) {}
+
+    fn on_commit(&mut self, us: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        // who's our parent really?
+        self.src.remap(remap);
+
+        // who are we?
+        self.us = Some(remap[&us]);
+    }
+
+    fn on_input(
+        &mut self,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        replay_key_cols: Option<&[usize]>,
+        _: &DomainNodes,
+        state: &StateMap,
+    ) -> ProcessingResult {
+        debug_assert_eq!(from, *self.src);
+
+        if rs.is_empty() {
+            return ProcessingResult {
+                results: rs,
+                misses: vec![],
+            };
+        }
+
+        // every group only has to be recomputed once, so handle the batch group by group
+        let group_by = &self.group_by;
+        let mut rs: Vec<_> = rs.into();
+        rs.sort_by(|a, b| {
+            group_by
+                .iter()
+                .map(|&col| &a[col])
+                .cmp(group_by.iter().map(|&col| &b[col]))
+        });
+
+        let us = self.us.unwrap();
+        let db = state
+            .get(&*us)
+            .expect("offset operators must have their own state materialized");
+        let parent = state
+            .get(&*self.src)
+            .expect("offset operators need their parent's state");
+
+        let mut out = Vec::new();
+        let mut misses = Vec::new();
+        let mut rs = rs.into_iter().peekable();
+        while let Some(r) = rs.next() {
+            let group: Vec<_> = group_by.iter().map(|&col| r[col].clone()).collect();
+            let mut group_rs = vec![r];
+            while rs
+                .peek()
+                .map(|r| group_by.iter().zip(&group).all(|(&col, v)| r[col] == *v))
+                .unwrap_or(false)
+            {
+                group_rs.push(rs.next().unwrap());
+            }
+
+            let key = KeyType::from(&group[..]);
+            let old = match db.lookup(&group_by[..], &key) {
+                LookupResult::Some(rs) => rs,
+                LookupResult::Missing => {
+                    misses.extend(group_rs.into_iter().map(|r| Miss {
+                        on: *us,
+                        lookup_idx: group_by.clone(),
+                        lookup_cols: group_by.clone(),
+                        replay_cols: replay_key_cols.map(Vec::from),
+                        record: r.extract().0,
+                    }));
+                    continue;
+                }
+            };
+            // our parent has already absorbed the records, so its state is up to date
+            let new = match parent.lookup(&group_by[..], &key) {
+                LookupResult::Some(rs) => self.window(rs.into_iter().collect()),
+                LookupResult::Missing => {
+                    misses.extend(group_rs.into_iter().map(|r| Miss {
+                        on: *self.src,
+                        lookup_idx: group_by.clone(),
+                        lookup_cols: group_by.clone(),
+                        replay_cols: replay_key_cols.map(Vec::from),
+                        record: r.extract().0,
+                    }));
+                    continue;
+                }
+            };
+
+            // emit the difference between the old and the new window
+            let mut old: Vec<_> = old.into_iter().collect();
+            let mut entered = Vec::new();
+            for r in new {
+                match old.iter().position(|o| *o == r) {
+                    Some(i) => {
+                        old.swap_remove(i);
+                    }
+                    None => entered.push(Record::Positive(r.into_owned())),
+                }
+            }
+            out.extend(old.into_iter().map(|r| Record::Negative(r.into_owned())));
+            out.extend(entered);
+        }
+
+        ProcessingResult {
+            results: out.into(),
+            misses,
+        }
+    }
+
+    fn on_eviction(
+        &mut self,
+        _: LocalNodeIndex,
+        key_columns: &[usize],
+        _: &mut Vec<Vec<DataType>>,
+    ) {
+        assert_eq!(key_columns, &self.group_by[..]);
+    }
+
+    fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
+        vec![
+            (this, (self.group_by.clone(), true)),
+            (self.src.as_global(), (self.group_by.clone(), true)),
+        ].into_iter()
+        .collect()
+    }
+
+    fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        Some(vec![(self.src.as_global(), col)])
+    }
+
+    fn description(&self) -> String {
+        let group_cols = self
+            .group_by
+            .iter()
+            .map(|g| g.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("Offset γ[{}] +{}", group_cols, self.offset)
+    }
+
+    fn parent_columns(&self, col: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        vec![(self.src.as_global(), Some(col))]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ops;
+
+    fn setup() -> (ops::test::MockGraph, IndexPair) {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "z"]);
+        g.set_op(
+            "offset",
+            &["x", "y", "z"],
+            Offset::new(s.as_global(), vec![(2, OrderType::OrderDescending)], vec![1], 1),
+            true,
+        );
+        (g, s)
+    }
+
+    #[test]
+    fn it_skips_first_rows() {
+        let (mut g, s) = setup();
+
+        let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
+        let r2: Vec<DataType> = vec![2.into(), "z".into(), 2.into()];
+        let r3: Vec<DataType> = vec![3.into(), "z".into(), 3.into()];
+
+        // the only row of a group is skipped
+        g.seed(s, r3.clone());
+        let a = g.narrow_one_row(r3.clone(), true);
+        assert_eq!(a.len(), 0);
+
+        // a row that sorts first pushes the old first row into the window
+        g.seed(s, r1.clone());
+        let a = g.narrow_one_row(r1.clone(), true);
+        assert_eq!(a, vec![r3.clone()].into());
+
+        g.seed(s, r2.clone());
+        let a = g.narrow_one_row(r2.clone(), true);
+        assert_eq!(a, vec![r2.clone()].into());
+    }
+
+    #[test]
+    fn it_shifts_window_when_rows_leave() {
+        let (mut g, s) = setup();
+
+        let r1: Vec<DataType> = vec![1.into(), "z".into(), 1.into()];
+        let r2: Vec<DataType> = vec![2.into(), "z".into(), 2.into()];
+        let r3: Vec<DataType> = vec![3.into(), "z".into(), 3.into()];
+        for r in &[&r1, &r2, &r3] {
+            g.seed(s, (*r).clone());
+            g.narrow_one_row((*r).clone(), true);
+        }
+
+        // once the first row is gone, the second row is the one that is skipped
+        g.unseed(s);
+        g.seed(s, r2.clone());
+        g.seed(s, r3.clone());
+        let a = g.narrow_one_row((r1.clone(), false), true);
+        assert_eq!(a, vec![(r2.clone(), false)].into());
+    }
+
+    #[test]
+    fn it_suggests_indices() {
+        let (g, s) = setup();
+        let me = 2.into();
+        let idx = g.node().suggest_indexes(me);
+        assert_eq!(idx.len(), 2);
+        assert_eq!(idx[&me], (vec![1], true));
+        assert_eq!(idx[&s.as_global()], (vec![1], true));
+    }
+}
@@ -12,11 +12,122 @@ pub enum ProjectExpressionBase {
     Literal(DataType),
 }
 
+/// A scalar function over strings.
+///
+/// Non-text arguments are converted to their string representation first. If any argument is
+/// NULL, the result is NULL.
+///
+/// The SQL parser does not accept these functions yet, so only graphs built directly on the
+/// dataflow level can use them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StringFunction {
+    /// `CONCAT(s, ...)` joins all of its arguments.
+    Concat,
+    /// `SUBSTRING(s, pos[, len])` takes up to `len` characters starting at the 1-based `pos`. A
+    /// negative `pos` counts from the end of `s`, and a `pos` of 0 gives the empty string.
+    Substring,
+    /// `LOWER(s)` converts `s` to lower case.
+    Lower,
+    /// `UPPER(s)` converts `s` to upper case.
+    Upper,
+    /// `LENGTH(s)` is the length of `s` in bytes.
+    Length,
+}
+
+/// The contents of `d` as a string, without the quotes that `Display` adds to text.
+fn text(d: &DataType) -> Cow<str> {
+    match *d {
+        DataType::Text(..) | DataType::TinyText(..) => d.into(),
+        _ => Cow::Owned(d.to_string()),
+    }
+}
+
+/// `d` as an integer, if it is one.
+fn integer(d: &DataType) -> Option<i64> {
+    match *d {
+        DataType::Int(n) => Some(n as i64),
+        DataType::BigInt(n) => Some(n),
+        _ => None,
+    }
+}
+
+impl StringFunction {
+    fn takes(&self, nargs: usize) -> bool {
+        match *self {
+            StringFunction::Concat => nargs >= 1,
+            StringFunction::Substring => nargs == 2 || nargs == 3,
+            StringFunction::Lower | StringFunction::Upper | StringFunction::Length => nargs == 1,
+        }
+    }
+
+    fn apply(&self, args: &[&DataType]) -> DataType {
+        if args.iter().any(|&a| *a == DataType::None) {
+            return DataType::None;
+        }
+
+        match *self {
+            StringFunction::Concat => args
+                .iter()
+                .map(|&a| text(a))
+                .collect::<String>()
+                .into(),
+            StringFunction::Substring => {
+                let s: Vec<char> = text(args[0]).chars().collect();
+                let n = s.len() as i64;
+                // like NULL, a position or length that is not an integer gives NULL
+                let pos = match integer(args[1]) {
+                    Some(pos) => pos,
+                    None => return DataType::None,
+                };
+                let len = match args.get(2) {
+                    Some(&l) => match integer(l) {
+                        Some(len) => len,
+                        None => return DataType::None,
+                    },
+                    None => n,
+                };
+
+                let start = if pos > 0 { pos - 1 } else { n + pos };
+                if pos == 0 || start < 0 || start >= n || len <= 0 {
+                    return "".into();
+                }
+                let end = n.min(start + len);
+                s[start as usize..end as usize]
+                    .iter()
+                    .collect::<String>()
+                    .into()
+            }
+            StringFunction::Lower => text(args[0]).to_lowercase().into(),
+            StringFunction::Upper => text(args[0]).to_uppercase().into(),
+            StringFunction::Length => text(args[0]).len().into(),
+        }
+    }
+}
+
+impl fmt::Display for StringFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            StringFunction::Concat => "CONCAT",
+            StringFunction::Substring => "SUBSTRING",
+            StringFunction::Lower => "LOWER",
+            StringFunction::Upper => "UPPER",
+            StringFunction::Length => "LENGTH",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ProjectExpression {
-    op: ArithmeticOperator,
-    left: ProjectExpressionBase,
-    right: ProjectExpressionBase,
+pub enum ProjectExpression {
+    Arithmetic {
+        op: ArithmeticOperator,
+        left: ProjectExpressionBase,
+        right: ProjectExpressionBase,
+    },
+    Function {
+        function: StringFunction,
+        args: Vec<ProjectExpressionBase>,
+    },
 }
 
 impl ProjectExpression {
@@ -25,12 +136,29 @@ impl ProjectExpression {
         left: ProjectExpressionBase,
         right: ProjectExpressionBase,
     ) -> ProjectExpression {
-        ProjectExpression {
+        ProjectExpression::Arithmetic {
             op: op,
             left: left,
             right: right,
         }
     }
+
+    /// Construct an expression that computes `function` over `args`.
+    pub fn function(
+        function: StringFunction,
+        args: Vec<ProjectExpressionBase>,
+    ) -> ProjectExpression {
+        assert!(
+            function.takes(args.len()),
+            "{} cannot take {} arguments",
+            function,
+            args.len()
+        );
+        ProjectExpression::Function {
+            function: function,
+            args: args,
+        }
+    }
 }
 
 impl fmt::Display for ProjectExpressionBase {
@@ -44,14 +172,29 @@ impl fmt::Display for ProjectExpressionBase {
 
 impl fmt::Display for ProjectExpression {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let op = match self.op {
-            ArithmeticOperator::Add => "+",
-            ArithmeticOperator::Subtract => "-",
-            ArithmeticOperator::Divide => "/",
-            ArithmeticOperator::Multiply => "*",
-        };
-
-        write!(f, "{} {} {}", self.left, op, self.right)
+        match *self {
+            ProjectExpression::Arithmetic {
+                ref op,
+                ref left,
+                ref right,
+            } => {
+                let op = match *op {
+                    ArithmeticOperator::Add => "+",
+                    ArithmeticOperator::Subtract => "-",
+                    ArithmeticOperator::Divide => "/",
+                    ArithmeticOperator::Multiply => "*",
+                };
+
+                write!(f, "{} {} {}", left, op, right)
+            }
+            ProjectExpression::Function {
+                ref function,
+                ref args,
+            } => {
+                let args: Vec<_> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", function, args.join(", "))
+            }
+        }
     }
 }
 
@@ -96,22 +239,37 @@ impl Project {
     }
 }
 
-fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
-    let left = match expression.left {
-        ProjectExpressionBase::Column(i) => &record[i],
-        ProjectExpressionBase::Literal(ref data) => data,
-    };
-
-    let right = match expression.right {
+fn eval_base<'a>(base: &'a ProjectExpressionBase, record: &'a [DataType]) -> &'a DataType {
+    match *base {
         ProjectExpressionBase::Column(i) => &record[i],
         ProjectExpressionBase::Literal(ref data) => data,
-    };
+    }
+}
 
-    match expression.op {
-        ArithmeticOperator::Add => left + right,
-        ArithmeticOperator::Subtract => left - right,
-        ArithmeticOperator::Multiply => left * right,
-        ArithmeticOperator::Divide => left / right,
+fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
+    match *expression {
+        ProjectExpression::Arithmetic {
+            ref op,
+            ref left,
+            ref right,
+        } => {
+            let left = eval_base(left, record);
+            let right = eval_base(right, record);
+
+            match *op {
+                ArithmeticOperator::Add => left + right,
+                ArithmeticOperator::Subtract => left - right,
+                ArithmeticOperator::Multiply => left * right,
+                ArithmeticOperator::Divide => left / right,
+            }
+        }
+        ProjectExpression::Function {
+            ref function,
+            ref args,
+        } => {
+            let args: Vec<_> = args.iter().map(|a| eval_base(a, record)).collect();
+            function.apply(&args)
+        }
     }
 }
 
@@ -294,6 +452,43 @@ impl Ingredient for Project {
         };
         vec![(self.src.as_global(), result)]
     }
+
+    fn column_sources(&self, column: usize) -> Vec<(NodeIndex, usize)> {
+        let emitted = match self.emit {
+            Some(ref emit) => emit.len(),
+            None => return vec![(self.src.as_global(), column)],
+        };
+        if column < emitted {
+            return vec![(self.src.as_global(), self.resolve_col(column))];
+        }
+
+        let bases: Vec<_> = match self
+            .expressions
+            .as_ref()
+            .and_then(|es| es.get(column - emitted))
+        {
+            Some(&ProjectExpression::Arithmetic {
+                ref left,
+                ref right,
+                ..
+            }) => vec![left, right],
+            Some(&ProjectExpression::Function { ref args, .. }) => args.iter().collect(),
+            // literals are not derived from anything
+            None => vec![],
+        };
+        let mut columns: Vec<_> = bases
+            .into_iter()
+            .filter_map(|b| match *b {
+                ProjectExpressionBase::Column(c) => Some(c),
+                ProjectExpressionBase::Literal(_) => None,
+            }).collect();
+        columns.sort();
+        columns.dedup();
+        columns
+            .into_iter()
+            .map(|c| (self.src.as_global(), c))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -341,11 +536,11 @@ mod tests {
     }
 
     fn setup_column_arithmetic(op: ArithmeticOperator) -> ops::test::MockGraph {
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: op,
-        };
+        let expression = ProjectExpression::new(
+            op,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        );
 
         setup_arithmetic(expression)
     }
@@ -459,11 +654,11 @@ mod tests {
     #[test]
     fn it_forwards_arithmetic_w_literals() {
         let number: DataType = 40.into();
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Literal(number),
-            op: ArithmeticOperator::Multiply,
-        };
+        let expression = ProjectExpression::new(
+            ArithmeticOperator::Multiply,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Literal(number),
+        );
 
         let mut p = setup_arithmetic(expression);
         let rec = vec![10.into(), 0.into()];
@@ -477,11 +672,11 @@ mod tests {
     fn it_forwards_arithmetic_w_only_literals() {
         let a: DataType = 80.into();
         let b: DataType = 40.into();
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Literal(a),
-            right: ProjectExpressionBase::Literal(b),
-            op: ArithmeticOperator::Divide,
-        };
+        let expression = ProjectExpression::new(
+            ArithmeticOperator::Divide,
+            ProjectExpressionBase::Literal(a),
+            ProjectExpressionBase::Literal(b),
+        );
 
         let mut p = setup_arithmetic(expression);
         let rec = vec![0.into(), 0.into()];
@@ -491,6 +686,110 @@ mod tests {
         );
     }
 
+    fn setup_string_function(
+        function: StringFunction,
+        args: Vec<ProjectExpressionBase>,
+    ) -> ops::test::MockGraph {
+        setup_arithmetic(ProjectExpression::function(function, args))
+    }
+
+    #[test]
+    fn it_describes_string_function() {
+        let p = setup_string_function(
+            StringFunction::Concat,
+            vec![
+                ProjectExpressionBase::Column(0),
+                ProjectExpressionBase::Literal(" ".into()),
+                ProjectExpressionBase::Column(1),
+            ],
+        );
+        assert_eq!(
+            p.node().description(),
+            "π[0, 1, CONCAT(0, (lit: \" \"), 1)]"
+        );
+    }
+
+    #[test]
+    fn it_forwards_concat() {
+        let mut p = setup_string_function(
+            StringFunction::Concat,
+            vec![
+                ProjectExpressionBase::Column(0),
+                ProjectExpressionBase::Literal(" ".into()),
+                ProjectExpressionBase::Column(1),
+            ],
+        );
+        let rec = vec!["Ada".into(), "Lovelace".into()];
+        assert_eq!(
+            p.narrow_one_row(rec, false),
+            vec![vec!["Ada".into(), "Lovelace".into(), "Ada Lovelace".into()]].into()
+        );
+
+        // non-text values are concatenated as text, and NULLs propagate
+        let rec = vec!["R".into(), 2.into()];
+        assert_eq!(
+            p.narrow_one_row(rec, false),
+            vec![vec!["R".into(), 2.into(), "R 2".into()]].into()
+        );
+        let rec = vec!["Ada".into(), DataType::None];
+        assert_eq!(
+            p.narrow_one_row(rec, false),
+            vec![vec!["Ada".into(), DataType::None, DataType::None]].into()
+        );
+    }
+
+    #[test]
+    fn it_forwards_substring() {
+        let mut p = setup_string_function(
+            StringFunction::Substring,
+            vec![
+                ProjectExpressionBase::Column(0),
+                ProjectExpressionBase::Column(1),
+                ProjectExpressionBase::Literal(3.into()),
+            ],
+        );
+        let substr = |p: &mut ops::test::MockGraph, s: &str, pos: i32| {
+            let rec = vec![s.into(), pos.into()];
+            p.narrow_one_row(rec, false)[0][2].clone()
+        };
+        assert_eq!(substr(&mut p, "distributary", 1), "dis".into());
+        assert_eq!(substr(&mut p, "distributary", 5), "rib".into());
+        assert_eq!(substr(&mut p, "distributary", -4), "tar".into());
+        assert_eq!(substr(&mut p, "distributary", 11), "ry".into());
+        assert_eq!(substr(&mut p, "distributary", 13), "".into());
+        assert_eq!(substr(&mut p, "distributary", 0), "".into());
+
+        let rec = vec![DataType::None, 1.into()];
+        assert_eq!(p.narrow_one_row(rec, false)[0][2], DataType::None);
+
+        // as does a position that is not an integer
+        let rec = vec!["distributary".into(), "1".into()];
+        assert_eq!(p.narrow_one_row(rec, false)[0][2], DataType::None);
+    }
+
+    #[test]
+    fn it_forwards_case_and_length() {
+        let functions = vec![
+            (StringFunction::Lower, DataType::from("hello, world")),
+            (StringFunction::Upper, DataType::from("HELLO, WORLD")),
+            (StringFunction::Length, DataType::from(12)),
+        ];
+        for (function, expected) in functions {
+            let mut p = setup_string_function(function, vec![ProjectExpressionBase::Column(0)]);
+            let rec = vec!["Hello, World".into(), 0.into()];
+            assert_eq!(p.narrow_one_row(rec, false)[0][2], expected);
+
+            let rec = vec![DataType::None, 0.into()];
+            assert_eq!(p.narrow_one_row(rec, false)[0][2], DataType::None);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_rejects_wrong_arity() {
+        ProjectExpression::function(StringFunction::Lower, vec![]);
+    }
+
     fn setup_query_through(
         mut state: Box<State>,
         permutation: &[usize],
@@ -602,11 +901,11 @@ mod tests {
     #[test]
     fn it_queries_through_w_arithmetic_and_literals() {
         let additional = Some(vec![DataType::Int(42)]);
-        let expressions = Some(vec![ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: ArithmeticOperator::Add,
-        }]);
+        let expressions = Some(vec![ProjectExpression::new(
+            ArithmeticOperator::Add,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        )]);
 
         let state = box MemoryState::default();
         let (p, states) = setup_query_through(state, &[1], additional, expressions);
@@ -617,11 +916,11 @@ mod tests {
     #[test]
     fn it_queries_through_w_arithmetic_and_literals_persistent() {
         let additional = Some(vec![DataType::Int(42)]);
-        let expressions = Some(vec![ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: ArithmeticOperator::Add,
-        }]);
+        let expressions = Some(vec![ProjectExpression::new(
+            ArithmeticOperator::Add,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        )]);
 
         let state = box PersistentState::new(
             String::from("it_queries_through_w_arithmetic_and_literals_persistent"),
@@ -672,6 +971,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_finds_column_sources() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "z"]);
+        let expressions = vec![
+            ProjectExpression::new(
+                ArithmeticOperator::Add,
+                ProjectExpressionBase::Column(2),
+                ProjectExpressionBase::Column(0),
+            ),
+            ProjectExpression::new(
+                ArithmeticOperator::Multiply,
+                ProjectExpressionBase::Column(1),
+                ProjectExpressionBase::Literal(2.into()),
+            ),
+        ];
+        g.set_op(
+            "project",
+            &["y", "z+x", "y*2", "lit"],
+            Project::new(s.as_global(), &[1], Some(vec![42.into()]), Some(expressions)),
+            false,
+        );
+
+        let s = s.as_global();
+        assert_eq!(g.node().column_sources(0), vec![(s, 1)]);
+        assert_eq!(g.node().column_sources(1), vec![(s, 0), (s, 2)]);
+        assert_eq!(g.node().column_sources(2), vec![(s, 1)]);
+        assert_eq!(g.node().column_sources(3), vec![]);
+    }
+
     #[test]
     #[should_panic(expected = "can't resolve literal column")]
     fn it_fails_to_resolve_literal() {
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use prelude::*;
+
+/// Wraps an operator and records, every time it is connected to a graph, which of its ancestors
+/// that graph already holds.
+///
+/// This is only meant for tests of when the controller calls `Ingredient::on_connected`. It turns
+/// into the wrapped operator once it is added to the graph, so it never reaches a domain.
+pub struct ConnectRecorder<I> {
+    inner: I,
+    connected: Arc<Mutex<Vec<Vec<NodeIndex>>>>,
+}
+
+impl<I> ConnectRecorder<I> {
+    /// Wrap `inner`, pushing the ancestors seen by each `on_connected` call onto `connected`.
+    pub fn new(inner: I, connected: Arc<Mutex<Vec<Vec<NodeIndex>>>>) -> Self {
+        ConnectRecorder { inner, connected }
+    }
+}
+
+impl<I: Into<NodeOperator>> From<ConnectRecorder<I>> for NodeOperator {
+    fn from(r: ConnectRecorder<I>) -> Self {
+        r.inner.into()
+    }
+}
+
+impl<I: Ingredient> Ingredient for ConnectRecorder<I> {
+    fn take(&mut self) -> NodeOperator {
+        self.inner.take()
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        self.inner.ancestors()
+    }
+
+    fn on_connected(&mut self, g: &Graph) {
+        let present = self
+            .inner
+            .ancestors()
+            .into_iter()
+            .filter(|&p| g.node_weight(p).is_some())
+            .collect();
+        self.connected.lock().unwrap().push(present);
+        self.inner.on_connected(g);
+    }
+
+    fn on_commit(&mut self, you: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.inner.on_commit(you, remap)
+    }
+
+    fn on_input(
+        &mut self,
+        from: LocalNodeIndex,
+        rs: Records,
+        tracer: &mut Tracer,
+        replay_key_cols: Option<&[usize]>,
+        domain: &DomainNodes,
+        states: &StateMap,
+    ) -> ProcessingResult {
+        self.inner
+            .on_input(from, rs, tracer, replay_key_cols, domain, states)
+    }
+
+    fn suggest_indexes(&self, you: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
+        self.inner.suggest_indexes(you)
+    }
+
+    fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+        self.inner.resolve(col)
+    }
+
+    fn description(&self) -> String {
+        self.inner.description()
+    }
+
+    fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+        self.inner.parent_columns(column)
+    }
+}
@@ -7,9 +7,9 @@ use prelude::*;
 use nom_sql::OrderType;
 
 #[derive(Clone, Serialize, Deserialize)]
-struct Order(Vec<(usize, OrderType)>);
+pub(crate) struct Order(Vec<(usize, OrderType)>);
 impl Order {
-    fn cmp(&self, a: &[DataType], b: &[DataType]) -> Ordering {
+    pub(crate) fn cmp(&self, a: &[DataType], b: &[DataType]) -> Ordering {
         for &(c, ref order_type) in &self.0 {
             let result = match *order_type {
                 OrderType::OrderAscending => a[c].cmp(&b[c]),
@@ -32,8 +32,9 @@ impl From<Vec<(usize, OrderType)>> for Order {
 /// TopK provides an operator that will produce the top k elements for each group.
 ///
 /// Positives are generally fast to process, while negative records can trigger expensive backwards
-/// queries. It is also worth noting that due the nature of Soup, the results of this operator are
-/// unordered.
+/// queries: when a row leaves a group that had k rows, the group is refilled from the parent's
+/// state, which must therefore be materialized. It is also worth noting that due the nature of
+/// Soup, the results of this operator are unordered.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TopK {
     src: IndexPair,
@@ -146,75 +147,111 @@ impl Ingredient for TopK {
         let db = state
             .get(&*us)
             .expect("topk operators must have their own state materialized");
+        let parent = state
+            .get(&*self.src)
+            .expect("topk operators need their parent's state");
 
+        let src = *self.src;
         let mut out = Vec::new();
         let mut grp = Vec::new();
         let mut grpk = 0;
         let mut missed = false;
         // current holds (Cow<Row>, bool) where bool = is_new
         let mut current: Vec<(Cow<[DataType]>, bool)> = Vec::new();
+        // the records of a full group, and how much output there was before it, in case the group
+        // shrinks and our parent no longer has the rows to refill it with
+        let mut grp_rs = Vec::new();
+        let mut grp_out = 0;
         let mut misses = Vec::new();
 
         macro_rules! post_group {
             ($out:ident, $current:ident, $grpk:expr, $k:expr, $order:expr) => {{
-                $current.sort_unstable_by(|a, b| $order.cmp(&*a.0, &*b.0));
-
-                let start = $current.len().saturating_sub($k);
-
-                if $grpk == $k {
-                    if $current.len() < $grpk {
-                        // there used to be k things in the group
-                        // now there are fewer than k
-                        // we don't know if querying would bring us back to k
-                        unimplemented!();
-                    }
-
-                    // FIXME: if all the elements with the smallest value in the new topk are new,
-                    // then it *could* be that there exists some value that is greater than all
-                    // those values, and <= the smallest old value. we would only discover that by
-                    // querying. unfortunately, the check below isn't *quite* right because it does
-                    // not consider old rows that were removed in this batch (which should still be
-                    // counted for this condition).
-                    if false {
-                        let all_new_bottom = $current[start..]
-                            .iter()
-                            .take_while(|(ref r, _)| {
-                                $order.cmp(r, &$current[start].0) == Ordering::Equal
-                            }).all(|&(_, is_new)| is_new);
-                        if all_new_bottom {
-                            eprintln!("topk is guesstimating bottom row");
+                let mut evicted = false;
+                if $grpk == $k && $current.len() < $k {
+                    // there used to be k things in the group, and now there are fewer. rows that
+                    // did not make the cut before may now do so, so look at the whole group. our
+                    // parent has already absorbed the records, so its state is up to date.
+                    match parent.lookup(&group_by[..], &KeyType::from(&grp[..])) {
+                        LookupResult::Some(rs) => {
+                            let mut rest: Vec<_> = rs.into_iter().collect();
+                            for &(ref r, _) in &$current {
+                                if let Some(i) = rest.iter().position(|p| p == r) {
+                                    rest.swap_remove(i);
+                                }
+                            }
+                            $current.extend(rest.into_iter().map(|r| (r, true)));
+                        }
+                        LookupResult::Missing => {
+                            // our parent has evicted the group, so we can't tell what takes the
+                            // place of the rows that left it. like a miss in our own state, the
+                            // group's records produce no output, and are reported instead.
+                            evicted = true;
+                            $out.truncate(grp_out);
+                            $current.clear();
+                            misses.extend(grp_rs.drain(..).map(|r| Miss {
+                                on: src,
+                                lookup_idx: group_by.clone(),
+                                lookup_cols: group_by.clone(),
+                                replay_cols: replay_key_cols.map(Vec::from),
+                                record: r,
+                            }));
                         }
                     }
                 }
 
-                // optimization: if we don't *have to* remove something, we don't
-                for i in start..$current.len() {
-                    if $current[i].1 {
-                        // we found an `is_new` in current
-                        // can we replace it with a !is_new with the same order value?
-                        let replace = $current[0..start].iter().position(|&(ref r, is_new)| {
-                            !is_new && $order.cmp(r, &$current[i].0) == Ordering::Equal
-                        });
-                        if let Some(ri) = replace {
-                            $current.swap(i, ri);
+                if !evicted {
+                    $current.sort_unstable_by(|a, b| $order.cmp(&*a.0, &*b.0));
+
+                    let start = $current.len().saturating_sub($k);
+
+                    if $grpk == $k {
+                        // FIXME: if all the elements with the smallest value in the new topk are
+                        // new, then it *could* be that there exists some value that is greater than
+                        // all those values, and <= the smallest old value. we would only discover
+                        // that by querying. unfortunately, the check below isn't *quite* right
+                        // because it does not consider old rows that were removed in this batch
+                        // (which should still be counted for this condition).
+                        if false {
+                            let all_new_bottom = $current[start..]
+                                .iter()
+                                .take_while(|(ref r, _)| {
+                                    $order.cmp(r, &$current[start].0) == Ordering::Equal
+                                }).all(|&(_, is_new)| is_new);
+                            if all_new_bottom {
+                                eprintln!("topk is guesstimating bottom row");
+                            }
                         }
                     }
-                }
 
-                for (r, is_new) in $current.drain(start..) {
-                    if is_new {
-                        $out.push(Record::Positive(r.into_owned()));
+                    // optimization: if we don't *have to* remove something, we don't
+                    for i in start..$current.len() {
+                        if $current[i].1 {
+                            // we found an `is_new` in current
+                            // can we replace it with a !is_new with the same order value?
+                            let replace = $current[0..start].iter().position(|&(ref r, is_new)| {
+                                !is_new && $order.cmp(r, &$current[i].0) == Ordering::Equal
+                            });
+                            if let Some(ri) = replace {
+                                $current.swap(i, ri);
+                            }
+                        }
                     }
-                }
 
-                if !$current.is_empty() {
-                    $out.extend($current.drain(..).filter_map(|(r, is_new)| {
-                        if !is_new {
-                            Some(Record::Negative(r.into_owned()))
-                        } else {
-                            None
+                    for (r, is_new) in $current.drain(start..) {
+                        if is_new {
+                            $out.push(Record::Positive(r.into_owned()));
                         }
-                    }));
+                    }
+
+                    if !$current.is_empty() {
+                        $out.extend($current.drain(..).filter_map(|(r, is_new)| {
+                            if !is_new {
+                                Some(Record::Negative(r.into_owned()))
+                            } else {
+                                None
+                            }
+                        }));
+                    }
                 }
             }};
         };
@@ -231,6 +268,8 @@ impl Ingredient for TopK {
                 // make ready for the new one
                 grp.clear();
                 grp.extend(group_by.iter().map(|&col| &r[col]).cloned());
+                grp_rs.clear();
+                grp_out = out.len();
 
                 // check out current state
                 match db.lookup(&group_by[..], &KeyType::from(&grp[..])) {
@@ -254,6 +293,9 @@ impl Ingredient for TopK {
                     record: r.extract().0,
                 });
             } else {
+                if grpk == self.k {
+                    grp_rs.push(r.rec().to_vec());
+                }
                 match r {
                     Record::Positive(r) => current.push((Cow::Owned(r), true)),
                     Record::Negative(r) => {
@@ -287,9 +329,12 @@ impl Ingredient for TopK {
     }
 
     fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
-        vec![(this, (self.group_by.clone(), true))]
-            .into_iter()
-            .collect()
+        vec![
+            (this, (self.group_by.clone(), true)),
+            // so that groups that shrink can be refilled
+            (self.src.as_global(), (self.group_by.clone(), true)),
+        ].into_iter()
+        .collect()
     }
 
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
@@ -389,7 +434,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn it_must_query() {
         let (mut g, s) = setup(false);
 
@@ -471,8 +515,9 @@ mod tests {
         let (g, _) = setup(false);
         let me = 2.into();
         let idx = g.node().suggest_indexes(me);
-        assert_eq!(idx.len(), 1);
-        assert_eq!(*idx.iter().next().unwrap().1, (vec![1], true));
+        assert_eq!(idx.len(), 2);
+        assert_eq!(idx[&me], (vec![1], true));
+        assert_eq!(idx[&g.narrow_base_id().as_global()], (vec![1], true));
     }
 
     #[test]
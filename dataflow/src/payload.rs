@@ -161,6 +161,11 @@ pub enum Packet {
         data: Records,
         tracer: Tracer,
         senders: Vec<SourceChannelIdentifier>,
+        /// The base write this update resulted from.
+        ///
+        /// Updates that carry a write are forwarded even if they end up empty, so that readers
+        /// learn that they reflect the write.
+        write: Option<api::WriteId>,
     },
 
     /// Update that is part of a tagged data-flow replay path.
@@ -223,6 +228,12 @@ pub enum Packet {
         new_tag: Option<(Tag, NodeIndex)>,
     },
 
+    /// Stop an Egress node from sending to an ingress node that is being removed.
+    RemoveEgressTx {
+        node: LocalNodeIndex,
+        dst: NodeIndex,
+    },
+
     /// Add a shard to a Sharder node.
     ///
     /// Note that this *must* be done *before* the sharder starts being used!
@@ -298,6 +309,10 @@ pub enum Packet {
     /// Ask domain to log its state size
     UpdateStateSize,
 
+    /// Ask domain to reply with `ControlReplyPacket::Synced` carrying the given id, so that the
+    /// controller can tell which replies were sent before this packet was handled.
+    Sync { id: u64 },
+
     /// The packet is being sent locally, so a pointer is sent to avoid
     /// serialization/deserialization costs.
     Local(LocalBypass<Packet>),
@@ -354,6 +369,14 @@ impl Packet {
         }
     }
 
+    /// The base write a regular update resulted from, if any.
+    pub fn write(&self) -> Option<api::WriteId> {
+        match *self {
+            Packet::Message { write, .. } => write,
+            _ => None,
+        }
+    }
+
     pub fn tag(&self) -> Option<Tag> {
         match *self {
             Packet::ReplayPiece { tag, .. } => Some(tag),
@@ -398,12 +421,14 @@ impl Packet {
                 ref data,
                 ref tracer,
                 ref senders,
+                write,
             } => Packet::Message {
                 link: link.clone(),
                 src: None,
                 data: data.clone(),
                 tracer: tracer.clone(),
                 senders: senders.clone(),
+                write,
             },
             Packet::ReplayPiece {
                 ref link,
@@ -425,6 +450,14 @@ impl Packet {
             Packet::Message {
                 tracer: Some((tag, Some(ref sender))),
                 ..
+            }
+            | Packet::Input {
+                inner:
+                    Input {
+                        tracer: Some((tag, Some(ref sender))),
+                        ..
+                    },
+                ..
             } => {
                 use api::debug::trace::{Event, EventType};
                 sender
@@ -440,6 +473,10 @@ impl Packet {
     pub fn tracer(&mut self) -> Option<&mut Tracer> {
         match *self {
             Packet::Message { ref mut tracer, .. } => Some(tracer),
+            Packet::Input {
+                inner: Input { ref mut tracer, .. },
+                ..
+            } => Some(tracer),
             _ => None,
         }
     }
@@ -513,6 +550,10 @@ pub enum ControlReplyPacket {
         HashMap<petgraph::graph::NodeIndex, api::debug::stats::NodeStats>,
     ),
     Booted(usize, SocketAddr),
+    /// (replay path, number of rows that reached the end of the path)
+    ReplayProgress(Tag, usize),
+    /// Reply to `Packet::Sync` with the given id.
+    Synced(u64),
 }
 
 impl ControlReplyPacket {
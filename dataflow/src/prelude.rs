@@ -19,12 +19,15 @@ pub use payload::{Packet, ReplayPathSegment, SourceChannelIdentifier};
 pub use Sharding;
 
 // domain local state
-pub use state::{LookupResult, MemoryState, PersistentState, RecordResult, Row, State};
+pub use state::{
+    EvictionPolicy, LookupResult, MemoryState, PersistentState, RecordResult, Row, State,
+};
 pub type StateMap = map::Map<Box<State>>;
 pub type DomainNodes = Map<cell::RefCell<Node>>;
 pub type ReplicaAddr = (DomainIndex, usize);
 
 // persistence configuration
+pub use Compression;
 pub use DurabilityMode;
 pub use PersistenceParameters;
 
@@ -33,5 +36,7 @@ use channel;
 /// Channel coordinator type specialized for domains
 pub type ChannelCoordinator = channel::ChannelCoordinator<(DomainIndex, usize)>;
 pub trait Executor {
-    fn send_back(&mut self, SourceChannelIdentifier, ());
+    /// Acknowledge an input from the given client with the sequence number of the base write it
+    /// became part of.
+    fn send_back(&mut self, SourceChannelIdentifier, u64);
 }
@@ -234,6 +234,17 @@ where
     // materialization, and returns results even for computed columns.
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)>;
 
+    /// The columns of this operator's parents that the values in the given column are derived
+    /// from.
+    ///
+    /// For columns that are passed through, this is where `resolve` says they originate. Operators
+    /// that compute a column from other columns of the same row should report those columns, so
+    /// that the lineage of computed columns can be traced; a column computed from nothing, such as
+    /// a literal, has no sources.
+    fn column_sources(&self, column: usize) -> Vec<(NodeIndex, usize)> {
+        self.resolve(column).unwrap_or_default()
+    }
+
     /// Performance hint: should return true if this operator reduces the size of its input
     fn is_selective(&self) -> bool {
         false
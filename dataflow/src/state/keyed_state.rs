@@ -51,6 +51,38 @@ impl KeyedState {
         }
     }
 
+    /// All keys in this state.
+    pub fn keys(&self) -> Vec<Vec<DataType>> {
+        match *self {
+            KeyedState::Single(ref m) => m.keys().map(|k| vec![k.clone()]).collect(),
+            KeyedState::Double(ref m) => m.keys().map(|k| vec![k.0.clone(), k.1.clone()]).collect(),
+            KeyedState::Tri(ref m) => m
+                .keys()
+                .map(|k| vec![k.0.clone(), k.1.clone(), k.2.clone()])
+                .collect(),
+            KeyedState::Quad(ref m) => m
+                .keys()
+                .map(|k| vec![k.0.clone(), k.1.clone(), k.2.clone(), k.3.clone()])
+                .collect(),
+            KeyedState::Quin(ref m) => m
+                .keys()
+                .map(|k| vec![k.0.clone(), k.1.clone(), k.2.clone(), k.3.clone(), k.4.clone()])
+                .collect(),
+            KeyedState::Sex(ref m) => m
+                .keys()
+                .map(|k| {
+                    vec![
+                        k.0.clone(),
+                        k.1.clone(),
+                        k.2.clone(),
+                        k.3.clone(),
+                        k.4.clone(),
+                        k.5.clone(),
+                    ]
+                }).collect(),
+        }
+    }
+
     /// Remove all rows for the first key at or after `index`, returning that key along with the
     /// number of bytes freed. Returns None if already empty.
     pub fn evict_at_index(&mut self, index: usize) -> Option<(u64, Vec<DataType>)> {
@@ -12,6 +12,7 @@ pub struct MemoryState {
     state: Vec<SingleState>,
     by_tag: HashMap<Tag, usize>,
     mem_size: u64,
+    eviction_policy: EvictionPolicy,
 }
 
 impl SizeOf for MemoryState {
@@ -46,8 +47,10 @@ impl State for MemoryState {
             return;
         }
 
-        self.state
-            .push(SingleState::new(columns, partial.is_some()));
+        let mut new = SingleState::new(columns, partial.is_some());
+        // the new index has no keys yet, so tracking them takes no space either
+        new.track_recency(self.eviction_policy == EvictionPolicy::Lru);
+        self.state.push(new);
 
         if !self.state.is_empty() && partial.is_none() {
             // we need to *construct* the index!
@@ -72,6 +75,10 @@ impl State for MemoryState {
         self.state.iter().any(|s| s.partial())
     }
 
+    fn generation(&self) -> u64 {
+        0
+    }
+
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
         if self.is_partial() {
             records.retain(|r| {
@@ -117,7 +124,7 @@ impl State for MemoryState {
     fn mark_filled(&mut self, key: Vec<DataType>, tag: &Tag) {
         debug_assert!(!self.state.is_empty(), "filling uninitialized index");
         let index = self.by_tag[tag];
-        self.state[index].mark_filled(key);
+        self.mem_size += self.state[index].mark_filled(key);
     }
 
     fn mark_hole(&mut self, key: &[DataType], tag: &Tag) {
@@ -156,6 +163,31 @@ impl State for MemoryState {
         (self.state[index].key(), keys, bytes_freed)
     }
 
+    fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.eviction_policy = policy;
+        for s in &mut self.state {
+            let (added, freed) = s.track_recency(policy == EvictionPolicy::Lru);
+            self.mem_size = (self.mem_size + added).checked_sub(freed).unwrap();
+        }
+    }
+
+    fn evict_keys_by_policy(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        match self.eviction_policy {
+            EvictionPolicy::Random => self.evict_random_keys(count),
+            EvictionPolicy::Lru => {
+                // evict from the index whose least recently used key has gone unused the longest
+                let index = (0..self.state.len())
+                    .filter_map(|i| self.state[i].least_recent_use().map(|t| (t, i)))
+                    .min()
+                    .map(|(_, i)| i)
+                    .unwrap_or(0);
+                let (bytes_freed, keys) = self.state[index].evict_lru_keys(count);
+                self.mem_size = self.mem_size.saturating_sub(bytes_freed);
+                (self.state[index].key(), keys, bytes_freed)
+            }
+        }
+    }
+
     fn evict_keys(&mut self, tag: &Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
         // we may be told to evict from a tag that add_key hasn't been called for yet
         // this can happen if an upstream domain issues an eviction for a replay path that we have
@@ -257,6 +289,112 @@ mod tests {
         }
     }
 
+    fn partial_state(policy: EvictionPolicy, keys: i32) -> MemoryState {
+        let tag = Tag(0);
+        let mut state = MemoryState::default();
+        state.set_eviction_policy(policy);
+        state.add_key(&[0], Some(vec![tag]));
+        for k in 0..keys {
+            fill(&mut state, k);
+        }
+        state
+    }
+
+    fn fill(state: &mut MemoryState, k: i32) {
+        let tag = Tag(0);
+        state.mark_filled(vec![k.into()], &tag);
+        let mut records: Records = vec![(vec![k.into(), "row".into()], true)].into();
+        state.process_records(&mut records, Some(tag));
+    }
+
+    fn is_filled(state: &MemoryState, k: i32) -> bool {
+        let k = DataType::from(k);
+        match state.lookup(&[0], &KeyType::Single(&k)) {
+            LookupResult::Some(_) => true,
+            LookupResult::Missing => false,
+        }
+    }
+
+    #[test]
+    fn lru_eviction_evicts_cold_keys() {
+        let mut state = partial_state(EvictionPolicy::Lru, 10);
+
+        // reading a key makes it hot
+        for k in 0..3 {
+            assert!(is_filled(&state, k));
+        }
+
+        let size = state.deep_size_of();
+        let (cols, mut keys, bytes) = state.evict_keys_by_policy(7);
+        assert_eq!(cols, &[0]);
+        keys.sort();
+        assert_eq!(keys, (3..10).map(|k| vec![k.into()]).collect::<Vec<_>>());
+        assert!(bytes > 0);
+        assert_eq!(state.deep_size_of(), size - bytes);
+
+        for k in 0..3 {
+            assert!(is_filled(&state, k));
+        }
+        for k in 3..10 {
+            assert!(!is_filled(&state, k));
+        }
+    }
+
+    #[test]
+    fn lru_eviction_under_memory_cap() {
+        let mut state = partial_state(EvictionPolicy::Lru, 1);
+        let cap = state.deep_size_of() * 4;
+
+        // keep filling past the cap while reading key 0, and evict like the domain does whenever
+        // the state grows too large
+        for k in 1..20 {
+            assert!(is_filled(&state, 0));
+            fill(&mut state, k);
+            while state.deep_size_of() > cap {
+                let (_, keys, _) = state.evict_keys_by_policy(1);
+                assert_eq!(keys.len(), 1);
+                assert_ne!(keys[0], vec![0.into()]);
+            }
+        }
+
+        // the hot key and the most recently filled keys survive
+        for &k in &[0, 17, 18, 19] {
+            assert!(is_filled(&state, k));
+        }
+        for k in 1..17 {
+            assert!(!is_filled(&state, k));
+        }
+    }
+
+    #[test]
+    fn lru_recency_counts_towards_size() {
+        let lru = partial_state(EvictionPolicy::Lru, 10);
+        let mut random = partial_state(EvictionPolicy::Random, 10);
+        assert!(lru.deep_size_of() > random.deep_size_of());
+
+        // switching policies starts or stops tracking the keys that are already there
+        let size = random.deep_size_of();
+        random.set_eviction_policy(EvictionPolicy::Lru);
+        assert_eq!(random.deep_size_of(), lru.deep_size_of());
+        random.set_eviction_policy(EvictionPolicy::Random);
+        assert_eq!(random.deep_size_of(), size);
+
+        // and evicting every key frees all of it
+        let mut lru = lru;
+        let (_, keys, _) = lru.evict_keys_by_policy(10);
+        assert_eq!(keys.len(), 10);
+        assert_eq!(lru.deep_size_of(), 0);
+    }
+
+    #[test]
+    fn random_eviction_needs_no_recency() {
+        let mut state = partial_state(EvictionPolicy::Random, 10);
+        let (_, keys, bytes) = state.evict_keys_by_policy(4);
+        assert_eq!(keys.len(), 4);
+        assert!(bytes > 0);
+        assert_eq!((0..10).filter(|&k| is_filled(&state, k)).count(), 6);
+    }
+
     #[test]
     fn memory_state_old_records_new_index() {
         let mut state = MemoryState::default();
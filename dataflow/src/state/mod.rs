@@ -14,6 +14,24 @@ use prelude::*;
 pub use self::memory_state::MemoryState;
 pub use self::persistent_state::PersistentState;
 
+/// How keys are chosen for eviction from partially materialized state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Evict keys at random.
+    Random,
+    /// Evict the keys that were least recently filled or read.
+    ///
+    /// This keeps frequently read keys materialized, at the cost of tracking when each key of
+    /// every partial index was last used.
+    Lru,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Random
+    }
+}
+
 pub trait State: SizeOf + Send {
     /// Add an index keyed by the given columns and replayed to by the given partial tags.
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>);
@@ -24,6 +42,10 @@ pub trait State: SizeOf + Send {
 
     fn is_partial(&self) -> bool;
 
+    /// How many times this state has been opened, which grows every time it is recovered after a
+    /// restart. State that does not survive restarts always returns 0.
+    fn generation(&self) -> u64;
+
     // Inserts or removes each record into State. Records that miss all indices in partial state
     // are removed from `records` (thus the mutable reference).
     fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>);
@@ -45,6 +67,13 @@ pub trait State: SizeOf + Send {
     /// from along with the keys evicted and the number of bytes evicted.
     fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
 
+    /// Set how `evict_keys_by_policy` chooses the keys to evict.
+    fn set_eviction_policy(&mut self, policy: EvictionPolicy);
+
+    /// Evict up to `count` keys chosen by the state's eviction policy, returning key columns of
+    /// the index chosen to evict from along with the keys evicted and the number of bytes evicted.
+    fn evict_keys_by_policy(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
+
     /// Evict the listed keys from the materialization targeted by `tag`, returning the key columns
     /// of the index that was evicted from and the number of bytes evicted.
     fn evict_keys(&mut self, tag: &Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)>;
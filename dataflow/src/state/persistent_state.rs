@@ -268,6 +268,10 @@ impl State for PersistentState {
         false
     }
 
+    fn generation(&self) -> u64 {
+        self.epoch
+    }
+
     fn mark_filled(&mut self, _: Vec<DataType>, _: &Tag) {
         unreachable!("PersistentState can't be partial")
     }
@@ -280,6 +284,14 @@ impl State for PersistentState {
         unreachable!("can't evict keys from PersistentState")
     }
 
+    fn set_eviction_policy(&mut self, _: EvictionPolicy) {
+        // never partial, so nothing is ever evicted
+    }
+
+    fn evict_keys_by_policy(&mut self, _: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
+        unreachable!("can't evict keys from PersistentState")
+    }
+
     fn evict_keys(&mut self, _: &Tag, _: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
         unreachable!("can't evict keys from PersistentState")
     }
@@ -375,20 +387,34 @@ impl PersistentState {
 
     fn build_options(name: &str, params: &PersistenceParameters) -> rocksdb::Options {
         let mut opts = rocksdb::Options::default();
-        opts.set_compression_type(rocksdb::DBCompressionType::Lz4);
         opts.create_if_missing(true);
         opts.create_missing_column_families(true);
 
-        let key_len = 0; // variable key length
         let bloom_bits_per_key = 10;
-        let hash_table_ratio = 0.75;
-        let index_sparseness = 16;
-        opts.set_plain_table_factory(
-            key_len,
-            bloom_bits_per_key,
-            hash_table_ratio,
-            index_sparseness,
-        );
+        match params.compression {
+            None => {
+                let key_len = 0; // variable key length
+                let hash_table_ratio = 0.75;
+                let index_sparseness = 16;
+                opts.set_compression_type(rocksdb::DBCompressionType::None);
+                opts.set_plain_table_factory(
+                    key_len,
+                    bloom_bits_per_key,
+                    hash_table_ratio,
+                    index_sparseness,
+                );
+            }
+            Some(compression) => {
+                // Plain tables are never compressed, so we have to use block-based tables:
+                let mut block_opts = rocksdb::BlockBasedOptions::default();
+                block_opts.set_bloom_filter(bloom_bits_per_key, false);
+                opts.set_block_based_table_factory(&block_opts);
+                opts.set_compression_type(match compression {
+                    Compression::Lz4 => rocksdb::DBCompressionType::Lz4,
+                    Compression::Zstd => rocksdb::DBCompressionType::Zstd,
+                });
+            }
+        }
 
         if let Some(ref path) = params.log_dir {
             // Append the db name to the WAL path to ensure
@@ -420,6 +446,13 @@ impl PersistentState {
         opts.set_max_bytes_for_level_base(2048 * 1024 * 1024);
         opts.set_target_file_size_base(256 * 1024 * 1024);
 
+        // A full memtable is flushed to a table file of roughly the same size, and compactions
+        // should not merge table files into larger ones:
+        if let Some(max_bytes) = params.max_table_file_bytes {
+            opts.set_write_buffer_size(max_bytes);
+            opts.set_target_file_size_base(max_bytes as u64);
+        }
+
         // Keep up to 4 parallel memtables:
         opts.set_max_write_buffer_number(4);
 
@@ -600,6 +633,7 @@ impl PersistentState {
 mod tests {
     use super::*;
     use bincode;
+    use std::fs;
     use std::path::PathBuf;
 
     fn insert<S: State>(state: &mut S, row: Vec<DataType>) {
@@ -866,6 +900,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_recover_compressed() {
+        let rows: Vec<Vec<DataType>> = (0..1000)
+            .map(|i| vec![i.into(), "Cat".repeat(100).into()])
+            .collect();
+
+        let recover = |compression| {
+            let (dir, name) = get_tmp_path();
+            let mut params = PersistenceParameters::default();
+            params.mode = DurabilityMode::Permanent;
+            params.compression = compression;
+            {
+                let mut state = PersistentState::new(name.clone(), None, &params);
+                state.add_key(&[0], None);
+                state.process_records(&mut rows.clone().into(), None);
+            }
+
+            let state = PersistentState::new(name.clone(), None, &params);
+            let mut recovered = state.cloned_records();
+            recovered.sort();
+            assert_eq!(recovered, rows);
+            drop(state);
+
+            let size: u64 = fs::read_dir(format!("{}.db", name))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum();
+            drop(dir);
+            size
+        };
+
+        let uncompressed = recover(None);
+        assert!(recover(Some(Compression::Lz4)) < uncompressed);
+        assert!(recover(Some(Compression::Zstd)) < uncompressed);
+    }
+
+    #[test]
+    fn persistent_state_recover_table_files() {
+        let rows: Vec<Vec<DataType>> = (0..2000)
+            .map(|i| vec![i.into(), "Cat".repeat(100).into()])
+            .collect();
+
+        let (_dir, name) = get_tmp_path();
+        let mut params = PersistenceParameters::default();
+        params.mode = DurabilityMode::Permanent;
+        params.max_table_file_bytes = Some(64 * 1024);
+        {
+            let mut state = PersistentState::new(name.clone(), None, &params);
+            state.add_key(&[0], None);
+            for chunk in rows.chunks(100) {
+                state.process_records(&mut chunk.to_vec().into(), None);
+            }
+        }
+
+        let state = PersistentState::new(name.clone(), None, &params);
+        let mut recovered = state.cloned_records();
+        recovered.sort();
+        assert_eq!(recovered, rows);
+
+        let table_files = fs::read_dir(format!("{}.db", name))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|ext| ext == "sst").unwrap_or(false))
+            .count();
+        assert!(table_files > 1);
+    }
+
     #[test]
     fn persistent_state_recover_unique_key() {
         let (_dir, name) = get_tmp_path();
@@ -1,15 +1,127 @@
 use rand::{Rng, ThreadRng};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
 use std::rc::Rc;
+use std::slice;
 
 use basics::data::SizeOf;
 use prelude::*;
 use state::keyed_state::KeyedState;
 
+/// Keeps track of when each filled key of a partial index was last used.
+///
+/// Keys are only added and removed through `&mut` methods of `SingleState`, which report the
+/// bytes this takes so that they are counted in the state's size. Lookups only borrow the state
+/// immutably, and only move already tracked keys, so that part uses interior mutability.
+#[derive(Default)]
+struct Recency {
+    clock: Cell<u64>,
+    last_used: RefCell<HashMap<Vec<DataType>, u64>>,
+    by_tick: RefCell<BTreeMap<u64, Vec<DataType>>>,
+}
+
+/// Bytes used to track `key`, which is kept in both maps of a `Recency`.
+fn recency_entry_size(key: &[DataType]) -> u64 {
+    let key = mem::size_of::<Vec<DataType>>() as u64
+        + key.iter().map(SizeOf::deep_size_of).sum::<u64>();
+    2 * (key + mem::size_of::<u64>() as u64)
+}
+
+impl Recency {
+    fn tick(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    /// Start tracking `key` as just used, and return the number of bytes this adds. A key that is
+    /// already tracked takes no more space.
+    fn add(&self, key: Vec<DataType>) -> u64 {
+        let now = self.tick();
+        let size = recency_entry_size(&key);
+        let old = self.last_used.borrow_mut().insert(key.clone(), now);
+        let mut by_tick = self.by_tick.borrow_mut();
+        by_tick.insert(now, key);
+        match old {
+            Some(old) => {
+                by_tick.remove(&old);
+                0
+            }
+            None => size,
+        }
+    }
+
+    /// Note that `key` was just used, if it is tracked.
+    fn touch(&self, key: &[DataType]) {
+        if let Some(t) = self.last_used.borrow_mut().get_mut(key) {
+            let now = self.tick();
+            let mut by_tick = self.by_tick.borrow_mut();
+            let key = by_tick.remove(&*t).unwrap();
+            by_tick.insert(now, key);
+            *t = now;
+        }
+    }
+
+    /// Stop tracking `key`, and return the number of bytes freed.
+    fn forget(&self, key: &[DataType]) -> u64 {
+        match self.last_used.borrow_mut().remove(key) {
+            Some(t) => {
+                self.by_tick.borrow_mut().remove(&t);
+                recency_entry_size(key)
+            }
+            None => 0,
+        }
+    }
+
+    /// Number of bytes used to track all keys.
+    fn size(&self) -> u64 {
+        self.last_used
+            .borrow()
+            .keys()
+            .map(|k| recency_entry_size(k))
+            .sum()
+    }
+
+    /// When the least recently used key was last used, if there are any keys.
+    fn oldest(&self) -> Option<u64> {
+        self.by_tick.borrow().keys().next().cloned()
+    }
+
+    /// The `count` least recently used keys, least recently used first.
+    fn coldest(&self, count: usize) -> Vec<Vec<DataType>> {
+        self.by_tick.borrow().values().take(count).cloned().collect()
+    }
+}
+
+fn key_of(key: &KeyType) -> Vec<DataType> {
+    match *key {
+        KeyType::Single(k) => vec![k.clone()],
+        KeyType::Double((ref k0, ref k1)) => vec![k0.clone(), k1.clone()],
+        KeyType::Tri((ref k0, ref k1, ref k2)) => vec![k0.clone(), k1.clone(), k2.clone()],
+        KeyType::Quad((ref k0, ref k1, ref k2, ref k3)) => {
+            vec![k0.clone(), k1.clone(), k2.clone(), k3.clone()]
+        }
+        KeyType::Quin((ref k0, ref k1, ref k2, ref k3, ref k4)) => {
+            vec![k0.clone(), k1.clone(), k2.clone(), k3.clone(), k4.clone()]
+        }
+        KeyType::Sex((ref k0, ref k1, ref k2, ref k3, ref k4, ref k5)) => vec![
+            k0.clone(),
+            k1.clone(),
+            k2.clone(),
+            k3.clone(),
+            k4.clone(),
+            k5.clone(),
+        ],
+    }
+}
+
 pub struct SingleState {
     key: Vec<usize>,
     state: KeyedState,
     partial: bool,
     rows: usize,
+    recency: Option<Recency>,
 }
 impl SingleState {
     pub fn new(columns: &[usize], partial: bool) -> Self {
@@ -18,6 +130,26 @@ impl SingleState {
             state: columns.into(),
             partial,
             rows: 0,
+            recency: None,
+        }
+    }
+
+    /// Start or stop keeping track of when each key was last used, which is needed for
+    /// `evict_lru_keys`. Only partial indices ever track their keys.
+    ///
+    /// Returns the number of bytes this adds to the state's size, and the number of bytes it
+    /// frees. Keys that are already filled are tracked as the least recently used ones.
+    pub fn track_recency(&mut self, track: bool) -> (u64, u64) {
+        if !track {
+            let freed = self.recency.take().map(|r| r.size()).unwrap_or(0);
+            (0, freed)
+        } else if self.partial && self.recency.is_none() {
+            let recency = Recency::default();
+            let added = self.state.keys().into_iter().map(|k| recency.add(k)).sum();
+            self.recency = Some(recency);
+            (added, 0)
+        } else {
+            (0, 0)
         }
     }
 
@@ -194,7 +326,12 @@ impl SingleState {
         None
     }
 
-    pub fn mark_filled(&mut self, key: Vec<DataType>) {
+    /// Mark `key` as filled, and return the number of bytes used to track when it is used.
+    pub fn mark_filled(&mut self, key: Vec<DataType>) -> u64 {
+        let tracked = match self.recency {
+            Some(ref recency) => recency.add(key.clone()),
+            None => 0,
+        };
         let mut key = key.into_iter();
         let replaced = match self.state {
             KeyedState::Single(ref mut map) => map.insert(key.next().unwrap(), Vec::new()),
@@ -241,9 +378,14 @@ impl SingleState {
             ),
         };
         assert!(replaced.is_none());
+        tracked
     }
 
     pub fn mark_hole(&mut self, key: &[DataType]) -> u64 {
+        let untracked = match self.recency {
+            Some(ref recency) => recency.forget(key),
+            None => 0,
+        };
         let removed = match self.state {
             KeyedState::Single(ref mut map) => map.remove(&key[0]),
             KeyedState::Double(ref mut map) => map.remove(&(key[0].clone(), key[1].clone())),
@@ -273,12 +415,12 @@ impl SingleState {
             )),
         };
         // mark_hole should only be called on keys we called mark_filled on
-        removed
+        untracked + removed
             .unwrap()
             .iter()
             .filter(|r| Rc::strong_count(&r.0) == 1)
             .map(SizeOf::deep_size_of)
-            .sum()
+            .sum::<u64>()
     }
 
     /// Evict `count` randomly selected keys from state and return them along with the number of
@@ -292,6 +434,9 @@ impl SingleState {
         let mut keys = Vec::with_capacity(count);
         for _ in 0..count {
             if let Some((n, key)) = self.state.evict_at_index(rng.gen()) {
+                if let Some(ref recency) = self.recency {
+                    bytes_freed += recency.forget(&key);
+                }
                 bytes_freed += n;
                 keys.push(key);
             } else {
@@ -301,9 +446,31 @@ impl SingleState {
         (bytes_freed, keys)
     }
 
+    /// Evict up to `count` of the least recently filled or read keys from state and return them
+    /// along with the number of bytes freed.
+    ///
+    /// Evicts nothing unless `track_recency` has been enabled.
+    pub fn evict_lru_keys(&mut self, count: usize) -> (u64, Vec<Vec<DataType>>) {
+        let keys = match self.recency {
+            Some(ref recency) => recency.coldest(count),
+            None => return (0, Vec::new()),
+        };
+        (self.evict_keys(&keys), keys)
+    }
+
+    /// When the least recently used key of this state was last used, if recency is tracked and
+    /// there are any keys.
+    pub fn least_recent_use(&self) -> Option<u64> {
+        self.recency.as_ref().and_then(Recency::oldest)
+    }
+
     /// Evicts a specified key from this state, returning the number of bytes freed.
     pub fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
-        keys.iter().map(|k| self.state.evict(k)).sum()
+        let untracked: u64 = match self.recency {
+            Some(ref recency) => keys.iter().map(|k| recency.forget(k)).sum(),
+            None => 0,
+        };
+        untracked + keys.iter().map(|k| self.state.evict(k)).sum::<u64>()
     }
 
     pub fn values<'a>(&'a self) -> Box<Iterator<Item = &'a Vec<Row>> + 'a> {
@@ -327,6 +494,12 @@ impl SingleState {
     }
     pub fn lookup<'a>(&'a self, key: &KeyType) -> LookupResult<'a> {
         if let Some(rs) = self.state.lookup(key) {
+            if let Some(ref recency) = self.recency {
+                match *key {
+                    KeyType::Single(k) => recency.touch(slice::from_ref(k)),
+                    _ => recency.touch(&key_of(key)),
+                }
+            }
             LookupResult::Some(RecordResult::Borrowed(&rs[..]))
         } else {
             if self.partial() {
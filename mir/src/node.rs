@@ -743,6 +743,10 @@ impl Debug for MirNodeType {
                                 FilterCondition::Comparison(ref op, ref x) => {
                                     Some(format!("f{} {} {:?}", i, escape(&format!("{}", op)), x))
                                 }
+                                FilterCondition::IsNull => Some(format!("f{} IS NULL", i)),
+                                FilterCondition::IsNotNull => {
+                                    Some(format!("f{} IS NOT NULL", i))
+                                }
                                 FilterCondition::In(ref xs) => Some(format!(
                                     "f{} IN ({})",
                                     i,
@@ -751,6 +755,19 @@ impl Debug for MirNodeType {
                                         .collect::<Vec<_>>()
                                         .join(", ")
                                 )),
+                                FilterCondition::InSet(ref xs) => {
+                                    Some(format!("f{} IN ({} values)", i, xs.len()))
+                                }
+                                FilterCondition::Like(ref p) => Some(format!("f{} LIKE {}", i, p)),
+                                FilterCondition::NotLike(ref p) => {
+                                    Some(format!("f{} NOT LIKE {}", i, p))
+                                }
+                                FilterCondition::Between(ref lo, ref hi) => {
+                                    Some(format!("f{} BETWEEN {} AND {}", i, lo, hi))
+                                }
+                                FilterCondition::NotBetween(ref lo, ref hi) => {
+                                    Some(format!("f{} NOT BETWEEN {} AND {}", i, lo, hi))
+                                }
                             },
                             None => None,
                         }).collect::<Vec<_>>()
@@ -0,0 +1,63 @@
+#[macro_use]
+extern crate clap;
+extern crate consensus;
+extern crate distributary;
+#[macro_use]
+extern crate slog;
+
+use consensus::ZookeeperAuthority;
+use distributary::ControllerHandle;
+use std::net::TcpListener;
+
+fn main() {
+    use clap::{App, Arg};
+    let matches = App::new("soup-mysql")
+        .version("0.0.1")
+        .about("Lets MySQL clients query and write to a Soup deployment.")
+        .arg(
+            Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .takes_value(true)
+                .default_value("127.0.0.1:3306")
+                .help("IP address and port to listen for MySQL clients on"),
+        )
+        .arg(
+            Arg::with_name("deployment")
+                .long("deployment")
+                .required(true)
+                .takes_value(true)
+                .help("Soup deployment ID."),
+        )
+        .arg(
+            Arg::with_name("max-views")
+                .long("max-views")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Most views to install for SELECT queries that are not in the recipe"),
+        )
+        .arg(
+            Arg::with_name("zookeeper")
+                .short("z")
+                .long("zookeeper")
+                .takes_value(true)
+                .default_value("127.0.0.1:2181")
+                .help("Zookeeper connection info."),
+        )
+        .get_matches();
+
+    let log = distributary::logger_pls();
+
+    let listen_addr = matches.value_of("address").unwrap();
+    let zookeeper_addr = matches.value_of("zookeeper").unwrap();
+    let deployment_name = matches.value_of("deployment").unwrap();
+    let max_views = value_t_or_exit!(matches, "max-views", usize);
+
+    let authority =
+        ZookeeperAuthority::new(&format!("{}/{}", zookeeper_addr, deployment_name)).unwrap();
+    let soup = ControllerHandle::new(authority).unwrap();
+
+    let listener = TcpListener::bind(listen_addr).unwrap();
+    info!(log, "listening for mysql clients"; "addr" => listen_addr);
+    distributary::serve_mysql(listener, soup.pointer(), max_views, log).unwrap();
+}
@@ -1,6 +1,9 @@
+use basics::ShardHasher;
 use consensus::{Authority, LocalAuthority};
+use dataflow::state::EvictionPolicy;
 use dataflow::PersistenceParameters;
 
+use std::io;
 use std::net::IpAddr;
 use std::sync::Arc;
 use std::time;
@@ -9,13 +12,17 @@ use failure;
 use slog;
 
 use crate::controller::sql::reuse::ReuseConfigType;
-use crate::controller::{self, ControllerConfig, LocalControllerHandle};
+use crate::controller::{self, ControllerConfig, LocalControllerHandle, PlacementPolicy};
 
 /// Used to construct a controller.
 pub struct ControllerBuilder {
     config: ControllerConfig,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    read_threads: Option<usize>,
+    migration_queue_timeout: Option<time::Duration>,
+    placement: Option<Box<PlacementPolicy>>,
+    migration_log: Option<Box<io::Write + Send>>,
     listen_addr: IpAddr,
     log: slog::Logger,
 }
@@ -27,6 +34,10 @@ impl Default for ControllerBuilder {
             log: slog::Logger::root(slog::Discard, o!()),
             memory_limit: None,
             memory_check_frequency: None,
+            read_threads: None,
+            migration_queue_timeout: None,
+            placement: None,
+            migration_log: None,
         }
     }
 }
@@ -45,6 +56,42 @@ impl ControllerBuilder {
         self.config.domain_config.replay_batch_timeout = t;
     }
 
+    /// Set the most records sent in a single packet when a node's entire state is replayed, such
+    /// as when a new fully materialized view is added. Smaller batches lower the peak memory use
+    /// of replaying large bases, but take longer to replay. The default is 256.
+    pub fn set_replay_batch_size(&mut self, n: usize) {
+        assert!(n > 0, "replay batches must hold at least one record");
+        self.config.domain_config.replay_batch_size = n;
+    }
+
+    /// Set how keys are mapped to the shards of sharded nodes.
+    ///
+    /// `ShardHasher::Consistent` moves fewer keys between shards if the number of shards changes.
+    pub fn set_shard_hasher(&mut self, hasher: ShardHasher) {
+        self.config.domain_config.shard_hasher = hasher;
+    }
+
+    /// Set how keys are chosen when evicting from partially materialized state. By default, keys
+    /// are evicted at random.
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.config.domain_config.eviction_policy = policy;
+    }
+
+    /// Set the most bytes of partially materialized state that a single node may hold. Whenever
+    /// a node's state grows beyond this, individual keys are evicted from it according to the
+    /// eviction policy. By default, nodes are only evicted from when the worker's memory limit is
+    /// exceeded.
+    pub fn set_partial_state_limit(&mut self, limit: Option<u64>) {
+        self.config.domain_config.partial_state_limit = limit;
+    }
+
+    /// Make the domain that hosts the node called `node` handle partial replays and regular
+    /// updates in the given order. This lets tests reproduce races between the two.
+    #[cfg(feature = "replay_ordering")]
+    pub fn set_packet_order(&mut self, node: &str, order: dataflow::PacketOrder) {
+        self.config.domain_config.packet_order = Some((node.to_owned(), order));
+    }
+
     /// Set the persistence parameters used by the system.
     pub fn set_persistence(&mut self, p: PersistenceParameters) {
         self.config.persistence = p;
@@ -67,6 +114,55 @@ impl ControllerBuilder {
         self.config.quorum = quorum;
     }
 
+    /// Set how long the controller waits for a domain to reply to a control message before it
+    /// gives up on the domain's worker. By default, the controller waits indefinitely.
+    pub fn set_domain_reply_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.config.domain_reply_timeout = timeout;
+    }
+
+    /// Set how long the controller waits for the shards of a new domain to start up on their
+    /// workers. If a shard does not start in time, the migration that created the domain fails.
+    /// By default, the controller waits indefinitely.
+    pub fn set_domain_boot_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.config.domain_boot_timeout = timeout;
+    }
+
+    /// Set how many bytes of packets the controller may hold back for each domain shard before it
+    /// writes them out in one go. Holding packets back saves system calls when the controller sends
+    /// many small packets to a domain, such as during migrations. Packets that are held back are
+    /// always written out before the controller waits for a reply from the domain, and once it has
+    /// handled each request. By default, every packet is written out as soon as it is sent.
+    pub fn set_packet_coalescing(&mut self, max_bytes: Option<usize>) {
+        self.config.packet_coalescing = max_bytes;
+    }
+
+    /// Set whether domains hand packets to other domains on the same worker in memory. If
+    /// disabled, such packets are serialized and sent over a loopback connection, just like
+    /// packets to domains on other workers. Enabled by default.
+    pub fn set_local_delivery(&mut self, enabled: bool) {
+        self.config.local_delivery = enabled;
+    }
+
+    /// Set how many heartbeat intervals a worker may go without sending a heartbeat before the
+    /// controller considers it to have failed. The default is 4.
+    ///
+    /// Once some worker has failed, any other worker that has missed `with_other_failures`
+    /// heartbeats is considered to have failed as well, so that correlated failures are handled
+    /// together. The default is 3, and it may not exceed `missed`.
+    pub fn set_missed_heartbeats_before_failure(&mut self, missed: u32, with_other_failures: u32) {
+        assert_ne!(missed, 0);
+        assert!(with_other_failures <= missed);
+        self.config.missed_heartbeats_before_failure = missed;
+        self.config.missed_heartbeats_with_other_failures = with_other_failures;
+    }
+
+    /// Set how long a recipe change may wait behind other migrations before it is started. A
+    /// change that has not started in time is dropped, and its caller gets an error. By default,
+    /// changes wait indefinitely.
+    pub fn set_migration_queue_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.migration_queue_timeout = timeout;
+    }
+
     /// Set the memory limit (target) and how often we check it (in millis).
     pub fn set_memory_limit(&mut self, limit: usize, check_freq: time::Duration) {
         assert_ne!(limit, 0);
@@ -75,11 +171,42 @@ impl ControllerBuilder {
         self.memory_check_frequency = Some(check_freq);
     }
 
+    /// Set the number of threads each worker uses to serve `View` lookups. By default, there is
+    /// one thread per CPU core (two in debug builds).
+    ///
+    /// Lookups that arrive over the same connection are always served by the same thread, so
+    /// lookups only run in parallel if they come from different `View` handles, such as those
+    /// made with `View::into_exclusive`.
+    ///
+    /// All the read threads share a single copy of each reader's state; every thread only keeps
+    /// its own handle to it. Adding threads therefore costs no memory beyond the threads
+    /// themselves. Giving each thread a replica of the state instead would avoid the (small) cost
+    /// of synchronizing with the domain that writes to the reader, but would multiply the memory
+    /// used by readers by the number of threads.
+    pub fn set_read_threads(&mut self, threads: usize) {
+        assert_ne!(threads, 0);
+        self.read_threads = Some(threads);
+    }
+
     /// Set the IP address that the controller should use for listening.
     pub fn set_listen_addr(&mut self, listen_addr: IpAddr) {
         self.listen_addr = listen_addr;
     }
 
+    /// Set the policy that decides which worker each new domain shard is placed on. By default,
+    /// shards are placed on workers round-robin.
+    pub fn set_placement_policy<P: PlacementPolicy + 'static>(&mut self, policy: P) {
+        self.placement = Some(Box::new(policy));
+    }
+
+    /// Write a JSON summary of every migration to `out`, one per line.
+    ///
+    /// Each summary records the number of nodes added and removed, the number of domains spawned
+    /// and replay paths created, and how long the migration took.
+    pub fn log_migrations_as_json<W: io::Write + Send + 'static>(&mut self, out: W) {
+        self.migration_log = Some(Box::new(out));
+    }
+
     /// Set the logger that the derived controller should use. By default, it uses `slog::Discard`.
     pub fn log_with(&mut self, log: slog::Logger) {
         self.log = log;
@@ -101,6 +228,11 @@ impl ControllerBuilder {
             self.config,
             self.memory_limit,
             self.memory_check_frequency,
+            self.read_threads,
+            self.migration_queue_timeout,
+            self.placement
+                .unwrap_or_else(|| Box::new(controller::RoundRobin::default())),
+            self.migration_log,
             self.log,
         )
     }
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::{self, cell, io};
+use std::time::{Duration, Instant};
+use std::{self, cell, fmt, io, thread};
 
 use mio;
 use slog::Logger;
@@ -14,12 +15,84 @@ use dataflow::payload::ControlReplyPacket;
 use dataflow::prelude::*;
 use dataflow::{DomainBuilder, DomainConfig};
 
-use crate::controller::{WorkerEndpoint, WorkerIdentifier, WorkerStatus};
+use crate::controller::{
+    PlacementPolicy, ReplayStatus, WorkerEndpoint, WorkerIdentifier, WorkerStatus,
+};
 use crate::coordination::{CoordinationMessage, CoordinationPayload};
 
 #[derive(Debug)]
 pub enum WaitError {
     WrongReply(ControlReplyPacket),
+    /// Not all shards replied within the domain's reply timeout.
+    Timeout,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WaitError::WrongReply(ref r) => write!(f, "got unexpected reply {:?}", r),
+            WaitError::Timeout => write!(f, "not all shards replied in time"),
+        }
+    }
+}
+
+/// A shard of a new domain did not boot.
+#[derive(Debug)]
+pub struct BootError {
+    pub domain: DomainIndex,
+    pub shard: usize,
+    /// The worker the shard was assigned to.
+    pub worker: WorkerIdentifier,
+    /// Why the shard could not connect back to the controller, unless it simply did not do so
+    /// within the domain boot timeout.
+    pub cause: Option<io::Error>,
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cause {
+            None => write!(
+                f,
+                "domain {}.{} did not boot on worker {:?} in time",
+                self.domain.index(),
+                self.shard,
+                self.worker
+            ),
+            Some(ref e) => write!(
+                f,
+                "domain {}.{} on worker {:?} could not connect back: {}",
+                self.domain.index(),
+                self.shard,
+                self.worker,
+                e
+            ),
+        }
+    }
+}
+
+/// Accept a connection on `listener`, giving up once `deadline` (if any) has passed.
+fn accept_before(
+    listener: &std::net::TcpListener,
+    deadline: Option<Instant>,
+) -> io::Result<std::net::TcpStream> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return listener.accept().map(|(s, _)| s),
+    };
+
+    listener.set_nonblocking(true)?;
+    loop {
+        match listener.accept() {
+            Ok((s, _)) => return Ok(s),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 struct DomainShardHandle {
@@ -34,6 +107,16 @@ pub struct DomainHandle {
     cr_poll: PollingLoop<ControlReplyPacket>,
     shards: Vec<DomainShardHandle>,
 
+    /// How long to wait for replies from the domain's shards before giving up.
+    reply_timeout: Option<Duration>,
+    /// Set when a wait gave up before all the replies it waited for had arrived. Those replies
+    /// may still show up, and must not be mistaken for replies to later requests.
+    desynced: bool,
+    /// The id of the last `Sync` sent to the shards, and how many shards have yet to echo it.
+    /// Until they all have, replies are stale and are discarded.
+    syncing: Option<(u64, usize)>,
+    last_sync: u64,
+
     log: Logger,
 }
 
@@ -44,15 +127,20 @@ impl DomainHandle {
         log: &Logger,
         graph: &mut Graph,
         config: &DomainConfig,
-        nodes: Vec<(NodeIndex, bool)>,
+        nodes: Vec<NodeIndex>,
         persistence_params: &PersistenceParameters,
         listen_addr: &IpAddr,
         channel_coordinator: &Arc<ChannelCoordinator>,
         debug_addr: &Option<SocketAddr>,
-        placer: &'a mut Box<Iterator<Item = (WorkerIdentifier, WorkerEndpoint)>>,
+        placer: &'a mut PlacementPolicy,
+        pinned: Option<WorkerIdentifier>,
+        placeable: &'a [(WorkerIdentifier, WorkerEndpoint)],
         workers: &'a mut Vec<WorkerEndpoint>,
         epoch: Epoch,
-    ) -> Self {
+        reply_timeout: Option<Duration>,
+        boot_timeout: Option<Duration>,
+        coalesce: Option<usize>,
+    ) -> Result<Self, BootError> {
         // NOTE: warning to future self...
         // the code currently relies on the fact that the domains that are sharded by the same key
         // *also* have the same number of shards. if this no longer holds, we actually need to do a
@@ -62,6 +150,26 @@ impl DomainHandle {
         let mut cr_rxs = Vec::new();
         let mut assignments = Vec::new();
         let mut nodes = Some(Self::build_descriptors(graph, nodes));
+        let boot_deadline = boot_timeout.map(|t| Instant::now() + t);
+
+        assert!(
+            !placeable.is_empty(),
+            "no workers available to place domain on!"
+        );
+        let candidates: Vec<_> = placeable.iter().map(|&(id, _)| id).collect();
+        let pinned = pinned.filter(|w| {
+            let available = candidates.contains(w);
+            if !available {
+                warn!(
+                    log,
+                    "domain {} is pinned to unavailable worker {:?}; placing it elsewhere",
+                    idx.index(),
+                    w
+                );
+            }
+            available
+        });
+        placer.begin_domain(idx, num_shards);
 
         for i in 0..num_shards.unwrap_or(1) {
             let nodes = if i == num_shards.unwrap_or(1) - 1 {
@@ -83,10 +191,15 @@ impl DomainHandle {
                 debug_addr: debug_addr.clone(),
             };
 
-            // TODO(malte): simple round-robin placement for the moment
-            let (identifier, endpoint) = placer
-                .next()
-                .expect("no workers available to place domain on!");
+            let identifier = match pinned {
+                Some(w) => w,
+                None => placer.place(idx, i, &candidates),
+            };
+            let endpoint = &placeable
+                .iter()
+                .find(|&&(id, _)| id == identifier)
+                .expect("placement policy chose a worker that is not available")
+                .1;
 
             // send domain to worker
             let mut w = endpoint.lock().unwrap();
@@ -106,14 +219,36 @@ impl DomainHandle {
 
             assignments.push(identifier);
 
-            let stream =
-                mio::net::TcpStream::from_stream(control_listener.accept().unwrap().0).unwrap();
+            // the shard connects back to us once the worker has started it
+            let stream = accept_before(&control_listener, boot_deadline)
+                .and_then(mio::net::TcpStream::from_stream)
+                .map_err(|e| BootError {
+                    domain: idx,
+                    shard: i,
+                    worker: identifier,
+                    cause: if e.kind() == io::ErrorKind::TimedOut {
+                        None
+                    } else {
+                        Some(e)
+                    },
+                })?;
             cr_rxs.push(TcpReceiver::new(stream));
         }
 
+        let mut timed_out = false;
         let mut cr_poll = PollingLoop::from_receivers(cr_rxs);
         cr_poll.run_polling_loop(|event| match event {
-            PollEvent::ResumePolling(_) => KeepPolling,
+            PollEvent::ResumePolling(timeout) => {
+                if let Some(deadline) = boot_deadline {
+                    let now = Instant::now();
+                    *timeout = Some(if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    });
+                }
+                KeepPolling
+            }
             PollEvent::Process(ControlReplyPacket::Booted(shard, addr)) => {
                 channel_coordinator.insert_addr((idx, shard), addr.clone(), false);
                 txs.insert(
@@ -157,14 +292,39 @@ impl DomainHandle {
                 }
             }
             PollEvent::Process(_) => unreachable!(),
-            PollEvent::Timeout => unreachable!(),
+            PollEvent::Timeout => {
+                timed_out = true;
+                StopPolling
+            }
         });
 
+        if timed_out {
+            let shard = (0..assignments.len())
+                .find(|i| !txs.contains_key(i))
+                .unwrap();
+
+            // tell the shards that did boot to go away again
+            for (_, (mut tx, is_local)) in txs.drain() {
+                let mut p = box Packet::Quit;
+                if is_local {
+                    p = p.make_local();
+                }
+                drop(tx.send(p));
+            }
+            return Err(BootError {
+                domain: idx,
+                shard,
+                worker: assignments[shard],
+                cause: None,
+            });
+        }
+
         let shards = assignments
             .into_iter()
             .enumerate()
             .map(|(i, worker)| {
-                let (tx, is_local) = txs.remove(&i).unwrap();
+                let (mut tx, is_local) = txs.remove(&i).unwrap();
+                tx.coalesce(coalesce);
                 DomainShardHandle {
                     is_local,
                     worker,
@@ -172,12 +332,16 @@ impl DomainHandle {
                 }
             }).collect();
 
-        DomainHandle {
+        Ok(DomainHandle {
             idx: idx,
             cr_poll,
             shards,
+            reply_timeout,
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
             log: log.clone(),
-        }
+        })
     }
 
     pub fn index(&self) -> DomainIndex {
@@ -196,27 +360,102 @@ impl DomainHandle {
         self.shards.iter().any(|s| s.worker == *worker)
     }
 
-    fn build_descriptors(graph: &mut Graph, nodes: Vec<(NodeIndex, bool)>) -> DomainNodes {
+    /// Tell every shard of the domain to shut down, including shards on failed workers.
+    pub(super) fn quit(&mut self) {
+        for shard in &mut self.shards {
+            let mut p = box Packet::Quit;
+            if shard.is_local {
+                p = p.make_local();
+            }
+            // don't unwrap, because the shard may already have terminated
+            drop(shard.tx.send(p));
+        }
+    }
+
+    /// Ask every shard of the domain that can still be reached to shut down, and return how many
+    /// shards were asked.
+    ///
+    /// Each shard is sent a single `Quit`. Shards on failed workers, and shards whose connection
+    /// has already gone away, are skipped.
+    pub(super) fn send_quit(&mut self, workers: &HashMap<WorkerIdentifier, WorkerStatus>) -> usize {
+        self.resync(workers);
+        let mut sent = 0;
+        for shard in &mut self.shards {
+            let mut p = box Packet::Quit;
+            if shard.is_local {
+                p = p.make_local();
+            } else if !workers.get(&shard.worker).map(|w| w.healthy).unwrap_or(false) {
+                continue;
+            }
+            if shard.tx.send(p).is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    fn build_descriptors(graph: &mut Graph, nodes: Vec<NodeIndex>) -> DomainNodes {
         nodes
             .into_iter()
-            .map(|(ni, _)| {
+            .map(|ni| {
                 let node = graph.node_weight_mut(ni).unwrap().take();
                 node.finalize(graph)
             }).map(|nd| (*nd.local_addr(), cell::RefCell::new(nd)))
             .collect()
     }
 
+    /// If an earlier wait gave up on some replies, make sure that they are not taken as replies
+    /// to the requests that follow.
+    ///
+    /// Every shard that can still be reached is sent a `Sync` with a fresh id, and
+    /// `wait_for_next_reply` discards all replies until each of those shards has echoed it. Since
+    /// a shard replies to requests in order, any late replies arrive before the echo.
+    fn resync(&mut self, workers: &HashMap<WorkerIdentifier, WorkerStatus>) {
+        if !self.desynced {
+            return;
+        }
+        self.desynced = false;
+        self.last_sync += 1;
+        let id = self.last_sync;
+
+        let mut sent = 0;
+        for shard in &mut self.shards {
+            let mut p = box Packet::Sync { id };
+            if shard.is_local {
+                p = p.make_local();
+            } else if !workers.get(&shard.worker).map(|w| w.healthy).unwrap_or(false) {
+                continue;
+            }
+            if shard.tx.send(p).is_ok() {
+                sent += 1;
+            }
+        }
+        debug!(self.log, "resynchronizing with domain shards";
+               "domain" => self.idx.index(),
+               "id" => id,
+               "shards" => sent);
+        self.syncing = if sent > 0 { Some((id, sent)) } else { None };
+    }
+
     pub(super) fn send_to_healthy(
         &mut self,
         p: Box<Packet>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
     ) -> Result<(), tcp::SendError> {
-        for shard in self.shards.iter_mut() {
+        self.resync(workers);
+        let last = self.shards.len() - 1;
+        let mut p = Some(p);
+        for (i, shard) in self.shards.iter_mut().enumerate() {
             if shard.is_local {
-                // TODO: avoid clone on last iteration.
-                shard.tx.send(p.clone().make_local())?;
+                // the last shard can have the packet itself, rather than a copy
+                let p = if i == last {
+                    p.take().unwrap()
+                } else {
+                    p.clone().unwrap()
+                };
+                shard.tx.send(p.make_local())?;
             } else if workers[&shard.worker].healthy {
-                shard.tx.send_ref(&p)?;
+                shard.tx.send_ref(p.as_ref().unwrap())?;
             } else {
                 error!(
                     self.log,
@@ -234,6 +473,7 @@ impl DomainHandle {
         mut p: Box<Packet>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
     ) -> Result<(), tcp::SendError> {
+        self.resync(workers);
         if self.shards[i].is_local {
             p = p.make_local();
         }
@@ -249,39 +489,520 @@ impl DomainHandle {
         Ok(())
     }
 
-    fn wait_for_next_reply(&mut self) -> ControlReplyPacket {
+    /// Write out any packets that are being held back for the domain's shards.
+    ///
+    /// Shards whose packets cannot be written are skipped; the error is returned once all the
+    /// other shards have been flushed.
+    pub(super) fn flush(&mut self) -> Result<(), tcp::SendError> {
+        let mut res = Ok(());
+        for shard in &mut self.shards {
+            if let Err(e) = shard.tx.flush() {
+                res = Err(e);
+            }
+        }
+        res
+    }
+
+    /// Wait for the next reply that is not stale, giving up at `deadline` (if any).
+    fn wait_for_next_reply(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<ControlReplyPacket, WaitError> {
+        // the request we are waiting on a reply to may still be held back
+        if let Err(e) = self.flush() {
+            error!(self.log, "failed to write out packets to domain: {}", e;
+                   "domain" => self.idx.index());
+        }
+
+        loop {
+            let reply = match self.poll_reply(deadline) {
+                Some(reply) => reply,
+                None => {
+                    self.desynced = true;
+                    return Err(WaitError::Timeout);
+                }
+            };
+
+            let (id, left) = match self.syncing {
+                Some(syncing) => syncing,
+                None => return Ok(reply),
+            };
+            match reply {
+                ControlReplyPacket::Synced(got) if got == id => {
+                    self.syncing = if left > 1 { Some((id, left - 1)) } else { None };
+                }
+                reply => {
+                    debug!(self.log, "discarding stale reply";
+                           "domain" => self.idx.index(),
+                           "reply" => ?reply);
+                }
+            }
+        }
+    }
+
+    /// Record that `reply` was not the reply we waited for. The replies we did wait for may still
+    /// arrive, so they have to be discarded before the next request.
+    fn wrong_reply(&mut self, reply: ControlReplyPacket) -> WaitError {
+        self.desynced = true;
+        WaitError::WrongReply(reply)
+    }
+
+    fn poll_reply(&mut self, deadline: Option<Instant>) -> Option<ControlReplyPacket> {
         let mut reply = None;
         self.cr_poll.run_polling_loop(|event| match event {
             PollEvent::Process(packet) => {
                 reply = Some(packet);
                 StopPolling
             }
-            PollEvent::ResumePolling(_) => KeepPolling,
-            PollEvent::Timeout => unreachable!(),
+            PollEvent::ResumePolling(timeout) => {
+                if let Some(deadline) = deadline {
+                    let now = Instant::now();
+                    *timeout = Some(if deadline > now {
+                        deadline - now
+                    } else {
+                        Duration::from_secs(0)
+                    });
+                }
+                KeepPolling
+            }
+            PollEvent::Timeout => {
+                if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                    StopPolling
+                } else {
+                    // spurious wakeup
+                    KeepPolling
+                }
+            }
         });
-        reply.unwrap()
+        reply
+    }
+
+    fn reply_deadline(&self) -> Option<Instant> {
+        self.reply_timeout.map(|t| Instant::now() + t)
     }
 
     pub fn wait_for_ack(&mut self) -> Result<(), WaitError> {
+        let deadline = self.reply_deadline();
         for _ in 0..self.shards() {
-            match self.wait_for_next_reply() {
+            match self.wait_for_next_reply(deadline)? {
                 ControlReplyPacket::Ack(_) => {}
-                r => return Err(WaitError::WrongReply(r)),
+                r => return Err(self.wrong_reply(r)),
             }
         }
         Ok(())
     }
 
+    /// Wait for `shards` shards to acknowledge a `Quit` sent by `send_quit`, giving up at
+    /// `deadline`.
+    pub(super) fn wait_for_quit(
+        &mut self,
+        shards: usize,
+        deadline: Instant,
+    ) -> Result<(), WaitError> {
+        for _ in 0..shards {
+            match self.wait_for_next_reply(Some(deadline))? {
+                ControlReplyPacket::Ack(_) => {}
+                r => return Err(self.wrong_reply(r)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for all shards to acknowledge that a full replay has completed, recording the progress
+    /// they report in the meantime.
+    ///
+    /// Since progress reports show that the replay is still moving, each one restarts the reply
+    /// timeout.
+    pub fn wait_for_replay(&mut self, status: &ReplayStatus) -> Result<(), WaitError> {
+        let mut deadline = self.reply_deadline();
+        let mut acks = 0;
+        while acks < self.shards() {
+            match self.wait_for_next_reply(deadline)? {
+                ControlReplyPacket::Ack(_) => acks += 1,
+                ControlReplyPacket::ReplayProgress(tag, rows) => {
+                    status.lock().unwrap().entry(tag).or_default().replayed += rows as u64;
+                    deadline = self.reply_deadline();
+                }
+                r => return Err(self.wrong_reply(r)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for all shards to report the size of a node's state, and return the total number of
+    /// rows across shards.
+    pub fn wait_for_state_size(&mut self) -> Result<usize, WaitError> {
+        let deadline = self.reply_deadline();
+        let mut rows = 0;
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply(deadline)? {
+                ControlReplyPacket::StateSize(r, _) => rows += r,
+                r => return Err(self.wrong_reply(r)),
+            }
+        }
+        Ok(rows)
+    }
+
     pub fn wait_for_statistics(
         &mut self,
     ) -> Result<Vec<(DomainStats, HashMap<NodeIndex, NodeStats>)>, WaitError> {
+        let deadline = self.reply_deadline();
         let mut stats = Vec::with_capacity(self.shards());
         for _ in 0..self.shards() {
-            match self.wait_for_next_reply() {
+            match self.wait_for_next_reply(deadline)? {
                 ControlReplyPacket::Statistics(d, s) => stats.push((d, s)),
-                r => return Err(WaitError::WrongReply(r)),
+                r => return Err(self.wrong_reply(r)),
             }
         }
         Ok(stats)
     }
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use slog;
+    use std::net::TcpListener;
+
+    /// A single-shard handle to a domain that is really just the other end of its connections.
+    ///
+    /// Packets sent to the domain arrive on the returned receiver, and control replies for the
+    /// handle are sent through the returned sender. The shard's worker is added to `workers`.
+    pub(in crate::controller) fn loopback_domain(
+        idx: DomainIndex,
+        reply_timeout: Option<Duration>,
+        workers: &mut HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> (DomainHandle, TcpReceiver<Box<Packet>>, TcpSender<ControlReplyPacket>) {
+        use std::sync::Mutex;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            let tx = std::net::TcpStream::connect(&addr).unwrap();
+            let (rx, _) = listener.accept().unwrap();
+            (tx, mio::net::TcpStream::from_stream(rx).unwrap())
+        };
+
+        let (shard_tx, shard_rx) = connect();
+        let (replies_tx, replies_rx) = connect();
+        let dh = DomainHandle {
+            idx,
+            cr_poll: PollingLoop::from_receivers(vec![TcpReceiver::new(replies_rx)]),
+            shards: vec![DomainShardHandle {
+                worker: addr,
+                tx: TcpSender::new(shard_tx).unwrap(),
+                is_local: false,
+            }],
+            reply_timeout,
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
+            log: Logger::root(slog::Discard, o!()),
+        };
+        let worker = TcpSender::new(connect().0).unwrap();
+        workers.insert(addr, WorkerStatus::new(Arc::new(Mutex::new(worker))));
+
+        (dh, TcpReceiver::new(shard_rx), TcpSender::new(replies_tx).unwrap())
+    }
+
+    #[test]
+    fn descriptors_are_keyed_by_local_address() {
+        use dataflow::node;
+
+        let mut g = Graph::new();
+        let src = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+        let add = |g: &mut Graph, name: &str, local: u32| {
+            let ni = g.add_node(node::Node::new(name, &["x"], node::special::Ingress));
+            let mut ip = IndexPair::from(ni);
+            ip.set_local(unsafe { LocalNodeIndex::make(local) });
+            g[ni].set_finalized_addr(ip);
+            g[ni].add_to(DomainIndex::from(0));
+            ni
+        };
+        let a = add(&mut g, "a", 0);
+        let b = add(&mut g, "b", 1);
+        g.add_edge(src, a, ());
+        g.add_edge(a, b, ());
+
+        let nodes = DomainHandle::build_descriptors(&mut g, vec![a, b]);
+        assert_eq!(nodes.len(), 2);
+        let a = nodes[unsafe { &LocalNodeIndex::make(0) }].borrow();
+        assert_eq!(a.name(), "a");
+        assert_eq!(a.children(), &[unsafe { LocalNodeIndex::make(1) }]);
+        let b = nodes[unsafe { &LocalNodeIndex::make(1) }].borrow();
+        assert_eq!(b.name(), "b");
+        assert!(b.children().is_empty());
+    }
+
+    #[test]
+    fn send_to_healthy_reaches_every_shard() {
+        use std::sync::Mutex;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            let tx = std::net::TcpStream::connect(&addr).unwrap();
+            let (rx, _) = listener.accept().unwrap();
+            (tx, mio::net::TcpStream::from_stream(rx).unwrap())
+        };
+
+        // the last shard is local, so it is the one that gets the packet without a copy
+        let (shards, mut rxs): (Vec<_>, Vec<_>) = [true, false, true]
+            .iter()
+            .map(|&is_local| {
+                let (tx, rx) = connect();
+                let shard = DomainShardHandle {
+                    worker: addr,
+                    tx: TcpSender::new(tx).unwrap(),
+                    is_local,
+                };
+                (shard, TcpReceiver::<Box<Packet>>::new(rx))
+            }).unzip();
+
+        let mut dh = DomainHandle {
+            idx: DomainIndex::from(0),
+            cr_poll: PollingLoop::from_receivers(vec![TcpReceiver::new(connect().1)]),
+            shards,
+            reply_timeout: None,
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
+            log: Logger::root(slog::Discard, o!()),
+        };
+        let mut workers = HashMap::new();
+        let worker = TcpSender::new(connect().0).unwrap();
+        workers.insert(addr, WorkerStatus::new(Arc::new(Mutex::new(worker))));
+
+        dh.send_to_healthy(box Packet::Spin, &workers).unwrap();
+        for (i, rx) in rxs.iter_mut().enumerate() {
+            let p = match *rx.recv().unwrap() {
+                Packet::Local(lp) => unsafe { lp.take() },
+                p => {
+                    assert_eq!(i, 1, "only the remote shard gets a serialized packet");
+                    Box::new(p)
+                }
+            };
+            match *p {
+                Packet::Spin => {}
+                ref p => panic!("shard {} got unexpected packet {:?}", i, p),
+            }
+        }
+    }
+
+    #[test]
+    fn boot_times_out_if_worker_never_boots_domain() {
+        use crate::controller::inner::tests::test_controller;
+        use dataflow::node;
+        use std::sync::Mutex;
+
+        // the "worker" accepts the domain assignment, but never starts the domain
+        let worker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let waddr = worker.local_addr().unwrap();
+        let endpoint: WorkerEndpoint = Arc::new(Mutex::new(TcpSender::connect(&waddr).unwrap()));
+        let _assignments = worker.accept().unwrap();
+
+        let mut c = test_controller(Default::default());
+        let ni = c
+            .ingredients
+            .add_node(node::Node::new("a", &["x"], node::special::Ingress));
+        let mut ip = IndexPair::from(ni);
+        ip.set_local(unsafe { LocalNodeIndex::make(0) });
+        c.ingredients[ni].set_finalized_addr(ip);
+        c.ingredients[ni].add_to(DomainIndex::from(0));
+
+        let start = Instant::now();
+        let booted = DomainHandle::new(
+            DomainIndex::from(0),
+            None,
+            &Logger::root(slog::Discard, o!()),
+            &mut c.ingredients,
+            &c.domain_config,
+            vec![ni],
+            &c.persistence,
+            &c.listen_addr,
+            &c.channel_coordinator,
+            &c.debug_channel,
+            &mut *c.placement,
+            None,
+            &[(waddr, endpoint.clone())],
+            &mut vec![endpoint],
+            c.epoch,
+            None,
+            Some(Duration::from_millis(100)),
+            None,
+        );
+        match booted {
+            Err(BootError {
+                shard,
+                worker,
+                cause,
+                ..
+            }) => {
+                assert_eq!(shard, 0);
+                assert_eq!(worker, waddr);
+                assert!(cause.is_none());
+            }
+            Ok(_) => panic!("domain booted without a worker"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn wait_for_ack_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the "shard" holds its end of the control reply connection open, but never replies
+        let _shard = std::net::TcpStream::connect(&addr).unwrap();
+        let (replies, _) = listener.accept().unwrap();
+        let replies = mio::net::TcpStream::from_stream(replies).unwrap();
+
+        let mut dh = DomainHandle {
+            idx: DomainIndex::from(0),
+            cr_poll: PollingLoop::from_receivers(vec![TcpReceiver::new(replies)]),
+            shards: vec![DomainShardHandle {
+                worker: addr,
+                tx: TcpSender::connect(&addr).unwrap(),
+                is_local: false,
+            }],
+            reply_timeout: Some(Duration::from_millis(100)),
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
+            log: Logger::root(slog::Discard, o!()),
+        };
+
+        let start = Instant::now();
+        match dh.wait_for_ack() {
+            Err(WaitError::Timeout) => {}
+            r => panic!("expected timeout, got {:?}", r),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn late_replies_are_discarded() {
+        use std::sync::Mutex;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            let tx = std::net::TcpStream::connect(&addr).unwrap();
+            let (rx, _) = listener.accept().unwrap();
+            (tx, mio::net::TcpStream::from_stream(rx).unwrap())
+        };
+
+        let (shard_tx, shard_rx) = connect();
+        let mut rx = TcpReceiver::<Box<Packet>>::new(shard_rx);
+        let (replies_tx, replies_rx) = connect();
+        let mut replies = TcpSender::<ControlReplyPacket>::new(replies_tx).unwrap();
+
+        let mut dh = DomainHandle {
+            idx: DomainIndex::from(0),
+            cr_poll: PollingLoop::from_receivers(vec![TcpReceiver::new(replies_rx)]),
+            shards: vec![DomainShardHandle {
+                worker: addr,
+                tx: TcpSender::new(shard_tx).unwrap(),
+                is_local: false,
+            }],
+            reply_timeout: Some(Duration::from_millis(100)),
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
+            log: Logger::root(slog::Discard, o!()),
+        };
+        let mut workers = HashMap::new();
+        let worker = TcpSender::new(connect().0).unwrap();
+        workers.insert(addr, WorkerStatus::new(Arc::new(Mutex::new(worker))));
+
+        // the shard only replies to the first request once the controller has given up on it
+        let probe = box Packet::StateSizeProbe {
+            node: unsafe { LocalNodeIndex::make(0) },
+        };
+        dh.send_to_healthy(probe, &workers).unwrap();
+        match dh.wait_for_state_size() {
+            Err(WaitError::Timeout) => {}
+            r => panic!("expected timeout, got {:?}", r),
+        }
+        replies.send(ControlReplyPacket::StateSize(1, 0)).unwrap();
+
+        // the next request is preceded by a sync, so the late reply is not taken as its ack
+        dh.send_to_healthy(box Packet::Spin, &workers).unwrap();
+        match *rx.recv().unwrap() {
+            Packet::StateSizeProbe { .. } => {}
+            ref p => panic!("shard got unexpected packet {:?}", p),
+        }
+        let id = match *rx.recv().unwrap() {
+            Packet::Sync { id } => id,
+            ref p => panic!("shard got unexpected packet {:?}", p),
+        };
+        match *rx.recv().unwrap() {
+            Packet::Spin => {}
+            ref p => panic!("shard got unexpected packet {:?}", p),
+        }
+        replies.send(ControlReplyPacket::Synced(id)).unwrap();
+        replies.send(ControlReplyPacket::ack()).unwrap();
+        dh.wait_for_ack().unwrap();
+    }
+
+    #[test]
+    fn shutdown_sends_one_quit_per_shard() {
+        use std::sync::Mutex;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            let tx = std::net::TcpStream::connect(&addr).unwrap();
+            let (rx, _) = listener.accept().unwrap();
+            (tx, mio::net::TcpStream::from_stream(rx).unwrap())
+        };
+
+        let (shards, mut rxs): (Vec<_>, Vec<_>) = (0..2)
+            .map(|_| {
+                let (tx, rx) = connect();
+                let shard = DomainShardHandle {
+                    worker: addr,
+                    tx: TcpSender::new(tx).unwrap(),
+                    is_local: false,
+                };
+                (shard, TcpReceiver::<Box<Packet>>::new(rx))
+            }).unzip();
+        let (replies_tx, replies_rx) = connect();
+        let mut replies = TcpSender::<ControlReplyPacket>::new(replies_tx).unwrap();
+
+        let mut dh = DomainHandle {
+            idx: DomainIndex::from(0),
+            cr_poll: PollingLoop::from_receivers(vec![TcpReceiver::new(replies_rx)]),
+            shards,
+            reply_timeout: None,
+            desynced: false,
+            syncing: None,
+            last_sync: 0,
+            log: Logger::root(slog::Discard, o!()),
+        };
+        let mut workers = HashMap::new();
+        let worker = TcpSender::new(connect().0).unwrap();
+        workers.insert(addr, WorkerStatus::new(Arc::new(Mutex::new(worker))));
+
+        assert_eq!(dh.send_quit(&workers), 2);
+        for rx in &mut rxs {
+            match *rx.recv().unwrap() {
+                Packet::Quit => {}
+                ref p => panic!("shard got unexpected packet {:?}", p),
+            }
+            replies.send(ControlReplyPacket::ack()).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(10);
+        dh.wait_for_quit(2, deadline).unwrap();
+
+        // once the handle is gone, the shards see their connections close without further quits
+        drop(dh);
+        for rx in &mut rxs {
+            assert!(rx.recv().is_err());
+        }
+    }
+}
@@ -12,6 +12,7 @@ impl Getter {
     pub(crate) fn new(
         node: NodeIndex,
         sharded: bool,
+        hasher: ShardHasher,
         readers: &Readers,
         ingredients: &Graph,
     ) -> Option<Self> {
@@ -26,7 +27,7 @@ impl Getter {
                     None => return None,
                 }
             }
-            ReadHandle::Sharded(getters)
+            ReadHandle::Sharded(getters, hasher)
         } else {
             let vr = readers.lock().unwrap();
             match vr.get(&(node, 0)).cloned() {
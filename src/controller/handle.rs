@@ -74,6 +74,16 @@ impl<A: Authority> LocalControllerHandle<A> {
 
     #[cfg(test)]
     pub fn migrate<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Migration) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.try_migrate(f).unwrap_or_else(|e| panic!("migration failed: {}", e))
+    }
+
+    /// Like `migrate`, but returns the error if the migration cannot be committed.
+    #[cfg(test)]
+    pub fn try_migrate<F, T>(&mut self, f: F) -> Result<T, String>
     where
         F: FnOnce(&mut Migration) -> T + Send + 'static,
         T: Send + 'static,
@@ -93,12 +103,18 @@ impl<A: Authority> LocalControllerHandle<A> {
             .unwrap();
 
         match fin_rx.wait() {
-            Ok(()) => ret_rx.wait().unwrap(),
+            Ok(Ok(())) => Ok(ret_rx.wait().unwrap()),
+            Ok(Err(e)) => Err(e),
             Err(e) => unreachable!("{:?}", e),
         }
     }
 
     /// Install a new set of policies on the controller.
+    ///
+    /// Row policies are given per table as a predicate over the table and the `UserContext`, e.g.
+    /// `{"table": "Post", "predicate": "WHERE Post.author = UserContext.id"}`. Every query in a
+    /// universe created by `create_universe` only sees the rows its policies allow, including
+    /// rows reached through other views that the query reads from.
     pub fn set_security_config(&mut self, p: String) {
         let url = match (&**self).url() {
             Some(ref url) => String::from(*url),
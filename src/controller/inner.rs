@@ -1,27 +1,55 @@
-use api::debug::stats::GraphStats;
+use api::debug::invariants::InvariantViolation;
+use api::debug::snapshot::{GraphSnapshot, NodeDescription, NodeSnapshot};
+use api::debug::stats::{GraphStats, NodeSize, NodeStats, QueryStats, ShardAssignment};
 use channel::tcp::{SendError, TcpSender};
 use consensus::{Authority, Epoch, STATE_KEY};
 use dataflow::prelude::*;
 use dataflow::{node, payload, DomainConfig};
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{io, time};
 
 use api::builders::*;
-use api::ActivationResult;
+use api::{
+    ActivationResult, BaseSchema, InputInfo, LookupBuilderError, OutputInfo, RecipeInfo,
+    UniverseInfo,
+};
+use crate::controller::domain_handle::{BootError, WaitError};
 use crate::controller::migrate::materialization::Materializations;
-use crate::controller::{ControllerState, DomainHandle, Migration, Recipe, WorkerIdentifier};
-use crate::coordination::CoordinationMessage;
+use crate::controller::sql::SqlIncorporator;
+use crate::controller::{
+    ControllerState, DomainHandle, Migration, MigrationError, MigrationSummary, PlacementPolicy,
+    Recipe, ReplayStatus, ViewIndex, WorkerIdentifier,
+};
+use crate::coordination::{CoordinationMessage, CoordinationPayload};
 
 use hyper::{self, Method, StatusCode};
 use mio::net::TcpListener;
 use petgraph;
 use petgraph::visit::Bfs;
 use slog;
-use std::mem;
+use std::{cmp, mem};
+
+/// How many times to try connecting to a worker that registers before rejecting it.
+const REGISTER_CONNECT_ATTEMPTS: u32 = 5;
+/// How long to wait before retrying a failed connection to a registering worker. The wait doubles
+/// with every attempt.
+const REGISTER_BACKOFF_MS: u64 = 10;
+/// How long to wait for domains to confirm that they are shutting down when the controller exits.
+const QUIT_ACK_TIMEOUT_MS: u64 = 1000;
+
+/// What became of an attempt to admit a registering worker.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Registration {
+    /// The worker was admitted.
+    Admitted,
+    /// The worker could not be reached yet, and its registration should be handled again after
+    /// the given delay.
+    RetryAfter(Duration),
+}
 
 #[derive(Clone)]
 pub(crate) struct WorkerStatus {
@@ -77,16 +105,173 @@ pub struct ControllerInner {
     pub(super) epoch: Epoch,
 
     pending_recovery: Option<(Vec<String>, usize)>,
+    /// Secondary indexes added to recipe views, which are added again when their views are
+    /// recovered.
+    view_indexes: Vec<ViewIndex>,
 
     quorum: usize,
     heartbeat_every: Duration,
     healthcheck_every: Duration,
+    /// Number of missed heartbeats after which a worker is considered failed.
+    missed_heartbeats_before_failure: u32,
+    /// Number of missed heartbeats after which a worker is considered failed if some other worker
+    /// has also just failed.
+    missed_heartbeats_with_other_failures: u32,
     last_checked_workers: Instant,
+    /// Set when a domain fails to reply in time, so that worker health is checked on the next
+    /// heartbeat rather than at the next scheduled health check.
+    recheck_workers: bool,
+    pub(super) domain_reply_timeout: Option<Duration>,
+    pub(super) domain_boot_timeout: Option<Duration>,
+    /// How many bytes of packets to hold back for each domain shard, if any.
+    pub(super) packet_coalescing: Option<usize>,
+
+    /// Budget for the total size of partial state across all domains, if any.
+    memory_limit: Option<u64>,
+    last_checked_memory: Instant,
+
+    /// Decides which workers new domain shards are placed on.
+    pub(super) placement: Box<PlacementPolicy>,
+    /// Workers that the domains of particular bases must be placed on, overriding `placement`.
+    pub(super) pinned_bases: HashMap<String, WorkerIdentifier>,
+
+    /// Security universes that have been created, in order.
+    universes: Vec<UniverseInfo>,
+
+    /// If set, a JSON summary of every migration is written here, one per line.
+    migration_log: Option<Box<io::Write + Send>>,
 
     log: slog::Logger,
 }
 
+/// Parse the query string of a request URL into its variables.
+///
+/// Keys and values are percent-decoded, and `+` decodes to a space. If a key is given more than
+/// once, the last value wins.
+fn parse_query(query: &Option<String>) -> HashMap<String, String> {
+    parse_query_pairs(query).into_iter().collect()
+}
+
+/// Convert the text `value` of a key column into a value of the column's SQL type, if known.
+fn key_value(value: String, sql_type: Option<&str>) -> Result<DataType, String> {
+    let sql_type = sql_type.map(|t| t.to_lowercase()).unwrap_or_default();
+    if sql_type.contains("int") {
+        value
+            .parse::<i64>()
+            .map(DataType::from)
+            .map_err(|_| format!("{} is not a valid {}", value, sql_type))
+    } else if sql_type.contains("char") || sql_type.contains("text") {
+        Ok(value.into())
+    } else {
+        Ok(match value.parse::<i64>() {
+            Ok(n) => n.into(),
+            Err(_) => value.into(),
+        })
+    }
+}
+
+/// Parse the query string of a request URL into its variables, in the order they were given.
+///
+/// Unlike `parse_query`, this keeps every value of a repeated key.
+fn parse_query_pairs(query: &Option<String>) -> Vec<(String, String)> {
+    let query = match *query {
+        Some(ref query) => query,
+        None => return Vec::new(),
+    };
+    query
+        .split('&')
+        .filter(|var| !var.is_empty())
+        .map(|var| {
+            let mut kv = var.splitn(2, '=');
+            let key = percent_decode(kv.next().unwrap());
+            let value = percent_decode(kv.next().unwrap_or(""));
+            (key, value)
+        }).collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                decoded.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+                i += 2;
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The kind of node `n` is, or a description of its operator if it is an internal node.
+fn operator_name(n: &Node) -> String {
+    if n.is_source() {
+        String::from("source")
+    } else if n.is_dropped() {
+        String::from("dropped")
+    } else if n.is_base() {
+        String::from("base")
+    } else if n.is_reader() {
+        String::from("reader")
+    } else if n.is_ingress() {
+        String::from("ingress")
+    } else if n.is_egress() {
+        String::from("egress")
+    } else if n.is_sharder() {
+        String::from("sharder")
+    } else {
+        n.description()
+    }
+}
+
 pub(crate) fn graphviz(graph: &Graph, materializations: &Materializations) -> String {
+    graphviz_filtered(graph, materializations, None, |_| true)
+}
+
+/// Produce the same output as `graphviz`, but only for the nodes that are at most `depth` edges
+/// away from `around` (following edges in either direction).
+pub(crate) fn graphviz_around(
+    graph: &Graph,
+    materializations: &Materializations,
+    around: NodeIndex,
+    depth: usize,
+) -> String {
+    let mut within = HashSet::new();
+    within.insert(around);
+    let mut frontier = vec![around];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for ni in frontier {
+            for neighbor in graph.neighbors_undirected(ni) {
+                if within.insert(neighbor) {
+                    next.push(neighbor);
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    graphviz_filtered(graph, materializations, None, |ni| within.contains(&ni))
+}
+
+/// Render the nodes for which `include` is true, labeling those that appear in `sizes` with their
+/// number of rows and bytes of state.
+fn graphviz_filtered<F>(
+    graph: &Graph,
+    materializations: &Materializations,
+    sizes: Option<&HashMap<NodeIndex, (u64, u64)>>,
+    include: F,
+) -> String
+where
+    F: Fn(NodeIndex) -> bool,
+{
     let mut s = String::new();
 
     let indentln = |s: &mut String| s.push_str("    ");
@@ -99,16 +284,21 @@ pub(crate) fn graphviz(graph: &Graph, materializations: &Materializations) -> St
     s.push_str("node [shape=record, fontsize=10]\n");
 
     // node descriptions.
-    for index in graph.node_indices() {
+    for index in graph.node_indices().filter(|&ni| include(ni)) {
         let node = &graph[index];
         let materialization_status = materializations.get_status(&index, node);
         indentln(&mut s);
         s.push_str(&format!("{}", index.index()));
-        s.push_str(&node.describe(index, materialization_status));
+        let size = sizes.and_then(|sizes| sizes.get(&index)).cloned();
+        s.push_str(&node.describe(index, materialization_status, size));
     }
 
     // edges.
-    for (_, edge) in graph.raw_edges().iter().enumerate() {
+    for edge in graph
+        .raw_edges()
+        .iter()
+        .filter(|e| include(e.source()) && include(e.target()))
+    {
         indentln(&mut s);
         s.push_str(&format!(
             "{} -> {}",
@@ -124,6 +314,154 @@ pub(crate) fn graphviz(graph: &Graph, materializations: &Materializations) -> St
     s
 }
 
+/// Check the structural invariants of `graph`, given the number of shards of each domain.
+///
+/// Removed nodes are ignored, as are edges that lead to them.
+fn check_invariants(
+    graph: &Graph,
+    domain_shards: &HashMap<DomainIndex, usize>,
+) -> Vec<InvariantViolation> {
+    use petgraph::EdgeDirection::{Incoming, Outgoing};
+
+    let mut violations = Vec::new();
+    for ni in graph.node_indices() {
+        let n = &graph[ni];
+        if n.is_source() || n.is_dropped() {
+            continue;
+        }
+
+        if graph.neighbors_directed(ni, Incoming).next().is_none() {
+            violations.push(InvariantViolation::NoParents { node: ni.index() });
+        }
+
+        if let Ok(target) = n.with_reader(|r| r.is_for()) {
+            let exists = target.index() < graph.node_count()
+                && !graph[target].is_source()
+                && !graph[target].is_dropped();
+            if !exists {
+                violations.push(InvariantViolation::DanglingReader {
+                    reader: ni.index(),
+                    for_node: target.index(),
+                });
+            }
+        }
+
+        if !n.has_domain() {
+            violations.push(InvariantViolation::UnknownDomain {
+                node: ni.index(),
+                domain: None,
+            });
+            continue;
+        }
+        let domain = n.domain();
+        match domain_shards.get(&domain) {
+            None => violations.push(InvariantViolation::UnknownDomain {
+                node: ni.index(),
+                domain: Some(domain.index()),
+            }),
+            Some(&shards) => {
+                let node_shards = n.sharded_by().shards().unwrap_or(1);
+                if node_shards != shards {
+                    violations.push(InvariantViolation::ShardMismatch {
+                        node: ni.index(),
+                        domain: domain.index(),
+                        node_shards,
+                        domain_shards: shards,
+                    });
+                }
+            }
+        }
+
+        for child in graph.neighbors_directed(ni, Outgoing) {
+            let c = &graph[child];
+            if c.is_dropped() || !c.has_domain() {
+                continue;
+            }
+
+            if c.domain() != domain {
+                if !(n.is_egress() || n.is_sharder()) || !c.is_ingress() {
+                    violations.push(InvariantViolation::UnroutedEdge {
+                        from: ni.index(),
+                        to: child.index(),
+                    });
+                }
+            } else if n.is_egress() {
+                violations.push(InvariantViolation::UnmatchedEgress {
+                    egress: ni.index(),
+                    child: child.index(),
+                });
+            } else if c.is_ingress() {
+                violations.push(InvariantViolation::UnmatchedIngress {
+                    ingress: child.index(),
+                    parent: ni.index(),
+                });
+            }
+        }
+    }
+    violations
+}
+
+/// Order all nodes of `graph` so that every node comes after all of its ancestors.
+///
+/// Among the nodes whose ancestors have all been placed, the lowest-indexed one goes first, so the
+/// order only depends on the structure of the graph.
+fn topological_sort(graph: &Graph) -> Vec<NodeIndex> {
+    use petgraph::EdgeDirection::{Incoming, Outgoing};
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    let mut waiting_on: HashMap<_, _> = graph
+        .node_indices()
+        .map(|ni| (ni, graph.neighbors_directed(ni, Incoming).count()))
+        .collect();
+    let mut ready: BinaryHeap<_> = waiting_on
+        .iter()
+        .filter(|&(_, &n)| n == 0)
+        .map(|(&ni, _)| Reverse(ni))
+        .collect();
+
+    let mut order = Vec::with_capacity(waiting_on.len());
+    while let Some(Reverse(ni)) = ready.pop() {
+        for child in graph.neighbors_directed(ni, Outgoing) {
+            let n = waiting_on.get_mut(&child).unwrap();
+            *n -= 1;
+            if *n == 0 {
+                ready.push(Reverse(child));
+            }
+        }
+        order.push(ni);
+    }
+    order
+}
+
+/// An error reply to an external request.
+pub(crate) struct ErrorReply {
+    pub(crate) status: StatusCode,
+    /// JSON-encoded details about the error, if there are any.
+    pub(crate) body: Option<String>,
+}
+
+impl From<StatusCode> for ErrorReply {
+    fn from(status: StatusCode) -> Self {
+        ErrorReply { status, body: None }
+    }
+}
+
+impl From<LookupBuilderError> for ErrorReply {
+    fn from(e: LookupBuilderError) -> Self {
+        let status = match e {
+            LookupBuilderError::NotFound(_)
+            | LookupBuilderError::NoReader(_)
+            | LookupBuilderError::NotATable(_) => StatusCode::NOT_FOUND,
+            LookupBuilderError::NotMaterialized(_) => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        ErrorReply {
+            status,
+            body: Some(serde_json::to_string(&e).unwrap()),
+        }
+    }
+}
+
 impl ControllerInner {
     pub fn external_request<A: Authority + 'static>(
         &mut self,
@@ -132,46 +470,160 @@ impl ControllerInner {
         query: Option<String>,
         body: Vec<u8>,
         authority: &Arc<A>,
-    ) -> Result<Result<String, String>, StatusCode> {
+    ) -> Result<Result<String, String>, ErrorReply> {
         use serde_json as json;
 
         match (&method, path.as_ref()) {
-            (&Method::GET, "/graph") => return Ok(Ok(self.graphviz())),
+            (&Method::GET, "/graph") => {
+                let vars = parse_query(&query);
+                let around: Option<usize> = match vars.get("around") {
+                    Some(n) => Some(n.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+                    None => None,
+                };
+                let depth = match vars.get("depth") {
+                    Some(d) => d.parse().map_err(|_| StatusCode::BAD_REQUEST)?,
+                    None => 1,
+                };
+                return match around {
+                    Some(n) if n < self.ingredients.node_count() => Ok(Ok(graphviz_around(
+                        &self.ingredients,
+                        &self.materializations,
+                        NodeIndex::new(n),
+                        depth,
+                    ))),
+                    Some(_) => Err(StatusCode::NOT_FOUND.into()),
+                    None => Ok(Ok(self.graphviz())),
+                };
+            }
             (&Method::POST, "/graphviz") => {
                 return Ok(Ok(json::to_string(&self.graphviz()).unwrap()))
             }
-            (&Method::GET, "/get_statistics") => {
+            (&Method::GET, "/graph_snapshot") | (&Method::POST, "/graph_snapshot") => {
+                return Ok(Ok(json::to_string(&self.graph_snapshot()).unwrap()))
+            }
+            (&Method::GET, "/check") | (&Method::POST, "/check") => {
+                return Ok(Ok(json::to_string(&self.check_invariants()).unwrap()))
+            }
+            (&Method::GET, path) | (&Method::POST, path) if path.starts_with("/node/") => {
+                let ni: usize = path["/node/".len()..]
+                    .parse()
+                    .map_err(|_| StatusCode::BAD_REQUEST)?;
+                return match self.describe_node(NodeIndex::new(ni)) {
+                    Some(node) => Ok(Ok(json::to_string(&node).unwrap())),
+                    None => Err(StatusCode::NOT_FOUND.into()),
+                };
+            }
+            (&Method::GET, "/universes") | (&Method::POST, "/universes") => {
+                return Ok(Ok(json::to_string(&self.universes).unwrap()))
+            }
+            (&Method::GET, "/get_statistics") | (&Method::POST, "/get_statistics") => {
                 return Ok(Ok(json::to_string(&self.get_statistics()).unwrap()))
             }
+            (&Method::GET, "/query_stats") | (&Method::POST, "/query_stats") => {
+                return Ok(Ok(json::to_string(&self.query_statistics()).unwrap()))
+            }
+            (&Method::GET, "/metrics") => return Ok(Ok(self.get_statistics().prometheus())),
+            (&Method::POST, "/metrics") => {
+                return Ok(Ok(json::to_string(&self.get_statistics().prometheus()).unwrap()))
+            }
+            (&Method::GET, "/recipe") | (&Method::POST, "/recipe") => {
+                let recipe = RecipeInfo {
+                    text: self.recipe.text(),
+                    version: self.recipe.version(),
+                };
+                return Ok(Ok(json::to_string(&recipe).unwrap()));
+            }
+            (&Method::GET, "/recipe/version") | (&Method::POST, "/recipe/version") => {
+                return Ok(Ok(json::to_string(&self.recipe.version()).unwrap()))
+            }
             _ => {}
         }
 
         if self.pending_recovery.is_some() || self.workers.len() < self.quorum {
-            return Err(StatusCode::SERVICE_UNAVAILABLE);
+            return Err(StatusCode::SERVICE_UNAVAILABLE.into());
         }
 
-        match (method, path.as_ref()) {
+        let reply = match (method, path.as_ref()) {
             (Method::GET, "/flush_partial") => {
                 Ok(Ok(json::to_string(&self.flush_partial()).unwrap()))
             }
+            (Method::POST, "/flush_partial_node") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|node: NodeIndex| {
+                    if node.index() >= self.ingredients.node_count()
+                        || self.ingredients[node].is_source()
+                        || self.ingredients[node].is_dropped()
+                    {
+                        return Err(format!("no node with index {}", node.index()));
+                    }
+                    Ok(json::to_string(&self.flush_partial_node(node)).unwrap())
+                }),
+            (Method::POST, "/pin_base") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(base, worker)| Ok(json::to_string(&self.pin_base(base, worker)).unwrap())),
+            (Method::POST, "/decommission") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|worker| {
+                    self.decommission_worker(worker)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/reshard_view") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args: (String, usize)| {
+                    self.reshard_view(&args.0, args.1)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/rename_view") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args: (String, String)| {
+                    self.rename_view(authority, &args.0, &args.1)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/add_view_index") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(name, view, key)| {
+                    let index = ViewIndex { name, view, key };
+                    self.add_view_index(authority, index)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/set_memory_limit") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| Ok(json::to_string(&self.set_memory_limit(args)).unwrap())),
+            (Method::POST, "/graphviz_with_stats") => {
+                Ok(Ok(json::to_string(&self.graphviz_with_stats()).unwrap()))
+            }
+            (Method::GET, "/node_sizes") | (Method::POST, "/node_sizes") => {
+                Ok(Ok(json::to_string(&self.node_sizes()).unwrap()))
+            }
             (Method::POST, "/inputs") => Ok(Ok(json::to_string(&self.inputs()).unwrap())),
             (Method::POST, "/outputs") => Ok(Ok(json::to_string(&self.outputs()).unwrap())),
-            (Method::GET, "/instances") => Ok(Ok(json::to_string(&self.get_instances()).unwrap())),
-            (Method::GET, "/nodes") => {
-                // TODO(malte): this is a pretty yucky hack, but hyper doesn't provide easy access
-                // to individual query variables unfortunately. We'll probably want to factor this
-                // out into a helper method.
-                let nodes = if let Some(query) = query {
-                    let vars: Vec<_> = query.split("&").map(String::from).collect();
-                    if let Some(n) = &vars.into_iter().find(|v| v.starts_with("w=")) {
-                        self.nodes_on_worker(Some(&n[2..].parse().unwrap()))
-                    } else {
-                        self.nodes_on_worker(None)
-                    }
-                } else {
-                    // all data-flow nodes
-                    self.nodes_on_worker(None)
+            (Method::POST, "/inputs_detailed") => {
+                Ok(Ok(json::to_string(&self.inputs_detailed()).unwrap()))
+            }
+            (Method::POST, "/outputs_detailed") => {
+                Ok(Ok(json::to_string(&self.outputs_detailed()).unwrap()))
+            }
+            (Method::POST, "/replay_paths") => {
+                Ok(Ok(json::to_string(&self.replay_paths()).unwrap()))
+            }
+            (Method::POST, "/column_lineage") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|(ni, column)| Ok(json::to_string(&self.column_lineage(ni, column)).unwrap())),
+            (Method::GET, "/instances") | (Method::POST, "/instances") => {
+                Ok(Ok(json::to_string(&self.get_instances()).unwrap()))
+            }
+            (Method::GET, "/assignments") | (Method::POST, "/assignments") => {
+                Ok(Ok(json::to_string(&self.domain_assignments()).unwrap()))
+            }
+            (Method::GET, "/nodes") | (Method::POST, "/nodes") => {
+                // all data-flow nodes, unless a worker is given. bases are only listed on request.
+                let vars = parse_query(&query);
+                let worker: Option<WorkerIdentifier> = match vars.get("w") {
+                    Some(w) => Some(w.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+                    None => None,
                 };
+                let with_bases = vars.contains_key("bases");
+                let nodes = self.nodes_on_worker(worker.as_ref());
                 Ok(Ok(json::to_string(
                     &nodes
                         .into_iter()
@@ -179,18 +631,37 @@ impl ControllerInner {
                             let n = &self.ingredients[ni];
                             if n.is_internal() {
                                 Some((ni, n.name(), n.description()))
+                            } else if with_bases && n.is_base() {
+                                Some((ni, n.name(), String::from("Base")))
                             } else {
                                 None
                             }
                         }).collect::<Vec<_>>(),
                 ).unwrap()))
             }
-            (Method::POST, "/table_builder") => json::from_slice(&body)
-                .map_err(|_| StatusCode::BAD_REQUEST)
-                .map(|args| Ok(json::to_string(&self.table_builder(args)).unwrap())),
-            (Method::POST, "/view_builder") => json::from_slice(&body)
-                .map_err(|_| StatusCode::BAD_REQUEST)
-                .map(|args| Ok(json::to_string(&self.view_builder(args)).unwrap())),
+            (Method::POST, "/table_builder") => {
+                let name: String = json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+                let tb = self.table_builder(&name)?;
+                Ok(Ok(json::to_string(&tb).unwrap()))
+            }
+            (Method::GET, "/base_schema") | (Method::POST, "/base_schema") => {
+                let name: String = match parse_query(&query).remove("name") {
+                    Some(name) => name,
+                    None => json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?,
+                };
+                let schema = self.base_schema(&name)?;
+                Ok(Ok(json::to_string(&schema).unwrap()))
+            }
+            (Method::POST, "/view_builder") => {
+                let name: String = json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+                let vb = self.view_builder(&name)?;
+                Ok(Ok(json::to_string(&vb).unwrap()))
+            }
+            (Method::GET, path) if path.starts_with("/query/") => {
+                // the lookup itself may block, so it is left to the caller
+                let prepared = self.prepare_view_query(&path["/query/".len()..], &query)?;
+                Ok(Ok(json::to_string(&prepared).unwrap()))
+            }
             (Method::POST, "/extend_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -200,9 +671,20 @@ impl ControllerInner {
             (Method::POST, "/install_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
-                    self.install_recipe(authority, args)
+                    let force = parse_query(&query).contains_key("force");
+                    self.install_recipe(authority, args, force)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/validate_recipe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    let replace = parse_query(&query).contains_key("replace");
+                    self.validate_recipe(args, replace)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/rollback_recipe") => Ok(self
+                .rollback_recipe(authority)
+                .map(|r| json::to_string(&r).unwrap())),
             (Method::POST, "/set_security_config") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -221,8 +703,9 @@ impl ControllerInner {
                     self.remove_nodes(vec![args].as_slice())
                         .map(|r| json::to_string(&r).unwrap())
                 }),
-            _ => return Err(StatusCode::NOT_FOUND),
-        }
+            _ => Err(StatusCode::NOT_FOUND),
+        };
+        reply.map_err(ErrorReply::from)
     }
 
     pub(crate) fn handle_register(
@@ -230,13 +713,58 @@ impl ControllerInner {
         msg: &CoordinationMessage,
         remote: &SocketAddr,
         read_listen_addr: SocketAddr,
-    ) -> Result<(), io::Error> {
-        info!(
-            self.log,
-            "new worker registered from {:?}, which listens on {:?}", msg.source, remote
-        );
+        attempt: u32,
+    ) -> Result<Registration, io::Error> {
+        self.register_worker(msg, remote, read_listen_addr, attempt, TcpSender::connect)
+    }
+
+    /// Makes the `attempt`th attempt to admit a registering worker, connecting to it with
+    /// `connect`.
+    ///
+    /// A worker may not be reachable yet at the moment it registers. If connecting fails, this
+    /// does not wait and try again, since that would hold up every other event the controller has
+    /// to handle. Instead, it asks the caller to retry after a backoff that doubles with every
+    /// attempt. The registration only fails once `REGISTER_CONNECT_ATTEMPTS` attempts have, or if
+    /// the worker is rejoining after a failure, and its old domains could not be moved off it.
+    fn register_worker<F>(
+        &mut self,
+        msg: &CoordinationMessage,
+        remote: &SocketAddr,
+        read_listen_addr: SocketAddr,
+        attempt: u32,
+        connect: F,
+    ) -> Result<Registration, io::Error>
+    where
+        F: FnOnce(&SocketAddr) -> Result<TcpSender<CoordinationMessage>, io::Error>,
+    {
+        if attempt == 1 {
+            info!(
+                self.log,
+                "new worker registered from {:?}, which listens on {:?}", msg.source, remote
+            );
+        }
+
+        if self.workers.get(&msg.source).map(|ws| !ws.healthy).unwrap_or(false) {
+            self.forget_failed_worker(&msg.source)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
 
-        let sender = Arc::new(Mutex::new(TcpSender::connect(remote)?));
+        let sender = match connect(remote) {
+            Ok(sender) => sender,
+            Err(e) => {
+                if attempt >= REGISTER_CONNECT_ATTEMPTS {
+                    return Err(e);
+                }
+                warn!(
+                    self.log,
+                    "failed to connect to registering worker {:?}: {}", msg.source, e;
+                    "attempt" => attempt
+                );
+                let backoff = Duration::from_millis(REGISTER_BACKOFF_MS << (attempt - 1));
+                return Ok(Registration::RetryAfter(backoff));
+            }
+        };
+        let sender = Arc::new(Mutex::new(sender));
         let ws = WorkerStatus::new(sender.clone());
         self.workers.insert(msg.source.clone(), ws);
         self.read_addrs.insert(msg.source.clone(), read_listen_addr);
@@ -253,42 +781,111 @@ impl ControllerInner {
                     Some(self.log.clone()),
                 );
                 for r in recipes {
-                    self.apply_recipe(self.recipe.clone().extend(&r).unwrap())
+                    self.apply_recipe(self.recipe.clone().extend(&r).unwrap(), false)
                         .unwrap();
                 }
+                self.restore_view_indexes();
+            }
+        }
+
+        Ok(Registration::Admitted)
+    }
+
+    /// Forget what we knew about a failed worker that has come back, so that it rejoins as a fresh
+    /// worker without any domains.
+    ///
+    /// The worker restarted, so none of the domain shards that were assigned to it run anymore.
+    /// Failure recovery has normally moved the queries and bases with nodes in those domains to
+    /// other workers already, but if it failed, they are moved again now. We then drop the
+    /// handles of all the worker's domains, and tell their shards on other workers to quit. If
+    /// any of those domains still holds nodes, the worker cannot rejoin, and stays failed.
+    fn forget_failed_worker(&mut self, wi: &WorkerIdentifier) -> Result<(), String> {
+        info!(self.log, "failed worker {:?} has rejoined", wi);
+        let affected_nodes = self.get_failed_nodes(wi);
+        if !affected_nodes.is_empty() {
+            if let Err(e) = self.remigrate(affected_nodes) {
+                crit!(self.log, "failed to recover domains of rejoined worker: {}", e);
             }
         }
 
+        let stale: Vec<_> = self
+            .domains
+            .iter()
+            .filter(|(_, dh)| dh.assigned_to_worker(wi))
+            .map(|(&di, _)| di)
+            .collect();
+        let remaining = self
+            .topo_order()
+            .into_iter()
+            .find(|&ni| stale.contains(&self.ingredients[ni].domain()));
+        if let Some(ni) = remaining {
+            return Err(format!("node {} could not be moved off {:?}", ni.index(), wi));
+        }
+
+        for di in stale {
+            // the worker is still marked as failed, so only shards elsewhere are asked to quit
+            self.domains.remove(&di).unwrap().send_quit(&self.workers);
+        }
+        self.workers.remove(wi);
+        self.read_addrs.remove(wi);
         Ok(())
     }
 
     fn check_worker_liveness(&mut self) {
+        let failed = self.find_failed_workers();
+        if !failed.is_empty() {
+            self.handle_failed_workers(failed);
+        }
+    }
+
+    /// Mark workers that have missed too many heartbeats as unhealthy, and return them.
+    fn find_failed_workers(&mut self) -> Vec<WorkerIdentifier> {
         let mut any_failed = false;
 
         // check if there are any newly failed workers
-        if self.last_checked_workers.elapsed() > self.healthcheck_every {
+        if self.recheck_workers || self.last_checked_workers.elapsed() > self.healthcheck_every {
+            let timeout = self.heartbeat_every * self.missed_heartbeats_before_failure;
             for (_addr, ws) in self.workers.iter() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 4 {
+                if ws.healthy && ws.last_heartbeat.elapsed() > timeout {
                     any_failed = true;
                 }
             }
             self.last_checked_workers = Instant::now();
+            self.recheck_workers = false;
         }
 
-        // if we have newly failed workers, iterate again to find all workers that have missed >= 3
-        // heartbeats. This is necessary so that we correctly handle correlated failures of
-        // workers.
+        // if we have newly failed workers, iterate again to find all workers that have missed
+        // almost as many heartbeats. This is necessary so that we correctly handle correlated
+        // failures of workers.
+        let mut failed = Vec::new();
         if any_failed {
-            let mut failed = Vec::new();
+            let timeout = self.heartbeat_every * self.missed_heartbeats_with_other_failures;
             for (addr, ws) in self.workers.iter_mut() {
-                if ws.healthy && ws.last_heartbeat.elapsed() > self.heartbeat_every * 3 {
+                if ws.healthy && ws.last_heartbeat.elapsed() > timeout {
                     error!(self.log, "worker at {:?} has failed!", addr);
                     ws.healthy = false;
                     failed.push(addr.clone());
                 }
             }
-            self.handle_failed_workers(failed);
         }
+        failed
+    }
+
+    /// Called when a domain does not reply to a control message within the reply timeout. The
+    /// domain's worker has likely failed, so we check for failed workers on the next heartbeat.
+    fn handle_unresponsive_domain(&mut self, di: DomainIndex) {
+        warn!(self.log, "domain did not reply in time"; "domain" => di.index());
+        self.recheck_workers = true;
+    }
+
+    /// The given new domain did not boot in time. Its worker may have failed, so as for an
+    /// unresponsive domain, we check for failed workers on the next heartbeat.
+    pub(super) fn handle_failed_boot(&mut self, e: &BootError) {
+        warn!(self.log, "domain did not boot in time";
+              "domain" => e.domain.index(),
+              "shard" => e.shard,
+              "worker" => ?e.worker);
+        self.recheck_workers = true;
     }
 
     fn handle_failed_workers(&mut self, failed: Vec<WorkerIdentifier>) {
@@ -299,14 +896,25 @@ impl ControllerInner {
             affected_nodes.extend(self.get_failed_nodes(&wi));
         }
 
-        // then, figure out which queries are affected (and thus must be removed and added again in
+        if let Err(e) = self.remigrate(affected_nodes) {
+            crit!(self.log, "failed to recover from worker failure: {}", e);
+        }
+    }
+
+    /// Remove and re-add all queries that the given nodes belong to, so that their domains are
+    /// placed anew.
+    ///
+    /// If the queries cannot be removed, nothing changes. If they cannot be added again, they
+    /// stay removed, and the recipe no longer has them.
+    fn remigrate(&mut self, affected_nodes: Vec<NodeIndex>) -> Result<(), String> {
+        // figure out which queries are affected (and thus must be removed and added again in
         // a migration)
         let affected_queries = self.recipe.queries_for_nodes(affected_nodes);
         let (recovery, mut original) = self.recipe.make_recovery(affected_queries);
 
         // activate recipe
-        self.apply_recipe(recovery.clone())
-            .expect("failed to apply recovery recipe");
+        self.apply_recipe(recovery.clone(), false)
+            .map_err(|e| format!("failed to remove affected queries: {}", e))?;
 
         // we must do this *after* the migration, since the migration itself modifies the recipe in
         // `recovery`, and we currently need to clone it here.
@@ -316,8 +924,271 @@ impl ControllerInner {
         original.set_sql_inc(tmp.sql_inc().clone());
 
         // back to original recipe, which should add the query again
-        self.apply_recipe(original)
-            .expect("failed to activate original recipe");
+        self.apply_recipe(original, false)
+            .map_err(|e| format!("failed to add affected queries again: {}", e))?;
+
+        // removing the queries also removed any secondary indexes on their views
+        self.restore_view_indexes();
+        Ok(())
+    }
+
+    /// Add a secondary index on the columns `key` of the recipe view `view`, which can be read
+    /// as the view `name`.
+    ///
+    /// The recipe does not describe the index, so it is recorded in the controller state
+    /// separately. It is added again whenever `view` is recovered, and when the controller
+    /// restores the recipe after a restart.
+    pub(crate) fn add_view_index<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        index: ViewIndex,
+    ) -> Result<(), String> {
+        if self.recipe.node_addr_for(&index.name).is_ok() {
+            return Err(format!("a view named {} already exists", index.name));
+        }
+        self.maintain_view_indexes(vec![index.clone()])?;
+
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.view_indexes.push(index.clone());
+                    Ok(state)
+                }
+            }).is_err()
+        {
+            return Err("Failed to persist view index".to_owned());
+        }
+        self.view_indexes.push(index);
+        Ok(())
+    }
+
+    /// Add back the secondary indexes whose readers are missing, because their views were
+    /// removed and added again. Indexes on views that are no longer in the recipe are forgotten.
+    fn restore_view_indexes(&mut self) {
+        {
+            let recipe = &self.recipe;
+            let log = &self.log;
+            self.view_indexes.retain(|idx| {
+                let exists = recipe.node_addr_for(&idx.view).is_ok();
+                if !exists {
+                    warn!(log, "dropping index on removed view"; "index" => &idx.name);
+                }
+                exists
+            });
+        }
+
+        let graph = &self.ingredients;
+        let missing: Vec<_> = self
+            .view_indexes
+            .iter()
+            .filter(|idx| {
+                !graph
+                    .node_indices()
+                    .any(|ni| graph[ni].is_reader() && graph[ni].name() == idx.name)
+            }).cloned()
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        info!(self.log, "restoring view indexes"; "#indexes" => missing.len());
+        if let Err(e) = self.maintain_view_indexes(missing) {
+            crit!(self.log, "failed to restore view indexes: {}", e);
+        }
+    }
+
+    /// Add readers for the given secondary indexes in a single migration.
+    fn maintain_view_indexes(&mut self, indexes: Vec<ViewIndex>) -> Result<(), String> {
+        let mut keyed = Vec::with_capacity(indexes.len());
+        for idx in indexes {
+            let ni = self
+                .recipe
+                .node_addr_for(&idx.view)
+                .map_err(|_| format!("no view named {}", idx.view))?;
+            let fields = self.ingredients[ni].fields();
+            let key = idx
+                .key
+                .iter()
+                .map(|c| {
+                    fields
+                        .iter()
+                        .position(|f| f == c)
+                        .ok_or_else(|| format!("view {} has no column {}", idx.view, c))
+                }).collect::<Result<Vec<_>, _>>()?;
+            keyed.push((idx.name, ni, key));
+        }
+
+        info!(self.log, "starting migration: adding view indexes");
+        let (r, summary) = self.run_migration(Default::default(), |mig| {
+            for (name, ni, key) in keyed {
+                mig.maintain_secondary(name, ni, &key)?;
+            }
+            Ok(())
+        });
+        let summary = summary.map_err(|e| format!("migration failed: {}", e))?;
+        self.log_migration(&summary);
+        r
+    }
+
+    /// Change the number of shards of the view `name` to `shards`.
+    ///
+    /// A new reader with `shards` shards is added next to the view's current one, behind a
+    /// sharder of its own, and is filled by replay from the view's state. Lookups then move over
+    /// to the new reader, and the old one is removed along with any nodes that only fed it. The
+    /// view's own node keeps its sharding, and serves lookups throughout, but `View` handles for
+    /// it must be obtained anew afterwards. The new number of shards is kept as a sharding
+    /// directive in the recipe, so the view keeps it if its query is recovered later.
+    pub fn reshard_view(&mut self, name: &str, shards: usize) -> Result<(), String> {
+        let ni = self
+            .recipe
+            .node_addr_for(name)
+            .map_err(|_| format!("no view named {}", name))?;
+        if self.sharding.is_none() {
+            return Err(String::from("sharding is disabled"));
+        }
+        if shards < 2 {
+            return Err(format!("cannot reshard {} to {} shards", name, shards));
+        }
+        match self.ingredients[ni].sharded_by() {
+            Sharding::ByColumn(..) => {}
+            s => return Err(format!("view {} is not sharded by a column ({:?})", name, s)),
+        }
+        let old = self
+            .find_view_for(ni)
+            .ok_or_else(|| format!("view {} has no reader", name))?;
+        match self.ingredients[old].with_reader(|r| r.key().map(|k| k.len())) {
+            Ok(Some(1)) => {}
+            _ => return Err(format!("view {} is not keyed by a single column", name)),
+        }
+        if self.ingredients[old].sharded_by().shards() == Some(shards) {
+            return Ok(());
+        }
+
+        info!(self.log, "starting migration: resharding view"; "view" => name, "shards" => shards);
+        let (_, summary) = self.run_migration(Default::default(), |mig| {
+            mig.maintain_resharded(old, shards)
+        });
+        let summary = summary.map_err(|e| format!("migration failed: {}", e))?;
+        self.log_migration(&summary);
+
+        // the new reader has been filled, so lookups can move over to it
+        let removals = self.detach_reader(old);
+        self.remove_nodes(&removals)?;
+        self.recipe.set_shards(name, shards);
+        Ok(())
+    }
+
+    /// Disconnect the reader `reader` from the graph, along with any nodes between it and the
+    /// node it is for that feed nothing else, and return all of them so they can be removed.
+    ///
+    /// Those are the sharder and ingress nodes in front of a reader that is sharded differently
+    /// from the node it is for.
+    fn detach_reader(&mut self, reader: NodeIndex) -> Vec<NodeIndex> {
+        let of = self.ingredients[reader].with_reader(|r| r.is_for()).unwrap();
+
+        let mut removals = Vec::new();
+        let mut nodes = vec![reader];
+        while let Some(node) = nodes.pop() {
+            let mut parents = self
+                .ingredients
+                .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
+                .detach();
+            while let Some(parent) = parents.next_node(&self.ingredients) {
+                let edge = self.ingredients.find_edge(parent, node).unwrap();
+                self.ingredients.remove_edge(edge);
+
+                if parent != of && self
+                    .ingredients
+                    .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
+                    .count() == 0
+                {
+                    nodes.push(parent);
+                }
+            }
+            removals.push(node);
+        }
+        removals
+    }
+
+    /// Move all domains off the worker `wi`, and then forget about it, so that the worker can be
+    /// shut down without triggering failure recovery.
+    ///
+    /// The queries whose domains run on the worker are moved the same way as if the worker had
+    /// failed, so their state is rebuilt by replay on their new workers. Base tables cannot be
+    /// moved, so a worker that hosts any cannot be decommissioned. If the queries cannot be
+    /// moved, the worker stays, and is again eligible for new domains.
+    pub fn decommission_worker(&mut self, wi: WorkerIdentifier) -> Result<(), String> {
+        match self.workers.get(&wi) {
+            None => return Err(format!("unknown worker {:?}", wi)),
+            Some(ws) if !ws.healthy => return Err(format!("worker {:?} has failed", wi)),
+            Some(_) => {}
+        }
+        if !self.workers.iter().any(|(&w, ws)| w != wi && ws.healthy) {
+            return Err(format!("no healthy worker to move {:?}'s domains to", wi));
+        }
+        let bases: Vec<_> = self
+            .nodes_on_worker(Some(&wi))
+            .into_iter()
+            .filter(|&ni| self.ingredients[ni].is_base())
+            .map(|ni| self.ingredients[ni].name().to_owned())
+            .collect();
+        if !bases.is_empty() {
+            return Err(format!("worker {:?} hosts base tables {:?}", wi, bases));
+        }
+
+        info!(self.log, "decommissioning worker {:?}", wi);
+
+        // no new domains should be placed on the worker while we move its queries away
+        self.workers.get_mut(&wi).unwrap().healthy = false;
+        let affected_nodes = self.get_failed_nodes(&wi);
+        if let Err(e) = self.remigrate(affected_nodes) {
+            // the worker keeps running whatever could not be moved
+            self.workers.get_mut(&wi).unwrap().healthy = true;
+            return Err(format!("could not move queries off {:?}: {}", wi, e));
+        }
+
+        // all nodes in the worker's domains should now have been removed
+        let stale: Vec<_> = self
+            .domains
+            .iter()
+            .filter(|(_, dh)| dh.assigned_to_worker(&wi))
+            .map(|(&di, _)| di)
+            .collect();
+        let remaining = self
+            .ingredients
+            .node_indices()
+            .filter(|&ni| ni != self.source)
+            .filter(|&ni| !self.ingredients[ni].is_dropped())
+            .find(|&ni| stale.contains(&self.ingredients[ni].domain()));
+        if let Some(ni) = remaining {
+            // the worker keeps running the domains that could not be moved
+            self.workers.get_mut(&wi).unwrap().healthy = true;
+            return Err(format!("node {} could not be moved off {:?}", ni.index(), wi));
+        }
+
+        for di in stale {
+            self.domains.remove(&di).unwrap().quit();
+        }
+        self.read_addrs.remove(&wi);
+
+        // tell the worker to stop, so that it does not keep sending heartbeats we don't expect
+        let ws = self.workers.remove(&wi).unwrap();
+        let told = {
+            let mut sender = ws.sender.lock().unwrap();
+            let source = sender.local_addr().unwrap();
+            sender
+                .send(CoordinationMessage {
+                    source,
+                    epoch: self.epoch,
+                    payload: CoordinationPayload::Decommission,
+                }).is_ok()
+        };
+        if !told {
+            warn!(self.log, "could not tell worker {:?} that it was decommissioned", wi);
+        }
+        Ok(())
     }
 
     pub(crate) fn handle_heartbeat(&mut self, msg: &CoordinationMessage) -> Result<(), io::Error> {
@@ -333,11 +1204,80 @@ impl ControllerInner {
         }
 
         self.check_worker_liveness();
+        self.check_memory_limit();
         Ok(())
     }
 
+    /// Set a budget for the total size of partial state across all domains. Once the budget is
+    /// exceeded, state is evicted from partial nodes until it is met again.
+    pub fn set_memory_limit(&mut self, bytes: Option<u64>) {
+        info!(self.log, "setting partial state memory limit"; "limit" => bytes);
+        self.memory_limit = bytes;
+    }
+
+    fn check_memory_limit(&mut self) {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        if self.last_checked_memory.elapsed() < self.heartbeat_every {
+            return;
+        }
+        self.last_checked_memory = Instant::now();
+
+        // sum up each partial node's size across its shards
+        let domains: Vec<_> = self.domains.keys().cloned().collect();
+        let mut sizes: HashMap<(DomainIndex, NodeIndex), u64> = HashMap::default();
+        for (di, node_stats) in self.node_statistics(&domains) {
+            for (ni, ns) in node_stats {
+                if let MaterializationStatus::Partial = ns.materialized {
+                    *sizes.entry((di, ni)).or_insert(0) += ns.mem_size;
+                }
+            }
+        }
+
+        let total: u64 = sizes.values().sum();
+        if total <= limit {
+            return;
+        }
+
+        // we don't know when nodes' state was last used, so evict from the largest nodes first
+        let mut sizes: Vec<_> = sizes.into_iter().collect();
+        sizes.sort_by_key(|&(_, size)| cmp::Reverse(size));
+
+        let mut excess = total - limit;
+        let mut to_evict: HashMap<DomainIndex, Vec<(NodeIndex, u64)>> = HashMap::default();
+        for ((di, ni), size) in sizes {
+            if excess == 0 {
+                break;
+            }
+            let bytes = cmp::min(size, excess);
+            excess -= bytes;
+
+            // every shard of the node receives the eviction, so split it up between them
+            let shards = self.domains[&di].shards() as u64;
+            to_evict
+                .entry(di)
+                .or_insert_with(Vec::new)
+                .push((ni, (bytes + shards - 1) / shards));
+        }
+
+        warn!(
+            self.log,
+            "partial state ({} bytes) exceeds memory limit ({} bytes); evicting", total, limit
+        );
+        self.evict(to_evict.into_iter().collect());
+    }
+
     /// Construct `ControllerInner` with a specified listening interface
-    pub(super) fn new(listen_addr: IpAddr, log: slog::Logger, state: ControllerState) -> Self {
+    pub(super) fn new(
+        listen_addr: IpAddr,
+        log: slog::Logger,
+        state: ControllerState,
+        placement: Box<PlacementPolicy>,
+        replays: ReplayStatus,
+        migration_log: Option<Box<io::Write + Send>>,
+    ) -> Self {
         let mut g = petgraph::Graph::new();
         let source = g.add_node(node::Node::new(
             "source",
@@ -349,6 +1289,7 @@ impl ControllerInner {
         if !state.config.partial_enabled {
             materializations.disable_partial()
         }
+        materializations.set_replay_status(replays);
 
         let cc = Arc::new(ChannelCoordinator::new());
         assert_ne!(state.config.quorum, 0);
@@ -374,6 +1315,13 @@ impl ControllerInner {
             persistence: state.config.persistence,
             heartbeat_every: state.config.heartbeat_every,
             healthcheck_every: state.config.healthcheck_every,
+            missed_heartbeats_before_failure: state.config.missed_heartbeats_before_failure,
+            missed_heartbeats_with_other_failures: state
+                .config
+                .missed_heartbeats_with_other_failures,
+            domain_reply_timeout: state.config.domain_reply_timeout,
+            domain_boot_timeout: state.config.domain_boot_timeout,
+            packet_coalescing: state.config.packet_coalescing,
             recipe: recipe,
             quorum: state.config.quorum,
             log,
@@ -389,7 +1337,27 @@ impl ControllerInner {
             workers: HashMap::default(),
 
             pending_recovery,
+            view_indexes: state.view_indexes,
             last_checked_workers: Instant::now(),
+            recheck_workers: false,
+
+            memory_limit: None,
+            last_checked_memory: Instant::now(),
+
+            placement,
+            pinned_bases: HashMap::default(),
+            universes: Vec::new(),
+            migration_log,
+        }
+    }
+
+    /// Write out any packets that are being held back for domains.
+    pub(super) fn flush_domains(&mut self) {
+        for (di, d) in &mut self.domains {
+            if let Err(e) = d.flush() {
+                error!(self.log, "failed to write out packets to domain: {}", e;
+                       "domain" => di.index());
+            }
         }
     }
 
@@ -439,45 +1407,75 @@ impl ControllerInner {
 
     /// Adds a new user universe.
     /// User universes automatically enforce security policies.
-    pub fn add_universe<F, T>(&mut self, context: HashMap<String, DataType>, f: F) -> T
+    ///
+    /// Fails if the migration that sets up the universe cannot be committed.
+    pub fn add_universe<F, T>(
+        &mut self,
+        context: HashMap<String, DataType>,
+        f: F,
+    ) -> Result<T, MigrationError>
     where
         F: FnOnce(&mut Migration) -> T,
     {
         info!(self.log, "starting migration: new soup universe");
-        let miglog = self.log.new(o!());
-        let mut m = Migration {
-            mainline: self,
-            added: Default::default(),
-            columns: Default::default(),
-            readers: Default::default(),
-            context: context,
-            start: time::Instant::now(),
-            log: miglog,
-        };
-        let r = f(&mut m);
-        m.commit();
-        r
+        let (r, summary) = self.run_migration(context, f);
+        self.log_migration(&summary?);
+        Ok(r)
     }
 
     /// Perform a new query schema migration.
-    pub fn migrate<F, T>(&mut self, f: F) -> T
+    ///
+    /// Fails if the migration cannot be committed; see `Migration::commit`.
+    pub fn migrate<F, T>(&mut self, f: F) -> Result<T, MigrationError>
     where
         F: FnOnce(&mut Migration) -> T,
     {
         info!(self.log, "starting migration");
+        let (r, summary) = self.run_migration(Default::default(), f);
+        self.log_migration(&summary?);
+        Ok(r)
+    }
+
+    fn run_migration<F, T>(
+        &mut self,
+        context: HashMap<String, DataType>,
+        f: F,
+    ) -> (T, Result<MigrationSummary, MigrationError>)
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        let mut m = self.start_migration(context);
+        let r = f(&mut m);
+        let summary = m.commit();
+        (r, summary)
+    }
+
+    fn start_migration(&mut self, context: HashMap<String, DataType>) -> Migration {
         let miglog = self.log.new(o!());
-        let mut m = Migration {
+        let domain_boot_timeout = self.domain_boot_timeout;
+        Migration {
             mainline: self,
             added: Default::default(),
             columns: Default::default(),
             readers: Default::default(),
-            context: Default::default(),
+            secondary_readers: Default::default(),
+            sharding: Default::default(),
+            domain_boot_timeout,
+            context: context,
             start: time::Instant::now(),
             log: miglog,
-        };
-        let r = f(&mut m);
-        m.commit();
-        r
+        }
+    }
+
+    /// Write a summary of a completed migration to the migration log, if there is one.
+    fn log_migration(&mut self, summary: &MigrationSummary) {
+        use std::io::Write;
+        if let Some(ref mut out) = self.migration_log {
+            let line = serde_json::to_string(summary).unwrap();
+            if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+                warn!(self.log, "failed to write migration summary: {}", e);
+            }
+        }
     }
 
     #[cfg(test)]
@@ -499,6 +1497,56 @@ impl ControllerInner {
             }).collect()
     }
 
+    /// Get every replay path planned so far along with its tag, ordered by tag.
+    ///
+    /// Each path lists its nodes from where the replay starts to the node it fills.
+    pub fn replay_paths(&self) -> Vec<(Tag, Vec<NodeIndex>)> {
+        self.materializations.replay_paths()
+    }
+
+    /// Get the base table columns that column `column` of node `ni` is derived from, ordered by
+    /// node and column.
+    ///
+    /// Columns are traced back through each operator using `Ingredient::column_sources`, which
+    /// follows `resolve` for columns that are passed through, and includes the inputs of computed
+    /// columns. A column that is computed from no other column, such as a literal or a count, has
+    /// no lineage. Nodes and columns that do not exist have none either.
+    pub fn column_lineage(&self, ni: NodeIndex, column: usize) -> Vec<(NodeIndex, usize)> {
+        let exists = |ni: NodeIndex, column| {
+            ni.index() < self.ingredients.node_count()
+                && column < self.ingredients[ni].fields().len()
+        };
+        if !exists(ni, column) {
+            return vec![];
+        }
+
+        let mut lineage = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![(ni, column)];
+        while let Some((ni, column)) = stack.pop() {
+            if !visited.insert((ni, column)) {
+                continue;
+            }
+
+            let n = &self.ingredients[ni];
+            if n.is_base() {
+                lineage.push((ni, column));
+            } else if n.is_internal() {
+                stack.extend(n.column_sources(column));
+            } else {
+                // ingress, egress, sharder, and reader nodes pass their columns through unchanged
+                stack.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                        .filter(|&p| p != self.source)
+                        .map(|p| (p, column)),
+                );
+            }
+        }
+        lineage.sort();
+        lineage
+    }
+
     /// Get a Vec of all known output nodes.
     ///
     /// Output nodes here refers to nodes of type `Reader`, which is the nodes created in response
@@ -517,30 +1565,100 @@ impl ControllerInner {
             }).collect()
     }
 
+    /// Describe every known input node, along with its columns and whether a `Table` handle can
+    /// currently be built for it.
+    pub fn inputs_detailed(&self) -> BTreeMap<String, InputInfo> {
+        self.inputs()
+            .into_iter()
+            .map(|(name, ni)| {
+                let node = &self.ingredients[ni];
+                let dropped = node.get_base().unwrap().get_dropped();
+                let columns = node
+                    .fields()
+                    .iter()
+                    .enumerate()
+                    .filter(|&(c, _)| !dropped.contains_key(c))
+                    .map(|(_, f)| f.clone())
+                    .collect();
+                let info = InputInfo {
+                    node: ni,
+                    columns,
+                    ready: self.table_builder(&name).is_ok(),
+                };
+                (name, info)
+            }).collect()
+    }
+
+    /// Describe every known output node, along with its columns, the columns its reader is keyed
+    /// by, and whether a `View` handle can currently be built for it.
+    pub fn outputs_detailed(&self) -> BTreeMap<String, OutputInfo> {
+        self.ingredients
+            .externals(petgraph::EdgeDirection::Outgoing)
+            .filter_map(|n| {
+                let reader = &self.ingredients[n];
+                let (node, key, materialized) = reader
+                    .with_reader(|r| (r.is_for(), r.key().map(Vec::from), r.is_materialized()))
+                    .ok()?;
+                let columns = reader.fields().to_vec();
+                let key = key
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|c| columns[c].clone())
+                    .collect();
+                let name = reader.name().to_owned();
+                let info = OutputInfo {
+                    node,
+                    reader: n,
+                    columns,
+                    key,
+                    materialized,
+                    ready: self.view_builder(&name).is_ok(),
+                };
+                Some((name, info))
+            }).collect()
+    }
+
     fn find_view_for(&self, node: NodeIndex) -> Option<NodeIndex> {
         // reader should be a child of the given node. however, due to sharding, it may not be an
         // *immediate* child. furthermore, once we go beyond depth 1, we may accidentally hit an
         // *unrelated* reader node. to account for this, readers keep track of what node they are
         // "for", and we simply search for the appropriate reader by that metric. since we know
         // that the reader must be relatively close, a BFS search is the way to go.
+        // secondary indexes are only ever looked up by name.
         let mut bfs = Bfs::new(&self.ingredients, node);
-        let mut reader = None;
         while let Some(child) = bfs.next(&self.ingredients) {
             if self.ingredients[child]
+                .with_reader(|r| r.is_for() == node && !r.is_secondary())
+                .unwrap_or(false)
+            {
+                return Some(child);
+            }
+        }
+
+        None
+    }
+
+    /// Find the reader of `node` called `name`, falling back to its primary reader.
+    ///
+    /// Secondary indexes on a node are kept in readers of their own, which are named after the
+    /// view they provide.
+    fn find_named_view_for(&self, node: NodeIndex, name: &str) -> Option<NodeIndex> {
+        let mut bfs = Bfs::new(&self.ingredients, node);
+        while let Some(child) = bfs.next(&self.ingredients) {
+            if self.ingredients[child].name() == name && self.ingredients[child]
                 .with_reader(|r| r.is_for() == node)
                 .unwrap_or(false)
             {
-                reader = Some(child);
-                break;
+                return Some(child);
             }
         }
 
-        reader
+        self.find_view_for(node)
     }
 
     /// Obtain a `ViewBuilder` that can be sent to a client and then used to query a given
     /// (already maintained) reader node called `name`.
-    pub fn view_builder(&self, name: &str) -> Option<ViewBuilder> {
+    pub fn view_builder(&self, name: &str) -> Result<ViewBuilder, LookupBuilderError> {
         // first try to resolve the node via the recipe, which handles aliasing between identical
         // queries.
         let node = match self.recipe.node_addr_for(name) {
@@ -548,34 +1666,184 @@ impl ControllerInner {
             Err(_) => {
                 // if the recipe doesn't know about this query, traverse the graph.
                 // we need this do deal with manually constructed graphs (e.g., in tests).
-                *self.outputs().get(name)?
+                match self.outputs().get(name) {
+                    Some(&ni) => ni,
+                    None if self.inputs().contains_key(name) => {
+                        return Err(LookupBuilderError::NoReader(name.to_owned()))
+                    }
+                    None => return Err(LookupBuilderError::NotFound(name.to_owned())),
+                }
             }
         };
 
-        self.find_view_for(node).map(|r| {
-            let domain = self.ingredients[r].domain();
-            let columns = self.ingredients[r].fields().to_vec();
-            let shards = (0..self.domains[&domain].shards())
-                .map(|i| self.read_addrs[&self.domains[&domain].assignment(i)].clone())
-                .collect();
+        let r = self
+            .find_named_view_for(node, name)
+            .ok_or_else(|| LookupBuilderError::NoReader(name.to_owned()))?;
+
+        // the reader may not have any state to read from yet, or may be on a worker that has
+        // failed and whose domains have not been recovered yet.
+        let not_ready = || LookupBuilderError::NotMaterialized(name.to_owned());
+        let key = self.ingredients[r]
+            .with_reader(|r| r.key().map(Vec::from))
+            .ok()
+            .and_then(|key| key)
+            .ok_or_else(not_ready)?;
+        let domain = self
+            .domains
+            .get(&self.ingredients[r].domain())
+            .ok_or_else(not_ready)?;
+        let columns = self.ingredients[r].fields().to_vec();
+        let shards = (0..domain.shards())
+            .map(|i| {
+                self.read_addrs
+                    .get(&domain.assignment(i))
+                    .cloned()
+                    .ok_or_else(not_ready)
+            }).collect::<Result<_, _>>()?;
 
-            ViewBuilder {
-                local_ports: vec![],
-                node: r,
-                columns,
-                shards,
-            }
+        Ok(ViewBuilder {
+            local_ports: vec![],
+            node: r,
+            columns,
+            key,
+            shards,
+            shard_hasher: self.domain_config.shard_hasher,
         })
     }
 
-    /// Obtain a TableBuild that can be used to construct a Table to perform writes and deletes
-    /// from the given named base node.
-    pub fn table_builder(&self, base: &str) -> Option<TableBuilder> {
+    /// Prepare a lookup in the view `name` for the key given by the `key` variables in `query`.
+    ///
+    /// Each `key` variable gives one key column, in order. Values for key columns that come from
+    /// a base table column are converted to that column's type. For other key columns, values
+    /// that parse as integers are looked up as integers, and all others as text.
+    fn prepare_view_query(
+        &self,
+        name: &str,
+        query: &Option<String>,
+    ) -> Result<(ViewBuilder, Vec<DataType>), ErrorReply> {
+        let vb = self.view_builder(name)?;
+        let values: Vec<String> = parse_query_pairs(query)
+            .into_iter()
+            .filter(|&(ref var, _)| var == "key")
+            .map(|(_, value)| value)
+            .collect();
+        if values.len() != vb.key.len() {
+            return Err(ErrorReply {
+                status: StatusCode::BAD_REQUEST,
+                body: Some(format!(
+                    "view {} is keyed by {} columns, got {}",
+                    name,
+                    vb.key.len(),
+                    values.len()
+                )),
+            });
+        }
+
+        let key = vb
+            .key
+            .iter()
+            .zip(values)
+            .map(|(&column, value)| {
+                let sql_type = self.column_sql_type(vb.node, column);
+                key_value(value, sql_type.as_ref().map(|t| t.as_str()))
+            }).collect::<Result<_, _>>()
+            .map_err(|e| ErrorReply {
+                status: StatusCode::BAD_REQUEST,
+                body: Some(e),
+            })?;
+        Ok((vb, key))
+    }
+
+    /// The SQL type of `column` of `ni`, if all of the base table columns it comes from have the
+    /// same type in the recipe.
+    fn column_sql_type(&self, ni: NodeIndex, column: usize) -> Option<String> {
+        let types: HashSet<Option<String>> = self
+            .column_lineage(ni, column)
+            .into_iter()
+            .map(|(base, column)| {
+                let n = &self.ingredients[base];
+                let schema = self.recipe.get_base_schema(n.name())?;
+                schema
+                    .fields
+                    .iter()
+                    .find(|f| f.column.name == n.fields()[column])
+                    .map(|f| f.sql_type.to_string())
+            }).collect();
+        if types.len() == 1 {
+            types.into_iter().next().unwrap()
+        } else {
+            None
+        }
+    }
+
+    /// Find the base table node with the given name.
+    fn find_base(&self, base: &str) -> Result<NodeIndex, LookupBuilderError> {
         let ni = match self.recipe.node_addr_for(base) {
             Ok(ni) => ni,
-            Err(_) => *self.inputs().get(base)?,
+            Err(_) => match self.inputs().get(base) {
+                Some(&ni) => ni,
+                None if self.outputs().contains_key(base) => {
+                    return Err(LookupBuilderError::NotATable(base.to_owned()))
+                }
+                None => return Err(LookupBuilderError::NotFound(base.to_owned())),
+            },
         };
+        if !self.ingredients[ni].is_base() {
+            return Err(LookupBuilderError::NotATable(base.to_owned()));
+        }
+        Ok(ni)
+    }
+
+    /// Describe the columns, column types, and primary key of the given base table.
+    pub fn base_schema(&self, base: &str) -> Result<BaseSchema, LookupBuilderError> {
+        let ni = self.find_base(base)?;
         let node = &self.ingredients[ni];
+        let dropped = node.get_base().unwrap().get_dropped();
+        let schema = self.recipe.get_base_schema(base);
+
+        let columns: Vec<String> = node
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !dropped.contains_key(i))
+            .map(|(_, c)| c.clone())
+            .collect();
+        let types = columns
+            .iter()
+            .map(|c| {
+                schema.as_ref().and_then(|s| {
+                    s.fields
+                        .iter()
+                        .find(|f| f.column.name == *c)
+                        .map(|f| f.sql_type.to_string())
+                })
+            }).collect();
+        let primary_key = node
+            .suggest_indexes(ni)
+            .remove(&ni)
+            .map(|(cols, _)| cols.into_iter().map(|i| node.fields()[i].clone()).collect())
+            .unwrap_or_else(Vec::new);
+        let dropped = dropped
+            .iter()
+            .map(|(i, default)| (node.fields()[i].clone(), default.clone()))
+            .collect();
+
+        Ok(BaseSchema {
+            name: node.name().to_owned(),
+            columns,
+            types,
+            primary_key,
+            dropped,
+        })
+    }
+
+    /// Obtain a TableBuild that can be used to construct a Table to perform writes and deletes
+    /// from the given named base node.
+    pub fn table_builder(&self, base: &str) -> Result<TableBuilder, LookupBuilderError> {
+        let ni = self.find_base(base)?;
+        let node = &self.ingredients[ni];
+        let not_ready = || LookupBuilderError::NotMaterialized(base.to_owned());
+        let domain = self.domains.get(&node.domain()).ok_or_else(not_ready)?;
 
         trace!(self.log, "creating table"; "for" => base);
 
@@ -593,12 +1861,12 @@ impl ControllerInner {
             is_primary = true;
         }
 
-        let txs = (0..self.domains[&node.domain()].shards())
+        let txs = (0..domain.shards())
             .map(|i| {
                 self.channel_coordinator
                     .get_addr(&(node.domain(), i))
-                    .unwrap()
-            }).collect();
+                    .ok_or_else(not_ready)
+            }).collect::<Result<_, _>>()?;
 
         let base_operator = node
             .get_base()
@@ -616,9 +1884,10 @@ impl ControllerInner {
         );
         let schema = self.recipe.get_base_schema(base);
 
-        Some(TableBuilder {
+        Ok(TableBuilder {
             local_port: None,
             txs,
+            node: ni,
             addr: (*node.local_addr()).into(),
             key: key,
             key_is_primary: is_primary,
@@ -626,36 +1895,119 @@ impl ControllerInner {
             table_name: node.name().to_owned(),
             columns,
             schema,
+            shard_hasher: self.domain_config.shard_hasher,
         })
     }
 
     /// Get statistics about the time spent processing different parts of the graph.
     pub fn get_statistics(&mut self) -> GraphStats {
         let workers = &self.workers;
-        // TODO: request stats from domains in parallel.
-        let domains = self
-            .domains
-            .iter_mut()
-            .flat_map(|(di, s)| {
-                s.send_to_healthy(box payload::Packet::GetStatistics, workers)
-                    .unwrap();
-                s.wait_for_statistics()
-                    .unwrap()
+        // ask all domains for their statistics up front, so that they are computed in parallel
+        for s in self.domains.values_mut() {
+            s.send_to_healthy(box payload::Packet::GetStatistics, workers)
+                .unwrap();
+        }
+
+        let mut domains = HashMap::new();
+        let mut unresponsive = Vec::new();
+        for (di, s) in self.domains.iter_mut() {
+            let stats = match s.wait_for_statistics() {
+                Ok(stats) => stats,
+                Err(WaitError::Timeout) => {
+                    unresponsive.push(*di);
+                    continue;
+                }
+                Err(e) => panic!("unexpected reply to statistics request: {:?}", e),
+            };
+
+            for (i, (domain_stats, node_stats)) in stats.into_iter().enumerate() {
+                let node_map = node_stats
                     .into_iter()
-                    .enumerate()
-                    .map(move |(i, (domain_stats, node_stats))| {
-                        let node_map = node_stats
-                            .into_iter()
-                            .map(|(ni, ns)| (ni.into(), ns))
-                            .collect();
-
-                        ((di.clone(), i), (domain_stats, node_map))
-                    })
-            }).collect();
+                    .map(|(ni, ns)| (ni.into(), ns))
+                    .collect();
+
+                domains.insert((*di, i), (domain_stats, node_map));
+            }
+        }
+
+        for di in unresponsive {
+            self.handle_unresponsive_domain(di);
+        }
 
         GraphStats { domains: domains }
     }
 
+    /// Attribute the processing time and memory in the graph to the queries that are served by
+    /// it, keyed by query name.
+    ///
+    /// A query is served by every node between its reader and the base tables, but not by the
+    /// base tables themselves. Usage of nodes shared between several queries is split evenly
+    /// between them, so that the per-query figures add up to the totals over all non-base nodes.
+    pub fn query_statistics(&mut self) -> BTreeMap<String, QueryStats> {
+        let mut usage: HashMap<NodeIndex, (u64, u64)> = HashMap::new();
+        for &(_, ref nodes) in self.get_statistics().domains.values() {
+            for (&ni, ns) in nodes {
+                let u = usage.entry(ni).or_insert((0, 0));
+                u.0 += ns.mem_size;
+                u.1 += ns.process_time;
+            }
+        }
+
+        // find the queries served by each node by walking up from each query's reader
+        let mut served: HashMap<NodeIndex, Vec<String>> = HashMap::new();
+        let mut queries = BTreeMap::new();
+        for reader in self.ingredients.externals(petgraph::EdgeDirection::Outgoing) {
+            if !self.ingredients[reader].is_reader() || self.ingredients[reader].is_dropped() {
+                continue;
+            }
+            let name = self.ingredients[reader].name().to_owned();
+            let mut seen = HashSet::new();
+            let mut stack = vec![reader];
+            while let Some(ni) = stack.pop() {
+                let n = &self.ingredients[ni];
+                if n.is_source() || n.is_base() || !seen.insert(ni) {
+                    continue;
+                }
+                served.entry(ni).or_default().push(name.clone());
+                stack.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming),
+                );
+            }
+            queries.insert(name, QueryStats::default());
+        }
+
+        for (ni, mut names) in served {
+            let (mem, time) = match usage.get(&ni) {
+                Some(&u) => u,
+                None => continue,
+            };
+            // the first query (by name) gets the remainder of an uneven split
+            names.sort();
+            let shares = names.len() as u64;
+            for (i, name) in names.iter().enumerate() {
+                let extra = if i == 0 { 1 } else { 0 };
+                let qs = queries.get_mut(name).unwrap();
+                qs.total_mem += mem / shares + extra * (mem % shares);
+                qs.total_process_time += time / shares + extra * (time % shares);
+            }
+        }
+
+        queries
+    }
+
+    /// Place the domain of the base called `base` on `worker` once the base is created. Bases
+    /// that already exist are not moved.
+    pub fn pin_base(&mut self, base: String, worker: WorkerIdentifier) {
+        if self.inputs().contains_key(&base) {
+            warn!(
+                self.log,
+                "not moving existing base {} to pinned worker {:?}", base, worker
+            );
+        }
+        self.pinned_bases.insert(base, worker);
+    }
+
     pub fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
         self.workers
             .iter()
@@ -663,31 +2015,56 @@ impl ControllerInner {
             .collect()
     }
 
-    pub fn flush_partial(&mut self) -> u64 {
-        // get statistics for current domain sizes
-        // and evict all state from partial nodes
-        let workers = &self.workers;
-        let to_evict: Vec<_> = self
-            .domains
-            .iter_mut()
-            .map(|(di, s)| {
-                s.send_to_healthy(box payload::Packet::GetStatistics, workers)
-                    .unwrap();
-                let to_evict: Vec<(NodeIndex, u64)> = s
-                    .wait_for_statistics()
-                    .unwrap()
-                    .into_iter()
-                    .flat_map(move |(_, node_stats)| {
-                        node_stats
-                            .into_iter()
-                            .filter_map(|(ni, ns)| match ns.materialized {
-                                MaterializationStatus::Partial => Some((ni, ns.mem_size)),
-                                _ => None,
-                            })
+    /// Get the worker that each shard of each domain runs on, keyed by domain.
+    pub fn domain_assignments(&self) -> HashMap<DomainIndex, Vec<ShardAssignment>> {
+        self.domains
+            .iter()
+            .map(|(&di, dh)| {
+                let shards = (0..dh.shards())
+                    .map(|shard| ShardAssignment {
+                        shard,
+                        worker: dh.assignment(shard),
                     }).collect();
-                (*di, to_evict)
-            }).collect();
+                (di, shards)
+            }).collect()
+    }
+
+    /// Fetches per-node statistics from every shard of the given domains, grouped by domain.
+    fn node_statistics(
+        &mut self,
+        domains: &[DomainIndex],
+    ) -> Vec<(DomainIndex, Vec<(NodeIndex, NodeStats)>)> {
+        let workers = &self.workers;
+        // ask all domains for their statistics up front, so that they are computed in parallel
+        for di in domains {
+            self.domains
+                .get_mut(di)
+                .unwrap()
+                .send_to_healthy(box payload::Packet::GetStatistics, workers)
+                .unwrap();
+        }
+
+        let mut stats = Vec::with_capacity(domains.len());
+        for &di in domains {
+            match self.domains.get_mut(&di).unwrap().wait_for_statistics() {
+                Ok(shards) => {
+                    let node_stats = shards
+                        .into_iter()
+                        .flat_map(|(_, node_stats)| node_stats)
+                        .collect();
+                    stats.push((di, node_stats));
+                }
+                Err(WaitError::Timeout) => self.handle_unresponsive_domain(di),
+                Err(e) => panic!("unexpected reply to statistics request: {:?}", e),
+            }
+        }
+        stats
+    }
 
+    /// Evicts the given number of bytes from each of the given nodes, and returns the total
+    /// number of bytes evicted.
+    fn evict(&mut self, to_evict: Vec<(DomainIndex, Vec<(NodeIndex, u64)>)>) -> u64 {
+        let workers = &self.workers;
         let mut total_evicted = 0;
         for (di, nodes) in to_evict {
             for (ni, bytes) in nodes {
@@ -705,6 +2082,43 @@ impl ControllerInner {
                 total_evicted += bytes;
             }
         }
+        total_evicted
+    }
+
+    pub fn node_sizes(&mut self) -> HashMap<NodeIndex, NodeSize> {
+        let domains: Vec<_> = self.domains.keys().cloned().collect();
+        let mut sizes: HashMap<NodeIndex, NodeSize> = HashMap::default();
+        for (_, node_stats) in self.node_statistics(&domains) {
+            for (ni, ns) in node_stats {
+                sizes
+                    .entry(ni)
+                    .or_insert(NodeSize {
+                        mem_size: 0,
+                        materialization_status: ns.materialized,
+                    }).mem_size += ns.mem_size;
+            }
+        }
+        sizes
+    }
+
+    pub fn flush_partial(&mut self) -> u64 {
+        // get statistics for current domain sizes
+        // and evict all state from partial nodes
+        let domains: Vec<_> = self.domains.keys().cloned().collect();
+        let to_evict: Vec<_> = self
+            .node_statistics(&domains)
+            .into_iter()
+            .map(|(di, node_stats)| {
+                let to_evict: Vec<(NodeIndex, u64)> = node_stats
+                    .into_iter()
+                    .filter_map(|(ni, ns)| match ns.materialized {
+                        MaterializationStatus::Partial => Some((ni, ns.mem_size)),
+                        _ => None,
+                    }).collect();
+                (di, to_evict)
+            }).collect();
+
+        let total_evicted = self.evict(to_evict);
 
         warn!(
             self.log,
@@ -714,6 +2128,43 @@ impl ControllerInner {
         total_evicted
     }
 
+    /// Evict all partial state of the given node and of its reader, if it has one, while leaving
+    /// all other nodes' state intact.
+    pub fn flush_partial_node(&mut self, node: NodeIndex) -> u64 {
+        let mut targets = vec![node];
+        targets.extend(self.find_view_for(node));
+        let mut domains: Vec<_> = targets
+            .iter()
+            .map(|&ni| self.ingredients[ni].domain())
+            .collect();
+        domains.sort();
+        domains.dedup();
+
+        let to_evict: Vec<_> = self
+            .node_statistics(&domains)
+            .into_iter()
+            .map(|(di, node_stats)| {
+                let to_evict: Vec<(NodeIndex, u64)> = node_stats
+                    .into_iter()
+                    .filter(|&(ni, _)| targets.contains(&ni))
+                    .filter_map(|(ni, ns)| match ns.materialized {
+                        MaterializationStatus::Partial => Some((ni, ns.mem_size)),
+                        _ => None,
+                    }).collect();
+                (di, to_evict)
+            }).collect();
+
+        let total_evicted = self.evict(to_evict);
+
+        warn!(
+            self.log,
+            "flushed {} bytes of partial state", total_evicted;
+            "node" => node.index(),
+        );
+
+        total_evicted
+    }
+
     pub fn create_universe(&mut self, context: HashMap<String, DataType>) -> Result<(), String> {
         let log = self.log.clone();
         let mut r = self.recipe.clone();
@@ -728,8 +2179,8 @@ impl ControllerInner {
         let uid = &[uid];
         if context.get("group").is_none() {
             for g in groups {
-                let rgb: Option<ViewBuilder> = self.view_builder(&g);
-                let mut view = rgb.map(|rgb| rgb.build_exclusive().unwrap()).unwrap();
+                let rgb: ViewBuilder = self.view_builder(&g).unwrap();
+                let mut view = rgb.build_exclusive().unwrap();
                 let my_groups: Vec<DataType> = view
                     .lookup(uid, true)
                     .unwrap()
@@ -740,6 +2191,11 @@ impl ControllerInner {
             }
         }
 
+        let universe = UniverseInfo {
+            id: uid[0].clone(),
+            group: context.get("group").map(|g| g.to_string()),
+            groups: universe_groups.clone(),
+        };
         self.add_universe(context.clone(), |mut mig| {
             r.next();
             match r.create_universe(&mut mig, universe_groups) {
@@ -752,10 +2208,11 @@ impl ControllerInner {
                     crit!(log, "failed to create universe: {:?}", e);
                     Err("failed to create universe".to_owned())
                 }
-            }.unwrap();
-        });
+            }
+        }).map_err(|e| format!("migration failed: {}", e))??;
 
         self.recipe = r;
+        self.universes.push(universe);
         Ok(())
     }
 
@@ -766,67 +2223,278 @@ impl ControllerInner {
         Ok(())
     }
 
-    fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, String> {
-        let r = self.migrate(|mig| {
-            new.activate(mig)
-                .map_err(|e| format!("failed to activate recipe: {}", e))
-        });
-
-        match r {
-            Ok(ref ra) => {
-                let (removed_bases, removed_other): (Vec<_>, Vec<_>) = ra
+    /// Find the base tables that `new` drops, along with the queries it retains that still read
+    /// from any of them.
+    fn base_dependents(&self, new: &Recipe) -> (Vec<String>, Vec<String>) {
+        let (removed_bases, removed_queries) = new.removed_names();
+        let mut dependents = Vec::new();
+        for base in &removed_bases {
+            let ni = match new.node_addr_for(base) {
+                Ok(ni) => ni,
+                Err(_) => continue,
+            };
+            let mut descendants = Vec::new();
+            let mut bfs = Bfs::new(&self.ingredients, ni);
+            while let Some(n) = bfs.next(&self.ingredients) {
+                if n != ni && !self.ingredients[n].is_dropped() {
+                    descendants.push(n);
+                }
+            }
+            dependents.extend(
+                new.queries_for_nodes(descendants)
+                    .into_iter()
+                    .filter(|q| !removed_queries.contains(q)),
+            );
+        }
+        dependents.sort();
+        dependents.dedup();
+        (removed_bases, dependents)
+    }
+
+    fn apply_recipe(&mut self, mut new: Recipe, force: bool) -> Result<ActivationResult, String> {
+        // bases that the new recipe drops may still feed queries that it retains. unless we were
+        // asked to force the removal, refuse to apply the recipe in that case; otherwise, drop
+        // the dependent queries from the recipe too, so that they are removed along with the base.
+        let (removed_bases, dependents) = self.base_dependents(&new);
+        if !dependents.is_empty() {
+            let mut refused = !force;
+            if force {
+                for q in &dependents {
+                    warn!(self.log, "removing query {} along with its base", q);
+                    refused |= !new.remove_query(q);
+                }
+            }
+            if refused {
+                let e = format!(
+                    "cannot remove base table(s) {} that still feed queries: {}",
+                    removed_bases.join(", "),
+                    dependents.join(", ")
+                );
+                crit!(self.log, "failed to apply recipe: {}", e);
+                let inc = new.sql_inc().clone();
+                self.restore_recipe(new, inc);
+                return Err(e);
+            }
+        }
+
+        // queries that only changed their names keep their nodes, so renaming them needs no
+        // migration either.
+        let renamed = new.renamed_queries();
+
+        // idempotent deployments often resubmit queries that are already installed, in which
+        // case there is no need to run a (potentially expensive) migration at all.
+        if new.is_unchanged() {
+            info!(self.log, "recipe is unchanged; skipping migration"; "version" => new.version());
+            let statements = new.statement_results(HashMap::new());
+            new.rename_queries();
+            self.rename_views(&renamed);
+            self.recipe = new;
+            return Ok(ActivationResult {
+                new_nodes: HashMap::default(),
+                removed_leaves: Vec::new(),
+                added_nodes: Vec::new(),
+                reused_nodes: Vec::new(),
+                expressions_added: 0,
+                expressions_removed: 0,
+                statements,
+            });
+        }
+
+        // query planning panics on relations that do not exist, so reject queries that read from
+        // them, conflicting base definitions, and sharding directives that cannot be honored,
+        // before the migration starts.
+        if let Err(e) = new
+            .check_relations()
+            .and_then(|_| new.check_bases())
+            .and_then(|_| new.check_sharding(self.sharding))
+        {
+            crit!(self.log, "failed to apply recipe: {}", e);
+            let inc = new.sql_inc().clone();
+            self.restore_recipe(new, inc);
+            return Err(e);
+        }
+
+        let dropped_before = self.dropped_nodes();
+        // activation records the new queries in the incorporator. if the migration is rolled back,
+        // so are the nodes those records point to, so we must go back to the incorporator as it
+        // was before.
+        let inc_before = new.sql_inc().clone();
+        info!(self.log, "starting migration");
+        let (r, summary) = self.run_migration(Default::default(), |mig| {
+            new.activate(mig)
+                .map_err(|e| format!("failed to activate recipe: {}", e))
+        });
+        let mut summary = match summary {
+            Ok(summary) => summary,
+            Err(e) => {
+                let e = format!("migration failed: {}", e);
+                crit!(self.log, "failed to apply recipe: {}", e);
+                self.restore_recipe(new, inc_before);
+                return Err(e);
+            }
+        };
+
+        match r {
+            Ok(ref ra) => {
+                let (removed_bases, removed_other): (Vec<_>, Vec<_>) = ra
                     .removed_leaves
                     .iter()
                     .cloned()
                     .partition(|ni| self.ingredients[*ni].is_base());
 
                 // first remove query nodes in reverse topological order
-                let mut topo_removals = Vec::with_capacity(removed_other.len());
-                let mut topo = petgraph::visit::Topo::new(&self.ingredients);
-                while let Some(node) = topo.next(&self.ingredients) {
-                    if removed_other.contains(&node) {
-                        topo_removals.push(node);
-                    }
-                }
-                topo_removals.reverse();
+                let topo_removals: Vec<_> = self
+                    .reverse_topo_order()
+                    .into_iter()
+                    .filter(|ni| removed_other.contains(ni))
+                    .collect();
 
                 for leaf in topo_removals {
-                    self.remove_leaf(leaf)?;
+                    if let Err(e) = self.remove_leaf(leaf) {
+                        crit!(self.log, "failed to apply recipe: {}", e);
+                        self.restore_recipe(new, inc_before);
+                        return Err(e);
+                    }
                 }
 
                 // now remove bases
                 for base in removed_bases {
-                    // TODO(malte): support removing bases that still have children?
-                    let children: Vec<NodeIndex> = self
-                        .ingredients
-                        .neighbors_directed(base, petgraph::EdgeDirection::Outgoing)
-                        .collect();
-                    // TODO(malte): what about domain crossings? can ingress/egress nodes be left
-                    // behind?
-                    assert_eq!(children.len(), 0);
+                    // all dependent queries are gone by now, but domain crossings below the base
+                    // may have left egress, sharder, or ingress nodes behind.
+                    let mut orphans = Vec::new();
+                    let mut bfs = Bfs::new(&self.ingredients, base);
+                    while let Some(n) = bfs.next(&self.ingredients) {
+                        if n != base {
+                            orphans.push(n);
+                        }
+                    }
+                    if orphans.iter().any(|&n| self.ingredients[n].is_internal()) {
+                        // should never happen, since we removed all dependent queries above. the
+                        // base stays in the graph, and like with any other failure, so does the
+                        // recipe as it was.
+                        let e = format!(
+                            "cannot remove base {} ({}), as it still has query nodes below it",
+                            self.ingredients[base].name(),
+                            base.index()
+                        );
+                        crit!(self.log, "failed to apply recipe: {}", e);
+                        self.restore_recipe(new, inc_before);
+                        return Err(e);
+                    }
                     debug!(
                         self.log,
                         "Removing base \"{}\"",
                         self.ingredients[base].name();
                         "node" => base.index(),
+                        "orphans" => orphans.len(),
                     );
+
+                    // detach the base and anything left below it from the graph
+                    let mut removals = orphans;
+                    removals.push(base);
+                    for &n in &removals {
+                        let mut parents = self
+                            .ingredients
+                            .neighbors_directed(n, petgraph::EdgeDirection::Incoming)
+                            .detach();
+                        while let Some(edge) = parents.next_edge(&self.ingredients) {
+                            self.ingredients.remove_edge(edge);
+                        }
+                    }
+
                     // now drop the (orphaned) base
-                    self.remove_nodes(vec![base].as_slice()).unwrap();
+                    self.remove_nodes(removals.as_slice()).unwrap();
                 }
 
+                self.rename_views(&renamed);
                 self.recipe = new;
             }
             Err(ref e) => {
                 crit!(self.log, "failed to apply recipe: {}", e);
-                // TODO(malte): a little yucky, since we don't really need the blank recipe
-                let recipe = mem::replace(&mut self.recipe, Recipe::blank(None));
-                self.recipe = recipe.revert();
+                self.restore_recipe(new, inc_before);
             }
         }
 
+        summary.nodes_removed = self.dropped_nodes() - dropped_before;
+        self.log_migration(&summary);
         r
     }
 
+    /// Go back to the recipe that `new` was derived from, since `new` could not be applied.
+    ///
+    /// `inc` is the incorporator to go back to along with it. Callers pass the one from before
+    /// `new` was activated, since activation may have recorded queries whose nodes never made it
+    /// into the graph.
+    fn restore_recipe(&mut self, new: Recipe, inc: SqlIncorporator) {
+        let mut old = new.revert();
+        old.set_sql_inc(inc);
+        self.recipe = old;
+    }
+
+    /// Give the readers of renamed queries their new names, and move secondary indexes on their
+    /// views over to the new names.
+    fn rename_views(&mut self, renamed: &[(String, String)]) {
+        for (old, new) in renamed {
+            let readers: Vec<_> = self
+                .ingredients
+                .node_indices()
+                .filter(|&ni| {
+                    self.ingredients[ni].is_reader() && self.ingredients[ni].name() == *old
+                }).collect();
+            for r in readers {
+                self.ingredients[r].set_name(new);
+            }
+            for idx in self.view_indexes.iter_mut().filter(|idx| idx.view == *old) {
+                idx.view = new.clone();
+            }
+        }
+    }
+
+    /// Give the view `old` the name `new`.
+    ///
+    /// The view's query keeps its dataflow nodes, so this needs no migration and the view's
+    /// state is kept. Existing `View` handles for it keep working.
+    pub fn rename_view<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+        old: &str,
+        new: &str,
+    ) -> Result<(), String> {
+        let current = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let renamed = match current.rename(old, new) {
+            Ok(renamed) => renamed,
+            Err((current, e)) => {
+                self.recipe = current;
+                return Err(e);
+            }
+        };
+        self.apply_recipe(renamed, false)?;
+
+        let text = self.recipe.text();
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.recipe_version = self.recipe.version();
+                    state.recipes = vec![text.clone()];
+                    state.view_indexes = self.view_indexes.clone();
+                    Ok(state)
+                }
+            }).is_err()
+        {
+            return Err("Failed to persist view rename".to_owned());
+        }
+        Ok(())
+    }
+
+    fn dropped_nodes(&self) -> usize {
+        self.ingredients
+            .node_indices()
+            .filter(|&ni| self.ingredients[ni].is_dropped())
+            .count()
+    }
+
     pub fn extend_recipe<A: Authority + 'static>(
         &mut self,
         authority: &Arc<A>,
@@ -836,7 +2504,11 @@ impl ControllerInner {
         let new = mem::replace(&mut self.recipe, Recipe::blank(None));
         match new.extend(&add_txt) {
             Ok(new) => {
-                let activation_result = self.apply_recipe(new);
+                let activation_result = self.apply_recipe(new, false);
+                if activation_result.is_err() {
+                    return activation_result;
+                }
+
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
@@ -857,21 +2529,153 @@ impl ControllerInner {
                 // need to restore the old recipe
                 crit!(self.log, "failed to extend recipe: {:?}", e);
                 self.recipe = old;
-                Err("failed to extend recipe".to_owned())
+                Err(format!("failed to extend recipe: {}", e))
             }
         }
     }
 
+    /// Check whether extending the recipe with `r_txt`, or replacing it with `r_txt` if `replace`
+    /// is set, would succeed, without changing either the recipe or the running graph.
+    ///
+    /// The new queries are planned all the way down to dataflow nodes, but those nodes are thrown
+    /// away again rather than committed. The returned `ActivationResult` names the queries that
+    /// would be added and counts the expressions that would be added and removed; its node
+    /// indices do not refer to nodes in the graph.
+    pub fn validate_recipe(
+        &mut self,
+        r_txt: String,
+        replace: bool,
+    ) -> Result<ActivationResult, String> {
+        let current = self.recipe.clone();
+        let mut new = if replace {
+            let r = Recipe::from_str(&r_txt, Some(self.log.clone()))
+                .map_err(|e| format!("failed to parse recipe: {}", e))?;
+            current.replace(r)?
+        } else {
+            current
+                .extend(&r_txt)
+                .map_err(|(_, e)| format!("failed to extend recipe: {}", e))?
+        };
+
+        let (removed_bases, dependents) = self.base_dependents(&new);
+        if !dependents.is_empty() {
+            return Err(format!(
+                "cannot remove base table(s) {} that still feed queries: {}",
+                removed_bases.join(", "),
+                dependents.join(", ")
+            ));
+        }
+
+        if new.is_unchanged() {
+            return Ok(ActivationResult {
+                new_nodes: HashMap::default(),
+                removed_leaves: Vec::new(),
+                added_nodes: Vec::new(),
+                reused_nodes: Vec::new(),
+                expressions_added: 0,
+                expressions_removed: 0,
+                statements: new.statement_results(HashMap::new()),
+            });
+        }
+
+        // planning adds nodes to the graph, and may add or drop columns of existing bases. none
+        // of that is committed, so restoring the graph as it was undoes all of it.
+        let graph = self.ingredients.clone();
+        let r = {
+            let mut mig = self.start_migration(Default::default());
+            new.activate(&mut mig)
+        };
+        self.ingredients = graph;
+        r.map_err(|e| format!("failed to plan recipe: {}", e))
+    }
+
+    /// Undo the last change to the recipe, removing any queries and bases it added and restoring
+    /// any that it removed. Rollbacks can be repeated until the first recipe has been undone.
+    ///
+    /// The restored recipe gets the next version number rather than its old one, so recipe
+    /// versions never go backwards.
+    pub fn rollback_recipe<A: Authority + 'static>(
+        &mut self,
+        authority: &Arc<A>,
+    ) -> Result<ActivationResult, String> {
+        if self.recipe.version() == 0 || self.recipe.prior().is_none() {
+            return Err("no earlier recipe version to roll back to".to_owned());
+        }
+
+        let current = mem::replace(&mut self.recipe, Recipe::blank(None));
+        let mut target = (**current.prior().unwrap()).clone();
+        info!(
+            self.log,
+            "rolling back recipe from version {} to the text of version {}",
+            current.version(),
+            target.version()
+        );
+
+        // activating `target` must compute its changes relative to the current recipe, but once
+        // that is done, it should resume its own lineage so that it can be rolled back further.
+        // the rollback is still a change to the recipe though, so it gets a new version, and
+        // clients that saw the current version will not mistake the old recipe for it.
+        target.set_version(current.version() + 1);
+        let earlier = target.take_prior();
+        target.set_sql_inc(current.sql_inc().clone());
+        target.set_prior(current);
+        let activation_result = self.apply_recipe(target, false)?;
+        match earlier {
+            Some(earlier) => self.recipe.set_prior(earlier),
+            None => drop(self.recipe.take_prior()),
+        }
+
+        let text = self.recipe.text();
+        if authority
+            .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+                None => unreachable!(),
+                Some(ref state) if state.epoch > self.epoch => Err(()),
+                Some(mut state) => {
+                    state.recipe_version = self.recipe.version();
+                    state.recipes = if text.is_empty() {
+                        vec![]
+                    } else {
+                        vec![text.clone()]
+                    };
+                    state.view_indexes = self.view_indexes.clone();
+                    Ok(state)
+                }
+            }).is_err()
+        {
+            return Err("Failed to persist recipe rollback".to_owned());
+        }
+
+        Ok(activation_result)
+    }
+
     pub fn install_recipe<A: Authority + 'static>(
         &mut self,
         authority: &Arc<A>,
         r_txt: String,
+        force: bool,
     ) -> Result<ActivationResult, String> {
         match Recipe::from_str(&r_txt, Some(self.log.clone())) {
             Ok(r) => {
+                let requested: Vec<String> = r.aliases().into_iter().map(String::from).collect();
                 let old = mem::replace(&mut self.recipe, Recipe::blank(None));
                 let new = old.replace(r).unwrap();
-                let activation_result = self.apply_recipe(new);
+                let activation_result = self.apply_recipe(new, force);
+                if activation_result.is_err() {
+                    return activation_result;
+                }
+
+                // a forced installation may have dropped queries that depended on removed bases;
+                // these must not come back when the recipe is restored.
+                let active = self.recipe.aliases();
+                let cascaded: Vec<String> = requested
+                    .into_iter()
+                    .filter(|q| !active.contains(&q.as_str()))
+                    .collect();
+                let r_txt = if cascaded.is_empty() {
+                    r_txt
+                } else {
+                    Recipe::strip_queries(&r_txt, &cascaded)
+                };
                 if authority
                     .read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
                         None => unreachable!(),
@@ -879,6 +2683,7 @@ impl ControllerInner {
                         Some(mut state) => {
                             state.recipe_version = self.recipe.version();
                             state.recipes = vec![r_txt.clone()];
+                            state.view_indexes = self.view_indexes.clone();
                             Ok(state)
                         }
                     }).is_err()
@@ -889,7 +2694,7 @@ impl ControllerInner {
             }
             Err(e) => {
                 crit!(self.log, "failed to parse recipe: {:?}", e);
-                Err("failed to parse recipe".to_owned())
+                Err(format!("failed to parse recipe: {}", e))
             }
         }
     }
@@ -898,9 +2703,135 @@ impl ControllerInner {
         graphviz(&self.ingredients, &self.materializations)
     }
 
-    fn remove_leaf(&mut self, mut leaf: NodeIndex) -> Result<(), String> {
+    /// Like `graphviz`, but labels every materialized node with the number of rows and bytes in
+    /// its state, summed across shards.
+    ///
+    /// This asks every domain for its statistics, so it is more expensive than `graphviz`.
+    pub fn graphviz_with_stats(&mut self) -> String {
+        let domains: Vec<_> = self.domains.keys().cloned().collect();
+        let mut sizes: HashMap<NodeIndex, (u64, u64)> = HashMap::default();
+        for (_, node_stats) in self.node_statistics(&domains) {
+            for (ni, ns) in node_stats {
+                if ns.materialized == MaterializationStatus::Not {
+                    continue;
+                }
+                let size = sizes.entry(ni).or_insert((0, 0));
+                size.0 += ns.rows;
+                size.1 += ns.mem_size;
+            }
+        }
+        graphviz_filtered(&self.ingredients, &self.materializations, Some(&sizes), |_| true)
+    }
+
+    /// All live nodes in the graph, ordered so that every node comes after all of its ancestors.
+    ///
+    /// The source node and nodes that have been removed are left out. The order is deterministic,
+    /// so it can be used to compare graphs or to plan changes outside of the controller.
+    pub fn topo_order(&self) -> Vec<NodeIndex> {
+        topological_sort(&self.ingredients)
+            .into_iter()
+            .filter(|&ni| ni != self.source && !self.ingredients[ni].is_dropped())
+            .collect()
+    }
+
+    /// The nodes of `topo_order` in reverse, so that every node comes before its ancestors.
+    ///
+    /// This is the order in which nodes can be removed without leaving any node without parents.
+    pub fn reverse_topo_order(&self) -> Vec<NodeIndex> {
+        let mut order = self.topo_order();
+        order.reverse();
+        order
+    }
+
+    /// Get a deterministic snapshot of the structure of the data-flow graph.
+    pub fn graph_snapshot(&self) -> GraphSnapshot {
+        use petgraph::EdgeDirection::Incoming;
+
+        let g = &self.ingredients;
+        let order = topological_sort(g);
+        let mut nodes = Vec::with_capacity(order.len());
+        for ni in order {
+            let n = &g[ni];
+            let mut parents: Vec<_> = g
+                .neighbors_directed(ni, Incoming)
+                .map(|p| p.index())
+                .collect();
+            parents.sort();
+
+            nodes.push(NodeSnapshot {
+                index: ni.index(),
+                name: n.name().to_owned(),
+                operator: operator_name(n),
+                columns: n.fields().to_vec(),
+                domain: if n.has_domain() {
+                    Some(n.domain().index())
+                } else {
+                    None
+                },
+                sharding: format!("{:?}", n.sharded_by()),
+                parents,
+            });
+        }
+
+        GraphSnapshot { nodes }
+    }
+
+    /// Describe the node with index `ni` in more detail than `graph_snapshot` does.
+    ///
+    /// Returns `None` if there is no such node.
+    pub fn describe_node(&self, ni: NodeIndex) -> Option<NodeDescription> {
+        use petgraph::EdgeDirection::{Incoming, Outgoing};
+
+        if ni.index() >= self.ingredients.node_count() {
+            return None;
+        }
+
+        let n = &self.ingredients[ni];
+        let neighbors = |direction| {
+            let mut neighbors: Vec<_> = self
+                .ingredients
+                .neighbors_directed(ni, direction)
+                .map(|n| n.index())
+                .collect();
+            neighbors.sort();
+            neighbors
+        };
+        let materialization = self.materializations.get_status(&ni, n);
+
+        Some(NodeDescription {
+            index: ni.index(),
+            name: n.name().to_owned(),
+            operator: operator_name(n),
+            columns: n.fields().to_vec(),
+            domain: if n.has_domain() {
+                Some(n.domain().index())
+            } else {
+                None
+            },
+            shards: n.sharded_by().shards().unwrap_or(1),
+            sharding: format!("{:?}", n.sharded_by()),
+            materialization,
+            parents: neighbors(Incoming),
+            children: neighbors(Outgoing),
+            graphviz: n.describe(ni, materialization, None),
+        })
+    }
+
+    /// Check the structure of the graph for inconsistencies, such as readers for nodes that do
+    /// not exist, or cross-domain edges that do not go through ingress and egress nodes.
+    ///
+    /// This is meant as a debugging aid after migrations; an empty list means that all is well.
+    pub fn check_invariants(&self) -> Vec<InvariantViolation> {
+        let domain_shards: HashMap<_, _> = self
+            .domains
+            .iter()
+            .map(|(&di, dh)| (di, dh.shards()))
+            .collect();
+        check_invariants(&self.ingredients, &domain_shards)
+    }
+
+    fn remove_leaf(&mut self, leaf: NodeIndex) -> Result<(), String> {
         let mut removals = vec![];
-        let start = leaf;
         assert!(!self.ingredients[leaf].is_source());
 
         info!(
@@ -909,58 +2840,45 @@ impl ControllerInner {
             leaf.index()
         );
 
+        // This query leaf node may have children -- typically, these are readers, but they can
+        // also include the sharders in front of readers that are sharded differently, egress
+        // nodes, or other, dependent queries. Readers for the leaf, including those for secondary
+        // indexes, are removed along with the nodes that only feed them.
+        let readers: Vec<_> = {
+            let graph = &self.ingredients;
+            let mut readers = Vec::new();
+            let mut bfs = Bfs::new(graph, leaf);
+            while let Some(n) = bfs.next(graph) {
+                if graph[n].with_reader(|r| r.is_for() == leaf).unwrap_or(false) {
+                    readers.push(n);
+                }
+            }
+            readers
+        };
+        for r in readers {
+            removals.extend(self.detach_reader(r));
+        }
+        debug!(
+            self.log,
+            "Removing query leaf \"{}\"", self.ingredients[leaf].name();
+            "node" => leaf.index(),
+        );
+
         if self
             .ingredients
             .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
             .count()
             > 0
         {
-            // This query leaf node has children -- typically, these are readers, but they can also
-            // include egress nodes or other, dependent queries.
-            let mut has_non_reader_children = false;
-            let readers: Vec<_> = self
-                .ingredients
-                .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
-                .filter(|ni| {
-                    if self.ingredients[*ni].is_reader() {
-                        true
-                    } else {
-                        has_non_reader_children = true;
-                        false
-                    }
-                }).collect();
-            if has_non_reader_children {
-                // should never happen, since we remove nodes in reverse topological order
-                crit!(
-                    self.log,
-                    "not removing node {} yet, as it still has non-reader children",
-                    leaf.index()
-                );
-                unreachable!();
-            }
-            // nodes can have only one reader attached
-            assert!(readers.len() <= 1);
-            debug!(
-                        self.log,
-                        "Removing query leaf \"{}\"", self.ingredients[leaf].name();
-                        "node" => leaf.index(),
-                    );
-            if !readers.is_empty() {
-                removals.push(readers[0]);
-                leaf = readers[0];
-            } else {
-                unreachable!();
-            }
+            // should never happen, since we remove nodes in reverse topological order
+            crit!(
+                self.log,
+                "not removing node {} yet, as it still has non-reader children",
+                leaf.index()
+            );
+            unreachable!();
         }
 
-        // `node` now does not have any children any more
-        assert_eq!(
-            self.ingredients
-                .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
-                .count(),
-            0
-        );
-
         let mut nodes = vec![leaf];
         while let Some(node) = nodes.pop() {
             let mut parents = self
@@ -973,8 +2891,7 @@ impl ControllerInner {
 
                 if !self.ingredients[parent].is_source()
                     && !self.ingredients[parent].is_base()
-                    // ok to remove original start leaf
-                    && (parent == start || !self.recipe.sql_inc().is_leaf_address(parent))
+                    && !self.recipe.sql_inc().is_leaf_address(parent)
                     && self
                         .ingredients
                         .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
@@ -1001,6 +2918,7 @@ impl ControllerInner {
                 .or_insert(Vec::new())
                 .push(*self.ingredients[*ni].local_addr())
         }
+        self.materializations.remove_nodes(removals);
 
         // Send messages to domains
         for (domain, nodes) in domain_removals {
@@ -1063,11 +2981,10 @@ impl ControllerInner {
         // domain. We do this to avoid keeping separate state that may get out of sync, but it
         // could become a performance bottleneck in the future (e.g., when recovergin large
         // graphs).
+        let live = self.topo_order();
         let domain_nodes = |i: DomainIndex| -> Vec<NodeIndex> {
-            self.ingredients
-                .node_indices()
-                .filter(|&ni| ni != self.source)
-                .filter(|&ni| !self.ingredients[ni].is_dropped())
+            live.iter()
+                .cloned()
                 .filter(|&ni| self.ingredients[ni].domain() == i)
                 .collect()
         };
@@ -1091,12 +3008,603 @@ impl ControllerInner {
 
 impl Drop for ControllerInner {
     fn drop(&mut self) {
-        for (_, d) in &mut self.domains {
-            // XXX: this is a terrible ugly hack to ensure that all workers exit
-            for _ in 0..100 {
-                // don't unwrap, because given domain may already have terminated
-                drop(d.send_to_healthy(box payload::Packet::Quit, &self.workers));
+        // ask all shards to quit before waiting for any of them, so that they can all shut down
+        // in parallel, and so that unresponsive shards share a single timeout.
+        let workers = &self.workers;
+        let asked: Vec<_> = self
+            .domains
+            .iter_mut()
+            .map(|(&di, d)| (di, d.send_quit(workers)))
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_millis(QUIT_ACK_TIMEOUT_MS);
+        for (di, shards) in asked {
+            if let Err(e) = self.domains.get_mut(&di).unwrap().wait_for_quit(shards, deadline) {
+                warn!(self.log, "domain did not confirm shutdown: {:?}", e; "domain" => di.index());
             }
         }
     }
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::controller::{ControllerConfig, RoundRobin};
+    use consensus::LocalAuthority;
+    use dataflow::node;
+
+    /// A controller with the given configuration that has just become leader, but has no workers.
+    pub(in crate::controller) fn test_controller(config: ControllerConfig) -> ControllerInner {
+        let epoch = LocalAuthority::new().become_leader(vec![]).unwrap().unwrap();
+        let state = ControllerState {
+            config,
+            epoch,
+            recipe_version: 0,
+            recipes: vec![],
+            view_indexes: vec![],
+        };
+        ControllerInner::new(
+            "127.0.0.1".parse().unwrap(),
+            slog::Logger::root(slog::Discard, o!()),
+            state,
+            Box::new(RoundRobin::default()),
+            Default::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn graphviz_around_is_limited_to_depth() {
+        let mut g = petgraph::Graph::new();
+        let src = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+
+        // src -> a -> x -> y -> z, and src -> b
+        let a = g.add_node(node::Node::new(
+            "a",
+            &["a1", "a2"],
+            node::NodeType::from(node::special::Base::default()),
+        ));
+        g.add_edge(src, a, ());
+        let b = g.add_node(node::Node::new(
+            "b",
+            &["b1", "b2"],
+            node::NodeType::from(node::special::Base::default()),
+        ));
+        g.add_edge(src, b, ());
+        let x = g.add_node(node::Node::new("x", &["x1", "x2"], node::special::Ingress));
+        g.add_edge(a, x, ());
+        let y = g.add_node(node::Node::new("y", &["y1", "y2"], node::special::Ingress));
+        g.add_edge(x, y, ());
+        let z = g.add_node(node::Node::new("z", &["z1", "z2"], node::special::Ingress));
+        g.add_edge(y, z, ());
+
+        let log = slog::Logger::root(slog::Discard, o!());
+        let materializations = Materializations::new(&log);
+
+        let nodes_in = |depth| -> Vec<usize> {
+            let dot = graphviz_around(&g, &materializations, x, depth);
+            let mut nodes: Vec<usize> = dot
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.contains("->"))
+                .filter_map(|l| l.split(' ').next().and_then(|n| n.parse().ok()))
+                .collect();
+            nodes.sort();
+            nodes
+        };
+
+        assert_eq!(nodes_in(0), vec![x.index()]);
+        assert_eq!(nodes_in(1), vec![a.index(), x.index(), y.index()]);
+        assert_eq!(
+            nodes_in(2),
+            vec![src.index(), a.index(), x.index(), y.index(), z.index()]
+        );
+
+        // edges to nodes outside the neighborhood are omitted
+        let dot = graphviz_around(&g, &materializations, x, 1);
+        assert!(dot.contains(&format!("{} -> {}", a.index(), x.index())));
+        assert!(dot.contains(&format!("{} -> {}", x.index(), y.index())));
+        assert!(!dot.contains(&format!("{} -> {}", y.index(), z.index())));
+        assert!(!dot.contains(&format!("{} -> {}", src.index(), a.index())));
+        assert!(!dot.contains(&format!("{} -> {}", src.index(), b.index())));
+    }
+
+    #[test]
+    fn invariant_violations_are_reported() {
+        let mut g = petgraph::Graph::new();
+        let add = |g: &mut Graph, name: &str, nt: node::NodeType, domain: usize| {
+            let mut n = node::Node::new(name, &["c1", "c2"], nt);
+            n.add_to(domain.into());
+            g.add_node(n)
+        };
+        let src = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+
+        // src -> a -> e => i -> r, with a and e in domain 0, and i and r in domain 1
+        let a = add(&mut g, "a", node::special::Base::default().into(), 0);
+        g.add_edge(src, a, ());
+        let e = add(&mut g, "e", node::special::Egress::default().into(), 0);
+        g.add_edge(a, e, ());
+        let i = add(&mut g, "i", node::special::Ingress.into(), 1);
+        g.add_edge(e, i, ());
+        let r = add(&mut g, "r", node::special::Reader::new(a).into(), 1);
+        g.add_edge(i, r, ());
+
+        let mut domain_shards = HashMap::new();
+        domain_shards.insert(DomainIndex::from(0), 1);
+        domain_shards.insert(DomainIndex::from(1), 1);
+        assert!(check_invariants(&g, &domain_shards).is_empty());
+
+        // a parentless ingress in a domain that does not exist
+        let orphan = add(&mut g, "orphan", node::special::Ingress.into(), 7);
+        // a reader for a node that does not exist
+        let dangling = add(
+            &mut g,
+            "dangling",
+            node::special::Reader::new(NodeIndex::new(99)).into(),
+            1,
+        );
+        g.add_edge(i, dangling, ());
+        // a cross-domain edge that bypasses the egress and ingress
+        let bypass = add(&mut g, "bypass", node::special::Reader::new(a).into(), 1);
+        g.add_edge(a, bypass, ());
+        // an egress that feeds an ingress in its own domain
+        let local = add(&mut g, "local", node::special::Ingress.into(), 0);
+        g.add_edge(e, local, ());
+        // a node sharded differently from its domain
+        g[r].shard_by(Sharding::ByColumn(0, 2));
+
+        let mut violations = check_invariants(&g, &domain_shards);
+        let mut expected = vec![
+            InvariantViolation::ShardMismatch {
+                node: r.index(),
+                domain: 1,
+                node_shards: 2,
+                domain_shards: 1,
+            },
+            InvariantViolation::NoParents {
+                node: orphan.index(),
+            },
+            InvariantViolation::UnknownDomain {
+                node: orphan.index(),
+                domain: Some(7),
+            },
+            InvariantViolation::DanglingReader {
+                reader: dangling.index(),
+                for_node: 99,
+            },
+            InvariantViolation::UnroutedEdge {
+                from: a.index(),
+                to: bypass.index(),
+            },
+            InvariantViolation::UnmatchedEgress {
+                egress: e.index(),
+                child: local.index(),
+            },
+        ];
+        let key = |v: &InvariantViolation| format!("{:?}", v);
+        violations.sort_by_key(key);
+        expected.sort_by_key(key);
+        assert_eq!(violations, expected);
+
+        // removed nodes are not checked
+        g[orphan].remove();
+        g[dangling].remove();
+        g[bypass].remove();
+        g[local].remove();
+        g[r].shard_by(Sharding::None);
+        assert!(check_invariants(&g, &domain_shards).is_empty());
+    }
+
+    #[test]
+    fn query_parsing() {
+        let query = |q: &str| parse_query(&Some(q.to_owned()));
+
+        assert!(parse_query(&None).is_empty());
+        assert!(query("").is_empty());
+
+        let vars = query("around=3&depth=2&flag");
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["around"], "3");
+        assert_eq!(vars["depth"], "2");
+        assert_eq!(vars["flag"], "");
+
+        // values are decoded, and the last of repeated keys wins
+        let vars = query("w=10.0.0.1%3A4000&name=a+b%20c&w=10.0.0.2%3a4000&bad=%zz%4");
+        assert_eq!(vars["w"], "10.0.0.2:4000");
+        assert_eq!(vars["name"], "a b c");
+        assert_eq!(vars["bad"], "%zz%4");
+
+        // repeated keys are all kept, in order, when asked for
+        let pairs = parse_query_pairs(&Some("key=1&x=y&key=a%20b".to_owned()));
+        let keys: Vec<_> = pairs
+            .into_iter()
+            .filter(|&(ref k, _)| k == "key")
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(keys, vec!["1", "a b"]);
+    }
+
+    #[test]
+    fn failure_threshold_is_configurable() {
+        use std::net::TcpListener;
+
+        let mut config = ControllerConfig::default();
+        config.heartbeat_every = Duration::from_millis(100);
+        config.missed_heartbeats_before_failure = 2;
+        config.missed_heartbeats_with_other_failures = 1;
+        let mut c = test_controller(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpSender::connect(&listener.local_addr().unwrap()).unwrap();
+        let sender = Arc::new(Mutex::new(sender));
+        let a: WorkerIdentifier = "10.0.0.1:4000".parse().unwrap();
+        let b: WorkerIdentifier = "10.0.0.2:4000".parse().unwrap();
+        let x: WorkerIdentifier = "10.0.0.3:4000".parse().unwrap();
+        for &w in &[a, b, x] {
+            c.workers.insert(w, WorkerStatus::new(sender.clone()));
+        }
+        let last_heard = |c: &mut ControllerInner, w, ms| {
+            c.workers.get_mut(&w).unwrap().last_heartbeat =
+                Instant::now() - Duration::from_millis(ms);
+        };
+
+        // missing fewer heartbeats than the threshold is fine
+        last_heard(&mut c, a, 150);
+        last_heard(&mut c, b, 150);
+        c.recheck_workers = true;
+        assert!(c.find_failed_workers().is_empty());
+
+        // but missing two marks a worker as failed, along with any worker that has missed one
+        last_heard(&mut c, a, 250);
+        c.recheck_workers = true;
+        let mut failed = c.find_failed_workers();
+        failed.sort();
+        assert_eq!(failed, vec![a, b]);
+        assert!(!c.workers[&a].healthy);
+        assert!(!c.workers[&b].healthy);
+        assert!(c.workers[&x].healthy);
+    }
+
+    #[test]
+    fn failed_worker_can_rejoin() {
+        use std::net::TcpListener;
+
+        let mut c = test_controller(Default::default());
+        let epoch = c.epoch;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let w: WorkerIdentifier = "10.0.0.1:4000".parse().unwrap();
+        let register = |c: &mut ControllerInner, read_addr: &str| {
+            let msg = CoordinationMessage {
+                source: w,
+                epoch,
+                payload: CoordinationPayload::Register {
+                    addr: remote,
+                    read_listen_addr: read_addr.parse().unwrap(),
+                    log_files: vec![],
+                },
+            };
+            let r = c.handle_register(&msg, &remote, read_addr.parse().unwrap(), 1);
+            assert_eq!(r.unwrap(), Registration::Admitted);
+        };
+
+        // the worker registers, and then fails
+        register(&mut c, "10.0.0.1:5000");
+        c.workers.get_mut(&w).unwrap().last_heartbeat = Instant::now() - Duration::from_secs(60);
+        c.recheck_workers = true;
+        assert_eq!(c.find_failed_workers(), vec![w]);
+        assert!(!c.workers[&w].healthy);
+
+        // when it comes back, it is a healthy worker again, with its new read address
+        register(&mut c, "10.0.0.1:5001");
+        assert_eq!(c.workers.len(), 1);
+        assert!(c.workers[&w].healthy);
+        assert_eq!(c.read_addrs[&w], "10.0.0.1:5001".parse().unwrap());
+    }
+
+    #[test]
+    fn rejoining_worker_sheds_its_domains() {
+        use crate::controller::domain_handle::tests::loopback_domain;
+        use std::net::TcpListener;
+
+        let mut c = test_controller(Default::default());
+        let epoch = c.epoch;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let di = DomainIndex::from(0);
+        let (dh, _rx, _replies) = loopback_domain(di, None, &mut c.workers);
+        let w = dh.assignment(0);
+        c.domains.insert(di, dh);
+        let register = |c: &mut ControllerInner| {
+            let msg = CoordinationMessage {
+                source: w,
+                epoch,
+                payload: CoordinationPayload::Register {
+                    addr: remote,
+                    read_listen_addr: remote,
+                    log_files: vec![],
+                },
+            };
+            c.handle_register(&msg, &remote, remote, 1)
+        };
+
+        // the worker's domain holds a base that is not in the recipe, so recovery cannot move it
+        let base = c.ingredients.add_node(node::Node::new(
+            "base",
+            &["c1", "c2"],
+            node::special::Base::default(),
+        ));
+        c.ingredients.add_edge(c.source, base, ());
+        c.ingredients[base].add_to(di);
+        c.workers.get_mut(&w).unwrap().healthy = false;
+
+        // so the worker cannot rejoin while the base still lives in the domain
+        assert!(register(&mut c).is_err());
+        assert!(!c.workers[&w].healthy);
+        assert!(c.domains.contains_key(&di));
+
+        // once the base is gone, the worker rejoins without the domain
+        c.ingredients[base].remove();
+        assert_eq!(register(&mut c).unwrap(), Registration::Admitted);
+        assert!(c.workers[&w].healthy);
+        assert!(c.domains.is_empty());
+    }
+
+    #[test]
+    fn register_retries_connect() {
+        use std::net::TcpListener;
+
+        let mut c = test_controller(Default::default());
+        let epoch = c.epoch;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let read_addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let msg = move |w: WorkerIdentifier| CoordinationMessage {
+            source: w,
+            epoch,
+            payload: CoordinationPayload::Register {
+                addr: remote,
+                read_listen_addr: read_addr,
+                log_files: vec![],
+            },
+        };
+        let refused = || Err(io::Error::new(io::ErrorKind::ConnectionRefused, "not yet"));
+
+        // drives a registration the way the event loop does, with the first `failures`
+        // connection attempts failing, and collects the backoffs between attempts
+        let register = |c: &mut ControllerInner, w: WorkerIdentifier, failures: u32| {
+            let msg = msg(w);
+            let mut backoffs = Vec::new();
+            for attempt in 1.. {
+                let r = c.register_worker(&msg, &remote, read_addr, attempt, |addr| {
+                    if attempt <= failures {
+                        refused()
+                    } else {
+                        TcpSender::connect(addr)
+                    }
+                });
+                match r {
+                    Ok(Registration::RetryAfter(backoff)) => backoffs.push(backoff),
+                    r => return (r, backoffs),
+                }
+            }
+            unreachable!();
+        };
+        let ms = Duration::from_millis;
+
+        // a worker that is briefly unreachable is still admitted
+        let w: WorkerIdentifier = "10.0.0.1:4000".parse().unwrap();
+        let (r, backoffs) = register(&mut c, w, 2);
+        assert_eq!(r.unwrap(), Registration::Admitted);
+        assert_eq!(backoffs, vec![ms(REGISTER_BACKOFF_MS), ms(2 * REGISTER_BACKOFF_MS)]);
+        assert!(c.workers[&w].healthy);
+
+        // a failed attempt hands the wait back to the event loop, so other events are handled
+        // while the registration is pending
+        let x: WorkerIdentifier = "10.0.0.2:4000".parse().unwrap();
+        let r = c.register_worker(&msg(x), &remote, read_addr, 1, |_| refused());
+        assert_eq!(r.unwrap(), Registration::RetryAfter(ms(REGISTER_BACKOFF_MS)));
+        assert!(!c.workers.contains_key(&x));
+        let before = Instant::now() - Duration::from_secs(1);
+        c.workers.get_mut(&w).unwrap().last_heartbeat = before;
+        let heartbeat = CoordinationMessage {
+            source: w,
+            epoch,
+            payload: CoordinationPayload::Heartbeat,
+        };
+        c.handle_heartbeat(&heartbeat).unwrap();
+        assert!(c.workers[&w].last_heartbeat > before);
+
+        // and the retry then admits the worker
+        let r = c.register_worker(&msg(x), &remote, read_addr, 2, TcpSender::connect);
+        assert_eq!(r.unwrap(), Registration::Admitted);
+        assert!(c.workers[&x].healthy);
+
+        // but one that never becomes reachable is eventually rejected
+        let y: WorkerIdentifier = "10.0.0.3:4000".parse().unwrap();
+        let (r, backoffs) = register(&mut c, y, REGISTER_CONNECT_ATTEMPTS);
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(backoffs.len() as u32, REGISTER_CONNECT_ATTEMPTS - 1);
+        assert!(!c.workers.contains_key(&y));
+    }
+
+    #[test]
+    fn lookup_builder_errors() {
+
+        let mut c = test_controller(Default::default());
+
+        // a base with a reader below it, both in a domain that has not been booted
+        let domain = DomainIndex::from(0);
+        let mut a = node::Node::new(
+            "a",
+            &["a1", "a2"],
+            node::NodeType::from(node::special::Base::default()),
+        );
+        a.add_to(domain);
+        let a = c.ingredients.add_node(a);
+        c.ingredients.add_edge(c.source, a, ());
+        let mut r = c.ingredients[a].named_mirror(node::special::Reader::new(a), "q".to_owned());
+        r.with_reader_mut(|r| r.set_key(&[0])).unwrap();
+        r.add_to(domain);
+        let r = c.ingredients.add_node(r);
+        c.ingredients.add_edge(a, r, ());
+
+        let err = |e: &str| Some(LookupBuilderError::NotFound(e.to_owned()));
+        assert_eq!(c.view_builder("b").err(), err("b"));
+        assert_eq!(c.table_builder("b").err(), err("b"));
+
+        let err = |e: &str| Some(LookupBuilderError::NoReader(e.to_owned()));
+        assert_eq!(c.view_builder("a").err(), err("a"));
+
+        let err = |e: &str| Some(LookupBuilderError::NotATable(e.to_owned()));
+        assert_eq!(c.table_builder("q").err(), err("q"));
+
+        let err = |e: &str| Some(LookupBuilderError::NotMaterialized(e.to_owned()));
+        assert_eq!(c.view_builder("q").err(), err("q"));
+        assert_eq!(c.table_builder("a").err(), err("a"));
+    }
+
+    #[test]
+    fn find_view_skips_secondary_readers() {
+
+        let mut c = test_controller(Default::default());
+
+        let a = node::Node::new(
+            "a",
+            &["a1", "a2"],
+            node::NodeType::from(node::special::Base::default()),
+        );
+        let a = c.ingredients.add_node(a);
+        c.ingredients.add_edge(c.source, a, ());
+
+        // the secondary index is added before the primary reader
+        let by_a2 = node::special::Reader::secondary(a);
+        let by_a2 = c.ingredients[a].named_mirror(by_a2, "by_a2".to_owned());
+        let by_a2 = c.ingredients.add_node(by_a2);
+        c.ingredients.add_edge(a, by_a2, ());
+        assert_eq!(c.find_view_for(a), None);
+
+        let q = node::special::Reader::new(a);
+        let q = c.ingredients[a].named_mirror(q, "q".to_owned());
+        let q = c.ingredients.add_node(q);
+        c.ingredients.add_edge(a, q, ());
+
+        assert_eq!(c.find_view_for(a), Some(q));
+        assert_eq!(c.find_named_view_for(a, "by_a2"), Some(by_a2));
+        assert_eq!(c.find_named_view_for(a, "a"), Some(q));
+    }
+
+    #[test]
+    fn topo_order_places_nodes_after_ancestors() {
+        use petgraph::EdgeDirection::Incoming;
+
+        let mut c = test_controller(Default::default());
+
+        let add = |c: &mut ControllerInner, name: &str, parents: &[NodeIndex]| {
+            let nt = if parents.is_empty() {
+                node::NodeType::from(node::special::Base::default())
+            } else {
+                node::NodeType::from(node::special::Ingress)
+            };
+            let ni = c.ingredients.add_node(node::Node::new(name, &["c1", "c2"], nt));
+            if parents.is_empty() {
+                c.ingredients.add_edge(c.source, ni, ());
+            }
+            for &p in parents {
+                c.ingredients.add_edge(p, ni, ());
+            }
+            ni
+        };
+
+        // a diamond below b, added in an order that does not match the topological one:
+        // src -> a, src -> b -> {x, y} -> z, with z also a child of a, and d below z
+        let z = c.ingredients.add_node(node::Node::new("z", &["c1", "c2"], node::special::Ingress));
+        let a = add(&mut c, "a", &[]);
+        let b = add(&mut c, "b", &[]);
+        let x = add(&mut c, "x", &[b]);
+        let y = add(&mut c, "y", &[b]);
+        for &p in &[x, y, a] {
+            c.ingredients.add_edge(p, z, ());
+        }
+        let d = add(&mut c, "d", &[z]);
+        c.ingredients[d].remove();
+
+        let order = c.topo_order();
+        assert!(!order.contains(&c.source));
+        assert!(!order.contains(&d));
+        assert_eq!(order.len(), 5);
+        for (i, &ni) in order.iter().enumerate() {
+            for p in c.ingredients.neighbors_directed(ni, Incoming) {
+                if p != c.source {
+                    assert!(order[..i].contains(&p), "{:?} placed before {:?}", ni, p);
+                }
+            }
+        }
+
+        let mut reverse = c.reverse_topo_order();
+        reverse.reverse();
+        assert_eq!(reverse, order);
+    }
+
+    #[test]
+    fn statistics_skip_unresponsive_domains() {
+        use crate::controller::domain_handle::tests::loopback_domain;
+        use api::debug::stats::DomainStats;
+        use std::thread;
+
+        let mut c = test_controller(Default::default());
+        let (d0, d1) = (DomainIndex::from(0), DomainIndex::from(1));
+        let mut shards = Vec::new();
+        for &di in &[d0, d1] {
+            let (dh, rx, replies) =
+                loopback_domain(di, Some(Duration::from_millis(100)), &mut c.workers);
+            c.domains.insert(di, dh);
+            shards.push((rx, replies));
+        }
+
+        // both domains are asked before either one replies, and then only domain 0 replies. the
+        // connections are handed back, so that domain 1 stays silent rather than disconnecting.
+        let domains = thread::spawn(move || {
+            for (rx, _) in &mut shards {
+                match *rx.recv().unwrap() {
+                    payload::Packet::GetStatistics => {}
+                    ref p => panic!("domain got unexpected packet {:?}", p),
+                }
+            }
+            let stats = DomainStats {
+                total_time: 1,
+                total_ptime: 2,
+                wait_time: 3,
+                replays: 4,
+                reader_latency: Default::default(),
+            };
+            shards[0]
+                .1
+                .send(payload::ControlReplyPacket::Statistics(stats, HashMap::new()))
+                .unwrap();
+            shards
+        });
+
+        let stats = c.get_statistics();
+        let _shards = domains.join().unwrap();
+
+        // the silent domain is left out rather than failing the whole request, and its worker is
+        // checked on the next heartbeat
+        assert_eq!(stats.domains.len(), 1);
+        let (ref domain, ref nodes) = stats.domains[&(d0, 0)];
+        assert_eq!(domain.replays, 4);
+        assert!(nodes.is_empty());
+        assert!(c.recheck_workers);
+    }
+}
@@ -5,8 +5,10 @@
 //! domains, but does not perform that copying itself (that is the role of the `augmentation`
 //! module).
 
+use api::debug::stats::ReplayProgress;
 use crate::controller::domain_handle::DomainHandle;
-use crate::controller::{inner::graphviz, keys, WorkerIdentifier, WorkerStatus};
+use crate::controller::migrate::MigrationError;
+use crate::controller::{inner::graphviz, keys, ReplayStatus, WorkerIdentifier, WorkerStatus};
 use dataflow::prelude::*;
 use petgraph;
 use petgraph::graph::NodeIndex;
@@ -32,8 +34,16 @@ pub struct Materializations {
     pub domains_on_path: HashMap<Tag, Vec<DomainIndex>>,
 
     tag_generator: AtomicUsize,
+    /// The nodes along every replay path set up so far, by the path's tag.
+    replay_paths: HashMap<Tag, Vec<NodeIndex>>,
+
+    /// Progress of full replays, shared with the external request handler.
+    replays: ReplayStatus,
 }
 
+/// The number of completed full replays whose progress is kept in the replay status.
+const FINISHED_REPLAYS_KEPT: usize = 64;
+
 impl Materializations {
     /// Create a new set of materializations.
     pub fn new(logger: &Logger) -> Self {
@@ -49,6 +59,9 @@ impl Materializations {
             domains_on_path: Default::default(),
 
             tag_generator: AtomicUsize::default(),
+            replay_paths: HashMap::default(),
+
+            replays: ReplayStatus::default(),
         }
     }
 
@@ -61,6 +74,11 @@ impl Materializations {
     pub fn disable_partial(&mut self) {
         self.partial_enabled = false;
     }
+
+    /// Record the progress of full replays in the given status map.
+    pub fn set_replay_status(&mut self, replays: ReplayStatus) {
+        self.replays = replays;
+    }
 }
 
 impl Materializations {
@@ -68,6 +86,64 @@ impl Materializations {
         Tag(self.tag_generator.fetch_add(1, Ordering::SeqCst) as u32)
     }
 
+    /// The number of replay path tags handed out so far.
+    pub(in crate::controller) fn tags_allocated(&self) -> usize {
+        self.tag_generator.load(Ordering::SeqCst)
+    }
+
+    /// Note that the replay path with the given tag goes through the given nodes.
+    ///
+    /// Tags are never reused, not even across migrations, since domains may still hold on to the
+    /// paths of earlier tags.
+    pub(in crate::controller) fn record_replay_path(
+        &mut self,
+        tag: Tag,
+        path: Vec<NodeIndex>,
+    ) -> Result<(), MigrationError> {
+        if self.replay_paths.contains_key(&tag) {
+            return Err(MigrationError::TagReused(tag));
+        }
+        self.replay_paths.insert(tag, path);
+        Ok(())
+    }
+
+    /// Forget the materializations of the given nodes, which are being removed, along with all
+    /// replay paths that go through any of them.
+    ///
+    /// The tags of those paths are not handed out again.
+    pub(in crate::controller) fn remove_nodes(&mut self, removed: &[NodeIndex]) {
+        for ni in removed {
+            self.have.remove(ni);
+            self.added.remove(ni);
+            self.partial.remove(ni);
+        }
+
+        let gone: Vec<_> = self
+            .replay_paths
+            .iter()
+            .filter(|&(_, path)| path.iter().any(|ni| removed.contains(ni)))
+            .map(|(&tag, _)| tag)
+            .collect();
+        for tag in gone {
+            self.replay_paths.remove(&tag);
+            self.domains_on_path.remove(&tag);
+        }
+    }
+
+    /// The nodes along every replay path set up so far, ordered by tag.
+    ///
+    /// Each path is listed from the materialized node the replay starts at to the node whose
+    /// state it fills.
+    pub fn replay_paths(&self) -> Vec<(Tag, Vec<NodeIndex>)> {
+        let mut paths: Vec<_> = self
+            .replay_paths
+            .iter()
+            .map(|(&tag, path)| (tag, path.clone()))
+            .collect();
+        paths.sort_by_key(|&(tag, _)| tag);
+        paths
+    }
+
     /// Extend the current set of materializations with any additional materializations needed to
     /// satisfy indexing obligations in the given set of (new) nodes.
     fn extend(&mut self, graph: &Graph, new: &HashSet<NodeIndex>) {
@@ -299,6 +375,13 @@ impl Materializations {
                 able = false;
             }
 
+            // a view keyed on no columns has a single key that holds every row, so there are no
+            // smaller holes to fill through replay.
+            if indexes.iter().any(|index| index.is_empty()) {
+                warn!(self.log, "full because keyed on no columns"; "node" => ni.index());
+                able = false;
+            }
+
             // we are already fully materialized, so can't be made partial
             if !new.contains(&ni)
                 && self.added.get(&ni).map(|i| i.len()).unwrap_or(0)
@@ -436,7 +519,7 @@ impl Materializations {
         new: &HashSet<NodeIndex>,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+    ) -> Result<(), MigrationError> {
         self.extend(graph, new);
 
         // check that we don't have fully materialized nodes downstream of partially materialized
@@ -595,6 +678,20 @@ impl Materializations {
             }
         }
 
+        let r = self.construct(graph, new, domains, workers);
+        self.added.clear();
+        r
+    }
+
+    /// Add the new indices to existing nodes, and ready the new nodes, constructing their state
+    /// through replay where needed.
+    fn construct(
+        &mut self,
+        graph: &Graph,
+        new: &HashSet<NodeIndex>,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<(), MigrationError> {
         let mut reindex = Vec::with_capacity(new.len());
         let mut make = Vec::with_capacity(new.len());
         let mut topo = petgraph::visit::Topo::new(graph);
@@ -665,8 +762,9 @@ impl Materializations {
                 info!(self.log, "adding partial index to existing {:?}", n);
                 let log = self.log.new(o!("node" => node.index()));
                 let log = mem::replace(&mut self.log, log);
-                self.setup(node, &mut index_on, graph, domains, workers);
+                let r = self.setup(node, &mut index_on, graph, domains, workers);
                 mem::replace(&mut self.log, log);
+                r?;
                 index_on.clear();
             } else if !n.sharded_by().is_none() {
                 // what do we even do here?!
@@ -690,7 +788,11 @@ impl Materializations {
             }
         }
 
-        // then, we start prepping new nodes
+        // bases first, all at once, and only then their descendants
+        let (bases, make): (Vec<_>, Vec<_>) = make.into_iter().partition(|&ni| graph[ni].is_base());
+        self.ready_bases(&bases, graph, domains, workers)?;
+
+        // then, we start prepping the other new nodes
         for ni in make {
             let n = &graph[ni];
             let mut index_on = self
@@ -702,7 +804,7 @@ impl Materializations {
                 }).unwrap_or_else(HashSet::new);
 
             let start = ::std::time::Instant::now();
-            self.ready_one(ni, &mut index_on, graph, domains, workers);
+            self.ready_one(ni, &mut index_on, graph, domains, workers)?;
             let reconstructed = index_on.is_empty();
 
             // communicate to the domain in charge of a particular node that it should start
@@ -720,7 +822,7 @@ impl Materializations {
                     },
                     workers,
                 ).unwrap();
-            domain.wait_for_ack().unwrap();
+            domain.wait_for_ack().map_err(|e| MigrationError::Domain(n.domain(), e))?;
             trace!(self.log, "node ready"; "node" => ni.index());
 
             if reconstructed {
@@ -731,7 +833,46 @@ impl Materializations {
             }
         }
 
-        self.added.clear();
+        Ok(())
+    }
+
+    /// Ready the given new bases, and wait for their domains to acknowledge them.
+    ///
+    /// New bases are never reconstructed, but when we are recovering, readying a base makes its
+    /// domain replay the base's persisted log, which can take a while. We therefore send every
+    /// `Ready` before waiting for any acknowledgement, so that the logs are replayed in parallel.
+    fn ready_bases(
+        &mut self,
+        bases: &[NodeIndex],
+        graph: &Graph,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<(), MigrationError> {
+        for &ni in bases {
+            let n = &graph[ni];
+            let index_on = self.added.remove(&ni).unwrap_or_else(HashSet::new);
+            trace!(self.log, "readying base"; "node" => ni.index());
+            domains
+                .get_mut(&n.domain())
+                .unwrap()
+                .send_to_healthy(
+                    box Packet::Ready {
+                        node: *n.local_addr(),
+                        index: index_on,
+                    },
+                    workers,
+                ).unwrap();
+        }
+        for &ni in bases {
+            let domain = graph[ni].domain();
+            domains
+                .get_mut(&domain)
+                .unwrap()
+                .wait_for_ack()
+                .map_err(|e| MigrationError::Domain(domain, e))?;
+            trace!(self.log, "base ready"; "node" => ni.index());
+        }
+        Ok(())
     }
 
     /// Perform all operations necessary to bring any materializations for the given node up, and
@@ -743,7 +884,7 @@ impl Materializations {
         graph: &Graph,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+    ) -> Result<(), MigrationError> {
         let n = &graph[ni];
         let mut has_state = !index_on.is_empty();
 
@@ -761,7 +902,7 @@ impl Materializations {
             // a new base must be empty, so we can materialize it immediately
             info!(self.log, "no need to replay empty new base"; "node" => ni.index());
             assert!(!self.partial.contains(&ni));
-            return;
+            return Ok(());
         }
 
         // if this node doesn't need to be materialized, then we're done.
@@ -774,21 +915,22 @@ impl Materializations {
 
         if !has_state {
             debug!(self.log, "no need to replay non-materialized view"; "node" => ni.index());
-            return;
+            return Ok(());
         }
 
         // we have a parent that has data, so we need to replay and reconstruct
         info!(self.log, "beginning reconstruction of {:?}", n);
         let log = self.log.new(o!("node" => ni.index()));
         let log = mem::replace(&mut self.log, log);
-        self.setup(ni, index_on, graph, domains, workers);
+        let r = self.setup(ni, index_on, graph, domains, workers);
         mem::replace(&mut self.log, log);
+        r?;
 
         // NOTE: the state has already been marked ready by the replay completing, but we want to
         // wait for the domain to finish replay, which the ready executed by the outer commit()
         // loop does.
         index_on.clear();
-        return;
+        Ok(())
     }
 
     /// Reconstruct the materialized state required by the given (new) node through replay.
@@ -799,7 +941,7 @@ impl Materializations {
         graph: &Graph,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+    ) -> Result<(), MigrationError> {
         if index_on.is_empty() {
             // we must be reconstructing a Reader.
             // figure out what key that Reader is using
@@ -816,7 +958,7 @@ impl Materializations {
         let pending = {
             let mut plan = plan::Plan::new(self, graph, ni, domains, workers);
             for index in index_on.drain() {
-                plan.add(index);
+                plan.add(index)?;
             }
             plan.finalize()
         };
@@ -825,14 +967,34 @@ impl Materializations {
             trace!(self.log, "all domains ready for replay");
 
             // prepare for, start, and wait for replays
-            for pending in pending {
+            for pending in &pending {
+                let source = domains.get_mut(&pending.source_domain).unwrap();
+
+                // find out roughly how much state there is to replay, so that progress can be
+                // reported against it
+                source
+                    .send_to_healthy(
+                        box Packet::StateSizeProbe {
+                            node: pending.source,
+                        },
+                        workers,
+                    ).unwrap();
+                let total = source
+                    .wait_for_state_size()
+                    .map_err(|e| MigrationError::Domain(pending.source_domain, e))?;
+                self.replays.lock().unwrap().insert(
+                    pending.tag,
+                    ReplayProgress {
+                        total: total as u64,
+                        ..ReplayProgress::default()
+                    },
+                );
+
                 // tell the first domain to start playing
                 trace!(self.log, "telling root domain to start replay";
                    "domain" => pending.source_domain.index());
 
-                domains
-                    .get_mut(&pending.source_domain)
-                    .unwrap()
+                source
                     .send_to_healthy(
                         box Packet::StartReplay {
                             tag: pending.tag,
@@ -849,7 +1011,98 @@ impl Materializations {
                "domain" => target.index(),
             );
 
-            domains.get_mut(&target).unwrap().wait_for_ack().unwrap();
+            domains
+                .get_mut(&target)
+                .unwrap()
+                .wait_for_replay(&self.replays)
+                .map_err(|e| MigrationError::Domain(target, e))?;
+
+            let mut replays = self.replays.lock().unwrap();
+            for pending in &pending {
+                replays.get_mut(&pending.tag).unwrap().done = true;
+            }
+
+            // only keep the most recent completed replays around, so that the status does not
+            // grow with every migration. tags are handed out in order, so the lowest are oldest.
+            let mut finished: Vec<_> = replays
+                .iter()
+                .filter(|&(_, p)| p.done)
+                .map(|(&tag, _)| tag)
+                .collect();
+            if finished.len() > FINISHED_REPLAYS_KEPT {
+                finished.sort();
+                let drop = finished.len() - FINISHED_REPLAYS_KEPT;
+                for tag in &finished[..drop] {
+                    replays.remove(tag);
+                }
+            }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::domain_handle::tests::loopback_domain;
+    use dataflow::node;
+    use dataflow::payload::ControlReplyPacket;
+    use slog;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn new_bases_are_readied_together() {
+        let mut g = Graph::new();
+        let src = g.add_node(node::Node::new(
+            "source",
+            &["because-type-inference"],
+            node::special::Source,
+        ));
+        let add = |g: &mut Graph, name: &str, domain: usize| {
+            let ni = g.add_node(node::Node::new(name, &["x"], node::special::Base::default()));
+            let mut ip = IndexPair::from(ni);
+            ip.set_local(unsafe { LocalNodeIndex::make(0) });
+            g[ni].set_finalized_addr(ip);
+            g[ni].add_to(DomainIndex::from(domain));
+            g.add_edge(src, ni, ());
+            ni
+        };
+        let a = add(&mut g, "a", 0);
+        let b = add(&mut g, "b", 1);
+
+        let mut workers = HashMap::new();
+        let mut domains = HashMap::new();
+        let mut shards = Vec::new();
+        for &ni in &[a, b] {
+            let domain = g[ni].domain();
+            let (dh, rx, replies) =
+                loopback_domain(domain, Some(Duration::from_secs(10)), &mut workers);
+            domains.insert(domain, dh);
+            shards.push((rx, replies));
+        }
+
+        // neither domain acknowledges its base until both bases have been readied, so the
+        // controller must not wait for the first base before readying the second.
+        let readied = thread::spawn(move || {
+            let mut readied = Vec::new();
+            for (rx, _) in &mut shards {
+                match *rx.recv().unwrap() {
+                    Packet::Ready { node, .. } => readied.push(node),
+                    ref p => panic!("domain got unexpected packet {:?}", p),
+                }
+            }
+            for (_, replies) in &mut shards {
+                replies.send(ControlReplyPacket::ack()).unwrap();
+            }
+            readied
+        });
+
+        let mut m = Materializations::new(&slog::Logger::root(slog::Discard, o!()));
+        m.ready_bases(&[a, b], &g, &mut domains, &workers).unwrap();
+        assert_eq!(
+            readied.join().unwrap(),
+            vec![*g[a].local_addr(), *g[b].local_addr()]
+        );
     }
 }
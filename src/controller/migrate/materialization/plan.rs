@@ -1,4 +1,5 @@
 use crate::controller::domain_handle::DomainHandle;
+use crate::controller::migrate::MigrationError;
 use crate::controller::{inner::graphviz, keys, WorkerIdentifier, WorkerStatus};
 use dataflow::payload::{SourceSelection, TriggerEndpoint};
 use dataflow::prelude::*;
@@ -98,24 +99,30 @@ impl<'a> Plan<'a> {
         paths
     }
 
+    /// Allocate a tag for a new replay path. Tags are unique across all migrations.
+    pub(in crate::controller) fn next_replay_tag(&mut self) -> Tag {
+        self.m.next_tag()
+    }
+
     /// Finds the appropriate replay paths for the given index, and inform all domains on those
     /// paths about them. It also notes if any data backfills will need to be run, which is
     /// eventually reported back by `finalize`.
-    pub fn add(&mut self, index_on: Vec<usize>) {
+    pub fn add(&mut self, index_on: Vec<usize>) -> Result<(), MigrationError> {
         if !self.partial && !self.paths.is_empty() {
             // non-partial views should not have one replay path per index. that would cause us to
             // replay several times, even though one full replay should always be sufficient.
             // we do need to keep track of the fact that there should be an index here though.
             self.tags.entry(index_on).or_default();
-            return;
+            return Ok(());
         }
 
         // inform domains about replay paths
         let mut tags = Vec::new();
         for path in self.paths(&index_on[..]) {
-            let tag = self.m.next_tag();
-            self.paths
-                .insert(tag, path.iter().map(|&(ni, _)| ni).collect());
+            let tag = self.next_replay_tag();
+            let nodes: Vec<_> = path.iter().map(|&(ni, _)| ni).collect();
+            self.m.record_replay_path(tag, nodes.clone())?;
+            self.paths.insert(tag, nodes);
 
             // what key are we using for partial materialization (if any)?
             let mut partial = None;
@@ -231,10 +238,9 @@ impl<'a> Plan<'a> {
                             let shards = src_sharding.shards().unwrap_or(1);
                             let lookup_on_shard_key = match src_sharding {
                                 Sharding::Random(..) => false,
-                                Sharding::ByColumn(c, _) => {
-                                    assert_eq!(key.len(), 1);
-                                    c == key[0]
-                                }
+                                // replays for compound keys can't be routed to a single shard,
+                                // even if the sharding column is part of the key.
+                                Sharding::ByColumn(c, _) => key.len() == 1 && c == key[0],
                                 _ => true,
                             };
 
@@ -329,7 +335,7 @@ impl<'a> Plan<'a> {
                 trace!(self.m.log, "telling domain about replay path"; "domain" => domain.index());
                 let ctx = self.domains.get_mut(&domain).unwrap();
                 ctx.send_to_healthy(setup, self.workers).unwrap();
-                ctx.wait_for_ack().unwrap();
+                ctx.wait_for_ack().map_err(|e| MigrationError::Domain(domain, e))?;
             }
 
             if !self.partial {
@@ -341,6 +347,7 @@ impl<'a> Plan<'a> {
         }
 
         self.tags.entry(index_on).or_default().extend(tags);
+        Ok(())
     }
 
     /// Instructs the target node to set up appropriate state for any new indices that have been
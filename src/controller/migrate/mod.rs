@@ -25,9 +25,11 @@ use dataflow::{node, payload};
 
 use rand::{thread_rng, Rng};
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use crate::controller::{ControllerInner, DomainHandle, WorkerEndpoint, WorkerIdentifier};
+use crate::controller::domain_handle::{BootError, WaitError};
+use crate::controller::{ControllerInner, DomainHandle};
 
 use petgraph;
 use slog;
@@ -38,6 +40,48 @@ pub mod materialization;
 pub mod routing;
 pub mod sharding;
 
+/// A summary of the changes a migration made to the graph.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MigrationSummary {
+    /// Number of nodes added, including ingress, egress, sharder and reader nodes.
+    pub nodes_added: usize,
+    /// Number of nodes removed.
+    pub nodes_removed: usize,
+    /// Number of new domains that were booted.
+    pub domains_spawned: usize,
+    /// Number of replay paths that were set up.
+    pub replay_paths_created: usize,
+    /// Time taken by the migration, in milliseconds.
+    pub duration_ms: u64,
+}
+
+/// Why a migration could not be committed.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// A new domain did not boot in time.
+    Boot(BootError),
+    /// A domain did not reply as expected while it was being set up for the migration.
+    Domain(DomainIndex, WaitError),
+    /// A replay path was given a tag that an existing replay path already uses.
+    TagReused(Tag),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MigrationError::Boot(ref e) => write!(f, "failed to boot new domains: {}", e),
+            MigrationError::Domain(di, ref e) => write!(f, "domain {}: {}", di.index(), e),
+            MigrationError::TagReused(tag) => write!(f, "replay path tag {:?} reused", tag),
+        }
+    }
+}
+
+impl From<BootError> for MigrationError {
+    fn from(e: BootError) -> Self {
+        MigrationError::Boot(e)
+    }
+}
+
 #[derive(Clone)]
 pub(super) enum ColumnChange {
     Add(String, DataType),
@@ -53,6 +97,11 @@ pub struct Migration<'a> {
     pub(super) added: Vec<NodeIndex>,
     pub(super) columns: Vec<(NodeIndex, ColumnChange)>,
     pub(super) readers: HashMap<NodeIndex, NodeIndex>,
+    pub(super) secondary_readers: Vec<NodeIndex>,
+    pub(super) sharding: HashMap<NodeIndex, sharding::Directive>,
+
+    /// How long to wait for the new domains of this migration to boot.
+    pub(super) domain_boot_timeout: Option<Duration>,
 
     pub(super) start: Instant,
     pub(super) log: slog::Logger,
@@ -86,7 +135,8 @@ impl<'a> Migration<'a> {
         info!(self.log,
               "adding new node";
               "node" => ni.index(),
-              "type" => format!("{:?}", self.mainline.ingredients[ni])
+              "type" => format!("{:?}", self.mainline.ingredients[ni]),
+              "parents" => ?parents.iter().map(|p| p.index()).collect::<Vec<_>>()
         );
 
         // keep track of the fact that it's new
@@ -133,6 +183,13 @@ impl<'a> Migration<'a> {
         ni.into()
     }
 
+    /// Shard the new node `ni` into `shards` shards, rather than into as many as the controller
+    /// uses by default. If `ni` is a base, `column` picks the column to shard it by.
+    pub fn shard(&mut self, ni: NodeIndex, column: Option<usize>, shards: Option<usize>) {
+        assert!(self.added.contains(&ni), "only new nodes can be sharded");
+        self.sharding.insert(ni, sharding::Directive { column, shards });
+    }
+
     /// Returns the context of this migration
     pub fn context(&self) -> &HashMap<String, DataType> {
         &self.context
@@ -208,6 +265,12 @@ impl<'a> Migration<'a> {
         self.mainline.graph()
     }
 
+    /// Change how long this migration waits for its new domains to boot.
+    #[cfg(test)]
+    pub fn set_domain_boot_timeout(&mut self, timeout: Option<Duration>) {
+        self.domain_boot_timeout = timeout;
+    }
+
     fn ensure_reader_for(&mut self, n: NodeIndex, name: Option<String>) {
         if !self.readers.contains_key(&n) {
             // make a reader
@@ -228,6 +291,7 @@ impl<'a> Migration<'a> {
     /// To query into the maintained state, use `ControllerInner::get_getter`.
     #[cfg(test)]
     pub fn maintain_anonymous(&mut self, n: NodeIndex, key: &[usize]) -> NodeIndex {
+        self.check_reader_key(n, key).unwrap();
         self.ensure_reader_for(n, None);
         let ri = self.readers[&n];
 
@@ -238,10 +302,40 @@ impl<'a> Migration<'a> {
         ri
     }
 
+    /// Check that a reader for `n` keyed on `key` could actually be filled.
+    fn check_reader_key(&self, n: NodeIndex, key: &[usize]) -> Result<(), String> {
+        let node = &self.mainline.ingredients[n];
+        if node.is_source() || node.is_reader() || node.is_dropped() {
+            return Err(format!(
+                "cannot maintain {} ({}): node cannot hold state for a reader",
+                node.name(),
+                n.index()
+            ));
+        }
+        let fields = node.fields();
+        if let Some(&bad) = key.iter().find(|&&c| c >= fields.len()) {
+            return Err(format!(
+                "cannot maintain {} keyed on column {}: node only has {} columns ({:?})",
+                node.name(),
+                bad,
+                fields.len(),
+                fields
+            ));
+        }
+        Ok(())
+    }
+
     /// Set up the given node such that its output can be efficiently queried.
     ///
     /// To query into the maintained state, use `ControllerInner::get_getter`.
-    pub fn maintain(&mut self, name: String, n: NodeIndex, key: &[usize]) {
+    ///
+    /// If `key` is empty, the reader is fully materialized and holds all of `n`'s rows under a
+    /// single key, which can be read using `View::all`.
+    ///
+    /// Fails without adding a reader if `n` cannot be looked up by `key`, for example because
+    /// one of the key columns does not exist in `n`'s output.
+    pub fn maintain(&mut self, name: String, n: NodeIndex, key: &[usize]) -> Result<(), String> {
+        self.check_reader_key(n, key)?;
         self.ensure_reader_for(n, Some(name));
 
         let ri = self.readers[&n];
@@ -249,6 +343,70 @@ impl<'a> Migration<'a> {
         self.mainline.ingredients[ri]
             .with_reader_mut(|r| r.set_key(key))
             .unwrap();
+        Ok(())
+    }
+
+    /// Add a secondary index on `key` to the node `n`, which can be queried as the view `name`.
+    ///
+    /// The index is kept in a reader of its own next to the one added by `maintain`, so that `n`
+    /// can be looked up efficiently both by the key it is maintained on and by `key`.
+    ///
+    /// Unlike indexes added through `ControllerHandle::add_view_index`, the index is not recorded
+    /// in the controller state, so it is not added back when `n` is recovered or the controller
+    /// restarts.
+    pub fn maintain_secondary(
+        &mut self,
+        name: String,
+        n: NodeIndex,
+        key: &[usize],
+    ) -> Result<(), String> {
+        self.check_reader_key(n, key)?;
+        {
+            let graph = &self.mainline.ingredients;
+            if graph
+                .node_indices()
+                .any(|ni| graph[ni].is_reader() && graph[ni].name() == name)
+            {
+                return Err(format!("a view named {} already exists", name));
+            }
+        }
+
+        let r = node::special::Reader::secondary(n);
+        let r = self.mainline.ingredients[n].named_mirror(r, name);
+        let r = self.mainline.ingredients.add_node(r);
+        self.mainline.ingredients.add_edge(n, r, ());
+        self.mainline.ingredients[r]
+            .with_reader_mut(|r| r.set_key(key))
+            .unwrap();
+        self.secondary_readers.push(r);
+        Ok(())
+    }
+
+    /// Add a reader split into `shards` shards next to the existing reader `reader`, and return
+    /// it.
+    ///
+    /// The new reader has the same name and key as `reader`, and is for the same node. Records
+    /// reach it through a sharder of its own, so the node it is for keeps its sharding, and it is
+    /// filled from that node's state when the migration commits. Until `reader` is removed,
+    /// lookups keep going to `reader`.
+    pub(crate) fn maintain_resharded(&mut self, reader: NodeIndex, shards: usize) -> NodeIndex {
+        let (n, key) = self.mainline.ingredients[reader]
+            .with_reader(|r| (r.is_for(), r.key().map(Vec::from)))
+            .unwrap();
+        let name = self.mainline.ingredients[reader].name().to_owned();
+
+        let r = node::special::Reader::new(n);
+        let r = self.mainline.ingredients[n].named_mirror(r, name);
+        let r = self.mainline.ingredients.add_node(r);
+        self.mainline.ingredients.add_edge(n, r, ());
+        if let Some(key) = key {
+            self.mainline.ingredients[r]
+                .with_reader_mut(|r| r.set_key(&key))
+                .unwrap();
+        }
+        self.added.push(r);
+        self.shard(r, None, Some(shards));
+        r
     }
 
     /// Commit the changes introduced by this `Migration` to the master `Soup`.
@@ -256,18 +414,30 @@ impl<'a> Migration<'a> {
     /// This will spin up an execution thread for each new thread domain, and hook those new
     /// domains into the larger Soup graph. The returned map contains entry points through which
     /// new updates should be sent to introduce them into the Soup.
-    pub fn commit(self) {
+    ///
+    /// Fails if a new domain does not boot within the controller's domain boot timeout. The
+    /// migration is then rolled back: the new domains that did boot are shut down again, and the
+    /// nodes it added are removed from the graph, so that the graph keeps serving exactly what it
+    /// did before. Column changes to existing bases are not undone.
+    ///
+    /// Also fails if a domain does not reply in time while it is being set up, for example
+    /// because a replay to fill new state takes too long. The new nodes are then also removed
+    /// from the existing domains they were added to, and existing nodes stop sending to them.
+    pub fn commit(self) -> Result<MigrationSummary, MigrationError> {
         info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
 
         let log = self.log;
         let start = self.start;
+        let boot_timeout = self.domain_boot_timeout;
         let mut mainline = self.mainline;
+        let ndomains = mainline.ndomains;
         let mut new: HashSet<_> = self.added.into_iter().collect();
 
         // Readers are nodes too.
         for (_parent, reader) in self.readers {
             new.insert(reader);
         }
+        new.extend(self.secondary_readers);
 
         // Shard the graph as desired
         let mut swapped0 = if let Some(shards) = mainline.sharding {
@@ -276,6 +446,7 @@ impl<'a> Migration<'a> {
                 &mut mainline.ingredients,
                 mainline.source,
                 &mut new,
+                &self.sharding,
                 shards,
             )
         } else {
@@ -468,21 +639,35 @@ impl<'a> Migration<'a> {
         // Randomize worker iteration order, so that we avoid putting the domains on machines in
         // the same sequence on each migration.
         thread_rng().shuffle(&mut placer_workers);
-        let mut placer: Box<Iterator<Item = (WorkerIdentifier, WorkerEndpoint)>> =
-            Box::new(placer_workers.into_iter().cycle());
 
         // Boot up new domains (they'll ignore all updates for now)
         debug!(log, "booting new domains");
+        let mut booted = Vec::new();
         for domain in changed_domains {
             if mainline.domains.contains_key(&domain) {
                 // this is not a new domain
                 continue;
             }
 
-            let nodes = uninformed_domain_nodes.remove(&domain).unwrap();
+            // a domain that did not exist before can only hold nodes from this migration
+            let nodes: Vec<_> = uninformed_domain_nodes
+                .remove(&domain)
+                .unwrap()
+                .into_iter()
+                .map(|(ni, new)| {
+                    debug_assert!(new);
+                    ni
+                }).collect();
+            let pinned = nodes
+                .iter()
+                .map(|&ni| &mainline.ingredients[ni])
+                .filter(|n| n.is_base())
+                .filter_map(|n| mainline.pinned_bases.get(n.name()))
+                .cloned()
+                .next();
             let d = DomainHandle::new(
                 domain,
-                mainline.ingredients[nodes[0].0].sharded_by().shards(),
+                mainline.ingredients[nodes[0]].sharded_by().shards(),
                 &log,
                 &mut mainline.ingredients,
                 &mainline.domain_config,
@@ -491,80 +676,237 @@ impl<'a> Migration<'a> {
                 &mainline.listen_addr,
                 &mainline.channel_coordinator,
                 &mainline.debug_channel,
-                &mut placer,
+                &mut *mainline.placement,
+                pinned,
+                &placer_workers,
                 &mut workers,
                 mainline.epoch,
+                mainline.domain_reply_timeout,
+                boot_timeout,
+                mainline.packet_coalescing,
             );
+            let d = match d {
+                Ok(d) => d,
+                Err(e) => {
+                    crit!(log, "abandoning migration: {}", e);
+                    mainline.handle_failed_boot(&e);
+                    roll_back(&log, mainline, &new, &booted, ndomains);
+                    return Err(e.into());
+                }
+            };
             mainline.domains.insert(domain, d);
+            booted.push(domain);
         }
+        let domains_spawned = booted.len();
 
         // Add any new nodes to existing domains (they'll also ignore all updates for now)
         debug!(log, "mutating existing domains");
         augmentation::inform(&log, &mut mainline, uninformed_domain_nodes);
 
-        // Tell all base nodes and base ingress children about newly added columns
-        for (ni, change) in self.columns {
-            let mut inform = if let ColumnChange::Add(..) = change {
-                // we need to inform all of the base's children too,
-                // so that they know to add columns to existing records when replaying
+        let replay_paths_created = match finish_setup(&log, mainline, self.columns, &new) {
+            Ok(created) => created,
+            Err(e) => {
+                crit!(log, "abandoning migration: {}", e);
+                roll_back_informed(&log, mainline, &new, &booted, ndomains);
+                return Err(e);
+            }
+        };
+
+        let duration_ms = start.elapsed().as_millis() as u64;
+        warn!(log, "migration completed"; "ms" => duration_ms);
+
+        Ok(MigrationSummary {
+            nodes_added: new.len(),
+            nodes_removed: 0,
+            domains_spawned,
+            replay_paths_created,
+            duration_ms,
+        })
+    }
+}
+
+/// Bring existing domains up to date with a migration once its new nodes have been added to them.
+///
+/// Tells bases about column changes, connects domains to each other, and sets up the new
+/// materializations. Returns the number of replay paths that were created.
+fn finish_setup(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    columns: Vec<(NodeIndex, ColumnChange)>,
+    new: &HashSet<NodeIndex>,
+) -> Result<usize, MigrationError> {
+    // Tell all base nodes and base ingress children about newly added columns
+    for (ni, change) in columns {
+        let mut inform = if let ColumnChange::Add(..) = change {
+            // we need to inform all of the base's children too,
+            // so that they know to add columns to existing records when replaying
+            mainline
+                .ingredients
+                .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+                .filter(|&eni| mainline.ingredients[eni].is_egress())
+                .flat_map(|eni| {
+                    // find ingresses under this egress
+                    mainline
+                        .ingredients
+                        .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
+                }).collect()
+        } else {
+            // ingress nodes don't need to know about deleted columns, because those are only
+            // relevant when new writes enter the graph.
+            Vec::new()
+        };
+        inform.push(ni);
+
+        for ni in inform {
+            let n = &mainline.ingredients[ni];
+            let m = match change.clone() {
+                ColumnChange::Add(field, default) => box payload::Packet::AddBaseColumn {
+                    node: *n.local_addr(),
+                    field: field,
+                    default: default,
+                },
+                ColumnChange::Drop(column) => box payload::Packet::DropBaseColumn {
+                    node: *n.local_addr(),
+                    column: column,
+                },
+            };
+
+            let di = n.domain();
+            let domain = mainline.domains.get_mut(&di).unwrap();
+
+            domain.send_to_healthy(m, &mainline.workers).unwrap();
+            domain.wait_for_ack().map_err(|e| MigrationError::Domain(di, e))?;
+        }
+    }
+
+    // Set up inter-domain connections
+    // NOTE: once we do this, we are making existing domains block on new domains!
+    info!(log, "bringing up inter-domain connections");
+    routing::connect(
+        log,
+        &mut mainline.ingredients,
+        &mut mainline.domains,
+        &mainline.workers,
+        new,
+    );
+
+    // And now, the last piece of the puzzle -- set up materializations
+    info!(log, "initializing new materializations");
+    let tags_before = mainline.materializations.tags_allocated();
+    mainline.materializations.commit(
+        &mainline.ingredients,
+        new,
+        &mut mainline.domains,
+        &mainline.workers,
+    )?;
+    Ok(mainline.materializations.tags_allocated() - tags_before)
+}
+
+/// Undo a migration that failed after existing domains were told about its new nodes.
+///
+/// The new nodes are removed from the existing domains they were added to, existing egress nodes
+/// stop sending to new ingress nodes, and the materializations and replay paths of the new nodes
+/// are forgotten. `roll_back` then undoes the rest.
+fn roll_back_informed(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    new: &HashSet<NodeIndex>,
+    booted: &[DomainIndex],
+    ndomains: usize,
+) {
+    let mut removals: HashMap<DomainIndex, Vec<LocalNodeIndex>> = HashMap::default();
+    let mut detach = Vec::new();
+    for &ni in new {
+        if ni == mainline.source {
+            continue;
+        }
+
+        let n = &mainline.ingredients[ni];
+        if n.is_ingress() {
+            detach.extend(
                 mainline
                     .ingredients
-                    .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
-                    .filter(|&eni| mainline.ingredients[eni].is_egress())
-                    .flat_map(|eni| {
-                        // find ingresses under this egress
-                        mainline
-                            .ingredients
-                            .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
-                    }).collect()
-            } else {
-                // ingress nodes don't need to know about deleted columns, because those are only
-                // relevant when new writes enter the graph.
-                Vec::new()
-            };
-            inform.push(ni);
-
-            for ni in inform {
-                let n = &mainline.ingredients[ni];
-                let m = match change.clone() {
-                    ColumnChange::Add(field, default) => box payload::Packet::AddBaseColumn {
-                        node: *n.local_addr(),
-                        field: field,
-                        default: default,
-                    },
-                    ColumnChange::Drop(column) => box payload::Packet::DropBaseColumn {
-                        node: *n.local_addr(),
-                        column: column,
-                    },
-                };
-
-                let domain = mainline.domains.get_mut(&n.domain()).unwrap();
-
-                domain.send_to_healthy(m, &mainline.workers).unwrap();
-                domain.wait_for_ack().unwrap();
-            }
+                    .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                    .filter(|e| !new.contains(e))
+                    .filter(|&e| mainline.ingredients[e].is_egress())
+                    .map(|e| (e, ni)),
+            );
+        }
+        if !booted.contains(&n.domain()) {
+            removals
+                .entry(n.domain())
+                .or_insert_with(Vec::new)
+                .push(*n.local_addr());
         }
+    }
 
-        // Set up inter-domain connections
-        // NOTE: once we do this, we are making existing domains block on new domains!
-        info!(log, "bringing up inter-domain connections");
-        routing::connect(
-            &log,
-            &mut mainline.ingredients,
-            &mut mainline.domains,
-            &mainline.workers,
-            &new,
-        );
+    // the domains may already be in trouble, which is likely why we are rolling back, so failing
+    // to reach them does not stop the rest of the rollback
+    for (egress, ingress) in detach {
+        let n = &mainline.ingredients[egress];
+        let m = box payload::Packet::RemoveEgressTx {
+            node: *n.local_addr(),
+            dst: ingress,
+        };
+        let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+        if let Err(e) = domain.send_to_healthy(m, &mainline.workers) {
+            warn!(log, "failed to detach egress node"; "node" => egress.index(), "err" => ?e);
+        }
+    }
+    for (di, nodes) in removals {
+        let m = box payload::Packet::RemoveNodes { nodes };
+        let domain = mainline.domains.get_mut(&di).unwrap();
+        if let Err(e) = domain.send_to_healthy(m, &mainline.workers) {
+            warn!(log, "failed to remove new nodes"; "domain" => di.index(), "err" => ?e);
+        }
+    }
 
-        // And now, the last piece of the puzzle -- set up materializations
-        info!(log, "initializing new materializations");
-        mainline.materializations.commit(
-            &mainline.ingredients,
-            &new,
-            &mut mainline.domains,
-            &mainline.workers,
-        );
+    let removed: Vec<_> = new.iter().cloned().collect();
+    mainline.materializations.remove_nodes(&removed);
+
+    roll_back(log, mainline, new, booted, ndomains);
+}
 
-        warn!(log, "migration completed"; "ms" => start.elapsed().as_millis() as u64);
+/// Undo the graph changes of a migration whose new domains did not all boot.
+///
+/// Existing domains have not been told about the migration at that point, so it suffices to shut
+/// down the new domains in `booted`, to remove the nodes in `new` from the graph again, and to go
+/// back to the `ndomains` domains there were before the migration.
+fn roll_back(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    new: &HashSet<NodeIndex>,
+    booted: &[DomainIndex],
+    ndomains: usize,
+) {
+    warn!(log, "rolling back migration";
+          "#nodes" => new.len(),
+          "#domains" => booted.len());
+
+    for di in booted {
+        mainline.domains.remove(di).unwrap().quit();
+    }
+
+    // forget the local addresses handed out to new nodes, so that later migrations hand them out
+    // again
+    for nodes in mainline.remap.values_mut() {
+        nodes.retain(|ni, _| !new.contains(ni));
     }
+    mainline.remap.retain(|_, nodes| !nodes.is_empty());
+
+    // nodes are only ever added to the graph, so the new ones are the last ones, and removing
+    // them from the back keeps the indices of all other nodes stable
+    let mut added: Vec<_> = new
+        .iter()
+        .cloned()
+        .filter(|&ni| ni != mainline.source)
+        .collect();
+    added.sort();
+    for ni in added.into_iter().rev() {
+        assert_eq!(ni.index(), mainline.ingredients.node_count() - 1);
+        mainline.ingredients.remove_node(ni);
+    }
+
+    // domain indices are handed out in order, so the new domains can be given out again too
+    mainline.ndomains = ndomains;
 }
@@ -6,11 +6,21 @@ use petgraph::graph::NodeIndex;
 use slog::Logger;
 use std::collections::{HashMap, HashSet};
 
+/// How a recipe asked for a new node to be sharded, instead of by the controller-wide default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Directive {
+    /// The column to shard a base table by. Other nodes choose their sharding column themselves.
+    pub column: Option<usize>,
+    /// The number of shards to use.
+    pub shards: Option<usize>,
+}
+
 pub fn shard(
     log: &Logger,
     graph: &mut Graph,
     source: NodeIndex,
     new: &mut HashSet<NodeIndex>,
+    directives: &HashMap<NodeIndex, Directive>,
     sharding_factor: usize,
 ) -> HashMap<(NodeIndex, NodeIndex), NodeIndex> {
     let mut topo_list = Vec::with_capacity(new.len());
@@ -37,6 +47,27 @@ pub fn shard(
             .map(|ni| (ni, graph[ni].sharded_by()))
             .collect();
 
+        // a node uses the number of shards that a directive asks for. nodes without one keep the
+        // number of shards of their inputs if those agree, so that queries over a base that was
+        // sharded differently from the default follow its sharding.
+        let directive = directives.get(&node).cloned().unwrap_or_default();
+        let sharding_factor = directive.shards.unwrap_or_else(|| {
+            let mut shards = input_shardings.values().filter_map(Sharding::shards);
+            match shards.next() {
+                Some(n) if shards.all(|m| m == n) => n,
+                _ => sharding_factor,
+            }
+        });
+
+        if let Some(col) = directive.column.filter(|_| graph[node].is_base()) {
+            info!(log, "sharding base node as directed"; "node" => ?node, "column" => col);
+            graph
+                .node_weight_mut(node)
+                .unwrap()
+                .shard_by(Sharding::ByColumn(col, sharding_factor));
+            continue;
+        }
+
         let mut need_sharding = if graph[node].is_internal() || graph[node].is_base() {
             // suggest_indexes is okay because `node` *must* be new, and therefore will return
             // global node indices.
@@ -59,7 +90,8 @@ pub fn shard(
                     }
                 }).unwrap_or(Sharding::ForcedNone);
             if s.is_none() {
-                info!(log, "de-sharding prior to stream-only reader"; "node" => ?node);
+                // stream-only readers have no key, and compound keys can't be sharded by
+                info!(log, "de-sharding prior to unkeyed or compound-key reader"; "node" => ?node);
             } else {
                 info!(log, "sharding reader"; "node" => ?node);
                 graph[node]
@@ -240,7 +272,9 @@ pub fn shard(
         // the safe thing to do here is to simply force all our ancestors to be unsharded. however,
         // if a single output column resolves to the lookup column of *every* ancestor, we know
         // that sharding by that column *should* be safe, so we mark the output as sharded by that
-        // key. we then make sure all our inputs are sharded by that key too.
+        // key. we then make sure all our inputs are sharded by that key too. this is what lets
+        // us join inputs that are sharded by different keys: the join column resolves to both
+        // inputs, and any input not already sharded by it is shuffled onto it.
         debug!(log, "testing for sharding opportunities"; "node" => ?node);
         'outer: for col in 0..graph[node].fields().len() {
             let srcs = if graph[node].is_base() {
@@ -374,7 +408,7 @@ pub fn shard(
 
             // and that its children must be sharded somehow (otherwise what is the sharder doing?)
             let col = graph[n].with_sharder(|s| s.sharded_by()).unwrap();
-            let by = Sharding::ByColumn(col, sharding_factor);
+            let by = Sharding::ByColumn(col, sharder_shards(graph, n, sharding_factor));
 
             // we can only push sharding above newly created nodes that are not already sharded.
             if !new.contains(&p) || graph[p].sharded_by() != Sharding::None {
@@ -470,7 +504,8 @@ pub fn shard(
                     // TODO: we *could* insert a de-shard here
                     continue 'sharders;
                 }
-                let csharding = Sharding::ByColumn(col.unwrap(), sharding_factor);
+                let csharding =
+                    Sharding::ByColumn(col.unwrap(), sharder_shards(graph, c, sharding_factor));
 
                 if csharding == by {
                     // sharding by the same key, which is now unnecessary.
@@ -573,6 +608,15 @@ pub fn shard(
     swaps
 }
 
+/// The number of shards that the sharder `n` splits its output into, which is that of its children.
+fn sharder_shards(graph: &Graph, n: NodeIndex, default: usize) -> usize {
+    graph
+        .neighbors_directed(n, petgraph::EdgeDirection::Outgoing)
+        .filter_map(|c| graph[c].sharded_by().shards())
+        .next()
+        .unwrap_or(default)
+}
+
 /// Modify the graph such that the path between `src` and `dst` shuffles the input such that the
 /// records received by `dst` are sharded by sharding `to`.
 fn reshard(
@@ -716,11 +760,9 @@ pub fn validate(
             if in_node.is_sharder() {
                 // ancestor is a sharder, so its output sharding must match ours
                 in_node.with_sharder(|s| {
-                    let in_sharding = remap(
-                        n,
-                        in_ni,
-                        Sharding::ByColumn(s.sharded_by(), sharding_factor),
-                    );
+                    let shards = n.sharded_by().shards().unwrap_or(sharding_factor);
+                    let in_sharding =
+                        remap(n, in_ni, Sharding::ByColumn(s.sharded_by(), shards));
                     if in_sharding != n.sharded_by() {
                         crit!(
                             log,
@@ -1,5 +1,6 @@
 use nom_sql::{
-    ArithmeticBase, ArithmeticExpression, ColumnConstraint, ColumnSpecification, OrderType,
+    ArithmeticBase, ArithmeticExpression, ColumnConstraint, ColumnSpecification, Literal,
+    OrderType,
 };
 use std::collections::HashMap;
 
@@ -14,7 +15,10 @@ use mir::node::{GroupedNodeType, MirNode, MirNodeType};
 use mir::query::{MirQuery, QueryFlowParts};
 use mir::{Column, FlowNode, MirNodeRef};
 
-pub fn mir_query_to_flow_parts(mir_query: &mut MirQuery, mig: &mut Migration) -> QueryFlowParts {
+pub fn mir_query_to_flow_parts(
+    mir_query: &mut MirQuery,
+    mig: &mut Migration,
+) -> Result<QueryFlowParts, String> {
     use std::collections::VecDeque;
 
     let mut new_nodes = Vec::new();
@@ -31,7 +35,7 @@ pub fn mir_query_to_flow_parts(mir_query: &mut MirQuery, mig: &mut Migration) ->
         let n = node_queue.pop_front().unwrap();
         assert_eq!(in_edge_counts[&n.borrow().versioned_name()], 0);
 
-        let flow_node = mir_node_to_flow_parts(&mut n.borrow_mut(), mig);
+        let flow_node = mir_node_to_flow_parts(&mut n.borrow_mut(), mig)?;
         match flow_node {
             FlowNode::New(na) => new_nodes.push(na),
             FlowNode::Existing(na) => reused_nodes.push(na),
@@ -61,15 +65,18 @@ pub fn mir_query_to_flow_parts(mir_query: &mut MirQuery, mig: &mut Migration) ->
         .expect("Leaf must have FlowNode by now")
         .address();
 
-    QueryFlowParts {
+    Ok(QueryFlowParts {
         name: mir_query.name.clone(),
         new_nodes: new_nodes,
         reused_nodes: reused_nodes,
         query_leaf: leaf_na,
-    }
+    })
 }
 
-pub fn mir_node_to_flow_parts(mir_node: &mut MirNode, mig: &mut Migration) -> FlowNode {
+pub fn mir_node_to_flow_parts(
+    mir_node: &mut MirNode,
+    mig: &mut Migration,
+) -> Result<FlowNode, String> {
     let name = mir_node.name.clone();
     match mir_node.flow_node {
         None => {
@@ -177,7 +184,7 @@ pub fn mir_node_to_flow_parts(mir_node: &mut MirNode, mig: &mut Migration) -> Fl
                 MirNodeType::Leaf { ref keys, .. } => {
                     assert_eq!(mir_node.ancestors.len(), 1);
                     let parent = mir_node.ancestors[0].clone();
-                    materialize_leaf_node(&parent, name, keys, mig);
+                    materialize_leaf_node(&parent, name, keys, mig)?;
                     // TODO(malte): below is yucky, but required to satisfy the type system:
                     // each match arm must return a `FlowNode`, so we use the parent's one
                     // here.
@@ -307,9 +314,9 @@ pub fn mir_node_to_flow_parts(mir_node: &mut MirNode, mig: &mut Migration) -> Fl
                 FlowNode::New(na) => Some(FlowNode::Existing(na)),
                 ref n @ FlowNode::Existing(..) => Some(n.clone()),
             };
-            flow_node
+            Ok(flow_node)
         }
-        Some(ref flow_node) => flow_node.clone(),
+        Some(ref flow_node) => Ok(flow_node.clone()),
     }
 }
 
@@ -330,7 +337,9 @@ pub(crate) fn adapt_base_node(
             .constraints
             .iter()
             .filter_map(|c| match *c {
-                ColumnConstraint::DefaultValue(ref dv) => Some(dv.into()),
+                ColumnConstraint::DefaultValue(ref dv) => {
+                    Some(DataType::column_default(dv, &a.sql_type))
+                }
                 _ => None,
             }).next()
         {
@@ -391,13 +400,27 @@ pub(crate) fn make_base_node(
         .map(|&(ref cs, _)| {
             for c in &cs.constraints {
                 match *c {
-                    ColumnConstraint::DefaultValue(ref dv) => return dv.into(),
+                    ColumnConstraint::DefaultValue(ref dv) => {
+                        return DataType::column_default(dv, &cs.sql_type)
+                    }
                     _ => (),
                 }
             }
             return DataType::None;
         }).collect::<Vec<DataType>>();
 
+    // columns that default to the current time need a fresh value for every insert
+    let timestamp_defaults = column_specs
+        .iter()
+        .enumerate()
+        .filter(|&(_, &(ref cs, _))| {
+            cs.constraints.iter().any(|c| match *c {
+                ColumnConstraint::DefaultValue(Literal::CurrentTimestamp) => true,
+                _ => false,
+            })
+        }).map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+
     let base = if pkey_columns.len() > 0 {
         let pkey_column_ids = pkey_columns
             .iter()
@@ -408,9 +431,11 @@ pub(crate) fn make_base_node(
                     .position(|&(ref cs, _)| Column::from(&cs.column) == *pkc)
                     .unwrap()
             }).collect();
-        node::special::Base::new(default_values).with_key(pkey_column_ids)
-    } else {
         node::special::Base::new(default_values)
+            .with_key(pkey_column_ids)
+            .with_timestamp_defaults(timestamp_defaults)
+    } else {
+        node::special::Base::new(default_values).with_timestamp_defaults(timestamp_defaults)
     };
 
     FlowNode::New(mig.add_base(name, column_names.as_slice(), base))
@@ -812,8 +837,6 @@ pub(crate) fn make_topk_node(
 
     let cmp_rows = match *order {
         Some(ref o) => {
-            assert_eq!(offset, 0); // Non-zero offset not supported
-
             let columns: Vec<_> = o
                 .iter()
                 .map(|&(ref c, ref order_type)| {
@@ -831,11 +854,23 @@ pub(crate) fn make_topk_node(
         None => Vec::new(),
     };
 
-    // make the new operator and record its metadata
+    // with an offset, the TopK keeps all the rows up to the end of the window, and an Offset
+    // operator below it skips the rows before the window.
+    let topk = ops::topk::TopK::new(
+        parent_na,
+        cmp_rows.clone(),
+        group_by_indx.clone(),
+        k + offset,
+    );
+    if offset == 0 {
+        let na = mig.add_ingredient(String::from(name), column_names.as_slice(), topk);
+        return FlowNode::New(na);
+    }
+    let topk_na = mig.add_ingredient(format!("{}_topk", name), column_names.as_slice(), topk);
     let na = mig.add_ingredient(
         String::from(name),
         column_names.as_slice(),
-        ops::topk::TopK::new(parent_na, cmp_rows, group_by_indx, k),
+        ops::offset::Offset::new(topk_na, cmp_rows, group_by_indx, offset),
     );
     FlowNode::New(na)
 }
@@ -845,7 +880,7 @@ pub(crate) fn materialize_leaf_node(
     name: String,
     key_cols: &Vec<Column>,
     mig: &mut Migration,
-) {
+) -> Result<(), String> {
     let na = parent.borrow().flow_node_addr().unwrap();
 
     // we must add a new reader for this query. This also requires adding an identity node (at
@@ -860,9 +895,9 @@ pub(crate) fn materialize_leaf_node(
             .iter()
             .map(|c| parent.borrow().column_id_for_column(c))
             .collect();
-        mig.maintain(name, na, &key_cols[..]);
+        mig.maintain(name, na, &key_cols[..])
     } else {
         // if no key specified, default to the first column
-        mig.maintain(name, na, &[0]);
+        mig.maintain(name, na, &[0])
     }
 }
@@ -1,6 +1,8 @@
-use api::{ControllerDescriptor, Input};
+use api::debug::stats::ReplayProgress;
+use api::builders::ViewBuilder;
+use api::{ControllerDescriptor, Input, ViewError};
 use async_bincode::{AsyncBincodeReader, AsyncBincodeWriter, AsyncDestination, SyncDestination};
-use basics::DomainIndex;
+use basics::{DataType, DomainIndex, Tag};
 use bincode;
 use bufstream::BufStream;
 use channel::{
@@ -10,7 +12,7 @@ use channel::{
 };
 use consensus::{Authority, Epoch, STATE_KEY};
 use crate::controller::domain_handle::DomainHandle;
-use crate::controller::inner::{ControllerInner, WorkerStatus};
+use crate::controller::inner::{ControllerInner, ErrorReply, Registration, WorkerStatus};
 use crate::controller::recipe::Recipe;
 use crate::controller::sql::reuse::ReuseConfigType;
 use crate::coordination::{CoordinationMessage, CoordinationPayload};
@@ -52,6 +54,7 @@ use std::boxed::FnBox;
 pub mod domain_handle;
 pub mod keys;
 pub mod migrate;
+pub mod placement;
 
 pub(crate) mod recipe;
 pub(crate) mod security;
@@ -67,13 +70,29 @@ pub use api::builders::*;
 pub use api::prelude::*;
 pub use crate::controller::builder::ControllerBuilder;
 pub use crate::controller::handle::LocalControllerHandle;
-pub use crate::controller::migrate::Migration;
+pub use crate::controller::migrate::{Migration, MigrationError, MigrationSummary};
+pub use crate::controller::placement::{PlacementPolicy, RoundRobin, ShardLocality};
 
 type WorkerIdentifier = SocketAddr;
 type WorkerEndpoint = Arc<Mutex<TcpSender<CoordinationMessage>>>;
 
 type ReplicaIndex = (DomainIndex, usize);
 type ChannelCoordinator = channel::ChannelCoordinator<ReplicaIndex>;
+/// In-memory inboxes of the domains running on this worker.
+type LocalInboxes = Arc<Mutex<FnvHashMap<ReplicaIndex, LocalSender>>>;
+type LocalSender = futures::sync::mpsc::Sender<Box<Packet>>;
+
+/// How many packets each domain on a worker may have waiting in its in-memory inbox.
+///
+/// Like a full socket buffer, a full inbox makes senders keep further packets in their own outbox
+/// until the receiving domain catches up.
+///
+/// The bound only has to be large enough that a sender rarely finds the inbox full while the
+/// receiving domain is keeping up, and small enough that a stalled domain cannot pin an unbounded
+/// amount of memory. With replays split into batches of `replay_batch_size` (256 by default)
+/// records, 1024 packets hold at most a few hundred thousand buffered records per domain. Note
+/// that each sender may additionally park one packet beyond this capacity.
+const LOCAL_INBOX_CAPACITY: usize = 1024;
 
 fn block_on<F, T>(f: F) -> T
 where
@@ -93,8 +112,14 @@ pub(crate) struct ControllerConfig {
     pub persistence: PersistenceParameters,
     pub heartbeat_every: Duration,
     pub healthcheck_every: Duration,
+    pub missed_heartbeats_before_failure: u32,
+    pub missed_heartbeats_with_other_failures: u32,
+    pub domain_reply_timeout: Option<Duration>,
+    pub domain_boot_timeout: Option<Duration>,
+    pub packet_coalescing: Option<usize>,
     pub quorum: usize,
     pub reuse: ReuseConfigType,
+    pub local_delivery: bool,
 }
 impl Default for ControllerConfig {
     fn default() -> Self {
@@ -107,12 +132,24 @@ impl Default for ControllerConfig {
             domain_config: DomainConfig {
                 concurrent_replays: 512,
                 replay_batch_timeout: time::Duration::new(0, 10_000),
+                replay_batch_size: 256,
+                shard_hasher: Default::default(),
+                eviction_policy: Default::default(),
+                partial_state_limit: None,
+                #[cfg(feature = "replay_ordering")]
+                packet_order: None,
             },
             persistence: Default::default(),
             heartbeat_every: Duration::from_secs(1),
             healthcheck_every: Duration::from_secs(10),
+            missed_heartbeats_before_failure: 4,
+            missed_heartbeats_with_other_failures: 3,
+            domain_reply_timeout: None,
+            domain_boot_timeout: None,
+            packet_coalescing: None,
             quorum: 1,
             reuse: ReuseConfigType::Finkelstein,
+            local_delivery: true,
         }
     }
 }
@@ -124,16 +161,34 @@ pub(crate) struct ControllerState {
 
     pub recipe_version: usize,
     pub recipes: Vec<String>,
+    /// Secondary indexes added to recipe views, which the recipes themselves do not describe.
+    ///
+    /// Missing from state written before such indexes were recorded.
+    #[serde(default)]
+    pub view_indexes: Vec<ViewIndex>,
+}
+
+/// A secondary index on a recipe view, which can be read as a view of its own.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ViewIndex {
+    /// The name the index is read under.
+    pub name: String,
+    /// The recipe view that is indexed.
+    pub view: String,
+    /// The columns of `view` that the index is keyed by.
+    pub key: Vec<String>,
 }
 
 enum Event {
     InternalMessage(CoordinationMessage),
+    /// Another attempt at admitting the worker that sent the given `Register` message.
+    RetryRegister(CoordinationMessage, u32),
     ExternalRequest(
         Method,
         String,
         Option<String>,
         Vec<u8>,
-        futures::sync::oneshot::Sender<Result<Result<String, String>, StatusCode>>,
+        futures::sync::oneshot::Sender<Result<Result<String, String>, ErrorReply>>,
     ),
     LeaderChange(ControllerState, ControllerDescriptor),
     WonLeaderElection(ControllerState),
@@ -143,7 +198,7 @@ enum Event {
     #[cfg(test)]
     ManualMigration {
         f: Box<FnBox(&mut Migration) + Send + 'static>,
-        done: futures::sync::oneshot::Sender<()>,
+        done: futures::sync::oneshot::Sender<Result<(), String>>,
     },
 }
 
@@ -152,6 +207,9 @@ impl fmt::Debug for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Event::InternalMessage(ref cm) => write!(f, "Internal({:?})", cm),
+            Event::RetryRegister(ref cm, attempt) => {
+                write!(f, "RetryRegister({:?}, {})", cm, attempt)
+            }
             Event::ExternalRequest(ref m, ref path, ..) => write!(f, "Request({} {})", m, path),
             Event::LeaderChange(..) => write!(f, "LeaderChange(..)"),
             Event::WonLeaderElection(..) => write!(f, "Won(..)"),
@@ -172,6 +230,10 @@ fn start_instance<A: Authority + 'static>(
     config: ControllerConfig,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<Duration>,
+    read_threads: Option<usize>,
+    migration_queue_timeout: Option<Duration>,
+    placement: Box<PlacementPolicy>,
+    migration_log: Option<Box<io::Write + Send>>,
     log: slog::Logger,
 ) -> Result<LocalControllerHandle<A>, failure::Error> {
     let mut pool = tokio::executor::thread_pool::Builder::new();
@@ -183,9 +245,13 @@ fn start_instance<A: Authority + 'static>(
     rt.threadpool_builder(pool);
     let mut rt = rt.build().unwrap();
 
+    // the io pool serves view lookups. every read connection is handled by a single thread of
+    // the pool, but all threads share the state of each reader.
     let mut pool = tokio_io_pool::Builder::default();
     pool.name_prefix("tokio-io-pool-");
-    if cfg!(debug_assertions) {
+    if let Some(n) = read_threads {
+        pool.pool_size(n);
+    } else if cfg!(debug_assertions) {
         pool.pool_size(2);
     }
     let iopool = pool.build().unwrap();
@@ -204,12 +270,19 @@ fn start_instance<A: Authority + 'static>(
     let xport = tokio::net::TcpListener::bind(&SocketAddr::new(listen_addr, 0))?;
     let xaddr = xport.local_addr()?;
     let ext_log = log.clone();
+    let replays = ReplayStatus::default();
+    let migrations = MigrationQueue::default();
     rt.spawn(
-        listen_external(tx.clone(), valve.wrap(xport.incoming()), authority.clone()).map_err(
-            move |e| {
-                warn!(ext_log, "external request failed: {:?}", e);
-            },
-        ),
+        listen_external(
+            tx.clone(),
+            valve.wrap(xport.incoming()),
+            authority.clone(),
+            migrations.clone(),
+            migration_queue_timeout,
+            replays.clone(),
+        ).map_err(move |e| {
+            warn!(ext_log, "external request failed: {:?}", e);
+        }),
     );
 
     // shared df state
@@ -253,9 +326,11 @@ fn start_instance<A: Authority + 'static>(
                         CoordinationPayload::RemoveDomain => fw(e, false),
                         CoordinationPayload::AssignDomain(..) => fw(e, false),
                         CoordinationPayload::DomainBooted(..) => fw(e, false),
+                        CoordinationPayload::Decommission => fw(e, false),
                         CoordinationPayload::Register { .. } => fw(e, true),
                         CoordinationPayload::Heartbeat => fw(e, true),
                     },
+                    Event::RetryRegister(..) => fw(e, true),
                     Event::ExternalRequest(..) => fw(e, true),
                     #[cfg(test)]
                     Event::ManualMigration { .. } => fw(e, true),
@@ -312,6 +387,27 @@ fn start_instance<A: Authority + 'static>(
                                     }
                                 }
                             }
+                            CoordinationPayload::Decommission => {
+                                let decommissioned = match worker_state {
+                                    InstanceState::Active { epoch, .. } => epoch == msg.epoch,
+                                    InstanceState::Pining => false,
+                                };
+                                if decommissioned {
+                                    // the controller has already moved our domains elsewhere and
+                                    // forgotten about us, so stop the worker (and its heartbeats)
+                                    // until the next leader change.
+                                    warn!(log, "worker decommissioned by controller");
+                                    if let InstanceState::Active {
+                                        add_domain,
+                                        trigger,
+                                        ..
+                                    } = worker_state.take()
+                                    {
+                                        drop(add_domain);
+                                        trigger.cancel();
+                                    }
+                                }
+                            }
                             _ => unreachable!(),
                         },
                         Event::LeaderChange(state, descriptor) => {
@@ -394,8 +490,17 @@ fn start_instance<A: Authority + 'static>(
         let authority2 = authority.clone();
 
         let mut campaign = campaign;
+        let mut placement = Some(placement);
+        let mut migration_log = Some(migration_log);
+        #[cfg(test)]
+        let migrations = migrations.clone();
+        // retried registrations come back on their own channel rather than on `tx`, since the
+        // loop holds a sender for it that would otherwise keep the events from ever ending. the
+        // valve ends it instead, when the instance shuts down.
+        let (retry_tx, retry_rx) = futures::sync::mpsc::unbounded();
         rt.spawn(
             ctrl_rx
+                .select(valve.wrap(retry_rx))
                 .map_err(|_| unreachable!())
                 .fold(None, move |mut controller: Option<ControllerInner>, e| {
                     match e {
@@ -403,16 +508,9 @@ fn start_instance<A: Authority + 'static>(
                             CoordinationPayload::Deregister => {
                                 unimplemented!();
                             }
-                            CoordinationPayload::Register {
-                                ref addr,
-                                ref read_listen_addr,
-                                ..
-                            } => {
+                            CoordinationPayload::Register { .. } => {
                                 if let Some(ref mut ctrl) = controller {
-                                    block_on(|| {
-                                        ctrl.handle_register(&msg, addr, read_listen_addr.clone())
-                                            .unwrap()
-                                    });
+                                    register_worker(ctrl, msg, 1, &retry_tx, &log);
                                 }
                             }
                             CoordinationPayload::Heartbeat => {
@@ -422,29 +520,42 @@ fn start_instance<A: Authority + 'static>(
                             }
                             _ => unreachable!(),
                         },
+                        Event::RetryRegister(msg, attempt) => {
+                            if let Some(ref mut ctrl) = controller {
+                                register_worker(ctrl, msg, attempt, &retry_tx, &log);
+                            }
+                        }
                         Event::ExternalRequest(method, path, query, body, reply_tx) => {
                             if let Some(ref mut ctrl) = controller {
                                 let authority = &authority;
                                 let reply = block_on(|| {
                                     ctrl.external_request(method, path, query, body, &authority)
                                 });
+                                // the client may rely on domains having seen what we sent them
+                                ctrl.flush_domains();
 
                                 if let Err(_) = reply_tx.send(reply) {
                                     warn!(log, "client hung up");
                                 }
                             } else {
-                                if let Err(_) = reply_tx.send(Err(StatusCode::NOT_FOUND)) {
+                                if let Err(_) = reply_tx.send(Err(StatusCode::NOT_FOUND.into())) {
                                     warn!(log, "client hung up for 404");
                                 }
                             }
                         }
                         #[cfg(test)]
                         Event::ManualMigration { f, done } => {
+                            // counts towards the migration queue like external migrations,
+                            // but runs right away, since it is already on the event loop
+                            let ticket = migrations.join().0;
                             if let Some(ref mut ctrl) = controller {
                                 if !ctrl.workers.is_empty() {
                                     block_on(|| {
-                                        ctrl.migrate(move |m| f.call_box((m,)));
-                                        done.send(()).unwrap();
+                                        let r = ctrl
+                                            .migrate(move |m| f.call_box((m,)))
+                                            .map_err(|e| e.to_string());
+                                        drop(ticket);
+                                        done.send(r).unwrap();
                                     });
                                 }
                             } else {
@@ -468,6 +579,9 @@ fn start_instance<A: Authority + 'static>(
                                 listen_addr,
                                 log.clone(),
                                 state.clone(),
+                                placement.take().unwrap(),
+                                replays.clone(),
+                                migration_log.take().unwrap(),
                             ));
                         }
                         Event::CampaignError(e) => {
@@ -475,6 +589,11 @@ fn start_instance<A: Authority + 'static>(
                         }
                         e => unreachable!("{:?} is not a controller event", e),
                     }
+
+                    // packets sent while handling the event may have been held back
+                    if let Some(ref mut ctrl) = controller {
+                        ctrl.flush_domains();
+                    }
                     Ok(controller)
                 }).and_then(move |controller| {
                     // shutting down
@@ -494,6 +613,47 @@ fn start_instance<A: Authority + 'static>(
     ))
 }
 
+/// Makes the `attempt`th attempt at admitting the worker that sent the `Register` message `msg`.
+///
+/// If the worker cannot be reached yet, the next attempt is sent back to the controller's event
+/// loop on `tx` as an `Event::RetryRegister` once the backoff has passed, so that the loop keeps
+/// handling other events in the meantime.
+fn register_worker(
+    ctrl: &mut ControllerInner,
+    msg: CoordinationMessage,
+    attempt: u32,
+    tx: &UnboundedSender<Event>,
+    log: &slog::Logger,
+) {
+    let (addr, read_listen_addr) = match msg.payload {
+        CoordinationPayload::Register {
+            addr,
+            read_listen_addr,
+            ..
+        } => (addr, read_listen_addr),
+        _ => unreachable!(),
+    };
+
+    match block_on(|| ctrl.handle_register(&msg, &addr, read_listen_addr, attempt)) {
+        Ok(Registration::Admitted) => {}
+        Ok(Registration::RetryAfter(backoff)) => {
+            let tx = tx.clone();
+            tokio::spawn(
+                tokio::timer::Delay::new(time::Instant::now() + backoff)
+                    .map_err(|e| panic!("{:?}", e))
+                    .and_then(move |_| {
+                        // the controller may have shut down in the meantime
+                        let _ = tx.unbounded_send(Event::RetryRegister(msg, attempt + 1));
+                        Ok(())
+                    }),
+            );
+        }
+        Err(e) => {
+            error!(log, "failed to admit registering worker {:?}: {}", msg.source, e);
+        }
+    }
+}
+
 /*
     epoch: state.epoch,
     heartbeat_every: state.config.heartbeat_every,
@@ -636,6 +796,13 @@ fn listen_df(
             }),
     );
 
+    // packets between domains on this worker skip the network stack entirely if we may
+    let local_inboxes: Option<LocalInboxes> = if state.config.local_delivery {
+        Some(Default::default())
+    } else {
+        None
+    };
+
     let state_sizes = Arc::new(Mutex::new(HashMap::new()));
     if let Some(evict_every) = evict_every {
         let log = log.clone();
@@ -672,13 +839,28 @@ fn listen_df(
                         addr,
                         &valve,
                         state_size.clone(),
-                    );
+                    )?;
+
+                    // register the domain's inbox before other domains learn where it is, so that
+                    // those on this worker never fall back to sending to it over the network
+                    let (local_tx, local_rx) = futures::sync::mpsc::channel(LOCAL_INBOX_CAPACITY);
+                    if let Some(ref inboxes) = local_inboxes {
+                        block_on(|| inboxes.lock().unwrap().insert((idx, shard), local_tx));
+                    }
 
                     // need to register the domain with the local channel coordinator
                     coord.insert_addr((idx, shard), addr, false);
                     block_on(|| state_sizes.lock().unwrap().insert((idx, shard), state_size));
 
-                    tokio::spawn(Replica::new(&valve, d, on, log.clone(), coord.clone()));
+                    tokio::spawn(Replica::new(
+                        &valve,
+                        d,
+                        on,
+                        log.clone(),
+                        coord.clone(),
+                        local_rx,
+                        local_inboxes.clone(),
+                    ));
 
                     trace!(
                         log,
@@ -701,7 +883,18 @@ fn listen_df(
                                 io::Error::new(io::ErrorKind::Other, "controller went away")
                             }),
                     ),
-                    Err(e) => Either::B(future::err(e)),
+                    Err(e) => {
+                        // most likely, the controller gave up waiting for the domain to boot and
+                        // abandoned the migration. that does not affect any other domains.
+                        warn!(
+                            log,
+                            "failed to boot domain {}.{}: {}",
+                            idx.index(),
+                            shard,
+                            e
+                        );
+                        Either::B(future::ok(ctrl_tx))
+                    }
                 }
             }).map_err(|e| panic!("{:?}", e))
             .map(|_| ()),
@@ -739,11 +932,99 @@ fn listen_internal(
         })
 }
 
-struct ExternalServer<A: Authority>(UnboundedSender<Event>, Arc<A>);
+/// External endpoints that perform a migration, and thus count towards the migration queue.
+const MIGRATION_PATHS: &[&str] = &["/extend_recipe", "/install_recipe", "/create_universe"];
+
+/// The migrations that are running or waiting to run, in the order in which they run.
+///
+/// This is tracked outside of the controller, since the controller cannot answer requests while a
+/// migration is running.
+#[derive(Clone, Default)]
+struct MigrationQueue(Arc<Mutex<MigrationQueueState>>);
+
+#[derive(Default)]
+struct MigrationQueueState {
+    next: u64,
+    running: Option<u64>,
+    waiting: VecDeque<(u64, futures::sync::oneshot::Sender<()>)>,
+}
+
+impl MigrationQueue {
+    /// The number of migrations that are running or waiting to run.
+    fn depth(&self) -> usize {
+        let state = self.0.lock().unwrap();
+        state.running.iter().count() + state.waiting.len()
+    }
+
+    /// Join the back of the queue.
+    ///
+    /// If other migrations are ahead, the returned receiver fires once it is the ticket's turn to
+    /// run. The ticket leaves the queue when it is dropped, whether or not its turn has come.
+    fn join(&self) -> (MigrationTicket, Option<futures::sync::oneshot::Receiver<()>>) {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next;
+        state.next += 1;
+        let turn = if state.running.is_none() && state.waiting.is_empty() {
+            state.running = Some(id);
+            None
+        } else {
+            let (tx, rx) = futures::sync::oneshot::channel();
+            state.waiting.push_back((id, tx));
+            Some(rx)
+        };
+        let ticket = MigrationTicket {
+            queue: self.clone(),
+            id,
+        };
+        (ticket, turn)
+    }
+}
+
+/// A place in the migration queue, which is given up when the ticket is dropped.
+struct MigrationTicket {
+    queue: MigrationQueue,
+    id: u64,
+}
+
+impl Drop for MigrationTicket {
+    fn drop(&mut self) {
+        let mut state = self.queue.0.lock().unwrap();
+        if state.running != Some(self.id) {
+            // gave up before it was our turn
+            state.waiting.retain(|&(id, _)| id != self.id);
+            return;
+        }
+
+        // it is the next migration's turn
+        state.running = None;
+        if let Some((id, turn)) = state.waiting.pop_front() {
+            state.running = Some(id);
+            // if the waiting caller has hung up, its ticket hands the turn on when it is dropped
+            let _ = turn.send(());
+        }
+    }
+}
+
+/// Progress of the full replays performed by migrations, keyed by replay path.
+///
+/// This is shared with the external request handler, since the controller cannot answer requests
+/// while the migrations that perform those replays are running.
+pub(crate) type ReplayStatus = Arc<Mutex<HashMap<Tag, ReplayProgress>>>;
+
+struct ExternalServer<A: Authority>(
+    UnboundedSender<Event>,
+    Arc<A>,
+    MigrationQueue,
+    Option<Duration>,
+    ReplayStatus,
+);
 fn listen_external<A: Authority + 'static>(
     event_tx: UnboundedSender<Event>,
     on: Valved<tokio::net::Incoming>,
     authority: Arc<A>,
+    migrations: MigrationQueue,
+    migration_queue_timeout: Option<Duration>,
+    replays: ReplayStatus,
 ) -> impl Future<Item = (), Error = hyper::Error> + Send {
     use hyper::{
         service::{NewService, Service},
@@ -752,7 +1033,13 @@ fn listen_external<A: Authority + 'static>(
     impl<A: Authority> Clone for ExternalServer<A> {
         // Needed due to #26925
         fn clone(&self) -> Self {
-            ExternalServer(self.0.clone(), self.1.clone())
+            ExternalServer(
+                self.0.clone(),
+                self.1.clone(),
+                self.2.clone(),
+                self.3,
+                self.4.clone(),
+            )
         }
     }
     impl<A: Authority> Service for ExternalServer<A> {
@@ -799,16 +1086,87 @@ fn listen_external<A: Authority + 'static>(
             let method = req.method().clone();
             let path = req.uri().path().to_string();
             let query = req.uri().query().map(|s| s.to_owned());
+
+            if path == "/migration_queue" {
+                res.header(CONTENT_TYPE, "application/json");
+                let depth = self.2.depth();
+                let res = res.body(hyper::Body::from(serde_json::to_string(&depth).unwrap()));
+                return Box::new(futures::future::ok(res.unwrap()));
+            }
+            if path == "/replay_status" {
+                res.header(CONTENT_TYPE, "application/json");
+                let status: HashMap<_, _> = self
+                    .4
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(tag, &progress)| (tag.id(), progress))
+                    .collect();
+                let res = res.body(hyper::Body::from(serde_json::to_string(&status).unwrap()));
+                return Box::new(futures::future::ok(res.unwrap()));
+            }
+            let (ticket, turn) = if MIGRATION_PATHS.contains(&&*path) {
+                if self.2.depth() > 0 && query.as_ref().map(|q| q == "nowait").unwrap_or(false) {
+                    // caller asked not to wait behind other migrations
+                    res.status(StatusCode::CONFLICT);
+                    return Box::new(futures::future::ok(res.body(hyper::Body::empty()).unwrap()));
+                }
+                let (ticket, turn) = self.2.join();
+                (Some(ticket), turn)
+            } else {
+                (None, None)
+            };
+
+            // a migration is only handed to the controller once the ones ahead of it are done, so
+            // that it can still give up if that takes too long.
+            let timeout = self.3;
+            let turn = match turn {
+                None => Either::A(futures::future::ok(true)),
+                Some(turn) => {
+                    let turn = turn.then(|_| Ok(true));
+                    Either::B(match timeout {
+                        None => Either::A(turn),
+                        Some(timeout) => Either::B(
+                            turn.select(
+                                tokio::timer::Delay::new(time::Instant::now() + timeout)
+                                    .then(|_| Ok(false)),
+                            ).map(|(ours, _)| ours)
+                            .map_err(|(e, _): ((), _)| e),
+                        ),
+                    })
+                }
+            };
+
+            if method == Method::GET && path == "/metrics" {
+                res.header(CONTENT_TYPE, "text/plain; version=0.0.4");
+            }
+
+            let is_view_query = method == Method::GET && path.starts_with("/query/");
             let event_tx = self.0.clone();
-            Box::new(req.into_body().concat2().and_then(move |body| {
+            let turn = turn.map_err(|()| unreachable!());
+            Box::new(req.into_body().concat2().join(turn).and_then(move |(body, ours)| {
+                if !ours {
+                    drop(ticket);
+                    res.status(StatusCode::GATEWAY_TIMEOUT);
+                    let res = res.body(hyper::Body::empty()).unwrap();
+                    return Either::A(futures::future::ok(res));
+                }
+
                 let body: Vec<u8> = body.iter().cloned().collect();
                 let (tx, rx) = futures::sync::oneshot::channel();
-                event_tx
+                let reply = event_tx
                     .clone()
                     .send(Event::ExternalRequest(method, path, query, body, tx))
                     .map_err(|_| futures::Canceled)
                     .then(move |_| rx)
-                    .then(move |reply| match reply {
+                    .then(move |reply| {
+                        // the migration (if any) has now left the queue
+                        drop(ticket);
+                        reply
+                    }).and_then(move |reply| match reply {
+                        Ok(Ok(ref prepared)) if is_view_query => Either::A(lookup_view(prepared)),
+                        reply => Either::B(futures::future::ok(reply)),
+                    }).then(move |reply| match reply {
                         Ok(reply) => {
                             let res = match reply {
                                 Ok(Ok(reply)) => res.body(hyper::Body::from(reply)),
@@ -816,9 +1174,12 @@ fn listen_external<A: Authority + 'static>(
                                     res.status(StatusCode::INTERNAL_SERVER_ERROR);
                                     res.body(hyper::Body::from(reply))
                                 }
-                                Err(status_code) => {
-                                    res.status(status_code);
-                                    res.body(hyper::Body::empty())
+                                Err(ErrorReply { status, body }) => {
+                                    res.status(status);
+                                    match body {
+                                        Some(body) => res.body(hyper::Body::from(body)),
+                                        None => res.body(hyper::Body::empty()),
+                                    }
                                 }
                             };
                             Ok(res.unwrap())
@@ -827,7 +1188,8 @@ fn listen_external<A: Authority + 'static>(
                             res.status(StatusCode::NOT_FOUND);
                             Ok(res.body(hyper::Body::empty()).unwrap())
                         }
-                    })
+                    });
+                Either::B(reply)
             }))
         }
     }
@@ -843,10 +1205,40 @@ fn listen_external<A: Authority + 'static>(
         }
     }
 
-    let service = ExternalServer(event_tx, authority);
+    let service = ExternalServer(
+        event_tx,
+        authority,
+        migrations,
+        migration_queue_timeout,
+        replays,
+    );
     server::Server::builder(on).serve(service)
 }
 
+/// Perform the lookup that the controller prepared for a `GET /query/{view}` request.
+///
+/// The lookup blocks until any partial misses are filled, so it runs as blocking work on the
+/// runtime's thread pool rather than holding up the controller.
+fn lookup_view(
+    prepared: &str,
+) -> impl Future<Item = Result<Result<String, String>, ErrorReply>, Error = futures::Canceled> {
+    let (vb, key): (ViewBuilder, Vec<DataType>) = serde_json::from_str(prepared).unwrap();
+    let mut lookup = Some(move || -> Result<Result<String, String>, ErrorReply> {
+        let mut view = vb
+            .build_exclusive()
+            .map_err(|_| ErrorReply::from(StatusCode::SERVICE_UNAVAILABLE))?;
+        let rows = view.lookup(&key, true).map_err(|e| match e {
+            ViewError::WrongKeyColumnCount(..) => ErrorReply::from(StatusCode::BAD_REQUEST),
+            _ => ErrorReply::from(StatusCode::SERVICE_UNAVAILABLE),
+        })?;
+        Ok(Ok(serde_json::to_string(&rows).unwrap()))
+    });
+    poll_fn(move || blocking(|| lookup.take().unwrap()())).or_else(|_| {
+        // the thread pool is shutting down
+        Ok::<_, futures::Canceled>(Err(ErrorReply::from(StatusCode::SERVICE_UNAVAILABLE)))
+    })
+}
+
 // NOTE: tokio::net::TcpStream doesn't expose underlying stream :(
 fn set_nonblocking(s: &tokio::net::TcpStream, on: bool) {
     use std::net::TcpStream;
@@ -909,6 +1301,7 @@ fn instance_campaign<A: Authority + 'static>(
                         epoch,
                         recipe_version: 0,
                         recipes: vec![],
+                        view_indexes: vec![],
                     }),
                     Some(ref state) if state.epoch > epoch => Err(()),
                     Some(mut state) => {
@@ -1024,6 +1417,12 @@ struct Replica {
         ),
     >,
 
+    /// Packets handed to us in memory by domains on the same worker.
+    local_inputs: futures::sync::mpsc::Receiver<Box<Packet>>,
+    /// Inboxes of the domains on the same worker, if local delivery is enabled.
+    local_inboxes: Option<LocalInboxes>,
+    local_outputs: FnvHashMap<ReplicaIndex, LocalSender>,
+
     outbox: FnvHashMap<ReplicaIndex, VecDeque<Box<Packet>>>,
     timeout: Option<tokio::timer::Delay>,
     sendback: Sendback,
@@ -1036,6 +1435,8 @@ impl Replica {
         on: tokio::net::TcpListener,
         log: slog::Logger,
         cc: Arc<ChannelCoordinator>,
+        local_inputs: futures::sync::mpsc::Receiver<Box<Packet>>,
+        local_inboxes: Option<LocalInboxes>,
     ) -> Self {
         let id = domain.id();
         let id = format!("{}.{}", id.0.index(), id.1);
@@ -1047,6 +1448,9 @@ impl Replica {
             log: log.new(o!{"id" => id}),
             inputs: Default::default(),
             outputs: Default::default(),
+            local_inputs,
+            local_inboxes,
+            local_outputs: Default::default(),
             outbox: Default::default(),
             sendback: Default::default(),
             timeout: None,
@@ -1059,19 +1463,18 @@ impl Replica {
 
         // first, queue up any additional writes we have to do
         let mut err = Vec::new();
-        self.sendback.back.retain(|&streami, n| {
-            use std::ops::SubAssign;
-
+        self.sendback.back.retain(|&streami, acks| {
             let stream = &mut inputs[streami];
 
-            let end = *n;
-            for i in 0..end {
-                match stream.start_send(true) {
+            let mut first = true;
+            while let Some(&seq) = acks.front() {
+                match stream.start_send(seq) {
                     Ok(AsyncSink::Ready) => {
-                        if i == 0 {
+                        if first {
                             pending.insert(streami);
+                            first = false;
                         }
-                        n.sub_assign(1);
+                        acks.pop_front();
                     }
                     Ok(AsyncSink::NotReady(_)) => {
                         break;
@@ -1079,11 +1482,12 @@ impl Replica {
                     Err(e) => {
                         // start_send shouldn't generally error
                         err.push(e.into());
+                        break;
                     }
                 }
             }
 
-            *n > 0
+            !acks.is_empty()
         });
 
         if !err.is_empty() {
@@ -1129,6 +1533,8 @@ impl Replica {
     fn try_flush(&mut self) -> Result<(), failure::Error> {
         let cc = &self.coord;
         let outputs = &mut self.outputs;
+        let local_outputs = &mut self.local_outputs;
+        let local_inboxes = &self.local_inboxes;
 
         // just like in try_ack:
         // first, queue up any additional writes we have to do
@@ -1138,6 +1544,33 @@ impl Replica {
                 continue;
             }
 
+            // domains on this worker are handed their packets as they are, without serializing
+            // them. we decide how to reach a domain once, just like we only connect to it once.
+            if !outputs.contains_key(&ri) && !local_outputs.contains_key(&ri) {
+                if let Some(ref inboxes) = *local_inboxes {
+                    if let Some(tx) = block_on(|| inboxes.lock().unwrap().get(&ri).cloned()) {
+                        local_outputs.insert(ri, tx);
+                    }
+                }
+            }
+            if let Some(tx) = local_outputs.get_mut(&ri) {
+                while let Some(m) = ms.pop_front() {
+                    match tx.start_send(m) {
+                        Ok(AsyncSink::Ready) => {}
+                        Ok(AsyncSink::NotReady(m)) => {
+                            // the inbox is full, and we'll be woken up once it has room again
+                            ms.push_front(m);
+                            break;
+                        }
+                        Err(_) => {
+                            err.push(format_err!("domain {}.{} went away", ri.0.index(), ri.1));
+                            break;
+                        }
+                    }
+                }
+                continue;
+            }
+
             let &mut (ref mut tx, ref mut pending, is_local) =
                 outputs.entry(ri).or_insert_with(|| {
                     let mut dest = None;
@@ -1168,7 +1601,7 @@ impl Replica {
                         break;
                     }
                     Err(e) => {
-                        err.push(e);
+                        err.push(e.into());
                         break;
                     }
                 }
@@ -1176,7 +1609,7 @@ impl Replica {
         }
 
         if !err.is_empty() {
-            return Err(err.swap_remove(0).into());
+            return Err(err.swap_remove(0));
         }
 
         // then, try to do any sends that are still pending
@@ -1263,17 +1696,25 @@ impl Replica {
     }
 }
 
+impl Drop for Replica {
+    fn drop(&mut self) {
+        // a domain that takes over our index later may not be on this worker
+        if let Some(ref inboxes) = self.local_inboxes {
+            inboxes.lock().unwrap().remove(&self.domain.id());
+        }
+    }
+}
+
 #[derive(Default)]
 struct Sendback {
-    // map from inputi to number of (empty) ACKs
-    back: FnvHashMap<usize, usize>,
+    // map from inputi to the write sequence numbers to ACK with
+    back: FnvHashMap<usize, VecDeque<u64>>,
     pending: FnvHashSet<usize>,
 }
 
 impl Executor for Sendback {
-    fn send_back(&mut self, id: SourceChannelIdentifier, _: ()) {
-        use std::ops::AddAssign;
-        self.back.entry(id.token).or_insert(0).add_assign(1);
+    fn send_back(&mut self, id: SourceChannelIdentifier, seq: u64) {
+        self.back.entry(id.token).or_default().push_back(seq);
     }
 }
 
@@ -1300,6 +1741,18 @@ impl Future for Replica {
             // then, see if our timer has expired
             self.try_timeout().context("check timeout")?;
 
+            // then, process what domains on this worker have handed us directly
+            while let Ok(Async::Ready(Some(packet))) = self.local_inputs.poll() {
+                let d = &mut self.domain;
+                let sb = &mut self.sendback;
+                let ob = &mut self.outbox;
+                if let ProcessResult::StopPolling =
+                    block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
+                {
+                    return Ok(Async::Ready(()));
+                }
+            }
+
             // and now, finally, we see if there's new input for us
             loop {
                 match self.inputs.poll() {
@@ -1430,4 +1883,88 @@ mod tests {
         let mut c = ControllerBuilder::default().build_local().unwrap();
         assert!(c.install_recipe(r_txt).is_ok());
     }
+
+    // Controller state written before view indexes were recorded can still be read.
+    #[test]
+    fn state_without_view_indexes_loads() {
+        let mut old = serde_json::Map::new();
+        let config = serde_json::to_value(ControllerConfig::default()).unwrap();
+        old.insert("config".to_owned(), config);
+        old.insert("epoch".to_owned(), 1.into());
+        old.insert("recipe_version".to_owned(), 0.into());
+        old.insert("recipes".to_owned(), serde_json::Value::Array(vec![]));
+
+        let old = serde_json::Value::Object(old);
+        let state: ControllerState = serde_json::from_value(old).unwrap();
+        assert!(state.view_indexes.is_empty());
+    }
+
+    // Replay path tags are never handed out twice, not even by later migrations.
+    #[test]
+    fn replay_path_tags_are_not_reused() {
+        let mut c = ControllerBuilder::default().build_local().unwrap();
+        c.install_recipe(
+            "CREATE TABLE a (x int, y int);
+             QUERY q1: SELECT x, y FROM a WHERE x = ?;",
+        ).unwrap();
+        let first: HashMap<_, _> = c.migrate(|mig| {
+            mig.mainline
+                .materializations
+                .replay_paths()
+                .into_iter()
+                .collect()
+        });
+        assert!(!first.is_empty());
+
+        c.extend_recipe("QUERY q2: SELECT x, y FROM a WHERE y = ?;").unwrap();
+        let second: HashMap<_, _> = c.migrate(|mig| {
+            mig.mainline
+                .materializations
+                .replay_paths()
+                .into_iter()
+                .collect()
+        });
+        assert!(second.len() > first.len());
+        for (tag, path) in &first {
+            assert_eq!(&second[tag], path);
+        }
+
+        // recording a path under a tag that is already taken is an error
+        let (&tag, path) = first.iter().next().unwrap();
+        let path = path.clone();
+        let reused = c.migrate(move |mig| {
+            mig.mainline.materializations.record_replay_path(tag, path)
+        });
+        match reused {
+            Err(MigrationError::TagReused(t)) => assert_eq!(t, tag),
+            r => panic!("expected tag reuse error, got {:?}", r.map(|_| ())),
+        }
+
+        // dropping q2 drops its replay paths, but their tags are not handed out again
+        c.install_recipe(
+            "CREATE TABLE a (x int, y int);
+             QUERY q1: SELECT x, y FROM a WHERE x = ?;",
+        ).unwrap();
+        let third: HashMap<_, _> = c.migrate(|mig| {
+            mig.mainline
+                .materializations
+                .replay_paths()
+                .into_iter()
+                .collect()
+        });
+        assert_eq!(third, first);
+
+        c.extend_recipe("QUERY q3: SELECT x, y FROM a WHERE y = ?;").unwrap();
+        let fourth: HashMap<_, _> = c.migrate(|mig| {
+            mig.mainline
+                .materializations
+                .replay_paths()
+                .into_iter()
+                .collect()
+        });
+        assert!(fourth.len() > first.len());
+        for tag in fourth.keys() {
+            assert!(first.contains_key(tag) || !second.contains_key(tag));
+        }
+    }
 }
@@ -0,0 +1,124 @@
+use basics::DomainIndex;
+
+use crate::controller::WorkerIdentifier;
+
+/// Decides which worker each shard of a newly created domain is placed on.
+///
+/// The controller consults its policy once for every shard of every domain it boots, passing in
+/// the healthy workers that the shard may be placed on. Since placement decisions are only made
+/// when a domain is first created, existing domains are never moved by a policy.
+pub trait PlacementPolicy: Send {
+    /// Called once before the shards of `domain` are placed. `shards` is `None` if the domain is
+    /// not sharded.
+    fn begin_domain(&mut self, _domain: DomainIndex, _shards: Option<usize>) {}
+
+    /// Choose the worker that shard `shard` of `domain` is placed on. The returned worker must be
+    /// one of `workers`, which is never empty.
+    fn place(
+        &mut self,
+        domain: DomainIndex,
+        shard: usize,
+        workers: &[WorkerIdentifier],
+    ) -> WorkerIdentifier;
+}
+
+/// Places domain shards on workers in turn.
+///
+/// This is the default policy. The controller presents workers in a random order on each
+/// migration, so that domains do not end up on machines in the same sequence every time.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl PlacementPolicy for RoundRobin {
+    fn place(
+        &mut self,
+        _: DomainIndex,
+        _: usize,
+        workers: &[WorkerIdentifier],
+    ) -> WorkerIdentifier {
+        let worker = workers[self.next % workers.len()];
+        self.next += 1;
+        worker
+    }
+}
+
+/// Places shard `i` of every sharded domain on the same worker, so that the shards of domains
+/// that are sharded by the same key are co-located, and records that flow between them do not
+/// need to cross workers. Unsharded domains are placed round-robin.
+///
+/// This relies on domains that are sharded by the same key also having the same number of
+/// shards, which the controller already assumes elsewhere.
+#[derive(Debug, Default)]
+pub struct ShardLocality {
+    sharded: bool,
+    unsharded: RoundRobin,
+}
+
+impl PlacementPolicy for ShardLocality {
+    fn begin_domain(&mut self, _: DomainIndex, shards: Option<usize>) {
+        self.sharded = shards.is_some();
+    }
+
+    fn place(
+        &mut self,
+        domain: DomainIndex,
+        shard: usize,
+        workers: &[WorkerIdentifier],
+    ) -> WorkerIdentifier {
+        if !self.sharded {
+            return self.unsharded.place(domain, shard, workers);
+        }
+
+        // the controller shuffles the workers, so we need a stable order to be consistent across
+        // migrations
+        let mut workers = workers.to_vec();
+        workers.sort_by_key(|w| (w.ip(), w.port()));
+        workers[shard % workers.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workers() -> Vec<WorkerIdentifier> {
+        vec![
+            "127.0.0.1:3000".parse().unwrap(),
+            "127.0.0.1:1000".parse().unwrap(),
+            "127.0.0.1:2000".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn round_robin_cycles() {
+        let workers = workers();
+        let mut p = RoundRobin::default();
+        let placed: Vec<_> = (0..4)
+            .map(|i| p.place(DomainIndex::from(i), 0, &workers))
+            .collect();
+        assert_eq!(placed, vec![workers[0], workers[1], workers[2], workers[0]]);
+    }
+
+    #[test]
+    fn shard_locality_colocates_shards() {
+        let mut workers = workers();
+        let mut p = ShardLocality::default();
+
+        p.begin_domain(DomainIndex::from(0), Some(2));
+        let first: Vec<_> = (0..2)
+            .map(|i| p.place(DomainIndex::from(0), i, &workers))
+            .collect();
+
+        // the order in which workers are presented must not matter
+        workers.reverse();
+        p.begin_domain(DomainIndex::from(1), Some(2));
+        let second: Vec<_> = (0..2)
+            .map(|i| p.place(DomainIndex::from(1), i, &workers))
+            .collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[1]);
+    }
+}
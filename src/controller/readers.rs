@@ -9,13 +9,17 @@ use std::{mem, time};
 use tokio;
 use tokio::prelude::*;
 
-use api::{ReadQuery, ReadReply};
+use api::{ReadQuery, ReadReply, WriteId};
 
 /// If a blocking reader finds itself waiting this long for a backfill to complete, it will
 /// re-issue the replay request. To avoid the system falling over if replays are slow for a little
 /// while, waiting readers will use exponential backoff on this delay if they continue to miss.
 const RETRY_TIMEOUT_US: u64 = 1_000;
 
+/// How long a read waits for the view to reflect the writes it was asked to reflect, before it
+/// gives up on them.
+const AWAIT_WRITES_TIMEOUT_SECS: u64 = 10;
+
 thread_local! {
     static READERS: RefCell<HashMap<
         (NodeIndex, usize),
@@ -29,111 +33,260 @@ fn dup(rs: &[Vec<DataType>]) -> Vec<Vec<DataType>> {
         .collect()
 }
 
+/// Make sure this thread has a handle to the reader for `target`.
+///
+/// Returns false if this worker has no such reader. That is the case if the reader's node has been
+/// removed, for example because its query was recovered onto new nodes, but a client still holds
+/// a handle for it.
+fn cache_reader(target: (NodeIndex, usize), s: &Readers) -> bool {
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        if readers_cache.contains_key(&target) {
+            return true;
+        }
+
+        let readers = s.lock().unwrap();
+        match readers.get(&target) {
+            Some(reader) => {
+                readers_cache.insert(target, reader.clone());
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Check whether a reader that claims not to be ready has in fact been removed.
+///
+/// A removed reader is also dropped from this thread's cache of reader handles.
+fn is_removed(target: (NodeIndex, usize), s: &Readers) -> bool {
+    if s.lock().unwrap().contains_key(&target) {
+        return false;
+    }
+    READERS.with(|readers_cache| readers_cache.borrow_mut().remove(&target));
+    true
+}
+
 pub(crate) fn handle_message(
     m: ReadQuery,
     s: &mut Readers,
 ) -> impl Future<Item = ReadReply, Error = bincode::Error> + Send {
-    match m {
+    let target = match m {
+        ReadQuery::Normal { target, .. }
+        | ReadQuery::AtLeast { target, .. }
+        | ReadQuery::Count { target, .. }
+        | ReadQuery::Size { target } => target,
+    };
+    if !cache_reader(target, s) {
+        return Either::A(future::ok(ReadReply::Stale));
+    }
+
+    let truth = s.clone();
+    let reply = match m {
         ReadQuery::Normal {
             target,
-            mut keys,
+            keys,
+            block,
+        } => Either::A(lookup(target, keys, block, s, dup).map(ReadReply::Normal)),
+        ReadQuery::AtLeast {
+            target,
+            keys,
+            block,
+            writes,
+        } => {
+            let retry = time::Duration::from_micros(10);
+            let now = time::Instant::now();
+            let wait = AwaitWrites {
+                target,
+                writes,
+                truth: s.clone(),
+                retry: tokio::timer::Interval::new(now + retry, retry),
+                deadline: now + time::Duration::from_secs(AWAIT_WRITES_TIMEOUT_SECS),
+            };
+            let mut s = s.clone();
+            let read = wait.and_then(move |seen| {
+                if seen {
+                    Either::A(lookup(target, keys, block, &mut s, dup).map(ReadReply::Normal))
+                } else {
+                    Either::B(future::ok(ReadReply::WritesNotSeen))
+                }
+            });
+            Either::B(Either::A(read))
+        }
+        ReadQuery::Count {
+            target,
+            keys,
             block,
         } => {
-            let immediate = READERS.with(|readers_cache| {
+            // only the number of rows for each key goes back over the wire, so don't copy them
+            let counts = lookup(target, keys, block, s, |rs| rs.len()).map(ReadReply::Count);
+            Either::B(Either::B(Either::A(counts)))
+        }
+        ReadQuery::Size { target } => {
+            let size = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
                 let reader = readers_cache.entry(target.clone()).or_insert_with(|| {
                     let readers = s.lock().unwrap();
                     readers.get(&target).unwrap().clone()
                 });
 
-                let mut ret = Vec::with_capacity(keys.len());
-                ret.resize(keys.len(), Vec::new());
+                reader.len()
+            });
 
-                // first do non-blocking reads for all keys to see if we can return immediately
-                let found = keys
-                    .iter_mut()
-                    .map(|key| {
-                        let rs = reader.try_find_and(key, dup).map(|r| r.0);
-                        (key, rs)
-                    }).enumerate();
+            Either::B(Either::B(Either::B(future::ok(ReadReply::Size(size)))))
+        }
+    };
 
-                let mut ready = true;
-                for (i, (key, v)) in found {
-                    match v {
-                        Ok(Some(rs)) => {
-                            // immediate hit!
-                            ret[i] = rs;
-                            *key = vec![];
-                        }
-                        Err(()) => {
-                            // map not yet ready
-                            ready = false;
-                            *key = vec![];
-                            break;
-                        }
-                        Ok(None) => {
-                            // triggered partial replay
-                        }
-                    }
-                }
+    // a reader whose node has been removed is never going to become ready
+    Either::B(reply.map(move |reply| match reply {
+        ReadReply::Normal(Err(())) | ReadReply::Count(Err(())) if is_removed(target, &truth) => {
+            ReadReply::Stale
+        }
+        reply => reply,
+    }))
+}
 
-                if !ready {
-                    return Ok(ReadReply::Normal(Err(())));
-                }
+/// Look up the given keys in the reader for `target`.
+///
+/// Resolves to `Err(())` if the reader is not yet ready.
+/// Look up `keys` in the reader for `target`, and use `then` to produce a result from the rows of
+/// each key.
+fn lookup<T>(
+    target: (NodeIndex, usize),
+    mut keys: Vec<Vec<DataType>>,
+    block: bool,
+    s: &mut Readers,
+    then: fn(&[Vec<DataType>]) -> T,
+) -> impl Future<Item = Result<Vec<T>, ()>, Error = bincode::Error> + Send
+where
+    T: Default + Clone + Send + 'static,
+{
+    let immediate = READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        let reader = readers_cache.entry(target.clone()).or_insert_with(|| {
+            let readers = s.lock().unwrap();
+            readers.get(&target).unwrap().clone()
+        });
 
-                Err((keys, ret))
-            });
+        let mut ret = Vec::with_capacity(keys.len());
+        ret.resize(keys.len(), T::default());
 
-            match immediate {
-                Ok(reply) => Either::A(Either::A(future::ok(reply))),
-                Err((keys, ret)) => {
-                    if !block {
-                        Either::A(Either::A(future::ok(ReadReply::Normal(Ok(ret)))))
-                    } else {
-                        let trigger = time::Duration::from_micros(RETRY_TIMEOUT_US);
-                        let retry = time::Duration::from_micros(10);
-                        let now = time::Instant::now();
-                        Either::A(Either::B(BlockingRead {
-                            target,
-                            keys,
-                            read: ret,
-                            truth: s.clone(),
-                            retry: tokio::timer::Interval::new(now + retry, retry),
-                            trigger_timeout: trigger,
-                            next_trigger: now,
-                        }))
-                    }
+        // first do non-blocking reads for all keys to see if we can return immediately
+        let found = keys
+            .iter_mut()
+            .map(|key| {
+                let rs = reader.try_find_and(key, then).map(|r| r.0);
+                (key, rs)
+            }).enumerate();
+
+        let mut ready = true;
+        for (i, (key, v)) in found {
+            match v {
+                Ok(Some(rs)) => {
+                    // immediate hit!
+                    ret[i] = rs;
+                    *key = vec![];
+                }
+                Err(()) => {
+                    // map not yet ready
+                    ready = false;
+                    *key = vec![];
+                    break;
+                }
+                Ok(None) => {
+                    // triggered partial replay
                 }
             }
         }
-        ReadQuery::Size { target } => {
-            let size = READERS.with(|readers_cache| {
+
+        if !ready {
+            return Ok(Err(()));
+        }
+
+        Err((keys, ret))
+    });
+
+    match immediate {
+        Ok(reply) => Either::A(future::ok(reply)),
+        Err((keys, ret)) => {
+            if !block {
+                Either::A(future::ok(Ok(ret)))
+            } else {
+                let trigger = time::Duration::from_micros(RETRY_TIMEOUT_US);
+                let retry = time::Duration::from_micros(10);
+                let now = time::Instant::now();
+                Either::B(BlockingRead {
+                    target,
+                    keys,
+                    read: ret,
+                    truth: s.clone(),
+                    retry: tokio::timer::Interval::new(now + retry, retry),
+                    trigger_timeout: trigger,
+                    next_trigger: now,
+                    then,
+                })
+            }
+        }
+    }
+}
+
+/// Resolves to true once the reader for `target` reflects all of `writes`, or to false if it
+/// still does not by `deadline`.
+struct AwaitWrites {
+    target: (NodeIndex, usize),
+    writes: Vec<WriteId>,
+    truth: Readers,
+    retry: tokio::timer::Interval,
+    deadline: time::Instant,
+}
+
+impl Future for AwaitWrites {
+    type Item = bool;
+    type Error = bincode::Error;
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            let seen = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
-                let reader = readers_cache.entry(target.clone()).or_insert_with(|| {
+                let s = &self.truth;
+                let target = &self.target;
+                let reader = readers_cache.entry(self.target.clone()).or_insert_with(|| {
                     let readers = s.lock().unwrap();
-                    readers.get(&target).unwrap().clone()
+                    readers.get(target).unwrap().clone()
                 });
 
-                reader.len()
+                self.writes.iter().all(|w| reader.has_seen(w))
             });
 
-            Either::B(future::ok(ReadReply::Size(size)))
+            if seen {
+                return Ok(Async::Ready(true));
+            }
+            if time::Instant::now() >= self.deadline {
+                return Ok(Async::Ready(false));
+            }
+
+            match self.retry.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => unreachable!("interval stopped yielding"),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => unreachable!("{:?}", e),
+            }
         }
     }
 }
 
-struct BlockingRead {
-    read: Vec<Vec<Vec<DataType>>>,
+struct BlockingRead<T> {
+    read: Vec<T>,
     target: (NodeIndex, usize),
     keys: Vec<Vec<DataType>>,
     truth: Readers,
     retry: tokio::timer::Interval,
     trigger_timeout: time::Duration,
     next_trigger: time::Instant,
+    then: fn(&[Vec<DataType>]) -> T,
 }
 
-impl Future for BlockingRead {
-    type Item = ReadReply;
+impl<T> Future for BlockingRead<T> {
+    type Item = Result<Vec<T>, ()>;
     type Error = bincode::Error;
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
         READERS.with(move |readers_cache| {
@@ -155,7 +308,7 @@ impl Future for BlockingRead {
                     // note that this *does* mean we'll trigger replay multiple times for things
                     // that miss and aren't replayed in time, which is a little sad. but at the
                     // same time, that replay trigger will just be ignored by the target domain.
-                    match reader.try_find_and(key, dup).map(|r| r.0) {
+                    match reader.try_find_and(key, self.then).map(|r| r.0) {
                         Ok(Some(rs)) => {
                             self.read[i] = rs;
                             key.clear();
@@ -190,10 +343,7 @@ impl Future for BlockingRead {
                     }
                 }
             } else {
-                Ok(Async::Ready(ReadReply::Normal(Ok(mem::replace(
-                    &mut self.read,
-                    Vec::new(),
-                )))))
+                Ok(Async::Ready(Ok(mem::replace(&mut self.read, Vec::new()))))
             }
         })
     }
@@ -1,4 +1,4 @@
-use api::ActivationResult;
+use api::{ActivationResult, StatementResult};
 use basics::NodeIndex;
 use crate::controller::security::SecurityConfig;
 use crate::controller::sql::reuse::ReuseConfigType;
@@ -8,17 +8,45 @@ use dataflow::ops::trigger::Trigger;
 use dataflow::ops::trigger::TriggerEvent;
 use dataflow::prelude::DataType;
 use nom_sql::parser as sql_parser;
-use nom_sql::SqlQuery;
+use nom_sql::{
+    ColumnConstraint, ConditionBase, ConditionExpression, JoinRightSide, SelectStatement, SqlQuery,
+    TableKey,
+};
 
-use nom::{self, is_alphanumeric, multispace};
+use nom::{self, digit, is_alphanumeric, multispace};
 use nom_sql::CreateTableStatement;
 use slog;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str;
 use std::vec::Vec;
 
 type QueryID = u64;
 
+/// A recipe statement asking for a base table or query to be sharded differently from the
+/// controller-wide default, such as `SHARD Vote BY article INTO 4;`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ShardingDirective {
+    /// The column to shard by. Only base tables take one.
+    column: Option<String>,
+    /// The number of shards.
+    shards: Option<usize>,
+}
+
+impl ShardingDirective {
+    /// Returns the recipe statement that gives `relation` this directive.
+    fn statement(&self, relation: &str) -> String {
+        let mut s = format!("SHARD {}", relation);
+        if let Some(ref column) = self.column {
+            s.push_str(&format!(" BY {}", column));
+        }
+        if let Some(shards) = self.shards {
+            s.push_str(&format!(" INTO {}", shards));
+        }
+        s.push(';');
+        s
+    }
+}
+
 /// Represents a Soup recipe.
 #[derive(Clone, Debug)]
 pub struct Recipe {
@@ -28,8 +56,13 @@ pub struct Recipe {
     expression_order: Vec<QueryID>,
     /// Named read/write expression aliases, mapping to queries in `expressions`.
     aliases: HashMap<String, QueryID>,
+    /// The statements of the text this recipe was created or last extended from, in order, as
+    /// the name they gave and the expression they stand for.
+    statements: Vec<(Option<String>, QueryID)>,
     /// Security configuration
     security_config: Option<SecurityConfig>,
+    /// Sharding directives, keyed by the base table or query they apply to.
+    sharding: HashMap<String, ShardingDirective>,
 
     /// Recipe revision.
     version: usize,
@@ -50,6 +83,7 @@ impl PartialEq for Recipe {
         self.expressions == other.expressions
             && self.expression_order == other.expression_order
             && self.aliases == other.aliases
+            && self.sharding == other.sharding
             && self.version == other.version
             && self.prior == other.prior
     }
@@ -64,6 +98,108 @@ fn hash_query(q: &SqlQuery) -> QueryID {
     h.finish()
 }
 
+/// Returns the names of the columns that make up the primary key of the base table `ctq`.
+fn primary_key(ctq: &CreateTableStatement) -> Vec<&str> {
+    let mut key: Vec<&str> = ctq
+        .fields
+        .iter()
+        .filter(|cs| cs.constraints.contains(&ColumnConstraint::PrimaryKey))
+        .map(|cs| cs.column.name.as_str())
+        .collect();
+    for k in ctq.keys.iter().flat_map(|ks| ks.iter()) {
+        if let TableKey::PrimaryKey(ref cols) = *k {
+            key.extend(cols.iter().map(|c| c.name.as_str()));
+        }
+    }
+    key
+}
+
+/// Describes the first column that has a different type in `new` than it has in `old`, if any.
+fn column_type_change(old: &CreateTableStatement, new: &CreateTableStatement) -> Option<String> {
+    new.fields
+        .iter()
+        .filter_map(|n| {
+            old.fields
+                .iter()
+                .find(|o| o.column.name == n.column.name && o.sql_type != n.sql_type)
+                .map(|o| {
+                    format!(
+                        "column {} changes type from {} to {}",
+                        n.column.name, o.sql_type, n.sql_type
+                    )
+                })
+        }).next()
+}
+
+/// Describes how two different definitions of the same base table differ.
+fn schema_difference(a: &CreateTableStatement, b: &CreateTableStatement) -> String {
+    if let Some(change) = column_type_change(a, b) {
+        return change;
+    }
+
+    let columns = |ctq: &CreateTableStatement| {
+        ctq.fields
+            .iter()
+            .map(|f| f.column.name.clone())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let (a_columns, b_columns) = (columns(a), columns(b));
+    if a_columns != b_columns {
+        format!("columns ({}) differ from ({})", a_columns, b_columns)
+    } else {
+        String::from("keys or column constraints differ")
+    }
+}
+
+/// Collects the names of the relations that `q` reads from, including those read by its
+/// subqueries.
+fn referred_relations(q: &SqlQuery) -> Vec<String> {
+    fn join_relations(jrs: &JoinRightSide, rels: &mut Vec<String>) {
+        match *jrs {
+            JoinRightSide::Table(ref t) => rels.push(t.name.clone()),
+            JoinRightSide::Tables(ref ts) => rels.extend(ts.iter().map(|t| t.name.clone())),
+            JoinRightSide::NestedSelect(ref sq, _) => select_relations(sq, rels),
+            JoinRightSide::NestedJoin(ref jc) => join_relations(&jc.right, rels),
+        }
+    }
+
+    fn condition_relations(ce: &ConditionExpression, rels: &mut Vec<String>) {
+        match *ce {
+            ConditionExpression::ComparisonOp(ref ct) | ConditionExpression::LogicalOp(ref ct) => {
+                condition_relations(&ct.left, rels);
+                condition_relations(&ct.right, rels);
+            }
+            ConditionExpression::NegationOp(ref inner)
+            | ConditionExpression::Bracketed(ref inner) => condition_relations(inner, rels),
+            ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+                select_relations(sq, rels)
+            }
+            ConditionExpression::Base(_) => (),
+        }
+    }
+
+    fn select_relations(sq: &SelectStatement, rels: &mut Vec<String>) {
+        rels.extend(sq.tables.iter().map(|t| t.name.clone()));
+        for jc in &sq.join {
+            join_relations(&jc.right, rels);
+        }
+        if let Some(ref ce) = sq.where_clause {
+            condition_relations(ce, rels);
+        }
+    }
+
+    let mut rels = Vec::new();
+    match *q {
+        SqlQuery::Select(ref sq) => select_relations(sq, &mut rels),
+        SqlQuery::CompoundSelect(ref csq) => for &(_, ref sq) in &csq.selects {
+            select_relations(sq, &mut rels);
+        },
+        _ => (),
+    }
+    rels
+}
+
 #[inline]
 fn is_ident(chr: u8) -> bool {
     is_alphanumeric(chr) || chr == '_' as u8
@@ -88,6 +224,34 @@ named!(query_expr<&[u8], (bool, Option<String>, SqlQuery)>,
     )
 );
 
+named!(shard_directive<&[u8], (String, ShardingDirective)>,
+    do_parse!(
+        tag_no_case!("shard") >>
+        multispace >>
+        relation: map_res!(take_while1!(is_ident), str::from_utf8) >>
+        column: opt!(complete!(do_parse!(
+            multispace >>
+            tag_no_case!("by") >>
+            multispace >>
+            column: map_res!(take_while1!(is_ident), str::from_utf8) >>
+            (column)
+        ))) >>
+        shards: opt!(complete!(do_parse!(
+            multispace >>
+            tag_no_case!("into") >>
+            multispace >>
+            shards: map_res!(map_res!(digit, str::from_utf8), str::parse::<usize>) >>
+            (shards)
+        ))) >>
+        opt!(complete!(multispace)) >>
+        tag!(";") >>
+        (relation.to_owned(), ShardingDirective {
+            column: column.map(|c| c.to_owned()),
+            shards: shards,
+        })
+    )
+);
+
 #[allow(unused)]
 impl Recipe {
     /// Return security groups in the recipe
@@ -110,6 +274,7 @@ impl Recipe {
             expressions: HashMap::default(),
             expression_order: Vec::default(),
             aliases: HashMap::default(),
+            statements: Vec::new(),
             version: 0,
             prior: None,
             inc: match log {
@@ -121,6 +286,7 @@ impl Recipe {
                 Some(log) => log,
             },
             security_config: None,
+            sharding: HashMap::default(),
         }
     }
 
@@ -148,6 +314,15 @@ impl Recipe {
         self.inc.as_mut().unwrap().enable_reuse(reuse_type)
     }
 
+    /// Gives the query `name` a sharding directive for `shards` shards, replacing any it had.
+    pub(crate) fn set_shards(&mut self, name: &str, shards: usize) {
+        let directive = ShardingDirective {
+            column: None,
+            shards: Some(shards),
+        };
+        self.sharding.insert(name.to_owned(), directive);
+    }
+
     /// Base table schema
     pub fn get_base_schema(&self, name: &str) -> Option<CreateTableStatement> {
         self.inc.as_ref().unwrap().get_base_schema(name)
@@ -189,19 +364,25 @@ impl Recipe {
     /// Note that the recipe is not backed by a Soup data-flow graph until `activate` is called on
     /// it.
     pub fn from_str(recipe_text: &str, log: Option<slog::Logger>) -> Result<Recipe, String> {
-        // remove comment lines
-        let lines: Vec<String> = recipe_text
+        // blank out comment lines, keeping the line numbers used in parse errors intact
+        let lines: Vec<&str> = recipe_text
             .lines()
             .map(str::trim)
-            .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with("--"))
-            .map(String::from)
-            .collect();
+            .map(|l| {
+                if l.starts_with('#') || l.starts_with("--") {
+                    ""
+                } else {
+                    l
+                }
+            }).collect();
         let cleaned_recipe_text = lines.join("\n");
 
         // parse and compute differences to current recipe
-        let parsed_queries = Recipe::parse(&cleaned_recipe_text)?;
+        let (parsed_queries, sharding) = Recipe::parse(&cleaned_recipe_text)?;
 
-        Ok(Recipe::from_queries(parsed_queries, log))
+        let mut recipe = Recipe::from_queries(parsed_queries, log);
+        recipe.sharding = sharding;
+        Ok(recipe)
     }
 
     /// Creates a recipe from a set of pre-parsed `SqlQuery` structures.
@@ -212,12 +393,14 @@ impl Recipe {
         log: Option<slog::Logger>,
     ) -> Recipe {
         let mut aliases = HashMap::default();
+        let mut statements = Vec::new();
         let mut expression_order = Vec::new();
         let mut duplicates = 0;
         let expressions = qs
             .into_iter()
             .map(|(n, q, is_leaf)| {
                 let qid = hash_query(&q);
+                statements.push((n.clone(), qid));
                 if !expression_order.contains(&qid) {
                     expression_order.push(qid);
                 } else {
@@ -248,7 +431,9 @@ impl Recipe {
             expressions: expressions,
             expression_order: expression_order,
             aliases: aliases,
+            statements: statements,
             security_config: None,
+            sharding: HashMap::default(),
             version: 0,
             prior: None,
             inc: Some(inc),
@@ -262,13 +447,16 @@ impl Recipe {
         mig: &mut Migration,
         universe_groups: HashMap<String, Vec<DataType>>,
     ) -> Result<ActivationResult, String> {
-        use crate::controller::sql::security::Multiverse;
+        use crate::controller::sql::security::{universe_view_name, Multiverse};
 
         let mut result = ActivationResult {
             new_nodes: HashMap::default(),
             removed_leaves: Vec::default(),
+            added_nodes: Vec::new(),
+            reused_nodes: Vec::new(),
             expressions_added: 0,
             expressions_removed: 0,
+            statements: Vec::new(),
         };
 
         if self.security_config.is_some() {
@@ -283,25 +471,15 @@ impl Recipe {
             }
         }
 
-        for expr in self.expressions.values() {
-            let (n, q, is_leaf) = expr.clone();
+        // queries are added in recipe order, so that the universe's versions of the views that a
+        // query reads from already exist when it is added.
+        for qid in &self.expression_order {
+            let (n, q, is_leaf) = self.expressions[qid].clone();
 
             // add the universe-specific query
             // don't use query name to avoid conflict with global queries
             let (id, group) = mig.universe();
-            let new_name = if n.is_some() {
-                match group {
-                    Some(ref g) => Some(format!(
-                        "{}_{}{}",
-                        n.clone().unwrap(),
-                        g.to_string(),
-                        id.to_string()
-                    )),
-                    None => Some(format!("{}_u{}", n.clone().unwrap(), id.to_string())),
-                }
-            } else {
-                None
-            };
+            let new_name = n.as_ref().map(|n| universe_view_name(n, &id, &group));
 
             let is_leaf = if group.is_some() { false } else { is_leaf };
 
@@ -332,6 +510,10 @@ impl Recipe {
                                  self.expressions.len(),
                                  self.aliases.len(); "version" => self.version);
 
+        self.check_relations()?;
+        self.check_bases()?;
+        self.rename_queries();
+
         let (added, removed) = match self.prior {
             None => self.compute_delta(&Recipe::blank(None)),
             Some(ref pr) => {
@@ -343,9 +525,13 @@ impl Recipe {
         let mut result = ActivationResult {
             new_nodes: HashMap::default(),
             removed_leaves: Vec::default(),
+            added_nodes: Vec::new(),
+            reused_nodes: Vec::new(),
             expressions_added: added.len(),
             expressions_removed: removed.len(),
+            statements: Vec::new(),
         };
+        let mut outcomes = HashMap::new();
 
         // upgrade schema version *before* applying changes, so that new queries are correctly
         // tagged with the new version. If this recipe was just created, there is no need to
@@ -398,6 +584,22 @@ impl Recipe {
         for qid in added {
             let (n, q, is_leaf) = self.expressions[&qid].clone();
 
+            // a sharding directive applies to all the nodes that the base or query adds
+            let directive = match q {
+                SqlQuery::CreateTable(ref ctq) => self.sharding.get(&ctq.table.name).map(|d| {
+                    let column = d
+                        .column
+                        .as_ref()
+                        .map(|c| ctq.fields.iter().position(|f| f.column.name == *c).unwrap());
+                    (column, d.shards)
+                }),
+                _ => n.as_ref().and_then(|n| self.sharding.get(n)).map(|d| (None, d.shards)),
+            };
+            let base = match q {
+                SqlQuery::CreateTable(ref ctq) => Some(ctq.table.name.clone()),
+                _ => None,
+            };
+
             // add the query
             let qfp = self
                 .inc
@@ -405,6 +607,20 @@ impl Recipe {
                 .unwrap()
                 .add_parsed_query(q, n.clone(), is_leaf, mig)?;
 
+            if let Some((column, shards)) = directive {
+                for &ni in &qfp.new_nodes {
+                    mig.shard(ni, column, shards);
+                }
+            }
+
+            // record which nodes reuse analysis shared with existing queries
+            result.added_nodes.extend(qfp.new_nodes.iter().cloned());
+            for &ni in &qfp.reused_nodes {
+                if !result.reused_nodes.contains(&ni) {
+                    result.reused_nodes.push(ni);
+                }
+            }
+
             // If the user provided us with a query name, use that.
             // If not, use the name internally used by the QFP.
             let query_name = match n {
@@ -412,8 +628,29 @@ impl Recipe {
                 None => qfp.name.clone(),
             };
 
+            // a query whose nodes all existed already is served by the query it matched
+            let outcome = match base {
+                Some(table) => StatementResult::BaseCreated(table),
+                None if qfp.new_nodes.is_empty() => {
+                    let inc = self.inc.as_ref().unwrap();
+                    let mut existing = inc.get_queries_for_node(qfp.query_leaf);
+                    existing.retain(|e| *e != qfp.name);
+                    existing.sort();
+                    StatementResult::Reused {
+                        name: query_name.clone(),
+                        of: existing
+                            .into_iter()
+                            .next()
+                            .unwrap_or_else(|| format!("node {}", qfp.query_leaf.index())),
+                    }
+                }
+                None => StatementResult::QueryAdded(query_name.clone()),
+            };
+            outcomes.insert(qid, outcome);
+
             result.new_nodes.insert(query_name, qfp.query_leaf);
         }
+        result.statements = self.statement_results(outcomes);
 
         result.removed_leaves = removed
             .iter()
@@ -425,10 +662,13 @@ impl Recipe {
                         // nodes; the code handling `removed_leaves` therefore needs to take care
                         // not to remove bases while they still have children, or to try removing
                         // them twice.
+                        // N.B.: the prior recipe no longer holds the incorporator, so we must
+                        // resolve the base's address through ours before removing it.
+                        let ni = self.inc.as_ref().unwrap().get_query_address(&ctq.table.name);
                         self.inc.as_mut().unwrap().remove_base(&ctq.table.name);
-                        match self.prior.as_ref().unwrap().node_addr_for(&ctq.table.name) {
-                            Ok(ni) => Some(ni),
-                            Err(e) => {
+                        match ni {
+                            Some(ni) => Some(ni),
+                            None => {
                                 crit!(
                                     self.log,
                                     "failed to remove base {} whose  address could not be resolved",
@@ -449,6 +689,216 @@ impl Recipe {
         Ok(result)
     }
 
+    /// Describes what each statement of the text this recipe was created or last extended from
+    /// did. `added` holds the outcomes of the expressions that activating the recipe added; any
+    /// other statement either gave an existing expression a new name, or did nothing.
+    pub(crate) fn statement_results(
+        &self,
+        mut added: HashMap<QueryID, StatementResult>,
+    ) -> Vec<StatementResult> {
+        // names that earlier statements already accounted for
+        let mut named = HashSet::new();
+        self.statements
+            .iter()
+            .map(|&(ref name, qid)| {
+                let (ref expr_name, ref q, _) = self.expressions[&qid];
+                if name == expr_name {
+                    if let Some(outcome) = added.remove(&qid) {
+                        named.extend(name.as_ref());
+                        return outcome;
+                    }
+                }
+
+                match *name {
+                    Some(ref n) if named.insert(n) => {
+                        let existed = self
+                            .prior
+                            .as_ref()
+                            .map(|pr| pr.aliases.get(n) == Some(&qid))
+                            .unwrap_or(false);
+                        if existed {
+                            StatementResult::Unchanged
+                        } else {
+                            StatementResult::Reused {
+                                name: n.clone(),
+                                of: expr_name.clone().unwrap_or_else(|| q.to_string()),
+                            }
+                        }
+                    }
+                    _ => StatementResult::Unchanged,
+                }
+            }).collect()
+    }
+
+    /// Checks that every relation read by the queries this recipe adds either already exists in
+    /// the graph, or is defined by an expression that this recipe adds before the query.
+    ///
+    /// Query planning panics on unknown relations, so this should be called before any part of
+    /// the recipe is activated.
+    pub(crate) fn check_relations(&self) -> Result<(), String> {
+        let (added, _) = match self.prior {
+            None => self.compute_delta(&Recipe::blank(None)),
+            Some(ref pr) => self.compute_delta(pr),
+        };
+        let (mut removed, removed_queries) = self.removed_names();
+        removed.extend(removed_queries);
+
+        let inc = self.inc.as_ref().unwrap();
+        let mut defined: HashSet<String> = self.security_groups().into_iter().collect();
+        for qid in added {
+            let (ref n, ref q, _) = self.expressions[&qid];
+            for rel in referred_relations(q) {
+                if !defined.contains(&rel) && (!inc.has_view(&rel) || removed.contains(&rel)) {
+                    let query = match *n {
+                        Some(ref name) => name.clone(),
+                        None => q.to_string(),
+                    };
+                    return Err(format!("unknown relation {} referenced by query {}", rel, query));
+                }
+            }
+
+            match *q {
+                SqlQuery::CreateTable(ref ctq) => defined.insert(ctq.table.name.clone()),
+                _ => n.as_ref().map_or(false, |name| defined.insert(name.clone())),
+            };
+        }
+        Ok(())
+    }
+
+    /// Checks that the base tables this recipe adds agree with each other and with the bases that
+    /// already exist.
+    ///
+    /// Repeating a base's definition verbatim is a no-op, and an existing base may gain or lose
+    /// columns, but a column may not change its type, and a recipe may not add two different
+    /// definitions of the same base. Like `check_relations`, this should be called before any
+    /// part of the recipe is activated.
+    pub(crate) fn check_bases(&self) -> Result<(), String> {
+        let (added, _) = match self.prior {
+            None => self.compute_delta(&Recipe::blank(None)),
+            Some(ref pr) => self.compute_delta(pr),
+        };
+
+        let inc = self.inc.as_ref().unwrap();
+        let mut defined: HashMap<&str, &CreateTableStatement> = HashMap::new();
+        for qid in added {
+            let ctq = match self.expressions[&qid].1 {
+                SqlQuery::CreateTable(ref ctq) => ctq,
+                _ => continue,
+            };
+            let name = &ctq.table.name[..];
+
+            if let Some(other) = defined.insert(name, ctq) {
+                let difference = schema_difference(other, ctq);
+                return Err(format!("conflicting definitions of table {}: {}", name, difference));
+            }
+            if let Some(existing) = inc.get_base_schema(name) {
+                if let Some(change) = column_type_change(&existing, ctq) {
+                    return Err(format!("cannot redefine table {}: {}", name, change));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the sharding directives in this recipe can be honored: that they name base
+    /// tables and queries in the recipe, that bases are only sharded by columns that writes to them
+    /// can be routed by, and that no query this recipe adds combines relations that directives
+    /// give different numbers of shards, unless a directive also gives one for the query itself.
+    ///
+    /// Like `check_relations`, this should be called before any part of the recipe is activated.
+    pub(crate) fn check_sharding(&self, default_shards: Option<usize>) -> Result<(), String> {
+        if self.sharding.is_empty() {
+            return Ok(());
+        }
+        if default_shards.is_none() {
+            return Err(String::from("recipe has sharding directives, but sharding is disabled"));
+        }
+
+        for (relation, directive) in &self.sharding {
+            match self.expression_for(relation) {
+                None => return Err(format!("cannot shard unknown relation {}", relation)),
+                Some(&(_, SqlQuery::CreateTable(ref ctq), _)) => {
+                    let column = match directive.column {
+                        Some(ref column) => column,
+                        None => continue,
+                    };
+                    if !ctq.fields.iter().any(|f| f.column.name == *column) {
+                        let e = format!("cannot shard {} by unknown column {}", relation, column);
+                        return Err(e);
+                    }
+                    let key = primary_key(ctq);
+                    if !key.is_empty() && key != [column.as_str()] {
+                        return Err(format!(
+                            "cannot shard {} by {}, as writes to it are routed by its key ({})",
+                            relation,
+                            column,
+                            key.join(", ")
+                        ));
+                    }
+                }
+                Some(_) => if directive.column.is_some() {
+                    return Err(format!(
+                        "cannot shard query {} by a column; only base tables take one",
+                        relation
+                    ));
+                },
+            }
+        }
+
+        let (added, _) = match self.prior {
+            None => self.compute_delta(&Recipe::blank(None)),
+            Some(ref pr) => self.compute_delta(pr),
+        };
+        for qid in added {
+            let (ref name, ref q, _) = self.expressions[&qid];
+            let name = match *name {
+                Some(ref name) => name.as_str(),
+                None => "unnamed query",
+            };
+            if self.sharding.get(name).and_then(|d| d.shards).is_some() {
+                continue;
+            }
+
+            let mut pinned = Vec::new();
+            for relation in referred_relations(q) {
+                self.pinned_shards(&relation, &mut pinned);
+            }
+            if let Some(&(ref first, shards)) = pinned.first() {
+                if let Some(&(ref other, other_shards)) = pinned.iter().find(|p| p.1 != shards) {
+                    return Err(format!(
+                        "{} combines {} and {}, which are sharded into {} and {} shards; give it a \
+                         sharding directive that picks its number of shards",
+                        name, first, other, shards, other_shards
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the relations that decide how many shards `relation` has: those among it and the
+    /// relations it reads from that a sharding directive gives a number of shards.
+    fn pinned_shards(&self, relation: &str, pinned: &mut Vec<(String, usize)>) {
+        if let Some(shards) = self.sharding.get(relation).and_then(|d| d.shards) {
+            pinned.push((relation.to_owned(), shards));
+        } else if let Some(&(_, ref q, _)) = self.expression_for(relation) {
+            for relation in referred_relations(q) {
+                self.pinned_shards(&relation, pinned);
+            }
+        }
+    }
+
+    /// Returns the expression that defines the base table or query `name`, if there is one.
+    fn expression_for(&self, name: &str) -> Option<&(Option<String>, SqlQuery, bool)> {
+        match self.aliases.get(name) {
+            Some(qid) => self.expressions.get(qid),
+            None => self.expressions.values().find(|&&(_, ref q, _)| match *q {
+                SqlQuery::CreateTable(ref ctq) => ctq.table.name == name,
+                _ => false,
+            }),
+        }
+    }
+
     /// Work out the delta between two recipes.
     /// Returns two sets of `QueryID` -> `SqlQuery` mappings:
     /// (1) those queries present in `self`, but not in `other`; and
@@ -470,6 +920,73 @@ impl Recipe {
         (added_queries, removed_queries)
     }
 
+    /// Returns the queries that this recipe keeps from the prior recipe, but under a different
+    /// name, as pairs of their old and new names.
+    ///
+    /// Such a query is the very same statement as before, so it lowers to the same MIR, and its
+    /// existing dataflow nodes (and their state) can serve it under the new name.
+    pub(crate) fn renamed_queries(&self) -> Vec<(String, String)> {
+        let prior = match self.prior {
+            None => return vec![],
+            Some(ref pr) => pr,
+        };
+        self.expression_order
+            .iter()
+            .filter_map(|qid| {
+                let (ref old, _, _) = *prior.expressions.get(qid)?;
+                match self.expressions[qid] {
+                    (_, SqlQuery::CreateTable(_), _) => None,
+                    (Some(ref new), _, _) => match *old {
+                        Some(ref old) if old != new => Some((old.clone(), new.clone())),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }).collect()
+    }
+
+    /// Registers the queries returned by `renamed_queries` under their new names, so that their
+    /// existing dataflow nodes can be found through those names.
+    pub(crate) fn rename_queries(&mut self) {
+        for (old, new) in self.renamed_queries() {
+            info!(self.log, "renaming query {} to {}", old, new; "version" => self.version);
+            self.inc.as_mut().unwrap().rename_query(&old, &new);
+        }
+    }
+
+    /// Returns true if activating this recipe would not change the graph, since it neither adds nor
+    /// removes any expressions compared to the recipe it replaces.
+    pub(crate) fn is_unchanged(&self) -> bool {
+        match self.prior {
+            Some(ref pr) if self.security_config.is_none() => {
+                let (added, removed) = self.compute_delta(pr);
+                added.is_empty() && removed.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the names of the expressions that were present in the prior recipe, but that are
+    /// missing from this one. Removed base tables and removed queries are returned separately.
+    pub(crate) fn removed_names(&self) -> (Vec<String>, Vec<String>) {
+        let prior = match self.prior {
+            None => return (vec![], vec![]),
+            Some(ref pr) => pr,
+        };
+
+        let (_, removed) = self.compute_delta(prior);
+        let mut bases = Vec::new();
+        let mut queries = Vec::new();
+        for qid in removed {
+            match prior.expressions[&qid] {
+                (_, SqlQuery::CreateTable(ref ctq), _) => bases.push(ctq.table.name.clone()),
+                (Some(ref n), _, _) => queries.push(n.clone()),
+                (None, _, _) => (),
+            }
+        }
+        (bases, queries)
+    }
+
     /// Returns the query expressions in the recipe.
     pub fn expressions(&self) -> Vec<(Option<&String>, &SqlQuery)> {
         self.expressions
@@ -478,6 +995,22 @@ impl Recipe {
             .collect()
     }
 
+    /// Returns the recipe's expressions, in the order they were added, as recipe text that
+    /// `from_str` accepts. Sharding directives follow the expressions.
+    pub fn text(&self) -> String {
+        let mut sharding: Vec<_> = self.sharding.iter().collect();
+        sharding.sort_by_key(|&(relation, _)| relation);
+        self.expression_order
+            .iter()
+            .map(|qid| match self.expressions[qid] {
+                (Some(ref n), ref q, true) => format!("QUERY {}: {};", n, q),
+                (Some(ref n), ref q, false) => format!("{}: {};", n, q),
+                (None, ref q, _) => format!("{};", q),
+            }).chain(sharding.into_iter().map(|(r, d)| d.statement(r)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
     /// Append the queries in the `additions` argument to this recipe. This will attempt to parse
     /// `additions`, and if successful, will extend the recipe. No expressions are removed from the
     /// recipe; use `replace` if removal of unused expressions is desired.
@@ -488,6 +1021,15 @@ impl Recipe {
             Ok(rp) => rp,
             Err(e) => return Err((self, e)),
         };
+        let conflict = add_rp
+            .sharding
+            .iter()
+            .find(|&(r, d)| self.sharding.get(r).map(|old| old != d).unwrap_or(false))
+            .map(|(r, _)| r.clone());
+        if let Some(relation) = conflict {
+            let e = format!("conflicting sharding directives for {}", relation);
+            return Err((self, e));
+        }
         let (added, _) = add_rp.compute_delta(&self);
 
         // move the incorporator state from the old recipe to the new one
@@ -498,8 +1040,10 @@ impl Recipe {
             expressions: self.expressions.clone(),
             expression_order: self.expression_order.clone(),
             aliases: self.aliases.clone(),
+            statements: add_rp.statements,
             version: self.version + 1,
             inc: prior_inc,
+            sharding: self.sharding.clone(),
             log: self.log.clone(),
             // retain the old recipe for future reference
             prior: Some(Box::new(self)),
@@ -514,17 +1058,75 @@ impl Recipe {
         }
 
         new.aliases.extend(add_rp.aliases);
+        new.sharding.extend(add_rp.sharding);
 
         // return new recipe as replacement for self
         Ok(new)
     }
 
+    /// Give the query `old` the name `new`, leaving the rest of the recipe as it is.
+    /// Consumes `self` and returns a replacement recipe, which keeps the query's dataflow nodes
+    /// when activated (see `renamed_queries`).
+    pub fn rename(mut self, old: &str, new: &str) -> Result<Recipe, (Recipe, String)> {
+        let qid = match self.aliases.get(old) {
+            Some(&qid) => qid,
+            None => return Err((self, format!("no query named {}", old))),
+        };
+        if let SqlQuery::CreateTable(_) = self.expressions[&qid].1 {
+            return Err((self, format!("cannot rename base table {}", old)));
+        }
+        let readers: Vec<_> = self
+            .expressions
+            .values()
+            .filter(|&&(_, ref q, _)| referred_relations(q).iter().any(|r| r == old))
+            .filter_map(|&(ref n, _, _)| n.clone())
+            .collect();
+        if !readers.is_empty() {
+            let e = format!("{} is read by other queries: {}", old, readers.join(", "));
+            return Err((self, e));
+        }
+        if self.aliases.contains_key(new) || self.inc.as_ref().unwrap().has_view(new) {
+            return Err((self, format!("a view named {} already exists", new)));
+        }
+
+        let prior_inc = self.inc.take();
+        let mut renamed = Recipe {
+            expressions: self.expressions.clone(),
+            expression_order: self.expression_order.clone(),
+            aliases: self.aliases.clone(),
+            statements: Vec::new(),
+            version: self.version + 1,
+            inc: prior_inc,
+            sharding: self.sharding.clone(),
+            log: self.log.clone(),
+            prior: Some(Box::new(self)),
+            security_config: None,
+        };
+
+        renamed.aliases.remove(old);
+        renamed.aliases.insert(new.to_owned(), qid);
+        if let Some(d) = renamed.sharding.remove(old) {
+            renamed.sharding.insert(new.to_owned(), d);
+        }
+        let expr = renamed.expressions.get_mut(&qid).unwrap();
+        if expr.0.as_ref().map(|n| n == old).unwrap_or(false) {
+            expr.0 = Some(new.to_owned());
+        }
+        Ok(renamed)
+    }
+
     /// Helper method to reparent a recipe. This is needed for the recovery logic to build
     /// recovery and original recipe (see `make_recovery`).
     pub(crate) fn set_prior(&mut self, new_prior: Recipe) {
         self.prior = Some(Box::new(new_prior));
     }
 
+    /// Helper method to detach a recipe from its predecessor. This is needed to roll back to a
+    /// recipe's predecessor without losing the predecessor's own lineage.
+    pub(crate) fn take_prior(&mut self) -> Option<Recipe> {
+        self.prior.take().map(|p| *p)
+    }
+
     /// Helper method to reparent a recipe. This is needed for some of t
     pub(crate) fn sql_inc(&self) -> &SqlIncorporator {
         self.inc.as_ref().unwrap()
@@ -535,55 +1137,112 @@ impl Recipe {
         self.inc = Some(new_inc);
     }
 
-    fn parse(recipe_text: &str) -> Result<Vec<(Option<String>, SqlQuery, bool)>, String> {
-        let lines: Vec<&str> = recipe_text
+    /// Removes the statements defining the named queries from `recipe_text`, and returns the
+    /// remaining statements. Comments are not retained.
+    pub(crate) fn strip_queries(recipe_text: &str, names: &[String]) -> String {
+        let lines: Vec<String> = recipe_text
             .lines()
-            .filter(|l| !l.is_empty() && !l.starts_with("#"))
-            .map(|l| {
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with("--"))
+            .map(String::from)
+            .collect();
+
+        Recipe::statements(&lines.join("\n"))
+            .into_iter()
+            .filter(|q| match query_expr(q.as_bytes()) {
+                nom::IResult::Done(_, (_, Some(ref n), _)) => !names.contains(n),
+                _ => true,
+            }).collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn statements(recipe_text: &str) -> Vec<String> {
+        Recipe::numbered_statements(recipe_text)
+            .into_iter()
+            .map(|(_, q)| q)
+            .collect()
+    }
+
+    /// Splits `recipe_text` into statements, each paired with the (1-based) line number on which
+    /// it starts.
+    fn numbered_statements(recipe_text: &str) -> Vec<(usize, String)> {
+        let lines = recipe_text
+            .lines()
+            .enumerate()
+            .filter(|&(_, l)| !l.is_empty() && !l.starts_with("#"))
+            .map(|(i, l)| {
                 // remove inline comments, too
                 match l.find("#") {
-                    None => l.trim(),
-                    Some(pos) => &l[0..pos - 1].trim(),
+                    None => (i + 1, l.trim()),
+                    Some(pos) => (i + 1, l[0..pos - 1].trim()),
                 }
-            }).collect();
+            });
         let mut query_strings = Vec::new();
         let mut q = String::new();
-        for l in lines {
+        let mut start = 0;
+        for (line, l) in lines {
+            if q.is_empty() {
+                start = line;
+            }
             if !l.ends_with(";") {
                 q.push_str(l);
                 q.push_str(" ");
             } else {
                 // end of query
                 q.push_str(l);
-                query_strings.push(q);
+                query_strings.push((start, q));
                 q = String::new();
             }
         }
+        query_strings
+    }
 
-        let parsed_queries = query_strings
-            .iter()
-            .map(|ref q| (q.clone(), query_expr(q.as_bytes())))
-            .collect::<Vec<_>>();
-
-        if !parsed_queries.iter().all(|pq| pq.1.is_done()) {
-            for pq in parsed_queries {
-                match pq.1 {
-                    nom::IResult::Error(e) => {
-                        return Err(format!("Query \"{}\", parse error: {}", pq.0, e))
-                    }
-                    nom::IResult::Done(_, _) => (),
-                    nom::IResult::Incomplete(_) => unreachable!(),
+    fn parse(
+        recipe_text: &str,
+    ) -> Result<
+        (
+            Vec<(Option<String>, SqlQuery, bool)>,
+            HashMap<String, ShardingDirective>,
+        ),
+        String,
+    > {
+        let query_strings = Recipe::numbered_statements(recipe_text);
+        let mut parsed_queries = Vec::with_capacity(query_strings.len());
+        let mut sharding = HashMap::new();
+        for (i, (line, q)) in query_strings.into_iter().enumerate() {
+            let error = match shard_directive(q.as_bytes()) {
+                nom::IResult::Done(_, (ref relation, _)) if sharding.contains_key(relation) => {
+                    format!("{} already has a sharding directive", relation)
                 }
-            }
-            return Err(format!("Failed to parse recipe!"));
+                nom::IResult::Done(_, (_, ShardingDirective { shards: Some(n), .. })) if n < 2 => {
+                    format!("cannot shard into {} shards", n)
+                }
+                nom::IResult::Done(_, (_, ref d)) if d.column.is_none() && d.shards.is_none() => {
+                    String::from("sharding directive needs a column or a number of shards")
+                }
+                nom::IResult::Done(_, (relation, directive)) => {
+                    sharding.insert(relation, directive);
+                    continue;
+                }
+                _ => match query_expr(q.as_bytes()) {
+                    nom::IResult::Done(_, (is_leaf, name, q)) => {
+                        parsed_queries.push((name, q, is_leaf));
+                        continue;
+                    }
+                    nom::IResult::Error(e) => format!("{}", e),
+                    nom::IResult::Incomplete(_) => String::from("statement is incomplete"),
+                },
+            };
+            return Err(format!(
+                "failed to parse statement {} on line {}: \"{}\": {}",
+                i + 1,
+                line,
+                q,
+                error
+            ));
         }
 
-        Ok(parsed_queries
-            .into_iter()
-            .map(|(_, t)| {
-                let pr = t.unwrap().1;
-                (pr.1, pr.2, pr.0)
-            }).collect::<Vec<_>>())
+        Ok((parsed_queries, sharding))
     }
 
     /// Returns the predecessor from which this `Recipe` was migrated to.
@@ -600,6 +1259,7 @@ impl Recipe {
         let qid = qid.unwrap();
 
         self.aliases.remove(qname);
+        self.sharding.remove(qname);
         self.expressions.remove(&qid).is_some() && self.expression_order.remove_item(&qid).is_some()
     }
 
@@ -634,6 +1294,12 @@ impl Recipe {
         self.version
     }
 
+    /// Helper method to renumber a recipe. This is needed so that rolling back to an earlier recipe
+    /// still moves the version forward.
+    pub(crate) fn set_version(&mut self, version: usize) {
+        self.version = version;
+    }
+
     /// Reverts to prior version of recipe
     pub fn revert(self) -> Recipe {
         if let Some(prior) = self.prior {
@@ -745,4 +1411,83 @@ mod tests {
         assert_eq!(r2.expressions.len(), 2);
         assert_eq!(r2.prior, Some(Box::new(r1_copy)));
     }
+
+    #[test]
+    fn it_reports_parse_errors() {
+        let r_txt = "# comment
+                     SELECT a FROM b;
+
+                     SELEC a
+                     FROM b WHERE x = 42;
+                     SELECT c FROM b;";
+        let e = Recipe::from_str(r_txt, None).unwrap_err();
+        let expected = "failed to parse statement 2 on line 4: \"SELEC a FROM b WHERE x = 42;\"";
+        assert!(e.starts_with(expected), "unexpected error: {}", e);
+    }
+
+    #[test]
+    fn it_rejects_unknown_relations() {
+        let r_txt = "CREATE TABLE b (a int, x int);
+                     QUERY q: SELECT a FROM b WHERE x IN (SELECT a FROM c);";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        let e = r.check_relations().unwrap_err();
+        assert_eq!(e, "unknown relation c referenced by query q");
+
+        let r_txt = "CREATE TABLE b (a int, x int);
+                     v: SELECT a FROM b;
+                     QUERY q: SELECT b.a FROM b JOIN v ON (b.a = v.a);";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        assert!(r.check_relations().is_ok());
+    }
+
+    #[test]
+    fn it_checks_base_redefinitions() {
+        let r_txt = "CREATE TABLE b (a int, x int);
+                     QUERY q: SELECT a FROM b;";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        assert!(r.check_bases().is_ok());
+
+        // an identical definition is the same expression, and adds nothing
+        let r = r.extend("CREATE TABLE b (a int, x int);").unwrap();
+        assert_eq!(r.compute_delta(r.prior().unwrap()).0.len(), 0);
+        assert!(r.check_bases().is_ok());
+
+        let r_txt = "CREATE TABLE b (a int, x int);
+                     CREATE TABLE b (a int, x text);";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        let e = r.check_bases().unwrap_err();
+        let expected = "conflicting definitions of table b: column x changes type from";
+        assert!(e.starts_with(expected), "unexpected error: {}", e);
+
+        let r_txt = "CREATE TABLE b (a int, x int);
+                     CREATE TABLE b (a int);";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        let e = r.check_bases().unwrap_err();
+        assert_eq!(e, "conflicting definitions of table b: columns (a, x) differ from (a)");
+    }
+
+    #[test]
+    fn it_checks_sharding_directives() {
+        let r_txt = "CREATE TABLE a (id int, x int, PRIMARY KEY(id));
+                     CREATE TABLE b (id int, x int);
+                     SHARD a INTO 3;
+                     SHARD b BY x INTO 4;
+                     QUERY q: SELECT a.x FROM a JOIN b ON (a.id = b.id);";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        assert_eq!(r.sharding.len(), 2);
+        let e = r.check_sharding(Some(2)).unwrap_err();
+        assert!(e.starts_with("q combines a and b"), "unexpected error: {}", e);
+        assert!(r.check_sharding(None).is_err());
+
+        // the directives survive a round trip through the recipe text
+        let r = r.extend("SHARD q INTO 3;").unwrap();
+        assert!(r.check_sharding(Some(2)).is_ok());
+        assert_eq!(Recipe::from_str(&r.text(), None).unwrap().sharding, r.sharding);
+
+        // bases with a primary key can only be sharded by it
+        let r_txt = "CREATE TABLE a (id int, x int, PRIMARY KEY(id));
+                     SHARD a BY x;";
+        let r = Recipe::from_str(r_txt, None).unwrap();
+        assert!(r.check_sharding(Some(2)).is_err());
+    }
 }
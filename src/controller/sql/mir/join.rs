@@ -1,8 +1,9 @@
+use basics::DataType;
 use crate::controller::sql::mir::SqlToMirConverter;
 use crate::controller::sql::query_graph::{JoinRef, QueryGraph, QueryGraphEdge};
 use dataflow::ops::join::JoinType;
 use mir::MirNodeRef;
-use nom_sql::ConditionTree;
+use nom_sql::{Column, ConditionBase, ConditionExpression, ConditionTree, Operator};
 use std::collections::{HashMap, HashSet};
 
 struct JoinChain {
@@ -49,11 +50,52 @@ pub fn make_joins(
         let (left_chain, right_chain) =
             pick_join_chains(&jref.src, &jref.dst, &mut join_chains, node_for_rel);
 
+        let cross_jp;
+        let (jp, left_node, right_node) = match jp {
+            Some(jp) => (
+                jp,
+                left_chain.last_node.clone(),
+                right_chain.last_node.clone(),
+            ),
+            None => {
+                // the relations are only related by non-equality predicates, so there is nothing
+                // to key the join on. instead, we add the same constant column to both sides and
+                // join on that, which gives the cross product; the predicates then filter it.
+                let mut cross_key = |side: &str, parent: &MirNodeRef| {
+                    let key = format!("{}_n{}_x{}", name, node_count, side);
+                    let cols: Vec<_> = parent.borrow().columns().iter().cloned().collect();
+                    let prj = mir_converter.make_project_node(
+                        &key,
+                        parent.clone(),
+                        cols.iter().collect(),
+                        vec![],
+                        vec![(key.clone(), DataType::from(0 as i32))],
+                        false,
+                    );
+                    let col = ConditionExpression::Base(ConditionBase::Field(Column::from(
+                        key.as_str(),
+                    )));
+                    (prj, Box::new(col))
+                };
+                let (left_node, left_key) = cross_key("l", &left_chain.last_node);
+                let (right_node, right_key) = cross_key("r", &right_chain.last_node);
+                join_nodes.push(left_node.clone());
+                join_nodes.push(right_node.clone());
+
+                cross_jp = ConditionTree {
+                    operator: Operator::Equal,
+                    left: left_key,
+                    right: right_key,
+                };
+                (&cross_jp, left_node, right_node)
+            }
+        };
+
         let jn = mir_converter.make_join_node(
             &format!("{}_n{}", name, node_count),
             jp,
-            left_chain.last_node.clone(),
-            right_chain.last_node.clone(),
+            left_node,
+            right_node,
             join_type,
         );
 
@@ -69,11 +111,16 @@ pub fn make_joins(
     join_nodes
 }
 
-fn from_join_ref<'a>(jref: &JoinRef, qg: &'a QueryGraph) -> (JoinType, &'a ConditionTree) {
+/// Returns the kind of join and the predicate to key it on, or `None` for a cross join.
+fn from_join_ref<'a>(
+    jref: &JoinRef,
+    qg: &'a QueryGraph,
+) -> (JoinType, Option<&'a ConditionTree>) {
     let edge = qg.edges.get(&(jref.src.clone(), jref.dst.clone())).unwrap();
     match *edge {
-        QueryGraphEdge::Join(ref jps) => (JoinType::Inner, jps.get(jref.index).unwrap()),
-        QueryGraphEdge::LeftJoin(ref jps) => (JoinType::Left, jps.get(jref.index).unwrap()),
+        QueryGraphEdge::Join(ref jps) if jps.is_empty() => (JoinType::Inner, None),
+        QueryGraphEdge::Join(ref jps) => (JoinType::Inner, Some(jps.get(jref.index).unwrap())),
+        QueryGraphEdge::LeftJoin(ref jps) => (JoinType::Left, Some(jps.get(jref.index).unwrap())),
         QueryGraphEdge::GroupBy(_) => unreachable!(),
     }
 }
@@ -10,8 +10,8 @@ pub use mir::FlowNode;
 use crate::controller::sql::query_graph::{OutputColumn, QueryGraph};
 use crate::controller::sql::query_signature::Signature;
 use nom_sql::{
-    ArithmeticExpression, ColumnSpecification, CompoundSelectOperator, ConditionBase,
-    ConditionExpression, ConditionTree, Literal, Operator, SqlQuery, TableKey,
+    ArithmeticBase, ArithmeticExpression, ColumnSpecification, CompoundSelectOperator,
+    ConditionBase, ConditionExpression, ConditionTree, Literal, Operator, SqlQuery, TableKey,
 };
 use nom_sql::{LimitClause, OrderClause, SelectStatement};
 
@@ -34,6 +34,50 @@ fn sanitize_leaf_column(c: &mut Column, view_name: &str) {
     c.function = None;
 }
 
+/// Returns the column and constant of a comparison of a column against an integer or string.
+fn literal_bound(ce: &ConditionExpression) -> Option<(&nom_sql::Column, &Operator, DataType)> {
+    match *ce {
+        ConditionExpression::ComparisonOp(ref ct) => match (&*ct.left, &*ct.right) {
+            (
+                &ConditionExpression::Base(ConditionBase::Field(ref f)),
+                &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+            ) => match *l {
+                Literal::Integer(_) | Literal::String(_) => Some((f, &ct.operator, l.into())),
+                _ => None,
+            },
+            _ => None,
+        },
+        ConditionExpression::Bracketed(ref inner) => literal_bound(inner),
+        _ => None,
+    }
+}
+
+/// Recognizes a pair of bounds on the same column that together form a range check.
+///
+/// `x >= lo AND x <= hi` is `x BETWEEN lo AND hi`, and `x < lo OR x > hi` is its negation,
+/// `x NOT BETWEEN lo AND hi`. Either can be checked by a single range condition rather than by a
+/// chain of two filters (or, for the negation, a union of two filters).
+fn range_condition(ct: &ConditionTree) -> Option<(nom_sql::Column, FilterCondition)> {
+    let (lf, lop, lv) = literal_bound(&ct.left)?;
+    let (rf, rop, rv) = literal_bound(&ct.right)?;
+    if lf != rf {
+        return None;
+    }
+
+    let cond = match (&ct.operator, lop, rop) {
+        (&Operator::And, &Operator::GreaterOrEqual, &Operator::LessOrEqual) => {
+            FilterCondition::Between(lv, rv)
+        }
+        (&Operator::And, &Operator::LessOrEqual, &Operator::GreaterOrEqual) => {
+            FilterCondition::Between(rv, lv)
+        }
+        (&Operator::Or, &Operator::Less, &Operator::Greater) => FilterCondition::NotBetween(lv, rv),
+        (&Operator::Or, &Operator::Greater, &Operator::Less) => FilterCondition::NotBetween(rv, lv),
+        _ => return None,
+    };
+    Some((lf.clone(), cond))
+}
+
 /// Returns all collumns used in a predicate
 fn predicate_columns(ce: &ConditionExpression) -> HashSet<Column> {
     use nom_sql::ConditionExpression::*;
@@ -165,8 +209,6 @@ impl SqlToMirConverter {
         columns: &mut Vec<Column>,
         n: &MirNodeRef,
     ) -> Vec<Option<FilterCondition>> {
-        use std::cmp::max;
-
         // TODO(malte): we only support one level of condition nesting at this point :(
         let l = match *ct.left.as_ref() {
             ConditionExpression::Base(ConditionBase::Field(ref f)) => f.clone(),
@@ -181,30 +223,55 @@ impl SqlToMirConverter {
                 )
             }
             ConditionExpression::Base(ConditionBase::Literal(Literal::String(ref s))) => {
-                FilterCondition::Comparison(
-                    ct.operator.clone(),
-                    filter::Value::Constant(DataType::from(s.clone())),
-                )
+                match ct.operator {
+                    Operator::Like => FilterCondition::Like(filter::LikePattern::new(s)),
+                    Operator::NotLike => FilterCondition::NotLike(filter::LikePattern::new(s)),
+                    _ => FilterCondition::Comparison(
+                        ct.operator.clone(),
+                        filter::Value::Constant(DataType::from(s.clone())),
+                    ),
+                }
             }
-            ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => {
-                FilterCondition::Comparison(
+            // the parser represents `IS NULL` and `IS NOT NULL` as (in)equality with NULL
+            ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => match ct.operator {
+                Operator::Equal => FilterCondition::IsNull,
+                Operator::NotEqual => FilterCondition::IsNotNull,
+                _ => FilterCondition::Comparison(
                     ct.operator.clone(),
                     filter::Value::Constant(DataType::None),
-                )
-            }
+                ),
+            },
             ConditionExpression::Base(ConditionBase::LiteralList(ref ll)) => {
-                FilterCondition::In(ll.iter().map(|l| DataType::from(l.clone())).collect())
+                FilterCondition::in_list(ll.iter().map(|l| DataType::from(l.clone())).collect())
             }
             ConditionExpression::Base(ConditionBase::Field(ref f)) => {
                 // NOTE(jon): the uwnrap here is almost certainly wrong given the business
                 // that goes on further down where it appens a column in magical circumstances.
-                // also, what if two columns share a name, but differ in .table?
-                let fi = columns.iter().rposition(|c| *c.name == f.name).unwrap();
+                // columns from different tables may share a name, so prefer the one from the
+                // same table, but computed columns have no table to match.
+                let fi = columns
+                    .iter()
+                    .rposition(|c| *c.name == f.name && c.table == f.table)
+                    .or_else(|| columns.iter().rposition(|c| *c.name == f.name))
+                    .unwrap();
                 FilterCondition::Comparison(ct.operator.clone(), filter::Value::Column(fi))
             }
             _ => unimplemented!(),
         });
 
+        self.place_condition(l, f, columns, n)
+    }
+
+    /// Builds the per-column condition vector of a filter that applies `f` to the column `l`.
+    fn place_condition(
+        &self,
+        l: nom_sql::Column,
+        f: Option<FilterCondition>,
+        columns: &mut Vec<Column>,
+        n: &MirNodeRef,
+    ) -> Vec<Option<FilterCondition>> {
+        use std::cmp::max;
+
         let absolute_column_ids: Vec<usize> = columns
             .iter()
             .map(|c| n.borrow().column_id_for_column(c))
@@ -426,6 +493,17 @@ impl SqlToMirConverter {
         }
     }
 
+    /// Registers the leaf of the query `old` under the name `new` instead. The query's other MIR
+    /// nodes keep their names.
+    pub fn rename_query(&mut self, old: &str, new: &str) {
+        if let Some(v) = self.current.remove(old) {
+            if let Some(leaf) = self.nodes.remove(&(old.to_owned(), v)) {
+                self.nodes.insert((new.to_owned(), v), leaf);
+            }
+            self.current.insert(new.to_owned(), v);
+        }
+    }
+
     pub fn remove_query(&mut self, name: &str, mq: &MirQuery) {
         use std::collections::VecDeque;
 
@@ -812,6 +890,33 @@ impl SqlToMirConverter {
         )
     }
 
+    /// Make a single filter node that checks whether `column` falls within (or outside of) a range.
+    fn make_range_filter_node(
+        &self,
+        name: &str,
+        parent: MirNodeRef,
+        column: &nom_sql::Column,
+        cond: FilterCondition,
+    ) -> MirNodeRef {
+        let mut fields = parent.borrow().columns().iter().cloned().collect();
+
+        let filter = self.place_condition(column.clone(), Some(cond), &mut fields, &parent);
+        trace!(
+            self.log,
+            "Added range filter node {} with condition {:?}",
+            name,
+            filter
+        );
+        MirNode::new(
+            name,
+            self.schema_version,
+            fields,
+            MirNodeType::Filter { conditions: filter },
+            vec![parent.clone()],
+            vec![],
+        )
+    }
+
     fn make_function_node(
         &mut self,
         name: &str,
@@ -1158,8 +1263,6 @@ impl SqlToMirConverter {
             None => None,
         };
 
-        assert_eq!(limit.offset, 0); // Non-zero offset not supported
-
         // make the new operator and record its metadata
         MirNode::new(
             name,
@@ -1169,7 +1272,7 @@ impl SqlToMirConverter {
                 order: order,
                 group_by: group_by.into_iter().cloned().collect(),
                 k: limit.limit as usize,
-                offset: 0,
+                offset: limit.offset as usize,
             },
             vec![parent.clone()],
             vec![],
@@ -1188,6 +1291,16 @@ impl SqlToMirConverter {
         let mut pred_nodes: Vec<MirNodeRef> = Vec::new();
         let output_cols = parent.borrow().columns().iter().cloned().collect();
         match *ce {
+            LogicalOp(ref ct) if range_condition(ct).is_some() => {
+                let (column, cond) = range_condition(ct).unwrap();
+                let f = self.make_range_filter_node(
+                    &format!("{}_f{}", name, nc),
+                    parent,
+                    &column,
+                    cond,
+                );
+                pred_nodes.push(f);
+            }
             LogicalOp(ref ct) => {
                 let (left, right);
                 match ct.operator {
@@ -1376,6 +1489,34 @@ impl SqlToMirConverter {
                 node_for_rel.insert(*rel, base_for_rel);
             }
 
+            // 0a. Filters from left join conditions, which decide what rows enter the join and
+            //     so must sit between the base node and the join
+            let mut join_filter_nodes: Vec<MirNodeRef> = Vec::new();
+            for rel in &sorted_rels {
+                if *rel == "computed_columns" {
+                    continue;
+                }
+
+                for (i, p) in qg.relations[*rel].join_predicates.iter().enumerate() {
+                    let fns = self.make_predicate_nodes(
+                        &format!(
+                            "q_{:x}_n{}_j{}{}",
+                            qg.signature().hash,
+                            new_node_count,
+                            i,
+                            uformat
+                        ),
+                        node_for_rel[rel].clone(),
+                        p,
+                        0,
+                    );
+
+                    new_node_count += fns.len();
+                    node_for_rel.insert(*rel, fns.last().unwrap().clone());
+                    join_filter_nodes.extend(fns);
+                }
+            }
+
             let join_nodes = make_joins(
                 self,
                 &format!("q_{:x}{}", qg.signature().hash, uformat),
@@ -1475,6 +1616,7 @@ impl SqlToMirConverter {
 
             nodes_added = base_nodes
                 .into_iter()
+                .chain(join_filter_nodes.into_iter())
                 .chain(join_nodes.into_iter())
                 .chain(predicates_above_group_by_nodes.into_iter())
                 .chain(policy_nodes.into_iter())
@@ -1516,9 +1658,13 @@ impl SqlToMirConverter {
                     // the following conditional is required to avoid "empty" nodes (without any
                     // projected columns) that are required as inputs to joins
                     if !qgn.predicates.is_empty() {
+                        // local predicates arrive split at conjunctions, so a BETWEEN shows up
+                        // as two separate bounds here; those get fused into a single range filter
+                        let mut fused = Vec::new();
+
                         // add a predicate chain for each query graph node's predicates
                         for (i, ref p) in qgn.predicates.iter().enumerate() {
-                            if created_predicates.contains(p) {
+                            if created_predicates.contains(p) || fused.contains(p) {
                                 continue;
                             }
 
@@ -1527,18 +1673,37 @@ impl SqlToMirConverter {
                                 Some(pn) => pn,
                             };
 
-                            let fns = self.make_predicate_nodes(
-                                &format!(
-                                    "q_{:x}_n{}_p{}{}",
-                                    qg.signature().hash,
-                                    new_node_count,
-                                    i,
-                                    uformat
-                                ),
-                                parent,
-                                p,
-                                0,
+                            let name = format!(
+                                "q_{:x}_n{}_p{}{}",
+                                qg.signature().hash,
+                                new_node_count,
+                                i,
+                                uformat
                             );
+                            let range = qgn.predicates[i + 1..]
+                                .iter()
+                                .filter(|q| !created_predicates.contains(q) && !fused.contains(q))
+                                .filter_map(|q| {
+                                    let ct = ConditionTree {
+                                        operator: Operator::And,
+                                        left: Box::new((*p).clone()),
+                                        right: Box::new(q.clone()),
+                                    };
+                                    range_condition(&ct).map(|r| (q, r))
+                                }).next();
+                            let fns = match range {
+                                Some((q, (column, cond))) => {
+                                    fused.push(q);
+                                    let f = self.make_range_filter_node(
+                                        &format!("{}_f0", name),
+                                        parent,
+                                        &column,
+                                        cond,
+                                    );
+                                    vec![f]
+                                }
+                                None => self.make_predicate_nodes(&name, parent, p, 0),
+                            };
 
                             assert!(fns.len() > 0);
                             new_node_count += fns.len();
@@ -1643,11 +1808,30 @@ impl SqlToMirConverter {
                 }
 
                 if st.distinct {
-                    let group_by: Vec<Column> = qg
-                        .parameters()
-                        .into_iter()
-                        .map(|col| Column::from(col))
-                        .collect();
+                    // rows are only duplicates if they agree on every column that ends up in the
+                    // output, which includes the columns that arithmetic expressions are computed
+                    // from, and the query parameters that the view is keyed by.
+                    let mut distinct_cols: Vec<Column> = Vec::new();
+                    for oc in &qg.columns {
+                        match *oc {
+                            OutputColumn::Data(ref c) => distinct_cols.push(Column::from(c)),
+                            OutputColumn::Arithmetic(ref ac) => {
+                                for base in &[&ac.expression.left, &ac.expression.right] {
+                                    if let ArithmeticBase::Column(ref c) = **base {
+                                        distinct_cols.push(Column::from(c));
+                                    }
+                                }
+                            }
+                            OutputColumn::Literal(_) => (),
+                        }
+                    }
+                    distinct_cols.extend(qg.parameters().into_iter().map(|col| Column::from(col)));
+                    let mut group_by: Vec<Column> = Vec::new();
+                    for c in distinct_cols {
+                        if !group_by.contains(&c) {
+                            group_by.push(c);
+                        }
+                    }
 
                     let node = self.make_distinct_node(
                         &format!("q_{:x}_n{}{}", qg.signature().hash, new_node_count, uformat),
@@ -167,6 +167,11 @@ impl SqlIncorporator {
         }
     }
 
+    /// Returns true if a base table or view called `name` exists.
+    pub fn has_view(&self, name: &str) -> bool {
+        self.view_schemas.contains_key(name)
+    }
+
     pub fn is_leaf_address(&self, ni: NodeIndex) -> bool {
         self.leaf_addresses.values().any(|nn| *nn == ni)
     }
@@ -183,20 +188,17 @@ impl SqlIncorporator {
         query_name: &str,
         universe: UniverseId,
         st: &SelectStatement,
-    ) -> (QueryGraph, QueryGraphReuse) {
+    ) -> Result<(QueryGraph, QueryGraphReuse), String> {
         debug!(self.log, "Making QG for \"{}\"", query_name);
         trace!(self.log, "Query \"{}\": {:#?}", query_name, st);
 
-        let mut qg = match to_query_graph(st) {
-            Ok(qg) => qg,
-            Err(e) => panic!(e),
-        };
+        let mut qg = to_query_graph(st)?;
 
         trace!(self.log, "QG for \"{}\": {:#?}", query_name, qg);
 
         // if reuse is disabled, we're done
         if self.reuse_type == ReuseConfigType::NoReuse {
-            return (qg, QueryGraphReuse::None);
+            return Ok((qg, QueryGraphReuse::None));
         }
 
         // Do we already have this exact query or a subset of it in the same universe?
@@ -231,7 +233,7 @@ impl SqlIncorporator {
                         existing_qg,
                     );
 
-                    return (qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone()));
+                    return Ok((qg, QueryGraphReuse::ExactMatch(mir_query.leaf.clone())));
                 } else if existing_qg.signature() == qg.signature()
                     && existing_qg.parameters() != qg.parameters()
                 {
@@ -255,6 +257,11 @@ impl SqlIncorporator {
                                 predicates_match = false;
                             }
                         }
+                        if existing_qg.relations.contains_key(r)
+                            && existing_qg.relations[r].join_predicates != n.join_predicates
+                        {
+                            predicates_match = false;
+                        }
                     }
 
                     // if any of our columns are grouped expressions, we can't reuse here, since
@@ -331,14 +338,14 @@ impl SqlIncorporator {
                                         Some(project_columns)
                                     }
                                 };
-                                return (
+                                return Ok((
                                     qg,
                                     QueryGraphReuse::ReaderOntoExisting(
                                         mn,
                                         project_columns,
                                         params,
                                     ),
-                                );
+                                ));
                             }
                             None => (),
                         }
@@ -377,12 +384,12 @@ impl SqlIncorporator {
                 mir_queries.extend(mqs);
             }
 
-            return (qg, QueryGraphReuse::ExtendExisting(mir_queries));
+            return Ok((qg, QueryGraphReuse::ExtendExisting(mir_queries)));
         } else {
             info!(self.log, "No reuse opportunity, adding fresh query");
         }
 
-        (qg, QueryGraphReuse::None)
+        Ok((qg, QueryGraphReuse::None))
     }
 
     fn add_leaf_to_existing_query(
@@ -392,7 +399,7 @@ impl SqlIncorporator {
         final_query_node: MirNodeRef,
         project_columns: Option<Vec<Column>>,
         mut mig: &mut Migration,
-    ) -> QueryFlowParts {
+    ) -> Result<QueryFlowParts, String> {
         trace!(self.log, "Adding a new leaf below: {:?}", final_query_node);
 
         let mut mir = self.mir_converter.add_leaf_below(
@@ -406,11 +413,11 @@ impl SqlIncorporator {
 
         // push it into the flow graph using the migration in `mig`, and obtain `QueryFlowParts`.
         // Note that we don't need to optimize the MIR here, because the query is trivial.
-        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig);
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig)?;
 
         self.register_query(query_name, None, &mir, mig.universe());
 
-        qfp
+        Ok(qfp)
     }
 
     fn add_base_via_mir(
@@ -418,7 +425,7 @@ impl SqlIncorporator {
         query_name: &str,
         query: &SqlQuery,
         mut mig: &mut Migration,
-    ) -> QueryFlowParts {
+    ) -> Result<QueryFlowParts, String> {
         // first, compute the MIR representation of the SQL query
         let mut mir = self.mir_converter.named_base_to_mir(query_name, query);
 
@@ -427,7 +434,7 @@ impl SqlIncorporator {
         // no optimization, because standalone base nodes can't be optimized
 
         // push it into the flow graph using the migration in `mig`, and obtain `QueryFlowParts`
-        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig);
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig)?;
 
         // remember the schema in case we need it later
         // on base table schema change, we will overwrite the existing schema here.
@@ -441,7 +448,7 @@ impl SqlIncorporator {
 
         self.register_query(query_name, None, &mir, mig.universe());
 
-        qfp
+        Ok(qfp)
     }
 
     fn add_compound_query(
@@ -451,15 +458,12 @@ impl SqlIncorporator {
         is_leaf: bool,
         mut mig: &mut Migration,
     ) -> Result<QueryFlowParts, String> {
-        let subqueries: Vec<MirQuery> = query
-            .selects
-            .iter()
-            .enumerate()
-            .map(|(i, sq)| {
-                self.add_select_query(&format!("{}_csq_{}", query_name, i), &sq.1, false, mig)
-                    .1
-                    .unwrap()
-            }).collect();
+        let mut subqueries: Vec<MirQuery> = Vec::new();
+        for (i, sq) in query.selects.iter().enumerate() {
+            let (_, mir) =
+                self.add_select_query(&format!("{}_csq_{}", query_name, i), &sq.1, false, mig)?;
+            subqueries.push(mir.unwrap());
+        }
 
         let mut combined_mir_query = self.mir_converter.compound_query_to_mir(
             query_name,
@@ -470,7 +474,7 @@ impl SqlIncorporator {
             is_leaf,
         );
 
-        let qfp = mir_query_to_flow_parts(&mut combined_mir_query, &mut mig);
+        let qfp = mir_query_to_flow_parts(&mut combined_mir_query, &mut mig)?;
 
         self.register_query(query_name, None, &combined_mir_query, mig.universe());
 
@@ -485,8 +489,8 @@ impl SqlIncorporator {
         sq: &SelectStatement,
         is_leaf: bool,
         mig: &mut Migration,
-    ) -> (QueryFlowParts, Option<MirQuery>) {
-        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq);
+    ) -> Result<(QueryFlowParts, Option<MirQuery>), String> {
+        let (qg, reuse) = self.consider_query_graph(&query_name, mig.universe(), sq)?;
         match reuse {
             QueryGraphReuse::ExactMatch(mn) => {
                 let flow_node = mn.borrow().flow_node.as_ref().unwrap().address();
@@ -496,20 +500,25 @@ impl SqlIncorporator {
                     reused_nodes: vec![flow_node],
                     query_leaf: flow_node,
                 };
-                (qfp, None)
+                Ok((qfp, None))
             }
             QueryGraphReuse::ExtendExisting(mqs) => {
-                let qfp = self.extend_existing_query(&query_name, sq, qg, mqs, is_leaf, mig);
-                (qfp, None)
+                let qfp = self.extend_existing_query(&query_name, sq, qg, mqs, is_leaf, mig)?;
+                Ok((qfp, None))
             }
             QueryGraphReuse::ReaderOntoExisting(mn, project_columns, params) => {
-                let qfp =
-                    self.add_leaf_to_existing_query(&query_name, &params, mn, project_columns, mig);
-                (qfp, None)
+                let qfp = self.add_leaf_to_existing_query(
+                    &query_name,
+                    &params,
+                    mn,
+                    project_columns,
+                    mig,
+                )?;
+                Ok((qfp, None))
             }
             QueryGraphReuse::None => {
-                let (qfp, mir) = self.add_query_via_mir(&query_name, sq, qg, is_leaf, mig);
-                (qfp, Some(mir))
+                let (qfp, mir) = self.add_query_via_mir(&query_name, sq, qg, is_leaf, mig)?;
+                Ok((qfp, Some(mir)))
             }
         }
     }
@@ -521,7 +530,7 @@ impl SqlIncorporator {
         qg: QueryGraph,
         is_leaf: bool,
         mut mig: &mut Migration,
-    ) -> (QueryFlowParts, MirQuery) {
+    ) -> Result<(QueryFlowParts, MirQuery), String> {
         use mir::visualize::GraphViz;
         let universe = mig.universe();
         // no QG-level reuse possible, so we'll build a new query.
@@ -542,12 +551,28 @@ impl SqlIncorporator {
         trace!(self.log, "Optimized MIR:\n{}", mir.to_graphviz().unwrap());
 
         // push it into the flow graph using the migration in `mig`, and obtain `QueryFlowParts`
-        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig);
+        let qfp = mir_query_to_flow_parts(&mut mir, &mut mig)?;
 
         // register local state
         self.register_query(query_name, Some(qg), &mir, universe);
 
-        (qfp, mir)
+        Ok((qfp, mir))
+    }
+
+    /// Gives the query `old` the name `new`, keeping its MIR and dataflow nodes as they are.
+    pub fn rename_query(&mut self, old: &str, new: &str) {
+        fn rename<V>(map: &mut HashMap<String, V>, old: &str, new: &str) {
+            if let Some(v) = map.remove(old) {
+                map.insert(new.to_owned(), v);
+            }
+        }
+
+        debug!(self.log, "renaming query \"{}\" to \"{}\"", old, new);
+        rename(&mut self.leaf_addresses, old, new);
+        rename(&mut self.named_queries, old, new);
+        rename(&mut self.base_mir_queries, old, new);
+        rename(&mut self.view_schemas, old, new);
+        self.mir_converter.rename_query(old, new);
     }
 
     pub fn remove_query(&mut self, query_name: &str, mig: &Migration) -> Option<NodeIndex> {
@@ -662,7 +687,7 @@ impl SqlIncorporator {
         reuse_mirs: Vec<(u64, UniverseId)>,
         is_leaf: bool,
         mut mig: &mut Migration,
-    ) -> QueryFlowParts {
+    ) -> Result<QueryFlowParts, String> {
         use mir::reuse::merge_mir_for_queries;
         use mir::visualize::GraphViz;
         let universe = mig.universe();
@@ -709,7 +734,7 @@ impl SqlIncorporator {
             post_reuse_opt_mir.to_graphviz().unwrap()
         );
 
-        let qfp = mir_query_to_flow_parts(&mut post_reuse_opt_mir, &mut mig);
+        let qfp = mir_query_to_flow_parts(&mut post_reuse_opt_mir, &mut mig)?;
 
         info!(
             self.log,
@@ -719,7 +744,7 @@ impl SqlIncorporator {
         // register local state
         self.register_query(query_name, Some(qg), &post_reuse_opt_mir, universe);
 
-        qfp
+        Ok(qfp)
     }
 
     fn nodes_for_query(
@@ -731,13 +756,30 @@ impl SqlIncorporator {
         let name = match q {
             SqlQuery::CreateTable(ref ctq) => ctq.table.name.clone(),
             SqlQuery::Select(_) | SqlQuery::CompoundSelect(_) => format!("q_{}", self.num_queries),
-            _ => panic!("only CREATE TABLE and SELECT queries can be added to the graph!"),
+            _ => {
+                return Err("only CREATE TABLE and SELECT queries can be added to the graph".into())
+            }
         };
         self.nodes_for_named_query(q, name, is_leaf, mig)
     }
 
+    /// Returns the name of the view that holds the results of the query described by `qfp`.
+    ///
+    /// This is usually the query's own name. However, if the query exactly matched an existing
+    /// query, no new view was created for it, and the existing query's view must be used instead.
+    fn view_for(&self, qfp: &QueryFlowParts) -> String {
+        if self.view_schemas.contains_key(&qfp.name) {
+            return qfp.name.clone();
+        }
+        self.leaf_addresses
+            .iter()
+            .find(|&(name, &na)| na == qfp.query_leaf && self.view_schemas.contains_key(name))
+            .map(|(name, _)| name.clone())
+            .expect(&format!("no view holds the results of query \"{}\"", qfp.name))
+    }
+
     /// Runs some standard rewrite passes on the query.
-    fn rewrite_query(&mut self, q: SqlQuery, mig: &mut Migration) -> SqlQuery {
+    fn rewrite_query(&mut self, q: SqlQuery, mig: &mut Migration) -> Result<SqlQuery, String> {
         use crate::controller::sql::passes::alias_removal::AliasRemoval;
         use crate::controller::sql::passes::count_star_rewrite::CountStarRewrite;
         use crate::controller::sql::passes::implied_tables::ImpliedTableExpansion;
@@ -746,6 +788,8 @@ impl SqlIncorporator {
         use crate::controller::sql::passes::star_expansion::StarExpansion;
         use crate::controller::sql::passes::subqueries::SubQueries;
         use crate::controller::sql::query_utils::ReferredTables;
+        use crate::controller::sql::security::universe_view_name;
+        use nom_sql::{JoinRightSide, Table};
 
         // need to increment here so that each subquery has a unique name.
         // (subqueries call recursively into `nodes_for_named_query` via `add_parsed_query` below,
@@ -759,15 +803,14 @@ impl SqlIncorporator {
             use self::passes::subqueries::{
                 field_with_table_name, query_from_condition_base, Subquery,
             };
-            use nom_sql::{JoinRightSide, Table};
             match sq {
                 Subquery::InComparison(cond_base) => {
                     let (sq, column) = query_from_condition_base(&cond_base);
 
                     let qfp = self
                         .add_parsed_query(sq, None, false, mig)
-                        .expect("failed to add subquery");
-                    *cond_base = field_with_table_name(qfp.name.clone(), column);
+                        .map_err(|e| format!("failed to add subquery: {}", e))?;
+                    *cond_base = field_with_table_name(self.view_for(&qfp), column);
                 }
                 Subquery::InJoin(join_right_side) => {
                     *join_right_side = match *join_right_side {
@@ -778,10 +821,12 @@ impl SqlIncorporator {
                                     alias.clone(),
                                     false,
                                     mig,
-                                ).expect("failed to add subquery in join");
+                                ).map_err(|e| format!("failed to add subquery in join: {}", e))?;
+                            // the outer query refers to the derived table by its alias, which
+                            // the alias removal pass below maps onto the view's actual name.
                             JoinRightSide::Table(Table {
-                                name: qfp.name.clone(),
-                                alias: None,
+                                name: self.view_for(&qfp),
+                                alias: alias.clone(),
                             })
                         }
                         _ => unreachable!(),
@@ -807,19 +852,52 @@ impl SqlIncorporator {
             | ref q @ SqlQuery::DropTable(_)
             | ref q @ SqlQuery::Insert(_) => for t in &q.referred_tables() {
                 if !self.view_schemas.contains_key(&t.name) {
-                    panic!("query refers to unknown table \"{}\"", t.name);
+                    return Err(format!("query refers to unknown table \"{}\"", t.name));
                 }
             },
         }
 
+        // a query in a universe must read the universe's own versions of other views, since only
+        // those are subject to the universe's policies. the global views would let it see every
+        // row of the bases they are computed from.
+        let (id, group) = mig.universe();
+        if id != "global".into() {
+            if let SqlQuery::Select(ref mut sq) = fq {
+                let view_schemas = &self.view_schemas;
+                let use_universe_view = |t: &mut Table| {
+                    let name = universe_view_name(&t.name, &id, &group);
+                    if view_schemas.contains_key(&name) {
+                        // keep referring to the view by its old name in the rest of the query
+                        if t.alias.is_none() {
+                            t.alias = Some(t.name.clone());
+                        }
+                        t.name = name;
+                    }
+                };
+                for t in &mut sq.tables {
+                    use_universe_view(t);
+                }
+                for jc in &mut sq.join {
+                    match jc.right {
+                        JoinRightSide::Table(ref mut t) => use_universe_view(t),
+                        JoinRightSide::Tables(ref mut ts) => for t in ts {
+                            use_universe_view(t);
+                        },
+                        _ => (),
+                    }
+                }
+            }
+        }
+
         // Run some standard rewrite passes on the query. This makes the later work easier,
         // as we no longer have to consider complications like aliases.
-        fq.expand_table_aliases(mig.context())
+        Ok(fq
+            .expand_table_aliases(mig.context())
             .remove_negation()
             .coalesce_key_definitions()
             .expand_stars(&self.view_schemas)
             .expand_implied_tables(&self.view_schemas)
-            .rewrite_count_star(&self.view_schemas)
+            .rewrite_count_star(&self.view_schemas))
     }
 
     fn nodes_for_named_query(
@@ -829,7 +907,7 @@ impl SqlIncorporator {
         is_leaf: bool,
         mig: &mut Migration,
     ) -> Result<QueryFlowParts, String> {
-        let q = self.rewrite_query(q, mig);
+        let q = self.rewrite_query(q, mig)?;
 
         // TODO(larat): extend existing should handle policy nodes
         // if this is a selection, we compute its `QueryGraph` and consider the existing ones we
@@ -839,12 +917,11 @@ impl SqlIncorporator {
                 // NOTE(malte): We can't currently reuse complete compound select queries, since
                 // our reuse logic operates on `SqlQuery` structures. Their subqueries do get
                 // reused, however.
-                self.add_compound_query(&query_name, csq, is_leaf, mig)
-                    .unwrap()
+                self.add_compound_query(&query_name, csq, is_leaf, mig)?
             }
-            SqlQuery::Select(ref sq) => self.add_select_query(&query_name, sq, is_leaf, mig).0,
-            ref q @ SqlQuery::CreateTable { .. } => self.add_base_via_mir(&query_name, q, mig),
-            ref q @ _ => panic!("unhandled query type in recipe: {:?}", q),
+            SqlQuery::Select(ref sq) => self.add_select_query(&query_name, sq, is_leaf, mig)?.0,
+            ref q @ SqlQuery::CreateTable { .. } => self.add_base_via_mir(&query_name, q, mig)?,
+            ref q @ _ => return Err(format!("unhandled query type in recipe: {:?}", q)),
         };
 
         // record info about query
@@ -6,7 +6,7 @@ use nom_sql::{
 };
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::string::String;
 use std::vec::Vec;
@@ -135,12 +135,19 @@ pub struct JoinRef {
 pub struct QueryGraphNode {
     pub rel_name: String,
     pub predicates: Vec<ConditionExpression>,
+    /// Constant predicates from the `ON` clause of a left join with this relation on its right
+    /// side. Unlike `predicates`, these are applied *before* the join, since they only decide
+    /// which rows match; left rows without a match are still emitted, padded with nulls.
+    pub join_predicates: Vec<ConditionExpression>,
     pub columns: Vec<Column>,
     pub parameters: Vec<Column>,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq)]
 pub enum QueryGraphEdge {
+    /// An inner join on the given equality predicates. If there are none, the relations are only
+    /// related by non-equality predicates (which are kept as global predicates), and are joined as
+    /// a cross product that those predicates then filter.
     Join(Vec<ConditionTree>),
     LeftJoin(Vec<ConditionTree>),
     GroupBy(Vec<Column>),
@@ -238,6 +245,108 @@ fn split_conjunctions(ces: Vec<ConditionExpression>) -> Vec<ConditionExpression>
     new_ces
 }
 
+/// Flip the equi-join predicate `ct` if needed so that its left side refers to `left_table`.
+fn orient_join_predicate(
+    ct: &ConditionTree,
+    left_table: &str,
+    right_table: &str,
+) -> Result<ConditionTree, String> {
+    // the condition tree might specify tables in opposite order to their join order in the
+    // query; if so, flip them
+    // TODO(malte): this only deals with simple, flat join conditions for now.
+    let l = match *ct.left.as_ref() {
+        ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
+        _ => return Err(format!("join conditions must compare columns, not {}", ct.left)),
+    };
+    let r = match *ct.right.as_ref() {
+        ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
+        _ => return Err(format!("join conditions must compare columns, not {}", ct.right)),
+    };
+    if l.table.as_ref().map(|t| t == right_table).unwrap_or(false)
+        && r.table.as_ref().map(|t| t == left_table).unwrap_or(false)
+    {
+        Ok(ConditionTree {
+            operator: ct.operator.clone(),
+            left: ct.right.clone(),
+            right: ct.left.clone(),
+        })
+    } else {
+        Ok(ct.clone())
+    }
+}
+
+/// If `ce` compares columns of two different tables, returns those tables (in sorted order).
+fn compared_tables(ce: &ConditionExpression) -> Option<(String, String)> {
+    if let ConditionExpression::ComparisonOp(ref ct) = *ce {
+        if let ConditionExpression::Base(ConditionBase::Field(ref l)) = *ct.left.as_ref() {
+            if let ConditionExpression::Base(ConditionBase::Field(ref r)) = *ct.right.as_ref() {
+                match (&l.table, &r.table) {
+                    (&Some(ref lt), &Some(ref rt)) if lt < rt => {
+                        return Some((lt.clone(), rt.clone()))
+                    }
+                    (&Some(ref lt), &Some(ref rt)) if lt > rt => {
+                        return Some((rt.clone(), lt.clone()))
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether `ce` only compares columns of `table` against literals.
+fn constrains_only(ce: &ConditionExpression, table: &str) -> bool {
+    match *ce {
+        ConditionExpression::LogicalOp(ref ct) => {
+            constrains_only(&ct.left, table) && constrains_only(&ct.right, table)
+        }
+        ConditionExpression::Bracketed(ref inner) => constrains_only(inner, table),
+        ConditionExpression::ComparisonOp(ref ct) => match (ct.left.as_ref(), ct.right.as_ref()) {
+            (
+                &ConditionExpression::Base(ConditionBase::Field(ref f)),
+                &ConditionExpression::Base(ConditionBase::Literal(_)),
+            )
+            | (
+                &ConditionExpression::Base(ConditionBase::Literal(_)),
+                &ConditionExpression::Base(ConditionBase::Field(ref f)),
+            )
+            | (
+                &ConditionExpression::Base(ConditionBase::Field(ref f)),
+                &ConditionExpression::Base(ConditionBase::LiteralList(_)),
+            ) => f.table.as_ref().map(String::as_str) == Some(table),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether the relations `a` and `b` are (transitively) joined by `edges`.
+fn connected(edges: &HashMap<(String, String), QueryGraphEdge>, a: &str, b: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut frontier = vec![a];
+    while let Some(rel) = frontier.pop() {
+        if rel == b {
+            return true;
+        }
+        if !seen.insert(rel) {
+            continue;
+        }
+        for (&(ref src, ref dst), edge) in edges {
+            match *edge {
+                QueryGraphEdge::Join(_) | QueryGraphEdge::LeftJoin(_) => (),
+                QueryGraphEdge::GroupBy(_) => continue,
+            }
+            if src == rel {
+                frontier.push(dst);
+            } else if dst == rel {
+                frontier.push(src);
+            }
+        }
+    }
+    false
+}
+
 // 1. Extract any predicates with placeholder parameters. We push these down to the edge
 //    nodes, since we cannot instantiate the parameters inside the data flow graph (except for
 //    non-materialized nodes).
@@ -365,6 +474,15 @@ fn classify_conditionals(
                             // column/column comparison
                             if let ConditionBase::Field(ref lf) = *l {
                                 if lf.table.is_some()
+                                    && lf.table == rf.table
+                                    && tables
+                                        .contains(&Table::from(lf.table.as_ref().unwrap().as_str()))
+                                {
+                                    // both columns are from the same table, so this filters that
+                                    // table's rows like a comparison with a literal does
+                                    let e = local.entry(lf.table.clone().unwrap()).or_default();
+                                    e.push(ce.clone());
+                                } else if lf.table.is_some()
                                     && tables
                                         .contains(&Table::from(lf.table.as_ref().unwrap().as_str()))
                                     && rf.table.is_some()
@@ -384,8 +502,9 @@ fn classify_conditionals(
                                         }
                                         join.push(join_ct);
                                     } else {
-                                        // non-equi-join?
-                                        unimplemented!();
+                                        // non-equi-join; this is applied as a filter on the
+                                        // join's output, like any other global predicate
+                                        global.push(ce.clone());
                                     }
                                 } else {
                                     // not a comma join, just an ordinary comparison with a
@@ -404,8 +523,9 @@ fn classify_conditionals(
                                 params.push(lf.clone());
                             }
                         }
-                        // right-hand side is a non-placeholder literal, so this is a predicate
-                        ConditionBase::Literal(_) => {
+                        // right-hand side is a non-placeholder literal or a list of literals, so
+                        // this is a predicate
+                        ConditionBase::Literal(_) | ConditionBase::LiteralList(_) => {
                             if let ConditionBase::Field(ref lf) = *l {
                                 // we assume that implied table names have previously been expanded
                                 // and thus all non-computed columns carry table names
@@ -419,7 +539,6 @@ fn classify_conditionals(
                                 }
                             }
                         }
-                        ConditionBase::LiteralList(_) => (),
                         ConditionBase::NestedSelect(_) => unimplemented!(),
                     }
                 };
@@ -454,12 +573,22 @@ fn classify_conditionals(
 pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
     let mut qg = QueryGraph::new();
 
+    // the implied tables pass leaves columns it cannot find in any table without one
+    for field in &st.fields {
+        if let FieldDefinitionExpression::Col(ref c) = *field {
+            if c.table.is_none() && c.function.is_none() {
+                return Err(format!("no table in the query has a column named {}", c.name));
+            }
+        }
+    }
+
     // a handy closure for making new relation nodes
     let new_node =
         |rel: String, preds: Vec<ConditionExpression>, st: &SelectStatement| -> QueryGraphNode {
             QueryGraphNode {
                 rel_name: rel.clone(),
                 predicates: preds,
+                join_predicates: Vec::new(),
                 columns: st
                     .fields
                     .iter()
@@ -518,6 +647,7 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
     // 2. Add edges for each pair of joined relations. Note that we must keep track of the join
     //    predicates here already, but more may be added when processing the WHERE clause lateron.
     let mut join_predicates = Vec::new();
+    let mut join_residuals = Vec::new();
     let wrapcol = |tbl: &str, col: &str| -> Box<ConditionExpression> {
         let col = Column::from(format!("{}.{}", tbl, col).as_str());
         Box::new(ConditionExpression::Base(ConditionBase::Field(col)))
@@ -541,52 +671,109 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                         let mut tables_mentioned: Vec<String> =
                             cond.referred_tables().into_iter().map(|t| t.name).collect();
 
+                        if tables_mentioned.len() == 2 {
+                            // tables can appear in any order in the join predicate, but
+                            // we cannot just rely on that order, since it may lead us to
+                            // flip LEFT JOINs by accident (yes, this happened)
+                            if tables_mentioned[1] != table.name {
+                                // tables are in the wrong order in join predicate, swap
+                                tables_mentioned.swap(0, 1);
+                                if tables_mentioned[1] != table.name {
+                                    return Err(format!(
+                                        "join condition {} does not refer to joined table {}",
+                                        cond, table.name
+                                    ));
+                                }
+                            }
+                            left_table = tables_mentioned.remove(0);
+                            right_table = tables_mentioned.remove(0);
+                        } else if tables_mentioned.len() == 1 {
+                            // just one table mentioned --> this is a self-join
+                            left_table = tables_mentioned.remove(0);
+                            right_table = left_table.clone();
+                        } else {
+                            return Err(format!(
+                                "join condition {} refers to more than two tables",
+                                cond
+                            ));
+                        };
+
                         match *cond {
-                            ConditionExpression::ComparisonOp(ref ct) => {
-                                if tables_mentioned.len() == 2 {
-                                    // tables can appear in any order in the join predicate, but
-                                    // we cannot just rely on that order, since it may lead us to
-                                    // flip LEFT JOINs by accident (yes, this happened)
-                                    if tables_mentioned[1] != table.name {
-                                        // tables are in the wrong order in join predicate, swap
-                                        tables_mentioned.swap(0, 1);
-                                        assert_eq!(tables_mentioned[1], table.name);
+                            ConditionExpression::ComparisonOp(ref ct)
+                                if ct.operator == Operator::Equal =>
+                            {
+                                Some(orient_join_predicate(ct, &left_table, &right_table)?)
+                            }
+                            ConditionExpression::ComparisonOp(_)
+                            | ConditionExpression::LogicalOp(_)
+                            | ConditionExpression::Bracketed(_) => {
+                                // a non-equality or compound join condition. we key the join on
+                                // the first equality between the two tables (if any), and filter
+                                // its output by the rest of the condition. for a left join, that
+                                // would drop the null-padded rows, so the rest must instead be
+                                // constant predicates on the right relation that we can apply
+                                // before the join.
+                                let outer = match jc.operator {
+                                    JoinOperator::Join | JoinOperator::InnerJoin => false,
+                                    JoinOperator::LeftJoin => true,
+                                    _ => {
+                                        return Err(String::from(
+                                            "non-equality join conditions are only supported \
+                                             for inner and left joins",
+                                        ))
                                     }
-                                    left_table = tables_mentioned.remove(0);
-                                    right_table = tables_mentioned.remove(0);
-                                } else if tables_mentioned.len() == 1 {
-                                    // just one table mentioned --> this is a self-join
-                                    left_table = tables_mentioned.remove(0);
-                                    right_table = left_table.clone();
-                                } else {
-                                    unreachable!("more than 2 tables mentioned in join condition!");
                                 };
+                                if left_table == right_table {
+                                    return Err(format!(
+                                        "non-equality self-joins are not supported: {}",
+                                        cond
+                                    ));
+                                }
 
-                                // the condition tree might specify tables in opposite order to
-                                // their join order in the query; if so, flip them
-                                // TODO(malte): this only deals with simple, flat join
-                                // conditions for now.
-                                let l = match *ct.left.as_ref() {
-                                    ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
-                                    _ => unimplemented!(),
-                                };
-                                let r = match *ct.right.as_ref() {
-                                    ConditionExpression::Base(ConditionBase::Field(ref f)) => f,
-                                    _ => unimplemented!(),
-                                };
-                                if *l.table.as_ref().unwrap() == right_table
-                                    && *r.table.as_ref().unwrap() == left_table
-                                {
-                                    ConditionTree {
-                                        operator: ct.operator.clone(),
-                                        left: ct.right.clone(),
-                                        right: ct.left.clone(),
+                                let mut equi = None;
+                                for ce in split_conjunctions(vec![cond.clone()]) {
+                                    match ce {
+                                        ConditionExpression::ComparisonOp(ref ct)
+                                            if equi.is_none()
+                                                && ct.operator == Operator::Equal
+                                                && compared_tables(&ce).is_some() =>
+                                        {
+                                            equi = Some(orient_join_predicate(
+                                                ct,
+                                                &left_table,
+                                                &right_table,
+                                            )?);
+                                            continue;
+                                        }
+                                        _ => (),
+                                    }
+                                    if !outer {
+                                        join_residuals.push(ce);
+                                    } else if constrains_only(&ce, &right_table) {
+                                        qg.relations
+                                            .get_mut(&right_table)
+                                            .unwrap()
+                                            .join_predicates
+                                            .push(ce);
+                                    } else {
+                                        return Err(format!(
+                                            "left join conditions may only add constant \
+                                             predicates on the right relation, not {}",
+                                            ce
+                                        ));
                                     }
-                                } else {
-                                    ct.clone()
                                 }
+                                if outer && equi.is_none() {
+                                    return Err(format!(
+                                        "left join condition needs an equality between {} and {}",
+                                        left_table, right_table
+                                    ));
+                                }
+                                equi
+                            }
+                            _ => {
+                                return Err(format!("join condition {} is not a comparison", cond))
                             }
-                            _ => panic!("join condition is not a comparison!"),
                         }
                     }
                     JoinConstraint::Using(ref cols) => {
@@ -596,11 +783,11 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                         left_table = prev_table.as_ref().unwrap().clone();
                         right_table = table.name.clone();
 
-                        ConditionTree {
+                        Some(ConditionTree {
                             operator: Operator::Equal,
                             left: wrapcol(&left_table, &col.name),
                             right: wrapcol(&right_table, &col.name),
-                        }
+                        })
                     }
                 };
 
@@ -609,9 +796,11 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
                     .edges
                     .entry((left_table.clone(), right_table.clone()))
                     .or_insert_with(|| match jc.operator {
-                        JoinOperator::LeftJoin => QueryGraphEdge::LeftJoin(vec![join_pred]),
+                        JoinOperator::LeftJoin => {
+                            QueryGraphEdge::LeftJoin(vec![join_pred.unwrap()])
+                        }
                         JoinOperator::Join | JoinOperator::InnerJoin => {
-                            QueryGraphEdge::Join(vec![join_pred])
+                            QueryGraphEdge::Join(join_pred.into_iter().collect())
                         }
                         _ => unimplemented!(),
                     });
@@ -708,6 +897,28 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
         qg.global_predicates = global_predicates;
     }
 
+    // Parts of explicit join conditions that we don't key the join on are applied as global
+    // predicates too
+    qg.global_predicates.extend(join_residuals);
+
+    // Relations that are only related by non-equality predicates still need to be joined. There is
+    // no key to do so by, so we join them as a cross product and let the global predicates filter
+    // the result. This is expensive: every update on one side is joined against *all* rows on the
+    // other side, and the join's state for each side ends up under a single key.
+    let cross_joins: Vec<_> = qg
+        .global_predicates
+        .iter()
+        .filter_map(compared_tables)
+        .collect();
+    for (a, b) in cross_joins {
+        if !qg.relations.contains_key(&a) || !qg.relations.contains_key(&b) {
+            continue;
+        }
+        if !connected(&qg.edges, &a, &b) {
+            qg.edges.insert((a, b), QueryGraphEdge::Join(vec![]));
+        }
+    }
+
     // Adds a computed column to the query graph if the given column has a function:
     let add_computed_column = |query_graph: &mut QueryGraph, column: &Column| {
         match column.function {
@@ -798,6 +1009,11 @@ pub fn to_query_graph(st: &SelectStatement) -> Result<QueryGraph, String> {
 
         for (&(ref src, ref dst), edge) in sorted_edges {
             match *edge {
+                QueryGraphEdge::Join(ref jps) if jps.is_empty() => qg.join_order.push(JoinRef {
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    index: 0,
+                }),
                 QueryGraphEdge::Join(ref jps) => qg.join_order.extend(
                     jps.iter()
                         .enumerate()
@@ -102,7 +102,7 @@ impl Signature for QueryGraph {
         let mut attrs = HashSet::<&Column>::new();
         let mut attrs_vec = Vec::<&Column>::new();
         for n in self.relations.values() {
-            for p in &n.predicates {
+            for p in n.predicates.iter().chain(n.join_predicates.iter()) {
                 match *p {
                     ComparisonOp(ref ct) | LogicalOp(ref ct) => for c in &ct.contained_columns() {
                         attrs_vec.push(c);
@@ -48,6 +48,9 @@ impl ReferredTables for ConditionExpression {
                 }
                 None => (),
             },
+            ConditionExpression::Bracketed(ref inner) => return inner.referred_tables(),
+            ConditionExpression::Base(ConditionBase::Literal(_))
+            | ConditionExpression::Base(ConditionBase::LiteralList(_)) => (),
             _ => unimplemented!(),
         }
         tables
@@ -101,6 +101,12 @@ impl Finkelstein {
         for (name, ex_qgn) in &existing_qg.relations {
             let new_qgn = &new_qg.relations[name];
 
+            // predicates applied before a left join change which rows it pads with nulls, so
+            // they can't be compensated for by filtering the existing query's output
+            if ex_qgn.join_predicates != new_qgn.join_predicates {
+                return None;
+            }
+
             // iterate over predicates and ensure that each matching
             // one on the existing QG is implied by the new one
             for ep in &ex_qgn.predicates {
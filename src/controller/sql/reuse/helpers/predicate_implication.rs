@@ -148,7 +148,9 @@ fn predicate_implies(np: &ConditionTree, ep: &ConditionTree) -> bool {
                 ConditionExpression::Base(ConditionBase::Literal(Literal::String(ref ev))) => {
                     check_op_elimination(nv, ev, &np.operator, &ep.operator)
                 }
-                ConditionExpression::Base(ConditionBase::Literal(_)) => false,
+                ConditionExpression::Base(ConditionBase::Literal(_))
+                | ConditionExpression::Base(ConditionBase::LiteralList(_))
+                | ConditionExpression::Base(ConditionBase::Field(_)) => false,
                 _ => panic!("right-hand side of predicate must currently be literal"),
             }
         }
@@ -157,15 +159,32 @@ fn predicate_implies(np: &ConditionTree, ep: &ConditionTree) -> bool {
                 ConditionExpression::Base(ConditionBase::Literal(Literal::Integer(ref ev))) => {
                     check_op_elimination(nv, ev, &np.operator, &ep.operator)
                 }
-                ConditionExpression::Base(ConditionBase::Literal(_)) => false,
+                ConditionExpression::Base(ConditionBase::Literal(_))
+                | ConditionExpression::Base(ConditionBase::LiteralList(_))
+                | ConditionExpression::Base(ConditionBase::Field(_)) => false,
                 _ => panic!("right-hand side of predicate must currently be literal"),
             }
         }
         ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => match *ep.right {
             ConditionExpression::Base(ConditionBase::Literal(Literal::Null)) => true,
-            ConditionExpression::Base(ConditionBase::Literal(_)) => false,
+            ConditionExpression::Base(ConditionBase::Literal(_))
+            | ConditionExpression::Base(ConditionBase::LiteralList(_))
+            | ConditionExpression::Base(ConditionBase::Field(_)) => false,
             _ => panic!("right-hand side of predicate must currently be literal"),
         },
+        // x IN (values) implies x IN (more values)
+        ConditionExpression::Base(ConditionBase::LiteralList(ref nl)) => match *ep.right {
+            ConditionExpression::Base(ConditionBase::LiteralList(ref el)) => {
+                np.operator == ep.operator && nl.iter().all(|l| el.contains(l))
+            }
+            ConditionExpression::Base(ConditionBase::Literal(_))
+            | ConditionExpression::Base(ConditionBase::Field(_)) => false,
+            _ => panic!("right-hand side of predicate must currently be literal"),
+        },
+        // a comparison between two columns only implies the very same comparison
+        ConditionExpression::Base(ConditionBase::Field(_)) => {
+            np.operator == ep.operator && np.right == ep.right
+        }
         _ => panic!("right-hand side of predicate must currently be literal"),
     }
 }
@@ -203,6 +222,35 @@ mod tests {
         assert!(predicate_implies(&pc, &pa));
     }
 
+    #[test]
+    fn predicate_implication_in_list() {
+        use nom_sql::ConditionBase::*;
+        use nom_sql::ConditionExpression::*;
+        use nom_sql::Literal;
+
+        let list = |xs: &[i64]| xs.iter().map(|&x| Literal::Integer(x)).collect::<Vec<_>>();
+        let pa = ConditionTree {
+            operator: Operator::In,
+            left: Box::new(Base(Field(Column::from("a")))),
+            right: Box::new(Base(LiteralList(list(&[1, 2])))),
+        };
+        let pb = ConditionTree {
+            operator: Operator::In,
+            left: Box::new(Base(Field(Column::from("a")))),
+            right: Box::new(Base(LiteralList(list(&[1, 2, 3])))),
+        };
+        let pc = ConditionTree {
+            operator: Operator::Equal,
+            left: Box::new(Base(Field(Column::from("a")))),
+            right: Box::new(Base(Literal(Literal::Integer(1.into())))),
+        };
+
+        assert!(predicate_implies(&pa, &pb));
+        assert!(!predicate_implies(&pb, &pa));
+        assert!(!predicate_implies(&pa, &pc));
+        assert!(!predicate_implies(&pc, &pa));
+    }
+
     #[test]
     fn complex_predicate_implication_or() {
         use nom_sql::ConditionBase::*;
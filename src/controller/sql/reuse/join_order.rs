@@ -117,11 +117,13 @@ fn chains_to_order(chains: Vec<JoinChain>, order: &mut Vec<JoinRef>) {
     mem::replace(order, new_order);
 }
 
-fn from_join_ref<'a>(jref: &JoinRef, qg: &'a QueryGraph) -> &'a ConditionTree {
+fn from_join_ref<'a>(jref: &JoinRef, qg: &'a QueryGraph) -> Option<&'a ConditionTree> {
     let edge = qg.edges.get(&(jref.src.clone(), jref.dst.clone())).unwrap();
     match *edge {
-        QueryGraphEdge::Join(ref jps) => jps.get(jref.index).unwrap(),
-        QueryGraphEdge::LeftJoin(ref jps) => jps.get(jref.index).unwrap(),
+        // cross joins have no predicate
+        QueryGraphEdge::Join(ref jps) if jps.is_empty() => None,
+        QueryGraphEdge::Join(ref jps) => Some(jps.get(jref.index).unwrap()),
+        QueryGraphEdge::LeftJoin(ref jps) => Some(jps.get(jref.index).unwrap()),
         QueryGraphEdge::GroupBy(_) => unreachable!(),
     }
 }
@@ -147,12 +149,22 @@ pub fn reorder_joins(qg: &mut QueryGraph, reuse_candidates: &Vec<(ReuseType, (u6
                 continue;
             }
 
-            let ejp = from_join_ref(&existing_jref, eqg);
+            // we don't try to find equivalent cross joins
+            let ejp = match from_join_ref(&existing_jref, eqg) {
+                Some(ejp) => ejp,
+                None => {
+                    existing_chain.stopped = true;
+                    continue;
+                }
+            };
 
             // look in the new query graph for an equivalent join predicate.
             let mut found = false;
             for new_jref in qg.join_order.iter() {
-                let njp = from_join_ref(&new_jref, qg);
+                let njp = match from_join_ref(&new_jref, qg) {
+                    Some(njp) => njp,
+                    None => continue,
+                };
                 // if we find an equivalent join, add it to the new query's join chains
                 if predicate_is_equivalent(njp, ejp) {
                     extend_chains(&mut shared_join_chains, new_jref);
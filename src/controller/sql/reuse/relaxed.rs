@@ -143,6 +143,11 @@ impl Relaxed {
             }
             let new_qgn = &new_qg.relations[name];
 
+            // the existing query's joins are only reusable if they were fed the same rows
+            if ex_qgn.join_predicates != new_qgn.join_predicates {
+                return None;
+            }
+
             // iterate over predicates and ensure that each
             // matching one on the existing QG is implied by the new one
             for ep in &ex_qgn.predicates {
@@ -28,6 +28,15 @@ impl Universe {
     }
 }
 
+/// Returns the name of a universe's own version of the view `name`. Unlike the global view, that
+/// version only contains the rows that the universe's policies allow it to see.
+pub fn universe_view_name(name: &str, id: &DataType, group: &Option<DataType>) -> String {
+    match *group {
+        Some(ref g) => format!("{}_{}{}", name, g.to_string(), id.to_string()),
+        None => format!("{}_u{}", name, id.to_string()),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RewritePolicy {
     pub value: String,
@@ -127,7 +136,7 @@ impl Multiverse for SqlIncorporator {
             }
 
             trace!(self.log, "Adding row policy {:?}", policy.name());
-            let predicate = self.rewrite_query(policy.predicate(), mig);
+            let predicate = self.rewrite_query(policy.predicate(), mig).unwrap();
             let st = match predicate {
                 SqlQuery::Select(ref st) => st,
                 _ => unreachable!(),
@@ -37,4 +37,6 @@ pub enum CoordinationPayload {
     RemoveDomain,
     /// Domain connectivity gossip.
     DomainBooted((DomainIndex, usize), SocketAddr),
+    /// Tell a worker that it has been decommissioned, and should stop sending heartbeats.
+    Decommission,
 }
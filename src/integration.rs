@@ -1,4 +1,5 @@
-use basics::DataType;
+use api::{RecipeInfo, StatementResult};
+use basics::{DataType, MaterializationStatus, NodeIndex};
 use consensus::LocalAuthority;
 use crate::controller::recipe::Recipe;
 use crate::controller::sql::SqlIncorporator;
@@ -9,12 +10,13 @@ use dataflow::ops::identity::Identity;
 use dataflow::ops::join::JoinSource::*;
 use dataflow::ops::join::{Join, JoinSource, JoinType};
 use dataflow::ops::project::Project;
+use dataflow::ops::recorder::ConnectRecorder;
 use dataflow::ops::union::Union;
 use dataflow::{DurabilityMode, PersistenceParameters};
 
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{env, thread};
 
 const DEFAULT_SETTLE_TIME_MS: u64 = 200;
@@ -228,6 +230,202 @@ fn base_mutation() {
     );
 }
 
+#[test]
+fn base_update_propagates_single_change() {
+    let mut g = build_local("base_update_propagates_single_change");
+    g.migrate(|mig| {
+        let item = mig.add_base(
+            "item",
+            &["id", "cat", "price"],
+            Base::new(vec![]).with_key(vec![0]),
+        );
+        let unkeyed = mig.add_base("unkeyed", &["id", "price"], Base::default());
+        mig.maintain_anonymous(unkeyed, &[0]);
+
+        let count = mig.add_ingredient(
+            "count",
+            &["cat", "n"],
+            Aggregation::COUNT.over(item, 0, &[1]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+        let sum = mig.add_ingredient(
+            "sum",
+            &["cat", "total"],
+            Aggregation::SUM.over(item, 2, &[1]),
+        );
+        mig.maintain_anonymous(sum, &[0]);
+    });
+
+    let mut countq = g.view("count").unwrap();
+    let mut sumq = g.view("sum").unwrap();
+    let mut item = g.table("item").unwrap();
+    let mut unkeyed = g.table("unkeyed").unwrap();
+
+    item.insert(vec![1.into(), 10.into(), 2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        sumq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 2.into()]]
+    );
+
+    // a plain value sets the column
+    item.update(vec![1.into()], vec![(2, DataType::from(5))]).unwrap();
+    sleep();
+
+    // the update must show up as exactly one net change: the row never disappears, so the count
+    // for its group stays at one, and the sum reflects only the new price.
+    assert_eq!(
+        countq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 1.into()]]
+    );
+    assert_eq!(
+        sumq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 5.into()]]
+    );
+
+    // updates on bases without a primary key are rejected
+    match unkeyed.update(vec![1.into()], vec![(1, DataType::from(5))]) {
+        Err(api::TableError::NoPrimaryKey(ref t)) if t == "unkeyed" => {}
+        r => panic!("expected NoPrimaryKey error, got {:?}", r),
+    }
+}
+
+#[test]
+fn base_upsert() {
+    let mut g = build_local("base_upsert");
+    g.migrate(|mig| {
+        let item = mig.add_base(
+            "item",
+            &["id", "cat", "price"],
+            Base::new(vec![]).with_key(vec![0]),
+        );
+        mig.maintain_anonymous(item, &[0]);
+        let unkeyed = mig.add_base("unkeyed", &["id", "price"], Base::default());
+        mig.maintain_anonymous(unkeyed, &[0]);
+
+        let count = mig.add_ingredient(
+            "count",
+            &["cat", "n"],
+            Aggregation::COUNT.over(item, 0, &[1]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+    });
+
+    let mut itemq = g.view("item").unwrap();
+    let mut countq = g.view("count").unwrap();
+    let mut item = g.table("item").unwrap();
+    let mut unkeyed = g.table("unkeyed").unwrap();
+
+    // upserting a row that does not exist inserts it
+    item.upsert(vec![1.into(), 10.into(), 2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        itemq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into(), 2.into()]]
+    );
+    assert_eq!(
+        countq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 1.into()]]
+    );
+
+    // upserting a row that exists replaces it
+    item.upsert(vec![1.into(), 10.into(), 3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        itemq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into(), 3.into()]]
+    );
+    assert_eq!(
+        countq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 1.into()]]
+    );
+
+    // the replacement may also move the row to another group
+    item.upsert(vec![1.into(), 20.into(), 3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        countq.lookup(&[20.into()], true).unwrap(),
+        vec![vec![20.into(), 1.into()]]
+    );
+    assert!(
+        countq
+            .lookup(&[10.into()], true)
+            .unwrap()
+            .iter()
+            .all(|r| r[1] == 0.into())
+    );
+
+    // upserts on bases without a primary key are rejected
+    match unkeyed.upsert(vec![1.into(), 2.into()]) {
+        Err(api::TableError::NoPrimaryKey(ref t)) if t == "unkeyed" => {}
+        r => panic!("expected NoPrimaryKey error, got {:?}", r),
+    }
+}
+
+#[test]
+fn base_delete_where() {
+    let mut g = build_local("base_delete_where");
+    g.migrate(|mig| {
+        let item = mig.add_base(
+            "item",
+            &["id", "cat", "status"],
+            Base::new(vec![]).with_key(vec![0]),
+        );
+        mig.maintain_anonymous(item, &[0]);
+
+        let count = mig.add_ingredient(
+            "count",
+            &["cat", "n"],
+            Aggregation::COUNT.over(item, 0, &[1]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+    });
+
+    let mut itemq = g.view("item").unwrap();
+    let mut countq = g.view("count").unwrap();
+    let mut item = g.table("item").unwrap();
+
+    item.insert_all(vec![
+        vec![1.into(), 10.into(), "live".into()],
+        vec![2.into(), 10.into(), "expired".into()],
+        vec![3.into(), 10.into(), "expired".into()],
+        vec![4.into(), 20.into(), "expired".into()],
+        vec![5.into(), 20.into(), "live".into()],
+    ]).unwrap();
+    sleep();
+    assert_eq!(
+        countq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 3.into()]]
+    );
+
+    // there is no index on status, so this scans the base
+    item.delete_where(vec![(2, "expired".into())]).unwrap();
+    sleep();
+
+    for id in 1..6 {
+        let rows = itemq.lookup(&[id.into()], true).unwrap();
+        if id == 1 || id == 5 {
+            assert_eq!(rows.len(), 1);
+        } else {
+            assert!(rows.is_empty(), "row {} was not deleted", id);
+        }
+    }
+    assert_eq!(
+        countq.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 1.into()]]
+    );
+    assert_eq!(
+        countq.lookup(&[20.into()], true).unwrap(),
+        vec![vec![20.into(), 1.into()]]
+    );
+
+    // predicates on the primary key use the base's index
+    item.delete_where(vec![(0, 5.into())]).unwrap();
+    sleep();
+    assert!(itemq.lookup(&[5.into()], true).unwrap().is_empty());
+    assert_eq!(itemq.lookup(&[1.into()], true).unwrap().len(), 1);
+}
+
 #[test]
 fn shared_interdomain_ancestor() {
     // set up graph
@@ -279,6 +477,66 @@ fn shared_interdomain_ancestor() {
     );
 }
 
+#[test]
+fn on_connected_sees_parents() {
+    let mut g = build_local("on_connected_sees_parents");
+    let connected = Arc::new(Mutex::new(Vec::new()));
+    let c = connected.clone();
+    let (a, b) = g.migrate(move |mig| {
+        let a = mig.add_base("a", &["a", "x"], Base::default());
+        let b = mig.add_base("b", &["b", "y"], Base::default());
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("j", &["id", "x", "y"], ConnectRecorder::new(j, c));
+        mig.maintain_anonymous(j, &[0]);
+        (a, b)
+    });
+
+    // the join was connected exactly once, when both its parents were already in the graph
+    assert_eq!(*connected.lock().unwrap(), vec![vec![a, b]]);
+}
+
+#[test]
+fn failed_migration_is_reported() {
+    let mut g = build_local("failed_migration_is_reported");
+    let (a, b) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "x"], Base::default());
+        let b = mig.add_base("b", &["b", "y"], Base::default());
+        (a, b)
+    });
+
+    // the new base's domain can't boot before it times out, so the whole migration, join and
+    // all, should be refused
+    let res = g.try_migrate(move |mig| {
+        mig.set_domain_boot_timeout(Some(Duration::from_millis(0)));
+        let c = mig.add_base("c", &["c", "z"], Base::default());
+        mig.maintain_anonymous(c, &[0]);
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("j", &["id", "x", "y"], j);
+        mig.maintain_anonymous(j, &[0]);
+    });
+    assert!(res.unwrap_err().contains("failed to boot"));
+    assert!(!g.outputs().unwrap().contains_key("c"));
+    assert!(!g.outputs().unwrap().contains_key("j"));
+
+    // and the controller should still accept migrations afterwards
+    g.migrate(move |mig| {
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("j", &["id", "x", "y"], j);
+        mig.maintain_anonymous(j, &[0]);
+    });
+
+    let mut muta = g.table("a").unwrap();
+    let mut mutb = g.table("b").unwrap();
+    let mut jq = g.view("j").unwrap();
+    muta.insert(vec![1.into(), 2.into()]).unwrap();
+    mutb.insert(vec![1.into(), 3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        jq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into(), 3.into()]]
+    );
+}
+
 #[test]
 fn it_works_w_mat() {
     // set up graph
@@ -546,6 +804,349 @@ fn it_works_with_vote() {
     );
 }
 
+#[test]
+fn it_works_with_derived_table_join() {
+    let mut g = build_local("it_works_with_derived_table_join");
+    // the derived table is identical to `VoteCount`, so it reuses that query's view, but must
+    // still be referred to by its alias.
+    let sql = "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        CREATE TABLE Vote (article_id int, uid int);
+
+        QUERY VoteCount: SELECT Vote.article_id, COUNT(uid) AS votes \
+                    FROM Vote GROUP BY Vote.article_id;
+        QUERY ArticleWithVoteCount: SELECT Article.id, title, vc.votes AS votes \
+                    FROM Article \
+                    JOIN (SELECT Vote.article_id, COUNT(uid) AS votes \
+                          FROM Vote GROUP BY Vote.article_id) AS vc \
+                    ON (Article.id = vc.article_id) WHERE Article.id = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut article = g.table("Article").unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    let mut awvc = g.view("ArticleWithVoteCount").unwrap();
+
+    article.insert(vec![1i64.into(), "a".into()]).unwrap();
+    article.insert(vec![2i64.into(), "b".into()]).unwrap();
+    vote.insert(vec![1i64.into(), 1.into()]).unwrap();
+    vote.insert(vec![1i64.into(), 2.into()]).unwrap();
+    vote.insert(vec![2i64.into(), 1.into()]).unwrap();
+    sleep();
+
+    assert_eq!(
+        awvc.lookup(&[1i64.into()], true).unwrap(),
+        vec![vec![1i64.into(), "a".into(), 2.into()]]
+    );
+    assert_eq!(
+        awvc.lookup(&[2i64.into()], true).unwrap(),
+        vec![vec![2i64.into(), "b".into(), 1.into()]]
+    );
+}
+
+#[test]
+fn recipe_parse_errors_name_statement() {
+    let mut g = build_local("recipe_parse_errors_name_statement");
+    let sql = "
+        CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+        QUERY Broken: SELEC id FROM Article WHERE id = ?;
+    ";
+    let e = g.install_recipe(sql).unwrap_err();
+    let cause = e
+        .causes()
+        .map(|c| c.to_string())
+        .find(|c| c.starts_with("failed to parse recipe"))
+        .unwrap();
+    assert!(
+        cause.contains("statement 2 on line 3"),
+        "unexpected error: {}",
+        cause
+    );
+    assert!(
+        cause.contains("QUERY Broken: SELEC id FROM Article WHERE id = ?;"),
+        "unexpected error: {}",
+        cause
+    );
+}
+
+#[test]
+fn recipe_rejects_unknown_relations() {
+    let mut g = build_local("recipe_rejects_unknown_relations");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;",
+    ).unwrap();
+    let graph = g.graphviz().unwrap();
+
+    let e = g
+        .extend_recipe("QUERY ArticleTitles: SELECT id, title FROM Artcle;")
+        .unwrap_err();
+    assert!(
+        format!("{:?}", e).contains("unknown relation Artcle referenced by query ArticleTitles"),
+        "unexpected error: {:?}",
+        e
+    );
+    assert_eq!(g.graphviz().unwrap(), graph);
+
+    // relations defined earlier in the same recipe are known
+    g.extend_recipe(
+        "CREATE TABLE Vote (aid int, uid int);
+         VoteCount: SELECT aid, COUNT(uid) AS votes FROM Vote GROUP BY aid;
+         QUERY ArticleWithVotes: SELECT Article.id, VoteCount.votes FROM Article
+             JOIN VoteCount ON (Article.id = VoteCount.aid) WHERE Article.id = ?;",
+    ).unwrap();
+    assert!(g.view("ArticleWithVotes").is_ok());
+}
+
+#[test]
+fn recipe_rejects_conflicting_base_definitions() {
+    let mut g = build_local("recipe_rejects_conflicting_base_definitions");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;",
+    ).unwrap();
+    let graph = g.graphviz().unwrap();
+
+    // repeating the definition of an existing table changes nothing
+    let ar = g
+        .extend_recipe("CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));")
+        .unwrap();
+    assert_eq!(ar.expressions_added, 0);
+    assert_eq!(g.graphviz().unwrap(), graph);
+
+    // changing the type of one of its columns does not
+    let e = g
+        .extend_recipe("CREATE TABLE Article (id int, title int, PRIMARY KEY(id));")
+        .unwrap_err();
+    assert!(
+        format!("{:?}", e).contains("cannot redefine table Article: column title changes type"),
+        "unexpected error: {:?}",
+        e
+    );
+    assert_eq!(g.graphviz().unwrap(), graph);
+}
+
+#[test]
+fn validate_recipe_does_not_migrate() {
+    let mut g = build_local("validate_recipe_does_not_migrate");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;",
+    ).unwrap();
+    let graph = g.graphviz().unwrap();
+
+    // a valid extension reports what it would add, but adds nothing
+    let add = "QUERY ArticleByTitle: SELECT id, title FROM Article WHERE title = ?;";
+    let r = g.validate_recipe(add).unwrap();
+    assert_eq!(r.expressions_added, 1);
+    assert_eq!(r.expressions_removed, 0);
+    assert!(r.new_nodes.contains_key("ArticleByTitle"));
+    assert_eq!(g.graphviz().unwrap(), graph);
+    assert!(g.view("ArticleByTitle").is_err());
+
+    // an unresolved column is reported rather than taking down the controller
+    let e = g
+        .validate_recipe("QUERY Broken: SELECT nope FROM Article;")
+        .unwrap_err();
+    let cause = e
+        .causes()
+        .map(|c| c.to_string())
+        .find(|c| c.starts_with("failed to plan recipe"))
+        .unwrap();
+    assert!(cause.contains("nope"), "unexpected error: {}", cause);
+    assert_eq!(g.graphviz().unwrap(), graph);
+
+    // dropping a base that a retained query reads from is a conflict
+    let e = g
+        .validate_install_recipe("QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;")
+        .unwrap_err();
+    assert!(format!("{:?}", e).contains("cannot remove base table(s) Article"));
+
+    // planning a column change to a base does not change the base either
+    g.validate_install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), body text, PRIMARY KEY(id));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;",
+    ).unwrap();
+    assert_eq!(g.graphviz().unwrap(), graph);
+    assert_eq!(g.table("Article").unwrap().columns(), &["id", "title"]);
+
+    // the recipe itself is unchanged, so the extension still applies for real
+    let r = g.extend_recipe(add).unwrap();
+    assert_eq!(r.expressions_added, 1);
+    let mut mutator = g.table("Article").unwrap();
+    mutator.insert(vec![1.into(), "a".into()]).unwrap();
+    sleep();
+    let mut q = g.view("ArticleByTitle").unwrap();
+    assert_eq!(
+        q.lookup(&["a".into()], true).unwrap(),
+        vec![vec![1.into(), "a".into()]]
+    );
+}
+
+#[test]
+fn base_schema_describes_keys() {
+    let mut g = build_local("base_schema_describes_keys");
+    g.install_recipe(
+        "CREATE TABLE Vote (aid int, uid int, comment varchar(255), PRIMARY KEY(aid, uid));
+         CREATE TABLE Log (msg text);
+         QUERY Votes: SELECT aid, uid FROM Vote WHERE aid = ?;",
+    ).unwrap();
+
+    let schema = g.base_schema("Vote").unwrap();
+    assert_eq!(schema.name, "Vote");
+    assert_eq!(schema.columns, vec!["aid", "uid", "comment"]);
+    assert_eq!(schema.primary_key, vec!["aid", "uid"]);
+    assert!(schema.dropped.is_empty());
+    let types: Vec<String> = schema
+        .types
+        .into_iter()
+        .map(|t| t.unwrap().to_lowercase())
+        .collect();
+    assert!(types[0].contains("int"), "{:?}", types);
+    assert!(types[1].contains("int"), "{:?}", types);
+    assert!(types[2].contains("varchar"), "{:?}", types);
+
+    let schema = g.base_schema("Log").unwrap();
+    assert_eq!(schema.columns, vec!["msg"]);
+    assert!(schema.primary_key.is_empty());
+
+    assert!(g.base_schema("Votes").is_err());
+    assert!(g.base_schema("Nope").is_err());
+}
+
+#[test]
+fn insert_fills_column_defaults() {
+    let mut g = build_local("insert_fills_column_defaults");
+    g.install_recipe(
+        "CREATE TABLE Post (id int, title varchar(255) NOT NULL, score int NOT NULL DEFAULT '10', \
+             posted datetime NOT NULL DEFAULT CURRENT_TIMESTAMP, PRIMARY KEY(id));
+         QUERY Posts: SELECT id, title, score, posted FROM Post WHERE id = ?;",
+    ).unwrap();
+    let mut post = g.table("Post").unwrap();
+    let mut posts = g.view("Posts").unwrap();
+
+    // score and posted are left out, and should be filled in by the base table
+    post.insert(vec![1.into(), "hello".into()]).unwrap();
+    post.insert(vec![2.into(), "world".into(), 3.into()]).unwrap();
+    sleep();
+
+    let rows = posts.lookup(&[1.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][1], "hello".into());
+    assert_eq!(rows[0][2], 10.into());
+    match rows[0][3] {
+        DataType::Timestamp(_) => {}
+        ref dt => panic!("expected the insert time, got {:?}", dt),
+    }
+
+    let rows = posts.lookup(&[2.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][2], 3.into());
+
+    // title has no default, and id is the primary key
+    match post.insert(vec![3.into()]) {
+        Err(api::TableError::MissingColumn(ref c)) if c == "title" => {}
+        r => panic!("expected title to be required, got {:?}", r),
+    }
+    match post.insert(Vec::<DataType>::new()) {
+        Err(api::TableError::MissingColumn(ref c)) if c == "id" => {}
+        r => panic!("expected id to be required, got {:?}", r),
+    }
+}
+
+#[test]
+fn insert_by_column_name() {
+    let mut g = build_local("insert_by_column_name");
+    g.install_recipe(
+        "CREATE TABLE Post (id int, title varchar(255) NOT NULL, score int DEFAULT '10', \
+             author varchar(255), PRIMARY KEY(id));
+         QUERY Posts: SELECT id, title, score, author FROM Post WHERE id = ?;",
+    ).unwrap();
+    let mut post = g.table("Post").unwrap();
+    let mut posts = g.view("Posts").unwrap();
+    assert_eq!(post.columns(), &["id", "title", "score", "author"]);
+
+    let mut row = HashMap::new();
+    row.insert("author", "alice".into());
+    row.insert("score", 3.into());
+    row.insert("id", 1.into());
+    row.insert("title", "hello".into());
+    post.insert_named(row).unwrap();
+
+    // columns that are left out get their default, or NULL if they have none
+    let mut row = HashMap::new();
+    row.insert("title", "world".into());
+    row.insert("id", 2.into());
+    post.insert_named(row).unwrap();
+    let mut row = HashMap::new();
+    row.insert("author", "bob".into());
+    row.insert("title", "again".into());
+    row.insert("id", 3.into());
+    post.insert_named(row).unwrap();
+    sleep();
+
+    assert_eq!(
+        posts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "hello".into(), 3.into(), "alice".into()]]
+    );
+    assert_eq!(
+        posts.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "world".into(), 10.into(), DataType::None]]
+    );
+    assert_eq!(
+        posts.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), "again".into(), 10.into(), "bob".into()]]
+    );
+
+    let mut row = HashMap::new();
+    row.insert("id", 4.into());
+    row.insert("nope", 1.into());
+    match post.insert_named(row) {
+        Err(api::TableError::UnknownColumn(ref c)) if c == "nope" => {}
+        r => panic!("expected an unknown column, got {:?}", r),
+    }
+    let mut row = HashMap::new();
+    row.insert("id", 4.into());
+    row.insert("author", "carol".into());
+    match post.insert_named(row) {
+        Err(api::TableError::MissingColumn(ref c)) if c == "title" => {}
+        r => panic!("expected title to be required, got {:?}", r),
+    }
+    let mut row = HashMap::new();
+    row.insert("title", "anonymous".into());
+    match post.insert_named(row) {
+        Err(api::TableError::MissingColumn(ref c)) if c == "id" => {}
+        r => panic!("expected id to be required, got {:?}", r),
+    }
+}
+
+#[test]
+fn insert_by_column_name_fills_timestamps() {
+    let mut g = build_local("insert_by_column_name_fills_timestamps");
+    g.install_recipe(
+        "CREATE TABLE Post (id int, posted datetime DEFAULT CURRENT_TIMESTAMP, \
+             title varchar(255), PRIMARY KEY(id));
+         QUERY Posts: SELECT id, posted, title FROM Post WHERE id = ?;",
+    ).unwrap();
+    let mut post = g.table("Post").unwrap();
+    let mut posts = g.view("Posts").unwrap();
+
+    // posted is not the last column, but is still given the time the base got the row
+    let mut row = HashMap::new();
+    row.insert("title", "hello".into());
+    row.insert("id", 1.into());
+    post.insert_named(row).unwrap();
+    sleep();
+
+    let rows = posts.lookup(&[1.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][2], "hello".into());
+    match rows[0][1] {
+        DataType::Timestamp(_) => {}
+        ref dt => panic!("expected the insert time, got {:?}", dt),
+    }
+}
+
 #[test]
 fn it_works_with_double_query_through() {
     let mut builder = ControllerBuilder::default();
@@ -665,100 +1266,2179 @@ fn double_shuffle() {
     ";
     g.install_recipe(sql).unwrap();
 
-    let mut car_mutator = g.table("Car").unwrap();
-    let mut price_mutator = g.table("Price").unwrap();
-    let mut getter = g.view("CarPrice").unwrap();
-    let cid = 1;
-    let pid = 1;
-    let price = 100;
+    let mut car_mutator = g.table("Car").unwrap();
+    let mut price_mutator = g.table("Price").unwrap();
+    let mut getter = g.view("CarPrice").unwrap();
+    let cid = 1;
+    let pid = 1;
+    let price = 100;
+
+    price_mutator
+        .insert(vec![pid.into(), price.into()])
+        .unwrap();
+    car_mutator.insert(vec![cid.into(), pid.into()]).unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    // Retrieve the result of the count query:
+    let result = getter.lookup(&[cid.into()], true).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][1], price.into());
+}
+
+#[test]
+fn shuffle_both_join_inputs() {
+    // both bases are sharded by their primary keys, and joined on a column that neither is
+    // sharded by, so both must be shuffled before the join
+    let mut g = build_local("shuffle_both_join_inputs");
+    let sql = "
+        CREATE TABLE Post (id int, city int, title text, PRIMARY KEY(id));
+        CREATE TABLE Profile (uid int, city int, name text, PRIMARY KEY(uid));
+        QUERY LocalPost: SELECT Post.id, title, name FROM Post \
+            JOIN Profile ON Post.city = Profile.city WHERE Post.id = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut post = g.table("Post").unwrap();
+    let mut profile = g.table("Profile").unwrap();
+    for city in 0..3 {
+        let name = format!("resident{}", city);
+        profile
+            .insert(vec![(10 + city).into(), city.into(), name.into()])
+            .unwrap();
+    }
+    for id in 0..12 {
+        let title = format!("post{}", id);
+        post.insert(vec![id.into(), (id % 3).into(), title.into()])
+            .unwrap();
+    }
+    sleep();
+
+    let snapshot = g.graph_snapshot().unwrap();
+    let sharders = snapshot
+        .nodes
+        .iter()
+        .filter(|n| n.operator == "sharder")
+        .count();
+    assert!(sharders >= 2);
+    assert!(
+        snapshot
+            .nodes
+            .iter()
+            .filter(|n| n.operator.contains("⋈"))
+            .all(|n| n.sharding.starts_with("ByColumn"))
+    );
+
+    let mut getter = g.view("LocalPost").unwrap();
+    for id in 0..12 {
+        let title = format!("post{}", id);
+        let name = format!("resident{}", id % 3);
+        let result = getter.lookup(&[id.into()], true).unwrap();
+        assert_eq!(result, vec![vec![id.into(), title.into(), name.into()]]);
+    }
+}
+
+#[test]
+fn it_works_with_arithmetic_aliases() {
+    let mut g = build_local("it_works_with_arithmetic_aliases");
+    let sql = "
+        CREATE TABLE Price (pid int, cent_price int, PRIMARY KEY(pid));
+        ModPrice: SELECT pid, cent_price / 100 AS price FROM Price;
+        QUERY AltPrice: SELECT pid, price FROM ModPrice WHERE pid = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut price_mutator = g.table("Price").unwrap();
+    let mut getter = g.view("AltPrice").unwrap();
+    let pid = 1;
+    let price = 10000;
+    price_mutator
+        .insert(vec![pid.into(), price.into()])
+        .unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    // Retrieve the result of the count query:
+    let result = getter.lookup(&[pid.into()], true).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][1], (price / 100).into());
+}
+
+#[test]
+fn it_works_with_aggregated_arithmetic() {
+    let mut g = build_local("it_works_with_aggregated_arithmetic");
+    let sql = "
+        CREATE TABLE LineItem (id int, c int, price int, quantity int, PRIMARY KEY(id));
+        LineTotal: SELECT id, c, price * quantity AS total FROM LineItem;
+        QUERY Revenue: SELECT c, SUM(total) AS revenue FROM LineTotal WHERE c = ? GROUP BY c;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut items = g.table("LineItem").unwrap();
+    let mut getter = g.view("Revenue").unwrap();
+    items.insert(vec![1.into(), 1.into(), 3.into(), 2.into()]).unwrap();
+    items.insert(vec![2.into(), 1.into(), 5.into(), 4.into()]).unwrap();
+    items.insert(vec![3.into(), 2.into(), 7.into(), 1.into()]).unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    let mut revenue = |c: i32| getter.lookup(&[c.into()], true).unwrap();
+    assert_eq!(revenue(1), vec![vec![1.into(), 26.into()]]);
+    assert_eq!(revenue(2), vec![vec![2.into(), 7.into()]]);
+
+    // the sum is maintained as line items come and go
+    items.delete(vec![1.into()]).unwrap();
+    items.insert(vec![4.into(), 1.into(), 10.into(), 10.into()]).unwrap();
+    sleep();
+    assert_eq!(revenue(1), vec![vec![1.into(), 120.into()]]);
+    assert_eq!(revenue(2), vec![vec![2.into(), 7.into()]]);
+}
+
+#[test]
+fn it_pages_with_limit_and_offset() {
+    let mut g = build_local("it_pages_with_limit_and_offset");
+    let sql = "
+        CREATE TABLE Item (id int, cat int, score int, PRIMARY KEY(id));
+        QUERY Page1: SELECT id, cat, score FROM Item WHERE cat = ? ORDER BY score DESC LIMIT 2;
+        QUERY Page2: SELECT id, cat, score FROM Item WHERE cat = ? \
+                     ORDER BY score DESC LIMIT 2 OFFSET 2;
+        QUERY Page3: SELECT id, cat, score FROM Item WHERE cat = ? \
+                     ORDER BY score DESC LIMIT 2 OFFSET 4;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut items = g.table("Item").unwrap();
+    for id in 1..7 {
+        items.insert(vec![id.into(), 1.into(), (id * 10).into()]).unwrap();
+    }
+    for id in 11..14 {
+        items.insert(vec![id.into(), 2.into(), id.into()]).unwrap();
+    }
+    sleep();
+
+    let mut pages: Vec<_> = (1..4)
+        .map(|p| g.view(&format!("Page{}", p)).unwrap())
+        .collect();
+    let mut ids = |page: usize, cat: i32| -> Vec<i32> {
+        let mut ids: Vec<i32> = pages[page - 1]
+            .lookup(&[cat.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[0].clone().into())
+            .collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(1, 1), vec![5, 6]);
+    assert_eq!(ids(2, 1), vec![3, 4]);
+    assert_eq!(ids(3, 1), vec![1, 2]);
+
+    // a row that enters an earlier page pushes the rows after it onto later pages
+    items.insert(vec![7.into(), 1.into(), 45.into()]).unwrap();
+    sleep();
+    assert_eq!(ids(1, 1), vec![5, 6]);
+    assert_eq!(ids(2, 1), vec![4, 7]);
+    assert_eq!(ids(3, 1), vec![2, 3]);
+
+    // and rows after one that leaves move up
+    assert_eq!(ids(1, 2), vec![12, 13]);
+    assert_eq!(ids(2, 2), vec![11]);
+    items.delete(vec![13.into()]).unwrap();
+    sleep();
+    assert_eq!(ids(1, 2), vec![11, 12]);
+    assert!(ids(2, 2).is_empty());
+}
+
+#[test]
+fn it_recovers_persisted_bases() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_recovers_persisted_bases");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        128,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+
+    {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        let mut g = g.build(authority.clone()).unwrap();
+
+        let sql = "
+            CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+            QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
+        ";
+        g.install_recipe(sql).unwrap();
+
+        let mut mutator = g.table("Car").unwrap();
+
+        for i in 1..10 {
+            let price = i * 10;
+            mutator.insert(vec![i.into(), price.into()]).unwrap();
+        }
+
+        // Let writes propagate:
+        sleep();
+    }
+
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(persistence_params);
+    let mut g = g.build(authority.clone()).unwrap();
+    let mut getter = g.view("CarPrice").unwrap();
+
+    // Make sure that the new graph contains the old writes
+    for i in 1..10 {
+        let price = i * 10;
+        let result = getter.lookup(&[i.into()], true).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0][0], price.into());
+    }
+}
+
+#[test]
+fn it_recovers_view_indexes() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_recovers_view_indexes");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        128,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+    let by_votes = |g: &mut LocalControllerHandle<LocalAuthority>| {
+        let mut view = g.view("ArticlesByVotes").unwrap();
+        for article in 1..5 {
+            let rows = view.lookup(&[article.into()], true).unwrap();
+            assert_eq!(rows, vec![vec![article.into(), article.into()]]);
+        }
+    };
+
+    {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        let mut g = g.build(authority.clone()).unwrap();
+        g.install_recipe(
+            "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+             QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                              WHERE article = ? GROUP BY article;",
+        ).unwrap();
+        g.add_view_index("VoteCount", "ArticlesByVotes", &["votes"]).unwrap();
+        assert!(g.add_view_index("VoteCount", "VoteCount", &["votes"]).is_err());
+        assert!(g.add_view_index("VoteCount", "ByNothing", &["nope"]).is_err());
+
+        let mut vote = g.table("Vote").unwrap();
+        let mut id = 0;
+        for article in 1..5 {
+            for _ in 0..article {
+                vote.insert(vec![id.into(), article.into()]).unwrap();
+                id += 1;
+            }
+        }
+        sleep();
+        by_votes(&mut g);
+
+        // rebuilding the view, as when its worker fails, brings back the index too
+        g.reshard_view("VoteCount", 4).unwrap();
+        by_votes(&mut g);
+    }
+
+    // and so does restarting the controller
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(persistence_params);
+    let mut g = g.build(authority.clone()).unwrap();
+    by_votes(&mut g);
+}
+
+#[test]
+fn it_recovers_many_persisted_bases() {
+    const BASES: usize = 4;
+    const ROWS: i32 = 1_000;
+
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("it_recovers_many_persisted_bases");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        128,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+
+    let mut sql = String::new();
+    for b in 0..BASES {
+        sql.push_str(&format!(
+            "CREATE TABLE Car{0} (id int, price int, PRIMARY KEY(id));
+             QUERY CarPrice{0}: SELECT price FROM Car{0} WHERE id = ?;",
+            b
+        ));
+    }
+
+    {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        let mut g = g.build(authority.clone()).unwrap();
+        g.install_recipe(&sql).unwrap();
+        for b in 0..BASES {
+            let mut mutator = g.table(&format!("Car{}", b)).unwrap();
+            mutator
+                .insert_all((0..ROWS).map(|i| vec![i.into(), (i * 10).into()]))
+                .unwrap();
+        }
+        sleep();
+    }
+
+    // all the bases are readied at once on recovery, and each must still get its own log back
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(persistence_params);
+    let mut g = g.build(authority.clone()).unwrap();
+    for b in 0..BASES {
+        let mut getter = g.view(&format!("CarPrice{}", b)).unwrap();
+        for i in 0..ROWS {
+            let result = getter.lookup(&[i.into()], true).unwrap();
+            assert_eq!(result, vec![vec![(i * 10).into()]], "Car{} id {}", b, i);
+        }
+    }
+}
+
+#[test]
+fn it_works_with_consistent_sharding() {
+    use crate::ShardHasher;
+
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(Some(3));
+    g.set_shard_hasher(ShardHasher::Consistent);
+    g.set_persistence(get_persistence_params("it_works_with_consistent_sharding"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+
+    // votes are sharded by id, but have to be counted by article
+    let mut vote = g.table("Vote").unwrap();
+    let mut id = 0;
+    for article in 1..20 {
+        for _ in 0..article {
+            vote.insert(vec![id.into(), article.into()]).unwrap();
+            id += 1;
+        }
+    }
+    sleep();
+
+    let mut counts = g.view("VoteCount").unwrap();
+    for article in 1..20 {
+        let rows = counts.lookup(&[article.into()], true).unwrap();
+        assert_eq!(rows, vec![vec![article.into(), article.into()]]);
+    }
+}
+
+#[test]
+fn custom_placement_policy() {
+    use crate::controller::PlacementPolicy;
+    use basics::DomainIndex;
+    use std::net::SocketAddr;
+
+    // places all shards of a domain on the same worker, and records where they went
+    struct OneWorkerPerDomain(Arc<Mutex<Vec<(DomainIndex, usize, SocketAddr)>>>);
+    impl PlacementPolicy for OneWorkerPerDomain {
+        fn place(
+            &mut self,
+            domain: DomainIndex,
+            shard: usize,
+            workers: &[SocketAddr],
+        ) -> SocketAddr {
+            let mut workers = workers.to_vec();
+            workers.sort_by_key(|w| (w.ip(), w.port()));
+            let worker = workers[domain.index() % workers.len()];
+            self.0.lock().unwrap().push((domain, shard, worker));
+            worker
+        }
+    }
+
+    // two instances, so there are two workers to choose from. either may become the leader, so
+    // both get the same policy.
+    let authority = Arc::new(LocalAuthority::new());
+    let placed = Arc::new(Mutex::new(Vec::new()));
+    let build = |placed: &Arc<Mutex<_>>| {
+        let mut g = ControllerBuilder::default();
+        g.set_sharding(Some(2));
+        g.set_quorum(2);
+        g.set_persistence(get_persistence_params("custom_placement_policy"));
+        g.set_placement_policy(OneWorkerPerDomain(placed.clone()));
+        g.build(authority.clone()).unwrap()
+    };
+    let mut g = build(&placed);
+    let _w = build(&placed);
+
+    let sql = "
+        CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+        QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut mutator = g.table("Car").unwrap();
+    let mut getter = g.view("CarPrice").unwrap();
+    for i in 1..10 {
+        mutator.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+    for i in 1..10 {
+        assert_eq!(
+            getter.lookup(&[i.into()], true).unwrap(),
+            vec![vec![(i * 10).into()]]
+        );
+    }
+
+    // every shard was placed by the policy, and each domain's shards share a worker
+    let placed = placed.lock().unwrap();
+    assert!(placed.iter().any(|&(_, shard, _)| shard == 1));
+    for &(domain, _, worker) in placed.iter() {
+        assert!(
+            placed
+                .iter()
+                .filter(|&&(d, _, _)| d == domain)
+                .all(|&(_, _, w)| w == worker)
+        );
+    }
+}
+
+#[test]
+fn pinned_base_placement() {
+    // two instances, so there are two workers to choose from
+    let authority = Arc::new(LocalAuthority::new());
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_quorum(2);
+        g.set_persistence(get_persistence_params("pinned_base_placement"));
+        g.build(authority.clone()).unwrap()
+    };
+    let mut g = build();
+    let _w = build();
+
+    let workers: Vec<_> = g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .map(|(w, _, _)| w)
+        .collect();
+    assert_eq!(workers.len(), 2);
+
+    // pin each base to a different worker
+    g.pin_base("Car", workers[0]).unwrap();
+    g.pin_base("Price", workers[1]).unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (cid int, pid int, PRIMARY KEY(cid));
+         CREATE TABLE Price (pid int, price int, PRIMARY KEY(pid));",
+    ).unwrap();
+
+    let inputs = g.inputs().unwrap();
+    let on = |g: &mut LocalControllerHandle<LocalAuthority>, w| -> Vec<NodeIndex> {
+        g.nodes_on_worker(Some(w), true)
+            .unwrap()
+            .into_iter()
+            .map(|(ni, _, _)| ni)
+            .collect()
+    };
+    let on_first = on(&mut g, workers[0]);
+    let on_second = on(&mut g, workers[1]);
+    assert!(on_first.contains(&inputs["Car"]));
+    assert!(!on_first.contains(&inputs["Price"]));
+    assert!(on_second.contains(&inputs["Price"]));
+    assert!(!on_second.contains(&inputs["Car"]));
+}
+
+#[test]
+fn domain_assignments_name_known_workers() {
+    // two instances, so that domains are spread across workers
+    let authority = Arc::new(LocalAuthority::new());
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_quorum(2);
+        g.set_sharding(Some(2));
+        g.set_persistence(get_persistence_params("domain_assignments_name_known_workers"));
+        g.build(authority.clone()).unwrap()
+    };
+    let mut g = build();
+    let _w = build();
+
+    g.install_recipe(
+        "CREATE TABLE Car (cid int, pid int, PRIMARY KEY(cid));
+         CREATE TABLE Price (pid int, price int, PRIMARY KEY(pid));
+         QUERY CarPrice: SELECT cid, price FROM Car JOIN Price ON (Car.pid = Price.pid)
+             WHERE cid = ?;",
+    ).unwrap();
+
+    let workers: Vec<_> = g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .map(|(w, _, _)| w)
+        .collect();
+    let assignments = g.assignments().unwrap();
+    assert!(assignments.len() > 1);
+    for shards in assignments.values() {
+        let indices: Vec<_> = shards.iter().map(|a| a.shard).collect();
+        assert_eq!(indices, (0..shards.len()).collect::<Vec<_>>());
+        assert!(shards.iter().all(|a| workers.contains(&a.worker)));
+    }
+
+    // every domain shard that reports statistics is accounted for
+    let stats = g.statistics().unwrap();
+    for &(di, shard) in stats.keys() {
+        assert!(assignments[&di].iter().any(|a| a.shard == shard));
+    }
+}
+
+#[test]
+fn failed_boot_rolls_back_migration() {
+    let mut g = build_local("failed_boot_rolls_back_migration");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    car.insert(vec![1.into(), 10.into()]).unwrap();
+    sleep();
+
+    let nodes_before = g.graph_snapshot().unwrap().nodes.len();
+    let recipe_before = g.recipe().unwrap();
+    let domains = |g: &mut LocalControllerHandle<LocalAuthority>| {
+        let mut domains: Vec<_> = g.statistics().unwrap().keys().map(|&(di, _)| di).collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    };
+    let domains_before = domains(&mut g);
+
+    // no domain can boot before it times out, so the new base never gets a domain
+    let res = g.try_migrate(|mig| {
+        mig.set_domain_boot_timeout(Some(Duration::from_millis(0)));
+        let bike = mig.add_base("Bike", &["id", "price"], Base::default());
+        mig.maintain_anonymous(bike, &[0]);
+    });
+    assert!(res.unwrap_err().contains("failed to boot"));
+
+    // none of the new nodes remain, and the recipe is as before
+    assert_eq!(g.graph_snapshot().unwrap().nodes.len(), nodes_before);
+    assert!(g.check_invariants().unwrap().is_empty());
+    assert_eq!(g.recipe().unwrap(), recipe_before);
+    assert_eq!(g.outputs().unwrap().keys().collect::<Vec<_>>(), vec!["CarPrice"]);
+    assert_eq!(domains(&mut g), domains_before);
+
+    // the existing query keeps working
+    car.insert(vec![2.into(), 20.into()]).unwrap();
+    sleep();
+    let mut car_price = g.view("CarPrice").unwrap();
+    assert_eq!(car_price.lookup(&[1.into()], true).unwrap(), vec![vec![10.into()]]);
+    assert_eq!(car_price.lookup(&[2.into()], true).unwrap(), vec![vec![20.into()]]);
+
+    // and the new base can be added once domains boot again, with the domain indices that the
+    // failed migration gave back
+    g.extend_recipe(
+        "CREATE TABLE Bike (id int, price int, PRIMARY KEY(id));
+         QUERY BikePrice: SELECT price FROM Bike WHERE id = ?;",
+    ).unwrap();
+    let after = domains(&mut g);
+    assert_eq!(after.len(), after.last().unwrap().index() + 1);
+    let mut bike = g.table("Bike").unwrap();
+    bike.insert(vec![1.into(), 5.into()]).unwrap();
+    sleep();
+    let mut bike_price = g.view("BikePrice").unwrap();
+    assert_eq!(bike_price.lookup(&[1.into()], true).unwrap(), vec![vec![5.into()]]);
+}
+
+#[test]
+fn decommission_worker() {
+    use crate::controller::PlacementPolicy;
+    use basics::DomainIndex;
+    use std::net::SocketAddr;
+
+    // places every domain on the worker with the highest address
+    struct Last;
+    impl PlacementPolicy for Last {
+        fn place(&mut self, _: DomainIndex, _: usize, workers: &[SocketAddr]) -> SocketAddr {
+            *workers.iter().max_by_key(|w| (w.ip(), w.port())).unwrap()
+        }
+    }
+
+    let authority = Arc::new(LocalAuthority::new());
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_sharding(Some(2));
+        g.set_quorum(2);
+        g.set_persistence(get_persistence_params("decommission_worker"));
+        g.set_placement_policy(Last);
+        g.build(authority.clone()).unwrap()
+    };
+    let mut g = build();
+    let _w = build();
+
+    let mut workers: Vec<_> = g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .map(|(w, _, _)| w)
+        .collect();
+    workers.sort_by_key(|w| (w.ip(), w.port()));
+    assert_eq!(workers.len(), 2);
+    let (keep, drain) = (workers[0], workers[1]);
+
+    // bases cannot be moved, so keep the base off the worker we drain. the query is keyed on a
+    // different column than the base is sharded by, so its reader ends up in a separate domain,
+    // which is placed on the drained worker.
+    g.pin_base("Car", keep).unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarByPrice: SELECT id FROM Car WHERE price = ?;",
+    ).unwrap();
+
+    let mut mutator = g.table("Car").unwrap();
+    for i in 1..10 {
+        mutator.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+
+    let mut getter = g.view("CarByPrice").unwrap();
+    assert_eq!(getter.lookup(&[10.into()], true).unwrap(), vec![vec![1.into()]]);
+
+    g.decommission(drain).unwrap();
+    let workers: Vec<_> = g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .map(|(w, _, _)| w)
+        .collect();
+    assert_eq!(workers, vec![keep]);
+
+    // the reader has been rebuilt on the remaining worker, and sees both old and new writes
+    mutator.insert(vec![10.into(), 100.into()]).unwrap();
+    sleep();
+    let mut getter = g.view("CarByPrice").unwrap();
+    for i in 1..11 {
+        let price = i * 10;
+        let result = getter.lookup(&[price.into()], true).unwrap();
+        assert_eq!(result, vec![vec![i.into()]]);
+    }
+}
+
+#[test]
+fn restarted_worker_rejoins() {
+    use crate::controller::PlacementPolicy;
+    use basics::DomainIndex;
+    use std::net::SocketAddr;
+
+    // places every domain on the worker with the highest address
+    struct Last;
+    impl PlacementPolicy for Last {
+        fn place(&mut self, _: DomainIndex, _: usize, workers: &[SocketAddr]) -> SocketAddr {
+            *workers.iter().max_by_key(|w| (w.ip(), w.port())).unwrap()
+        }
+    }
+
+    let authority = Arc::new(LocalAuthority::new());
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_sharding(None);
+        g.set_quorum(2);
+        g.set_missed_heartbeats_before_failure(2, 1);
+        g.set_persistence(get_persistence_params("restarted_worker_rejoins"));
+        g.set_placement_policy(Last);
+        g.build(authority.clone()).unwrap()
+    };
+    let mut g = build();
+    let w = build();
+
+    let mut workers: Vec<_> = g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .map(|(w, _, _)| w)
+        .collect();
+    workers.sort_by_key(|w| (w.ip(), w.port()));
+    assert_eq!(workers.len(), 2);
+    let (keep, lost) = (workers[0], workers[1]);
+
+    // one base stays on the worker that keeps running, while the other base and the readers'
+    // domains are placed on the worker that is restarted.
+    g.pin_base("Car", keep).unwrap();
+    g.pin_base("Bike", lost).unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         CREATE TABLE Bike (id int, price int, PRIMARY KEY(id));
+         QUERY CarByPrice: SELECT id FROM Car WHERE price = ?;
+         QUERY BikeByPrice: SELECT id FROM Bike WHERE price = ?;",
+    ).unwrap();
+    let bases: Vec<_> = g
+        .nodes_on_worker(Some(lost), true)
+        .unwrap()
+        .into_iter()
+        .filter(|&(_, _, ref desc)| desc == "Base")
+        .collect();
+    assert_eq!(bases.len(), 1);
+    let mut mutator = g.table("Car").unwrap();
+    for i in 1..10 {
+        mutator.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+    let mut getter = g.view("CarByPrice").unwrap();
+    assert_eq!(getter.lookup(&[10.into()], true).unwrap(), vec![vec![1.into()]]);
+
+    // restart the worker, and wait for the controller to notice that the old one is gone
+    drop(w);
+    let _w = build();
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while g
+        .instances()
+        .unwrap()
+        .into_iter()
+        .any(|(w, healthy, _)| w == lost && healthy)
+    {
+        assert!(Instant::now() < deadline, "worker failure was never detected");
+        thread::sleep(Duration::from_millis(100));
+    }
+    sleep();
+
+    // the base kept its domain and its data, and the query has been recovered onto the live
+    // workers
+    mutator.insert(vec![10.into(), 100.into()]).unwrap();
+    sleep();
+    let mut getter = g.view("CarByPrice").unwrap();
+    for i in 1..11 {
+        let price = i * 10;
+        let result = getter.lookup(&[price.into()], true).unwrap();
+        assert_eq!(result, vec![vec![i.into()]]);
+    }
+
+    // the base on the restarted worker has been recovered onto the live workers too, and takes
+    // writes through a new handle
+    assert!(g.nodes_on_worker(Some(lost), true).unwrap().is_empty());
+    let mut bikes = g.table("Bike").unwrap();
+    for i in 1..4 {
+        bikes.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+    let mut getter = g.view("BikeByPrice").unwrap();
+    for i in 1..4 {
+        let price = i * 10;
+        let result = getter.lookup(&[price.into()], true).unwrap();
+        assert_eq!(result, vec![vec![i.into()]]);
+    }
+}
+
+#[test]
+fn full_replays_respect_batch_size() {
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(None);
+    g.disable_partial();
+    g.set_replay_batch_size(4);
+    g.set_persistence(get_persistence_params("full_replays_respect_batch_size"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe("CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));").unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    for id in 0..100 {
+        vote.insert(vec![id.into(), (id % 10).into()]).unwrap();
+    }
+    sleep();
+
+    let replays = |g: &mut LocalControllerHandle<LocalAuthority>| -> u64 {
+        g.statistics()
+            .unwrap()
+            .values()
+            .map(|&(ref domain, _)| domain.replays)
+            .sum()
+    };
+    let before = replays(&mut g);
+
+    // the new view is filled by replaying all of the base, at most four records at a time
+    g.extend_recipe(
+        "QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+    let mut counts = g.view("VoteCount").unwrap();
+    for article in 0..10 {
+        assert_eq!(
+            counts.lookup(&[article.into()], true).unwrap(),
+            vec![vec![article.into(), 10.into()]]
+        );
+    }
+    assert!(replays(&mut g) - before >= 100 / 4);
+}
+
+#[cfg(feature = "replay_ordering")]
+#[test]
+fn upsert_during_partial_replay() {
+    use dataflow::PacketOrder;
+    use std::thread;
+
+    let build = |prefix: &str, order: PacketOrder| {
+        let mut builder = ControllerBuilder::default();
+        builder.set_sharding(None);
+        builder.set_persistence(get_persistence_params(prefix));
+        builder.set_packet_order("t", order);
+        let mut g = builder.build_local().unwrap();
+        g.install_recipe(
+            "CREATE TABLE t (id int, owner int, v int, PRIMARY KEY(id));
+             QUERY q: SELECT id, v FROM t WHERE owner = ?;",
+        ).unwrap();
+        g
+    };
+
+    // the upsert reaches the base while the replay request for its key is outstanding, so the
+    // replay has to pick up the new value rather than the replaced one.
+    let mut g = build(
+        "upsert_during_partial_replay_first",
+        PacketOrder::ReplaysAfterUpdates(2),
+    );
+    let mut t = g.table("t").unwrap();
+    let mut q = g.view("q").unwrap();
+    t.insert(vec![1.into(), 7.into(), 10.into()]).unwrap();
+    assert!(q.lookup(&[7.into()], false).unwrap().is_empty());
+    sleep();
+    t.upsert(vec![1.into(), 7.into(), 20.into()]).unwrap();
+    assert_eq!(
+        q.lookup(&[7.into()], true).unwrap(),
+        vec![vec![1.into(), 20.into()]]
+    );
+    drop(g);
+
+    // the replay fills the key before the upsert arrives, so the upsert has to update the
+    // replayed state. the upsert is held back until then, so it has to be issued concurrently.
+    let mut g = build(
+        "upsert_during_partial_replay_second",
+        PacketOrder::UpdatesAfterReplays(1),
+    );
+    let mut t = g.table("t").unwrap().into_exclusive().unwrap();
+    let mut q = g.view("q").unwrap();
+    let writer = thread::spawn(move || t.upsert(vec![1.into(), 7.into(), 20.into()]).unwrap());
+    sleep();
+    assert!(q.lookup(&[7.into()], false).unwrap().is_empty());
+    writer.join().unwrap();
+    assert_eq!(
+        q.lookup(&[7.into()], true).unwrap(),
+        vec![vec![1.into(), 20.into()]]
+    );
+}
+
+#[test]
+fn reshard_view() {
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(Some(2));
+    g.set_persistence(get_persistence_params("reshard_view"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut id = 0;
+    for article in 1..10 {
+        for _ in 0..article {
+            vote.insert(vec![id.into(), article.into()]).unwrap();
+            id += 1;
+        }
+    }
+    sleep();
+
+    // the sharding of the live readers and operators called VoteCount
+    let shards_of = |g: &mut LocalControllerHandle<LocalAuthority>| -> (Vec<_>, Vec<_>) {
+        let (readers, others): (Vec<_>, Vec<_>) = g
+            .graph_snapshot()
+            .unwrap()
+            .nodes
+            .into_iter()
+            .filter(|n| n.name == "VoteCount")
+            .filter(|n| match &n.operator[..] {
+                "dropped" | "ingress" | "egress" | "sharder" => false,
+                _ => true,
+            }).partition(|n| n.operator == "reader");
+        (
+            readers.into_iter().map(|n| n.sharding).collect(),
+            others.into_iter().map(|n| n.sharding).collect(),
+        )
+    };
+    let check = |g: &mut LocalControllerHandle<LocalAuthority>, extra| {
+        let mut counts = g.view("VoteCount").unwrap();
+        for article in 1..10 {
+            let rows = counts.lookup(&[article.into()], true).unwrap();
+            let expected = if article == 1 { article + extra } else { article };
+            assert_eq!(rows, vec![vec![article.into(), expected.into()]]);
+        }
+    };
+    check(&mut g, 0);
+    let (readers, others) = shards_of(&mut g);
+    assert_eq!(readers.len(), 1);
+    assert!(readers[0].ends_with(", 2)"));
+    assert!(!others.is_empty());
+    assert!(others.iter().all(|s| s.ends_with(", 2)")));
+
+    // only the reader is resharded, and the old one is gone
+    g.reshard_view("VoteCount", 4).unwrap();
+    let (readers, others) = shards_of(&mut g);
+    assert_eq!(readers.len(), 1);
+    assert!(readers[0].ends_with(", 4)"));
+    assert!(others.iter().all(|s| s.ends_with(", 2)")));
+    check(&mut g, 0);
+
+    // new writes reach the resharded view exactly once
+    vote.insert(vec![id.into(), 1.into()]).unwrap();
+    sleep();
+    check(&mut g, 1);
+
+    // resharding to the same number of shards again changes nothing
+    g.reshard_view("VoteCount", 4).unwrap();
+    assert_eq!(shards_of(&mut g).0.len(), 1);
+
+    // the view can still be removed, along with the nodes in front of its new reader
+    g.install_recipe("CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));").unwrap();
+    assert!(shards_of(&mut g).0.is_empty());
+    let nodes = g.graph_snapshot().unwrap().nodes;
+    assert!(!nodes.iter().any(|n| n.name == "VoteCount" && n.operator == "sharder"));
+    assert!(g.check_invariants().unwrap().is_empty());
+}
+
+#[test]
+fn local_delivery() {
+    // domains on the same worker produce the same results whether they hand each other packets in
+    // memory or send them over loopback connections
+    let counts = |local: bool| -> Vec<Vec<Vec<DataType>>> {
+        let mut g = ControllerBuilder::default();
+        g.set_sharding(Some(2));
+        g.set_local_delivery(local);
+        g.set_persistence(get_persistence_params(&format!("local_delivery_{}", local)));
+        let mut g = g.build_local().unwrap();
+        g.install_recipe(
+            "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+             QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                              WHERE article = ? GROUP BY article;",
+        ).unwrap();
+
+        let mut vote = g.table("Vote").unwrap();
+        vote.batch_insert((0..1000).map(|id: i32| vec![id.into(), (id % 10).into()])).unwrap();
+        sleep();
+
+        let mut counts = g.view("VoteCount").unwrap();
+        (0..10)
+            .map(|article: i32| counts.lookup(&[article.into()], true).unwrap())
+            .collect()
+    };
+
+    let local = counts(true);
+    for (article, rows) in local.iter().enumerate() {
+        assert_eq!(*rows, vec![vec![(article as i32).into(), 100.into()]]);
+    }
+    assert_eq!(counts(false), local);
+}
+
+#[test]
+fn packet_coalescing() {
+    // with a threshold no packet reaches, domains only see the controller's packets when it
+    // flushes them, which it must do before waiting on them and after each request.
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(Some(2));
+    g.set_packet_coalescing(Some(1 << 30));
+    g.set_persistence(get_persistence_params("packet_coalescing"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    vote.batch_insert((0..100).map(|id: i32| vec![id.into(), (id % 10).into()])).unwrap();
+    sleep();
+
+    // a query added later is set up in the existing domains
+    g.extend_recipe("QUERY Votes: SELECT id, article FROM Vote WHERE id = ?;")
+        .unwrap();
+
+    let mut counts = g.view("VoteCount").unwrap();
+    for article in 0..10i32 {
+        assert_eq!(
+            counts.lookup(&[article.into()], true).unwrap(),
+            vec![vec![article.into(), 10.into()]]
+        );
+    }
+    let mut votes = g.view("Votes").unwrap();
+    assert_eq!(
+        votes.lookup(&[42.into()], true).unwrap(),
+        vec![vec![42.into(), 2.into()]]
+    );
+}
+
+#[test]
+fn sharding_directives() {
+    let mut g = build_local("sharding_directives");
+    let tables = "CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));
+                  CREATE TABLE Vote (aid int, uid int);
+                  SHARD Article INTO 3;
+                  SHARD Vote BY aid INTO 4;";
+    let query = "QUERY ArticleVotes: SELECT Article.aid, title, uid FROM Article \
+                 JOIN Vote ON (Article.aid = Vote.aid) WHERE Article.aid = ?;";
+
+    // the join can't follow the number of shards of both bases
+    let e = g.install_recipe(&format!("{}\n{}", tables, query)).unwrap_err();
+    let e = format!("{:?}", e);
+    assert!(
+        e.contains("ArticleVotes combines Article and Vote, which are sharded into 3 and 4 shards"),
+        "unexpected error: {}",
+        e
+    );
+
+    // unless the query picks one itself
+    g.install_recipe(&format!("{}\n{}\nSHARD ArticleVotes INTO 3;", tables, query)).unwrap();
+    let shards_of = |g: &mut LocalControllerHandle<LocalAuthority>, name: &str| -> Vec<String> {
+        g.graph_snapshot()
+            .unwrap()
+            .nodes
+            .into_iter()
+            .filter(|n| n.name == name)
+            .map(|n| n.sharding)
+            .collect()
+    };
+    assert!(shards_of(&mut g, "Article").contains(&"ByColumn(0, 3)".to_owned()));
+    assert!(shards_of(&mut g, "Vote").contains(&"ByColumn(0, 4)".to_owned()));
+    assert!(!shards_of(&mut g, "ArticleVotes").is_empty());
+    assert!(shards_of(&mut g, "ArticleVotes").iter().all(|s| s.ends_with(", 3)")));
+
+    let mut article = g.table("Article").unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    let mut votes = g.view("ArticleVotes").unwrap();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    vote.insert(vec![1.into(), 1.into()]).unwrap();
+    vote.insert(vec![1.into(), 2.into()]).unwrap();
+    vote.insert(vec![2.into(), 1.into()]).unwrap();
+    sleep();
+
+    let mut rows = votes.lookup(&[1.into()], true).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            vec![1.into(), "a".into(), 1.into()],
+            vec![1.into(), "a".into(), 2.into()],
+        ]
+    );
+}
+
+#[test]
+fn stale_handles_are_rejected() {
+    let mut g = build_local("stale_handles_are_rejected");
+    let votes = "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+                 QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                                  WHERE article = ? GROUP BY article;";
+    g.install_recipe(&format!(
+        "{}\nCREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));",
+        votes
+    )).unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut article = g.table("Article").unwrap();
+    let mut counts = g.view("VoteCount").unwrap();
+    vote.insert(vec![1.into(), 1.into()]).unwrap();
+    article.insert(vec![1.into(), "a".into()]).unwrap();
+    sleep();
+    assert_eq!(
+        counts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+
+    // recovering the query onto new nodes leaves the old view handle behind
+    g.reshard_view("VoteCount", 4).unwrap();
+    sleep();
+    match counts.lookup(&[1.into()], true) {
+        Err(api::ViewError::StaleHandle) => {}
+        r => panic!("expected a stale view handle, got {:?}", r),
+    }
+    let mut counts = g.view("VoteCount").unwrap();
+    assert_eq!(
+        counts.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+
+    // writes through a handle for a removed base are rejected, rather than never acknowledged
+    g.install_recipe(votes).unwrap();
+    sleep();
+    match article.insert(vec![2.into(), "b".into()]) {
+        Err(api::TableError::StaleHandle(ref t)) if t == "Article" => {}
+        r => panic!("expected a stale table handle, got {:?}", r),
+    }
+    vote.insert(vec![2.into(), 1.into()]).unwrap();
+}
+
+#[test]
+fn read_threads_serve_concurrent_lookups() {
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(None);
+    g.set_read_threads(4);
+    g.set_persistence(get_persistence_params("read_threads_serve_concurrent_lookups"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    ).unwrap();
+
+    let mut car = g.table("Car").unwrap();
+    for i in 0..100 {
+        car.insert(vec![i.into(), format!("brand{}", i % 10).into()]).unwrap();
+    }
+    sleep();
+
+    // every client has its own connection, and so may be served by its own read thread
+    let clients: Vec<_> = (0..4)
+        .map(|client| {
+            let mut q = g.view("CarsByBrand").unwrap().into_exclusive().unwrap();
+            thread::spawn(move || {
+                for i in 0..200 {
+                    let brand = (client + i) % 10;
+                    let rows = q.lookup(&[format!("brand{}", brand).into()], true).unwrap();
+                    assert_eq!(rows.len(), 10);
+                    assert!(rows.iter().all(|r| {
+                        let id: i32 = r[0].clone().into();
+                        id % 10 == brand
+                    }));
+                }
+            })
+        }).collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+#[test]
+fn view_count() {
+    let mut g = build_local("view_count");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id FROM Car WHERE brand = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    let mut q = g.view("CarsByBrand").unwrap();
+
+    let brands = ["Volvo", "Volvo", "Volvo", "Saab", "Audi", "Audi"];
+    for (i, &brand) in brands.iter().enumerate() {
+        car.insert(vec![(i as i32).into(), brand.into()]).unwrap();
+    }
+    sleep();
+
+    for &brand in &["Volvo", "Saab", "Audi", "Ford"] {
+        let key = [DataType::from(brand)];
+        let rows = q.lookup(&key, true).unwrap();
+        assert_eq!(q.count(&key, true).unwrap(), rows.len());
+    }
+    assert_eq!(q.count(&["Volvo".into()], true).unwrap(), 3);
+    assert_eq!(q.count(&["Ford".into()], true).unwrap(), 0);
+
+    // keys of the wrong length are rejected, just like for lookups
+    assert!(q.count(&["Volvo".into(), 1.into()], true).is_err());
+}
+
+#[test]
+fn async_lookups() {
+    use futures::future::{self, Future};
+
+    let mut g = build_local("async_lookups");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    let mut q = g.view("CarPrice").unwrap();
+
+    let n = 100;
+    for i in 0..n {
+        car.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+
+    // issue all lookups before waiting for any of them, including some for missing keys
+    let pending: Vec<_> = (0..n + 10)
+        .map(|i| q.lookup_async(&[i.into()], true).unwrap())
+        .collect();
+    let results = future::join_all(pending).wait().unwrap();
+    assert_eq!(results.len(), n as usize + 10);
+    for (i, rows) in results.into_iter().enumerate() {
+        if (i as i32) < n {
+            assert_eq!(rows, vec![vec![DataType::from(i as i32 * 10)]]);
+        } else {
+            assert!(rows.is_empty());
+        }
+    }
+
+    // blocking lookups still work on the same connections afterwards
+    assert_eq!(q.lookup(&[1.into()], true).unwrap(), vec![vec![10.into()]]);
+
+    // and so do lookups issued after others were dropped without waiting for their replies
+    let dropped: Vec<_> = (0..10)
+        .map(|i| q.lookup_async(&[i.into()], true).unwrap())
+        .collect();
+    drop(dropped);
+    assert_eq!(q.lookup(&[2.into()], true).unwrap(), vec![vec![20.into()]]);
+    let rows = q.lookup_async(&[3.into()], true).unwrap().wait().unwrap();
+    assert_eq!(rows, vec![vec![30.into()]]);
+}
+
+#[test]
+fn sharded_multi_lookup_keeps_key_order() {
+    let mut g = build_local("sharded_multi_lookup_keeps_key_order");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT id, price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    let mut q = g.view("CarPrice").unwrap();
+
+    car.insert_all((0..20).map(|i: i32| vec![i.into(), (i * 10).into()]))
+        .unwrap();
+    sleep();
+
+    // keys in an order that interleaves the shards, with a repeat and a key that has no rows
+    let keys: Vec<i32> = vec![17, 3, 8, 42, 0, 19, 3, 11, 6, 14, 1];
+    let results = q
+        .multi_lookup(keys.iter().map(|&k| vec![k.into()]).collect(), true)
+        .unwrap();
+    assert_eq!(results.len(), keys.len());
+    for (&k, rows) in keys.iter().zip(results) {
+        if k < 20 {
+            assert_eq!(rows, vec![vec![k.into(), (k * 10).into()]]);
+        } else {
+            assert!(rows.is_empty());
+        }
+    }
+}
+
+#[test]
+fn read_your_writes() {
+    let mut g = build_local("read_your_writes");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), price int, PRIMARY KEY(id));
+         QUERY CheapCars: SELECT id, price FROM Car WHERE brand = ? AND price < 100;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    let mut q = g.view("CheapCars").unwrap();
+    let volvo = [DataType::from("Volvo")];
+
+    for i in 0..10 {
+        let token = car.insert(vec![i.into(), "Volvo".into(), i.into()]).unwrap();
+        assert!(!token.writes.is_empty());
+
+        // a plain lookup may or may not see the new row yet, but this one must
+        let rows = q.lookup_at_least(&volvo, &token, true).unwrap();
+        assert_eq!(rows.len(), i as usize + 1);
+        assert!(rows.contains(&vec![i.into(), i.into()]));
+    }
+
+    // writes that the view filters out are still seen by it
+    let token = car.insert(vec![100.into(), "Volvo".into(), 1000.into()]).unwrap();
+    assert_eq!(q.lookup_at_least(&volvo, &token, true).unwrap().len(), 10);
+
+    // and a batch of writes yields a token that covers all of them
+    let token = car
+        .batch_insert((200..210).map(|i| vec![i.into(), "Volvo".into(), 1.into()]))
+        .unwrap();
+    assert_eq!(q.lookup_at_least(&volvo, &token, true).unwrap().len(), 20);
+}
+
+#[test]
+fn read_your_writes_gives_up_on_unrelated_writes() {
+    let mut g = build_local("read_your_writes_gives_up_on_unrelated_writes");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         CREATE TABLE Bike (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut bike = g.table("Bike").unwrap();
+    let mut q = g.view("CarPrice").unwrap();
+
+    // the view never sees writes to a base table it is not computed from
+    let token = bike.insert(vec![1.into(), 10.into()]).unwrap();
+    match q.lookup_at_least(&[1.into()], &token, true) {
+        Err(api::ViewError::WritesNotSeen) => {}
+        r => panic!("lookup for unrelated writes returned {:?}", r),
+    }
+}
+
+#[test]
+fn write_token_covers_all_writes() {
+    let mut g = build_local("write_token_covers_all_writes");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    let mut q = g.view("CarPrice").unwrap();
+
+    // nothing written yet
+    car.flush().unwrap();
+    assert!(car.write_token().writes.is_empty());
+
+    car.insert_all((0..100).map(|i| vec![i.into(), (i * 10).into()])).unwrap();
+    car.delete(vec![0.into()]).unwrap();
+    let token = car.write_token();
+    // the rows are spread over both shards of the base
+    assert_eq!(token.writes.len(), 2);
+
+    for i in 0..100 {
+        let rows = q.lookup_at_least(&[i.into()], &token, true).unwrap();
+        if i == 0 {
+            assert!(rows.is_empty());
+        } else {
+            assert_eq!(rows, vec![vec![(i * 10).into()]]);
+        }
+    }
+}
+
+#[test]
+fn flush_makes_writes_durable() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flush_makes_writes_durable");
+    let persistence_params = PersistenceParameters::new(
+        DurabilityMode::Permanent,
+        128,
+        Duration::from_millis(1),
+        Some(path.to_string_lossy().into()),
+        1,
+    );
+
+    {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        let mut g = g.build(authority.clone()).unwrap();
+        g.install_recipe(
+            "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+             QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+        ).unwrap();
+        let mut car = g.table("Car").unwrap();
+        let mut q = g.view("CarPrice").unwrap();
+
+        car.insert_all((0..100).map(|i| vec![i.into(), (i * 10).into()]))
+            .unwrap();
+        // the batch fails at the second row, after the first has already been sent
+        match car.batch_insert(vec![
+            vec![100.into(), 1000.into()],
+            vec![101.into(), 1010.into(), 0.into()],
+        ]) {
+            Err(api::TableError::WrongColumnCount(2, 3)) => {}
+            r => panic!("expected a bad row, got {:?}", r),
+        }
+        car.flush().unwrap();
+
+        let token = car.write_token();
+        for i in 0..101 {
+            let rows = q.lookup_at_least(&[i.into()], &token, true).unwrap();
+            assert_eq!(rows, vec![vec![(i * 10).into()]]);
+        }
+    }
+
+    // no sleep before shutting down: what flush waited for must already be on disk
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(persistence_params);
+    let mut g = g.build(authority.clone()).unwrap();
+    let mut q = g.view("CarPrice").unwrap();
+    for i in 0..101 {
+        assert_eq!(
+            q.lookup(&[i.into()], true).unwrap(),
+            vec![vec![(i * 10).into()]]
+        );
+    }
+    assert!(q.lookup(&[101.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn band_join() {
+    let mut g = build_local("band_join");
+    g.install_recipe(
+        "CREATE TABLE Band (id int, low int, high int, PRIMARY KEY(id));
+         CREATE TABLE Reading (rid int, val int, PRIMARY KEY(rid));
+         QUERY InBand: SELECT Reading.rid, Band.id FROM Band \
+            JOIN Reading ON Band.low <= Reading.val AND Reading.val <= Band.high \
+            WHERE Band.id = ?;
+         QUERY Above: SELECT Reading.rid, Band.id FROM Band, Reading \
+            WHERE Reading.val > Band.high AND Band.id = ?;",
+    ).unwrap();
+    let mut band = g.table("Band").unwrap();
+    let mut reading = g.table("Reading").unwrap();
+    let mut in_band = g.view("InBand").unwrap();
+    let mut above = g.view("Above").unwrap();
+
+    band.insert(vec![1.into(), 0.into(), 10.into()]).unwrap();
+    band.insert(vec![2.into(), 5.into(), 15.into()]).unwrap();
+    for &(rid, val) in &[(1, 3), (2, 7), (3, 12), (4, 20)] {
+        reading.insert(vec![rid.into(), val.into()]).unwrap();
+    }
+    sleep();
+
+    let rids = |rows: Vec<Vec<DataType>>| {
+        let mut rids: Vec<i64> = rows.into_iter().map(|r| (&r[0]).into()).collect();
+        rids.sort();
+        rids
+    };
+    assert_eq!(rids(in_band.lookup(&[1.into()], true).unwrap()), vec![1, 2]);
+    assert_eq!(rids(in_band.lookup(&[2.into()], true).unwrap()), vec![2, 3]);
+    assert_eq!(rids(above.lookup(&[1.into()], true).unwrap()), vec![3, 4]);
+    assert_eq!(rids(above.lookup(&[2.into()], true).unwrap()), vec![4]);
+
+    // new readings match every band they fall into
+    reading.insert(vec![5.into(), 10.into()]).unwrap();
+    sleep();
+    assert_eq!(rids(in_band.lookup(&[1.into()], true).unwrap()), vec![1, 2, 5]);
+    assert_eq!(rids(in_band.lookup(&[2.into()], true).unwrap()), vec![2, 3, 5]);
+
+    // and new bands match all existing readings
+    band.insert(vec![3.into(), 11.into(), 25.into()]).unwrap();
+    sleep();
+    assert_eq!(rids(in_band.lookup(&[3.into()], true).unwrap()), vec![3, 4]);
+}
+
+#[test]
+fn unsupported_join_conditions_are_rejected() {
+    let mut g = build_local("unsupported_join_conditions_are_rejected");
+    g.install_recipe(
+        "CREATE TABLE Band (id int, low int, high int, PRIMARY KEY(id));
+         CREATE TABLE Reading (rid int, val int, PRIMARY KEY(rid));
+         CREATE TABLE Gauge (gid int, rid int, PRIMARY KEY(gid));",
+    ).unwrap();
+
+    // a join condition may only relate the joined table to one other table
+    assert!(
+        g.extend_recipe(
+            "QUERY Bad: SELECT Reading.rid FROM Band \
+                JOIN Reading ON Band.low <= Reading.val AND Reading.rid = Gauge.rid;",
+        ).is_err()
+    );
+
+    // the controller keeps working
+    g.extend_recipe(
+        "QUERY Good: SELECT Reading.rid, Band.id FROM Band \
+            JOIN Reading ON Band.low <= Reading.val WHERE Band.id = ?;",
+    ).unwrap();
+    let mut band = g.table("Band").unwrap();
+    let mut reading = g.table("Reading").unwrap();
+    let mut good = g.view("Good").unwrap();
+    band.insert(vec![1.into(), 5.into(), 10.into()]).unwrap();
+    reading.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+    assert_eq!(good.lookup(&[1.into()], true).unwrap().len(), 1);
+}
+
+#[test]
+fn it_filters_on_column_comparisons() {
+    let mut g = build_local("it_filters_on_column_comparisons");
+    g.install_recipe(
+        "CREATE TABLE Shift (id int, starts int, ends int, PRIMARY KEY(id));
+         QUERY Valid: SELECT id FROM Shift WHERE starts < ends;
+         QUERY Zero: SELECT id FROM Shift WHERE Shift.starts = Shift.ends;",
+    ).unwrap();
+    let mut shift = g.table("Shift").unwrap();
+    let mut valid = g.view("Valid").unwrap();
+    let mut zero = g.view("Zero").unwrap();
+
+    shift.insert(vec![1.into(), 9.into(), 17.into()]).unwrap();
+    shift.insert(vec![2.into(), 22.into(), 6.into()]).unwrap();
+    shift.insert(vec![3.into(), 12.into(), 12.into()]).unwrap();
+    // comparisons with NULL are unknown, so these rows match neither query
+    shift.insert(vec![4.into(), DataType::None, 12.into()]).unwrap();
+    shift.insert(vec![5.into(), DataType::None, DataType::None]).unwrap();
+    sleep();
+
+    let ids = |rows: Vec<Vec<DataType>>| {
+        let mut ids: Vec<i64> = rows.into_iter().map(|r| (&r[0]).into()).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(valid.lookup(&[0.into()], true).unwrap()), vec![1]);
+    assert_eq!(ids(zero.lookup(&[0.into()], true).unwrap()), vec![3]);
+
+    // rows are re-checked when either of the compared columns changes
+    shift.delete(vec![1.into()]).unwrap();
+    shift
+        .update(vec![2.into()], vec![(2, Modification::Set(23.into()))])
+        .unwrap();
+    sleep();
+    assert_eq!(ids(valid.lookup(&[0.into()], true).unwrap()), vec![2]);
+    shift
+        .update(vec![2.into()], vec![(1, Modification::Set(30.into()))])
+        .unwrap();
+    sleep();
+    assert!(ids(valid.lookup(&[0.into()], true).unwrap()).is_empty());
+}
+
+#[test]
+fn left_join_on_constants() {
+    let mut g = build_local("left_join_on_constants");
+    g.install_recipe(
+        "CREATE TABLE Post (id int, author int, PRIMARY KEY(id));
+         CREATE TABLE Vote (pid int, uid int, up int, PRIMARY KEY(uid));
+         QUERY UpOn: SELECT Post.id, Vote.uid FROM Post \
+            LEFT JOIN Vote ON Post.id = Vote.pid AND Vote.up = 1 \
+            WHERE Post.author = ?;
+         QUERY UpWhere: SELECT Post.id, Vote.uid FROM Post \
+            LEFT JOIN Vote ON Post.id = Vote.pid \
+            WHERE Vote.up = 1 AND Post.author = ?;",
+    ).unwrap();
+    let mut post = g.table("Post").unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    let mut up_on = g.view("UpOn").unwrap();
+    let mut up_where = g.view("UpWhere").unwrap();
+
+    post.insert(vec![1.into(), 1.into()]).unwrap();
+    post.insert(vec![2.into(), 1.into()]).unwrap();
+    vote.insert(vec![1.into(), 10.into(), 1.into()]).unwrap();
+    vote.insert(vec![2.into(), 11.into(), 0.into()]).unwrap();
+    sleep();
+
+    let pairs = |rows: Vec<Vec<DataType>>| {
+        let mut pairs: Vec<(i64, Option<i64>)> = rows
+            .into_iter()
+            .map(|r| {
+                let uid = match r[1] {
+                    DataType::None => None,
+                    ref uid => Some(uid.into()),
+                };
+                ((&r[0]).into(), uid)
+            }).collect();
+        pairs.sort();
+        pairs
+    };
+
+    // in the ON clause, the constant only decides what matches, so the post without an upvote
+    // is still there, padded with nulls
+    assert_eq!(
+        pairs(up_on.lookup(&[1.into()], true).unwrap()),
+        vec![(1, Some(10)), (2, None)]
+    );
+    // in the WHERE clause, it filters the joined rows, null-padded ones included
+    assert_eq!(
+        pairs(up_where.lookup(&[1.into()], true).unwrap()),
+        vec![(1, Some(10))]
+    );
+
+    // an upvote replaces the null-padded row
+    vote.insert(vec![2.into(), 12.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        pairs(up_on.lookup(&[1.into()], true).unwrap()),
+        vec![(1, Some(10)), (2, Some(12))]
+    );
+    assert_eq!(
+        pairs(up_where.lookup(&[1.into()], true).unwrap()),
+        vec![(1, Some(10)), (2, Some(12))]
+    );
+
+    // constants on the left relation can't be applied before the join, and are rejected
+    let err = g
+        .extend_recipe(
+            "QUERY LeftOn: SELECT Post.id, Vote.uid FROM Post \
+                LEFT JOIN Vote ON Post.id = Vote.pid AND Post.author = 1 \
+                WHERE Post.author = ?;",
+        ).unwrap_err();
+    assert!(
+        format!("{:?}", err).contains("left join conditions may only add constant predicates"),
+        "unexpected error: {:?}",
+        err
+    );
+}
+
+#[test]
+fn mysql_adapter() {
+    use mysql::{self, OptsBuilder};
+    use std::net::TcpListener;
+
+    let mut g = build_local("mysql_adapter");
+    g.install_recipe("CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));")
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let soup = g.pointer();
+    thread::spawn(move || {
+        let log = slog::Logger::root(slog::Discard, o!());
+        crate::serve_mysql(listener, soup, 3, log).unwrap();
+    });
+
+    let mut opts = OptsBuilder::new();
+    opts.ip_or_hostname(Some("127.0.0.1")).tcp_port(port);
+    let mut conn = mysql::Conn::new(opts).unwrap();
+
+    for &(id, brand) in &[(1, "Volvo"), (2, "Volvo"), (3, "Saab")] {
+        conn.prep_exec("INSERT INTO Car (id, brand) VALUES (?, ?)", (id, brand))
+            .unwrap();
+    }
+    sleep();
+
+    let ids = |conn: &mut mysql::Conn, brand: &str| {
+        let mut ids: Vec<i32> = conn
+            .prep_exec("SELECT id FROM Car WHERE brand = ?", (brand,))
+            .unwrap()
+            .map(|row| mysql::from_row(row.unwrap()))
+            .collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(&mut conn, "Volvo"), vec![1, 2]);
+    assert_eq!(ids(&mut conn, "Saab"), vec![3]);
+    assert_eq!(ids(&mut conn, "Audi"), vec![]);
+
+    // the view's name only depends on the query
+    assert!(g.view("mysql_46ce976ee84bcbfc").is_ok());
+
+    // queries that only differ in the literals they look up share a view
+    let views = g.outputs().unwrap().len();
+    for &(brand, n) in &[("Volvo", 2), ("Saab", 1), ("Audi", 0)] {
+        let rows: Vec<_> = conn
+            .query(format!("SELECT id FROM Car WHERE brand = '{}'", brand))
+            .unwrap()
+            .collect();
+        assert_eq!(rows.len(), n);
+    }
+    assert_eq!(g.outputs().unwrap().len(), views + 1);
+
+    conn.prep_exec("DELETE FROM Car WHERE id = ?", (1,)).unwrap();
+    sleep();
+    assert_eq!(ids(&mut conn, "Volvo"), vec![2]);
+
+    // once three views are installed, queries that need another one are rejected, while those
+    // that have one are still answered
+    assert!(conn.query("SELECT id FROM Car WHERE id = 2").is_ok());
+    assert!(conn.query("SELECT brand FROM Car WHERE id = 2").is_err());
+    assert_eq!(g.outputs().unwrap().len(), views + 2);
+    assert_eq!(ids(&mut conn, "Volvo"), vec![2]);
+
+    // unsupported statements are rejected without breaking the connection
+    assert!(conn.query("UPDATE Car SET brand = 'Audi' WHERE id = 2").is_err());
+    assert_eq!(ids(&mut conn, "Volvo"), vec![2]);
+}
+
+#[test]
+fn query_view_over_http() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let mut g = build_local("query_view_over_http");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id, brand FROM Car WHERE brand = ?;
+         QUERY CarsById: SELECT id, brand FROM Car WHERE id = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    car.insert(vec![1.into(), "Volvo".into()]).unwrap();
+    car.insert(vec![3.into(), "911".into()]).unwrap();
+    car.insert(vec![2.into(), "Saab".into()]).unwrap();
+    sleep();
+
+    let addr = g.url().unwrap()["http://".len()..].to_owned();
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let status: u16 = res[9..12].parse().unwrap();
+        let body = res.splitn(2, "\r\n\r\n").nth(1).unwrap().to_owned();
+        (status, body)
+    };
+
+    let (status, body) = get("/query/CarsByBrand?key=Volvo");
+    assert_eq!(status, 200);
+    let rows: Vec<Vec<DataType>> = serde_json::from_str(&body).unwrap();
+    assert_eq!(rows, vec![vec![1.into(), "Volvo".into()]]);
+
+    let (status, body) = get("/query/CarsByBrand?key=Audi");
+    assert_eq!(status, 200);
+    let rows: Vec<Vec<DataType>> = serde_json::from_str(&body).unwrap();
+    assert!(rows.is_empty());
+
+    // keys are converted to the type of the column they are looked up in
+    let (status, body) = get("/query/CarsByBrand?key=911");
+    assert_eq!(status, 200);
+    let rows: Vec<Vec<DataType>> = serde_json::from_str(&body).unwrap();
+    assert_eq!(rows, vec![vec![3.into(), "911".into()]]);
+    let (status, body) = get("/query/CarsById?key=1");
+    assert_eq!(status, 200);
+    let rows: Vec<Vec<DataType>> = serde_json::from_str(&body).unwrap();
+    assert_eq!(rows, vec![vec![1.into(), "Volvo".into()]]);
+    assert_eq!(get("/query/CarsById?key=Volvo").0, 400);
+
+    assert_eq!(get("/query/CarsByBrand").0, 400);
+    assert_eq!(get("/query/NoSuchView?key=1").0, 404);
+}
+
+#[test]
+fn graphviz_with_stats() {
+    let mut g = build_local("graphviz_with_stats");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, brand varchar(255), PRIMARY KEY(id));
+         QUERY CarsByBrand: SELECT id, brand FROM Car WHERE brand = ?;",
+    ).unwrap();
+    let mut car = g.table("Car").unwrap();
+    car.insert(vec![1.into(), "Volvo".into()]).unwrap();
+    car.insert(vec![2.into(), "Saab".into()]).unwrap();
+    sleep();
+
+    // the base table is fully materialized, so its rows are counted
+    let base = g.inputs().unwrap()["Car"];
+    let graph = g.graphviz_with_stats().unwrap();
+    let line = graph
+        .lines()
+        .find(|l| l.trim().starts_with(&format!("{} [", base.index())))
+        .unwrap();
+    assert!(line.contains("xlabel=\"2 rows\\n"), "{}", line);
+
+    // the plain rendering has no size labels
+    assert!(!g.graphviz().unwrap().contains("xlabel"));
+}
+
+#[test]
+fn maintain_rejects_unavailable_key_column() {
+    let mut g = build_local("maintain_rejects_unavailable_key_column");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b"], Base::default());
+        let p = mig.add_ingredient("p", &["b"], Project::new(a, &[1], None, None));
+
+        let err = mig.maintain("p".to_string(), p, &[1]).unwrap_err();
+        assert!(err.contains("column 1"), "{}", err);
+
+        // a valid key still works, and no reader was left behind by the failed attempt
+        mig.maintain("p".to_string(), p, &[0]).unwrap();
+    });
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    g.view("p").unwrap();
+}
+
+#[test]
+fn keyless_view_holds_all_rows() {
+    let mut g = build_local("keyless_view_holds_all_rows");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b"], Base::new(vec![]).with_key(vec![0]));
+        let p = mig.add_ingredient("p", &["b", "a"], Project::new(a, &[1, 0], None, None));
+        mig.maintain("p".to_string(), p, &[]).unwrap();
+        mig.maintain("q".to_string(), a, &[0]).unwrap();
+    });
+
+    let mut a = g.table("a").unwrap();
+    let mut p = g.view("p").unwrap();
+    assert!(p.key_columns().is_empty());
+    assert!(p.all().unwrap().is_empty());
+
+    a.insert(vec![1.into(), "x".into()]).unwrap();
+    a.insert(vec![2.into(), "y".into()]).unwrap();
+    a.insert(vec![3.into(), "z".into()]).unwrap();
+    sleep();
+    let mut rows = p.all().unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            vec!["x".into(), 1.into()],
+            vec!["y".into(), 2.into()],
+            vec!["z".into(), 3.into()],
+        ]
+    );
+
+    a.delete(vec![2.into()]).unwrap();
+    sleep();
+    let mut rows = p.all().unwrap();
+    rows.sort();
+    assert_eq!(rows, vec![vec!["x".into(), 1.into()], vec!["z".into(), 3.into()]]);
+    assert_eq!(p.lookup(&[], true).unwrap().len(), 2);
+
+    // views keyed by a column cannot be read in full
+    let mut q = g.view("q").unwrap();
+    match q.all() {
+        Err(api::ViewError::WrongKeyColumnCount(1, 0)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn replay_paths_run_from_source_to_destination() {
+    let mut g = build_local_unsharded("replay_paths_run_from_source_to_destination");
+    let (a, p, r) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b"], Base::new(vec![]).with_key(vec![0]));
+        let p = mig.add_ingredient("p", &["b", "a"], Project::new(a, &[1, 0], None, None));
+        let r = mig.maintain_anonymous(p, &[1]);
+        (a, p, r)
+    });
+
+    let paths = g.replay_paths().unwrap();
+    assert_eq!(paths.len(), 1);
+    assert_eq!(paths[0].1, vec![a, p, r]);
+}
+
+#[test]
+fn secondary_index_lookups() {
+    let mut g = build_local("secondary_index_lookups");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "category"], Base::new(vec![]).with_key(vec![0]));
+        let p = mig.add_ingredient("p", &["id", "category"], Project::new(a, &[0, 1], None, None));
+        mig.maintain("by_id".to_string(), p, &[0]).unwrap();
+        mig.maintain_secondary("by_category".to_string(), p, &[1]).unwrap();
+
+        // view names stay unique
+        assert!(mig.maintain_secondary("by_id".to_string(), p, &[1]).is_err());
+    });
+    assert_eq!(g.outputs().unwrap().len(), 2);
+
+    let mut mutator = g.table("a").unwrap();
+    mutator.insert(vec![1.into(), 10.into()]).unwrap();
+    mutator.insert(vec![2.into(), 10.into()]).unwrap();
+    mutator.insert(vec![3.into(), 20.into()]).unwrap();
+    sleep();
+
+    let mut by_id = g.view("by_id").unwrap();
+    assert_eq!(
+        by_id.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 10.into()]]
+    );
+
+    let mut by_category = g.view("by_category").unwrap();
+    let mut rows = by_category.lookup(&[10.into()], true).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![vec![1.into(), 10.into()], vec![2.into(), 10.into()]]
+    );
+    assert_eq!(
+        by_category.lookup(&[20.into()], true).unwrap(),
+        vec![vec![3.into(), 20.into()]]
+    );
+}
+
+#[test]
+fn list_universes() {
+    let mut g = build_local("list_universes");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    assert!(g.universes().unwrap().is_empty());
+
+    for id in 1..3 {
+        let mut context = HashMap::new();
+        context.insert(String::from("id"), DataType::from(id));
+        g.rpc::<_, ()>("create_universe", &context).unwrap();
+    }
+
+    let universes = g.universes().unwrap();
+    let ids: Vec<_> = universes.iter().map(|u| u.id.clone()).collect();
+    assert_eq!(ids, vec![1.into(), 2.into()]);
+    assert!(universes.iter().all(|u| u.group.is_none()));
+}
+
+#[test]
+fn universes_see_only_their_rows() {
+    let mut g = build_local("universes_see_only_their_rows");
+    let schema = "CREATE TABLE Post (pid int, author int, content text, PRIMARY KEY(pid));";
+    g.install_recipe(schema).unwrap();
+    g.set_security_config(
+        r#"{"policies": [{"table": "Post", "predicate": "WHERE Post.author = UserContext.id"}]}"#
+            .to_string(),
+    );
+    g.install_recipe(&format!(
+        "{}
+         QUERY posts: SELECT pid, author FROM Post WHERE pid = ?;
+         QUERY AllPosts: SELECT pid, author FROM Post;
+         QUERY ReadAll: SELECT pid, author FROM AllPosts WHERE author = ?;",
+        schema
+    )).unwrap();
+
+    for id in 1..3 {
+        let mut context = HashMap::new();
+        context.insert(String::from("id"), DataType::from(id));
+        g.create_universe(context);
+    }
+
+    let mut post = g.table("Post").unwrap();
+    post.insert(vec![1.into(), 1.into(), "mine".into()]).unwrap();
+    post.insert(vec![2.into(), 2.into(), "theirs".into()]).unwrap();
+    sleep();
+
+    let mut posts_u1 = g.view("posts_u1").unwrap();
+    let mut posts_u2 = g.view("posts_u2").unwrap();
+    assert_eq!(
+        posts_u1.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert!(posts_u1.lookup(&[2.into()], true).unwrap().is_empty());
+    assert!(posts_u2.lookup(&[1.into()], true).unwrap().is_empty());
+    assert_eq!(
+        posts_u2.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 2.into()]]
+    );
+
+    // reading through another view must not bypass the policy
+    let mut read_all_u1 = g.view("ReadAll_u1").unwrap();
+    assert_eq!(
+        read_all_u1.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert!(read_all_u1.lookup(&[2.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn migration_json_log() {
+    use crate::controller::MigrationSummary;
+    use std::io::{self, Write};
+
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let summaries = |sink: &Sink| -> Vec<MigrationSummary> {
+        let out = sink.0.lock().unwrap();
+        String::from_utf8(out.clone())
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    };
 
-    price_mutator
-        .insert(vec![pid.into(), price.into()])
+    let sink = Sink::default();
+    let mut builder = ControllerBuilder::default();
+    builder.set_persistence(get_persistence_params("migration_json_log"));
+    builder.log_migrations_as_json(sink.clone());
+    let mut g = builder.build_local().unwrap();
+
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let logged = summaries(&sink);
+    assert_eq!(logged.len(), 1);
+    let added = &logged[0];
+    assert!(added.nodes_added >= 2);
+    assert_eq!(added.nodes_removed, 0);
+    assert!(added.domains_spawned >= 1);
+    assert!(added.replay_paths_created >= 1);
+
+    // dropping the query removes its nodes again
+    g.install_recipe("CREATE TABLE Car (id int, price int, PRIMARY KEY(id));")
         .unwrap();
-    car_mutator.insert(vec![cid.into(), pid.into()]).unwrap();
+    let logged = summaries(&sink);
+    assert_eq!(logged.len(), 2);
+    assert_eq!(logged[1].nodes_added, 0);
+    assert!(logged[1].nodes_removed >= 1);
+}
 
-    // Let writes propagate:
-    sleep();
+#[test]
+fn recipe_text_and_version() {
+    let mut g = build_local("recipe_text_and_version");
+    g.install_recipe("CREATE TABLE Car (id int, price int, PRIMARY KEY(id));").unwrap();
+    let RecipeInfo {
+        text,
+        version: v1,
+    } = g.recipe().unwrap();
+    assert!(text.contains("Car"));
+    assert!(!text.contains("CarPrice"));
+    assert_eq!(g.recipe_version().unwrap(), v1);
+
+    g.extend_recipe("QUERY CarPrice: SELECT price FROM Car WHERE id = ?;").unwrap();
+    let RecipeInfo {
+        text,
+        version: v2,
+    } = g.recipe().unwrap();
+    assert!(v2 > v1);
+    assert_eq!(g.recipe_version().unwrap(), v2);
+
+    // the returned text describes the whole recipe, and can be installed again as-is
+    assert!(text.contains("Car"));
+    assert!(text.contains("QUERY CarPrice:"));
+    g.install_recipe(&text).unwrap();
+    assert!(g.outputs().unwrap().contains_key("CarPrice"));
+    assert!(g.recipe_version().unwrap() > v2);
+}
 
-    // Retrieve the result of the count query:
-    let result = getter.lookup(&[cid.into()], true).unwrap();
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0][1], price.into());
+#[test]
+fn failed_extension_keeps_recipe() {
+    let mut g = build_local("failed_extension_keeps_recipe");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let before = g.recipe().unwrap();
+
+    // the query only refers to existing tables, so it is only found to be bad once the migration
+    // tries to plan it
+    let e = g
+        .extend_recipe("QUERY CarColor: SELECT color FROM Car WHERE id = ?;")
+        .unwrap_err();
+    assert!(e.to_string().contains("color"));
+    assert_eq!(g.recipe().unwrap(), before);
+    assert!(!g.outputs().unwrap().contains_key("CarColor"));
+
+    // and the recipe can be extended from where it was
+    g.extend_recipe("QUERY CarsByPrice: SELECT id FROM Car WHERE price = ?;").unwrap();
+    let text = g.recipe().unwrap().text;
+    assert!(text.contains("QUERY CarPrice:"));
+    assert!(text.contains("QUERY CarsByPrice:"));
+    assert!(!text.contains("CarColor"));
 }
 
 #[test]
-fn it_works_with_arithmetic_aliases() {
-    let mut g = build_local("it_works_with_arithmetic_aliases");
-    let sql = "
-        CREATE TABLE Price (pid int, cent_price int, PRIMARY KEY(pid));
-        ModPrice: SELECT pid, cent_price / 100 AS price FROM Price;
-        QUERY AltPrice: SELECT pid, price FROM ModPrice WHERE pid = ?;
-    ";
-    g.install_recipe(sql).unwrap();
+fn rollback_recipe() {
+    let mut g = build_local("rollback_recipe");
 
-    let mut price_mutator = g.table("Price").unwrap();
-    let mut getter = g.view("AltPrice").unwrap();
-    let pid = 1;
-    let price = 10000;
-    price_mutator
-        .insert(vec![pid.into(), price.into()])
-        .unwrap();
+    // nothing to roll back yet
+    assert!(g.rollback_recipe().is_err());
 
-    // Let writes propagate:
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+    let v1_text = g.recipe().unwrap().text;
+    g.extend_recipe("QUERY CarsByPrice: SELECT id FROM Car WHERE price = ?;").unwrap();
+    assert!(g.outputs().unwrap().contains_key("CarsByPrice"));
+    let v2 = g.recipe_version().unwrap();
+
+    let mut mutator = g.table("Car").unwrap();
+    mutator.insert(vec![1.into(), 10.into()]).unwrap();
     sleep();
 
-    // Retrieve the result of the count query:
-    let result = getter.lookup(&[pid.into()], true).unwrap();
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0][1], (price / 100).into());
+    // the rollback is a new version that has the text of the earlier one
+    g.rollback_recipe().unwrap();
+    let RecipeInfo {
+        text,
+        version: v3,
+    } = g.recipe().unwrap();
+    assert_eq!(v3, v2 + 1);
+    assert_eq!(g.recipe_version().unwrap(), v3);
+    assert_eq!(text, v1_text);
+    let outputs = g.outputs().unwrap();
+    assert!(!outputs.contains_key("CarsByPrice"));
+    assert!(outputs.contains_key("CarPrice"));
+    assert!(g.view("CarsByPrice").is_err());
+
+    // the earlier view still works
+    let mut getter = g.view("CarPrice").unwrap();
+    assert_eq!(getter.lookup(&[1.into()], true).unwrap(), vec![vec![10.into()]]);
+
+    // rolling back the installation removes the base table as well
+    g.rollback_recipe().unwrap();
+    assert!(g.inputs().unwrap().is_empty());
+    assert_eq!(g.recipe_version().unwrap(), v3 + 1);
+    assert!(g.rollback_recipe().is_err());
 }
 
 #[test]
-fn it_recovers_persisted_bases() {
-    let authority = Arc::new(LocalAuthority::new());
-    let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("it_recovers_persisted_bases");
-    let persistence_params = PersistenceParameters::new(
-        DurabilityMode::Permanent,
-        128,
-        Duration::from_millis(1),
-        Some(path.to_string_lossy().into()),
-        1,
+fn detailed_outputs_report_keys() {
+    let mut g = build_local("detailed_outputs_report_keys");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+
+    let inputs = g.inputs_detailed().unwrap();
+    let car = &inputs["Car"];
+    assert_eq!(car.node, g.inputs().unwrap()["Car"]);
+    assert_eq!(car.columns, vec!["id", "price"]);
+    assert!(car.ready);
+
+    let outputs = g.outputs_detailed().unwrap();
+    assert_eq!(outputs.keys().collect::<Vec<_>>(), vec!["CarPrice"]);
+    let view = &outputs["CarPrice"];
+    assert_eq!(view.node, g.outputs().unwrap()["CarPrice"]);
+    assert_ne!(view.reader, view.node);
+    assert_eq!(view.key, vec!["id"]);
+    assert!(view.columns.contains(&"price".to_owned()));
+    assert_eq!(view.columns, g.view("CarPrice").unwrap().columns());
+    assert!(view.materialized);
+    assert!(view.ready);
+}
+
+#[test]
+fn lookup_builder_errors() {
+    use api::LookupBuilderError;
+
+    let mut g = build_local("lookup_builder_errors");
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    ).unwrap();
+
+    let cause = |e: failure::Error| {
+        let cause = e
+            .causes()
+            .filter_map(|c| c.downcast_ref::<LookupBuilderError>())
+            .next()
+            .cloned();
+        cause
+    };
+    assert_eq!(
+        g.view("CarPrise").err().and_then(cause),
+        Some(LookupBuilderError::NotFound("CarPrise".to_owned()))
+    );
+    assert_eq!(
+        g.view("Car").err().and_then(cause),
+        Some(LookupBuilderError::NoReader("Car".to_owned()))
+    );
+    assert_eq!(
+        g.table("CarPrice").err().and_then(cause),
+        Some(LookupBuilderError::NotATable("CarPrice".to_owned()))
     );
 
-    {
-        let mut g = ControllerBuilder::default();
-        g.set_persistence(persistence_params.clone());
-        let mut g = g.build(authority.clone()).unwrap();
+    // the errors did not get in the way of later requests
+    assert!(g.view("CarPrice").is_ok());
+    assert!(g.table("Car").is_ok());
+}
 
-        let sql = "
-            CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
-            QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
-        ";
-        g.install_recipe(sql).unwrap();
+#[test]
+fn compound_key_lookups() {
+    let mut g = build_local("compound_key_lookups");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b", "c"], Base::default());
+        let q = mig.add_ingredient("q", &["a", "b", "c"], Identity::new(a));
+        mig.maintain_anonymous(q, &[0, 2]);
+    });
 
-        let mut mutator = g.table("Car").unwrap();
+    let mut write = g.table("a").unwrap();
+    let mut read = g.view("q").unwrap();
+    assert_eq!(read.key_columns(), vec!["a", "c"]);
 
-        for i in 1..10 {
-            let price = i * 10;
-            mutator.insert(vec![i.into(), price.into()]).unwrap();
-        }
+    write.insert(vec![1.into(), "x".into(), 10.into()]).unwrap();
+    write.insert(vec![1.into(), "y".into(), 20.into()]).unwrap();
+    write.insert(vec![2.into(), "z".into(), 10.into()]).unwrap();
+    sleep();
 
-        // Let writes propagate:
-        sleep();
-    }
+    assert_eq!(
+        read.lookup(&[1.into(), 10.into()], true).unwrap(),
+        vec![vec![1.into(), "x".into(), 10.into()]]
+    );
+    assert_eq!(
+        read.lookup(&[1.into(), 20.into()], true).unwrap(),
+        vec![vec![1.into(), "y".into(), 20.into()]]
+    );
+    assert_eq!(
+        read.lookup(&[2.into(), 10.into()], true).unwrap(),
+        vec![vec![2.into(), "z".into(), 10.into()]]
+    );
 
-    let mut g = ControllerBuilder::default();
-    g.set_persistence(persistence_params);
-    let mut g = g.build(authority.clone()).unwrap();
-    let mut getter = g.view("CarPrice").unwrap();
+    // only one component of the key matches any row
+    assert!(read.lookup(&[2.into(), 20.into()], true).unwrap().is_empty());
+    assert!(read.lookup(&[3.into(), 10.into()], true).unwrap().is_empty());
 
-    // Make sure that the new graph contains the old writes
-    for i in 1..10 {
-        let price = i * 10;
-        let result = getter.lookup(&[i.into()], true).unwrap();
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0][0], price.into());
+    // keys must give a value for every key column
+    match read.lookup(&[1.into()], true) {
+        Err(api::ViewError::WrongKeyColumnCount(2, 1)) => {}
+        r => panic!("expected WrongKeyColumnCount error, got {:?}", r),
     }
 }
 
@@ -948,6 +3628,34 @@ fn it_works_with_join_arithmetic() {
     assert_eq!(result[0][1], (price as f64 * fraction).into());
 }
 
+#[test]
+fn column_lineage_traces_join_arithmetic() {
+    let mut g = build_local("column_lineage_traces_join_arithmetic");
+    let sql = "
+        CREATE TABLE Car (car_id int, price_id int, PRIMARY KEY(car_id));
+        CREATE TABLE Price (price_id int, price int, PRIMARY KEY(price_id));
+        CREATE TABLE Sales (sales_id int, price_id int, fraction float, PRIMARY KEY(sales_id));
+        QUERY CarPrice: SELECT car_id, price * fraction AS discounted FROM Car \
+                  JOIN Price ON Car.price_id = Price.price_id \
+                  JOIN Sales ON Price.price_id = Sales.price_id \
+                  WHERE car_id = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let inputs = g.inputs().unwrap();
+    let (car, price, sales) = (inputs["Car"], inputs["Price"], inputs["Sales"]);
+    let leaf = g.outputs().unwrap()["CarPrice"];
+    let columns = g.view("CarPrice").unwrap().columns().to_vec();
+    let column = |name: &str| columns.iter().position(|c| c == name).unwrap();
+
+    assert_eq!(g.column_lineage(leaf, column("car_id")).unwrap(), vec![(car, 0)]);
+    // the computed column is derived from the columns on both sides of the join
+    let mut expected = vec![(price, 1), (sales, 2)];
+    expected.sort();
+    assert_eq!(g.column_lineage(leaf, column("discounted")).unwrap(), expected);
+    assert!(g.column_lineage(leaf, columns.len() + 10).unwrap().is_empty());
+}
+
 #[test]
 fn it_works_with_function_arithmetic() {
     let mut g = build_local("it_works_with_function_arithmetic");
@@ -973,6 +3681,304 @@ fn it_works_with_function_arithmetic() {
     assert_eq!(result[0][0], DataType::from(max_price * 2));
 }
 
+#[test]
+fn it_works_with_like() {
+    let mut g = build_local("it_works_with_like");
+    let sql = "
+        CREATE TABLE Person (id int, name varchar(255), PRIMARY KEY(id));
+        QUERY Prefix: SELECT id FROM Person WHERE name LIKE 'Ha%';
+        QUERY Suffix: SELECT id FROM Person WHERE name LIKE '%er';
+        QUERY Infix: SELECT id FROM Person WHERE name LIKE '%an%';
+        QUERY Literal: SELECT id FROM Person WHERE name LIKE 'ha';
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut mutator = g.table("Person").unwrap();
+    for (id, name) in vec!["Harry", "Hannah", "Baker", "Nathan", "ha"]
+        .into_iter()
+        .enumerate()
+    {
+        mutator.insert(vec![(id + 1).into(), name.into()]).unwrap();
+    }
+
+    // Let writes propagate:
+    sleep();
+
+    let mut ids = |view: &str| {
+        let mut found: Vec<DataType> = g
+            .view(view)
+            .unwrap()
+            .lookup(&[0.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect();
+        found.sort();
+        found
+    };
+
+    // matching is case-sensitive, so "ha" is not matched by 'Ha%'
+    assert_eq!(ids("Prefix"), vec![1.into(), 2.into()]);
+    assert_eq!(ids("Suffix"), vec![3.into()]);
+    assert_eq!(ids("Infix"), vec![2.into(), 4.into()]);
+    assert_eq!(ids("Literal"), vec![5.into()]);
+}
+
+#[test]
+fn it_works_with_in_list() {
+    let mut g = build_local("it_works_with_in_list");
+    let many = (0..20)
+        .map(|i| format!("'s{}'", i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!(
+        "CREATE TABLE Orders (id int, status varchar(255), PRIMARY KEY(id));
+         QUERY Open: SELECT id FROM Orders WHERE status IN ('new', 'paid', NULL);
+         QUERY Many: SELECT id FROM Orders WHERE status IN ({});
+         QUERY Nothing: SELECT id FROM Orders WHERE status IN ();",
+        many
+    );
+    g.install_recipe(&sql).unwrap();
+
+    let mut mutator = g.table("Orders").unwrap();
+    mutator.insert(vec![1.into(), "new".into()]).unwrap();
+    mutator.insert(vec![2.into(), "paid".into()]).unwrap();
+    mutator.insert(vec![3.into(), "shipped".into()]).unwrap();
+    mutator.insert(vec![4.into(), DataType::None]).unwrap();
+    mutator.insert(vec![5.into(), "s7".into()]).unwrap();
+    mutator.insert(vec![6.into(), "s19".into()]).unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    let mut ids = |view: &str| {
+        let mut found: Vec<DataType> = g
+            .view(view)
+            .unwrap()
+            .lookup(&[0.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect();
+        found.sort();
+        found
+    };
+
+    // the NULL status does not match the NULL in the list
+    assert_eq!(ids("Open"), vec![1.into(), 2.into()]);
+    assert_eq!(ids("Many"), vec![5.into(), 6.into()]);
+    assert!(ids("Nothing").is_empty());
+}
+
+#[test]
+fn it_works_with_ranges() {
+    let mut g = build_local("it_works_with_ranges");
+    let sql = "
+        CREATE TABLE Orders (id int, total int, PRIMARY KEY(id));
+        QUERY InRange: SELECT id FROM Orders WHERE total >= 10 AND total <= 20;
+        QUERY OutOfRange: SELECT id FROM Orders WHERE total < 10 OR total > 20;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut mutator = g.table("Orders").unwrap();
+    for (id, total) in vec![(1, 9), (2, 10), (3, 15), (4, 20), (5, 21)] {
+        mutator.insert(vec![id.into(), total.into()]).unwrap();
+    }
+    mutator.insert(vec![6.into(), DataType::None]).unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    let mut ids = |view: &str| {
+        let mut found: Vec<DataType> = g
+            .view(view)
+            .unwrap()
+            .lookup(&[0.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect();
+        found.sort();
+        found
+    };
+
+    // both bounds are inclusive, and a NULL total is in neither result
+    assert_eq!(ids("InRange"), vec![2.into(), 3.into(), 4.into()]);
+    assert_eq!(ids("OutOfRange"), vec![1.into(), 5.into()]);
+
+    // each range is checked by a single filter, without a union for the negated form
+    let graph = g.graphviz().unwrap();
+    assert_eq!(graph.matches(" BETWEEN ").count(), 2, "{}", graph);
+    assert!(!graph.contains("⋃"), "{}", graph);
+}
+
+#[test]
+fn it_works_with_nulls_in_filters() {
+    let mut g = build_local("it_works_with_nulls_in_filters");
+    let sql = "
+        CREATE TABLE Paper (id int, principal int, PRIMARY KEY(id));
+        QUERY Eq: SELECT id FROM Paper WHERE principal = 2;
+        QUERY NotEq: SELECT id FROM Paper WHERE principal != 2;
+        QUERY IsNull: SELECT id FROM Paper WHERE principal IS NULL;
+        QUERY IsNotNull: SELECT id FROM Paper WHERE principal IS NOT NULL;
+        QUERY Visible: SELECT id FROM Paper WHERE principal IS NULL OR principal != 2;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut mutator = g.table("Paper").unwrap();
+    mutator.insert(vec![1.into(), 2.into()]).unwrap();
+    mutator.insert(vec![2.into(), 3.into()]).unwrap();
+    mutator.insert(vec![3.into(), DataType::None]).unwrap();
+
+    // Let writes propagate:
+    sleep();
+
+    let mut ids = |view: &str| {
+        let mut found: Vec<DataType> = g
+            .view(view)
+            .unwrap()
+            .lookup(&[0.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect();
+        found.sort();
+        found
+    };
+
+    assert_eq!(ids("Eq"), vec![1.into()]);
+    // NULL != 2 is unknown, so paper 3 is excluded
+    assert_eq!(ids("NotEq"), vec![2.into()]);
+    assert_eq!(ids("IsNull"), vec![3.into()]);
+    assert_eq!(ids("IsNotNull"), vec![1.into(), 2.into()]);
+    assert_eq!(ids("Visible"), vec![2.into(), 3.into()]);
+}
+
+#[test]
+fn it_works_with_distinct() {
+    let mut g = build_local("it_works_with_distinct");
+    let sql = "
+        CREATE TABLE Visit (id int, page varchar(255), uid int, PRIMARY KEY(id));
+        QUERY Pages: SELECT DISTINCT page FROM Visit;
+        QUERY UserPages: SELECT DISTINCT page FROM Visit WHERE uid = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    let mut mutator = g.table("Visit").unwrap();
+    let mut pages = g.view("Pages").unwrap();
+    let mut user_pages = g.view("UserPages").unwrap();
+
+    // duplicates only show up once
+    mutator.insert(vec![1.into(), "home".into(), 1.into()]).unwrap();
+    mutator.insert(vec![2.into(), "home".into(), 1.into()]).unwrap();
+    mutator.insert(vec![3.into(), "home".into(), 2.into()]).unwrap();
+    mutator.insert(vec![4.into(), "about".into(), 1.into()]).unwrap();
+    sleep();
+
+    let names = |rows: Vec<Vec<DataType>>| {
+        let mut names: Vec<DataType> = rows.into_iter().map(|r| r[0].clone()).collect();
+        names.sort();
+        names
+    };
+    assert_eq!(
+        names(pages.lookup(&[0.into()], true).unwrap()),
+        vec!["about".into(), "home".into()]
+    );
+    assert_eq!(
+        names(user_pages.lookup(&[1.into()], true).unwrap()),
+        vec!["about".into(), "home".into()]
+    );
+
+    // removing one of the duplicates leaves the row in place
+    mutator.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        names(pages.lookup(&[0.into()], true).unwrap()),
+        vec!["about".into(), "home".into()]
+    );
+    assert_eq!(
+        names(user_pages.lookup(&[1.into()], true).unwrap()),
+        vec!["about".into(), "home".into()]
+    );
+
+    // removing the last one removes the row
+    mutator.delete(vec![2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        names(pages.lookup(&[0.into()], true).unwrap()),
+        vec!["about".into(), "home".into()]
+    );
+    assert_eq!(
+        names(user_pages.lookup(&[1.into()], true).unwrap()),
+        vec!["about".into()]
+    );
+    mutator.delete(vec![3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        names(pages.lookup(&[0.into()], true).unwrap()),
+        vec!["about".into()]
+    );
+}
+
+#[test]
+fn it_loads_csv() {
+    use api::CsvErrorPolicy;
+
+    let mut g = build_local("it_loads_csv");
+    let sql = "
+        CREATE TABLE Person (id int, name varchar(255), joined timestamp, PRIMARY KEY(id));
+        QUERY people: SELECT id, name, joined FROM Person WHERE id = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+    sleep();
+
+    let mut person = g.table("Person").unwrap();
+    let mut people = g.view("people").unwrap();
+    let types: Vec<_> = person
+        .schema()
+        .unwrap()
+        .fields
+        .iter()
+        .map(|f| f.sql_type.clone())
+        .collect();
+
+    // rows that don't parse can be skipped
+    let csv = "1,alice,2018-09-01 12:00:00\n\
+               2,\"bob, jr.\",2018-09-02 08:30:00\n\
+               three,carol,2018-09-03 00:00:00\n\
+               4,dave,\n";
+    let stats = person
+        .load_csv(csv.as_bytes(), &types, CsvErrorPolicy::Skip)
+        .unwrap();
+    assert_eq!(stats.rows, 3);
+    assert_eq!(stats.skipped, 1);
+
+    // or abort the load
+    let csv = "5,erin,2018-09-05 00:00:00\n6,frank\n";
+    match person.load_csv(csv.as_bytes(), &types, CsvErrorPolicy::Abort) {
+        Err(api::TableError::BadCsvRow(2, _)) => {}
+        r => unreachable!("{:?}", r),
+    }
+    sleep();
+
+    let alice = people.lookup(&[1.into()], true).unwrap();
+    assert_eq!(alice.len(), 1);
+    assert_eq!(alice[0][0], 1.into());
+    assert_eq!(alice[0][1], "alice".into());
+    match alice[0][2] {
+        DataType::Timestamp(_) => {}
+        ref d => unreachable!("{:?}", d),
+    }
+    assert_eq!(
+        people.lookup(&[2.into()], true).unwrap()[0][1],
+        "bob, jr.".into()
+    );
+    assert!(people.lookup(&[3.into()], true).unwrap().is_empty());
+    assert_eq!(people.lookup(&[4.into()], true).unwrap()[0][2], DataType::None);
+    assert_eq!(people.lookup(&[5.into()], true).unwrap().len(), 1);
+    assert!(people.lookup(&[6.into()], true).unwrap().is_empty());
+}
+
 #[test]
 fn votes() {
     // set up graph
@@ -1353,17 +4359,74 @@ fn key_on_added() {
         a
     });
 
-    // add a maintained view keyed on newly added column
-    let _ = g.migrate(move |mig| {
-        mig.add_column(a, "c", 3.into());
-        let b = mig.add_ingredient("x", &["c", "b"], Project::new(a, &[2, 1], None, None));
-        mig.maintain_anonymous(b, &[0]);
-        b
-    });
+    // add a maintained view keyed on newly added column
+    let _ = g.migrate(move |mig| {
+        mig.add_column(a, "c", 3.into());
+        let b = mig.add_ingredient("x", &["c", "b"], Project::new(a, &[2, 1], None, None));
+        mig.maintain_anonymous(b, &[0]);
+        b
+    });
+
+    // make sure we can read (may trigger a replay)
+    let mut bq = g.view("x").unwrap();
+    assert!(bq.lookup(&[3.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn replay_progress_is_reported() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_persistence(get_persistence_params("replay_progress_is_reported"));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe("CREATE TABLE t (a int, b int);").unwrap();
+
+    // enough rows that the replay is split into many batches
+    let n = 10_000;
+    let mut t = g.table("t").unwrap();
+    t.insert_all((0..n).map(|i: i64| vec![i.into(), i.into()]))
+        .unwrap();
+    sleep();
+
+    // watch the replay status while a migration replays t into a new view
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = {
+        let done = done.clone();
+        let ptr = g.pointer();
+        thread::spawn(move || {
+            let mut ctrl = ptr.connect().unwrap();
+            let mut seen = Vec::new();
+            while !done.load(Ordering::SeqCst) {
+                seen.push(ctrl.replay_status().unwrap());
+                thread::sleep(Duration::from_millis(1));
+            }
+            seen
+        })
+    };
+    g.extend_recipe("QUERY q: SELECT a, b FROM t WHERE a = ?;")
+        .unwrap();
+    done.store(true, Ordering::SeqCst);
+
+    // progress only ever moves forward
+    let mut last = HashMap::new();
+    for status in watcher.join().unwrap() {
+        for (tag, progress) in status {
+            let prev = last.insert(tag, progress.replayed).unwrap_or(0);
+            assert!(progress.replayed >= prev);
+            assert!(progress.replayed <= progress.total);
+        }
+    }
 
-    // make sure we can read (may trigger a replay)
-    let mut bq = g.view("x").unwrap();
-    assert!(bq.lookup(&[3.into()], true).unwrap().is_empty());
+    // and the replay of t covered all of its rows
+    let status = g.replay_status().unwrap();
+    assert!(
+        status
+            .values()
+            .any(|p| p.done && p.total == n as u64 && p.replayed == n as u64),
+        "no completed replay of t in {:?}",
+        status
+    );
 }
 
 #[test]
@@ -2013,6 +5076,448 @@ fn state_replay_migration_query() {
     assert!(out.lookup(&[3.into()], true).unwrap().is_empty());
 }
 
+#[test]
+fn bulk_load_is_bounded() {
+    let mut g = build_local("bulk_load_is_bounded");
+    g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+    });
+
+    let mut countq = g.view("count").unwrap();
+    let mut vote = g.table("vote").unwrap();
+
+    // a small cap forces the loader to wait for the base domain many times
+    let cap = 256 * 1024;
+    let n = 100_000;
+    let stats = vote
+        .load_from_bounded(
+            (0..n).map(|uid: i64| vec![(uid % 10).into(), uid.into()]),
+            cap,
+        ).unwrap();
+    // each row is a 24-byte Vec of two 16-byte values, so a batch of 1024 rows is 57,344 bytes.
+    // four batches fit under the cap, and the fifth has to wait until they are acknowledged.
+    assert_eq!(
+        stats,
+        api::LoadStats {
+            rows: 100_000,
+            batches: 98,
+            max_in_flight: 229_376,
+            skipped: 0,
+        }
+    );
+
+    // rows of the wrong length are rejected
+    assert!(vote.load_from(vec![vec![1.into()]]).is_err());
+
+    // even after whole batches have been sent ahead of the bad row, which then stay loaded, and
+    // the handle remains usable
+    let good = (0..10_240).map(|uid: i64| vec![11.into(), uid.into()]);
+    let bad = Some(vec![11.into()]);
+    assert!(vote.load_from(good.chain(bad)).is_err());
+    vote.insert(vec![11.into(), 10_240.into()]).unwrap();
+
+    sleep();
+    assert_eq!(
+        countq.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), (n / 10).into()]]
+    );
+    assert_eq!(
+        countq.lookup(&[11.into()], true).unwrap(),
+        vec![vec![11.into(), 10_241.into()]]
+    );
+}
+
+#[test]
+fn concurrent_migrations_are_queued() {
+    use std::sync::mpsc;
+
+    let mut b = ControllerBuilder::default();
+    b.set_persistence(get_persistence_params("concurrent_migrations_are_queued"));
+    b.set_migration_queue_timeout(Some(Duration::from_millis(10)));
+    let mut g = b.build_local().unwrap();
+    g.install_recipe("CREATE TABLE t (a int, b int);").unwrap();
+    assert_eq!(g.migration_queue().unwrap(), 0);
+    let mut c = g.pointer().connect().unwrap();
+
+    // a migration that is held up until we let it finish
+    let (started_tx, started) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel::<()>();
+    let slow = thread::spawn(move || {
+        g.migrate(move |_| {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        g
+    });
+    started.recv().unwrap();
+    assert_eq!(c.migration_queue().unwrap(), 1);
+
+    // a fail-fast migration is rejected while the other one is running
+    let e = c
+        .try_extend_recipe("QUERY fast: SELECT a FROM t WHERE b = ?;")
+        .unwrap_err();
+    assert!(e.to_string().contains("already in progress"));
+
+    // one that waits gives up once it has waited for longer than the timeout, and leaves the
+    // queue again without having changed the recipe
+    let e = c
+        .extend_recipe("QUERY fast: SELECT a FROM t WHERE b = ?;")
+        .unwrap_err();
+    assert!(e.to_string().contains("timed out"));
+    assert_eq!(c.migration_queue().unwrap(), 1);
+
+    // once the running migration is done, the queue is empty, and migrations go through again
+    release.send(()).unwrap();
+    let mut g = slow.join().unwrap();
+    assert_eq!(g.migration_queue().unwrap(), 0);
+    g.try_extend_recipe("QUERY fast: SELECT a FROM t WHERE b = ?;")
+        .unwrap();
+    assert!(g.outputs().unwrap().contains_key("fast"));
+}
+
+#[test]
+fn statistics_cover_all_domains() {
+    let mut g = build_local_unsharded("statistics_cover_all_domains");
+    g.migrate(|mig| {
+        // independent bases all end up in their own domain
+        for i in 0..8 {
+            let b = mig.add_base(format!("b{}", i), &["a", "b"], Base::default());
+            mig.maintain_anonymous(b, &[0]);
+        }
+    });
+
+    // statistics are requested from all domains at once, but there must still be exactly one
+    // reply per domain.
+    let stats = g.statistics().unwrap();
+    assert_eq!(stats.len(), 8);
+    assert!(stats.keys().all(|&(_, shard)| shard == 0));
+}
+
+#[test]
+fn statistics_report_traced_latencies() {
+    let mut g = build_local_unsharded("statistics_report_traced_latencies");
+    let count = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+        count
+    });
+
+    let mut vote = g.table("vote").unwrap();
+    for i in 0..10 {
+        vote.trace_next(i);
+        vote.insert(vec![1.into(), (i as i64).into()]).unwrap();
+    }
+    sleep();
+
+    // writes may be merged by group commit, and then only the merged write is measured
+    let stats = g.statistics().unwrap();
+    let latency = stats
+        .values()
+        .filter_map(|&(_, ref nodes)| nodes.get(&count))
+        .map(|n| n.latency)
+        .next()
+        .unwrap();
+    assert!(latency.count >= 1 && latency.count <= 10, "{:?}", latency);
+    assert!(latency.p50 <= latency.p99 && latency.p99 <= latency.max);
+
+    let reader: u64 = stats.values().map(|&(ref d, _)| d.reader_latency.count).sum();
+    assert!(reader >= 1 && reader <= 10);
+}
+
+#[test]
+fn metrics_in_prometheus_format() {
+    let mut g = build_local_unsharded("metrics_in_prometheus_format");
+    let count = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+        count
+    });
+
+    let mut vote = g.table("vote").unwrap();
+    vote.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+
+    let metrics = g.metrics().unwrap();
+    let mut families = HashSet::new();
+    for line in metrics.lines() {
+        if line.starts_with("# HELP ") {
+            continue;
+        }
+        if line.starts_with("# TYPE ") {
+            let mut parts = line[7..].split(' ');
+            families.insert(parts.next().unwrap().to_owned());
+            assert!(["counter", "gauge"].contains(&parts.next().unwrap()));
+            assert_eq!(parts.next(), None);
+            continue;
+        }
+
+        // every sample is `name{label="value",...} value`, with a previously declared name
+        let open = line.find('{').expect(line);
+        let close = line.find("} ").expect(line);
+        let name = &line[..open];
+        assert!(families.contains(name), "undeclared metric in {:?}", line);
+        assert!(
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "bad metric name in {:?}",
+            line
+        );
+        for label in line[open + 1..close].split(',') {
+            let eq = label.find('=').expect(line);
+            assert!(["domain", "shard", "node"].contains(&&label[..eq]));
+            let value = &label[eq + 1..];
+            assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'));
+        }
+        line[close + 2..].parse::<f64>().expect(line);
+    }
+
+    // the aggregation's state shows up under its node label
+    let node = format!(",node=\"{}\"}} ", count.index());
+    assert!(
+        metrics
+            .lines()
+            .any(|l| l.starts_with("soup_node_state_bytes{") && l.contains(&*node)),
+        "no state size reported for the count node in:\n{}",
+        metrics
+    );
+    assert!(
+        metrics
+            .lines()
+            .any(|l| l.starts_with("soup_domain_replays_total{domain=\"0\",shard=\"0\"} "))
+    );
+}
+
+#[test]
+fn query_statistics() {
+    let mut g = build_local_unsharded("query_statistics");
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (aid int, uid int, PRIMARY KEY(uid));
+         QUERY ArticleById: SELECT id, title FROM Article WHERE id = ?;
+         QUERY VotesByArticle: SELECT aid, uid FROM Vote WHERE aid = ?;",
+    ).unwrap();
+    let mut article = g.table("Article").unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    let mut article_by_id = g.view("ArticleById").unwrap();
+    let mut votes_by_article = g.view("VotesByArticle").unwrap();
+
+    article.insert(vec![1.into(), "Hello world".into()]).unwrap();
+    for uid in 0..20 {
+        vote.insert(vec![1.into(), uid.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(article_by_id.lookup(&[1.into()], true).unwrap().len(), 1);
+    assert_eq!(votes_by_article.lookup(&[1.into()], true).unwrap().len(), 20);
+    sleep();
+
+    let stats = g.statistics().unwrap();
+    let queries = g.query_statistics().unwrap();
+    assert_eq!(queries.keys().collect::<Vec<_>>(), vec!["ArticleById", "VotesByArticle"]);
+    assert_ne!(queries["ArticleById"], queries["VotesByArticle"]);
+    assert!(queries["VotesByArticle"].total_mem > queries["ArticleById"].total_mem);
+
+    // everything but the base tables is attributed to some query
+    let bases: HashSet<_> = g.inputs().unwrap().values().cloned().collect();
+    let (mut mem, mut time) = (0, 0);
+    for &(_, ref nodes) in stats.values() {
+        for (ni, ns) in nodes {
+            if !bases.contains(ni) {
+                mem += ns.mem_size;
+                time += ns.process_time;
+            }
+        }
+    }
+    assert_eq!(queries.values().map(|q| q.total_mem).sum::<u64>(), mem);
+    assert_eq!(queries.values().map(|q| q.total_process_time).sum::<u64>(), time);
+}
+
+#[test]
+fn node_sizes() {
+    let mut g = build_local("node_sizes");
+    let count = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+        count
+    });
+
+    let mut countq = g.view("count").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    for uid in 0..10 {
+        vote.insert(vec![1.into(), uid.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(
+        countq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+
+    // the aggregation is materialized, and now holds state
+    let sizes = g.node_sizes().unwrap();
+    let size = &sizes[&count];
+    assert_ne!(size.materialization_status, MaterializationStatus::Not);
+    assert!(size.mem_size > 0);
+}
+
+#[test]
+fn node_last_processed() {
+    let mut g = build_local_unsharded("node_last_processed");
+    let count = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+        count
+    });
+
+    let mut vote = g.table("vote").unwrap();
+    let mut last_processed = || {
+        g.statistics()
+            .unwrap()
+            .values()
+            .filter_map(|&(_, ref nodes)| nodes.get(&count))
+            .next()
+            .expect("no statistics for count node")
+            .last_packet_processed
+    };
+
+    vote.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+    let first = last_processed().expect("count node has not processed anything");
+
+    vote.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    let second = last_processed().unwrap();
+    assert!(second > first);
+}
+
+#[test]
+fn flush_partial_node() {
+    let mut g = build_local_unsharded("flush_partial_node");
+    let (by_article, by_user) = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let by_article = mig.add_ingredient(
+            "by_article",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(by_article, &[0]);
+        let by_user = mig.add_ingredient(
+            "by_user",
+            &["uid", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(by_user, &[0]);
+        (by_article, by_user)
+    });
+
+    let mut by_article_q = g.view("by_article").unwrap();
+    let mut by_user_q = g.view("by_user").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    for uid in 0..10 {
+        vote.insert(vec![1.into(), uid.into()]).unwrap();
+    }
+    sleep();
+
+    // fill both partial views
+    assert_eq!(by_article_q.lookup(&[1.into()], true).unwrap().len(), 1);
+    assert_eq!(by_user_q.lookup(&[1.into()], true).unwrap().len(), 1);
+    sleep();
+
+    let before = g.node_sizes().unwrap();
+    assert_eq!(
+        before[&by_article].materialization_status,
+        MaterializationStatus::Partial
+    );
+    assert_eq!(
+        before[&by_user].materialization_status,
+        MaterializationStatus::Partial
+    );
+    assert!(before[&by_article].mem_size > 0);
+    assert!(before[&by_user].mem_size > 0);
+
+    assert!(g.flush_partial_node(by_article).unwrap() > 0);
+    sleep();
+
+    // only the targeted node lost its state
+    let after = g.node_sizes().unwrap();
+    assert!(after[&by_article].mem_size < before[&by_article].mem_size);
+    assert_eq!(after[&by_user].mem_size, before[&by_user].mem_size);
+
+    // the evicted state is recomputed on demand
+    assert_eq!(
+        by_article_q.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+}
+
+#[test]
+fn memory_limit_evicts_partial_state() {
+    let mut g = build_local_unsharded("memory_limit_evicts_partial_state");
+    g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        let count = mig.add_ingredient(
+            "count",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain_anonymous(count, &[0]);
+    });
+
+    let mut countq = g.view("count").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    for aid in 0..100 {
+        vote.insert(vec![aid.into(), 1.into()]).unwrap();
+    }
+    sleep();
+
+    // fill the partial state
+    for aid in 0..100 {
+        assert_eq!(countq.lookup(&[aid.into()], true).unwrap().len(), 1);
+    }
+    sleep();
+
+    let partial_size = |g: &mut LocalControllerHandle<LocalAuthority>| -> u64 {
+        g.node_sizes()
+            .unwrap()
+            .values()
+            .filter(|s| s.materialization_status == MaterializationStatus::Partial)
+            .map(|s| s.mem_size)
+            .sum()
+    };
+    let before = partial_size(&mut g);
+    assert!(before > 0);
+
+    // the limit is checked on worker heartbeats, which arrive every second
+    let limit = before / 2;
+    g.set_memory_limit(Some(limit)).unwrap();
+    thread::sleep(Duration::from_secs(3));
+
+    assert!(partial_size(&mut g) <= limit);
+}
+
 #[test]
 fn recipe_activates() {
     let mut g = build_local("recipe_activates");
@@ -2028,6 +5533,167 @@ fn recipe_activates() {
     assert_eq!(g.inputs().unwrap().len(), 1);
 }
 
+#[test]
+fn renaming_views_keeps_state() {
+    let mut g = build_local("renaming_views_keeps_state");
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+    let mut vote = g.table("Vote").unwrap();
+    for id in 0..3 {
+        vote.insert(vec![id.into(), 1.into()]).unwrap();
+    }
+    sleep();
+
+    let mut before = g.view("VoteCount").unwrap();
+    assert_eq!(
+        before.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 3.into()]]
+    );
+    let paths = g.replay_paths().unwrap();
+
+    // neither renaming the view directly nor installing a recipe that renames it adds any nodes
+    // or replays state
+    g.rename_view("VoteCount", "Votes").unwrap();
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY Tally: SELECT article, COUNT(id) AS votes FROM Vote \
+                      WHERE article = ? GROUP BY article;",
+    ).unwrap();
+    assert_eq!(g.replay_paths().unwrap(), paths);
+    let outputs = g.outputs().unwrap();
+    assert!(outputs.contains_key("Tally"));
+    assert!(!outputs.contains_key("Votes"));
+    assert!(!outputs.contains_key("VoteCount"));
+    assert!(g.view("VoteCount").is_err());
+    assert!(g.view("Votes").is_err());
+
+    let mut after = g.view("Tally").unwrap();
+    assert_eq!(
+        after.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 3.into()]]
+    );
+    vote.insert(vec![3.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        after.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 4.into()]]
+    );
+    // handles obtained under the old name still read the same view
+    assert_eq!(
+        before.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 4.into()]]
+    );
+
+    assert!(g.rename_view("Tally", "Vote").is_err());
+    assert!(g.rename_view("VoteCount", "Votes").is_err());
+    assert!(g.rename_view("Vote", "Votes").is_err());
+}
+
+#[test]
+fn extending_reports_statement_results() {
+    let mut g = build_local("extending_reports_statement_results");
+    let sql = "CREATE TABLE b (a int, c int, PRIMARY KEY(a));
+               QUERY qa: SELECT a, c FROM b WHERE a = ?;";
+    g.install_recipe(sql).unwrap();
+
+    let sql = "CREATE TABLE b (a int, c int, PRIMARY KEY(a));
+               CREATE TABLE d (x int, y int, PRIMARY KEY(x));
+               QUERY qa: SELECT a, c FROM b WHERE a = ?;
+               QUERY qb: SELECT a, c FROM b WHERE a = ?;
+               QUERY qc: SELECT x, y FROM d WHERE x = ?;
+               QUERY qd: SELECT a, c FROM b WHERE b.a = ?;
+               QUERY qc: SELECT x, y FROM d WHERE x = ?;";
+    let ar = g.extend_recipe(sql).unwrap();
+    assert_eq!(
+        ar.statements,
+        vec![
+            StatementResult::Unchanged,
+            StatementResult::BaseCreated("d".into()),
+            StatementResult::Unchanged,
+            // a new name for a query that is already in the recipe
+            StatementResult::Reused {
+                name: "qb".into(),
+                of: "qa".into(),
+            },
+            StatementResult::QueryAdded("qc".into()),
+            // a differently written query that the dataflow already computes
+            StatementResult::Reused {
+                name: "qd".into(),
+                of: "qa".into(),
+            },
+            StatementResult::Unchanged,
+        ]
+    );
+    assert_eq!(ar.expressions_added, 3);
+
+    // all the names can be read from
+    for view in &["qa", "qb", "qc", "qd"] {
+        g.view(view).unwrap();
+    }
+}
+
+#[test]
+fn extending_reports_reused_nodes() {
+    let mut g = build_local("extending_reports_reused_nodes");
+    let ar = g
+        .install_recipe(
+            "CREATE TABLE b (a int, c int, PRIMARY KEY(a));
+             QUERY qa: SELECT a, c FROM b WHERE a = ?;",
+        ).unwrap();
+    let qa = ar.new_nodes["qa"];
+    let b = g.inputs().unwrap()["b"];
+    assert!(!ar.added_nodes.is_empty());
+
+    let ar = g
+        .extend_recipe(
+            "QUERY qb: SELECT a, c FROM b WHERE a = ?;
+             QUERY qc: SELECT a FROM b WHERE c = ?;",
+        ).unwrap();
+    // qb is computed entirely by qa's nodes, and both queries read from the existing base
+    assert!(ar.reused_nodes.contains(&qa));
+    assert!(ar.reused_nodes.contains(&b));
+    assert!(!ar.added_nodes.contains(&qa));
+    assert!(!ar.added_nodes.contains(&b));
+    // qc needs nodes of its own
+    assert!(!ar.added_nodes.is_empty());
+}
+
+#[test]
+fn extending_with_existing_queries_skips_migration() {
+    let mut g = build_local("extending_with_existing_queries_skips_migration");
+    let sql = "CREATE TABLE b (a int, c int, PRIMARY KEY(a));
+               QUERY qa: SELECT a, c FROM b WHERE a = ?;";
+    g.install_recipe(sql).unwrap();
+    let graph = g.graphviz().unwrap();
+    let version = g.recipe_version().unwrap();
+
+    // resubmitting an already-installed query adds nothing
+    let ar = g.extend_recipe("QUERY qa: SELECT a, c FROM b WHERE a = ?;").unwrap();
+    assert!(ar.new_nodes.is_empty());
+    assert_eq!(ar.expressions_added, 0);
+    assert_eq!(ar.expressions_removed, 0);
+    assert_eq!(g.graphviz().unwrap(), graph);
+
+    // but the recipe still records the extension
+    assert_eq!(g.recipe_version().unwrap(), version + 1);
+
+    // a new name for an existing query doesn't need a migration either
+    let ar = g.extend_recipe("QUERY qb: SELECT a, c FROM b WHERE a = ?;").unwrap();
+    assert_eq!(ar.expressions_added, 0);
+    assert_eq!(g.graphviz().unwrap(), graph);
+
+    let mut b = g.table("b").unwrap();
+    b.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        g.view("qb").unwrap().lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+}
+
 #[test]
 fn recipe_activates_and_migrates() {
     let r_txt = "CREATE TABLE b (a text, c text, x text);\n";
@@ -2066,6 +5732,113 @@ fn recipe_activates_and_migrates_with_join() {
     assert_eq!(g.outputs().unwrap().len(), 1);
 }
 
+#[test]
+fn graph_snapshot_is_stable() {
+    use std::fs;
+
+    let recipe = include_str!("../tests/conference-recipe.txt");
+    let snapshot = || {
+        let mut g = build_local_unsharded("graph_snapshot_is_stable");
+        g.install_recipe(recipe).unwrap();
+        g.graph_snapshot().unwrap()
+    };
+
+    // the same recipe always plans to the same graph
+    let first = snapshot();
+    assert_eq!(first, snapshot());
+    assert!(first.nodes.iter().any(|n| n.name == "PaperTitles"));
+
+    // and the plan only changes when it is meant to. set SOUP_BLESS_SNAPSHOTS to write the
+    // golden file after an intentional planning change.
+    let golden = "tests/conference-graph.json";
+    let current = serde_json::to_string_pretty(&first).unwrap() + "\n";
+    if env::var("SOUP_BLESS_SNAPSHOTS").is_ok() {
+        fs::write(golden, current).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(golden).unwrap_or_else(|e| {
+        panic!(
+            "could not read {}: {}; set SOUP_BLESS_SNAPSHOTS to create it",
+            golden, e
+        )
+    });
+    assert_eq!(
+        expected, current,
+        "graph snapshot differs from {}; set SOUP_BLESS_SNAPSHOTS to update it",
+        golden
+    );
+}
+
+#[test]
+fn describe_node() {
+    let mut g = build_local("describe_node");
+    g.install_recipe(
+        "CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY VoteCount: SELECT article, COUNT(id) AS votes FROM Vote \
+                          WHERE article = ? GROUP BY article;",
+    ).unwrap();
+
+    let snapshot = g.graph_snapshot().unwrap();
+    let agg = snapshot
+        .nodes
+        .iter()
+        .find(|n| n.operator.starts_with("|*|"))
+        .unwrap();
+
+    let desc = g.describe_node(NodeIndex::new(agg.index)).unwrap();
+    assert_eq!(desc.index, agg.index);
+    assert_eq!(desc.name, agg.name);
+    assert_eq!(desc.operator, agg.operator);
+    assert_eq!(desc.columns, agg.columns);
+    assert_eq!(desc.domain, agg.domain);
+    assert!(desc.domain.is_some());
+    assert_eq!(desc.sharding, agg.sharding);
+    assert_eq!(desc.shards, 2);
+    assert_ne!(desc.materialization, MaterializationStatus::Not);
+    assert_eq!(desc.parents, agg.parents);
+    assert!(!desc.children.is_empty());
+    for &child in &desc.children {
+        let child = snapshot.nodes.iter().find(|n| n.index == child).unwrap();
+        assert!(child.parents.contains(&agg.index));
+    }
+    assert!(desc.graphviz.contains(&agg.name), "{}", desc.graphviz);
+
+    // nodes that do not exist cannot be described
+    assert!(g.describe_node(NodeIndex::new(10_000)).is_err());
+}
+
+#[test]
+fn graph_invariants_hold_across_migrations() {
+    let mut g = build_local("graph_invariants_hold_across_migrations");
+    let violations = g.check_invariants().unwrap();
+    assert!(violations.is_empty(), "{:?}", violations);
+
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         VoteCount: SELECT article, COUNT(id) AS votes FROM Vote GROUP BY article;
+         QUERY ArticleWithVoteCount: SELECT Article.id, title, VoteCount.votes \
+                                     FROM Article \
+                                     LEFT JOIN VoteCount ON (Article.id = VoteCount.article) \
+                                     WHERE Article.id = ?;",
+    ).unwrap();
+    let violations = g.check_invariants().unwrap();
+    assert!(violations.is_empty(), "{:?}", violations);
+
+    g.extend_recipe("QUERY ArticlesByTitle: SELECT id, title FROM Article WHERE title = ?;")
+        .unwrap();
+    let violations = g.check_invariants().unwrap();
+    assert!(violations.is_empty(), "{:?}", violations);
+
+    g.install_recipe(
+        "CREATE TABLE Article (id int, title varchar(255), PRIMARY KEY(id));
+         CREATE TABLE Vote (id int, article int, PRIMARY KEY(id));
+         QUERY ArticlesByTitle: SELECT id, title FROM Article WHERE title = ?;",
+    ).unwrap();
+    let violations = g.check_invariants().unwrap();
+    assert!(violations.is_empty(), "{:?}", violations);
+}
+
 #[test]
 fn finkelstein1982_queries() {
     use std::fs::File;
@@ -2292,3 +6065,72 @@ fn remove_query() {
     assert_eq!(qa.lookup(&[0.into()], true).unwrap().len(), 3);
     assert_eq!(qb.lookup(&[0.into()], true).unwrap().len(), 1);
 }
+
+#[test]
+fn remove_base_with_dependents_is_rejected() {
+    let r_txt = "CREATE TABLE a (x int, y int);\n
+                 CREATE TABLE b (z int);\n
+                 QUERY qa: SELECT x FROM a;\n
+                 QUERY qb: SELECT z FROM b;";
+
+    // drops `a`, but keeps `qa`, which reads from it
+    let r2_txt = "CREATE TABLE b (z int);\n
+                  QUERY qa: SELECT x FROM a;\n
+                  QUERY qb: SELECT z FROM b;";
+
+    let mut g = ControllerBuilder::default().build_local().unwrap();
+    g.install_recipe(r_txt).unwrap();
+    assert_eq!(g.inputs().unwrap().len(), 2);
+
+    let mut muta = g.table("a").unwrap();
+    muta.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+
+    let e = g.install_recipe(r2_txt).unwrap_err();
+    assert!(format!("{:?}", e).contains("qa"));
+
+    // the graph must be left untouched
+    assert_eq!(g.inputs().unwrap().len(), 2);
+    assert_eq!(g.outputs().unwrap().len(), 2);
+    let mut qa = g.view("qa").unwrap();
+    assert_eq!(qa.lookup(&[0.into()], true).unwrap().len(), 1);
+
+    muta.insert(vec![3.into(), 4.into()]).unwrap();
+    sleep();
+    assert_eq!(qa.lookup(&[0.into()], true).unwrap().len(), 2);
+}
+
+#[test]
+fn remove_base_with_dependents_forced() {
+    let r_txt = "CREATE TABLE a (x int, y int);\n
+                 CREATE TABLE b (z int);\n
+                 QUERY qa: SELECT x FROM a;\n
+                 QUERY qab: SELECT a.x, b.z FROM a JOIN b ON a.y = b.z;\n
+                 QUERY qb: SELECT z FROM b;";
+
+    // drops `a`, but keeps `qa` and `qab`, which read from it
+    let r2_txt = "CREATE TABLE b (z int);\n
+                  QUERY qa: SELECT x FROM a;\n
+                  QUERY qab: SELECT a.x, b.z FROM a JOIN b ON a.y = b.z;\n
+                  QUERY qb: SELECT z FROM b;";
+
+    // sharding introduces domain crossings below the bases
+    let mut g = build_local("remove_base_with_dependents_forced");
+    g.install_recipe(r_txt).unwrap();
+    assert_eq!(g.inputs().unwrap().len(), 2);
+    assert_eq!(g.outputs().unwrap().len(), 3);
+
+    g.force_install_recipe(r2_txt).unwrap();
+    assert_eq!(g.inputs().unwrap().len(), 1);
+    assert_eq!(g.outputs().unwrap().len(), 1);
+    assert!(g.view("qa").is_err());
+    assert!(g.view("qab").is_err());
+    assert!(g.table("a").is_err());
+
+    // the remaining base and query must keep working
+    let mut mutb = g.table("b").unwrap();
+    let mut qb = g.view("qb").unwrap();
+    mutb.insert(vec![1.into()]).unwrap();
+    sleep();
+    assert_eq!(qb.lookup(&[0.into()], true).unwrap().len(), 1);
+}
@@ -327,20 +327,28 @@ extern crate slog;
 
 mod controller;
 mod coordination;
+mod mysql_adapter;
 
 #[cfg(test)]
 mod integration;
 
 pub use consensus::{LocalAuthority, ZookeeperAuthority};
 
-pub use basics::{DataType, Datas, Modification, NodeIndex, Operation};
+pub use basics::{
+    DataType, Datas, DomainIndex, Modification, NodeIndex, Operation, ShardHasher,
+};
 
-pub use dataflow::{DurabilityMode, PersistenceParameters};
+pub use dataflow::state::EvictionPolicy;
+pub use dataflow::{Compression, DurabilityMode, PersistenceParameters};
 
 pub use api::*;
 
 pub use crate::controller::sql::reuse::ReuseConfigType;
-pub use crate::controller::{ControllerBuilder, LocalControllerHandle};
+pub use crate::mysql_adapter::serve_mysql;
+pub use crate::controller::{
+    ControllerBuilder, LocalControllerHandle, MigrationSummary, PlacementPolicy, RoundRobin,
+    ShardLocality,
+};
 
 /// Just give me a damn terminal logger
 pub fn logger_pls() -> slog::Logger {
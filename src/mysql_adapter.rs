@@ -0,0 +1,667 @@
+//! A front-end that lets MySQL clients talk to Soup.
+//!
+//! Only a small subset of MySQL is supported:
+//!
+//!  - `SELECT` queries are installed as Soup queries the first time they are seen, and are then
+//!    answered by looking up their parameters (the `?` placeholders) in the resulting view.
+//!    Literals that columns are compared to for equality in the `WHERE` clause are treated like
+//!    placeholders, so queries that only differ in those literals share a view. Any other
+//!    literal makes for a separate view per distinct query string. These views are never
+//!    removed, so once a given number of them exist, `SELECT`s that would need a new one are
+//!    rejected; the views that are already there keep being served.
+//!  - `INSERT` statements become `Table::insert`s, and missing columns are set to NULL.
+//!  - `DELETE` statements whose `WHERE` clause is a conjunction of column equalities become
+//!    `Table::delete_where`s.
+//!  - `SET` statements are accepted and ignored, and `SELECT @@variable` queries return a value
+//!    for the handful of variables that client libraries ask for when they connect.
+//!
+//! Anything else is answered with an error.
+
+use api::{ControllerHandle, ControllerPointer, Table, View};
+use basics::DataType;
+use consensus::Authority;
+use msql_srv::{
+    Column, ColumnFlags, ColumnType, ErrorKind, MysqlIntermediary, MysqlShim, ParamParser,
+    QueryResultWriter, RowWriter, StatementMetaWriter, ValueInner,
+};
+use nom_sql::parser as sql_parser;
+use nom_sql::{
+    ConditionBase, ConditionExpression, DeleteStatement, FieldDefinitionExpression,
+    InsertStatement, Literal, Operator, SelectStatement, SqlQuery,
+};
+use fnv::FnvHasher;
+use slog;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// A value in a statement, which is either given, or filled in by a parameter on execution.
+enum Value {
+    Given(DataType),
+    Param,
+}
+
+impl<'a> From<&'a Literal> for Value {
+    fn from(l: &'a Literal) -> Self {
+        match *l {
+            Literal::Placeholder => Value::Param,
+            ref l => Value::Given(l.into()),
+        }
+    }
+}
+
+impl Value {
+    fn fill(&self, params: &mut Iterator<Item = DataType>) -> Result<DataType, StatementError> {
+        match *self {
+            Value::Given(ref v) => Ok(v.clone()),
+            Value::Param => params.next().ok_or_else(|| {
+                StatementError::new(ErrorKind::ER_WRONG_ARGUMENTS, "too few parameters")
+            }),
+        }
+    }
+}
+
+enum Statement {
+    Select {
+        view: View,
+        /// The view's columns that the query selects, in order.
+        project: Vec<usize>,
+        /// The value to look up for each of the view's key columns.
+        key: Vec<Value>,
+    },
+    Insert {
+        table: Table,
+        /// For each row, the value to write to each of the table's columns.
+        rows: Vec<Vec<(usize, Value)>>,
+    },
+    Delete {
+        table: Table,
+        predicate: Vec<(usize, Value)>,
+    },
+    Variables(Vec<(String, DataType)>),
+    Ignored,
+}
+
+impl Statement {
+    fn num_params(&self) -> usize {
+        fn params<'a, I: Iterator<Item = &'a Value>>(vs: I) -> usize {
+            vs.filter(|v| match **v {
+                Value::Param => true,
+                Value::Given(_) => false,
+            }).count()
+        }
+
+        match *self {
+            Statement::Select { ref key, .. } => params(key.iter()),
+            Statement::Insert { ref rows, .. } => {
+                params(rows.iter().flat_map(|r| r.iter().map(|&(_, ref v)| v)))
+            }
+            Statement::Delete { ref predicate, .. } => {
+                params(predicate.iter().map(|&(_, ref v)| v))
+            }
+            Statement::Variables(_) | Statement::Ignored => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StatementError {
+    kind: ErrorKind,
+    msg: String,
+}
+
+impl StatementError {
+    fn new<S: Into<String>>(kind: ErrorKind, msg: S) -> Self {
+        StatementError {
+            kind,
+            msg: msg.into(),
+        }
+    }
+
+    fn unsupported<S: Into<String>>(msg: S) -> Self {
+        Self::new(ErrorKind::ER_NOT_SUPPORTED_YET, msg)
+    }
+}
+
+/// The prefix of the names of views installed for `SELECT` queries.
+const VIEW_PREFIX: &str = "mysql_";
+
+/// The name of the view installed for the `SELECT` query `query`.
+///
+/// The name is derived from an FNV hash of the query, which, unlike the standard library's
+/// default hasher, is the same across Rust releases; a query therefore maps to the same view
+/// after an upgrade, rather than to a duplicate of it.
+fn view_name(query: &str) -> String {
+    let mut hasher = FnvHasher::default();
+    hasher.write(query.as_bytes());
+    format!("{}{:x}", VIEW_PREFIX, hasher.finish())
+}
+
+/// Serves a single MySQL client connection using the given connection to Soup.
+struct MysqlAdapter<A: Authority> {
+    soup: ControllerHandle<A>,
+    prepared: HashMap<u32, Statement>,
+    next_id: u32,
+    /// The most views that may be installed for `SELECT` queries.
+    max_views: usize,
+}
+
+impl<A: Authority> MysqlAdapter<A> {
+    fn new(soup: ControllerHandle<A>, max_views: usize) -> Self {
+        MysqlAdapter {
+            soup,
+            prepared: HashMap::new(),
+            next_id: 1,
+            max_views,
+        }
+    }
+
+    fn prepare(&mut self, query: &str) -> Result<Statement, StatementError> {
+        let query = query.trim().trim_right_matches(';').trim();
+        if query.starts_with("SELECT @@") || query.starts_with("select @@") {
+            return Ok(Statement::Variables(variables(&query["SELECT ".len()..])));
+        }
+
+        match sql_parser::parse_query(query) {
+            Ok(SqlQuery::Select(ref st)) => self.prepare_select(query, st),
+            Ok(SqlQuery::Insert(ref st)) => self.prepare_insert(st),
+            Ok(SqlQuery::Delete(ref st)) => self.prepare_delete(st),
+            Ok(SqlQuery::Set(_)) => Ok(Statement::Ignored),
+            Ok(_) => Err(StatementError::unsupported(format!(
+                "only SELECT, INSERT, DELETE and SET statements are supported: {}",
+                query
+            ))),
+            Err(e) => Err(StatementError::new(
+                ErrorKind::ER_PARSE_ERROR,
+                format!("failed to parse query: {}", e),
+            )),
+        }
+    }
+
+    fn prepare_select(
+        &mut self,
+        query: &str,
+        st: &SelectStatement,
+    ) -> Result<Statement, StatementError> {
+        let mut shared = st.clone();
+        let mut key = Vec::new();
+        if let Some(ref mut cond) = shared.where_clause {
+            parameterize(cond, &mut key);
+        }
+        if key.iter().any(|v| match *v {
+            Value::Given(_) => true,
+            Value::Param => false,
+        }) {
+            // if the view is not keyed the way we expect, fall back to the query as given
+            if let Ok(stmt) = self.install_select(&shared.to_string(), &shared, Some(key)) {
+                return Ok(stmt);
+            }
+        }
+        self.install_select(query, st, None)
+    }
+
+    /// Install `query` if it is not installed yet, and prepare to look up `key` in its view.
+    ///
+    /// If `key` is `None`, every key column of the view is looked up by a parameter.
+    fn install_select(
+        &mut self,
+        query: &str,
+        st: &SelectStatement,
+        key: Option<Vec<Value>>,
+    ) -> Result<Statement, StatementError> {
+        let name = view_name(query);
+        let view = match self.soup.view(&name) {
+            Ok(view) => view,
+            Err(_) => {
+                let outputs = self.soup.outputs().map_err(|e| {
+                    StatementError::new(ErrorKind::ER_UNKNOWN_ERROR, format!("{}", e))
+                })?;
+                let installed = outputs.keys().filter(|v| v.starts_with(VIEW_PREFIX)).count();
+                if installed >= self.max_views {
+                    return Err(StatementError::unsupported(format!(
+                        "{} views are installed for SELECT queries already, which is the most \
+                         allowed; add the query to the recipe instead: {}",
+                        installed, query
+                    )));
+                }
+
+                self.soup
+                    .extend_recipe(&format!("QUERY {}: {};", name, query))
+                    .map_err(|e| {
+                        StatementError::unsupported(format!("failed to install query: {}", e))
+                    })?;
+                self.soup.view(&name).map_err(|e| {
+                    StatementError::new(ErrorKind::ER_UNKNOWN_ERROR, format!("{}", e))
+                })?
+            }
+        };
+
+        // the view also contains the columns it is keyed by, which the query may not select
+        let named: Option<Vec<usize>> = st
+            .fields
+            .iter()
+            .map(|f| match *f {
+                FieldDefinitionExpression::Col(ref c) => {
+                    let name = c.alias.as_ref().unwrap_or(&c.name);
+                    view.columns().iter().position(|vc| vc == name)
+                }
+                _ => None,
+            }).collect();
+        let project = named.unwrap_or_else(|| {
+            (0..view.columns().len())
+                .filter(|&i| view.columns()[i] != "bogokey")
+                .collect()
+        });
+
+        let nkey = if view.key_columns() == ["bogokey"] {
+            0
+        } else {
+            view.key_columns().len()
+        };
+        let key = match key {
+            Some(key) => key,
+            None => (0..nkey).map(|_| Value::Param).collect(),
+        };
+        if key.len() != nkey {
+            return Err(StatementError::unsupported(format!(
+                "query {} is keyed by {} columns, not {}",
+                query,
+                nkey,
+                key.len()
+            )));
+        }
+
+        Ok(Statement::Select { view, project, key })
+    }
+
+    fn prepare_insert(&mut self, st: &InsertStatement) -> Result<Statement, StatementError> {
+        let table = self.table(&st.table.name)?;
+
+        let columns: Vec<usize> = match st.fields {
+            None => (0..table.columns().len()).collect(),
+            Some(ref fields) => fields
+                .iter()
+                .map(|f| column_index(&table, &f.name))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let rows = st
+            .data
+            .iter()
+            .map(|row| {
+                if row.len() != columns.len() {
+                    return Err(StatementError::new(
+                        ErrorKind::ER_WRONG_VALUE_COUNT_ON_ROW,
+                        format!("expected {} values, got {}", columns.len(), row.len()),
+                    ));
+                }
+                Ok(columns
+                    .iter()
+                    .zip(row)
+                    .map(|(&c, v)| (c, Value::from(v)))
+                    .collect())
+            }).collect::<Result<_, _>>()?;
+
+        Ok(Statement::Insert { table, rows })
+    }
+
+    fn prepare_delete(&mut self, st: &DeleteStatement) -> Result<Statement, StatementError> {
+        let table = self.table(&st.table.name)?;
+
+        let mut predicate = Vec::new();
+        match st.where_clause {
+            Some(ref cond) => equalities(&table, cond, &mut predicate)?,
+            None => {
+                return Err(StatementError::unsupported(
+                    "DELETE without a WHERE clause is not supported",
+                ))
+            }
+        }
+
+        Ok(Statement::Delete { table, predicate })
+    }
+
+    fn table(&mut self, name: &str) -> Result<Table, StatementError> {
+        self.soup.table(name).map_err(|_| {
+            StatementError::new(ErrorKind::ER_NO_SUCH_TABLE, format!("no table named {}", name))
+        })
+    }
+
+    fn execute<W: Write>(
+        stmt: &mut Statement,
+        params: Vec<DataType>,
+        results: QueryResultWriter<W>,
+    ) -> io::Result<()> {
+        match Self::try_execute(stmt, params) {
+            Ok(Outcome::Rows(columns, rows)) => {
+                let mut rw = results.start(&columns)?;
+                for row in rows {
+                    for v in &row {
+                        write_value(&mut rw, v)?;
+                    }
+                    rw.end_row()?;
+                }
+                rw.finish()
+            }
+            Ok(Outcome::Done(affected)) => results.completed(affected, 0),
+            Err(e) => results.error(e.kind, e.msg.as_bytes()),
+        }
+    }
+
+    fn try_execute(
+        stmt: &mut Statement,
+        params: Vec<DataType>,
+    ) -> Result<Outcome, StatementError> {
+        let nparams = params.len();
+        if nparams != stmt.num_params() {
+            return Err(StatementError::new(
+                ErrorKind::ER_WRONG_ARGUMENTS,
+                format!("expected {} parameters, got {}", stmt.num_params(), nparams),
+            ));
+        }
+        let mut params = params.into_iter();
+        let soup_err = |e: &::std::fmt::Display| {
+            StatementError::new(ErrorKind::ER_UNKNOWN_ERROR, format!("{}", e))
+        };
+
+        match *stmt {
+            Statement::Select {
+                ref mut view,
+                ref project,
+                ref key,
+            } => {
+                let key: Vec<DataType> = if key.is_empty() {
+                    vec![0.into()]
+                } else {
+                    key.iter()
+                        .map(|v| v.fill(&mut params))
+                        .collect::<Result<_, _>>()?
+                };
+                let rows: Vec<Vec<DataType>> = view
+                    .lookup(&key, true)
+                    .map_err(|e| soup_err(&e))?
+                    .into_iter()
+                    .map(|row| project.iter().map(|&c| row[c].clone()).collect())
+                    .collect();
+                let columns = project
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &c)| Column {
+                        table: String::new(),
+                        column: view.columns()[c].clone(),
+                        coltype: column_type(rows.iter().map(|r| &r[i])),
+                        colflags: ColumnFlags::empty(),
+                    }).collect();
+                Ok(Outcome::Rows(columns, rows))
+            }
+            Statement::Insert {
+                ref mut table,
+                ref rows,
+            } => {
+                let ncols = table.columns().len();
+                let rows = rows
+                    .iter()
+                    .map(|values| {
+                        let mut row = vec![DataType::None; ncols];
+                        for &(c, ref v) in values {
+                            row[c] = v.fill(&mut params)?;
+                        }
+                        Ok::<_, StatementError>(row)
+                    }).collect::<Result<Vec<_>, _>>()?;
+                let n = rows.len() as u64;
+                table.insert_all(rows).map_err(|e| soup_err(&e))?;
+                Ok(Outcome::Done(n))
+            }
+            Statement::Delete {
+                ref mut table,
+                ref predicate,
+            } => {
+                let predicate = predicate
+                    .iter()
+                    .map(|&(c, ref v)| Ok::<_, StatementError>((c, v.fill(&mut params)?)))
+                    .collect::<Result<_, _>>()?;
+                table.delete_where(predicate).map_err(|e| soup_err(&e))?;
+                // we don't know how many rows were deleted
+                Ok(Outcome::Done(0))
+            }
+            Statement::Variables(ref vars) => {
+                let columns = vars
+                    .iter()
+                    .map(|&(ref name, ref v)| Column {
+                        table: String::new(),
+                        column: name.clone(),
+                        coltype: column_type(Some(v)),
+                        colflags: ColumnFlags::empty(),
+                    }).collect();
+                let row = vars.iter().map(|&(_, ref v)| v.clone()).collect();
+                Ok(Outcome::Rows(columns, vec![row]))
+            }
+            Statement::Ignored => Ok(Outcome::Done(0)),
+        }
+    }
+}
+
+enum Outcome {
+    Rows(Vec<Column>, Vec<Vec<DataType>>),
+    Done(u64),
+}
+
+impl<A: Authority, W: Write> MysqlShim<W> for MysqlAdapter<A> {
+    type Error = io::Error;
+
+    fn on_prepare(&mut self, query: &str, info: StatementMetaWriter<W>) -> io::Result<()> {
+        let stmt = match self.prepare(query) {
+            Ok(stmt) => stmt,
+            Err(e) => return info.error(e.kind, e.msg.as_bytes()),
+        };
+
+        // we don't know the parameter or result types yet, so we claim that everything is text;
+        // the result columns are described again, with their actual types, on execution
+        let text = |name: &str| Column {
+            table: String::new(),
+            column: name.to_owned(),
+            coltype: ColumnType::MYSQL_TYPE_VAR_STRING,
+            colflags: ColumnFlags::empty(),
+        };
+        let params: Vec<_> = (0..stmt.num_params()).map(|_| text("?")).collect();
+        let columns: Vec<_> = match stmt {
+            Statement::Select {
+                ref view,
+                ref project,
+                ..
+            } => project.iter().map(|&c| text(&view.columns()[c])).collect(),
+            Statement::Variables(ref vars) => vars.iter().map(|&(ref n, _)| text(n)).collect(),
+            _ => vec![],
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.prepared.insert(id, stmt);
+        info.reply(id, &params, &columns)
+    }
+
+    fn on_execute(
+        &mut self,
+        id: u32,
+        params: ParamParser,
+        results: QueryResultWriter<W>,
+    ) -> io::Result<()> {
+        let params: Result<Vec<_>, _> = params
+            .into_iter()
+            .map(|p| match p.value.into_inner() {
+                ValueInner::NULL => Ok(DataType::None),
+                ValueInner::Bytes(b) => Ok(String::from_utf8_lossy(b).into_owned().into()),
+                ValueInner::Int(i) => Ok(DataType::from(i)),
+                ValueInner::UInt(i) => Ok(DataType::from(i as i64)),
+                ValueInner::Double(f) => Ok(DataType::from(f)),
+                _ => Err(StatementError::unsupported("unsupported parameter type")),
+            }).collect();
+
+        match (self.prepared.get_mut(&id), params) {
+            (Some(stmt), Ok(params)) => Self::execute(stmt, params, results),
+            (None, _) => results.error(
+                ErrorKind::ER_UNKNOWN_STMT_HANDLER,
+                format!("no prepared statement with id {}", id).as_bytes(),
+            ),
+            (_, Err(e)) => results.error(e.kind, e.msg.as_bytes()),
+        }
+    }
+
+    fn on_close(&mut self, id: u32) {
+        self.prepared.remove(&id);
+    }
+
+    fn on_query(&mut self, query: &str, results: QueryResultWriter<W>) -> io::Result<()> {
+        match self.prepare(query) {
+            Ok(mut stmt) => Self::execute(&mut stmt, vec![], results),
+            Err(e) => results.error(e.kind, e.msg.as_bytes()),
+        }
+    }
+}
+
+/// Find the index of the column `name` in `table`.
+fn column_index(table: &Table, name: &str) -> Result<usize, StatementError> {
+    table.columns().iter().position(|c| c == name).ok_or_else(|| {
+        StatementError::new(
+            ErrorKind::ER_BAD_FIELD_ERROR,
+            format!("no column {} in table {}", name, table.table_name()),
+        )
+    })
+}
+
+/// Collect the column equalities that `cond` is a conjunction of.
+fn equalities(
+    table: &Table,
+    cond: &ConditionExpression,
+    into: &mut Vec<(usize, Value)>,
+) -> Result<(), StatementError> {
+    match *cond {
+        ConditionExpression::LogicalOp(ref ct) if ct.operator == Operator::And => {
+            equalities(table, &ct.left, into)?;
+            equalities(table, &ct.right, into)
+        }
+        ConditionExpression::Bracketed(ref inner) => equalities(table, inner, into),
+        ConditionExpression::ComparisonOp(ref ct) if ct.operator == Operator::Equal => {
+            match (&*ct.left, &*ct.right) {
+                (
+                    &ConditionExpression::Base(ConditionBase::Field(ref c)),
+                    &ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                ) => {
+                    into.push((column_index(table, &c.name)?, Value::from(l)));
+                    Ok(())
+                }
+                _ => Err(StatementError::unsupported(
+                    "DELETE conditions must compare a column to a value",
+                )),
+            }
+        }
+        _ => Err(StatementError::unsupported(
+            "DELETE conditions must be conjunctions of equalities",
+        )),
+    }
+}
+
+/// Replace the literals that columns are compared to for equality in the conjunction `cond` by
+/// placeholders, and collect the values of all of those placeholders in order.
+fn parameterize(cond: &mut ConditionExpression, key: &mut Vec<Value>) {
+    match *cond {
+        ConditionExpression::LogicalOp(ref mut ct) if ct.operator == Operator::And => {
+            parameterize(&mut ct.left, key);
+            parameterize(&mut ct.right, key);
+        }
+        ConditionExpression::Bracketed(ref mut inner) => parameterize(inner, key),
+        ConditionExpression::ComparisonOp(ref mut ct) if ct.operator == Operator::Equal => {
+            if let ConditionExpression::Base(ConditionBase::Field(_)) = *ct.left {
+                if let ConditionExpression::Base(ConditionBase::Literal(ref mut l)) = *ct.right {
+                    key.push(Value::from(&*l));
+                    *l = Literal::Placeholder;
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Values for the `@@variable`s in a `SELECT @@variable, ...` query.
+fn variables(fields: &str) -> Vec<(String, DataType)> {
+    fields
+        .split(',')
+        .map(|f| {
+            let name = f.trim().to_owned();
+            let value = match name.trim_left_matches('@').to_lowercase().as_str() {
+                "max_allowed_packet" => DataType::from(64 * 1024 * 1024),
+                "wait_timeout" => DataType::from(28800),
+                "version_comment" => DataType::from("soup"),
+                "auto_increment_increment" => DataType::from(1),
+                _ => DataType::None,
+            };
+            (name, value)
+        }).collect()
+}
+
+/// The MySQL type of a result column with the given values.
+fn column_type<'a, I: IntoIterator<Item = &'a DataType>>(values: I) -> ColumnType {
+    let first = values.into_iter().find(|v| **v != DataType::None);
+    match first {
+        Some(&DataType::Int(_)) | Some(&DataType::BigInt(_)) => ColumnType::MYSQL_TYPE_LONGLONG,
+        Some(&DataType::Real(..)) => ColumnType::MYSQL_TYPE_DOUBLE,
+        Some(&DataType::Timestamp(_)) => ColumnType::MYSQL_TYPE_DATETIME,
+        Some(&DataType::Text(..))
+        | Some(&DataType::TinyText(..))
+        | Some(&DataType::None)
+        | None => ColumnType::MYSQL_TYPE_VAR_STRING,
+    }
+}
+
+fn write_value<W: Write>(rw: &mut RowWriter<W>, v: &DataType) -> io::Result<()> {
+    match *v {
+        DataType::None => rw.write_col(None::<i64>),
+        DataType::Int(n) => rw.write_col(n as i64),
+        DataType::BigInt(n) => rw.write_col(n),
+        DataType::Real(..) => rw.write_col(Into::<f64>::into(v)),
+        DataType::Text(..) | DataType::TinyText(..) => {
+            let s: Cow<str> = v.into();
+            rw.write_col(&*s)
+        }
+        DataType::Timestamp(ts) => rw.write_col(ts),
+    }
+}
+
+/// Accept MySQL clients on `listener`, and serve each one on its own thread using a new
+/// connection to the Soup controller that `soup` points to.
+///
+/// At most `max_views` views are installed for the `SELECT` queries that clients send, across all
+/// clients. See the `mysql_adapter` module documentation for the subset of MySQL that is
+/// supported. This function only returns if accepting a client connection fails.
+pub fn serve_mysql<A: Authority + 'static>(
+    listener: TcpListener,
+    soup: ControllerPointer<A>,
+    max_views: usize,
+    log: slog::Logger,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let soup = soup.clone();
+        let log = log.clone();
+        thread::Builder::new()
+            .name("mysql-client".to_owned())
+            .spawn(move || {
+                let soup = match soup.connect() {
+                    Ok(soup) => soup,
+                    Err(e) => {
+                        error!(log, "mysql client could not connect to soup"; "err" => %e);
+                        return;
+                    }
+                };
+                let adapter = MysqlAdapter::new(soup, max_views);
+                if let Err(e) = MysqlIntermediary::run_on_tcp(adapter, stream) {
+                    debug!(log, "mysql client connection failed"; "err" => ?e);
+                }
+            })?;
+    }
+    Ok(())
+}